@@ -0,0 +1,164 @@
+/*!
+Events sent to receivers outside the services.
+
+Each event is wrapped in an [`Event`] envelope with a unique `eventId`, the
+`eventVersion` of its payload and the time it `occurredAt`. The body is
+signed with the Ed25519 key served at `/api/v1/.well-known/signing-key`
+together with the time it was sent, and the [`EVENT_SIGNATURE_HEADER`]
+carries both as `t=<unix seconds>,v1=<base64 signature>`.
+
+Receivers check deliveries with [`verify_event`], which rejects bodies
+signed by another key and deliveries sent longer ago than a tolerance, and
+then ignore events whose `eventId` they already handled. Together these
+keep a captured delivery from being replayed.
+*/
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Header holding the signature of an event delivery.
+pub const EVENT_SIGNATURE_HEADER: &str = "x-event-signature";
+
+/// Version of the event payloads sent by this release.
+pub const EVENT_VERSION: u32 = 1;
+
+/// Age in seconds after which receivers should reject a delivery.
+pub const DEFAULT_EVENT_TOLERANCE_SECS: i64 = 5 * 60;
+
+/// Envelope of an event.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Event<T> {
+    /// Unique id of the event, the same for every delivery of it.
+    pub event_id: String,
+    /// Kind of the event, such as `user.created`.
+    pub event_type: String,
+    /// Version of the payload shape.
+    pub event_version: u32,
+    /// RFC 3339 time the event occurred.
+    pub occurred_at: String,
+    pub data: T,
+}
+
+impl<T> Event<T> {
+    /// Event of `event_type` with a new id and the current payload version.
+    pub fn new(event_type: &str, occurred_at: chrono::DateTime<chrono::Utc>, data: T) -> Self {
+        Self {
+            event_id: ulid::Ulid::new().to_string(),
+            event_type: event_type.to_owned(),
+            event_version: EVENT_VERSION,
+            occurred_at: occurred_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            data,
+        }
+    }
+}
+
+/// Bytes signed for a delivery of `body` sent at `timestamp`, in seconds
+/// since the epoch.
+pub fn signed_payload(timestamp: i64, body: &[u8]) -> Vec<u8> {
+    let mut payload = format!("{timestamp}.").into_bytes();
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// Value of the [`EVENT_SIGNATURE_HEADER`] for a delivery sent at
+/// `timestamp` with `signature` of its [`signed_payload`].
+pub fn signature_header(timestamp: i64, signature: &[u8]) -> String {
+    format!("t={timestamp},v1={}", base64::encode(signature))
+}
+
+/// Event delivery that can't be trusted.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EventVerifyError {
+    #[error("Malformed event signature header")]
+    Malformed,
+    #[error("Event sent {0} seconds ago, outside the tolerance")]
+    Expired(i64),
+    #[error("Event signature doesn't match the body")]
+    Invalid,
+}
+
+/// Check `header` holds a signature of `body` by `public_key`, the raw
+/// Ed25519 key, sent within `tolerance_secs` of `now`, both in seconds
+/// since the epoch. Any of several `v1` signatures may match so the key
+/// can be rotated.
+pub fn verify_event(
+    public_key: &[u8],
+    header: &str,
+    body: &[u8],
+    now: i64,
+    tolerance_secs: i64,
+) -> Result<(), EventVerifyError> {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", t)) => {
+                timestamp = Some(t.parse::<i64>().map_err(|_| EventVerifyError::Malformed)?)
+            }
+            Some(("v1", signature)) => signatures.push(signature),
+            _ => (),
+        }
+    }
+    let timestamp = timestamp.ok_or(EventVerifyError::Malformed)?;
+    if signatures.is_empty() {
+        return Err(EventVerifyError::Malformed);
+    }
+    if (now - timestamp).abs() > tolerance_secs {
+        return Err(EventVerifyError::Expired(now - timestamp));
+    }
+
+    let payload = signed_payload(timestamp, body);
+    let key = UnparsedPublicKey::new(&ED25519, public_key);
+    signatures
+        .into_iter()
+        .filter_map(|signature| base64::decode(signature).ok())
+        .any(|signature| key.verify(&payload, &signature).is_ok())
+        .then_some(())
+        .ok_or(EventVerifyError::Invalid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{signature_header, signed_payload, verify_event, Event, EventVerifyError};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+
+    #[test]
+    fn test_verify_event() {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let public_key = key_pair.public_key().as_ref();
+        let event = Event::new("user.created", chrono::Utc::now(), json!({"id": "1"}));
+        let body = serde_json::to_vec(&event).unwrap();
+        let header = signature_header(1000, key_pair.sign(&signed_payload(1000, &body)).as_ref());
+
+        assert_eq!(verify_event(public_key, &header, &body, 1100, 300), Ok(()));
+        assert_eq!(
+            verify_event(public_key, &header, &body, 1400, 300),
+            Err(EventVerifyError::Expired(400))
+        );
+        assert_eq!(
+            verify_event(public_key, &header, b"{}", 1100, 300),
+            Err(EventVerifyError::Invalid)
+        );
+        // The signature covers the timestamp.
+        let replayed = header.replace("t=1000", "t=1300");
+        assert_eq!(
+            verify_event(public_key, &replayed, &body, 1300, 300),
+            Err(EventVerifyError::Invalid)
+        );
+        assert_eq!(
+            verify_event(public_key, &format!("v1=x,{header}"), &body, 1100, 300),
+            Ok(())
+        );
+        assert_eq!(
+            verify_event(public_key, "t=1000", &body, 1100, 300),
+            Err(EventVerifyError::Malformed)
+        );
+
+        let value = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(value["eventVersion"], 1);
+        assert_eq!(value["eventId"].as_str().unwrap().len(), 26);
+        assert!(value["occurredAt"].as_str().unwrap().ends_with('Z'));
+    }
+}
@@ -0,0 +1,318 @@
+/*!
+Request and response bodies of the REST APIs.
+
+Handlers read and answer these types rather than the persistence types so
+the public API only changes when one of them does. Fields added to the
+persistence types, such as the schema version of stored users, stay out of
+responses until they are added here, and requests can't set them.
+
+Field names are camelCase like the rest of the API.
+*/
+pub mod events;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use user_persist::{
+    audit::{AuditFilter, AuditOperation},
+    jobs::{Job, JobState},
+    types::{
+        validate_email_field, Address, Email, EmailValidation, Gender, Phone, SearchSort, User,
+        UserKey, UserSearch, ValidateRequest,
+    },
+    MaskedDebug,
+};
+use validator::{Validate, ValidationErrors};
+
+/// Body of a request creating a user. The key is assigned by the service.
+#[derive(Clone, MaskedDebug, Deserialize, Serialize, Validate, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct CreateUserRequest {
+    #[masked]
+    pub name: String,
+    #[validate(range(min = 100))]
+    pub age: u32,
+    #[masked]
+    pub email: Email,
+    pub gender: Gender,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<Phone>,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+}
+
+impl ValidateRequest for CreateUserRequest {
+    fn validate_emails(&self, mode: EmailValidation) -> Result<(), ValidationErrors> {
+        validate_email_field("email", Some(&self.email), mode)
+    }
+}
+
+impl From<CreateUserRequest> for User {
+    fn from(request: CreateUserRequest) -> Self {
+        User {
+            id: None,
+            name: request.name,
+            age: request.age,
+            email: request.email,
+            gender: request.gender,
+            phone: request.phone,
+            address: request.address,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+}
+
+/// User returned to clients.
+#[derive(Clone, MaskedDebug, Deserialize, Serialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UserResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<UserKey>,
+    #[masked]
+    pub name: String,
+    pub age: u32,
+    #[masked]
+    pub email: Email,
+    pub gender: Gender,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<Phone>,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+    /// Time the user was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Time the user was last updated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        UserResponse {
+            id: user.id,
+            name: user.name,
+            age: user.age,
+            email: user.email,
+            gender: user.gender,
+            phone: user.phone,
+            address: user.address,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
+/// Body of a user search. Unknown fields are rejected so a misspelled
+/// criterion doesn't silently widen the search.
+#[derive(Clone, Default, MaskedDebug, Deserialize, Serialize, Validate)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SearchRequest {
+    #[masked]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<Email>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gender: Option<Gender>,
+    #[masked]
+    #[validate(length(max = 100))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Only users created after this RFC 3339 time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_after: Option<DateTime<Utc>>,
+    /// Only users last updated before this RFC 3339 time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_before: Option<DateTime<Utc>>,
+    /// Order of the results, by name unless `id`, `created` or `updated`
+    /// is given.
+    #[serde(default, skip_serializing_if = "SearchSort::is_default")]
+    pub sort: SearchSort,
+}
+
+impl ValidateRequest for SearchRequest {
+    fn validate_emails(&self, mode: EmailValidation) -> Result<(), ValidationErrors> {
+        validate_email_field("email", self.email.as_ref(), mode)
+    }
+}
+
+impl From<SearchRequest> for UserSearch {
+    fn from(request: SearchRequest) -> Self {
+        UserSearch {
+            email: request.email,
+            gender: request.gender,
+            name: request.name,
+            created_after: request.created_after,
+            updated_before: request.updated_before,
+            sort: request.sort,
+        }
+    }
+}
+
+/// Body of an audit log search. Every criterion left out matches all
+/// records.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Validate)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AuditSearchRequest {
+    /// Subject of the token which authorized the operations.
+    #[validate(length(max = 100))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<AuditOperation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_user: Option<UserKey>,
+    /// Earliest time in milliseconds since the epoch, inclusive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_ms: Option<i64>,
+    /// Latest time in milliseconds since the epoch, exclusive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_ms: Option<i64>,
+}
+
+impl ValidateRequest for AuditSearchRequest {}
+
+impl From<AuditSearchRequest> for AuditFilter {
+    fn from(request: AuditSearchRequest) -> Self {
+        AuditFilter {
+            actor: request.actor,
+            operation: request.operation,
+            target_user: request.target_user,
+            from_ms: request.from_ms,
+            to_ms: request.to_ms,
+        }
+    }
+}
+
+/// Public key verifying response signatures.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SigningKeyResponse {
+    pub algorithm: String,
+    /// Base64 encoded public key.
+    pub public_key: String,
+}
+
+/// Streamed download returned to clients.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadResponse {
+    pub id: String,
+    /// Subject of the token authorizing the download.
+    pub actor: String,
+    pub format: String,
+    pub started_at_ms: i64,
+    /// Start time formatted in the time zone of the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+    pub bytes_sent: u64,
+}
+
+/// Background job returned to clients. The payload is left out since it
+/// can hold a whole imported file.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct JobResponse {
+    pub id: String,
+    pub kind: String,
+    pub state: JobState,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub created_at_ms: i64,
+    /// Creation time formatted in the time zone of the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+}
+
+impl From<Job> for JobResponse {
+    fn from(job: Job) -> Self {
+        JobResponse {
+            id: job.id,
+            kind: job.kind,
+            state: job.state,
+            attempts: job.attempts,
+            max_attempts: job.max_attempts,
+            created_at_ms: job.created_at_ms,
+            created_at: None,
+            last_error: job.last_error,
+            result: job.result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CreateUserRequest, SearchRequest, UserResponse};
+    use serde_json::{from_value, json, to_value};
+    use user_persist::{
+        types::{Email, Gender, User, UserSearch},
+        Validate,
+    };
+
+    fn create_request() -> CreateUserRequest {
+        CreateUserRequest {
+            name: "Test User".to_owned(),
+            age: 100,
+            email: Email("test@test.com".to_owned()),
+            gender: Gender::Male,
+            phone: None,
+            address: None,
+        }
+    }
+
+    #[test]
+    fn test_create_user_request() {
+        let request = from_value::<CreateUserRequest>(json!({
+          "id": "61c0d1954c6b974ca7000000",
+          "name": "Test User",
+          "age": 100,
+          "email": "test@test.com",
+          "gender": "Male",
+        }))
+        .unwrap();
+        assert_eq!(request, create_request());
+        assert!(request.validate().is_ok());
+        assert_eq!(User::from(request).id, None);
+
+        let invalid = CreateUserRequest {
+            age: 99,
+            ..create_request()
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_user_response() {
+        let user = User {
+            id: Some("61c0d1954c6b974ca7000000".parse().unwrap()),
+            ..User::from(create_request())
+        };
+        assert_eq!(
+            to_value(UserResponse::from(user)).unwrap(),
+            json!({
+              "id": "61c0d1954c6b974ca7000000",
+              "name": "Test User",
+              "age": 100,
+              "email": "test@test.com",
+              "gender": "Male",
+            })
+        );
+    }
+
+    #[test]
+    fn test_search_request() {
+        assert!(from_value::<SearchRequest>(json!({"phone": "555"})).is_err());
+        let request = from_value::<SearchRequest>(json!({"gender": "Female"})).unwrap();
+        assert!(!UserSearch::from(request).is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+/*!
+Allocation counting.
+
+The harness installs [`CountingAllocator`] as its global allocator so a
+run can report how many allocations each request cost. Services run in
+process so the counts cover the server and the client driving it. The
+client side is the same for every framework so differences between
+frameworks come from the servers.
+*/
+use serde::Serialize;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// System allocator counting allocations and allocated bytes.
+pub struct CountingAllocator;
+
+// SAFETY: delegates to the system allocator and only adds counting.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn record(size: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+}
+
+/// Allocation counters at a point in time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Allocations {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl Allocations {
+    /// Current process wide counters.
+    pub fn snapshot() -> Self {
+        Self {
+            count: ALLOCATIONS.load(Ordering::Relaxed),
+            bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Allocations made since an `earlier` snapshot.
+    pub fn since(self, earlier: Self) -> Self {
+        Self {
+            count: self.count.saturating_sub(earlier.count),
+            bytes: self.bytes.saturating_sub(earlier.bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Allocations;
+
+    #[test]
+    fn test_counts_allocations() {
+        let before = Allocations::snapshot();
+        let data = std::hint::black_box(vec![0u8; 4096]);
+        let after = Allocations::snapshot().since(before);
+        drop(data);
+
+        assert!(after.count >= 1);
+        assert!(after.bytes >= 4096);
+    }
+}
@@ -0,0 +1,27 @@
+/*!
+Runs the goose load test scenarios against a service.
+
+Goose options such as `--host`, `--test-plan` and `--report-file` are
+given on the command line. `LOADTEST_RESULTS` names a file receiving the
+metrics as JSON and `LOADTEST_MAX_FAILURE_PERCENT` fails the run when
+more requests fail.
+*/
+use bench_harness::loadtest::{check_failures, register};
+use goose::prelude::*;
+use std::{env, error::Error};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let metrics = register(GooseAttack::initialize()?)
+        .set_default(GooseDefault::ReportFile, "loadtest-report.html")?
+        .execute()
+        .await?;
+
+    if let Ok(path) = env::var("LOADTEST_RESULTS") {
+        std::fs::write(path, serde_json::to_vec_pretty(&metrics)?)?;
+    }
+    if let Ok(max_percent) = env::var("LOADTEST_MAX_FAILURE_PERCENT") {
+        check_failures(&metrics, max_percent.parse()?)?;
+    }
+    Ok(())
+}
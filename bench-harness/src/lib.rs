@@ -0,0 +1,34 @@
+/*!
+Benchmark harness comparing the framework implementations end to end.
+
+Each framework is started in process against the in-memory backend and
+driven with the same workload. The results are summarized as latency
+percentiles per operation, overall throughput and allocations per request.
+
+The [`loadtest`] scenarios drive a separately running service instead.
+*/
+pub mod alloc;
+pub mod loadtest;
+pub mod report;
+pub mod runner;
+pub mod services;
+pub mod workload;
+
+use report::Report;
+use runner::Workload;
+use services::Framework;
+use std::io;
+
+#[global_allocator]
+static GLOBAL: alloc::CountingAllocator = alloc::CountingAllocator;
+
+/// Benchmark each framework in turn.
+pub async fn benchmark(frameworks: &[Framework], workload: &Workload) -> io::Result<Report> {
+    let mut report = Report::default();
+    for framework in frameworks {
+        report
+            .frameworks
+            .push(runner::run(*framework, workload).await?);
+    }
+    Ok(report)
+}
@@ -0,0 +1,309 @@
+/*!
+Load test scenarios for [goose](https://docs.rs/goose/latest/goose/).
+
+The scenarios target a running service given by goose's `--host` option
+so any of the framework implementations can be tested:
+
+- `Login` authenticates repeatedly against the admin counts endpoint.
+- `CrudMix` creates users then reads and searches for them.
+- `HeavyDownload` streams the full user download.
+
+Authorization headers are read from `LOADTEST_ADMIN_JWT` and
+`LOADTEST_USER_JWT`. Goose's `--test-plan` ramps users over time and
+`--throttle-requests` caps the request rate.
+*/
+use goose::prelude::*;
+use reqwest::header::AUTHORIZATION;
+use serde_json::{json, Value};
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+use thiserror::Error;
+
+/// Environment variable holding the admin authorization header.
+pub const ADMIN_JWT_ENV: &str = "LOADTEST_ADMIN_JWT";
+/// Environment variable holding the user authorization header.
+pub const USER_JWT_ENV: &str = "LOADTEST_USER_JWT";
+
+const USER_PATH: &str = "/api/v1/user";
+
+/// Authorization headers sent by the scenarios.
+#[derive(Debug, Clone, Default)]
+pub struct Tokens {
+    pub admin: Option<String>,
+    pub user: Option<String>,
+}
+
+impl Tokens {
+    /// Tokens from the environment. A bare token gets the `Bearer`
+    /// scheme.
+    pub fn from_env() -> Self {
+        let read = |name| {
+            env::var(name).ok().map(|token| {
+                if token.starts_with("Bearer ") {
+                    token
+                } else {
+                    format!("Bearer {token}")
+                }
+            })
+        };
+        Self {
+            admin: read(ADMIN_JWT_ENV),
+            user: read(USER_JWT_ENV),
+        }
+    }
+}
+
+static TOKENS: OnceLock<Tokens> = OnceLock::new();
+
+fn tokens() -> &'static Tokens {
+    TOKENS.get_or_init(Tokens::from_env)
+}
+
+/// Users created by a goose user.
+#[derive(Debug, Default)]
+struct Created {
+    ids: Vec<String>,
+    next: usize,
+}
+
+/// Unique suffix for created users.
+static CREATED: AtomicU64 = AtomicU64::new(0);
+
+async fn send(
+    user: &mut GooseUser,
+    method: GooseMethod,
+    path: &str,
+    name: &str,
+    auth: Option<&str>,
+    body: Option<Value>,
+) -> Result<GooseResponse, Box<TransactionError>> {
+    let mut builder = user.get_request_builder(&method, path)?;
+    if let Some(auth) = auth {
+        builder = builder.header(AUTHORIZATION, auth);
+    }
+    if let Some(body) = body {
+        builder = builder.json(&body);
+    }
+    let request = GooseRequest::builder()
+        .method(method)
+        .path(path)
+        .name(name)
+        .set_request_builder(builder)
+        .build();
+    user.request(request).await
+}
+
+/// Authenticate against an admin endpoint.
+async fn login(user: &mut GooseUser) -> TransactionResult {
+    let path = format!("{USER_PATH}/counts");
+    send(
+        user,
+        GooseMethod::Get,
+        &path,
+        "login",
+        tokens().admin.as_deref(),
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+async fn create_user(user: &mut GooseUser) -> TransactionResult {
+    let n = CREATED.fetch_add(1, Ordering::Relaxed);
+    let body = json!({
+      "name": format!("Load Test {n}"),
+      "age": 100 + n % 20,
+      "email": format!("loadtest{n}@test.com"),
+      "gender": if n % 2 == 0 { "Male" } else { "Female" },
+    });
+    let goose = send(
+        user,
+        GooseMethod::Post,
+        USER_PATH,
+        "create",
+        tokens().user.as_deref(),
+        Some(body),
+    )
+    .await?;
+
+    let id = match goose.response {
+        Ok(response) => response
+            .json::<Value>()
+            .await
+            .ok()
+            .and_then(|saved| saved["id"].as_str().map(str::to_owned)),
+        Err(_) => None,
+    };
+    if let Some(id) = id {
+        match user.get_session_data_mut::<Created>() {
+            Some(created) => created.ids.push(id),
+            None => user.set_session_data(Created {
+                ids: vec![id],
+                next: 0,
+            }),
+        }
+    }
+    Ok(())
+}
+
+async fn read_user(user: &mut GooseUser) -> TransactionResult {
+    let id = match user.get_session_data_mut::<Created>() {
+        Some(created) if !created.ids.is_empty() => {
+            created.next = (created.next + 1) % created.ids.len();
+            created.ids[created.next].clone()
+        }
+        // Nothing created yet.
+        _ => return Ok(()),
+    };
+    let path = format!("{USER_PATH}/{id}");
+    send(
+        user,
+        GooseMethod::Get,
+        &path,
+        "read",
+        tokens().admin.as_deref(),
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+async fn search_users(user: &mut GooseUser) -> TransactionResult {
+    let path = format!("{USER_PATH}/search");
+    send(
+        user,
+        GooseMethod::Post,
+        &path,
+        "search",
+        tokens().admin.as_deref(),
+        Some(json!({"gender": "Female"})),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn download_users(user: &mut GooseUser) -> TransactionResult {
+    let path = format!("{USER_PATH}/download");
+    let goose = send(
+        user,
+        GooseMethod::Get,
+        &path,
+        "download",
+        tokens().admin.as_deref(),
+        None,
+    )
+    .await?;
+    // Consume the whole body so the transaction covers the transfer.
+    if let Ok(response) = goose.response {
+        let _ = response.bytes().await;
+    }
+    Ok(())
+}
+
+/// Repeated authentication.
+pub fn login_scenario() -> Scenario {
+    scenario!("Login")
+        .set_weight(2)
+        .expect("non zero weight")
+        .register_transaction(transaction!(login))
+}
+
+/// Create users then read and search for them.
+pub fn crud_scenario() -> Scenario {
+    scenario!("CrudMix")
+        .set_weight(7)
+        .expect("non zero weight")
+        .register_transaction(transaction!(login).set_on_start())
+        .register_transaction(
+            transaction!(create_user)
+                .set_weight(2)
+                .expect("non zero weight"),
+        )
+        .register_transaction(
+            transaction!(read_user)
+                .set_weight(6)
+                .expect("non zero weight"),
+        )
+        .register_transaction(
+            transaction!(search_users)
+                .set_weight(3)
+                .expect("non zero weight"),
+        )
+}
+
+/// Stream the full user download.
+pub fn download_scenario() -> Scenario {
+    scenario!("HeavyDownload")
+        .set_weight(1)
+        .expect("non zero weight")
+        .register_transaction(transaction!(login).set_on_start())
+        .register_transaction(transaction!(download_users))
+}
+
+/// Register every scenario with an attack.
+pub fn register(attack: GooseAttack) -> GooseAttack {
+    attack
+        .register_scenario(login_scenario())
+        .register_scenario(crud_scenario())
+        .register_scenario(download_scenario())
+}
+
+/// Load test exceeding its failure budget.
+#[derive(Debug, Error, PartialEq)]
+#[error("{failed} of {total} requests failed, more than the allowed {max_percent}%")]
+pub struct GateError {
+    pub failed: usize,
+    pub total: usize,
+    pub max_percent: f64,
+}
+
+/// Check the failed requests of a completed attack against a budget as a
+/// percentage of all requests.
+pub fn check_failures(metrics: &GooseMetrics, max_percent: f64) -> Result<(), GateError> {
+    let (total, failed) = metrics
+        .requests
+        .values()
+        .fold((0, 0), |(total, failed), request| {
+            (
+                total + request.success_count + request.fail_count,
+                failed + request.fail_count,
+            )
+        });
+    failure_budget(total, failed, max_percent)
+}
+
+fn failure_budget(total: usize, failed: usize, max_percent: f64) -> Result<(), GateError> {
+    if total > 0 && failed as f64 * 100.0 / total as f64 > max_percent {
+        Err(GateError {
+            failed,
+            total,
+            max_percent,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{failure_budget, GateError};
+
+    #[test]
+    fn test_failure_budget() {
+        assert_eq!(failure_budget(0, 0, 0.0), Ok(()));
+        assert_eq!(failure_budget(100, 1, 1.0), Ok(()));
+        assert_eq!(
+            failure_budget(100, 2, 1.0),
+            Err(GateError {
+                failed: 2,
+                total: 100,
+                max_percent: 1.0
+            })
+        );
+    }
+}
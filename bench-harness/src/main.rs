@@ -0,0 +1,63 @@
+use bench_harness::{
+    benchmark, report::Format, runner::Workload, services::Framework, workload::Mix,
+};
+use clap::Parser;
+use std::{error::Error, path::PathBuf};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct HarnessArgs {
+    #[clap(
+        long,
+        value_enum,
+        use_value_delimiter = true,
+        default_value = "axum,actix,rocket,warp"
+    )]
+    #[clap(help = "Comma separated frameworks to benchmark")]
+    frameworks: Vec<Framework>,
+    #[clap(long, default_value = "10000")]
+    #[clap(help = "Requests sent to each framework")]
+    requests: usize,
+    #[clap(long, default_value = "32")]
+    #[clap(help = "Requests in flight at once")]
+    concurrency: usize,
+    #[clap(long, default_value = "100")]
+    #[clap(help = "Users stored before each run")]
+    users: usize,
+    #[clap(long, default_value = "get=60,search=20,save=15,download=5")]
+    #[clap(help = "Operation weights as OPERATION=WEIGHT pairs")]
+    mix: Mix,
+    #[clap(long, value_enum, default_value = "markdown")]
+    #[clap(help = "Report format")]
+    format: Format,
+    #[clap(long)]
+    #[clap(help = "Write the report to a file instead of stdout")]
+    output: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_target(true)
+        .init();
+
+    let args = HarnessArgs::parse();
+    let workload = Workload {
+        requests: args.requests,
+        concurrency: args.concurrency,
+        users: args.users,
+        mix: args.mix,
+    };
+
+    let report = benchmark(&args.frameworks, &workload)
+        .await?
+        .render(args.format);
+
+    match args.output {
+        Some(path) => std::fs::write(path, report)?,
+        None => println!("{report}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,242 @@
+/*!
+Benchmark results and their JSON and markdown reports.
+*/
+use crate::{alloc::Allocations, services::Framework, workload::Operation};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+/// Report output format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Json,
+    Markdown,
+}
+
+/// Outcome of a single request.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub operation: Operation,
+    pub latency: Duration,
+    pub success: bool,
+}
+
+/// Latency statistics for one operation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OperationStats {
+    pub operation: Operation,
+    pub requests: usize,
+    pub errors: usize,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+/// Results for one framework.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FrameworkReport {
+    pub framework: Framework,
+    pub requests: usize,
+    pub errors: usize,
+    pub duration_secs: f64,
+    pub throughput_rps: f64,
+    pub allocations_per_request: f64,
+    pub allocated_bytes_per_request: f64,
+    pub operations: Vec<OperationStats>,
+}
+
+/// Results for every framework benchmarked.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Report {
+    pub frameworks: Vec<FrameworkReport>,
+}
+
+/// Nearest rank percentile of sorted latencies in milliseconds.
+fn percentile(sorted: &[Duration], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1].as_secs_f64() * 1000.0
+}
+
+impl FrameworkReport {
+    /// Summarize the samples of a run that took `elapsed` and made
+    /// `allocations`.
+    pub fn new(
+        framework: Framework,
+        samples: &[Sample],
+        elapsed: Duration,
+        allocations: Allocations,
+    ) -> Self {
+        let mut by_operation = BTreeMap::<Operation, Vec<&Sample>>::new();
+        for sample in samples {
+            by_operation
+                .entry(sample.operation)
+                .or_default()
+                .push(sample);
+        }
+
+        let operations = by_operation
+            .into_iter()
+            .map(|(operation, samples)| {
+                let mut latencies = samples.iter().map(|s| s.latency).collect::<Vec<_>>();
+                latencies.sort();
+                OperationStats {
+                    operation,
+                    requests: samples.len(),
+                    errors: samples.iter().filter(|s| !s.success).count(),
+                    p50_ms: percentile(&latencies, 50.0),
+                    p95_ms: percentile(&latencies, 95.0),
+                    p99_ms: percentile(&latencies, 99.0),
+                }
+            })
+            .collect();
+
+        let duration_secs = elapsed.as_secs_f64();
+        let per_request = |total: u64| {
+            if samples.is_empty() {
+                0.0
+            } else {
+                total as f64 / samples.len() as f64
+            }
+        };
+        Self {
+            framework,
+            requests: samples.len(),
+            errors: samples.iter().filter(|s| !s.success).count(),
+            duration_secs,
+            throughput_rps: if duration_secs > 0.0 {
+                samples.len() as f64 / duration_secs
+            } else {
+                0.0
+            },
+            allocations_per_request: per_request(allocations.count),
+            allocated_bytes_per_request: per_request(allocations.bytes),
+            operations,
+        }
+    }
+}
+
+impl Report {
+    /// Render the report in the given format.
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Json => serde_json::to_string_pretty(self).unwrap_or_default(),
+            Format::Markdown => self.markdown(),
+        }
+    }
+
+    fn markdown(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "|Framework|Requests|Errors|Throughput (req/s)|Allocations/req|Allocated KiB/req|\n",
+        );
+        out.push_str(
+            "|---------|--------|------|------------------|---------------|-----------------|\n",
+        );
+        for f in &self.frameworks {
+            let _ = writeln!(
+                out,
+                "|{}|{}|{}|{:.1}|{:.1}|{:.1}|",
+                f.framework,
+                f.requests,
+                f.errors,
+                f.throughput_rps,
+                f.allocations_per_request,
+                f.allocated_bytes_per_request / 1024.0
+            );
+        }
+
+        out.push_str("\n|Framework|Operation|Requests|Errors|p50 (ms)|p95 (ms)|p99 (ms)|\n");
+        out.push_str("|---------|---------|--------|------|--------|--------|--------|\n");
+        for f in &self.frameworks {
+            for op in &f.operations {
+                let _ = writeln!(
+                    out,
+                    "|{}|{}|{}|{}|{:.2}|{:.2}|{:.2}|",
+                    f.framework,
+                    op.operation,
+                    op.requests,
+                    op.errors,
+                    op.p50_ms,
+                    op.p95_ms,
+                    op.p99_ms
+                );
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{percentile, Format, FrameworkReport, Report, Sample};
+    use crate::{alloc::Allocations, services::Framework, workload::Operation};
+    use std::time::Duration;
+
+    fn sample(operation: Operation, millis: u64, success: bool) -> Sample {
+        Sample {
+            operation,
+            latency: Duration::from_millis(millis),
+            success,
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&latencies, 50.0), 50.0);
+        assert_eq!(percentile(&latencies, 95.0), 95.0);
+        assert_eq!(percentile(&latencies, 99.0), 99.0);
+        assert_eq!(percentile(&latencies[..1], 99.0), 1.0);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+    }
+
+    #[test]
+    fn test_framework_report() {
+        let samples = [
+            sample(Operation::Get, 1, true),
+            sample(Operation::Get, 3, true),
+            sample(Operation::Save, 2, false),
+        ];
+        let allocations = Allocations {
+            count: 30,
+            bytes: 3072,
+        };
+        let report = FrameworkReport::new(
+            Framework::Axum,
+            &samples,
+            Duration::from_secs(2),
+            allocations,
+        );
+
+        assert_eq!(report.requests, 3);
+        assert_eq!(report.errors, 1);
+        assert_eq!(report.throughput_rps, 1.5);
+        assert_eq!(report.allocations_per_request, 10.0);
+        assert_eq!(report.allocated_bytes_per_request, 1024.0);
+        assert_eq!(report.operations.len(), 2);
+        assert_eq!(report.operations[0].operation, Operation::Get);
+        assert_eq!(report.operations[0].p50_ms, 1.0);
+        assert_eq!(report.operations[1].errors, 1);
+    }
+
+    #[test]
+    fn test_markdown() {
+        let report = Report {
+            frameworks: vec![FrameworkReport::new(
+                Framework::Warp,
+                &[sample(Operation::Search, 4, true)],
+                Duration::from_secs(1),
+                Allocations {
+                    count: 5,
+                    bytes: 2048,
+                },
+            )],
+        };
+        let markdown = report.render(Format::Markdown);
+        assert!(markdown.contains("|warp|1|0|1.0|5.0|2.0|"));
+        assert!(markdown.contains("|warp|search|1|0|4.00|4.00|4.00|"));
+    }
+}
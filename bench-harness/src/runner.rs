@@ -0,0 +1,182 @@
+/*!
+Drives a workload against a running service.
+*/
+use crate::{
+    alloc::Allocations,
+    report::{FrameworkReport, Sample},
+    services::{self, Framework, Service},
+    workload::{Mix, Operation},
+};
+use futures::{stream, StreamExt};
+use hyper::{
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request,
+};
+use serde_json::json;
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{event, Level};
+use user_persist::{
+    memory::MemoryPersistence,
+    persistence::UserPersistence,
+    types::{Email, Gender, User, UserKey},
+};
+
+/// Tracing target for the harness.
+pub const HARNESS_TARGET: &str = "bench-harness";
+
+/// Shape of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    /// Requests sent to each framework.
+    pub requests: usize,
+    /// Requests in flight at once.
+    pub concurrency: usize,
+    /// Users stored before the run starts.
+    pub users: usize,
+    pub mix: Mix,
+}
+
+fn test_user(n: usize) -> User {
+    User {
+        id: None,
+        name: format!("Bench User {n}"),
+        age: 100 + (n % 20) as u32,
+        email: Email(format!("bench{n}@test.com")),
+        gender: if n % 2 == 0 {
+            Gender::Male
+        } else {
+            Gender::Female
+        },
+        phone: None,
+        address: None,
+        created_at: None,
+        updated_at: None,
+    }
+}
+
+/// Store `count` users returning the saved users.
+async fn seed(persist: &MemoryPersistence, count: usize) -> io::Result<Vec<User>> {
+    let mut users = Vec::with_capacity(count);
+    for n in 0..count {
+        let user = persist
+            .save_user(&test_user(n))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        users.push(user);
+    }
+    Ok(users)
+}
+
+fn request(service: &Service, operation: Operation, n: usize, ids: &[UserKey]) -> Request<Body> {
+    let base = format!("http://{}/api/v1/user", service.addr);
+    let (method, uri, body) = match operation {
+        Operation::Get => (
+            Method::GET,
+            format!("{base}/{}", ids[n % ids.len()]),
+            Body::empty(),
+        ),
+        Operation::Search => (
+            Method::POST,
+            format!("{base}/search"),
+            Body::from(json!({"gender": "Female"}).to_string()),
+        ),
+        Operation::Save => (
+            Method::POST,
+            base,
+            Body::from(serde_json::to_string(&test_user(n)).unwrap_or_default()),
+        ),
+        Operation::Download => (Method::GET, format!("{base}/download"), Body::empty()),
+    };
+
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(auth) = service.auth(operation) {
+        builder = builder.header(AUTHORIZATION, auth);
+    }
+    builder.body(body).expect("valid request")
+}
+
+/// Send one request and time it until the whole body is received.
+async fn send(client: &Client<HttpConnector>, operation: Operation, req: Request<Body>) -> Sample {
+    let start = Instant::now();
+    let success = match client.request(req).await {
+        Ok(response) => {
+            let status = response.status();
+            hyper::body::to_bytes(response.into_body()).await.is_ok() && status.is_success()
+        }
+        Err(e) => {
+            event!(target: HARNESS_TARGET, Level::WARN, "{operation} failed: {e}");
+            false
+        }
+    };
+    Sample {
+        operation,
+        latency: start.elapsed(),
+        success,
+    }
+}
+
+/// Drive the workload against a running service.
+pub async fn drive(service: &Service, workload: &Workload, ids: &[UserKey]) -> FrameworkReport {
+    let client = Client::new();
+    let mix = workload.mix.retain(|operation| service.supports(operation));
+    let schedule = mix
+        .map(|mix| mix.schedule(workload.requests))
+        .unwrap_or_default();
+
+    let allocations = Allocations::snapshot();
+    let start = Instant::now();
+    let samples = stream::iter(schedule.into_iter().enumerate())
+        .map(|(n, operation)| send(&client, operation, request(service, operation, n, ids)))
+        .buffer_unordered(workload.concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    let elapsed = start.elapsed();
+    FrameworkReport::new(
+        service.framework,
+        &samples,
+        elapsed,
+        Allocations::snapshot().since(allocations),
+    )
+}
+
+/// Start a framework against a freshly seeded in-memory backend and
+/// drive the workload against it.
+pub async fn run(framework: Framework, workload: &Workload) -> io::Result<FrameworkReport> {
+    let persist = Arc::new(MemoryPersistence::default());
+    let users = seed(&persist, workload.users.max(1)).await?;
+    let ids = users
+        .iter()
+        .filter_map(|u| u.id.clone())
+        .collect::<Vec<_>>();
+
+    // The materialized export served by frameworks that support downloads.
+    let export = std::env::temp_dir().join(format!(
+        "bench-harness-{framework}-{}.json",
+        std::process::id()
+    ));
+    std::fs::write(&export, serde_json::to_vec(&users)?)?;
+
+    event!(
+      target: HARNESS_TARGET,
+      Level::INFO,
+      "Benchmarking {framework} with {} requests",
+      workload.requests
+    );
+
+    let service = services::start(framework, persist, export.clone()).await?;
+    // Let the service settle before timing.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let report = drive(&service, workload, &ids).await;
+
+    let _ = std::fs::remove_file(export);
+    Ok(report)
+}
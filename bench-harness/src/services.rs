@@ -0,0 +1,247 @@
+/*!
+Starting the framework implementations in process.
+
+Each service is bound to an ephemeral port on the loopback interface
+without TLS and backed by its own [`MemoryPersistence`].
+*/
+use crate::workload::Operation;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    fmt, io,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+use user_persist::{memory::MemoryPersistence, persistence::UserPersistence};
+
+/// Framework implementation being benchmarked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Framework {
+    Axum,
+    Actix,
+    Rocket,
+    Warp,
+}
+
+impl fmt::Display for Framework {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Framework::Axum => "axum",
+                Framework::Actix => "actix",
+                Framework::Rocket => "rocket",
+                Framework::Warp => "warp",
+            }
+        )
+    }
+}
+
+/// A running service.
+#[derive(Debug, Clone)]
+pub struct Service {
+    pub framework: Framework,
+    pub addr: SocketAddr,
+    /// Authorization header for admin operations.
+    pub admin_auth: Option<String>,
+    /// Authorization header for user operations.
+    pub user_auth: Option<String>,
+}
+
+impl Service {
+    /// Whether the service implements an operation against the in-memory
+    /// backend.
+    pub fn supports(&self, operation: Operation) -> bool {
+        match operation {
+            // Only axum can download without mongodb, from a materialized
+            // export.
+            Operation::Download => self.framework == Framework::Axum,
+            _ => true,
+        }
+    }
+
+    /// Authorization header for an operation.
+    pub fn auth(&self, operation: Operation) -> Option<&str> {
+        match operation {
+            Operation::Save => self.user_auth.as_deref(),
+            _ => self.admin_auth.as_deref(),
+        }
+    }
+}
+
+fn loopback() -> io::Result<TcpListener> {
+    TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+}
+
+/// Start a framework. `export` is a materialized user export for the
+/// frameworks that serve downloads from one.
+pub async fn start(
+    framework: Framework,
+    persist: Arc<MemoryPersistence>,
+    export: PathBuf,
+) -> io::Result<Service> {
+    let persist: Arc<dyn UserPersistence> = persist;
+    let service = match framework {
+        Framework::Axum => start_axum(persist, export)?,
+        Framework::Actix => start_actix(persist)?,
+        Framework::Rocket => start_rocket(persist).await?,
+        Framework::Warp => start_warp(persist),
+    };
+    wait_ready(service.addr).await?;
+    Ok(service)
+}
+
+/// Wait for a service to accept connections.
+async fn wait_ready(addr: SocketAddr) -> io::Result<()> {
+    let mut attempts = 0;
+    loop {
+        match tokio::net::TcpStream::connect(addr).await {
+            Ok(_) => return Ok(()),
+            Err(e) if attempts == 50 => return Err(e),
+            Err(_) => {
+                attempts += 1;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+fn start_axum(persist: Arc<dyn UserPersistence>, export: PathBuf) -> io::Result<Service> {
+    use rust_axum::{
+        arguments::{test_jwt, AppConfig, AppState, Settings},
+        build_app,
+        types::jwt::Role,
+    };
+
+    let mut settings = Settings::default();
+    settings.export.file = Some(export);
+    let config = AppConfig::test(b"TEST_SECRET")
+        .with_settings(settings)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let admin_auth = format!("Bearer {}", test_jwt(&config, Role::Admin));
+    let user_auth = format!("Bearer {}", test_jwt(&config, Role::User));
+
+    let listener = loopback()?;
+    let addr = listener.local_addr()?;
+    let server = axum::Server::from_tcp(listener)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        .serve(build_app(AppState::new(persist, config)).into_make_service());
+    tokio::spawn(server);
+
+    Ok(Service {
+        framework: Framework::Axum,
+        addr,
+        admin_auth: Some(admin_auth),
+        user_auth: Some(user_auth),
+    })
+}
+
+fn start_actix(persist: Arc<dyn UserPersistence>) -> io::Result<Service> {
+    use actix_web::{web, App, HttpServer};
+    use rust_actix_web::{
+        handlers,
+        middleware::{create_test_jwt, CatchPanic, JwtAuth, RequestTimer},
+        types::Role,
+    };
+    use tracing_actix_web::TracingLogger;
+
+    let token = |role| {
+        create_test_jwt(role)
+            .map(|jwt| format!("Bearer {jwt}"))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+    };
+    let admin_auth = token(Role::Admin)?;
+    let user_auth = token(Role::User)?;
+
+    // Actix runs its own single threaded runtimes so the server gets a
+    // system on a dedicated thread.
+    let listener = loopback()?;
+    let addr = listener.local_addr()?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(persist.clone()))
+                    .wrap(CatchPanic)
+                    .wrap(JwtAuth::default())
+                    .wrap(TracingLogger::default())
+                    .wrap(RequestTimer::default())
+                    .service(
+                        web::scope("/api/v1/user")
+                            .service(handlers::count_users)
+                            .service(handlers::search_users)
+                            .service(handlers::get_user)
+                            .service(handlers::save_user)
+                            .service(handlers::update_user)
+                            .service(handlers::options),
+                    )
+            })
+            .listen(listener);
+
+            match server {
+                Ok(server) => {
+                    let _ = tx.send(Ok(()));
+                    let _ = server.run().await;
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                }
+            }
+        })
+    });
+    rx.recv()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+
+    Ok(Service {
+        framework: Framework::Actix,
+        addr,
+        admin_auth: Some(admin_auth),
+        user_auth: Some(user_auth),
+    })
+}
+
+async fn start_rocket(persist: Arc<dyn UserPersistence>) -> io::Result<Service> {
+    use rocket::config::{Config, LogLevel};
+    use rust_rocket::{build_rocket, test_jwt, types::Role};
+
+    // Rocket binds the listener itself so reserve a free port for it.
+    let addr = loopback()?.local_addr()?;
+    let config = Config {
+        address: addr.ip(),
+        port: addr.port(),
+        log_level: LogLevel::Off,
+        ..Config::default()
+    };
+    let rocket = build_rocket(persist, None)
+        .configure(config)
+        .ignite()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    tokio::spawn(rocket.launch());
+
+    Ok(Service {
+        framework: Framework::Rocket,
+        addr,
+        admin_auth: Some(test_jwt(Role::Admin)),
+        user_auth: Some(test_jwt(Role::User)),
+    })
+}
+
+fn start_warp(persist: Arc<dyn UserPersistence>) -> Service {
+    let (addr, server) =
+        warp::serve(rust_warp::filters::user(persist)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    Service {
+        framework: Framework::Warp,
+        addr,
+        admin_auth: None,
+        user_auth: None,
+    }
+}
@@ -0,0 +1,175 @@
+/*!
+Workload definitions.
+*/
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+/// Operation performed against a service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Get,
+    Search,
+    Save,
+    Download,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Operation::Get => "get",
+                Operation::Search => "search",
+                Operation::Save => "save",
+                Operation::Download => "download",
+            }
+        )
+    }
+}
+
+/// Invalid workload mix.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MixError {
+    #[error("mix entry `{0}` must be given as OPERATION=WEIGHT")]
+    Format(String),
+    #[error("unknown operation in mix entry `{0}`")]
+    Operation(String),
+    #[error("invalid weight in mix entry `{0}`")]
+    Weight(String),
+    #[error("mix needs at least one operation with a weight greater than zero")]
+    Empty,
+}
+
+/// Relative weights of the operations in a workload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mix(Vec<(Operation, u32)>);
+
+impl Default for Mix {
+    fn default() -> Self {
+        Self(vec![
+            (Operation::Get, 60),
+            (Operation::Search, 20),
+            (Operation::Save, 15),
+            (Operation::Download, 5),
+        ])
+    }
+}
+
+impl FromStr for Mix {
+    type Err = MixError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let weights = s
+            .split(',')
+            .map(|entry| {
+                let (operation, weight) = entry
+                    .split_once('=')
+                    .ok_or_else(|| MixError::Format(entry.to_owned()))?;
+                let operation = Operation::from_str(operation.trim(), true)
+                    .map_err(|_| MixError::Operation(entry.to_owned()))?;
+                let weight = weight
+                    .trim()
+                    .parse()
+                    .map_err(|_| MixError::Weight(entry.to_owned()))?;
+                Ok((operation, weight))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if weights.iter().all(|(_, weight)| *weight == 0) {
+            return Err(MixError::Empty);
+        }
+        Ok(Self(weights))
+    }
+}
+
+impl Mix {
+    /// Mix without the operations `supported` rejects.
+    pub fn retain(&self, supported: impl Fn(Operation) -> bool) -> Option<Self> {
+        let weights = self
+            .0
+            .iter()
+            .copied()
+            .filter(|(operation, weight)| *weight > 0 && supported(*operation))
+            .collect::<Vec<_>>();
+        (!weights.is_empty()).then(|| Self(weights))
+    }
+
+    /// A deterministic sequence of `requests` operations interleaved in
+    /// proportion to their weights.
+    pub fn schedule(&self, requests: usize) -> Vec<Operation> {
+        let total = self.0.iter().map(|(_, w)| u64::from(*w)).sum::<u64>();
+        // Smooth weighted round robin so operations are spread evenly.
+        let mut current = vec![0i64; self.0.len()];
+        (0..requests)
+            .map(|_| {
+                for (c, (_, weight)) in current.iter_mut().zip(&self.0) {
+                    *c += i64::from(*weight);
+                }
+                let (index, _) = current
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(i, c)| (**c, std::cmp::Reverse(*i)))
+                    .expect("mix is never empty");
+                current[index] -= total as i64;
+                self.0[index].0
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Mix, MixError, Operation};
+
+    #[test]
+    fn test_parse_mix() {
+        assert_eq!(
+            "get=3, save=1".parse(),
+            Ok(Mix(vec![(Operation::Get, 3), (Operation::Save, 1)]))
+        );
+        assert_eq!(
+            "get".parse::<Mix>(),
+            Err(MixError::Format("get".to_owned()))
+        );
+        assert_eq!(
+            "delete=1".parse::<Mix>(),
+            Err(MixError::Operation("delete=1".to_owned()))
+        );
+        assert_eq!("get=0".parse::<Mix>(), Err(MixError::Empty));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mix = "get=3,save=1".parse::<Mix>().unwrap();
+        let schedule = mix.schedule(8);
+        assert_eq!(
+            schedule,
+            [
+                Operation::Get,
+                Operation::Get,
+                Operation::Save,
+                Operation::Get,
+                Operation::Get,
+                Operation::Get,
+                Operation::Save,
+                Operation::Get
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retain() {
+        let mix = Mix::default()
+            .retain(|op| op != Operation::Download)
+            .unwrap();
+        assert!(mix
+            .schedule(100)
+            .iter()
+            .all(|op| *op != Operation::Download));
+        assert!(Mix::default().retain(|_| false).is_none());
+    }
+}
@@ -0,0 +1,34 @@
+use bench_harness::{benchmark, runner::Workload, services::Framework, workload::Operation};
+
+fn workload() -> Workload {
+    Workload {
+        requests: 40,
+        concurrency: 4,
+        users: 5,
+        mix: "get=2,search=1,save=1,download=1".parse().unwrap(),
+    }
+}
+
+#[tokio::test]
+async fn benchmark_axum_and_warp() {
+    let report = benchmark(&[Framework::Axum, Framework::Warp], &workload())
+        .await
+        .unwrap();
+
+    assert_eq!(report.frameworks.len(), 2);
+    for framework in &report.frameworks {
+        assert_eq!(framework.requests, 40);
+        assert_eq!(framework.errors, 0, "{framework:?}");
+        assert!(framework.allocations_per_request > 0.0);
+    }
+
+    // Warp can't download without mongodb so the operation isn't sent.
+    let has_download = |i: usize| {
+        report.frameworks[i]
+            .operations
+            .iter()
+            .any(|op| op.operation == Operation::Download)
+    };
+    assert!(has_download(0));
+    assert!(!has_download(1));
+}
@@ -0,0 +1,141 @@
+/*!
+Read timeouts of request bodies.
+
+An upload trickled a few bytes at a time holds its connection and handler
+for as long as the client likes. A body guarded with [`guard_body`] fails
+when no data arrives within the read timeout, or when it arrives slower
+than the minimum throughput once the read timeout has passed. The services
+answer such requests with `408` and the [`BODY_TIMEOUT_LABEL`] label.
+*/
+use futures::{stream, Stream, StreamExt};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::Instant;
+
+/// Label of the error answering a request whose body timed out.
+pub const BODY_TIMEOUT_LABEL: &str = "body.timeout";
+
+/// Limits on how slowly a request body may arrive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadTimeouts {
+    /// Longest wait for the next chunk of a body.
+    pub read_timeout: Duration,
+    /// Slowest average rate in bytes a second a body may arrive at. Not
+    /// checked when zero.
+    pub min_bytes_per_sec: u64,
+}
+
+impl Default for ReadTimeouts {
+    fn default() -> Self {
+        Self {
+            read_timeout: Duration::from_secs(10),
+            min_bytes_per_sec: 1024,
+        }
+    }
+}
+
+impl ReadTimeouts {
+    /// Whether `received` bytes in `elapsed` is below the minimum
+    /// throughput. Bodies get the read timeout to reach it.
+    fn too_slow(&self, elapsed: Duration, received: u64) -> bool {
+        elapsed > self.read_timeout
+            && (received as f64) < self.min_bytes_per_sec as f64 * elapsed.as_secs_f64()
+    }
+}
+
+/// Why reading a body timed out.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum BodyTimeout {
+    #[error("no body data received for {} ms", .0.as_millis())]
+    Idle(Duration),
+    #[error("body received slower than {0} bytes a second")]
+    TooSlow(u64),
+}
+
+/// Fail reading `body` with the error `on_timeout` makes when it arrives
+/// slower than `timeouts` allow. The body ends after the failure.
+pub fn guard_body<S, T, E, F>(
+    body: S,
+    timeouts: ReadTimeouts,
+    on_timeout: F,
+) -> impl Stream<Item = Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+    T: AsRef<[u8]>,
+    F: Fn(BodyTimeout) -> E + Clone,
+{
+    let start = Instant::now();
+    stream::unfold(Some((body, 0u64)), move |state| {
+        let on_timeout = on_timeout.clone();
+        async move {
+            let (mut body, received) = state?;
+            match tokio::time::timeout(timeouts.read_timeout, body.next()).await {
+                Err(_) => Some((
+                    Err(on_timeout(BodyTimeout::Idle(timeouts.read_timeout))),
+                    None,
+                )),
+                Ok(None) => None,
+                Ok(Some(Ok(chunk))) => {
+                    let received = received + chunk.as_ref().len() as u64;
+                    if timeouts.too_slow(start.elapsed(), received) {
+                        let error = on_timeout(BodyTimeout::TooSlow(timeouts.min_bytes_per_sec));
+                        Some((Err(error), None))
+                    } else {
+                        Some((Ok(chunk), Some((body, received))))
+                    }
+                }
+                Ok(Some(Err(e))) => Some((Err(e), Some((body, received)))),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{guard_body, BodyTimeout, ReadTimeouts};
+    use futures::{stream, StreamExt};
+    use std::{convert::identity, time::Duration};
+
+    fn timeouts() -> ReadTimeouts {
+        ReadTimeouts {
+            read_timeout: Duration::from_secs(1),
+            min_bytes_per_sec: 10,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_body() {
+        let body = stream::iter([Ok(vec![0; 4])]).chain(stream::pending());
+        let chunks = guard_body(body, timeouts(), identity)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            chunks,
+            [
+                Ok(vec![0; 4]),
+                Err(BodyTimeout::Idle(Duration::from_secs(1)))
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_body() {
+        // A chunk every half second stays within the read timeout but is
+        // too slow once the first second has passed.
+        let body = stream::iter(0..10).then(|_| async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            Ok(vec![0; 4])
+        });
+        let chunks = guard_body(Box::pin(body), timeouts(), identity)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2], Err(BodyTimeout::TooSlow(10)));
+
+        let body = stream::iter([Ok(vec![0; 40]), Ok(vec![0; 40])]);
+        let chunks = guard_body(body, timeouts(), identity)
+            .collect::<Vec<_>>()
+            .await;
+        assert!(chunks.iter().all(Result::is_ok));
+    }
+}
@@ -0,0 +1,229 @@
+/*!
+Startup self-check run with `--check`.
+
+Each binary records its checks in a [`CheckReport`] which is printed as
+JSON to stdout. The process exits with a non zero status when a check
+fails so it can gate a deployment.
+*/
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    process,
+};
+use thiserror::Error;
+use user_persist::{mongo_persistence::MongoPersistence, MongoArgs};
+
+/// Outcome of a single check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// The check doesn't apply to the configuration.
+    Skip,
+}
+
+/// A single named check.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Results of the startup self-check.
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    checks: Vec<Check>,
+}
+
+/// Invalid TLS certificate or key file.
+#[derive(Debug, Error)]
+pub enum TlsFileError {
+    #[error("failed to read `{0}`: {1}")]
+    Read(String, io::Error),
+    #[error("no certificates in `{0}`")]
+    NoCertificates(String),
+    #[error("no private key in `{0}`")]
+    NoPrivateKey(String),
+}
+
+/// Unusable JWT secret.
+#[derive(Debug, Error)]
+#[error("JWT secret is empty")]
+pub struct EmptySecretError;
+
+impl CheckReport {
+    /// Record a passing check.
+    pub fn pass(&mut self, name: &'static str, detail: Option<String>) {
+        self.push(name, CheckStatus::Pass, detail);
+    }
+
+    /// Record a failing check.
+    pub fn fail(&mut self, name: &'static str, detail: impl Display) {
+        self.push(name, CheckStatus::Fail, Some(detail.to_string()));
+    }
+
+    /// Record a check that doesn't apply.
+    pub fn skip(&mut self, name: &'static str, reason: &str) {
+        self.push(name, CheckStatus::Skip, Some(reason.to_owned()));
+    }
+
+    /// Record the outcome of `result` returning the success value.
+    pub fn record<T, E: Display>(&mut self, name: &'static str, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.pass(name, None);
+                Some(value)
+            }
+            Err(e) => {
+                self.fail(name, e);
+                None
+            }
+        }
+    }
+
+    fn push(&mut self, name: &'static str, status: CheckStatus, detail: Option<String>) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail,
+        });
+    }
+
+    /// Recorded checks.
+    pub fn checks(&self) -> &[Check] {
+        &self.checks
+    }
+
+    /// Whether no check failed.
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != CheckStatus::Fail)
+    }
+
+    /// Check the TLS certificate and key files parse when configured.
+    pub fn tls_files(&mut self, files: Option<(&Path, &Path)>) {
+        match files {
+            Some((cert_file, key_file)) => {
+                self.record("tls", check_tls_files(cert_file, key_file));
+            }
+            None => self.skip("tls", "no server certificate configured"),
+        }
+    }
+
+    /// Check a JWT secret is available when the service authorizes
+    /// requests.
+    pub fn jwt_secret(&mut self, secret: Option<&[u8]>) {
+        match secret {
+            Some([]) => self.fail("jwtSecret", EmptySecretError),
+            Some(_) => self.pass("jwtSecret", None),
+            None => self.skip("jwtSecret", "requests are not authorized"),
+        }
+    }
+
+    /// Connect to mongodb without changing it and detect pending
+    /// migrations.
+    pub async fn mongo(&mut self, args: MongoArgs) {
+        let persistence = match self.record("mongo", MongoPersistence::connect(args).await) {
+            Some(persistence) => persistence,
+            None => return self.skip("migrations", "no database connection"),
+        };
+        match persistence.pending_migrations().await {
+            Ok(pending) if pending.is_empty() => self.pass("migrations", None),
+            Ok(pending) => self.fail(
+                "migrations",
+                pending
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            Err(e) => self.fail("migrations", e),
+        }
+    }
+
+    /// Print the report as JSON and exit with a status reflecting the
+    /// outcome.
+    pub fn exit(self) -> ! {
+        let passed = self.passed();
+        println!("{}", json!({"passed": passed, "checks": self.checks}));
+        process::exit(if passed { 0 } else { 1 })
+    }
+}
+
+/// Parse the PEM encoded certificate chain and private key.
+pub fn check_tls_files(cert_file: &Path, key_file: &Path) -> Result<(), TlsFileError> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| TlsFileError::Read(path.display().to_string(), e))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(cert_file)?)
+        .map_err(|e| TlsFileError::Read(cert_file.display().to_string(), e))?;
+    if certs.is_empty() {
+        return Err(TlsFileError::NoCertificates(
+            cert_file.display().to_string(),
+        ));
+    }
+
+    let keys = rustls_pemfile::read_all(&mut open(key_file)?)
+        .map_err(|e| TlsFileError::Read(key_file.display().to_string(), e))?;
+    let has_key = keys.iter().any(|item| {
+        matches!(
+            item,
+            rustls_pemfile::Item::RSAKey(_)
+                | rustls_pemfile::Item::PKCS8Key(_)
+                | rustls_pemfile::Item::ECKey(_)
+        )
+    });
+    if has_key {
+        Ok(())
+    } else {
+        Err(TlsFileError::NoPrivateKey(key_file.display().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_tls_files, CheckReport, CheckStatus, TlsFileError};
+    use std::{env, fs, path::Path};
+
+    #[test]
+    fn test_report_outcome() {
+        let mut report = CheckReport::default();
+        report.jwt_secret(None);
+        report.tls_files(None);
+        assert!(report.passed());
+        assert!(report
+            .checks()
+            .iter()
+            .all(|check| check.status == CheckStatus::Skip));
+
+        report.jwt_secret(Some(b""));
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn test_tls_files() {
+        let dir = env::temp_dir();
+        let empty = dir.join("bootstrap-check-empty.pem");
+        fs::write(&empty, "").unwrap();
+
+        assert!(matches!(
+            check_tls_files(&empty, &empty),
+            Err(TlsFileError::NoCertificates(_))
+        ));
+        assert!(matches!(
+            check_tls_files(Path::new("/does/not/exist.pem"), &empty),
+            Err(TlsFileError::Read(..))
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+/*!
+Registered JWT claims policy shared by the services.
+
+Each service verifies the token signature with its own JWT library and
+then checks the registered claims against the same [`ClaimsPolicy`]. Besides
+expiry, a policy may limit how long ago a token was issued so a stolen token
+minted with a far future `exp` can't be used indefinitely, and may require
+the token to name this deployment as its audience and a trusted issuer.
+*/
+use clap::Args;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Time a token is still accepted after it expires to allow for clock skew
+/// between issuer and server.
+pub const DEFAULT_JWT_LEEWAY: Duration = Duration::from_secs(60);
+
+/// Registered claims rejected by the policy.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ClaimsError {
+    #[error("token has expired")]
+    Expired,
+    #[error("token has no issued at time")]
+    MissingIssuedAt,
+    #[error("token was issued too long ago or in the future")]
+    IssuedAt,
+    #[error("token audience is not accepted")]
+    Audience,
+    #[error("token issuer is not accepted")]
+    Issuer,
+}
+
+/// Registered claims of a verified token. Times are in seconds since the
+/// unix epoch.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RegisteredClaims<'a> {
+    pub exp: i64,
+    pub iat: Option<i64>,
+    pub aud: Option<&'a str>,
+    pub iss: Option<&'a str>,
+}
+
+/// Checks applied to the registered claims of every token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClaimsPolicy {
+    /// Time skew allowed for `exp` and `iat`.
+    pub leeway: Duration,
+    /// Reject tokens issued longer ago than this whatever their expiry.
+    /// Tokens must then have an `iat` claim.
+    pub max_age: Option<Duration>,
+    /// Required `aud` claim.
+    pub audience: Option<String>,
+    /// Required `iss` claim.
+    pub issuer: Option<String>,
+}
+
+impl Default for ClaimsPolicy {
+    fn default() -> Self {
+        Self {
+            leeway: DEFAULT_JWT_LEEWAY,
+            max_age: None,
+            audience: None,
+            issuer: None,
+        }
+    }
+}
+
+impl ClaimsPolicy {
+    /// Check the registered claims at `now` in seconds since the unix epoch.
+    pub fn validate(&self, claims: RegisteredClaims<'_>, now: i64) -> Result<(), ClaimsError> {
+        let leeway = secs(self.leeway);
+        if claims.exp.saturating_add(leeway) < now {
+            return Err(ClaimsError::Expired);
+        }
+        if let Some(max_age) = self.max_age {
+            let iat = claims.iat.ok_or(ClaimsError::MissingIssuedAt)?;
+            let age = now.saturating_sub(iat);
+            if age > secs(max_age).saturating_add(leeway) || age < -leeway {
+                return Err(ClaimsError::IssuedAt);
+            }
+        }
+        if self.audience.is_some() && claims.aud != self.audience.as_deref() {
+            return Err(ClaimsError::Audience);
+        }
+        if self.issuer.is_some() && claims.iss != self.issuer.as_deref() {
+            return Err(ClaimsError::Issuer);
+        }
+        Ok(())
+    }
+}
+
+fn secs(duration: Duration) -> i64 {
+    i64::try_from(duration.as_secs()).unwrap_or(i64::MAX)
+}
+
+/// Command line arguments configuring the [`ClaimsPolicy`].
+#[derive(Args, Debug, Clone)]
+pub struct ClaimsArgs {
+    #[clap(long, default_value = "60")]
+    #[clap(help = "Seconds of clock skew allowed when checking token times")]
+    pub jwt_leeway_secs: u64,
+    #[clap(long)]
+    #[clap(help = "Reject tokens issued more than this many seconds ago")]
+    pub jwt_max_age_secs: Option<u64>,
+    #[clap(long)]
+    #[clap(help = "Required token audience")]
+    pub jwt_audience: Option<String>,
+    #[clap(long)]
+    #[clap(help = "Required token issuer")]
+    pub jwt_issuer: Option<String>,
+}
+
+impl ClaimsArgs {
+    /// Policy configured by the arguments.
+    pub fn policy(&self) -> ClaimsPolicy {
+        ClaimsPolicy {
+            leeway: Duration::from_secs(self.jwt_leeway_secs),
+            max_age: self.jwt_max_age_secs.map(Duration::from_secs),
+            audience: self.jwt_audience.clone(),
+            issuer: self.jwt_issuer.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClaimsError, ClaimsPolicy, RegisteredClaims};
+    use std::time::Duration;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn claims(exp: i64) -> RegisteredClaims<'static> {
+        RegisteredClaims {
+            exp,
+            ..RegisteredClaims::default()
+        }
+    }
+
+    #[test]
+    fn test_expiry_leeway() {
+        let policy = ClaimsPolicy::default();
+        assert_eq!(policy.validate(claims(NOW - 60), NOW), Ok(()));
+        assert_eq!(
+            policy.validate(claims(NOW - 61), NOW),
+            Err(ClaimsError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_max_age() {
+        let policy = ClaimsPolicy {
+            max_age: Some(Duration::from_secs(3600)),
+            ..ClaimsPolicy::default()
+        };
+        let issued = |iat| RegisteredClaims {
+            iat,
+            ..claims(NOW + 86_400)
+        };
+        assert_eq!(policy.validate(issued(Some(NOW - 3600)), NOW), Ok(()));
+        assert_eq!(
+            policy.validate(issued(Some(NOW - 3661)), NOW),
+            Err(ClaimsError::IssuedAt)
+        );
+        assert_eq!(
+            policy.validate(issued(Some(NOW + 61)), NOW),
+            Err(ClaimsError::IssuedAt)
+        );
+        assert_eq!(
+            policy.validate(issued(None), NOW),
+            Err(ClaimsError::MissingIssuedAt)
+        );
+    }
+
+    #[test]
+    fn test_audience_and_issuer() {
+        let policy = ClaimsPolicy {
+            audience: Some("users".to_owned()),
+            issuer: Some("auth".to_owned()),
+            ..ClaimsPolicy::default()
+        };
+        let token = |aud, iss| RegisteredClaims {
+            aud,
+            iss,
+            ..claims(NOW)
+        };
+        assert_eq!(
+            policy.validate(token(Some("users"), Some("auth")), NOW),
+            Ok(())
+        );
+        assert_eq!(
+            policy.validate(token(Some("other"), Some("auth")), NOW),
+            Err(ClaimsError::Audience)
+        );
+        assert_eq!(
+            policy.validate(token(Some("users"), None), NOW),
+            Err(ClaimsError::Issuer)
+        );
+        assert_eq!(
+            ClaimsPolicy::default().validate(token(None, None), NOW),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,140 @@
+/*!
+Conditional user updates shared by the services.
+
+A user is served with an `ETag` computed from its stored fields. A client
+sends it back in `If-Match` when updating the user and the update is
+refused with `412 Precondition Failed` if the user changed since it was
+read, so concurrent edits don't silently overwrite each other. In strict
+mode an update without `If-Match` is refused with
+`428 Precondition Required`.
+
+The stored user is compared before it is updated and the write only
+applies to the user that was compared, so of two updates racing between
+the comparison and the write only one succeeds.
+*/
+use clap::Args;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Update rejected by its preconditions.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PreconditionError {
+    #[error("Updates require an If-Match header")]
+    Required,
+    #[error("The user was changed or removed since it was read")]
+    Failed,
+}
+
+/// Preconditions checked before a user is updated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Preconditions {
+    /// Refuse updates without an `If-Match` header.
+    pub strict: bool,
+}
+
+impl Preconditions {
+    /// Whether the stored user must be read to check an update with the
+    /// `If-Match` header value `if_match`.
+    pub fn applies(&self, if_match: Option<&str>) -> bool {
+        self.strict || if_match.is_some()
+    }
+
+    /// Check the `If-Match` header value of an update against the entity
+    /// tag of the stored user, `None` when there is no user. Weak tags
+    /// never match.
+    pub fn check(
+        &self,
+        if_match: Option<&str>,
+        current: Option<&str>,
+    ) -> Result<(), PreconditionError> {
+        let if_match = match if_match {
+            Some(if_match) => if_match,
+            None if self.strict => return Err(PreconditionError::Required),
+            None => return Ok(()),
+        };
+        let current = current.ok_or(PreconditionError::Failed)?;
+        if if_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag == current)
+        {
+            Ok(())
+        } else {
+            Err(PreconditionError::Failed)
+        }
+    }
+}
+
+/// Command line arguments configuring the [`Preconditions`].
+#[derive(Args, Debug, Clone)]
+pub struct ConditionalArgs {
+    #[clap(long)]
+    #[clap(help = "Refuse user updates without an If-Match header")]
+    pub strict_if_match: bool,
+}
+
+impl ConditionalArgs {
+    /// Preconditions configured by the arguments.
+    pub fn preconditions(&self) -> Preconditions {
+        Preconditions {
+            strict: self.strict_if_match,
+        }
+    }
+}
+
+/// Strong entity tag of a serialized user.
+pub fn etag<T: Serialize>(user: &T) -> Result<String, serde_json::Error> {
+    let digest = Sha256::digest(serde_json::to_vec(user)?);
+    Ok(format!("\"{digest:x}\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{etag, PreconditionError, Preconditions};
+    use serde_json::json;
+
+    #[test]
+    fn test_etag() {
+        let user = json!({"name": "Test User", "age": 100});
+        let tag = etag(&user).unwrap();
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert_eq!(tag, etag(&user).unwrap());
+        assert_ne!(
+            tag,
+            etag(&json!({"name": "Test User", "age": 101})).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let lenient = Preconditions::default();
+        let strict = Preconditions { strict: true };
+        let current = Some("\"abc\"");
+
+        assert!(!lenient.applies(None));
+        assert!(lenient.applies(Some("*")));
+        assert!(strict.applies(None));
+
+        assert_eq!(lenient.check(None, current), Ok(()));
+        assert_eq!(
+            strict.check(None, current),
+            Err(PreconditionError::Required)
+        );
+        assert_eq!(strict.check(Some("\"abc\""), current), Ok(()));
+        assert_eq!(strict.check(Some("\"x\", \"abc\""), current), Ok(()));
+        assert_eq!(strict.check(Some("*"), current), Ok(()));
+        assert_eq!(
+            strict.check(Some("W/\"abc\""), current),
+            Err(PreconditionError::Failed)
+        );
+        assert_eq!(
+            lenient.check(Some("\"old\""), current),
+            Err(PreconditionError::Failed)
+        );
+        assert_eq!(
+            lenient.check(Some("*"), None),
+            Err(PreconditionError::Failed)
+        );
+    }
+}
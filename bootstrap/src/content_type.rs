@@ -0,0 +1,61 @@
+/*!
+Content type negotiation of JSON request bodies shared by the services.
+
+A body is read as JSON when its content type is `application/json` or has
+a `+json` suffix, compared without regard to case and ignoring parameters
+such as `charset=utf-8`. Any other or a missing content type is answered
+with a `415 Unsupported Media Type` in the standard error envelope.
+*/
+use thiserror::Error;
+
+/// Label of the error envelope for a rejected content type.
+pub const UNSUPPORTED_MEDIA_TYPE_LABEL: &str = "unsupported.media_type";
+
+/// Content type rejected for a JSON body.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ContentTypeError {
+    #[error("Expected a Content-Type of application/json")]
+    Missing,
+    #[error("Expected a Content-Type of application/json, not `{0}`")]
+    Unsupported(String),
+}
+
+/// Check the `Content-Type` header value of a request with a JSON body.
+pub fn check_json(content_type: Option<&str>) -> Result<(), ContentTypeError> {
+    let content_type = content_type.ok_or(ContentTypeError::Missing)?;
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match essence.split_once('/') {
+        Some(("application", subtype)) if subtype == "json" || subtype.ends_with("+json") => Ok(()),
+        _ => Err(ContentTypeError::Unsupported(content_type.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_json, ContentTypeError};
+
+    #[test]
+    fn test_check_json() {
+        for accepted in [
+            "application/json",
+            "application/json; charset=utf-8",
+            "Application/JSON;charset=UTF-8",
+            "application/merge-patch+json",
+        ] {
+            assert_eq!(check_json(Some(accepted)), Ok(()), "{accepted}");
+        }
+
+        assert_eq!(check_json(None), Err(ContentTypeError::Missing));
+        for rejected in ["text/plain", "application/jsonp", "json", ""] {
+            assert_eq!(
+                check_json(Some(rejected)),
+                Err(ContentTypeError::Unsupported(rejected.to_owned()))
+            );
+        }
+    }
+}
@@ -0,0 +1,147 @@
+/*!
+Sparse fieldsets for user responses shared by the services.
+
+A client passing `?fields=name,email` receives only those fields of each
+user, which keeps payloads small for mobile clients. Fields are pruned
+from the serialized response so they are chosen from exactly what the
+client was allowed to see. `id` and `hashId` are always kept so a pruned
+user can still be fetched and updated. Unknown fields are rejected
+rather than ignored.
+*/
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Label of the error envelope for a rejected field list.
+pub const INVALID_FIELDS_LABEL: &str = "fields.invalid";
+
+/// Fields of a serialized user that can be requested.
+pub const USER_FIELDS: &[&str] = &[
+    "id", "name", "age", "email", "gender", "phone", "address", "hashId",
+];
+
+/// Fields kept whether they are requested or not.
+const ALWAYS_KEPT: &[&str] = &["id", "hashId"];
+
+/// Field list rejected for a sparse fieldset.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FieldsError {
+    #[error("At least one field must be requested")]
+    Empty,
+    #[error("Unknown field `{0}`, expected one of {known}", known = USER_FIELDS.join(", "))]
+    Unknown(String),
+}
+
+/// User fields requested by a client.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FieldSet(Vec<&'static str>);
+
+impl FromStr for FieldSet {
+    type Err = FieldsError;
+
+    fn from_str(fields: &str) -> Result<Self, Self::Err> {
+        let mut set = Vec::new();
+        for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let known = USER_FIELDS
+                .iter()
+                .find(|known| **known == field)
+                .ok_or_else(|| FieldsError::Unknown(field.to_owned()))?;
+            if !set.contains(known) {
+                set.push(*known);
+            }
+        }
+        if set.is_empty() {
+            Err(FieldsError::Empty)
+        } else {
+            Ok(Self(set))
+        }
+    }
+}
+
+impl TryFrom<String> for FieldSet {
+    type Error = FieldsError;
+
+    fn try_from(fields: String) -> Result<Self, Self::Error> {
+        fields.parse()
+    }
+}
+
+impl FieldSet {
+    /// Whether `field` is kept.
+    pub fn contains(&self, field: &str) -> bool {
+        ALWAYS_KEPT.contains(&field) || self.0.contains(&field)
+    }
+
+    /// Remove the fields that weren't requested from a user, a list of
+    /// users or a page of users.
+    pub fn prune(&self, value: &mut Value) {
+        match value {
+            Value::Array(users) => users.iter_mut().for_each(|user| self.prune(user)),
+            Value::Object(page) if page.contains_key("items") && page.contains_key("total") => {
+                if let Some(items) = page.get_mut("items") {
+                    self.prune(items);
+                }
+            }
+            Value::Object(user) => user.retain(|field, _| self.contains(field)),
+            _ => (),
+        }
+    }
+}
+
+/// Serialize `value` keeping only the requested fields, or every field
+/// when none were requested.
+pub fn shape<T: Serialize>(
+    fields: Option<&FieldSet>,
+    value: &T,
+) -> Result<Value, serde_json::Error> {
+    let mut value = serde_json::to_value(value)?;
+    if let Some(fields) = fields {
+        fields.prune(&mut value);
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{shape, FieldSet, FieldsError};
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_fields() {
+        assert_eq!(
+            " name, email,name ".parse::<FieldSet>(),
+            Ok(FieldSet(vec!["name", "email"]))
+        );
+        assert_eq!("".parse::<FieldSet>(), Err(FieldsError::Empty));
+        assert_eq!(" , ".parse::<FieldSet>(), Err(FieldsError::Empty));
+        assert_eq!(
+            "name,password".parse::<FieldSet>(),
+            Err(FieldsError::Unknown("password".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_prune() {
+        let fields = "name".parse::<FieldSet>().unwrap();
+        let user = json!({"id": "1", "name": "Test", "age": 100, "hashId": "abc"});
+        let pruned = json!({"id": "1", "name": "Test", "hashId": "abc"});
+
+        assert_eq!(shape(Some(&fields), &user).unwrap(), pruned);
+        assert_eq!(shape(None, &user).unwrap(), user);
+        assert_eq!(
+            shape(Some(&fields), &json!([user.clone(), user.clone()])).unwrap(),
+            json!([pruned.clone(), pruned.clone()])
+        );
+        assert_eq!(
+            shape(
+                Some(&fields),
+                &json!({"items": [user], "total": 1, "offset": 0, "limit": 10})
+            )
+            .unwrap(),
+            json!({"items": [pruned], "total": 1, "offset": 0, "limit": 10})
+        );
+        assert_eq!(shape(Some(&fields), &json!(null)).unwrap(), json!(null));
+    }
+}
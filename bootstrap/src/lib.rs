@@ -0,0 +1,390 @@
+/*!
+Startup settings shared by the service binaries.
+
+The deployment [`Profile`] bundles defaults for TLS, mongodb certificate
+validation and the log format. Settings which are only safe for local
+testing, such as the development token endpoint, are refused at startup
+with the production profile.
+*/
+pub mod body_timeout;
+pub mod check;
+pub mod claims;
+pub mod conditional;
+pub mod content_type;
+pub mod fields;
+pub mod metrics;
+pub mod route_policy;
+pub mod token;
+
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::{
+    backtrace::Backtrace,
+    fmt::{self, Display},
+    time::Duration,
+};
+use thiserror::Error;
+use tracing::{event, Level};
+use tracing_subscriber::EnvFilter;
+
+/// Tracing target of events logged by the bootstrap.
+pub const BOOTSTRAP_TARGET: &str = "ms-framework";
+
+/// Secret the services sign test JWTs with. It is public so it is refused
+/// with the production profile.
+pub const TEST_JWT_SECRET: &[u8] = b"TEST_SECRET";
+
+/// Path of the development token endpoint.
+pub const DEV_TOKEN_PATH: &str = "/api/v1/dev/token";
+/// Lifetime of a development token when the request doesn't give one.
+pub const DEFAULT_DEV_TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+/// Longest lifetime of a development token.
+pub const MAX_DEV_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Deployment profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    Dev,
+    Staging,
+    Prod,
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Profile::Dev => "dev",
+                Profile::Staging => "staging",
+                Profile::Prod => "prod",
+            }
+        )
+    }
+}
+
+impl Profile {
+    /// Defaults bundled with the profile.
+    pub fn defaults(self) -> ProfileDefaults {
+        match self {
+            Profile::Dev => ProfileDefaults {
+                tls_required: false,
+                dev_tokens: false,
+                allow_invalid_certificates: true,
+                log_format: LogFormat::Pretty,
+            },
+            Profile::Staging | Profile::Prod => ProfileDefaults {
+                tls_required: true,
+                dev_tokens: false,
+                allow_invalid_certificates: false,
+                log_format: LogFormat::Json,
+            },
+        }
+    }
+}
+
+/// Format of log events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Multi line human readable events.
+    Pretty,
+    /// One JSON object per event.
+    Json,
+}
+
+/// Defaults bundled with a [`Profile`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProfileDefaults {
+    /// The server must be configured with a certificate and key.
+    pub tls_required: bool,
+    /// Serve the development token endpoint.
+    pub dev_tokens: bool,
+    /// Accept self signed mongodb certificates.
+    pub allow_invalid_certificates: bool,
+    pub log_format: LogFormat,
+}
+
+/// Invalid startup settings.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BootstrapError {
+    #[error("the dev token endpoint can't be enabled with the {0} profile")]
+    DevTokens(Profile),
+    #[error("the test JWT secret can't be used with the {0} profile")]
+    TestJwtSecret(Profile),
+    #[error("invalid mongodb certificates can't be allowed with the {0} profile")]
+    InvalidCertificates(Profile),
+    #[error("a server TLS certificate and key are required with the {0} profile")]
+    TlsRequired(Profile),
+}
+
+/// Command line arguments shared by the service binaries.
+#[derive(Args, Debug, Clone)]
+pub struct BootstrapArgs {
+    #[clap(long, value_enum, default_value = "dev")]
+    #[clap(help = "Deployment profile")]
+    pub profile: Profile,
+    #[clap(long)]
+    #[clap(help = "Serve the development token endpoint")]
+    pub dev_tokens: bool,
+    #[clap(long, value_enum)]
+    #[clap(help = "Log format, defaults from the profile")]
+    pub log_format: Option<LogFormat>,
+    #[clap(long)]
+    #[clap(help = "Check the configuration and dependencies, print a JSON report and exit")]
+    pub check: bool,
+}
+
+/// Settings configured by a service binary which the profile checks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Configured<'a> {
+    /// Secret used to sign and verify JWTs when the service authorizes
+    /// requests.
+    pub jwt_secret: Option<&'a [u8]>,
+    /// The server has a TLS certificate and key.
+    pub tls: bool,
+    /// Explicit mongodb certificate validation setting, otherwise the
+    /// profile default is used.
+    pub allow_invalid_certificates: Option<bool>,
+}
+
+/// Startup settings resolved from the profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bootstrap {
+    pub profile: Profile,
+    pub dev_tokens: bool,
+    pub allow_invalid_certificates: bool,
+    pub log_format: LogFormat,
+}
+
+impl BootstrapArgs {
+    /// Resolve the startup settings from the profile defaults and the
+    /// arguments. Fails with combinations the profile doesn't allow.
+    pub fn resolve(&self, configured: Configured<'_>) -> Result<Bootstrap, BootstrapError> {
+        let profile = self.profile;
+        let defaults = profile.defaults();
+        let bootstrap = Bootstrap {
+            profile,
+            dev_tokens: self.dev_tokens || defaults.dev_tokens,
+            allow_invalid_certificates: configured
+                .allow_invalid_certificates
+                .unwrap_or(defaults.allow_invalid_certificates),
+            log_format: self.log_format.unwrap_or(defaults.log_format),
+        };
+
+        if defaults.tls_required && !configured.tls {
+            return Err(BootstrapError::TlsRequired(profile));
+        }
+        if profile == Profile::Prod {
+            if bootstrap.dev_tokens {
+                return Err(BootstrapError::DevTokens(profile));
+            }
+            if configured.jwt_secret == Some(TEST_JWT_SECRET) {
+                return Err(BootstrapError::TestJwtSecret(profile));
+            }
+            if bootstrap.allow_invalid_certificates {
+                return Err(BootstrapError::InvalidCertificates(profile));
+            }
+        }
+        Ok(bootstrap)
+    }
+}
+
+impl Bootstrap {
+    /// Install the global tracing subscriber with the resolved log format.
+    pub fn init_tracing(&self) {
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_target(true);
+        match self.log_format {
+            LogFormat::Pretty => builder.pretty().init(),
+            LogFormat::Json => builder.json().flatten_event(true).init(),
+        }
+    }
+}
+
+/// Log panics with a backtrace through tracing so the log line is
+/// attributed to the request span it occurred in.
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::force_capture();
+        event!(
+          target: BOOTSTRAP_TARGET,
+          Level::ERROR,
+          "panic: {info}\n{backtrace}"
+        );
+    }));
+}
+
+/// Invalid development token request.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DevTokenError {
+    #[error("ttl must be between 1 and {} seconds", MAX_DEV_TOKEN_TTL.as_secs())]
+    Ttl,
+    #[error("sub must not be empty")]
+    Subject,
+}
+
+/// Request for a development token. `R` is the framework's role type.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DevTokenRequest<R> {
+    pub role: R,
+    pub sub: String,
+    /// Lifetime in seconds.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+impl<R> DevTokenRequest<R> {
+    /// Check the request returning the token lifetime.
+    pub fn validate(&self) -> Result<Duration, DevTokenError> {
+        if self.sub.trim().is_empty() {
+            return Err(DevTokenError::Subject);
+        }
+        match self.ttl.map(Duration::from_secs) {
+            None => Ok(DEFAULT_DEV_TOKEN_TTL),
+            Some(ttl) if !ttl.is_zero() && ttl <= MAX_DEV_TOKEN_TTL => Ok(ttl),
+            Some(_) => Err(DevTokenError::Ttl),
+        }
+    }
+}
+
+/// Minted development token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DevTokenResponse {
+    pub token: String,
+    /// Expiry in seconds since the unix epoch.
+    pub expires_at: i64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        BootstrapArgs, BootstrapError, Configured, DevTokenError, DevTokenRequest, LogFormat,
+        Profile, DEFAULT_DEV_TOKEN_TTL, TEST_JWT_SECRET,
+    };
+    use std::time::Duration;
+
+    fn args(profile: Profile, dev_tokens: bool) -> BootstrapArgs {
+        BootstrapArgs {
+            profile,
+            dev_tokens,
+            log_format: None,
+            check: false,
+        }
+    }
+
+    const SECURE: Configured<'static> = Configured {
+        jwt_secret: Some(b"production secret"),
+        tls: true,
+        allow_invalid_certificates: None,
+    };
+
+    #[test]
+    fn test_dev_tokens_refused_in_prod() {
+        let dev_tokens = |profile, enabled| {
+            args(profile, enabled)
+                .resolve(SECURE)
+                .map(|bootstrap| bootstrap.dev_tokens)
+        };
+        assert_eq!(dev_tokens(Profile::Dev, true), Ok(true));
+        assert_eq!(dev_tokens(Profile::Staging, true), Ok(true));
+        assert_eq!(dev_tokens(Profile::Prod, false), Ok(false));
+        assert_eq!(
+            dev_tokens(Profile::Prod, true),
+            Err(BootstrapError::DevTokens(Profile::Prod))
+        );
+    }
+
+    #[test]
+    fn test_profile_defaults() {
+        let dev = args(Profile::Dev, false)
+            .resolve(Configured::default())
+            .unwrap();
+        assert!(dev.allow_invalid_certificates);
+        assert_eq!(dev.log_format, LogFormat::Pretty);
+
+        let prod = args(Profile::Prod, false).resolve(SECURE).unwrap();
+        assert!(!prod.allow_invalid_certificates);
+        assert_eq!(prod.log_format, LogFormat::Json);
+
+        let overridden = BootstrapArgs {
+            log_format: Some(LogFormat::Pretty),
+            ..args(Profile::Prod, false)
+        };
+        assert_eq!(
+            overridden.resolve(SECURE).unwrap().log_format,
+            LogFormat::Pretty
+        );
+    }
+
+    #[test]
+    fn test_prod_safety_rails() {
+        let prod = args(Profile::Prod, false);
+        assert_eq!(
+            prod.resolve(Configured {
+                jwt_secret: Some(TEST_JWT_SECRET),
+                ..SECURE
+            }),
+            Err(BootstrapError::TestJwtSecret(Profile::Prod))
+        );
+        assert_eq!(
+            prod.resolve(Configured {
+                allow_invalid_certificates: Some(true),
+                ..SECURE
+            }),
+            Err(BootstrapError::InvalidCertificates(Profile::Prod))
+        );
+        assert_eq!(
+            prod.resolve(Configured {
+                tls: false,
+                ..SECURE
+            }),
+            Err(BootstrapError::TlsRequired(Profile::Prod))
+        );
+
+        // Staging requires TLS but allows testing conveniences when they
+        // are asked for explicitly.
+        let staging = args(Profile::Staging, false);
+        assert_eq!(
+            staging.resolve(Configured::default()),
+            Err(BootstrapError::TlsRequired(Profile::Staging))
+        );
+        assert!(staging
+            .resolve(Configured {
+                jwt_secret: Some(TEST_JWT_SECRET),
+                allow_invalid_certificates: Some(true),
+                tls: true,
+            })
+            .is_ok());
+    }
+
+    #[test]
+    fn test_dev_token_request() {
+        let request = |sub: &str, ttl| DevTokenRequest {
+            role: (),
+            sub: sub.to_owned(),
+            ttl,
+        };
+        assert_eq!(request("dev", None).validate(), Ok(DEFAULT_DEV_TOKEN_TTL));
+        assert_eq!(
+            request("dev", Some(60)).validate(),
+            Ok(Duration::from_secs(60))
+        );
+        assert_eq!(request("dev", Some(0)).validate(), Err(DevTokenError::Ttl));
+        assert_eq!(
+            request("dev", Some(86_401)).validate(),
+            Err(DevTokenError::Ttl)
+        );
+        assert_eq!(request(" ", None).validate(), Err(DevTokenError::Subject));
+    }
+
+    #[test]
+    fn test_deserialize_request() {
+        let request: DevTokenRequest<String> =
+            serde_json::from_str(r#"{"role": "Admin", "sub": "dev"}"#).unwrap();
+        assert_eq!(request.role, "Admin");
+        assert_eq!(request.ttl, None);
+    }
+}
@@ -0,0 +1,29 @@
+/*!
+Request latency metrics recorded by every service.
+
+The services record the latency of each request in the
+[`REQUEST_DURATION_METRIC`] histogram, labelled with its method, route
+template and status, and serve it to prometheus with
+[`install_metrics_exporter`].
+*/
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder};
+use std::net::SocketAddr;
+
+/// Histogram recording request latency in seconds.
+pub const REQUEST_DURATION_METRIC: &str = "http_request_duration_seconds";
+
+/// Histogram buckets in seconds for request latency.
+pub const REQUEST_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Install the prometheus recorder and serve its scrape endpoint on `addr`.
+pub fn install_metrics_exporter(addr: SocketAddr) -> Result<(), BuildError> {
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .set_buckets_for_metric(
+            Matcher::Full(REQUEST_DURATION_METRIC.to_owned()),
+            REQUEST_DURATION_BUCKETS,
+        )?
+        .install()
+}
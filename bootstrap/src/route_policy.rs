@@ -0,0 +1,167 @@
+/*!
+Roles allowed on each route, configurable per deployment.
+
+Handlers declare the role they require by taking an `AdminAccess` or a
+`UserAccess`. A [`RoutePolicy`] read from the JSON file given with
+`--route-policy-file` overrides that role for the routes it lists, mapping
+`METHOD /route` to the names of the roles allowed:
+
+```json
+{"GET /api/v1/user/:id": ["Admin", "User"], "PUT /api/v1/user": ["Admin"]}
+```
+
+Path parameters may be written in the syntax of any of the services,
+`:id`, `<id>` or `{id}`. Routes the policy doesn't list keep the role of
+their handler, and a route listed without roles is closed to every role.
+
+The [`PUBLIC_PATHS`] issue tokens or describe the deployment and are served
+without a token, whatever the policy.
+*/
+use crate::token::{LOGIN_PATH, REFRESH_PATH};
+use clap::Args;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Paths served without a token.
+pub const PUBLIC_PATHS: &[&str] = &[
+    LOGIN_PATH,
+    REFRESH_PATH,
+    "/readyz",
+    "/api/v1/.well-known/signing-key",
+    "/api/v1/openapi.json",
+];
+
+/// Whether `path` is served without a token.
+pub fn is_public(path: &str) -> bool {
+    PUBLIC_PATHS.contains(&path)
+}
+
+/// Route policy file that can't be used.
+#[derive(Debug, Error)]
+pub enum RoutePolicyError {
+    #[error("failed to read the route policy: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("invalid route policy: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid route `{0}`, expected METHOD /path")]
+    Route(String),
+}
+
+/// Roles allowed on the routes overriding the role of their handler.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoutePolicy {
+    routes: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl RoutePolicy {
+    /// Read a policy from a JSON object mapping `METHOD /route` to role
+    /// names.
+    pub fn from_json(json: &[u8]) -> Result<Self, RoutePolicyError> {
+        let routes = serde_json::from_slice::<BTreeMap<String, BTreeSet<String>>>(json)?
+            .into_iter()
+            .map(|(route, roles)| match route.split_once(' ') {
+                Some((method, path)) if path.trim_start().starts_with('/') => {
+                    Ok((route_key(method, path.trim_start()), roles))
+                }
+                _ => Err(RoutePolicyError::Route(route)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { routes })
+    }
+
+    /// Read a policy from the JSON file at `path`.
+    pub fn read(path: &Path) -> Result<Self, RoutePolicyError> {
+        Self::from_json(&std::fs::read(path)?)
+    }
+
+    /// Roles the policy allows on `method` `route`, none when it doesn't
+    /// list the route.
+    pub fn roles(&self, method: &str, route: &str) -> Option<&BTreeSet<String>> {
+        self.routes.get(&route_key(method, route))
+    }
+
+    /// Whether `role` may call `method` `route`, whose handler requires
+    /// `required` unless the policy lists the route.
+    pub fn permits(&self, method: &str, route: &str, role: &str, required: &str) -> bool {
+        match self.roles(method, route) {
+            Some(roles) => roles.contains(role),
+            None => role == required,
+        }
+    }
+}
+
+/// Key of a route with its path parameters written as `*`.
+fn route_key(method: &str, route: &str) -> String {
+    let path = route
+        .trim_end_matches('/')
+        .split('/')
+        .map(|segment| {
+            let param = segment.starts_with(':')
+                || segment.starts_with('*')
+                || (segment.starts_with('<') && segment.ends_with('>'))
+                || (segment.starts_with('{') && segment.ends_with('}'));
+            if param {
+                "*"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("{} {path}", method.to_ascii_uppercase())
+}
+
+/// Command line arguments configuring the route policy.
+#[derive(Args, Debug, Clone)]
+pub struct RoutePolicyArgs {
+    #[clap(long)]
+    #[clap(help = "JSON file mapping METHOD /route to the roles allowed on it")]
+    pub route_policy_file: Option<PathBuf>,
+}
+
+impl RoutePolicyArgs {
+    /// Policy of the configured file, the roles of the handlers without
+    /// one.
+    pub fn policy(&self) -> Result<RoutePolicy, RoutePolicyError> {
+        self.route_policy_file
+            .as_deref()
+            .map_or_else(|| Ok(RoutePolicy::default()), RoutePolicy::read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_public, RoutePolicy, RoutePolicyError};
+
+    #[test]
+    fn test_route_policy() {
+        let policy = RoutePolicy::from_json(
+            br#"{"GET /api/v1/user/:id": ["Admin", "User"], "post /api/v1/user/": []}"#,
+        )
+        .unwrap();
+
+        for route in ["/api/v1/user/:id", "/api/v1/user/<id>", "/api/v1/user/{id}"] {
+            assert!(policy.permits("GET", route, "User", "Admin"));
+            assert!(policy.permits("GET", route, "Admin", "Admin"));
+        }
+        assert!(!policy.permits("POST", "/api/v1/user", "User", "User"));
+        assert!(policy.permits("PUT", "/api/v1/user", "User", "User"));
+        assert!(!policy.permits("PUT", "/api/v1/user", "Admin", "User"));
+
+        assert!(matches!(
+            RoutePolicy::from_json(br#"{"/api/v1/user": ["Admin"]}"#),
+            Err(RoutePolicyError::Route(_))
+        ));
+    }
+
+    #[test]
+    fn test_public_paths() {
+        assert!(is_public("/api/v1/auth/login"));
+        assert!(is_public("/readyz"));
+        assert!(!is_public("/api/v1/user"));
+        assert!(!is_public("/api/v1/auth/login/"));
+    }
+}
@@ -0,0 +1,647 @@
+/*!
+Token issuance shared by the services.
+
+`POST /api/v1/auth/login` exchanges the credentials of an account for an
+access token and a refresh token, and `POST /api/v1/auth/refresh` exchanges
+a refresh token for new ones. A [`TokenIssuer`] checks the credentials and
+keeps the refresh tokens; each service signs the access token for the
+[`Grant`] with its own JWT library, as it verifies it.
+
+Accounts are read from a JSON object mapping user names to their role and
+a PBKDF2 password hash made with [`hash_password`]. A login naming no
+account is checked against a dummy hash so it takes as long as one naming
+an account, and the response time doesn't tell which accounts exist.
+
+Refresh tokens are opaque random strings of which only a digest is kept,
+in the [`KvStore`] of the service so every replica accepts them and they
+survive restarts. They rotate: a refresh token is consumed by the refresh
+it is used for, and using it again revokes every token descended from the
+same login, as it has been stolen from one of the parties holding it.
+*/
+use clap::Args;
+use ring::{
+    digest, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+use user_persist::kv::{KvStore, MemoryKvStore};
+
+/// Path of the login endpoint.
+pub const LOGIN_PATH: &str = "/api/v1/auth/login";
+/// Path of the refresh endpoint.
+pub const REFRESH_PATH: &str = "/api/v1/auth/refresh";
+/// Label of the error answering a failed login or refresh.
+pub const TOKEN_ERROR_LABEL: &str = "auth.token";
+
+/// Longest token lifetime in seconds, ten years. Longer lifetimes overflow
+/// the expiry times computed by the token stores.
+pub const MAX_TOKEN_TTL_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const HASH_LEN: usize = digest::SHA256_OUTPUT_LEN;
+const SALT_LEN: usize = 16;
+const REFRESH_TOKEN_LEN: usize = 32;
+const FAMILY_ID_LEN: usize = 16;
+/// Value of the current token of a login whose tokens were revoked.
+const REVOKED: &str = "revoked";
+/// Recommended iterations of [`hash_password`].
+pub const PASSWORD_HASH_ITERATIONS: u32 = 600_000;
+
+/// Failed login or refresh. A reused refresh token is answered like an
+/// invalid one so a thief can't tell the tokens were revoked.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum IssueError {
+    #[error("invalid user name or password")]
+    InvalidCredentials,
+    #[error("invalid or expired refresh token")]
+    InvalidRefreshToken,
+    #[error("refresh token was already used")]
+    RefreshTokenReused,
+    #[error("random tokens can't be generated")]
+    Random,
+    #[error("refresh tokens can't be stored: {0}")]
+    Store(String),
+}
+
+/// Invalid accounts file.
+#[derive(Debug, Error)]
+pub enum AccountsError {
+    #[error("accounts can't be read: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("accounts are not valid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("password of account `{0}` is not a {HASH_SCHEME} hash")]
+    PasswordHash(String),
+}
+
+/// Request exchanging credentials for tokens.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+/// Request exchanging a refresh token for new tokens.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Tokens issued by a login or refresh.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenResponse {
+    pub access_token: String,
+    /// Expiry of the access token in seconds since the unix epoch.
+    pub expires_at: i64,
+    pub refresh_token: String,
+    /// Expiry of the refresh token in seconds since the unix epoch.
+    pub refresh_expires_at: i64,
+}
+
+/// Lifetimes of issued tokens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenSettings {
+    pub access_ttl: Duration,
+    pub refresh_ttl: Duration,
+}
+
+impl Default for TokenSettings {
+    fn default() -> Self {
+        Self {
+            access_ttl: Duration::from_secs(15 * 60),
+            refresh_ttl: Duration::from_secs(14 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Command line arguments enabling the login and refresh endpoints.
+#[derive(Args, Debug, Clone)]
+pub struct TokenArgs {
+    #[clap(long)]
+    #[clap(help = "JSON file of the accounts allowed to log in, enables the auth endpoints")]
+    pub accounts_file: Option<PathBuf>,
+    #[clap(long, default_value = "900")]
+    #[clap(value_parser = clap::value_parser!(u64).range(..=MAX_TOKEN_TTL_SECS))]
+    #[clap(help = "Seconds an issued access token is valid")]
+    pub access_token_ttl_secs: u64,
+    #[clap(long, default_value = "1209600")]
+    #[clap(value_parser = clap::value_parser!(u64).range(..=MAX_TOKEN_TTL_SECS))]
+    #[clap(help = "Seconds an issued refresh token is valid")]
+    pub refresh_token_ttl_secs: u64,
+}
+
+impl TokenArgs {
+    /// Lifetimes configured by the arguments.
+    pub fn settings(&self) -> TokenSettings {
+        TokenSettings {
+            access_ttl: Duration::from_secs(self.access_token_ttl_secs),
+            refresh_ttl: Duration::from_secs(self.refresh_token_ttl_secs),
+        }
+    }
+
+    /// Issuer of the configured accounts when the endpoints are enabled.
+    pub fn issuer<R>(&self) -> Result<Option<TokenIssuer<R>>, AccountsError>
+    where
+        R: DeserializeOwned + Clone,
+    {
+        self.accounts_file
+            .as_deref()
+            .map(|path| Ok(TokenIssuer::new(Accounts::read(path)?, self.settings())))
+            .transpose()
+    }
+}
+
+/// An account allowed to log in. `R` is the framework's role type.
+#[derive(Debug, Clone, Deserialize)]
+struct Account<R> {
+    role: R,
+    password: String,
+}
+
+/// Accounts allowed to log in by user name.
+#[derive(Debug, Clone)]
+pub struct Accounts<R> {
+    accounts: BTreeMap<String, Account<R>>,
+}
+
+impl<R: DeserializeOwned> Accounts<R> {
+    /// Read the accounts of a JSON object mapping user names to their
+    /// `role` and `password` hash.
+    pub fn from_json(json: &[u8]) -> Result<Self, AccountsError> {
+        let accounts = serde_json::from_slice::<BTreeMap<String, Account<R>>>(json)?;
+        if let Some((name, _)) = accounts
+            .iter()
+            .find(|(_, account)| PasswordHash::parse(&account.password).is_none())
+        {
+            return Err(AccountsError::PasswordHash(name.clone()));
+        }
+        Ok(Self { accounts })
+    }
+
+    /// Read the accounts of the JSON file `path`.
+    pub fn read(path: &Path) -> Result<Self, AccountsError> {
+        Self::from_json(&std::fs::read(path)?)
+    }
+}
+
+/// Parsed `pbkdf2-sha256$<iterations>$<salt>$<hash>` password hash.
+#[derive(Debug)]
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Hash no password matches, verified when a login names no account.
+    fn dummy(iterations: NonZeroU32) -> Self {
+        Self {
+            iterations,
+            salt: vec![0; SALT_LEN],
+            hash: vec![0; HASH_LEN],
+        }
+    }
+
+    fn parse(encoded: &str) -> Option<Self> {
+        let mut parts = encoded.split('$');
+        if parts.next()? != HASH_SCHEME {
+            return None;
+        }
+        let iterations = parts.next()?.parse().ok()?;
+        let salt = base64::decode(parts.next()?).ok()?;
+        let hash = base64::decode(parts.next()?).ok()?;
+        (parts.next().is_none() && hash.len() == HASH_LEN).then_some(Self {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+/// Hash `password` with a random salt for an accounts file.
+pub fn hash_password(password: &str, iterations: NonZeroU32) -> Result<String, IssueError> {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| IssueError::Random)?;
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "{HASH_SCHEME}${iterations}${}${}",
+        base64::encode(salt),
+        base64::encode(hash)
+    ))
+}
+
+/// Tokens granted to an account. The service signs an access token for
+/// `sub` and `role` valid for `access_ttl`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant<R> {
+    pub sub: String,
+    pub role: R,
+    pub access_ttl: Duration,
+    pub refresh_token: String,
+    pub refresh_expires_at: i64,
+}
+
+impl<R> Grant<R> {
+    /// Response with the signed `access_token` expiring at `expires_at`.
+    pub fn response(self, access_token: String, expires_at: i64) -> TokenResponse {
+        TokenResponse {
+            access_token,
+            expires_at,
+            refresh_token: self.refresh_token,
+            refresh_expires_at: self.refresh_expires_at,
+        }
+    }
+}
+
+/// A refresh token kept by digest.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshRecord {
+    username: String,
+    /// Login the token descends from.
+    family: String,
+    expires_at: i64,
+}
+
+/// Checks credentials and issues and rotates refresh tokens.
+///
+/// Each login starts a family of refresh tokens whose current token is
+/// kept by digest under the family, and replaced by each refresh. A token
+/// that isn't the current one of its family was already used.
+#[derive(Debug)]
+pub struct TokenIssuer<R> {
+    accounts: Accounts<R>,
+    settings: TokenSettings,
+    kv: Arc<dyn KvStore>,
+    dummy_hash: PasswordHash,
+    random: SystemRandom,
+}
+
+impl<R: Clone> TokenIssuer<R> {
+    /// Issuer keeping refresh tokens in memory until
+    /// [`with_kv_store`](Self::with_kv_store) shares them.
+    pub fn new(accounts: Accounts<R>, settings: TokenSettings) -> Self {
+        // Logins naming no account pay for the costliest hash of an
+        // account.
+        let iterations = accounts
+            .accounts
+            .values()
+            .filter_map(|account| PasswordHash::parse(&account.password))
+            .map(|hash| hash.iterations)
+            .max()
+            .or(NonZeroU32::new(PASSWORD_HASH_ITERATIONS))
+            .unwrap_or(NonZeroU32::MIN);
+        Self {
+            accounts,
+            settings,
+            kv: Arc::new(MemoryKvStore::default()),
+            dummy_hash: PasswordHash::dummy(iterations),
+            random: SystemRandom::new(),
+        }
+    }
+
+    /// Keep refresh tokens in `kv`, shared by every replica.
+    pub fn with_kv_store(self, kv: Arc<dyn KvStore>) -> Self {
+        Self { kv, ..self }
+    }
+
+    /// Grant tokens to the account of `request` at `now` in seconds since
+    /// the unix epoch.
+    pub async fn login(&self, request: &LoginRequest, now: i64) -> Result<Grant<R>, IssueError> {
+        let account = self.accounts.accounts.get(&request.username);
+        let verified = match account.and_then(|account| PasswordHash::parse(&account.password)) {
+            Some(hash) => hash.verify(&request.password),
+            None => {
+                self.dummy_hash.verify(&request.password);
+                false
+            }
+        };
+        let account = account
+            .filter(|_| verified)
+            .ok_or(IssueError::InvalidCredentials)?;
+        let family = self.random_token(FAMILY_ID_LEN)?;
+        self.grant(&request.username, account, &family, None, now)
+            .await
+    }
+
+    /// Rotate the refresh token of `request` at `now` in seconds since the
+    /// unix epoch.
+    pub async fn refresh(
+        &self,
+        request: &RefreshRequest,
+        now: i64,
+    ) -> Result<Grant<R>, IssueError> {
+        let digest = token_digest(&request.refresh_token);
+        let record = self
+            .kv
+            .get(&token_key(&digest))
+            .await
+            .map_err(store_error)?
+            .and_then(|record| serde_json::from_str::<RefreshRecord>(&record).ok())
+            .filter(|record| record.expires_at > now)
+            .ok_or(IssueError::InvalidRefreshToken)?;
+        match self
+            .kv
+            .get(&family_key(&record.family))
+            .await
+            .map_err(store_error)?
+        {
+            Some(current) if current == digest => (),
+            Some(current) if current != REVOKED => {
+                self.revoke(&record.family).await?;
+                return Err(IssueError::RefreshTokenReused);
+            }
+            _ => return Err(IssueError::InvalidRefreshToken),
+        }
+        // Accounts removed since the login can't refresh.
+        let account = self
+            .accounts
+            .accounts
+            .get(&record.username)
+            .ok_or(IssueError::InvalidRefreshToken)?;
+        self.grant(
+            &record.username,
+            account,
+            &record.family,
+            Some(&digest),
+            now,
+        )
+        .await
+    }
+
+    /// Issue a refresh token replacing `previous`, the current token of
+    /// `family` unless the family is new.
+    async fn grant(
+        &self,
+        username: &str,
+        account: &Account<R>,
+        family: &str,
+        previous: Option<&str>,
+        now: i64,
+    ) -> Result<Grant<R>, IssueError> {
+        let refresh_token = self.random_token(REFRESH_TOKEN_LEN)?;
+        let refresh_secs = i64::try_from(self.settings.refresh_ttl.as_secs()).unwrap_or(i64::MAX);
+        let refresh_expires_at = now.saturating_add(refresh_secs);
+        let ttl = Some(chrono::Duration::seconds(refresh_secs));
+
+        let digest = token_digest(&refresh_token);
+        let record = RefreshRecord {
+            username: username.to_owned(),
+            family: family.to_owned(),
+            expires_at: refresh_expires_at,
+        };
+        let record =
+            serde_json::to_string(&record).map_err(|e| IssueError::Store(e.to_string()))?;
+        self.kv
+            .set(&token_key(&digest), &record, ttl)
+            .await
+            .map_err(store_error)?;
+        // Two refreshes racing with the same token can't both rotate it.
+        let rotated = self
+            .kv
+            .compare_and_set(&family_key(family), previous, &digest, ttl)
+            .await
+            .map_err(store_error)?;
+        if !rotated {
+            self.revoke(family).await?;
+            return Err(IssueError::RefreshTokenReused);
+        }
+        Ok(Grant {
+            sub: username.to_owned(),
+            role: account.role.clone(),
+            access_ttl: self.settings.access_ttl,
+            refresh_token,
+            refresh_expires_at,
+        })
+    }
+
+    /// Revoke every token of `family`.
+    async fn revoke(&self, family: &str) -> Result<(), IssueError> {
+        let ttl = i64::try_from(self.settings.refresh_ttl.as_secs()).unwrap_or(i64::MAX);
+        self.kv
+            .set(
+                &family_key(family),
+                REVOKED,
+                Some(chrono::Duration::seconds(ttl)),
+            )
+            .await
+            .map_err(store_error)
+    }
+
+    fn random_token(&self, len: usize) -> Result<String, IssueError> {
+        let mut token = vec![0u8; len];
+        self.random
+            .fill(&mut token)
+            .map_err(|_| IssueError::Random)?;
+        Ok(base64::encode_config(token, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+fn token_digest(token: &str) -> String {
+    base64::encode_config(
+        digest::digest(&digest::SHA256, token.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+fn token_key(digest: &str) -> String {
+    format!("refresh-token:{digest}")
+}
+
+fn family_key(family: &str) -> String {
+    format!("refresh-family:{family}")
+}
+
+fn store_error(e: impl std::fmt::Display) -> IssueError {
+    IssueError::Store(e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        hash_password, Accounts, AccountsError, IssueError, LoginRequest, RefreshRequest,
+        TokenArgs, TokenIssuer, TokenSettings, MAX_TOKEN_TTL_SECS,
+    };
+    use clap::Parser;
+    use std::{num::NonZeroU32, sync::Arc};
+    use user_persist::kv::MemoryKvStore;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn issuer() -> TokenIssuer<String> {
+        issuer_with(TokenSettings::default())
+    }
+
+    fn issuer_with(settings: TokenSettings) -> TokenIssuer<String> {
+        let hash = hash_password("secret", NonZeroU32::new(1).unwrap()).unwrap();
+        let accounts = format!(r#"{{"droberts": {{"role": "Admin", "password": "{hash}"}}}}"#);
+        TokenIssuer::new(Accounts::from_json(accounts.as_bytes()).unwrap(), settings)
+    }
+
+    fn login(username: &str, password: &str) -> LoginRequest {
+        LoginRequest {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+
+    fn refresh(refresh_token: &str) -> RefreshRequest {
+        RefreshRequest {
+            refresh_token: refresh_token.to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let issuer = issuer();
+        let grant = issuer
+            .login(&login("droberts", "secret"), NOW)
+            .await
+            .unwrap();
+        assert_eq!(grant.sub, "droberts");
+        assert_eq!(grant.role, "Admin");
+        assert_eq!(grant.refresh_expires_at, NOW + 14 * 24 * 60 * 60);
+
+        assert_eq!(
+            issuer.login(&login("droberts", "guess"), NOW).await,
+            Err(IssueError::InvalidCredentials)
+        );
+        assert_eq!(
+            issuer.login(&login("nobody", "secret"), NOW).await,
+            Err(IssueError::InvalidCredentials)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rotation() {
+        let issuer = issuer();
+        let first = issuer
+            .login(&login("droberts", "secret"), NOW)
+            .await
+            .unwrap();
+        let second = issuer
+            .refresh(&refresh(&first.refresh_token), NOW + 1)
+            .await
+            .unwrap();
+        assert_ne!(first.refresh_token, second.refresh_token);
+        let third = issuer
+            .refresh(&refresh(&second.refresh_token), NOW + 2)
+            .await
+            .unwrap();
+
+        // Reusing a consumed token revokes the tokens descended from it.
+        assert_eq!(
+            issuer
+                .refresh(&refresh(&first.refresh_token), NOW + 3)
+                .await,
+            Err(IssueError::RefreshTokenReused)
+        );
+        assert_eq!(
+            issuer
+                .refresh(&refresh(&third.refresh_token), NOW + 4)
+                .await,
+            Err(IssueError::InvalidRefreshToken)
+        );
+
+        // Other logins are unaffected, until their token expires.
+        let other = issuer
+            .login(&login("droberts", "secret"), NOW)
+            .await
+            .unwrap();
+        assert_eq!(
+            issuer
+                .refresh(&refresh(&other.refresh_token), other.refresh_expires_at)
+                .await,
+            Err(IssueError::InvalidRefreshToken)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_refresh_tokens() {
+        // Replicas sharing a store accept each other's tokens, and a token
+        // refreshed by one is spent for the other.
+        let kv = Arc::new(MemoryKvStore::default());
+        let first = issuer().with_kv_store(kv.clone());
+        let second = issuer().with_kv_store(kv);
+        let grant = first
+            .login(&login("droberts", "secret"), NOW)
+            .await
+            .unwrap();
+        let rotated = second
+            .refresh(&refresh(&grant.refresh_token), NOW + 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            first.refresh(&refresh(&grant.refresh_token), NOW + 2).await,
+            Err(IssueError::RefreshTokenReused)
+        );
+        assert_eq!(
+            second
+                .refresh(&refresh(&rotated.refresh_token), NOW + 3)
+                .await,
+            Err(IssueError::InvalidRefreshToken)
+        );
+    }
+
+    #[test]
+    fn test_invalid_accounts() {
+        let accounts = br#"{"droberts": {"role": "Admin", "password": "secret"}}"#;
+        assert!(matches!(
+            Accounts::<String>::from_json(accounts),
+            Err(AccountsError::PasswordHash(name)) if name == "droberts"
+        ));
+    }
+
+    #[derive(Parser)]
+    struct Args {
+        #[clap(flatten)]
+        token: TokenArgs,
+    }
+
+    #[tokio::test]
+    async fn test_ttl_range() {
+        let max = MAX_TOKEN_TTL_SECS.to_string();
+        let args = Args::try_parse_from(["test", "--refresh-token-ttl-secs", &max]).unwrap();
+        let grant = issuer_with(args.token.settings())
+            .login(&login("droberts", "secret"), NOW)
+            .await
+            .unwrap();
+        assert_eq!(grant.refresh_expires_at, NOW + MAX_TOKEN_TTL_SECS as i64);
+
+        let over = (MAX_TOKEN_TTL_SECS + 1).to_string();
+        for arg in ["--refresh-token-ttl-secs", "--access-token-ttl-secs"] {
+            assert!(Args::try_parse_from(["test", arg, &over]).is_err());
+        }
+    }
+}
@@ -0,0 +1,350 @@
+/*!
+Errors answered by the services.
+
+Every failure a client sees is an [`ApiError`]: a status, a label clients
+can match on and a message, sent in the `{label, message, requestId}`
+envelope. The failures the services share are mapped here once so the same
+failure reads the same whichever framework serves it:
+
+* [`DatabaseError`] for persistence failures.
+* [`AuthError`] for missing or rejected tokens.
+* [`ConfigError`] for features the configuration leaves out.
+* The request errors of the [`bootstrap`] modules.
+
+Failures specific to a single service are mapped by the service.
+*/
+use bootstrap::{
+    conditional::PreconditionError,
+    content_type::{ContentTypeError, UNSUPPORTED_MEDIA_TYPE_LABEL},
+    fields::{FieldsError, INVALID_FIELDS_LABEL},
+    token::{IssueError, TOKEN_ERROR_LABEL},
+    DevTokenError,
+};
+use http::StatusCode;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt::{self, Display};
+use thiserror::Error;
+use user_persist::persistence::PersistenceError;
+
+/// Body of every error response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorEnvelope {
+    /// Label clients can match on, such as `not.found`.
+    pub label: String,
+    pub message: String,
+    /// Id of the request, null when none has been assigned to it.
+    pub request_id: Option<String>,
+    /// Why the request's token was rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<AuthError>,
+}
+
+/// JSON error envelope of every error response. The request id is null
+/// when none has been assigned to the request.
+pub fn envelope(label: &str, message: impl Display, request_id: Option<&str>) -> Value {
+    json!(ErrorEnvelope {
+        label: label.to_owned(),
+        message: message.to_string(),
+        request_id: request_id.map(str::to_owned),
+        reason: None,
+    })
+}
+
+/// Error answered to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub label: &'static str,
+    pub message: String,
+    /// Why the request's token was rejected.
+    pub reason: Option<AuthError>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, label: &'static str, message: impl Display) -> Self {
+        Self {
+            status,
+            label,
+            message: message.to_string(),
+            reason: None,
+        }
+    }
+
+    /// The requested resource doesn't exist.
+    pub fn not_found(message: impl Display) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not.found", message)
+    }
+
+    /// Failure of the service itself, whose details are only logged.
+    pub fn internal() -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal.error",
+            "Internal server error",
+        )
+    }
+
+    /// A search without criteria that doesn't ask for every user.
+    pub fn unfiltered_search() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "search.unfiltered",
+            "Search requires at least one filter or all=true",
+        )
+    }
+
+    /// Envelope of the error for the request with `request_id`.
+    pub fn envelope(&self, request_id: Option<&str>) -> Value {
+        json!(ErrorEnvelope {
+            label: self.label.to_owned(),
+            message: self.message.clone(),
+            request_id: request_id.map(str::to_owned),
+            reason: self.reason,
+        })
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Persistence failure as seen by clients. Database errors are reported
+/// without their details as they can quote stored values.
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum DatabaseError {
+    #[error("Database operation timed out")]
+    Timeout,
+    #[error("A user with the same unique value already exists")]
+    Conflict,
+    #[error("{0}")]
+    InvalidRequest(String),
+    /// The key can't identify any stored user.
+    #[error("User not found")]
+    InvalidKey,
+    #[error("Database unavailable")]
+    Unavailable,
+}
+
+impl From<&PersistenceError> for DatabaseError {
+    fn from(err: &PersistenceError) -> Self {
+        match err {
+            PersistenceError::Timeout => Self::Timeout,
+            PersistenceError::DuplicateKey => Self::Conflict,
+            PersistenceError::InvalidQuery(_) | PersistenceError::BulkLimitExceeded { .. } => {
+                Self::InvalidRequest(err.to_string())
+            }
+            PersistenceError::InvalidKey(_) => Self::InvalidKey,
+            PersistenceError::MongoError(_)
+            | PersistenceError::BsonError(_)
+            | PersistenceError::TestError => Self::Unavailable,
+        }
+    }
+}
+
+impl From<DatabaseError> for ApiError {
+    fn from(err: DatabaseError) -> Self {
+        let (status, label) = match err {
+            DatabaseError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "persistence.timeout"),
+            DatabaseError::Conflict => (StatusCode::CONFLICT, "persistence.conflict"),
+            DatabaseError::InvalidRequest(_) => (StatusCode::BAD_REQUEST, "persistence.invalid"),
+            DatabaseError::InvalidKey => return Self::not_found(err),
+            DatabaseError::Unavailable => (StatusCode::SERVICE_UNAVAILABLE, "persistence.error"),
+        };
+        Self::new(status, label, err)
+    }
+}
+
+impl From<&PersistenceError> for ApiError {
+    fn from(err: &PersistenceError) -> Self {
+        DatabaseError::from(err).into()
+    }
+}
+
+/// Why a request's token was rejected, returned to clients so they only
+/// prompt for a new login when the token itself was rejected.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum AuthError {
+    #[error("Missing authorization")]
+    MissingHeader,
+    #[error("Invalid token signature")]
+    InvalidSignature,
+    #[error("Token has expired")]
+    Expired,
+    #[error("Token claims rejected")]
+    InvalidClaims,
+    #[error("Role not permitted")]
+    WrongRole,
+}
+
+impl AuthError {
+    /// `WWW-Authenticate` challenge for a new token, none when the token is
+    /// valid but its role isn't permitted.
+    pub fn challenge(&self) -> Option<&'static str> {
+        match self {
+            Self::MissingHeader => Some("Bearer"),
+            Self::InvalidSignature | Self::Expired | Self::InvalidClaims => {
+                Some(r#"Bearer error="invalid_token""#)
+            }
+            Self::WrongRole => None,
+        }
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(err: AuthError) -> Self {
+        let (status, message) = match err {
+            AuthError::WrongRole => (StatusCode::FORBIDDEN, "Not authorized to make request"),
+            _ => (StatusCode::UNAUTHORIZED, "Missing or invalid token"),
+        };
+        Self {
+            reason: Some(err),
+            ..Self::new(status, "unauthorized", message)
+        }
+    }
+}
+
+/// Feature left out by the configuration of the service.
+#[derive(Debug, Clone, Copy, Error, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("{0} is not enabled")]
+    Disabled(&'static str),
+}
+
+impl From<ConfigError> for ApiError {
+    fn from(err: ConfigError) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "feature.disabled", err)
+    }
+}
+
+impl From<&PreconditionError> for ApiError {
+    fn from(err: &PreconditionError) -> Self {
+        let (status, label) = match err {
+            PreconditionError::Required => {
+                (StatusCode::PRECONDITION_REQUIRED, "precondition.required")
+            }
+            PreconditionError::Failed => (StatusCode::PRECONDITION_FAILED, "precondition.failed"),
+        };
+        Self::new(status, label, err)
+    }
+}
+
+impl From<&DevTokenError> for ApiError {
+    fn from(err: &DevTokenError) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "token.request", err)
+    }
+}
+
+impl From<&IssueError> for ApiError {
+    fn from(err: &IssueError) -> Self {
+        match err {
+            IssueError::InvalidCredentials | IssueError::InvalidRefreshToken => {
+                Self::new(StatusCode::UNAUTHORIZED, TOKEN_ERROR_LABEL, err)
+            }
+            IssueError::RefreshTokenReused => Self::new(
+                StatusCode::UNAUTHORIZED,
+                TOKEN_ERROR_LABEL,
+                IssueError::InvalidRefreshToken,
+            ),
+            IssueError::Random | IssueError::Store(_) => Self::internal(),
+        }
+    }
+}
+
+impl From<&ContentTypeError> for ApiError {
+    fn from(err: &ContentTypeError) -> Self {
+        Self::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            UNSUPPORTED_MEDIA_TYPE_LABEL,
+            err,
+        )
+    }
+}
+
+impl From<&FieldsError> for ApiError {
+    fn from(err: &FieldsError) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, INVALID_FIELDS_LABEL, err)
+    }
+}
+
+impl From<&serde_json::Error> for ApiError {
+    fn from(err: &serde_json::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "response.error", err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ApiError, AuthError, ConfigError, DatabaseError};
+    use bootstrap::conditional::PreconditionError;
+    use http::StatusCode;
+    use serde_json::json;
+    use user_persist::persistence::PersistenceError;
+
+    #[test]
+    fn test_persistence_errors() {
+        let error = |err: PersistenceError| ApiError::from(&err);
+
+        assert_eq!(
+            error(PersistenceError::Timeout).status,
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            error(PersistenceError::DuplicateKey).status,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            error(PersistenceError::BulkLimitExceeded {
+                matched: 2,
+                limit: 1
+            })
+            .status,
+            StatusCode::BAD_REQUEST
+        );
+        let unavailable = error(PersistenceError::TestError);
+        assert_eq!(unavailable.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(unavailable.label, "persistence.error");
+        assert_eq!(unavailable.message, DatabaseError::Unavailable.to_string());
+    }
+
+    #[test]
+    fn test_envelope() {
+        assert_eq!(
+            ApiError::from(&PreconditionError::Failed).envelope(Some("abc")),
+            json!({
+                "label": "precondition.failed",
+                "message": "The user was changed or removed since it was read",
+                "requestId": "abc",
+            })
+        );
+        assert_eq!(
+            ApiError::from(ConfigError::Disabled("Aggregation")).envelope(None),
+            json!({
+                "label": "feature.disabled",
+                "message": "Aggregation is not enabled",
+                "requestId": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_auth_errors() {
+        let expired = ApiError::from(AuthError::Expired);
+        assert_eq!(expired.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(expired.envelope(None)["reason"], json!("expired"));
+        assert!(AuthError::Expired.challenge().is_some());
+
+        let forbidden = ApiError::from(AuthError::WrongRole);
+        assert_eq!(forbidden.status, StatusCode::FORBIDDEN);
+        assert_eq!(forbidden.label, "unauthorized");
+        assert_eq!(AuthError::WrongRole.challenge(), None);
+    }
+}
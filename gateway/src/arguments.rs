@@ -0,0 +1,128 @@
+/*!
+Command line arguments for the gateway.
+*/
+use bootstrap::claims::ClaimsArgs;
+use clap::Parser;
+use http::Uri;
+use secrecy::SecretString;
+use std::{path::PathBuf, str::FromStr};
+use thiserror::Error;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct GatewayArgs {
+    #[clap(long)]
+    #[clap(help = "ssl tls key file")]
+    pub tls_key_file: PathBuf,
+    #[clap(long)]
+    #[clap(help = "ssl tls certificate file")]
+    pub tls_cert_file: PathBuf,
+    #[clap(long, default_value = "9443")]
+    #[clap(help = "Port the gateway listens on")]
+    pub port: u16,
+    #[clap(long)]
+    #[clap(help = "JWT Secret")]
+    pub jwt_secret: SecretString,
+    #[clap(flatten)]
+    pub claims: ClaimsArgs,
+    #[clap(long = "backend", required = true)]
+    #[clap(help = "Backend as NAME[:WEIGHT]=URL, may be repeated")]
+    pub backends: Vec<Backend>,
+    #[clap(long)]
+    #[clap(help = "Check the configuration, print a JSON report and exit")]
+    pub check: bool,
+}
+
+/// Invalid backend argument.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BackendError {
+    #[error("backend `{0}` must be given as NAME[:WEIGHT]=URL")]
+    Format(String),
+    #[error("invalid weight in backend `{0}`")]
+    Weight(String),
+    #[error("invalid URL in backend `{0}`")]
+    Url(String),
+}
+
+/// A service requests are proxied to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backend {
+    /// Name matched against the `x-backend` header.
+    pub name: String,
+    /// Share of requests routed to this backend.
+    pub weight: u32,
+    /// Scheme and authority requests are sent to.
+    pub url: Uri,
+}
+
+impl FromStr for Backend {
+    type Err = BackendError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, url) = s
+            .split_once('=')
+            .ok_or_else(|| BackendError::Format(s.to_owned()))?;
+        let (name, weight) = match name.split_once(':') {
+            Some((name, weight)) => (
+                name,
+                weight
+                    .parse()
+                    .map_err(|_| BackendError::Weight(s.to_owned()))?,
+            ),
+            None => (name, 1),
+        };
+        if name.is_empty() {
+            return Err(BackendError::Format(s.to_owned()));
+        }
+        let url = url
+            .parse::<Uri>()
+            .ok()
+            .filter(|url| url.scheme().is_some() && url.authority().is_some())
+            .ok_or_else(|| BackendError::Url(s.to_owned()))?;
+
+        Ok(Self {
+            name: name.to_owned(),
+            weight,
+            url,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backend, BackendError};
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(
+            "axum:3=https://localhost:8443".parse(),
+            Ok(Backend {
+                name: "axum".to_owned(),
+                weight: 3,
+                url: "https://localhost:8443".parse().unwrap(),
+            })
+        );
+        assert_eq!(
+            "rocket=http://localhost:8000"
+                .parse::<Backend>()
+                .map(|b| b.weight),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_backend() {
+        assert_eq!(
+            "axum".parse::<Backend>(),
+            Err(BackendError::Format("axum".to_owned()))
+        );
+        assert_eq!(
+            "axum:x=http://localhost".parse::<Backend>(),
+            Err(BackendError::Weight("axum:x=http://localhost".to_owned()))
+        );
+        assert_eq!(
+            "axum=localhost".parse::<Backend>(),
+            Err(BackendError::Url("axum=localhost".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,247 @@
+/*!
+Gateway proxying requests to the framework implementations.
+
+TLS is terminated once at the gateway. Each request has its JWT
+validated at the edge against the same [`ClaimsPolicy`] as the services
+and is then proxied to a backend chosen by weight or by the `x-backend`
+request header. The public routes of the services, such as login, are
+proxied without a token.
+*/
+use crate::routing::{Backends, BACKEND_HEADER};
+use axum::{
+    body::{boxed, Body},
+    extract::State,
+    http::{
+        header::{AUTHORIZATION, HOST, WWW_AUTHENTICATE},
+        uri::PathAndQuery,
+        HeaderMap, HeaderValue, Method, Request, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use bootstrap::{
+    claims::{ClaimsPolicy, RegisteredClaims},
+    route_policy,
+};
+use hyper::client::HttpConnector;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tracing::{event, Level};
+
+pub mod arguments;
+pub mod routing;
+
+/// Tracing target for the gateway.
+pub const GATEWAY_TARGET: &str = "gateway";
+
+/// Claims checked at the edge. Roles are enforced by the backends.
+#[derive(Deserialize, Debug)]
+struct EdgeClaims {
+    sub: String,
+    exp: i64,
+    #[serde(default)]
+    iat: Option<i64>,
+    #[serde(default)]
+    aud: Option<String>,
+    #[serde(default)]
+    iss: Option<String>,
+}
+
+/// Error type for requests the gateway can't proxy.
+#[derive(Debug, Error)]
+pub enum GatewayError {
+    #[error("Missing authorization")]
+    MissingAuth,
+    #[error("Invalid token")]
+    InvalidToken,
+    #[error("Invalid upstream URI: `{0}`")]
+    InvalidUri(#[from] http::Error),
+    #[error("Backend `{0}` failed: `{1}`")]
+    Backend(String, hyper::Error),
+}
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        event!(
+          target: GATEWAY_TARGET,
+          Level::ERROR,
+          "Gateway error: {self}"
+        );
+        let (status, label) = match self {
+            Self::MissingAuth | Self::InvalidToken => (StatusCode::UNAUTHORIZED, "not.authorized"),
+            Self::InvalidUri(_) => (StatusCode::BAD_REQUEST, "invalid.uri"),
+            Self::Backend(..) => (StatusCode::BAD_GATEWAY, "bad.gateway"),
+        };
+        let body = Json(json!({
+          "label": label,
+          "message": self.to_string(),
+        }));
+        let mut response = (status, body).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+/// State shared by gateway requests.
+#[derive(Clone)]
+pub struct GatewayState {
+    backends: Arc<Backends>,
+    decoding_key: Arc<DecodingKey>,
+    claims: Arc<ClaimsPolicy>,
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+}
+
+impl GatewayState {
+    /// Create gateway state validating tokens signed with `jwt_secret`.
+    pub fn new(backends: Backends, jwt_secret: &[u8]) -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Self {
+            backends: Arc::new(backends),
+            decoding_key: Arc::new(DecodingKey::from_secret(jwt_secret)),
+            claims: Arc::new(ClaimsPolicy::default()),
+            client: hyper::Client::builder().build(connector),
+        }
+    }
+
+    /// Check the registered claims of tokens against `claims`.
+    pub fn with_claims_policy(self, claims: ClaimsPolicy) -> Self {
+        Self {
+            claims: Arc::new(claims),
+            ..self
+        }
+    }
+}
+
+/// Validate the bearer token signature and its registered claims.
+fn authorize(
+    headers: &HeaderMap,
+    key: &DecodingKey,
+    policy: &ClaimsPolicy,
+) -> Result<EdgeClaims, GatewayError> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(GatewayError::MissingAuth)?;
+
+    // Expiry is checked with the leeway of the policy rather than by the
+    // decoder.
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+
+    let claims = decode::<EdgeClaims>(token, key, &validation)
+        .map(|t| t.claims)
+        .map_err(|_| GatewayError::InvalidToken)?;
+
+    let registered = RegisteredClaims {
+        exp: claims.exp,
+        iat: claims.iat,
+        aud: claims.aud.as_deref(),
+        iss: claims.iss.as_deref(),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| i64::try_from(d.as_secs()).unwrap_or(i64::MAX));
+    policy
+        .validate(registered, now)
+        .map_err(|_| GatewayError::InvalidToken)?;
+    Ok(claims)
+}
+
+/// Same path and query on the backend.
+fn upstream_uri(backend: &Uri, uri: &Uri) -> Result<Uri, GatewayError> {
+    let path = uri.path_and_query().map_or("/", PathAndQuery::as_str);
+    let mut parts = backend.clone().into_parts();
+    parts.path_and_query = Some(
+        format!("{}{path}", backend.path().trim_end_matches('/'))
+            .parse()
+            .map_err(http::Error::from)?,
+    );
+    Uri::from_parts(parts).map_err(|e| GatewayError::InvalidUri(e.into()))
+}
+
+/// Proxy a request to the selected backend.
+async fn proxy(State(state): State<GatewayState>, mut req: Request<Body>) -> Response {
+    // OPTIONS only describes a resource and doesn't require a token, nor
+    // do the public routes of the services such as login.
+    if req.method() != Method::OPTIONS && !route_policy::is_public(req.uri().path()) {
+        match authorize(req.headers(), &state.decoding_key, &state.claims) {
+            Ok(claims) => event!(
+              target: GATEWAY_TARGET,
+              Level::DEBUG,
+              "Authorized {}",
+              claims.sub
+            ),
+            Err(e) => return e.into_response(),
+        }
+    }
+
+    let backend = state.backends.select(req.headers());
+    let uri = match upstream_uri(&backend.url, req.uri()) {
+        Ok(uri) => uri,
+        Err(e) => return e.into_response(),
+    };
+
+    event!(
+      target: GATEWAY_TARGET,
+      Level::DEBUG,
+      "{} {} -> {} {uri}",
+      req.method(),
+      req.uri(),
+      backend.name
+    );
+
+    *req.uri_mut() = uri;
+    req.headers_mut().remove(HOST);
+    req.headers_mut().remove(BACKEND_HEADER);
+
+    let mut response = match state.client.request(req).await {
+        Ok(response) => response.map(boxed),
+        Err(e) => return GatewayError::Backend(backend.name.clone(), e).into_response(),
+    };
+    if let Ok(name) = HeaderValue::from_str(&backend.name) {
+        response.headers_mut().insert(BACKEND_HEADER, name);
+    }
+    response
+}
+
+/// Builds the gateway routes. Every request is proxied.
+pub fn build_gateway(state: GatewayState) -> Router {
+    Router::new().fallback(proxy).with_state(state)
+}
+
+#[cfg(test)]
+mod test {
+    use super::upstream_uri;
+    use axum::http::Uri;
+
+    #[test]
+    fn test_upstream_uri() {
+        let backend = "https://localhost:8443".parse::<Uri>().unwrap();
+        assert_eq!(
+            upstream_uri(&backend, &"/api/v1/user/1?x=1".parse().unwrap()).unwrap(),
+            "https://localhost:8443/api/v1/user/1?x=1"
+        );
+
+        let backend = "http://localhost:8000/prefix/".parse::<Uri>().unwrap();
+        assert_eq!(
+            upstream_uri(&backend, &"/api/v1/user".parse().unwrap()).unwrap(),
+            "http://localhost:8000/prefix/api/v1/user"
+        );
+    }
+}
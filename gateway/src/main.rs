@@ -0,0 +1,56 @@
+use axum_server::tls_rustls::RustlsConfig;
+use bootstrap::check::CheckReport;
+use clap::Parser;
+use gateway::{arguments::GatewayArgs, build_gateway, routing::Backends, GatewayState};
+use secrecy::ExposeSecret;
+use std::{error::Error, net::SocketAddr};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_target(true)
+        .init();
+
+    let args = GatewayArgs::parse();
+    if args.check {
+        self_check(args).exit();
+    }
+
+    for backend in &args.backends {
+        info!(
+            "Backend {} weight {} at {}",
+            backend.name, backend.weight, backend.url
+        );
+    }
+
+    let state = GatewayState::new(
+        Backends::new(args.backends)?,
+        args.jwt_secret.expose_secret().as_bytes(),
+    )
+    .with_claims_policy(args.claims.policy());
+
+    let config = RustlsConfig::from_pem_file(&args.tls_cert_file, &args.tls_key_file).await?;
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
+    axum_server::bind_rustls(addr, config)
+        .serve(build_gateway(state).into_make_service())
+        .await
+        .map(Ok)?
+}
+
+/// Run the startup self-check. The gateway has no database.
+fn self_check(args: GatewayArgs) -> CheckReport {
+    let mut report = CheckReport::default();
+    report.record("config", Backends::new(args.backends));
+    report.tls_files(Some((
+        args.tls_cert_file.as_path(),
+        args.tls_key_file.as_path(),
+    )));
+    report.jwt_secret(Some(args.jwt_secret.expose_secret().as_bytes()));
+    report.skip("mongo", "no database");
+    report.skip("migrations", "no database");
+    report
+}
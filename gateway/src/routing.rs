@@ -0,0 +1,129 @@
+/*!
+Backend selection.
+*/
+use crate::arguments::Backend;
+use http::HeaderMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+
+/// Header naming the backend a request is routed to. Clients may set it
+/// to pin a request to one backend and it is set on every response.
+pub const BACKEND_HEADER: &str = "x-backend";
+
+/// Invalid backend configuration.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RoutingError {
+    #[error("at least one backend needs a weight greater than zero")]
+    NoWeightedBackend,
+    #[error("backend `{0}` is configured more than once")]
+    DuplicateBackend(String),
+}
+
+/// Backends with weighted round robin selection.
+#[derive(Debug)]
+pub struct Backends {
+    backends: Vec<Backend>,
+    total_weight: u64,
+    count: AtomicU64,
+}
+
+impl Backends {
+    pub fn new(backends: Vec<Backend>) -> Result<Self, RoutingError> {
+        for (i, backend) in backends.iter().enumerate() {
+            if backends[..i].iter().any(|b| b.name == backend.name) {
+                return Err(RoutingError::DuplicateBackend(backend.name.clone()));
+            }
+        }
+        let total_weight = backends.iter().map(|b| u64::from(b.weight)).sum();
+        if total_weight == 0 {
+            return Err(RoutingError::NoWeightedBackend);
+        }
+        Ok(Self {
+            backends,
+            total_weight,
+            count: AtomicU64::default(),
+        })
+    }
+
+    /// Backend named by the request's `x-backend` header, otherwise the
+    /// next backend by weight.
+    pub fn select(&self, headers: &HeaderMap) -> &Backend {
+        headers
+            .get(BACKEND_HEADER)
+            .and_then(|name| name.to_str().ok())
+            .and_then(|name| self.backends.iter().find(|b| b.name == name))
+            .unwrap_or_else(|| self.weighted())
+    }
+
+    fn weighted(&self) -> &Backend {
+        let mut slot = self.count.fetch_add(1, Ordering::Relaxed) % self.total_weight;
+        for backend in &self.backends {
+            match slot.checked_sub(u64::from(backend.weight)) {
+                Some(rest) => slot = rest,
+                None => return backend,
+            }
+        }
+        unreachable!("slot is always below the total weight")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backends, RoutingError, BACKEND_HEADER};
+    use crate::arguments::Backend;
+    use http::HeaderMap;
+
+    fn backends(specs: &[&str]) -> Result<Backends, RoutingError> {
+        Backends::new(
+            specs
+                .iter()
+                .map(|s| s.parse::<Backend>().unwrap())
+                .collect(),
+        )
+    }
+
+    fn names(backends: &Backends, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| backends.select(&HeaderMap::new()).name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_weighted_selection() {
+        let backends = backends(&[
+            "axum:2=http://localhost:1",
+            "rocket:1=http://localhost:2",
+            "actix:0=http://localhost:3",
+        ])
+        .unwrap();
+        assert_eq!(
+            names(&backends, 6),
+            ["axum", "axum", "rocket", "axum", "axum", "rocket"]
+        );
+    }
+
+    #[test]
+    fn test_header_override() {
+        let backends =
+            backends(&["axum=http://localhost:1", "actix:0=http://localhost:3"]).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(BACKEND_HEADER, "actix".parse().unwrap());
+        assert_eq!(backends.select(&headers).name, "actix");
+
+        // Unknown names fall back to weighted selection.
+        headers.insert(BACKEND_HEADER, "warp".parse().unwrap());
+        assert_eq!(backends.select(&headers).name, "axum");
+    }
+
+    #[test]
+    fn test_invalid_backends() {
+        assert_eq!(
+            backends(&["axum:0=http://localhost:1"]).unwrap_err(),
+            RoutingError::NoWeightedBackend
+        );
+        assert_eq!(
+            backends(&["axum=http://localhost:1", "axum=http://localhost:2"]).unwrap_err(),
+            RoutingError::DuplicateBackend("axum".to_owned())
+        );
+    }
+}
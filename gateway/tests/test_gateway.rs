@@ -0,0 +1,191 @@
+use axum::{
+    body::Body,
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        Method, Request, StatusCode,
+    },
+    Router,
+};
+use bootstrap::{claims::ClaimsPolicy, token::LOGIN_PATH};
+use gateway::{
+    arguments::Backend,
+    build_gateway,
+    routing::{Backends, BACKEND_HEADER},
+    GatewayState,
+};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Response, Server,
+};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::{json, Value};
+use std::{convert::Infallible, net::SocketAddr};
+use tower::ServiceExt;
+
+static SECRET: &[u8] = b"TEST_SECRET";
+
+/// Start a backend answering with its name and the path requested.
+fn backend(name: &'static str) -> Backend {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |req: Request<Body>| async move {
+            Ok::<_, Infallible>(Response::new(Body::from(format!("{name} {}", req.uri()))))
+        }))
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    format!("{name}=http://{addr}").parse().unwrap()
+}
+
+fn gateway(backends: Vec<Backend>) -> Router {
+    build_gateway(GatewayState::new(Backends::new(backends).unwrap(), SECRET))
+}
+
+fn jwt(secret: &[u8]) -> String {
+    jwt_with(secret, json!({}))
+}
+
+/// Token signed with `secret` with `extra` added to its claims.
+fn jwt_with(secret: &[u8], extra: Value) -> String {
+    let mut claims = json!({
+      "sub": "droberts",
+      "role": "Admin",
+      "exp": (chrono::Utc::now() + chrono::Duration::minutes(5)).timestamp(),
+    });
+    if let (Some(claims), Value::Object(extra)) = (claims.as_object_mut(), extra) {
+        claims.extend(extra);
+    }
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret),
+    )
+    .unwrap();
+    format!("Bearer {token}")
+}
+
+async fn body_string(response: axum::response::Response) -> String {
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn proxy_request() {
+    let response = gateway(vec![backend("axum")])
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/1?x=1")
+                .header(AUTHORIZATION, jwt(SECRET))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(BACKEND_HEADER).unwrap(), "axum");
+    assert_eq!(body_string(response).await, "axum /api/v1/user/1?x=1");
+}
+
+#[tokio::test]
+async fn proxy_header_override() {
+    let mut rocket = backend("rocket");
+    rocket.weight = 0;
+
+    let response = gateway(vec![backend("axum"), rocket])
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/counts")
+                .header(AUTHORIZATION, jwt(SECRET))
+                .header(BACKEND_HEADER, "rocket")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.headers().get(BACKEND_HEADER).unwrap(), "rocket");
+    assert_eq!(body_string(response).await, "rocket /api/v1/user/counts");
+}
+
+#[tokio::test]
+async fn reject_invalid_token() {
+    let gateway = gateway(vec![backend("axum")]);
+
+    let response = gateway
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+
+    let response = gateway
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/1")
+                .header(AUTHORIZATION, jwt(b"OTHER_SECRET"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.headers().get(BACKEND_HEADER).is_none());
+}
+
+#[tokio::test]
+async fn proxy_login_without_token() {
+    let response = gateway(vec![backend("axum")])
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(LOGIN_PATH)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_string(response).await, format!("axum {LOGIN_PATH}"));
+}
+
+#[tokio::test]
+async fn reject_wrong_audience() {
+    let gateway = build_gateway(
+        GatewayState::new(Backends::new(vec![backend("axum")]).unwrap(), SECRET)
+            .with_claims_policy(ClaimsPolicy {
+                audience: Some("users".to_owned()),
+                ..ClaimsPolicy::default()
+            }),
+    );
+    let request = |token: String| {
+        Request::builder()
+            .uri("/api/v1/user/1")
+            .header(AUTHORIZATION, token)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = gateway
+        .clone()
+        .oneshot(request(jwt_with(SECRET, json!({"aud": "other"}))))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = gateway.clone().oneshot(request(jwt(SECRET))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = gateway
+        .oneshot(request(jwt_with(SECRET, json!({"aud": "users"}))))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
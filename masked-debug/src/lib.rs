@@ -0,0 +1,88 @@
+/*!
+Derive macro for `Debug` implementations that mask personally identifiable
+fields.
+
+Fields annotated with `#[masked]` are formatted through
+`user_persist::masked::MaskedDebugField` while all other fields use their
+own `Debug` implementation.
+
+```ignore
+#[derive(MaskedDebug)]
+pub struct User {
+    pub id: Option<UserKey>,
+    #[masked]
+    pub name: String,
+}
+```
+*/
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Field, Fields, Index};
+
+/// Derive `Debug` masking fields annotated with `#[masked]`.
+#[proc_macro_derive(MaskedDebug, attributes(masked))]
+pub fn derive_masked_debug(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let name_str = name.to_string();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "MaskedDebug can only be derived for structs",
+            ))
+        }
+    };
+
+    let body = match fields {
+        Fields::Named(named) => {
+            let entries = named.named.iter().map(|field| {
+                let ident = field.ident.as_ref().expect("named field");
+                let label = ident.to_string();
+                let value = field_value(field, quote!(self.#ident));
+                quote!(.field(#label, #value))
+            });
+            quote!(f.debug_struct(#name_str) #(#entries)* .finish())
+        }
+        Fields::Unnamed(unnamed) => {
+            let entries = unnamed.unnamed.iter().enumerate().map(|(i, field)| {
+                let index = Index::from(i);
+                let value = field_value(field, quote!(self.#index));
+                quote!(.field(#value))
+            });
+            quote!(f.debug_tuple(#name_str) #(#entries)* .finish())
+        }
+        Fields::Unit => quote!(f.write_str(#name_str)),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::std::fmt::Debug for #name #ty_generics #where_clause {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                #body
+            }
+        }
+    })
+}
+
+/// Expression used to format a field.
+fn field_value(field: &Field, access: TokenStream2) -> TokenStream2 {
+    if field
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("masked"))
+    {
+        quote!(&::user_persist::masked::MaskedDebugField(&#access))
+    } else {
+        quote!(&#access)
+    }
+}
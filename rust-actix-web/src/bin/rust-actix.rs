@@ -1,65 +1,171 @@
 use actix_web::{web, App, HttpServer};
+use bootstrap::{
+    install_panic_hook, metrics::install_metrics_exporter, token::LOGIN_PATH, DEV_TOKEN_PATH,
+};
 use clap::Parser;
 use rust_actix_web::{
     common::USER_MS_TARGET,
     handlers, init_tls,
-    middleware::{create_test_jwt, JwtAuth},
+    middleware::{BodyReadTimeout, CatchPanic, JwtAuth, PrincipalRootSpan, RequestTimer},
     types::Role,
     ProgramArgs,
 };
-use std::{process, sync::Arc};
+use std::{net::SocketAddr, process, sync::Arc};
 use tracing::{event, Level};
 use tracing_actix_web::TracingLogger;
-use tracing_subscriber::EnvFilter;
-use user_persist::{mongo_persistence::MongoPersistence, persistence::UserPersistence};
+use user_persist::{
+    clock::SystemClock, kv::MongoKvStore, mongo_persistence::MongoPersistence,
+    persistence::UserPersistence,
+};
 
 #[actix_web::main]
 async fn main() -> Result<(), std::io::Error> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_target(true)
-        .pretty()
-        // .json()
-        // .flatten_event(true)
-        .init();
-
     let program_opts = ProgramArgs::parse();
+    if program_opts.bootstrap.check {
+        program_opts.self_check().await.exit();
+    }
+
+    let bootstrap = match program_opts.bootstrap() {
+        Ok(bootstrap) => bootstrap,
+        Err(e) => {
+            eprintln!("Invalid startup settings: {e}");
+            process::exit(1);
+        }
+    };
+    bootstrap.init_tracing();
+
+    install_panic_hook();
 
     let tls_opts = init_tls(&program_opts);
 
-    event!(
-      target: USER_MS_TARGET,
-      Level::DEBUG,
-      "Test admin jwt: {}",
-      create_test_jwt(Role::Admin).unwrap()
-    );
+    if let Err(e) =
+        install_metrics_exporter(SocketAddr::from(([0, 0, 0, 0], program_opts.metrics_port)))
+    {
+        event!(Level::ERROR, "Failed to install metrics exporter: {}", e);
+        process::exit(1);
+    }
 
     event!(
       target: USER_MS_TARGET,
-      Level::DEBUG,
-      "Test user jwt: {}",
-      create_test_jwt(Role::User).unwrap()
+      Level::INFO,
+      "Starting with the {} profile",
+      bootstrap.profile
     );
 
-    match MongoPersistence::new(program_opts.mongo_opts).await {
+    let dev_tokens = bootstrap.dev_tokens;
+    if dev_tokens {
+        event!(
+          target: USER_MS_TARGET,
+          Level::WARN,
+          "Dev token endpoint enabled at {DEV_TOKEN_PATH}"
+        );
+    }
+
+    let token_issuer = match program_opts.tokens.issuer::<Role>() {
+        Ok(issuer) => issuer,
+        Err(e) => {
+            eprintln!("Invalid accounts: {e}");
+            process::exit(1);
+        }
+    };
+    if token_issuer.is_some() {
+        event!(
+          target: USER_MS_TARGET,
+          Level::INFO,
+          "Login endpoint enabled at {LOGIN_PATH}"
+        );
+    }
+
+    let route_policy = match program_opts.route_policy.policy() {
+        Ok(policy) => web::Data::new(policy),
+        Err(e) => {
+            eprintln!("Invalid route policy: {e}");
+            process::exit(1);
+        }
+    };
+
+    let mongo_opts = program_opts
+        .mongo_opts
+        .with_allow_invalid_certificates(bootstrap.allow_invalid_certificates);
+
+    let claims_policy = program_opts.claims.policy();
+    let preconditions = program_opts.conditional.preconditions();
+    let read_timeouts = program_opts.read_timeouts();
+    let route_read_timeouts = program_opts.route_read_timeouts();
+
+    match MongoPersistence::new(mongo_opts).await {
         Ok(persistence) => {
-            HttpServer::new(move || {
+            // Refresh tokens are shared by every replica.
+            let token_issuer = match token_issuer {
+                Some(issuer) => {
+                    let kv_store = MongoKvStore::new(&persistence, Arc::new(SystemClock));
+                    kv_store
+                        .ensure_indexes()
+                        .await
+                        .map_err(std::io::Error::other)?;
+                    Some(web::Data::new(issuer.with_kv_store(Arc::new(kv_store))))
+                }
+                None => None,
+            };
+            let server = HttpServer::new(move || {
                 let persist: web::Data<Arc<dyn UserPersistence>> =
                     web::Data::new(Arc::new(persistence.clone()));
                 App::new()
                     .app_data(persist)
-                    .wrap(JwtAuth::default())
-                    .wrap(TracingLogger::default())
+                    .app_data(web::Data::new(claims_policy.clone()))
+                    .app_data(route_policy.clone())
+                    .app_data(web::Data::new(preconditions))
+                    .app_data(handlers::json_config())
+                    .wrap(BodyReadTimeout::new(
+                        read_timeouts,
+                        route_read_timeouts.clone(),
+                    ))
+                    .wrap(CatchPanic)
+                    .wrap(JwtAuth::new(Arc::new(SystemClock), claims_policy.clone()))
+                    .wrap(TracingLogger::<PrincipalRootSpan>::new())
+                    .wrap(RequestTimer::default())
                     .service(
                         web::scope("/api/v1/user")
                             .service(handlers::count_users)
                             .service(handlers::search_users)
                             .service(handlers::get_user)
+                            .service(handlers::get_user_by_email)
                             .service(handlers::save_user)
-                            .service(handlers::update_user),
+                            .service(handlers::update_user)
+                            .service(handlers::options),
                     )
+                    // Registered ahead of the dev scope, which would answer
+                    // every path under it.
+                    .configure(|cfg| {
+                        if let Some(issuer) = &token_issuer {
+                            cfg.service(
+                                web::scope("/api/v1/auth")
+                                    .app_data(issuer.clone())
+                                    .service(handlers::login)
+                                    .service(handlers::refresh),
+                            );
+                        }
+                    })
+                    .configure(|cfg| {
+                        if dev_tokens {
+                            cfg.service(web::scope("/api/v1").service(handlers::dev_token));
+                        }
+                    })
             })
-            .bind_openssl("127.0.0.1:8443", tls_opts)?
+            // Headers trickled in are cut off by the server, bodies by the
+            // read timeout middleware.
+            .client_request_timeout(read_timeouts.read_timeout);
+            match tls_opts {
+                Some(tls_opts) => server.bind_openssl("127.0.0.1:8443", tls_opts)?,
+                None => {
+                    event!(
+                      target: USER_MS_TARGET,
+                      Level::WARN,
+                      "Serving plain HTTP without a TLS certificate"
+                    );
+                    server.bind("127.0.0.1:8443")?
+                }
+            }
             .run()
             .await
         }
@@ -1,22 +1,69 @@
 use crate::{
     common::USER_MS_TARGET,
-    types::{AdminAccess, HandlerError, UserAccess},
+    middleware::sign_jwt,
+    types::{
+        AdminAccess, FieldsParams, HandlerError, JWTClaims, Role, SearchParams,
+        UnsupportedMediaType, UserAccess,
+    },
 };
 use actix_http::{ResponseBuilder, StatusCode};
-use actix_web::{get, post, put, web, Responder, Result};
+use actix_web::{
+    error::JsonPayloadError, http::header, post, put, route, web, HttpRequest, HttpResponse,
+    Responder, Result,
+};
+use api_types::{CreateUserRequest, SearchRequest, UserResponse};
+use bootstrap::{
+    claims::ClaimsPolicy,
+    conditional::{etag, PreconditionError, Preconditions},
+    content_type::{check_json, ContentTypeError},
+    fields::shape,
+    token::{Grant, LoginRequest, RefreshRequest, TokenIssuer, TokenResponse},
+    DevTokenRequest, DevTokenResponse,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
 use std::sync::Arc;
 use tracing::{event, Level};
+use tracing_actix_web::RequestId;
 use user_persist::{
-    persistence::UserPersistence,
-    types::{UpdateUser, User, UserKey, UserSearch},
+    persistence::{search_capped, CappedSearch, UserPersistence, DEFAULT_MAX_SEARCH_RESULTS},
+    streaming::{encode_ndjson, StreamErrorPolicy},
+    types::{Email, UpdateUser, User, UserKey, UserSearch},
 };
 
 type Persist = web::Data<Arc<dyn UserPersistence>>;
 
-#[get("{id}")]
+/// Content type of newline delimited JSON.
+const NDJSON: &str = "application/x-ndjson";
+
+/// JSON extractor configuration answering a body with another or no content
+/// type with a 415 in the standard error envelope.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, req| match err {
+        JsonPayloadError::ContentType => {
+            let content_type = req
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok());
+            let source = check_json(content_type).err().unwrap_or_else(|| {
+                ContentTypeError::Unsupported(content_type.unwrap_or_default().to_owned())
+            });
+            UnsupportedMediaType {
+                source,
+                request_id: req.extensions().get::<RequestId>().map(|id| id.to_string()),
+            }
+            .into()
+        }
+        err => err.into(),
+    })
+}
+
+/// Lookup a user, tagged with an `ETag` for conditional updates.
+#[route("{id}", method = "GET", method = "HEAD")]
 pub async fn get_user(
     db: Persist,
     id: web::Path<UserKey>,
+    params: web::Query<FieldsParams>,
     claims: AdminAccess,
 ) -> Result<impl Responder, HandlerError> {
     event!(
@@ -29,12 +76,43 @@ pub async fn get_user(
 
     event!(target: USER_MS_TARGET, Level::DEBUG, "db result: {user:?}");
 
-    Ok(web::Json(user))
+    let mut response = HttpResponse::Ok();
+    if let Some(user) = &user {
+        response.insert_header((header::ETAG, etag(user)?));
+    }
+    let user = user.map(UserResponse::from);
+    Ok(response.json(shape(params.fields.as_ref(), &user)?))
+}
+
+/// Lookup the user with an email, ignoring case.
+#[route("by-email/{email}", method = "GET", method = "HEAD")]
+pub async fn get_user_by_email(
+    db: Persist,
+    email: web::Path<String>,
+    params: web::Query<FieldsParams>,
+    claims: AdminAccess,
+) -> Result<impl Responder, HandlerError> {
+    let email = Email(email.into_inner());
+    event!(
+      target: USER_MS_TARGET,
+      Level::DEBUG,
+      "Received email: {email} with claims: {claims:?}"
+    );
+
+    let user = db
+        .get_user_by_email(&email)
+        .await?
+        .ok_or(HandlerError::UserNotFound)?;
+
+    Ok(web::Json(shape(
+        params.fields.as_ref(),
+        &UserResponse::from(user),
+    )?))
 }
 
 #[post("")]
 pub async fn save_user(
-    user: web::Json<User>,
+    user: web::Json<CreateUserRequest>,
     db: Persist,
     _claims: UserAccess,
 ) -> Result<impl Responder, HandlerError> {
@@ -43,14 +121,18 @@ pub async fn save_user(
       Level::DEBUG,
       "saving user: {user:?}"
     );
-    let saved_user = db.save_user(&user).await?;
-    Ok(web::Json(saved_user))
+    let saved_user = db.save_user(&User::from(user.into_inner())).await?;
+    Ok(web::Json(UserResponse::from(saved_user)))
 }
 
+/// Update a user. An `If-Match` header must match the `ETag` of the stored
+/// user, and is required in strict mode.
 #[put("")]
 pub async fn update_user(
     db: Persist,
+    req: HttpRequest,
     user: web::Json<UpdateUser>,
+    preconditions: Option<web::Data<Preconditions>>,
     _claims: AdminAccess,
 ) -> Result<impl Responder, HandlerError> {
     event!(
@@ -58,26 +140,77 @@ pub async fn update_user(
       Level::DEBUG,
       "updating user with {user:?}"
     );
-    db.update_user(&user).await?;
+    let preconditions = preconditions.as_deref().copied().unwrap_or_default();
+    // A header that isn't visible ASCII matches no entity tag.
+    let if_match = req
+        .headers()
+        .get(header::IF_MATCH)
+        .map(|value| value.to_str().unwrap_or_default());
+    let current = if preconditions.applies(if_match) {
+        let current = db.get_user(&user.id).await?;
+        let tag = current.as_ref().map(etag).transpose()?;
+        preconditions.check(if_match, tag.as_deref())?;
+        current
+    } else {
+        None
+    };
+    match current {
+        // A user changed since it was checked isn't overwritten.
+        Some(current) => {
+            if !db.update_user_if_unchanged(&user, &current).await? {
+                return Err(PreconditionError::Failed.into());
+            }
+        }
+        None => db.update_user(&user).await?,
+    }
     Ok(ResponseBuilder::new(StatusCode::OK))
 }
 
 #[post("/search")]
 pub async fn search_users(
-    user_search: web::Json<UserSearch>,
+    user_search: web::Json<SearchRequest>,
+    params: web::Query<SearchParams>,
     db: Persist,
     _claims: AdminAccess,
-) -> Result<impl Responder, HandlerError> {
+) -> Result<HttpResponse, HandlerError> {
+    let user_search = UserSearch::from(user_search.into_inner());
     event!(
       target: USER_MS_TARGET,
       Level::DEBUG,
       "Searching for users with {user_search:?}"
     );
-    let results = db.search_users(&user_search).await?;
-    Ok(web::Json(results))
+    if user_search.is_empty() && !params.all {
+        return Err(HandlerError::UnfilteredSearch);
+    }
+    if params.stream {
+        // A failing stream aborts the response rather than truncating it.
+        let users = db
+            .search_users_stream(&user_search)
+            .await?
+            .map(|user| user.map(UserResponse::from));
+        let stream =
+            encode_ndjson(users, StreamErrorPolicy::Abort).map(|line| line.map(web::Bytes::from));
+        return Ok(HttpResponse::Ok().content_type(NDJSON).streaming(stream));
+    }
+    let CappedSearch { users, truncated } = search_capped(
+        db.get_ref().as_ref(),
+        &user_search,
+        DEFAULT_MAX_SEARCH_RESULTS,
+    )
+    .await?;
+    let users = users
+        .into_iter()
+        .map(UserResponse::from)
+        .collect::<Vec<_>>();
+    let users = shape(params.fields.as_ref(), &users)?;
+    if truncated {
+        Ok(HttpResponse::PartialContent().json(users))
+    } else {
+        Ok(HttpResponse::Ok().json(users))
+    }
 }
 
-#[get("counts")]
+#[route("counts", method = "GET", method = "HEAD")]
 pub async fn count_users(db: Persist, claims: AdminAccess) -> Result<impl Responder, HandlerError> {
     event!(target: USER_MS_TARGET, Level::DEBUG, "Claims: {claims:?}");
     let counts = db.count_genders().await?;
@@ -88,3 +221,113 @@ pub async fn count_users(db: Persist, claims: AdminAccess) -> Result<impl Respon
     );
     Ok(web::Json(counts))
 }
+
+/// Methods allowed for a path relative to the user scope.
+fn allowed_methods(path: &str) -> Option<&'static str> {
+    match path {
+        "" => Some("POST, PUT, OPTIONS"),
+        "counts" => Some("GET, HEAD, OPTIONS"),
+        "search" => Some("POST, OPTIONS"),
+        email
+            if email
+                .strip_prefix("by-email/")
+                .is_some_and(|email| !email.contains('/')) =>
+        {
+            Some("GET, HEAD, OPTIONS")
+        }
+        id if !id.contains('/') => Some("GET, HEAD, OPTIONS"),
+        _ => None,
+    }
+}
+
+#[route("{path:.*}", method = "OPTIONS")]
+pub async fn options(path: web::Path<String>) -> HttpResponse {
+    match allowed_methods(path.trim_start_matches('/')) {
+        Some(methods) => HttpResponse::NoContent()
+            .insert_header((header::ALLOW, methods))
+            .finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Mint a signed JWT for local testing. Only mounted when dev tokens are
+/// enabled.
+#[post("/dev/token")]
+pub async fn dev_token(
+    request: web::Json<DevTokenRequest<Role>>,
+    policy: Option<web::Data<ClaimsPolicy>>,
+) -> Result<impl Responder, HandlerError> {
+    let ttl = request.validate()?;
+    let request = request.into_inner();
+    let claims = JWTClaims::issue(
+        request.sub,
+        request.role,
+        Utc::now(),
+        Duration::seconds(ttl.as_secs() as i64),
+        policy.as_deref().unwrap_or(&ClaimsPolicy::default()),
+    );
+
+    event!(
+      target: USER_MS_TARGET,
+      Level::WARN,
+      "Minting dev token for {claims:?}"
+    );
+
+    Ok(web::Json(DevTokenResponse {
+        token: sign_jwt(&claims)?,
+        expires_at: claims.exp,
+    }))
+}
+
+/// Exchange the credentials of an account for tokens. Only mounted when an
+/// accounts file is configured.
+#[post("/login")]
+pub async fn login(
+    request: web::Json<LoginRequest>,
+    issuer: web::Data<TokenIssuer<Role>>,
+    policy: Option<web::Data<ClaimsPolicy>>,
+) -> Result<impl Responder, HandlerError> {
+    let now = Utc::now();
+    // The user name isn't logged as users mistype passwords into it.
+    let grant = issuer.login(&request, now.timestamp()).await.map_err(|e| {
+        event!(target: USER_MS_TARGET, Level::WARN, "Login failed: {e}");
+        e
+    })?;
+    Ok(web::Json(sign_grant(grant, now, policy.as_deref())?))
+}
+
+/// Exchange a refresh token for new tokens. Only mounted when an accounts
+/// file is configured.
+#[post("/refresh")]
+pub async fn refresh(
+    request: web::Json<RefreshRequest>,
+    issuer: web::Data<TokenIssuer<Role>>,
+    policy: Option<web::Data<ClaimsPolicy>>,
+) -> Result<impl Responder, HandlerError> {
+    let now = Utc::now();
+    let grant = issuer
+        .refresh(&request, now.timestamp())
+        .await
+        .map_err(|e| {
+            event!(target: USER_MS_TARGET, Level::WARN, "Refresh failed: {e}");
+            e
+        })?;
+    Ok(web::Json(sign_grant(grant, now, policy.as_deref())?))
+}
+
+/// Sign the access token of `grant`.
+fn sign_grant(
+    grant: Grant<Role>,
+    now: DateTime<Utc>,
+    policy: Option<&ClaimsPolicy>,
+) -> Result<TokenResponse, HandlerError> {
+    let claims = JWTClaims::issue(
+        grant.sub.clone(),
+        grant.role,
+        now,
+        Duration::seconds(grant.access_ttl.as_secs() as i64),
+        policy.unwrap_or(&ClaimsPolicy::default()),
+    );
+    let access_token = sign_jwt(&claims)?;
+    Ok(grant.response(access_token, claims.exp))
+}
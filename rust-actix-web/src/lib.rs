@@ -1,6 +1,12 @@
+use bootstrap::{
+    body_timeout::ReadTimeouts, check::CheckReport, claims::ClaimsArgs,
+    conditional::ConditionalArgs, route_policy::RoutePolicyArgs, token::TokenArgs, Bootstrap,
+    BootstrapArgs, BootstrapError, Configured,
+};
 use clap::Parser;
+use middleware::TEST_JWT_SECRET;
 use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 use user_persist::MongoArgs;
 
 pub mod common;
@@ -14,19 +20,103 @@ pub mod types;
 pub struct ProgramArgs {
     #[clap(flatten)]
     pub mongo_opts: MongoArgs,
-    #[clap(long)]
-    server_tls_key_file: PathBuf,
-    #[clap(long)]
-    server_tls_cert_file: PathBuf,
+    #[clap(flatten)]
+    pub bootstrap: BootstrapArgs,
+    #[clap(flatten)]
+    pub claims: ClaimsArgs,
+    #[clap(flatten)]
+    pub conditional: ConditionalArgs,
+    #[clap(flatten)]
+    pub tokens: TokenArgs,
+    #[clap(flatten)]
+    pub route_policy: RoutePolicyArgs,
+    #[clap(long, requires = "server_tls_cert_file")]
+    server_tls_key_file: Option<PathBuf>,
+    #[clap(long, requires = "server_tls_key_file")]
+    server_tls_cert_file: Option<PathBuf>,
+    #[clap(long, default_value = "9100")]
+    pub metrics_port: u16,
+    #[clap(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    #[clap(help = "Seconds a request body may go without sending data")]
+    body_read_timeout_secs: u64,
+    #[clap(long, default_value = "1024")]
+    #[clap(help = "Slowest rate in bytes a second request bodies may arrive at, 0 disables it")]
+    body_min_bytes_per_sec: u64,
+    #[clap(long, use_value_delimiter = true, value_parser = parse_route_timeout)]
+    #[clap(help = "Comma separated ROUTE=SECONDS body read timeouts overriding the default")]
+    body_read_timeout_routes: Vec<(String, u64)>,
+}
+
+/// Parse a `ROUTE=SECONDS` read timeout.
+fn parse_route_timeout(value: &str) -> Result<(String, u64), String> {
+    value
+        .split_once('=')
+        .and_then(|(route, secs)| Some((route.to_owned(), secs.parse().ok()?)))
+        .filter(|(_, secs)| *secs > 0)
+        .ok_or_else(|| format!("invalid body read timeout `{value}`, expected ROUTE=SECONDS"))
 }
 
-pub fn init_tls(args: &ProgramArgs) -> SslAcceptorBuilder {
+impl ProgramArgs {
+    /// Resolve the startup settings for the deployment profile.
+    pub fn bootstrap(&self) -> Result<Bootstrap, BootstrapError> {
+        self.bootstrap.resolve(Configured {
+            jwt_secret: Some(TEST_JWT_SECRET),
+            tls: self.server_tls_key_file.is_some() && self.server_tls_cert_file.is_some(),
+            allow_invalid_certificates: self.mongo_opts.allow_invalid_certificates(),
+        })
+    }
+
+    /// Read timeouts of request bodies.
+    pub fn read_timeouts(&self) -> ReadTimeouts {
+        ReadTimeouts {
+            read_timeout: Duration::from_secs(self.body_read_timeout_secs),
+            min_bytes_per_sec: self.body_min_bytes_per_sec,
+        }
+    }
+
+    /// Read timeouts of routes overriding the default, by route pattern.
+    pub fn route_read_timeouts(&self) -> BTreeMap<String, Duration> {
+        self.body_read_timeout_routes
+            .iter()
+            .map(|(route, secs)| (route.clone(), Duration::from_secs(*secs)))
+            .collect()
+    }
+
+    /// Run the startup self-check.
+    pub async fn self_check(self) -> CheckReport {
+        let mut report = CheckReport::default();
+        let bootstrap = report.record("config", self.bootstrap());
+        report.record("accounts", self.tokens.issuer::<types::Role>());
+        report.record("route policy", self.route_policy.policy());
+        report.tls_files(
+            self.server_tls_cert_file
+                .as_deref()
+                .zip(self.server_tls_key_file.as_deref()),
+        );
+        report.jwt_secret(Some(TEST_JWT_SECRET));
+        let mongo_opts = match bootstrap {
+            Some(bootstrap) => self
+                .mongo_opts
+                .with_allow_invalid_certificates(bootstrap.allow_invalid_certificates),
+            None => self.mongo_opts,
+        };
+        report.mongo(mongo_opts).await;
+        report
+    }
+}
+
+/// Create the TLS acceptor when a certificate and key are configured.
+pub fn init_tls(args: &ProgramArgs) -> Option<SslAcceptorBuilder> {
+    let (key_file, cert_file) = args
+        .server_tls_key_file
+        .as_ref()
+        .zip(args.server_tls_cert_file.as_ref())?;
     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
     builder
-        .set_private_key_file(args.server_tls_key_file.as_path(), SslFiletype::PEM)
+        .set_private_key_file(key_file.as_path(), SslFiletype::PEM)
         .unwrap();
     builder
-        .set_certificate_chain_file(args.server_tls_cert_file.as_path())
+        .set_certificate_chain_file(cert_file.as_path())
         .unwrap();
-    builder
+    Some(builder)
 }
@@ -1,31 +1,52 @@
 use crate::common::FRAMEWORK_TARGET;
 use crate::types::{AdminAccess, JWTClaims, JWTError, Role, UserAccess};
+use actix_http::BoxedPayloadStream;
 use actix_service::{Service, Transform};
 use actix_web::{
     body::{BoxBody, MessageBody},
     dev::{Payload, ServiceRequest, ServiceResponse},
-    http::StatusCode,
-    FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+    error::PayloadError,
+    http::{header::WWW_AUTHENTICATE, Method, StatusCode},
+    web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use bootstrap::metrics::REQUEST_DURATION_METRIC;
+use bootstrap::{
+    body_timeout::{guard_body, BodyTimeout, ReadTimeouts, BODY_TIMEOUT_LABEL},
+    claims::ClaimsPolicy,
+    route_policy::{self, RoutePolicy},
+    DEV_TOKEN_PATH,
 };
 use chrono::{Duration, Utc};
+use errors::ApiError;
 use futures::{
     future::{ready, Ready},
-    Future,
+    Future, FutureExt,
 };
 use hmac::{Hmac, Mac};
 use jwt::{SignWithKey, VerifyWithKey};
+use secrecy::{ExposeSecret, SecretVec};
+use serde_json::json;
 use sha2::Sha256;
-use std::{clone::Clone, pin::Pin, rc::Rc};
+use std::{
+    cell::OnceCell, clone::Clone, collections::BTreeMap, io, panic::AssertUnwindSafe, pin::Pin,
+    rc::Rc, sync::Arc,
+};
 use thiserror::Error;
-use tracing::{event, Level};
+use tracing::{event, field, Level, Span};
+use tracing_actix_web::{DefaultRootSpanBuilder, RequestId, RootSpan, RootSpanBuilder};
+use user_persist::clock::{Clock, SystemClock};
 
 #[derive(Debug)]
 pub struct JwtAuth(Rc<Inner>);
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct Inner {
     // Secret for validating JWT signatures.
-    secret: Vec<u8>,
+    secret: SecretVec<u8>,
+    // Clock for checking expiry.
+    clock: Arc<dyn Clock>,
+    // Checks applied to the registered claims.
+    policy: ClaimsPolicy,
 }
 
 pub struct JwtMiddleware<S> {
@@ -35,10 +56,24 @@ pub struct JwtMiddleware<S> {
 
 impl Default for JwtAuth {
     fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl JwtAuth {
+    /// Check token claims with `policy` against the time read from `clock`.
+    pub fn new(clock: Arc<dyn Clock>, policy: ClaimsPolicy) -> Self {
         JwtAuth(Rc::new(Inner {
-            secret: TEST_JWT_SECRET.to_owned(),
+            secret: SecretVec::new(TEST_JWT_SECRET.to_owned()),
+            clock,
+            policy,
         }))
     }
+
+    /// Check expiry against `clock` instead of the system clock.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self::new(clock, ClaimsPolicy::default())
+    }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for JwtAuth
@@ -75,6 +110,16 @@ where
     actix_service::forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
+        // OPTIONS only describes a resource and doesn't require a token.
+        // The dev token endpoint is only mounted when enabled and issues
+        // tokens like the public login and refresh endpoints.
+        if req.method() == Method::OPTIONS
+            || req.path() == DEV_TOKEN_PATH
+            || route_policy::is_public(req.path())
+        {
+            return Box::pin(self.service.call(req));
+        }
+
         match self.extract_jwt(&req) {
             Ok(claims) => {
                 event!(
@@ -82,6 +127,10 @@ where
                   Level::DEBUG,
                   "parsed claims: {claims:?}"
                 );
+                if let Some(span) = req.extensions().get::<RootSpan>() {
+                    span.record("sub", &claims.sub.as_str());
+                    span.record("role", &field::debug(&claims.role));
+                }
                 req.extensions_mut().insert::<JWTClaims>(claims);
             }
             Err(e) => {
@@ -103,6 +152,327 @@ where
     }
 }
 
+/// Root span builder adding the subject and role of the verified token to
+/// the request span. Use with `TracingLogger::<PrincipalRootSpan>::new()`.
+pub struct PrincipalRootSpan;
+
+impl RootSpanBuilder for PrincipalRootSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        tracing_actix_web::root_span!(request, sub = field::Empty, role = field::Empty)
+    }
+
+    fn on_request_end<B: MessageBody>(
+        span: Span,
+        outcome: &Result<ServiceResponse<B>, actix_web::Error>,
+    ) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+/// Middleware that logs the duration of each request and records it
+/// against the matched route pattern.
+#[derive(Debug)]
+pub struct RequestTimer {
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for RequestTimer {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl RequestTimer {
+    /// Measure requests with `clock` instead of the system clock.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self { clock }
+    }
+}
+
+pub struct RequestTimerMiddleware<S> {
+    service: S,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTimer
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestTimerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTimerMiddleware {
+            service,
+            clock: self.clock.clone(),
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTimerMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let clock = self.clock.clone();
+        let start = clock.now();
+        let method = req.method().to_string();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let result = fut.await;
+            let latency = (clock.now() - start).to_std().unwrap_or_default();
+
+            // Requests rejected before routing (ex: by JwtAuth) have no
+            // matched pattern.
+            let (route, status) = match &result {
+                Ok(res) => (
+                    res.request()
+                        .match_pattern()
+                        .unwrap_or_else(|| "unmatched".to_owned()),
+                    res.status(),
+                ),
+                Err(e) => ("unrouted".to_owned(), e.as_response_error().status_code()),
+            };
+
+            event!(
+              target: FRAMEWORK_TARGET,
+              Level::INFO,
+              "{method} {route} completed in {} ms",
+              latency.as_millis()
+            );
+
+            metrics::histogram!(
+                REQUEST_DURATION_METRIC,
+                latency.as_secs_f64(),
+                "method" => method,
+                "route" => route,
+                "status" => status.as_u16().to_string()
+            );
+
+            result
+        })
+    }
+}
+
+/// Middleware that converts a panic in a handler into a 500 JSON
+/// response rather than dropping the connection. Must be registered
+/// inside `TracingLogger` for the response to carry the request id.
+#[derive(Debug, Default)]
+pub struct CatchPanic;
+
+pub struct CatchPanicMiddleware<S> {
+    service: S,
+}
+
+/// Error returned when a handler panicked.
+#[derive(Debug, Error)]
+#[error("Internal server error")]
+pub struct PanicError {
+    request_id: Option<String>,
+}
+
+impl ResponseError for PanicError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        HttpResponse::build(self.status_code()).json(json!({
+          "label": "internal.error",
+          "message": self.to_string(),
+          "requestId": self.request_id,
+        }))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CatchPanic
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = CatchPanicMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CatchPanicMiddleware { service }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for CatchPanicMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            match AssertUnwindSafe(fut).catch_unwind().await {
+                Ok(result) => result,
+                Err(_) => {
+                    event!(
+                      target: FRAMEWORK_TARGET,
+                      Level::ERROR,
+                      "Handler panicked for request {request_id:?}"
+                    );
+                    Err(PanicError { request_id }.into())
+                }
+            }
+        })
+    }
+}
+
+/// Middleware failing request bodies read slower than the read timeouts
+/// of the matched route. Such requests are answered with `408` whatever
+/// the handler made of the failed read.
+#[derive(Debug, Default)]
+pub struct BodyReadTimeout(Rc<ReadTimeoutRoutes>);
+
+#[derive(Debug, Default)]
+struct ReadTimeoutRoutes {
+    timeouts: ReadTimeouts,
+    // Read timeouts overriding the default, by route pattern.
+    routes: BTreeMap<String, std::time::Duration>,
+}
+
+impl BodyReadTimeout {
+    /// Guard bodies with `timeouts`, overriding the read timeout of the
+    /// route patterns of `routes`.
+    pub fn new(timeouts: ReadTimeouts, routes: BTreeMap<String, std::time::Duration>) -> Self {
+        Self(Rc::new(ReadTimeoutRoutes { timeouts, routes }))
+    }
+}
+
+pub struct BodyReadTimeoutMiddleware<S> {
+    service: S,
+    inner: Rc<ReadTimeoutRoutes>,
+}
+
+/// Error returned when a request body timed out.
+#[derive(Debug, Error)]
+#[error("{timeout}")]
+pub struct BodyTimeoutError {
+    timeout: BodyTimeout,
+    request_id: Option<String>,
+}
+
+impl ResponseError for BodyTimeoutError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::REQUEST_TIMEOUT
+    }
+
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        HttpResponse::build(self.status_code()).json(errors::envelope(
+            BODY_TIMEOUT_LABEL,
+            self,
+            self.request_id.as_deref(),
+        ))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for BodyReadTimeout
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = BodyReadTimeoutMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(BodyReadTimeoutMiddleware {
+            service,
+            inner: self.0.clone(),
+        }))
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for BodyReadTimeoutMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_service::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let route = req.match_pattern().unwrap_or_default();
+        let timeouts = match self.inner.routes.get(&route) {
+            Some(&read_timeout) => ReadTimeouts {
+                read_timeout,
+                ..self.inner.timeouts
+            },
+            None => self.inner.timeouts,
+        };
+        let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string());
+
+        let timed_out = Rc::new(OnceCell::new());
+        let on_timeout = {
+            let timed_out = timed_out.clone();
+            move |timeout: BodyTimeout| {
+                let _ = timed_out.set(timeout);
+                PayloadError::Io(io::Error::new(io::ErrorKind::TimedOut, timeout))
+            }
+        };
+        let payload: BoxedPayloadStream =
+            Box::pin(guard_body(req.take_payload(), timeouts, on_timeout));
+        req.set_payload(Payload::from(payload));
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let result = fut.await;
+            match timed_out.get() {
+                Some(&timeout) => {
+                    event!(
+                      target: FRAMEWORK_TARGET,
+                      Level::WARN,
+                      "Request body of {route} timed out: {timeout}"
+                    );
+                    Err(BodyTimeoutError {
+                        timeout,
+                        request_id,
+                    }
+                    .into())
+                }
+                None => result,
+            }
+        })
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum JsonValidationError {
     #[error("Validation failed")]
@@ -124,7 +494,7 @@ pub enum JsonValidationError {
 
 type HmacSha256 = Hmac<Sha256>;
 
-pub const TEST_JWT_SECRET: &[u8] = b"TEST_SECRET";
+pub const TEST_JWT_SECRET: &[u8] = bootstrap::TEST_JWT_SECRET;
 
 impl<S> JwtMiddleware<S> {
     /// Extract the Authorization header and parse a JWT from
@@ -145,10 +515,10 @@ impl<S> JwtMiddleware<S> {
           req.uri()
         );
 
-        let key = HmacSha256::new_from_slice(&self.inner.secret)?;
+        let key = HmacSha256::new_from_slice(self.inner.secret.expose_secret())?;
         let claims: JWTClaims = jwt_token.verify_with_key(&key)?;
 
-        Ok(claims.check_expired()?)
+        claims.check(&self.inner.policy, self.inner.clock.now())
       }
       None => Err(JWTError::NoAutorizationHeader),
     }
@@ -158,13 +528,18 @@ impl<S> JwtMiddleware<S> {
 /// Create a test JWT with a given role. Token expires in
 /// 5 minutes.
 pub fn create_test_jwt(role: Role) -> Result<String, JWTError> {
-    let key = HmacSha256::new_from_slice(TEST_JWT_SECRET).unwrap();
-    let expiration = Utc::now() + Duration::minutes(5);
-    let claims = JWTClaims {
-        sub: "somebody".to_owned(),
+    sign_jwt(&JWTClaims::issue(
+        "somebody".to_owned(),
         role,
-        exp: expiration.timestamp(),
-    };
+        Utc::now(),
+        Duration::minutes(5),
+        &ClaimsPolicy::default(),
+    ))
+}
+
+/// Sign the claims with the JWT secret.
+pub fn sign_jwt(claims: &JWTClaims) -> Result<String, JWTError> {
+    let key = HmacSha256::new_from_slice(TEST_JWT_SECRET)?;
     Ok(claims.sign_with_key(&key)?)
 }
 
@@ -183,15 +558,31 @@ impl FromRequest for JWTClaims {
     }
 }
 
+/// Whether the role of `claims` may call the route of `req`, whose handler
+/// requires `required` unless the route policy in the app data lists it.
+fn route_permits(req: &HttpRequest, claims: &JWTClaims, required: Role) -> bool {
+    let role = format!("{:?}", claims.role);
+    let required = format!("{required:?}");
+    match (
+        req.app_data::<web::Data<RoutePolicy>>(),
+        req.match_pattern(),
+    ) {
+        (Some(policy), Some(route)) => {
+            policy.permits(req.method().as_str(), &route, &role, &required)
+        }
+        _ => role == required,
+    }
+}
+
 /// Enforce a handler to have an Admin role as defined in
-/// The JWT claims.
+/// The JWT claims, or a role the route policy allows on the route.
 impl FromRequest for AdminAccess {
     type Error = JWTError;
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         let result = match req.extensions().get::<JWTClaims>() {
-            Some(c) if c.role == Role::Admin => Ok(AdminAccess(c.clone())),
+            Some(c) if route_permits(req, c, Role::Admin) => Ok(AdminAccess(c.clone())),
             _ => Err(JWTError::InvalidRole),
         };
         ready(result)
@@ -199,26 +590,33 @@ impl FromRequest for AdminAccess {
 }
 
 /// Enforce a handler to have a User role as defined in
-/// the JWT claims.
+/// the JWT claims, or a role the route policy allows on the route.
 impl FromRequest for UserAccess {
     type Error = JWTError;
     type Future = Ready<Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         let result = match req.extensions().get::<JWTClaims>() {
-            Some(c) if c.role == Role::User => Ok(UserAccess(c.clone())),
+            Some(c) if route_permits(req, c, Role::User) => Ok(UserAccess(c.clone())),
             _ => Err(JWTError::InvalidRole),
         };
         ready(result)
     }
 }
 
+/// Requests without a valid token are challenged for one while a valid
+/// token without the required role is forbidden.
 impl ResponseError for JWTError {
     fn status_code(&self) -> StatusCode {
-        StatusCode::FORBIDDEN
+        ApiError::from(self).status
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::build(StatusCode::FORBIDDEN).body("no access")
+        let error = ApiError::from(self);
+        let mut response = HttpResponse::build(error.status);
+        if let Some(challenge) = error.reason.and_then(|reason| reason.challenge()) {
+            response.insert_header((WWW_AUTHENTICATE, challenge));
+        }
+        response.json(error.envelope(None))
     }
 }
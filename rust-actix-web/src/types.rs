@@ -1,6 +1,15 @@
 use crate::common::FRAMEWORK_TARGET;
 use actix_web::{body, http, HttpResponse, ResponseError};
-use chrono::{DateTime, Utc};
+use bootstrap::{
+    claims::{ClaimsError, ClaimsPolicy, RegisteredClaims},
+    conditional::PreconditionError,
+    content_type::ContentTypeError,
+    fields::FieldSet,
+    token::IssueError,
+    DevTokenError,
+};
+use chrono::{DateTime, Duration, Utc};
+use errors::{ApiError, AuthError};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::{event, Level};
@@ -10,18 +19,64 @@ use user_persist::persistence::PersistenceError;
 pub enum HandlerError {
     #[error("Persistence error")]
     PersistenceError(#[from] PersistenceError),
+    #[error("Invalid token request: {0}")]
+    DevTokenError(#[from] DevTokenError),
+    #[error("Token error: {0}")]
+    TokenError(#[from] JWTError),
+    #[error("Token issue failed: {0}")]
+    IssueError(#[from] IssueError),
+    #[error("Search requires at least one filter")]
+    UnfilteredSearch,
+    #[error("User not found")]
+    UserNotFound,
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("{0}")]
+    PreconditionError(#[from] PreconditionError),
+}
+
+impl From<&HandlerError> for ApiError {
+    fn from(err: &HandlerError) -> Self {
+        match err {
+            HandlerError::PersistenceError(e) => e.into(),
+            HandlerError::DevTokenError(e) => e.into(),
+            HandlerError::TokenError(e) => e.into(),
+            HandlerError::IssueError(e) => e.into(),
+            HandlerError::UnfilteredSearch => ApiError::unfiltered_search(),
+            HandlerError::UserNotFound => ApiError::not_found(err),
+            HandlerError::SerializationError(e) => e.into(),
+            HandlerError::PreconditionError(e) => e.into(),
+        }
+    }
 }
 
 impl ResponseError for HandlerError {
     fn status_code(&self) -> http::StatusCode {
-        http::StatusCode::SERVICE_UNAVAILABLE
+        ApiError::from(self).status
     }
 
     fn error_response(&self) -> HttpResponse<body::BoxBody> {
-        let body = serde_json::to_string(&format!("{}", self)).unwrap_or_default();
-        HttpResponse::ServiceUnavailable()
-            .content_type("application/json")
-            .body(body)
+        let error = ApiError::from(self);
+        HttpResponse::build(error.status).json(error.envelope(None))
+    }
+}
+
+/// JSON request body rejected for its content type.
+#[derive(Debug, Error)]
+#[error("{source}")]
+pub struct UnsupportedMediaType {
+    pub source: ContentTypeError,
+    pub request_id: Option<String>,
+}
+
+impl ResponseError for UnsupportedMediaType {
+    fn status_code(&self) -> http::StatusCode {
+        http::StatusCode::UNSUPPORTED_MEDIA_TYPE
+    }
+
+    fn error_response(&self) -> HttpResponse<body::BoxBody> {
+        let error = ApiError::from(&self.source);
+        HttpResponse::build(error.status).json(error.envelope(self.request_id.as_deref()))
     }
 }
 
@@ -43,6 +98,15 @@ pub struct JWTClaims {
     pub role: Role,
     /// Expiration date time in unix epoch.
     pub exp: i64,
+    /// Issued at date time in unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    /// Audience the token was issued for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Issuer of the token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
 }
 
 /// Error type for all errors that
@@ -60,29 +124,74 @@ pub enum JWTError {
     InvalidRole,
     #[error("JWT has expired")]
     Expired,
+    #[error("JWT claims rejected: {0}")]
+    InvalidClaims(ClaimsError),
     #[error("Actix web error")]
     ActixError(#[from] actix_web::Error),
 }
 
+impl From<ClaimsError> for JWTError {
+    fn from(err: ClaimsError) -> Self {
+        match err {
+            ClaimsError::Expired => Self::Expired,
+            _ => Self::InvalidClaims(err),
+        }
+    }
+}
+
+/// Failing to sign or verify a token with the key is an internal error.
+impl From<&JWTError> for ApiError {
+    fn from(err: &JWTError) -> Self {
+        match err {
+            JWTError::NoAutorizationHeader => AuthError::MissingHeader.into(),
+            JWTError::VerificationFailed(_) => AuthError::InvalidSignature.into(),
+            JWTError::Expired => AuthError::Expired.into(),
+            JWTError::InvalidClaims(_) => AuthError::InvalidClaims.into(),
+            JWTError::InvalidRole => AuthError::WrongRole.into(),
+            JWTError::InvalidJwtLength(_) | JWTError::ActixError(_) => ApiError::internal(),
+        }
+    }
+}
+
 impl JWTClaims {
-    /// Method that checks if the JWT has expired.
-    /// This is has a max age of 5 minutes.
-    pub fn check_expired(self) -> Result<Self, JWTError> {
-        let exp = DateTime::from_timestamp(self.exp, 0).ok_or(JWTError::Expired)?;
-        let now = Utc::now();
-        let exp_minutes = (exp - now).num_minutes();
+    /// Claims issued at `now` for `ttl` with the audience and issuer
+    /// required by `policy`.
+    pub fn issue(
+        sub: String,
+        role: Role,
+        now: DateTime<Utc>,
+        ttl: Duration,
+        policy: &ClaimsPolicy,
+    ) -> Self {
+        Self {
+            sub,
+            role,
+            exp: (now + ttl).timestamp(),
+            iat: Some(now.timestamp()),
+            aud: policy.audience.clone(),
+            iss: policy.issuer.clone(),
+        }
+    }
 
+    /// Method that checks the registered claims against `policy` at `now`.
+    pub fn check(self, policy: &ClaimsPolicy, now: DateTime<Utc>) -> Result<Self, JWTError> {
         event!(
           target: FRAMEWORK_TARGET,
           Level::DEBUG,
-          "Jwt expires in: {exp_minutes} minutes"
+          "Jwt expires in: {} minutes",
+          (self.exp - now.timestamp()) / 60
         );
 
-        if exp_minutes <= 0 {
-            Err(JWTError::Expired)
-        } else {
-            Ok(self)
-        }
+        policy.validate(
+            RegisteredClaims {
+                exp: self.exp,
+                iat: self.iat,
+                aud: self.aud.as_deref(),
+                iss: self.iss.as_deref(),
+            },
+            now.timestamp(),
+        )?;
+        Ok(self)
     }
 }
 
@@ -93,3 +202,22 @@ pub struct UserAccess(pub JWTClaims);
 /// JWT Claims when the role is Admin
 #[derive(Debug, Clone)]
 pub struct AdminAccess(pub JWTClaims);
+
+/// Query parameters for a search.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchParams {
+    /// Stream the results as newline delimited JSON.
+    pub stream: bool,
+    /// Search every user when no criteria are given.
+    pub all: bool,
+    /// Only return these user fields.
+    pub fields: Option<FieldSet>,
+}
+
+/// Query parameters choosing the user fields of a response.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FieldsParams {
+    pub fields: Option<FieldSet>,
+}
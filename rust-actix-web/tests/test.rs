@@ -1,18 +1,26 @@
 use actix_http::header::TryIntoHeaderPair;
 use actix_service::Service;
 use actix_web::{body::MessageBody, dev, http, test, web, App};
-use async_trait::async_trait;
+use bootstrap::{DevTokenResponse, DEV_TOKEN_PATH};
+use chrono::{Duration, Utc};
+use futures::{stream, StreamExt};
 use rust_actix_web::{
     handlers,
-    middleware::{create_test_jwt, JwtAuth},
+    middleware::{create_test_jwt, CatchPanic, JwtAuth, PrincipalRootSpan, RequestTimer},
     types::Role,
 };
 use serde_json::{json, Value};
-use std::sync::{Arc, Once};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Once,
+};
+use test_support::mock::MockDatabase;
 use tracing_actix_web::TracingLogger;
 use tracing_subscriber::EnvFilter;
-use user_persist::persistence::{PersistenceError, PersistenceResult, UserPersistence};
-use user_persist::types::{Email, Gender, UpdateUser, User, UserKey, UserSearch};
+use user_persist::clock::{Clock, MockClock, SystemClock};
+use user_persist::patch::Patch;
+use user_persist::persistence::{PersistenceError, UserPersistence};
+use user_persist::types::{Email, Gender, UpdateUser, User, UserSearch};
 
 static INIT: Once = Once::new();
 
@@ -36,76 +44,102 @@ fn test_user() -> User {
         email: Email(String::from("test@test.com")),
         age: 100,
         gender: Gender::Male,
+        phone: None,
+        address: None,
+        created_at: None,
+        updated_at: None,
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct TestPersistence;
+/// Searching for this name streams one user and then fails.
+const BROKEN_STREAM_NAME: &str = "Broken Stream";
 
 // A mock persistence for testing.
-#[async_trait]
-impl UserPersistence for TestPersistence {
-    async fn get_user(&self, id: &UserKey) -> Result<Option<User>, PersistenceError> {
-        if id.0 == "61c0d1954c6b974ca7000000" {
-            Ok(Some(test_user()))
-        } else {
-            Ok(None)
-        }
-    }
-
-    async fn save_user(&self, user: &User) -> Result<User, PersistenceError> {
-        Ok(user.clone())
-    }
-
-    async fn update_user(&self, _user: &UpdateUser) -> Result<(), PersistenceError> {
-        Ok(())
-    }
-
-    async fn remove_user(&self, _user: &UserKey) -> PersistenceResult<()> {
-        todo!()
-    }
+fn test_persistence() -> MockDatabase {
+    MockDatabase::builder()
+        .on_get_user(|id| Ok((id.to_string() == "61c0d1954c6b974ca7000000").then(test_user)))
+        .on_get_user_by_email(|email| {
+            let user = test_user();
+            Ok(user.email.eq_ignore_ascii_case(email).then_some(user))
+        })
+        .on_search_users(|_, _| Ok(vec![test_user()]))
+        .on_search_users_stream(|user_search| {
+            let mut users = vec![Ok(test_user())];
+            if user_search.name.as_deref() == Some(BROKEN_STREAM_NAME) {
+                users.push(Err(PersistenceError::Timeout));
+                users.push(Ok(test_user()));
+            }
+            Ok(stream::iter(users).boxed())
+        })
+        .on_count_genders(|| {
+            Ok(vec![
+                json!({
+                    "gender": "Male",
+                    "count": 6
+                }),
+                json!({
+                    "gender": "Female",
+                    "count": 12
+                }),
+            ])
+        })
+        .build()
+}
 
-    async fn search_users(&self, _user_search: &UserSearch) -> Result<Vec<User>, PersistenceError> {
-        Ok(vec![test_user()])
-    }
+async fn get_service() -> impl Service<
+    actix_http::Request,
+    Response = dev::ServiceResponse<impl MessageBody>,
+    Error = actix_web::Error,
+> {
+    get_service_with_clock(Arc::new(SystemClock)).await
+}
 
-    async fn count_genders(&self) -> Result<Vec<Value>, PersistenceError> {
-        Ok(vec![
-            json! (   {
-                "_id": "Male",
-                "count": 6
-            }),
-            json!({
-                "_id": "Female",
-                "count": 12
-            }),
-        ])
-    }
+async fn get_service_with_clock(
+    clock: Arc<dyn Clock>,
+) -> impl Service<
+    actix_http::Request,
+    Response = dev::ServiceResponse<impl MessageBody>,
+    Error = actix_web::Error,
+> {
+    get_service_with(test_persistence(), clock).await
 }
 
-async fn get_service() -> impl Service<
+async fn get_service_with(
+    persistence: MockDatabase,
+    clock: Arc<dyn Clock>,
+) -> impl Service<
     actix_http::Request,
     Response = dev::ServiceResponse<impl MessageBody>,
     Error = actix_web::Error,
 > {
-    let persist: web::Data<Arc<dyn UserPersistence>> = web::Data::new(Arc::new(TestPersistence));
+    let persist: web::Data<Arc<dyn UserPersistence>> = web::Data::new(Arc::new(persistence));
     test::init_service(
         App::new()
             .app_data(persist)
-            .wrap(JwtAuth::default())
-            .wrap(TracingLogger::default())
+            .app_data(handlers::json_config())
+            .wrap(CatchPanic)
+            .wrap(JwtAuth::with_clock(clock.clone()))
+            .wrap(TracingLogger::<PrincipalRootSpan>::new())
+            .wrap(RequestTimer::with_clock(clock))
             .service(
                 web::scope("/api/v1/user")
                     .service(handlers::count_users)
                     .service(handlers::get_user)
+                    .service(handlers::get_user_by_email)
                     .service(handlers::search_users)
                     .service(handlers::save_user)
-                    .service(handlers::update_user),
-            ),
+                    .service(handlers::update_user)
+                    .service(handlers::options),
+            )
+            .route("/panic", web::get().to(panicking_handler)),
     )
     .await
 }
 
+async fn panicking_handler() -> &'static str {
+    panic!("test panic")
+}
+
 fn jwt_header(role: Role) -> impl TryIntoHeaderPair {
     (
         "Authorization",
@@ -126,6 +160,154 @@ async fn get_user() {
     assert_eq!(res.status(), http::StatusCode::OK);
 }
 
+#[actix_web::test]
+async fn get_user_fields() {
+    init_log();
+    let service = get_service().await;
+    let req =
+        test::TestRequest::with_uri("/api/v1/user/61c0d1954c6b974ca7000000?fields=name,email")
+            .insert_header(jwt_header(Role::Admin))
+            .to_request();
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::OK);
+    let user: Value = test::read_body_json(res).await;
+    let mut fields = user.as_object().unwrap().keys().collect::<Vec<_>>();
+    fields.sort();
+    assert_eq!(fields, vec!["email", "name"]);
+
+    let req = test::TestRequest::with_uri("/api/v1/user/61c0d1954c6b974ca7000000?fields=password")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn update_user_if_match() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::with_uri("/api/v1/user/61c0d1954c6b974ca7000000")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    let etag = res.headers().get(http::header::ETAG).unwrap().clone();
+
+    let update = json!({
+        "id": "61c0d1954c6b974ca7000000",
+        "name": "New Name",
+        "email": "test@test.com",
+        "age": 100,
+        "hashId": "xBS6Bfv589WArC5A3psqFZRv/sPe8thJqRHBaipYsho=",
+    });
+    let req = test::TestRequest::put()
+        .uri("/api/v1/user")
+        .insert_header(jwt_header(Role::Admin))
+        .insert_header((http::header::IF_MATCH, etag))
+        .set_json(&update)
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    let req = test::TestRequest::put()
+        .uri("/api/v1/user")
+        .insert_header(jwt_header(Role::Admin))
+        .insert_header((http::header::IF_MATCH, "\"stale\""))
+        .set_json(&update)
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::PRECONDITION_FAILED);
+}
+
+#[actix_web::test]
+async fn update_user_if_match_race() {
+    init_log();
+    // The user is written by someone else after the handler checked the
+    // precondition, on the third lookup.
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let persistence = MockDatabase::builder()
+        .on_get_user(move |_| {
+            let updated_at = (lookups.fetch_add(1, Ordering::SeqCst) >= 2).then(Utc::now);
+            Ok(Some(User {
+                updated_at,
+                ..test_user()
+            }))
+        })
+        .build();
+    let service = get_service_with(persistence, Arc::new(SystemClock)).await;
+    let req = test::TestRequest::with_uri("/api/v1/user/61c0d1954c6b974ca7000000")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    let etag = res.headers().get(http::header::ETAG).unwrap().clone();
+
+    let req = test::TestRequest::put()
+        .uri("/api/v1/user")
+        .insert_header(jwt_header(Role::Admin))
+        .insert_header((http::header::IF_MATCH, etag))
+        .set_json(json!({
+            "id": "61c0d1954c6b974ca7000000",
+            "name": "New Name",
+            "email": "test@test.com",
+            "age": 100,
+            "hashId": "xBS6Bfv589WArC5A3psqFZRv/sPe8thJqRHBaipYsho=",
+        }))
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::PRECONDITION_FAILED);
+}
+
+#[actix_web::test]
+async fn get_user_by_email() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::with_uri("/api/v1/user/by-email/Test@Test.com")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::OK);
+    let user: User = test::read_body_json(res).await;
+    assert_eq!(user, test_user());
+
+    let req = test::TestRequest::with_uri("/api/v1/user/by-email/nobody@test.com")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn get_user_token_expiry() {
+    init_log();
+    let clock = Arc::new(MockClock::new(Utc::now()));
+    let service = get_service_with_clock(clock.clone()).await;
+    let token = format!("Bearer {}", create_test_jwt(Role::Admin).unwrap());
+    let get_user = || {
+        test::TestRequest::with_uri("/api/v1/user/61c0d1954c6b974ca7000000")
+            .insert_header(("Authorization", token.clone()))
+            .to_request()
+    };
+
+    // Test tokens expire after 5 minutes and are accepted for a minute
+    // longer.
+    clock.advance(Duration::minutes(5));
+    let res = service.call(get_user()).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    clock.advance(Duration::minutes(2));
+    let err = service.call(get_user()).await.err().unwrap();
+    assert_eq!(
+        err.as_response_error().status_code(),
+        http::StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        err.error_response()
+            .headers()
+            .get(http::header::WWW_AUTHENTICATE)
+            .unwrap(),
+        r#"Bearer error="invalid_token""#
+    );
+}
+
 #[actix_web::test]
 async fn count_users() {
     init_log();
@@ -139,6 +321,54 @@ async fn count_users() {
     assert_eq!(res.status(), http::StatusCode::OK);
 }
 
+#[actix_web::test]
+async fn head_user() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::default()
+        .method(http::Method::HEAD)
+        .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+
+    let res = service.call(req).await.unwrap();
+
+    assert_eq!(res.status(), http::StatusCode::OK);
+    assert_eq!(
+        res.headers().get(http::header::CONTENT_TYPE).unwrap(),
+        "application/json"
+    );
+}
+
+#[actix_web::test]
+async fn options_user() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::default()
+        .method(http::Method::OPTIONS)
+        .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+        .to_request();
+
+    let res = service.call(req).await.unwrap();
+
+    assert_eq!(res.status(), http::StatusCode::NO_CONTENT);
+    assert_eq!(
+        res.headers().get(http::header::ALLOW).unwrap(),
+        "GET, HEAD, OPTIONS"
+    );
+
+    let req = test::TestRequest::default()
+        .method(http::Method::OPTIONS)
+        .uri("/api/v1/user/search")
+        .to_request();
+
+    let res = service.call(req).await.unwrap();
+    assert_eq!(
+        res.headers().get(http::header::ALLOW).unwrap(),
+        "POST, OPTIONS"
+    );
+}
+
 #[actix_web::test]
 async fn save_user() {
     init_log();
@@ -154,6 +384,36 @@ async fn save_user() {
     assert_eq!(res.status(), http::StatusCode::OK);
 }
 
+#[actix_web::test]
+async fn save_user_content_type() {
+    init_log();
+    let service = get_service().await;
+    let body = serde_json::to_string(&test_user()).unwrap();
+    let save_user = |content_type: Option<&str>| {
+        let mut req = test::TestRequest::post()
+            .uri("/api/v1/user")
+            .insert_header(jwt_header(Role::User))
+            .set_payload(body.clone());
+        if let Some(content_type) = content_type {
+            req = req.insert_header((http::header::CONTENT_TYPE, content_type.to_owned()));
+        }
+        req.to_request()
+    };
+
+    let res = service
+        .call(save_user(Some("application/json; charset=utf-8")))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    for content_type in [None, Some("text/plain")] {
+        let res = service.call(save_user(content_type)).await.unwrap();
+        assert_eq!(res.status(), http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["label"], "unsupported.media_type");
+    }
+}
+
 #[actix_web::test]
 async fn search_users() {
     init_log();
@@ -165,12 +425,97 @@ async fn search_users() {
             email: Some(Email("some@where.com".to_owned())),
             name: None,
             gender: None,
+            ..Default::default()
+        })
+        .to_request();
+
+    let res = service.call(req).await.unwrap();
+
+    assert_eq!(res.status(), http::StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn search_users_stream() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::post()
+        .uri("/api/v1/user/search?stream=true")
+        .insert_header(jwt_header(Role::Admin))
+        .set_json(UserSearch {
+            email: None,
+            name: Some("Test User".to_owned()),
+            gender: None,
+            ..Default::default()
         })
         .to_request();
 
     let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::OK);
+    assert_eq!(
+        res.headers().get("content-type").unwrap(),
+        "application/x-ndjson"
+    );
 
+    let body = test::read_body(res).await;
+    let users = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<User>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(users, vec![test_user()]);
+}
+
+#[actix_web::test]
+async fn search_users_unfiltered() {
+    init_log();
+    let service = get_service().await;
+    let search = |uri: &str, body: Value| {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(jwt_header(Role::Admin))
+            .set_json(body)
+            .to_request()
+    };
+
+    let res = service
+        .call(search("/api/v1/user/search", json!({})))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+
+    let res = service
+        .call(search("/api/v1/user/search?all=true", json!({})))
+        .await
+        .unwrap();
     assert_eq!(res.status(), http::StatusCode::OK);
+
+    let res = service
+        .call(search("/api/v1/user/search", json!({"nmae": "Test User"})))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+}
+
+// A database failure after the status was sent aborts the chunked body
+// instead of ending it as if the results were complete.
+#[actix_web::test]
+async fn search_users_stream_failure() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::post()
+        .uri("/api/v1/user/search?stream=true")
+        .insert_header(jwt_header(Role::Admin))
+        .set_json(UserSearch {
+            email: None,
+            name: Some(BROKEN_STREAM_NAME.to_owned()),
+            gender: None,
+            ..Default::default()
+        })
+        .to_request();
+
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::OK);
+    assert!(actix_web::body::to_bytes(res.into_body()).await.is_err());
 }
 
 #[actix_web::test]
@@ -181,11 +526,13 @@ async fn update_user() {
         .uri("/api/v1/user")
         .insert_header(jwt_header(Role::Admin))
         .set_json(UpdateUser {
-            id: UserKey("some_key".to_owned()),
+            id: "some_key".parse().unwrap(),
             name: "New name".to_owned(),
             age: 100,
             email: Email("test@test.com".into()),
-            hid: "xBS6Bfv589WArC5A3psqFZRv/sPe8thJqRHBaipYsho=".into(),
+            hash_id: "xBS6Bfv589WArC5A3psqFZRv/sPe8thJqRHBaipYsho=".into(),
+            phone: Patch::Absent,
+            address: Patch::Absent,
         })
         .to_request();
 
@@ -193,3 +540,123 @@ async fn update_user() {
 
     assert_eq!(res.status(), http::StatusCode::OK);
 }
+
+#[actix_web::test]
+async fn handler_panic() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::with_uri("/panic")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+
+    let res = service.call(req).await;
+
+    let status = match res {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    assert_eq!(status, http::StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[actix_web::test]
+async fn dev_token() {
+    init_log();
+    let persist: web::Data<Arc<dyn UserPersistence>> = web::Data::new(Arc::new(test_persistence()));
+    let service = test::init_service(
+        App::new()
+            .app_data(persist)
+            .wrap(JwtAuth::default())
+            .service(web::scope("/api/v1/user").service(handlers::get_user))
+            .service(web::scope("/api/v1").service(handlers::dev_token)),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri(DEV_TOKEN_PATH)
+        .set_json(json!({"role": "Admin", "sub": "dev", "ttl": 300}))
+        .to_request();
+    let minted: DevTokenResponse = test::call_and_read_body_json(&service, req).await;
+
+    let req = test::TestRequest::with_uri("/api/v1/user/61c0d1954c6b974ca7000000")
+        .insert_header(("Authorization", format!("Bearer {}", minted.token)))
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::OK);
+
+    let req = test::TestRequest::post()
+        .uri(DEV_TOKEN_PATH)
+        .set_json(json!({"role": "Admin", "sub": "dev", "ttl": 0}))
+        .to_request();
+    let res = service.call(req).await.unwrap();
+    assert_eq!(res.status(), http::StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn user_snapshot() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::with_uri("/api/v1/user/61c0d1954c6b974ca7000000")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+
+    let user: Value = test::call_and_read_body_json(&service, req).await;
+
+    insta::assert_json_snapshot!(user, @r###"
+    {
+      "name": "Test User",
+      "age": 100,
+      "email": "test@test.com",
+      "gender": "Male"
+    }
+    "###);
+}
+
+#[actix_web::test]
+async fn count_users_snapshot() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::with_uri("/api/v1/user/counts")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+
+    let counts: Value = test::call_and_read_body_json(&service, req).await;
+
+    insta::assert_json_snapshot!(counts, @r###"
+    [
+      {
+        "gender": "Male",
+        "count": 6
+      },
+      {
+        "gender": "Female",
+        "count": 12
+      }
+    ]
+    "###);
+}
+
+#[actix_web::test]
+async fn handler_panic_snapshot() {
+    init_log();
+    let service = get_service().await;
+    let req = test::TestRequest::with_uri("/panic")
+        .insert_header(jwt_header(Role::Admin))
+        .to_request();
+
+    let body = match service.call(req).await {
+        Ok(res) => test::read_body(res).await,
+        Err(e) => actix_web::body::to_bytes(e.error_response().into_body())
+            .await
+            .unwrap(),
+    };
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    // The request id is generated by the tracing middleware.
+    insta::assert_json_snapshot!(body, { ".requestId" => "[request-id]" }, @r###"
+    {
+      "label": "internal.error",
+      "message": "Internal server error",
+      "requestId": "[request-id]"
+    }
+    "###);
+}
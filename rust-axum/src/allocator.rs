@@ -0,0 +1,120 @@
+/*!
+Optional global allocator and its statistics.
+
+Enabling the `jemalloc` or `mimalloc` feature replaces the system
+allocator and exposes its statistics at `/debug/allocator`. Without
+either feature the endpoint responds with not found.
+*/
+use serde::Serialize;
+use thiserror::Error;
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("features `jemalloc` and `mimalloc` are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// Allocator statistics error.
+#[derive(Debug, Error)]
+pub enum AllocatorError {
+    #[error("Allocator statistics require the jemalloc or mimalloc feature")]
+    Unavailable,
+    #[cfg(feature = "jemalloc")]
+    #[error("Failed to read jemalloc statistics: `{0}`")]
+    Jemalloc(#[from] tikv_jemalloc_ctl::Error),
+}
+
+/// Allocator statistics in bytes. Fields an allocator does not report
+/// are omitted.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocatorStats {
+    pub allocator: &'static str,
+    /// Bytes allocated by the application.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allocated: Option<u64>,
+    /// Bytes in pages backing active allocations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active: Option<u64>,
+    /// Bytes committed by the allocator.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub committed: Option<u64>,
+    /// Peak bytes committed by the allocator.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_committed: Option<u64>,
+    /// Bytes in physically resident pages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resident: Option<u64>,
+    /// Peak bytes in physically resident pages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_resident: Option<u64>,
+    /// Bytes mapped by the allocator.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mapped: Option<u64>,
+    /// Bytes unmapped but retained for reuse.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retained: Option<u64>,
+}
+
+/// Current statistics of the global allocator.
+#[cfg(feature = "jemalloc")]
+pub fn stats() -> Result<AllocatorStats, AllocatorError> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // Statistics are cached until the epoch is advanced.
+    epoch::advance()?;
+    Ok(AllocatorStats {
+        allocator: "jemalloc",
+        allocated: Some(stats::allocated::read()? as u64),
+        active: Some(stats::active::read()? as u64),
+        resident: Some(stats::resident::read()? as u64),
+        mapped: Some(stats::mapped::read()? as u64),
+        retained: Some(stats::retained::read()? as u64),
+        ..AllocatorStats::default()
+    })
+}
+
+/// Current statistics of the global allocator.
+#[cfg(feature = "mimalloc")]
+pub fn stats() -> Result<AllocatorStats, AllocatorError> {
+    let mut elapsed_msecs = 0;
+    let mut user_msecs = 0;
+    let mut system_msecs = 0;
+    let mut current_rss = 0;
+    let mut peak_rss = 0;
+    let mut current_commit = 0;
+    let mut peak_commit = 0;
+    let mut page_faults = 0;
+    // SAFETY: every pointer refers to a live local.
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed_msecs,
+            &mut user_msecs,
+            &mut system_msecs,
+            &mut current_rss,
+            &mut peak_rss,
+            &mut current_commit,
+            &mut peak_commit,
+            &mut page_faults,
+        );
+    }
+    Ok(AllocatorStats {
+        allocator: "mimalloc",
+        committed: Some(current_commit as u64),
+        peak_committed: Some(peak_commit as u64),
+        resident: Some(current_rss as u64),
+        peak_resident: Some(peak_rss as u64),
+        ..AllocatorStats::default()
+    })
+}
+
+/// Current statistics of the global allocator.
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn stats() -> Result<AllocatorStats, AllocatorError> {
+    Err(AllocatorError::Unavailable)
+}
@@ -1,12 +1,45 @@
 /*!
 Program arguments and application state.
 */
-use crate::{JWTClaims, Role};
-use chrono::{Duration, Utc};
+use crate::{
+    downloads::DownloadTracker,
+    jobs::JobSettings,
+    middleware::{contract::ContractMode, RequestIdFormat},
+    security::signing::{ResponseSigner, SignedClients, SigningKeyError},
+    tasks::Supervisor,
+    JWTClaims, Role,
+};
+use axum_macros::FromRef;
+use bootstrap::{
+    body_timeout::ReadTimeouts,
+    check::CheckReport,
+    claims::{ClaimsArgs, ClaimsPolicy},
+    conditional::{ConditionalArgs, Preconditions},
+    route_policy::{RoutePolicy, RoutePolicyArgs},
+    token::{TokenArgs, TokenIssuer},
+    Bootstrap, BootstrapArgs, BootstrapError, Configured, Profile,
+};
 use clap::Parser;
+use http::{HeaderValue, Uri};
 use jsonwebtoken::{encode, DecodingKey, EncodingKey, Header};
-use std::path::PathBuf;
-use user_persist::MongoArgs;
+use secrecy::{ExposeSecret, SecretString};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+use thiserror::Error;
+use user_persist::{
+    anomaly::{AnomalyDetecting, AnomalyDetector, ThresholdDetector},
+    audit::{AuditLog, MemoryAuditLog},
+    clock::{Clock, SystemClock},
+    export::FormatRegistry,
+    import::ColumnMapping,
+    jobs::{JobQueue, MemoryJobQueue},
+    kv::{KvStore, MemoryKvStore},
+    mongo_persistence::MongoPersistence,
+    persistence::{UserPersistence, DEFAULT_MAX_SEARCH_RESULTS},
+    references::References,
+    types::EmailValidation,
+    validation::ValidationStats,
+    MongoArgs,
+};
 
 /// Command line arguments.
 #[derive(Parser, Clone)]
@@ -14,28 +47,605 @@ use user_persist::MongoArgs;
 pub struct ProgramArgs {
     #[clap(flatten)]
     mongo_opts: MongoArgs,
-    #[clap(long)]
+    #[clap(flatten)]
+    bootstrap: BootstrapArgs,
+    #[clap(flatten)]
+    claims: ClaimsArgs,
+    #[clap(flatten)]
+    conditional: ConditionalArgs,
+    #[clap(flatten)]
+    tokens: TokenArgs,
+    #[clap(flatten)]
+    route_policy: RoutePolicyArgs,
+    #[clap(long, requires = "server_tls_cert_file")]
     #[clap(help = "ssl tls key file")]
-    server_tls_key_file: PathBuf,
-    #[clap(long)]
+    server_tls_key_file: Option<PathBuf>,
+    #[clap(long, requires = "server_tls_key_file")]
     #[clap(help = "ssl tls certificate file")]
-    server_tls_cert_file: PathBuf,
+    server_tls_cert_file: Option<PathBuf>,
     #[clap(long)]
     #[clap(help = "JWT Secret")]
-    jwt_secret: String,
+    jwt_secret: SecretString,
+    #[clap(long, default_value = "9100")]
+    #[clap(help = "Port serving prometheus metrics")]
+    metrics_port: u16,
+    #[clap(long, default_value = "30")]
+    #[clap(help = "Request timeout in seconds")]
+    request_timeout_secs: u64,
+    #[clap(long, default_value = "1048576")]
+    #[clap(help = "Maximum request body size in bytes")]
+    body_limit_bytes: usize,
+    #[clap(long, default_value = "10")]
+    #[clap(help = "Seconds a request body may go without sending data")]
+    body_read_timeout_secs: u64,
+    #[clap(long, default_value = "1024")]
+    #[clap(help = "Slowest rate in bytes a second request bodies may arrive at, 0 disables it")]
+    body_min_bytes_per_sec: u64,
+    #[clap(long, use_value_delimiter = true)]
+    #[clap(help = "Comma separated ROUTE=SECONDS body read timeouts overriding the default")]
+    body_read_timeout_routes: Vec<String>,
+    #[clap(long, default_value = "600")]
+    #[clap(help = "Requests a subject may make each rate limit window, 0 disables the headers")]
+    rate_limit_requests: u64,
+    #[clap(long, default_value = "60")]
+    #[clap(help = "Seconds of a rate limit window")]
+    rate_limit_window_secs: u64,
+    #[clap(long, default_value = "1000")]
+    #[clap(help = "Maximum number of users a bulk update may change")]
+    bulk_update_limit: u64,
+    #[clap(long, default_value = "1000")]
+    #[clap(help = "Maximum number of users returned by a search")]
+    max_search_results: u64,
+    #[clap(long, use_value_delimiter = true)]
+    #[clap(help = "Comma separated origins allowed for CORS requests")]
+    cors_allowed_origins: Vec<String>,
+    #[clap(long, default_value = "0")]
+    #[clap(help = "Cache-Control max-age in seconds, 0 disables caching")]
+    cache_max_age_secs: u64,
+    #[clap(long, default_value = "20")]
+    #[clap(help = "Default number of results per page")]
+    default_page_size: u32,
+    #[clap(long, default_value = "100")]
+    #[clap(help = "Maximum number of results per page")]
+    max_page_size: u32,
+    #[clap(long, default_value = "3600")]
+    #[clap(help = "Lifetime in seconds of search page cursors")]
+    cursor_ttl_secs: u64,
+    #[clap(long)]
+    #[clap(
+        help = "Materialized user export written by export jobs and served by the download endpoint"
+    )]
+    export_file: Option<PathBuf>,
+    #[clap(long, default_value = "86400")]
+    #[clap(help = "Lifetime in seconds of download resume tokens, 0 disables them")]
+    download_resume_ttl_secs: u64,
+    #[clap(long)]
+    #[clap(help = "End streamed downloads when the authorizing token expires")]
+    download_stop_at_token_expiry: bool,
+    #[clap(long, default_value = "30")]
+    #[clap(help = "Seconds shutdown waits for streamed downloads before cancelling them")]
+    download_drain_secs: u64,
+    #[clap(long)]
+    #[clap(help = "Base URL of a secondary deployment read-only requests are mirrored to")]
+    mirror_url: Option<String>,
+    #[clap(long, default_value = "0")]
+    #[clap(help = "Percentage of read-only requests mirrored to the secondary")]
+    mirror_percent: u8,
+    #[clap(long, value_enum, default_value = "uuid4")]
+    #[clap(help = "Format of generated request ids")]
+    request_id_format: RequestIdFormat,
+    #[clap(long, value_enum, default_value = "off")]
+    #[clap(help = "Check bodies against the api-types contract, not allowed in production")]
+    contract_validation: ContractMode,
+    #[clap(long)]
+    #[clap(help = "JSON file mapping the columns of imported files to user fields")]
+    import_mapping_file: Option<PathBuf>,
+    #[clap(long, default_value = "300")]
+    #[clap(help = "Seconds a background job may run before another worker claims it")]
+    job_visibility_timeout_secs: u64,
+    #[clap(long, default_value = "5")]
+    #[clap(help = "Attempts of a background job before it is marked dead")]
+    job_max_attempts: u32,
+    #[clap(long)]
+    #[clap(help = "JSON array of users saved on startup when there are no users")]
+    seed_file: Option<PathBuf>,
+    #[clap(long)]
+    #[clap(help = "Base64 32 byte Ed25519 seed signing response bodies")]
+    response_signing_key: Option<SecretString>,
+    #[clap(long)]
+    #[clap(help = "JSON file mapping client ids to the Ed25519 keys verifying their requests")]
+    signed_clients_file: Option<PathBuf>,
+    #[clap(long, requires = "signed_clients_file")]
+    #[clap(help = "Reject POST and PUT requests not signed by a registered client")]
+    require_signed_requests: bool,
 }
 
 impl ProgramArgs {
-    pub fn server_tls_key_file(&self) -> &PathBuf {
-        &self.server_tls_key_file
+    /// Server certificate and key files when TLS is configured.
+    pub fn server_tls_files(&self) -> Option<(&PathBuf, &PathBuf)> {
+        self.server_tls_cert_file
+            .as_ref()
+            .zip(self.server_tls_key_file.as_ref())
+    }
+
+    /// Resolve the startup settings for the deployment profile.
+    pub fn bootstrap(&self) -> Result<Bootstrap, BootstrapError> {
+        self.bootstrap.resolve(Configured {
+            jwt_secret: Some(self.jwt_secret.expose_secret().as_bytes()),
+            tls: self.server_tls_files().is_some(),
+            allow_invalid_certificates: self.mongo_opts.allow_invalid_certificates(),
+        })
     }
 
-    pub fn server_tls_cert_file(&self) -> &PathBuf {
-        &self.server_tls_cert_file
+    /// Issuer of the login and refresh endpoints when an accounts file is
+    /// configured.
+    pub fn token_issuer(&self) -> Result<Option<TokenIssuer<Role>>, ConfigError> {
+        self.tokens.issuer().map_err(|e| {
+            ConfigError::Accounts(
+                self.tokens.accounts_file.clone().unwrap_or_default(),
+                e.to_string(),
+            )
+        })
     }
 
-    pub fn mongo_opts(self) -> MongoArgs {
+    /// Users seeding an empty database.
+    pub fn seed_file(&self) -> Option<&PathBuf> {
+        self.seed_file.as_ref()
+    }
+
+    pub fn metrics_port(&self) -> u16 {
+        self.metrics_port
+    }
+
+    pub fn email_validation(&self) -> EmailValidation {
+        self.mongo_opts.email_validation()
+    }
+
+    /// Whether the startup self-check was requested.
+    pub fn check(&self) -> bool {
+        self.bootstrap.check
+    }
+
+    /// Run the startup self-check.
+    pub async fn self_check(self) -> CheckReport {
+        let mut report = CheckReport::default();
+        let bootstrap = report.record(
+            "config",
+            AppConfig::new(&self)
+                .and_then(|_| self.token_issuer())
+                .and_then(|_| self.bootstrap().map_err(ConfigError::from)),
+        );
+        report.tls_files(
+            self.server_tls_files()
+                .map(|(cert_file, key_file)| (cert_file.as_path(), key_file.as_path())),
+        );
+        report.jwt_secret(Some(self.jwt_secret.expose_secret().as_bytes()));
+        let mongo_opts = match bootstrap {
+            Some(bootstrap) => self.mongo_opts(&bootstrap),
+            None => self.mongo_opts,
+        };
+        report.mongo(mongo_opts).await;
+        report
+    }
+
+    /// Mongodb arguments with certificate validation resolved from the
+    /// profile.
+    pub fn mongo_opts(self, bootstrap: &Bootstrap) -> MongoArgs {
         self.mongo_opts
+            .with_allow_invalid_certificates(bootstrap.allow_invalid_certificates)
+    }
+}
+
+/// Invalid application settings.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("request timeout must be greater than zero")]
+    RequestTimeout,
+    #[error("body limit must be greater than zero")]
+    BodyLimit,
+    #[error("body read timeouts must be greater than zero")]
+    BodyReadTimeout,
+    #[error("invalid body read timeout `{0}`, expected ROUTE=SECONDS")]
+    BodyReadTimeoutRoute(String),
+    #[error("bulk update limit must be greater than zero")]
+    BulkUpdateLimit,
+    #[error("maximum search results must be greater than zero")]
+    MaxSearchResults,
+    #[error("default page size {default} must be between 1 and max page size {max}")]
+    PageSize { default: u32, max: u32 },
+    #[error("cursor lifetime must be greater than zero")]
+    CursorTtl,
+    #[error("invalid CORS origin `{0}`")]
+    CorsOrigin(String),
+    #[error("invalid mirror URL `{0}`")]
+    MirrorUrl(String),
+    #[error("mirror percentage {0} must be between 0 and 100")]
+    MirrorPercent(u8),
+    #[error("contract validation can't be enabled with the {0} profile")]
+    ContractValidation(Profile),
+    #[error("invalid import mapping {0:?}: {1}")]
+    ImportMapping(PathBuf, String),
+    #[error("rate limit window must be greater than zero")]
+    RateLimitWindow,
+    #[error("job visibility timeout must be between 1 second and 1 day")]
+    JobVisibilityTimeout,
+    #[error("job max attempts must be greater than zero")]
+    JobMaxAttempts,
+    #[error(transparent)]
+    SigningKey(#[from] SigningKeyError),
+    #[error("invalid signed clients {0:?}: {1}")]
+    SignedClients(PathBuf, String),
+    #[error("invalid accounts {0:?}: {1}")]
+    Accounts(PathBuf, String),
+    #[error("invalid route policy {0:?}: {1}")]
+    RoutePolicy(PathBuf, String),
+    #[error(transparent)]
+    Bootstrap(#[from] BootstrapError),
+}
+
+/// Request limits.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Time allowed to produce a response.
+    pub request_timeout: Duration,
+    /// Maximum size of a request body.
+    pub body_limit: usize,
+    /// Maximum number of users a bulk update may change.
+    pub bulk_update_limit: u64,
+    /// Maximum number of users returned by a search.
+    pub max_search_results: u64,
+}
+
+/// Read timeouts of request bodies.
+#[derive(Clone, Debug, Default)]
+pub struct BodyReadSettings {
+    pub timeouts: ReadTimeouts,
+    /// Read timeouts of routes overriding the default, by route path such
+    /// as `/api/v1/user/import`.
+    pub routes: BTreeMap<String, Duration>,
+}
+
+impl BodyReadSettings {
+    /// Read timeouts of the body of a request to `route`.
+    pub fn timeouts(&self, route: &str) -> ReadTimeouts {
+        match self.routes.get(route) {
+            Some(&read_timeout) => ReadTimeouts {
+                read_timeout,
+                ..self.timeouts
+            },
+            None => self.timeouts,
+        }
+    }
+}
+
+/// Cross origin request settings.
+#[derive(Clone, Debug, Default)]
+pub struct CorsSettings {
+    /// Origins allowed to make cross origin requests.
+    pub allowed_origins: Vec<HeaderValue>,
+}
+
+/// Response cache settings.
+#[derive(Clone, Debug, Default)]
+pub struct CacheSettings {
+    /// Cache-Control max-age. Responses are not cached when zero.
+    pub max_age: Duration,
+}
+
+impl CacheSettings {
+    /// Cache-Control header value for responses.
+    pub fn cache_control(&self) -> HeaderValue {
+        if self.max_age.is_zero() {
+            HeaderValue::from_static("no-store")
+        } else {
+            HeaderValue::from_str(&format!("private, max-age={}", self.max_age.as_secs()))
+                .expect("valid header value")
+        }
+    }
+}
+
+/// Advisory rate limits reported to authenticated clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitSettings {
+    /// Requests a subject may make each window. No headers are sent when
+    /// zero.
+    pub requests: u64,
+    pub window: Duration,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            requests: 600,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Pagination defaults.
+#[derive(Clone, Debug)]
+pub struct Pagination {
+    /// Page size used when a request doesn't specify one.
+    pub default_page_size: u32,
+    /// Largest page size a request may ask for.
+    pub max_page_size: u32,
+    /// Lifetime of the cursors continuing a paged search.
+    pub cursor_ttl: Duration,
+}
+
+/// User export settings.
+#[derive(Clone, Debug)]
+pub struct ExportSettings {
+    /// Export artifact written by a background job. When present it is
+    /// served with range support, in the format of its manifest, instead of
+    /// streaming from the database.
+    pub file: Option<PathBuf>,
+    /// Lifetime of the resume token returned with a streamed download.
+    /// No token is returned when zero.
+    pub resume_ttl: Duration,
+    /// End a streamed download when the token authorizing it expires.
+    pub stop_at_token_expiry: bool,
+    /// Time shutdown waits for streamed downloads to finish before
+    /// cancelling them.
+    pub drain_timeout: Duration,
+    /// Formats a streamed download can be requested in.
+    pub formats: FormatRegistry,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            file: None,
+            resume_ttl: Duration::from_secs(24 * 60 * 60),
+            stop_at_token_expiry: false,
+            drain_timeout: Duration::from_secs(30),
+            formats: export_formats(),
+        }
+    }
+}
+
+/// Formats of streamed downloads.
+fn export_formats() -> FormatRegistry {
+    let formats = FormatRegistry::default();
+    #[cfg(feature = "parquet")]
+    let formats = formats.register(user_persist::export::ParquetFormatter::default());
+    formats
+}
+
+/// Traffic mirroring settings.
+#[derive(Clone, Debug, Default)]
+pub struct MirrorSettings {
+    /// Secondary deployment requests are mirrored to. Mirroring is
+    /// disabled when not set.
+    pub base_url: Option<Uri>,
+    /// Percentage of read-only requests mirrored.
+    pub percent: u8,
+}
+
+/// Typed application settings.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub limits: Limits,
+    pub body_read: BodyReadSettings,
+    pub rate_limit: RateLimitSettings,
+    pub cors: CorsSettings,
+    pub cache: CacheSettings,
+    pub pagination: Pagination,
+    pub export: ExportSettings,
+    pub mirror: MirrorSettings,
+    /// Format of request ids generated for requests without one.
+    pub request_id: RequestIdFormat,
+    /// Checks of bodies against the api-types contract.
+    pub contract: ContractMode,
+    /// Serve the development token endpoint.
+    pub dev_tokens: bool,
+    /// Checks applied to the registered claims of tokens.
+    pub claims: ClaimsPolicy,
+    /// Roles allowed on routes instead of the role of their handler.
+    pub route_policy: RoutePolicy,
+    /// Preconditions checked before a user is updated.
+    pub preconditions: Preconditions,
+    /// Columns of imported files holding each user field, unless the
+    /// request maps them.
+    pub import_mapping: ColumnMapping,
+    /// Background job queue settings.
+    pub jobs: JobSettings,
+    /// Mode the emails of requests and imported users are validated in.
+    pub email_validation: EmailValidation,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            limits: Limits {
+                request_timeout: Duration::from_secs(30),
+                body_limit: 1024 * 1024,
+                bulk_update_limit: 1000,
+                max_search_results: DEFAULT_MAX_SEARCH_RESULTS,
+            },
+            body_read: BodyReadSettings::default(),
+            rate_limit: RateLimitSettings::default(),
+            cors: CorsSettings::default(),
+            cache: CacheSettings::default(),
+            pagination: Pagination {
+                default_page_size: 20,
+                max_page_size: 100,
+                cursor_ttl: Duration::from_secs(60 * 60),
+            },
+            export: ExportSettings::default(),
+            mirror: MirrorSettings::default(),
+            request_id: RequestIdFormat::default(),
+            contract: ContractMode::default(),
+            dev_tokens: false,
+            claims: ClaimsPolicy::default(),
+            route_policy: RoutePolicy::default(),
+            preconditions: Preconditions::default(),
+            import_mapping: ColumnMapping::default(),
+            jobs: JobSettings::default(),
+            email_validation: EmailValidation::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Create validated settings from the program arguments.
+    pub fn new(options: &ProgramArgs) -> Result<Self, ConfigError> {
+        let allowed_origins = options
+            .cors_allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin).map_err(|_| ConfigError::CorsOrigin(origin.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mirror_url = options
+            .mirror_url
+            .as_ref()
+            .map(|url| match url.parse::<Uri>() {
+                Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => Ok(uri),
+                _ => Err(ConfigError::MirrorUrl(url.clone())),
+            })
+            .transpose()?;
+
+        let body_read_routes = options
+            .body_read_timeout_routes
+            .iter()
+            .map(|route| {
+                route
+                    .split_once('=')
+                    .and_then(|(path, secs)| Some((path.to_owned(), secs.parse().ok()?)))
+                    .map(|(path, secs)| (path, Duration::from_secs(secs)))
+                    .ok_or_else(|| ConfigError::BodyReadTimeoutRoute(route.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let import_mapping = match &options.import_mapping_file {
+            Some(path) => std::fs::read(path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| serde_json::from_slice(&json).map_err(|e| e.to_string()))
+                .map_err(|e| ConfigError::ImportMapping(path.clone(), e))?,
+            None => ColumnMapping::default(),
+        };
+
+        let route_policy = options.route_policy.policy().map_err(|e| {
+            ConfigError::RoutePolicy(
+                options
+                    .route_policy
+                    .route_policy_file
+                    .clone()
+                    .unwrap_or_default(),
+                e.to_string(),
+            )
+        })?;
+
+        let bootstrap = options.bootstrap()?;
+        if bootstrap.profile == Profile::Prod && options.contract_validation != ContractMode::Off {
+            return Err(ConfigError::ContractValidation(bootstrap.profile));
+        }
+
+        let settings = Self {
+            limits: Limits {
+                request_timeout: Duration::from_secs(options.request_timeout_secs),
+                body_limit: options.body_limit_bytes,
+                bulk_update_limit: options.bulk_update_limit,
+                max_search_results: options.max_search_results,
+            },
+            body_read: BodyReadSettings {
+                timeouts: ReadTimeouts {
+                    read_timeout: Duration::from_secs(options.body_read_timeout_secs),
+                    min_bytes_per_sec: options.body_min_bytes_per_sec,
+                },
+                routes: body_read_routes,
+            },
+            rate_limit: RateLimitSettings {
+                requests: options.rate_limit_requests,
+                window: Duration::from_secs(options.rate_limit_window_secs),
+            },
+            cors: CorsSettings { allowed_origins },
+            cache: CacheSettings {
+                max_age: Duration::from_secs(options.cache_max_age_secs),
+            },
+            pagination: Pagination {
+                default_page_size: options.default_page_size,
+                max_page_size: options.max_page_size,
+                cursor_ttl: Duration::from_secs(options.cursor_ttl_secs),
+            },
+            export: ExportSettings {
+                file: options.export_file.clone(),
+                resume_ttl: Duration::from_secs(options.download_resume_ttl_secs),
+                stop_at_token_expiry: options.download_stop_at_token_expiry,
+                drain_timeout: Duration::from_secs(options.download_drain_secs),
+                formats: export_formats(),
+            },
+            mirror: MirrorSettings {
+                base_url: mirror_url,
+                percent: options.mirror_percent,
+            },
+            request_id: options.request_id_format,
+            contract: options.contract_validation,
+            dev_tokens: bootstrap.dev_tokens,
+            claims: options.claims.policy(),
+            route_policy,
+            preconditions: options.conditional.preconditions(),
+            import_mapping,
+            jobs: JobSettings {
+                visibility_timeout: Duration::from_secs(options.job_visibility_timeout_secs),
+                max_attempts: options.job_max_attempts,
+                ..JobSettings::default()
+            },
+            email_validation: options.email_validation(),
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Check the settings are usable.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.limits.request_timeout.is_zero() {
+            return Err(ConfigError::RequestTimeout);
+        }
+        if self.limits.body_limit == 0 {
+            return Err(ConfigError::BodyLimit);
+        }
+        if self.body_read.timeouts.read_timeout.is_zero()
+            || self.body_read.routes.values().any(Duration::is_zero)
+        {
+            return Err(ConfigError::BodyReadTimeout);
+        }
+        if self.rate_limit.window.is_zero() {
+            return Err(ConfigError::RateLimitWindow);
+        }
+        if self.limits.bulk_update_limit == 0 {
+            return Err(ConfigError::BulkUpdateLimit);
+        }
+        if self.limits.max_search_results == 0 {
+            return Err(ConfigError::MaxSearchResults);
+        }
+        let Pagination {
+            default_page_size,
+            max_page_size,
+            cursor_ttl,
+        } = self.pagination;
+        if default_page_size == 0 || default_page_size > max_page_size {
+            return Err(ConfigError::PageSize {
+                default: default_page_size,
+                max: max_page_size,
+            });
+        }
+        if cursor_ttl.is_zero() {
+            return Err(ConfigError::CursorTtl);
+        }
+        if self.mirror.percent > 100 {
+            return Err(ConfigError::MirrorPercent(self.mirror.percent));
+        }
+        let visibility = self.jobs.visibility_timeout;
+        if visibility < Duration::from_secs(1) || visibility > Duration::from_secs(24 * 60 * 60) {
+            return Err(ConfigError::JobVisibilityTimeout);
+        }
+        if self.jobs.max_attempts == 0 {
+            return Err(ConfigError::JobMaxAttempts);
+        }
+        Ok(())
     }
 }
 
@@ -44,18 +654,47 @@ impl ProgramArgs {
 pub struct AppConfig {
     jwt_encoding_key: EncodingKey,
     jwt_decoding_key: DecodingKey,
-    hash_prefix: String,
+    hash_prefix: SecretString,
+    settings: Settings,
+    clock: Arc<dyn Clock>,
+    signer: Option<Arc<ResponseSigner>>,
+    signed_clients: Option<Arc<SignedClients>>,
 }
 
 impl AppConfig {
     /// Create a new application config state.
-    pub fn new(options: &ProgramArgs) -> Self {
-        let secret = options.jwt_secret.as_bytes();
-        Self {
+    pub fn new(options: &ProgramArgs) -> Result<Self, ConfigError> {
+        let secret = options.jwt_secret.expose_secret().as_bytes();
+        let signed_clients = match &options.signed_clients_file {
+            Some(path) => Some(Arc::new(
+                std::fs::read(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|json| SignedClients::from_json(&json))
+                    .map(|clients| {
+                        if options.require_signed_requests {
+                            clients.require_all()
+                        } else {
+                            clients
+                        }
+                    })
+                    .map_err(|e| ConfigError::SignedClients(path.clone(), e))?,
+            )),
+            None => None,
+        };
+        Ok(Self {
             jwt_decoding_key: DecodingKey::from_secret(secret),
             jwt_encoding_key: EncodingKey::from_secret(secret),
-            hash_prefix: "some_secret_prefix".to_owned(),
-        }
+            hash_prefix: SecretString::new("some_secret_prefix".to_owned()),
+            settings: Settings::new(options)?,
+            clock: Arc::new(SystemClock),
+            signer: options
+                .response_signing_key
+                .as_ref()
+                .map(ResponseSigner::from_seed)
+                .transpose()?
+                .map(Arc::new),
+            signed_clients,
+        })
     }
 
     /// Create a test application config state.
@@ -63,10 +702,61 @@ impl AppConfig {
         Self {
             jwt_decoding_key: DecodingKey::from_secret(secret),
             jwt_encoding_key: EncodingKey::from_secret(secret),
-            hash_prefix: "some_secret_prefix".to_owned(),
+            hash_prefix: SecretString::new("some_secret_prefix".to_owned()),
+            settings: Settings::default(),
+            clock: Arc::new(SystemClock),
+            signer: None,
+            signed_clients: None,
         }
     }
 
+    /// Replace the settings after validating them.
+    pub fn with_settings(self, settings: Settings) -> Result<Self, ConfigError> {
+        settings.validate()?;
+        Ok(Self { settings, ..self })
+    }
+
+    /// Read the time from `clock` instead of the system clock.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Sign response bodies with `signer`.
+    pub fn with_signer(self, signer: ResponseSigner) -> Self {
+        Self {
+            signer: Some(Arc::new(signer)),
+            ..self
+        }
+    }
+
+    /// Signer of response bodies when signing is enabled.
+    pub fn signer(&self) -> Option<&Arc<ResponseSigner>> {
+        self.signer.as_ref()
+    }
+
+    /// Verify the request bodies of the clients of `clients`.
+    pub fn with_signed_clients(self, clients: SignedClients) -> Self {
+        Self {
+            signed_clients: Some(Arc::new(clients)),
+            ..self
+        }
+    }
+
+    /// Clients whose request bodies are verified.
+    pub fn signed_clients(&self) -> Option<&Arc<SignedClients>> {
+        self.signed_clients.as_ref()
+    }
+
+    /// Get a reference to the clock used for expiry checks and timers.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Get a reference to the application settings.
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
     /// Get a reference to the JWT encoding key.
     pub fn jwt_encoding_key(&self) -> &EncodingKey {
         &self.jwt_encoding_key
@@ -79,17 +769,186 @@ impl AppConfig {
 
     /// Get a reference to the prefix for hashing.
     pub fn hash_prefix(&self) -> &str {
-        &self.hash_prefix
+        self.hash_prefix.expose_secret()
+    }
+}
+
+/// Shared state available to handlers and extractors via `State`.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    persist: Arc<dyn UserPersistence>,
+    config: Arc<AppConfig>,
+    downloader: Option<Arc<MongoPersistence>>,
+    downloads: DownloadTracker,
+    anomalies: Arc<dyn AnomalyDetector>,
+    tasks: Supervisor,
+    jobs: Arc<dyn JobQueue>,
+    audit: Arc<dyn AuditLog>,
+    references: References,
+    validation: ValidationStats,
+    tokens: Option<Arc<TokenIssuer<Role>>>,
+    kv: Arc<dyn KvStore>,
+}
+
+impl AppState {
+    /// Create the application state using the default anomaly detector.
+    pub fn new(persist: Arc<dyn UserPersistence>, config: AppConfig) -> Self {
+        let detector = ThresholdDetector::default().with_clock(config.clock.clone());
+        Self::with_anomaly_detector(persist, config, Arc::new(detector))
+    }
+
+    /// Create the application state. Mutations made through `persist` are
+    /// reported to `detector`.
+    pub fn with_anomaly_detector(
+        persist: Arc<dyn UserPersistence>,
+        config: AppConfig,
+        detector: Arc<dyn AnomalyDetector>,
+    ) -> Self {
+        let kv = MemoryKvStore::default().with_clock(config.clock.clone());
+        Self {
+            persist: Arc::new(AnomalyDetecting::new(persist, detector.clone())),
+            config: Arc::new(config),
+            downloader: None,
+            downloads: DownloadTracker::default(),
+            anomalies: detector,
+            tasks: Supervisor::default(),
+            jobs: Arc::new(MemoryJobQueue::default()),
+            audit: Arc::new(MemoryAuditLog::default()),
+            references: References::default(),
+            validation: ValidationStats::default(),
+            tokens: None,
+            kv: Arc::new(kv),
+        }
+    }
+
+    /// Enable the download endpoint which streams directly from mongodb.
+    pub fn with_downloader(self, downloader: Arc<MongoPersistence>) -> Self {
+        Self {
+            downloader: Some(downloader),
+            ..self
+        }
+    }
+
+    /// Register streamed downloads with `downloads`.
+    pub fn with_downloads(self, downloads: DownloadTracker) -> Self {
+        Self { downloads, ..self }
+    }
+
+    /// Report the health of the background tasks of `tasks` in `/readyz`.
+    pub fn with_tasks(self, tasks: Supervisor) -> Self {
+        Self { tasks, ..self }
+    }
+
+    /// Enqueue background jobs in `jobs`.
+    pub fn with_jobs(self, jobs: Arc<dyn JobQueue>) -> Self {
+        Self { jobs, ..self }
+    }
+
+    /// Record audited operations in `audit`.
+    pub fn with_audit_log(self, audit: Arc<dyn AuditLog>) -> Self {
+        Self { audit, ..self }
+    }
+
+    /// Cascade removals of users to the collections of `references` and
+    /// check them for dangling references.
+    pub fn with_references(self, references: References) -> Self {
+        Self { references, ..self }
+    }
+
+    /// Keep short lived state such as rate limit counters in `kv`.
+    pub fn with_kv_store(self, kv: Arc<dyn KvStore>) -> Self {
+        Self { kv, ..self }
+    }
+
+    /// Serve the login and refresh endpoints with `issuer`.
+    pub fn with_token_issuer(self, issuer: TokenIssuer<Role>) -> Self {
+        Self {
+            tokens: Some(Arc::new(issuer)),
+            ..self
+        }
+    }
+
+    /// Get a reference to the application config.
+    pub fn config(&self) -> &AppConfig {
+        &self.config
     }
 }
 
 /// Creates a test JWT for the given role.
 pub fn test_jwt(opts: &AppConfig, role: Role) -> String {
-    let expiration = Utc::now() + Duration::minutes(25);
-    let test_claims = JWTClaims {
-        sub: "droberts".to_owned(),
+    let test_claims = JWTClaims::issue(
+        "droberts".to_owned(),
         role,
-        exp: expiration.timestamp(),
-    };
+        opts.clock.now(),
+        chrono::Duration::minutes(25),
+        &opts.settings.claims,
+    );
     encode(&Header::default(), &test_claims, &opts.jwt_encoding_key).unwrap()
 }
+
+#[cfg(test)]
+mod test {
+    use super::{ConfigError, Settings};
+    use std::time::Duration;
+
+    #[test]
+    fn test_default_settings_valid() {
+        assert_eq!(Settings::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let mut settings = Settings::default();
+        settings.limits.request_timeout = Duration::ZERO;
+        assert_eq!(settings.validate(), Err(ConfigError::RequestTimeout));
+
+        let mut settings = Settings::default();
+        settings.limits.bulk_update_limit = 0;
+        assert_eq!(settings.validate(), Err(ConfigError::BulkUpdateLimit));
+
+        let mut settings = Settings::default();
+        settings
+            .body_read
+            .routes
+            .insert("/api/v1/user/import".to_owned(), Duration::ZERO);
+        assert_eq!(settings.validate(), Err(ConfigError::BodyReadTimeout));
+
+        let mut settings = Settings::default();
+        settings.rate_limit.window = Duration::ZERO;
+        assert_eq!(settings.validate(), Err(ConfigError::RateLimitWindow));
+
+        let mut settings = Settings::default();
+        settings.limits.max_search_results = 0;
+        assert_eq!(settings.validate(), Err(ConfigError::MaxSearchResults));
+
+        let mut settings = Settings::default();
+        settings.pagination.default_page_size = 500;
+        assert_eq!(
+            settings.validate(),
+            Err(ConfigError::PageSize {
+                default: 500,
+                max: 100
+            })
+        );
+
+        let mut settings = Settings::default();
+        settings.mirror.percent = 101;
+        assert_eq!(settings.validate(), Err(ConfigError::MirrorPercent(101)));
+
+        let mut settings = Settings::default();
+        settings.jobs.visibility_timeout = Duration::ZERO;
+        assert_eq!(settings.validate(), Err(ConfigError::JobVisibilityTimeout));
+
+        let mut settings = Settings::default();
+        settings.jobs.max_attempts = 0;
+        assert_eq!(settings.validate(), Err(ConfigError::JobMaxAttempts));
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.cache.cache_control(), "no-store");
+        settings.cache.max_age = Duration::from_secs(60);
+        assert_eq!(settings.cache.cache_control(), "private, max-age=60");
+    }
+}
@@ -0,0 +1,42 @@
+/*!
+Recording of audited operations.
+
+Handlers of operations changing users or reading them in bulk add a record
+to the [`AuditLog`] of the application state besides logging to
+[`AUDIT_TARGET`](crate::AUDIT_TARGET). The operation has already been done
+when it is recorded, so a record that can't be stored is logged rather
+than failing the request.
+*/
+use crate::{types::jwt::JWTClaims, AppConfig, USER_MS_TARGET};
+use tracing::{event, Level};
+use user_persist::{
+    audit::{AuditLog, AuditOperation, AuditRecord},
+    types::UserKey,
+};
+
+/// Record `operation` by the subject of `claims` in `log`, timed by the
+/// clock of `config`.
+pub async fn record(
+    log: &dyn AuditLog,
+    config: &AppConfig,
+    claims: &JWTClaims,
+    operation: AuditOperation,
+    target_user: Option<UserKey>,
+    detail: String,
+) {
+    let record = AuditRecord {
+        at_ms: config.clock().now().timestamp_millis(),
+        actor: claims.sub.clone(),
+        operation,
+        target_user,
+        detail,
+    };
+    if let Err(e) = log.record(&record).await {
+        event!(
+          target: USER_MS_TARGET,
+          Level::ERROR,
+          "Failed to record {operation:?} by {}: {e}",
+          record.actor
+        );
+    }
+}
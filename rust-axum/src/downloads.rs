@@ -0,0 +1,237 @@
+/*!
+Tracking of streamed user downloads.
+
+A download streamed from the database can run for minutes, holding its
+connection open. Each one is registered with the [`DownloadTracker`] for
+as long as its body is streamed, so admins can list and cancel them and
+shutdown can wait for them to finish before closing connections. A
+cancelled download ends like one cut short at token expiry, with a
+truncated body the client resumes from.
+
+The number of active downloads and the bytes they sent so far are
+exported as the [`ACTIVE_DOWNLOADS_METRIC`] and [`DOWNLOAD_BYTES_METRIC`]
+gauges.
+*/
+use futures::{FutureExt, StreamExt};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{oneshot, watch};
+use user_persist::export::ExportStream;
+use uuid::Uuid;
+
+/// Gauge of the downloads being streamed.
+pub const ACTIVE_DOWNLOADS_METRIC: &str = "downloads_active";
+/// Gauge of the bytes sent by the downloads being streamed.
+pub const DOWNLOAD_BYTES_METRIC: &str = "downloads_active_bytes_sent";
+
+/// A download being streamed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloadInfo {
+    pub id: String,
+    /// Subject of the token authorizing the download.
+    pub actor: String,
+    /// Name of the export format.
+    pub format: String,
+    /// Start time in milliseconds since the epoch.
+    pub started_at_ms: i64,
+    /// Bytes of the body sent so far.
+    pub bytes_sent: u64,
+}
+
+struct ActiveDownload {
+    info: DownloadInfo,
+    bytes_sent: Arc<AtomicU64>,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+/// Registry of the downloads being streamed.
+#[derive(Clone)]
+pub struct DownloadTracker {
+    active: Arc<Mutex<BTreeMap<String, ActiveDownload>>>,
+    /// Number of active downloads, watched while draining.
+    count: Arc<watch::Sender<usize>>,
+}
+
+impl Default for DownloadTracker {
+    fn default() -> Self {
+        Self {
+            active: Arc::default(),
+            count: Arc::new(watch::channel(0).0),
+        }
+    }
+}
+
+/// Unregisters a download when its body is dropped, whether it completed,
+/// failed, was cancelled or the client went away.
+struct Registration {
+    tracker: DownloadTracker,
+    id: String,
+    bytes_sent: Arc<AtomicU64>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut active = self.tracker.active.lock().unwrap();
+        active.remove(&self.id);
+        self.tracker.count.send_replace(active.len());
+        metrics::decrement_gauge!(ACTIVE_DOWNLOADS_METRIC, 1.0);
+        metrics::decrement_gauge!(
+            DOWNLOAD_BYTES_METRIC,
+            self.bytes_sent.load(Ordering::Relaxed) as f64
+        );
+    }
+}
+
+impl DownloadTracker {
+    /// Register a download by `actor` in `format` streaming `body`. The
+    /// download stays registered until the returned stream is dropped and
+    /// ends early when cancelled.
+    pub fn track(
+        &self,
+        actor: String,
+        format: String,
+        started_at_ms: i64,
+        body: ExportStream,
+    ) -> ExportStream {
+        let id = Uuid::new_v4().to_string();
+        let bytes_sent = Arc::new(AtomicU64::new(0));
+        let (cancel, cancelled) = oneshot::channel();
+        {
+            let mut active = self.active.lock().unwrap();
+            active.insert(
+                id.clone(),
+                ActiveDownload {
+                    info: DownloadInfo {
+                        id: id.clone(),
+                        actor,
+                        format,
+                        started_at_ms,
+                        bytes_sent: 0,
+                    },
+                    bytes_sent: bytes_sent.clone(),
+                    cancel: Some(cancel),
+                },
+            );
+            self.count.send_replace(active.len());
+        }
+        metrics::increment_gauge!(ACTIVE_DOWNLOADS_METRIC, 1.0);
+
+        let registration = Registration {
+            tracker: self.clone(),
+            id,
+            bytes_sent,
+        };
+        body.take_until(cancelled.map(|_| ()))
+            .inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    let len = chunk.len() as u64;
+                    registration.bytes_sent.fetch_add(len, Ordering::Relaxed);
+                    metrics::increment_gauge!(DOWNLOAD_BYTES_METRIC, len as f64);
+                }
+            })
+            .boxed()
+    }
+
+    /// Downloads being streamed, oldest first.
+    pub fn list(&self) -> Vec<DownloadInfo> {
+        let mut downloads = self
+            .active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|download| DownloadInfo {
+                bytes_sent: download.bytes_sent.load(Ordering::Relaxed),
+                ..download.info.clone()
+            })
+            .collect::<Vec<_>>();
+        downloads.sort_by_key(|download| download.started_at_ms);
+        downloads
+    }
+
+    /// Cancel the download `id`. Returns false when no such download is
+    /// being streamed.
+    pub fn cancel(&self, id: &str) -> bool {
+        let mut active = self.active.lock().unwrap();
+        match active
+            .get_mut(id)
+            .and_then(|download| download.cancel.take())
+        {
+            Some(cancel) => {
+                let _ = cancel.send(());
+                true
+            }
+            None => active.contains_key(id),
+        }
+    }
+
+    /// Cancel every download being streamed, returning how many were.
+    pub fn cancel_all(&self) -> usize {
+        let mut active = self.active.lock().unwrap();
+        for download in active.values_mut() {
+            if let Some(cancel) = download.cancel.take() {
+                let _ = cancel.send(());
+            }
+        }
+        active.len()
+    }
+
+    /// Wait up to `timeout` for the active downloads to finish. Returns
+    /// the number still being streamed.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        let mut count = self.count.subscribe();
+        let _ = tokio::time::timeout(timeout, count.wait_for(|count| *count == 0)).await;
+        let remaining = *count.borrow();
+        remaining
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DownloadTracker;
+    use futures::{stream, StreamExt};
+    use std::time::Duration;
+    use user_persist::export::ExportStream;
+
+    fn body(chunks: usize) -> ExportStream {
+        stream::iter((0..chunks).map(|_| Ok(b"abcd".to_vec())))
+            .chain(stream::pending())
+            .boxed()
+    }
+
+    #[tokio::test]
+    async fn test_track_and_cancel() {
+        let tracker = DownloadTracker::default();
+        let mut first = tracker.track("alice".to_owned(), "json".to_owned(), 2, body(2));
+        let second = tracker.track("bob".to_owned(), "csv".to_owned(), 1, body(0));
+
+        first.next().await.unwrap().unwrap();
+        first.next().await.unwrap().unwrap();
+        let downloads = tracker.list();
+        assert_eq!(
+            downloads
+                .iter()
+                .map(|d| (d.actor.as_str(), d.bytes_sent))
+                .collect::<Vec<_>>(),
+            [("bob", 0), ("alice", 8)]
+        );
+
+        assert!(tracker.cancel(&downloads[1].id));
+        assert!(first.next().await.is_none());
+        assert!(!tracker.cancel("unknown"));
+
+        // Cancelled downloads are registered until their body is dropped.
+        assert_eq!(tracker.drain(Duration::from_millis(10)).await, 2);
+        drop(first);
+        assert_eq!(tracker.list().len(), 1);
+
+        assert_eq!(tracker.cancel_all(), 1);
+        drop(second);
+        assert_eq!(tracker.drain(Duration::from_secs(1)).await, 0);
+    }
+}
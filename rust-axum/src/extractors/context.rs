@@ -0,0 +1,16 @@
+use crate::types::context::{RequestContext, TimezoneError};
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::request::Parts};
+
+#[async_trait]
+/// Extractor of the locale and time zone of the client.
+impl<S> FromRequestParts<S> for RequestContext
+where
+    S: Send + Sync,
+{
+    type Rejection = TimezoneError;
+
+    async fn from_request_parts(req: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        RequestContext::from_headers(&req.headers)
+    }
+}
@@ -9,7 +9,7 @@ use crate::{
 use async_trait::async_trait;
 use axum::{
     body::HttpBody,
-    extract::FromRequest,
+    extract::{FromRef, FromRequest},
     response::{IntoResponse, Response},
     BoxError, Json,
 };
@@ -18,7 +18,7 @@ use serde::de::DeserializeOwned;
 use serde_json::json;
 use std::sync::Arc;
 use thiserror::Error;
-use user_persist::Validate;
+use user_persist::{types::ValidateRequest, validation::ValidationStats, Validate};
 
 /// An extractor that applies the following:
 /// * Hashing validation
@@ -41,6 +41,7 @@ impl IntoResponse for HashedValidatingError {
           "message": self.to_string()
         });
         match self {
+            Self::Json(e @ JsonValidationError::ContentType { .. }) => e.into_response(),
             Self::InvalidHash => (StatusCode::UNAUTHORIZED, Json(body)).into_response(),
             _ => (StatusCode::BAD_REQUEST, Json(body)).into_response(),
         }
@@ -53,17 +54,15 @@ where
     B: HttpBody + Send + 'static,
     B::Data: Send,
     B::Error: Into<BoxError>,
-    T: Validate + HashValidating + DeserializeOwned,
+    T: ValidateRequest + HashValidating + DeserializeOwned,
+    Arc<AppConfig>: FromRef<S>,
+    ValidationStats: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = HashedValidatingError;
 
     async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
-        let config = req
-            .extensions()
-            .get::<Arc<AppConfig>>()
-            .expect("No AppConfig. Did you forget to add Extension layer?")
-            .clone();
+        let config = Arc::<AppConfig>::from_ref(state);
 
         let ValidatingJson(data): ValidatingJson<T> =
             ValidatingJson::from_request(req, state).await?;
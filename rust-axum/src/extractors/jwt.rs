@@ -1,10 +1,11 @@
 use crate::{
+    middleware::request_trace::record_principal,
     types::jwt::{AdminAccess, AuthError, JWTClaims, Role, UserAccess},
     AppConfig,
 };
 use async_trait::async_trait;
 use axum::{
-    extract::{FromRequestParts, TypedHeader},
+    extract::{FromRef, FromRequestParts, MatchedPath, TypedHeader},
     headers::{authorization::Bearer, Authorization},
     http::request::Parts,
 };
@@ -14,6 +15,7 @@ use std::sync::Arc;
 #[async_trait]
 impl<S> FromRequestParts<S> for JWTClaims
 where
+    Arc<AppConfig>: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = AuthError;
@@ -24,53 +26,89 @@ where
 }
 
 #[async_trait]
-/// Extractor that enforces access for an Amdin role.
+/// Extractor that enforces access for an Amdin role, or the roles the
+/// route policy allows on the route.
 impl<S> FromRequestParts<S> for AdminAccess
 where
+    Arc<AppConfig>: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = AuthError;
 
     async fn from_request_parts(req: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        match extract_jwt(req, state).await? {
-            claims if claims.role == Role::Admin => Ok(Self(claims)),
-            JWTClaims { role, .. } => Err(AuthError::RoleNotPermitted(role)),
-        }
+        let claims = extract_jwt(req, state).await?;
+        authorize(req, state, claims, Role::Admin).map(Self)
     }
 }
 
 #[async_trait]
-/// Extractor that enforces access for a User role.
+/// Extractor that enforces access for a User role, or the roles the route
+/// policy allows on the route.
 impl<S> FromRequestParts<S> for UserAccess
 where
+    Arc<AppConfig>: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = AuthError;
 
     async fn from_request_parts(req: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        match extract_jwt(req, state).await? {
-            claims if claims.role == Role::User => Ok(Self(claims)),
-            JWTClaims { role, .. } => Err(AuthError::RoleNotPermitted(role)),
-        }
+        let claims = extract_jwt(req, state).await?;
+        authorize(req, state, claims, Role::User).map(Self)
+    }
+}
+
+/// Check the role of `claims` may call the matched route, whose handler
+/// requires `required` unless the route policy lists it.
+fn authorize<S>(
+    req: &Parts,
+    state: &S,
+    claims: JWTClaims,
+    required: Role,
+) -> Result<JWTClaims, AuthError>
+where
+    Arc<AppConfig>: FromRef<S>,
+{
+    let config = Arc::<AppConfig>::from_ref(state);
+    let route = req
+        .extensions
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or_else(|| req.uri.path());
+    let permitted = config.settings().route_policy.permits(
+        req.method.as_str(),
+        route,
+        &claims.role.to_string(),
+        &required.to_string(),
+    );
+    if permitted {
+        Ok(claims)
+    } else {
+        Err(AuthError::RoleNotPermitted(claims.role))
     }
 }
 
 /// Parse the JWT from the request header.
 async fn extract_jwt<S>(req: &mut Parts, state: &S) -> Result<JWTClaims, AuthError>
 where
+    Arc<AppConfig>: FromRef<S>,
     S: Send + Sync,
 {
     let TypedHeader(Authorization(bearer)) =
         TypedHeader::<Authorization<Bearer>>::from_request_parts(req, state)
             .await
             .map_err(|_| AuthError::MissingAuth)?;
-    let key = req
-        .extensions
-        .get::<Arc<AppConfig>>()
-        .map(|config| config.jwt_decoding_key())
-        .expect("Missing Extension(Arc<AppConfig>)");
+    let config = Arc::<AppConfig>::from_ref(state);
 
-    decode::<JWTClaims>(bearer.token(), key, &Validation::default())
+    // Expiry is checked against the configured clock rather than by the
+    // decoder which always reads the system time.
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+
+    let claims = decode::<JWTClaims>(bearer.token(), config.jwt_decoding_key(), &validation)
         .map(|t| t.claims)
-        .map_err(|_| AuthError::InvalidToken)
+        .map_err(|_| AuthError::InvalidToken)?;
+
+    claims.validate(&config.settings().claims, config.clock().now())?;
+    record_principal(&claims);
+    Ok(claims)
 }
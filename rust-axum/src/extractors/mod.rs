@@ -1,6 +1,7 @@
 /*!
 API Payload extractors.
 */
+pub mod context;
 pub mod hashing;
 pub mod jwt;
 pub mod validator;
@@ -1,18 +1,26 @@
-use crate::USER_MS_TARGET;
+use crate::{
+    middleware::audit::AuditEvent,
+    types::{context::Locale, handler::error_envelope},
+    AppConfig, USER_MS_TARGET,
+};
 use async_trait::async_trait;
 use axum::{
     body::HttpBody,
-    extract::{rejection::JsonRejection, FromRequest, Json},
-    http::{Request, StatusCode},
+    extract::{rejection::JsonRejection, FromRef, FromRequest, Json},
+    http::{header::CONTENT_TYPE, Request, StatusCode},
     response::{IntoResponse, Response},
     BoxError,
 };
+use bootstrap::content_type::{check_json, ContentTypeError, UNSUPPORTED_MEDIA_TYPE_LABEL};
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::{json, to_value};
-use std::ops::Deref;
+use std::{ops::Deref, sync::Arc};
 use thiserror::Error;
+use tower_http::request_id::RequestId;
 use tracing::error;
-use user_persist::{Validate, ValidationErrors};
+use user_persist::{
+    types::ValidateRequest, validation::ValidationStats, Validate, ValidationErrors,
+};
 
 /// An extractor that adds value validators to a Json validator.
 #[derive(Debug, Clone, Copy, Default)]
@@ -20,6 +28,11 @@ pub struct ValidatingJson<T: Validate>(pub T);
 
 #[derive(Debug, Error)]
 pub enum JsonValidationError {
+    #[error("{source}")]
+    ContentType {
+        source: ContentTypeError,
+        request_id: Option<RequestId>,
+    },
     #[error("Json validation error: `{0}`")]
     JsonError(#[from] JsonRejection),
     #[error("Validation failed: `{0}`")]
@@ -28,13 +41,16 @@ pub enum JsonValidationError {
 
 /// Validation errors for all validations that failed.
 #[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 struct ValidationErrorResponse {
     validation_errors: ValidationErrors,
     label: String,
 }
 
 /// Uses a Json extractor and adds validation
-/// to the extracted type via the Validate trait.
+/// to the extracted type via the ValidateRequest trait, checking emails in
+/// the mode of the settings. Failures are counted in the
+/// [`ValidationStats`] of the state.
 #[async_trait]
 impl<S, B, T> FromRequest<S, B> for ValidatingJson<T>
 where
@@ -42,14 +58,35 @@ where
     B::Data: Send,
     B::Error: Into<BoxError>,
 
-    T: Validate + DeserializeOwned,
+    T: ValidateRequest + DeserializeOwned,
+    Arc<AppConfig>: FromRef<S>,
+    ValidationStats: FromRef<S>,
     S: Send + Sync,
 {
     type Rejection = JsonValidationError;
 
     async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        if let Err(source) = check_json(content_type) {
+            return Err(JsonValidationError::ContentType {
+                source,
+                request_id: req.extensions().get::<RequestId>().cloned(),
+            });
+        }
+
+        let locale = Locale::from_headers(req.headers());
         let Json(data): Json<T> = Json::from_request(req, state).await?;
-        data.validate()?;
+        let mode = Arc::<AppConfig>::from_ref(state)
+            .settings()
+            .email_validation;
+        data.validate_request(mode).map_err(|mut errors| {
+            ValidationStats::from_ref(state).record(&errors);
+            locale.localize(&mut errors);
+            errors
+        })?;
         Ok(Self(data))
     }
 }
@@ -58,22 +95,37 @@ impl IntoResponse for JsonValidationError {
     fn into_response(self) -> Response {
         error!(target: USER_MS_TARGET, "Input failed validation: {self}");
 
-        let body = match self {
+        let (body, fields) = match self {
+            Self::ContentType { source, request_id } => {
+                let body =
+                    error_envelope(UNSUPPORTED_MEDIA_TYPE_LABEL, source, request_id.as_ref());
+                return (StatusCode::UNSUPPORTED_MEDIA_TYPE, body).into_response();
+            }
             Self::JsonError(e) => {
-                json!({
+                let body = json!({
                   "label": "json_parse.failed",
                   "message": e.to_string()
-                })
+                });
+                (body, Vec::new())
             }
             Self::JsonValidation(e) => {
+                let mut fields = e.errors().keys().copied().collect::<Vec<_>>();
+                fields.sort_unstable();
                 let validation_response = ValidationErrorResponse {
                     validation_errors: e,
                     label: "validation.failed".to_owned(),
                 };
-                to_value(&validation_response).unwrap_or_else(|e| json!({"error": e.to_string()}))
+                let body = to_value(&validation_response)
+                    .unwrap_or_else(|e| json!({"error": e.to_string()}));
+                (body, fields)
             }
         };
-        (StatusCode::BAD_REQUEST, Json(body)).into_response()
+        let response = (StatusCode::BAD_REQUEST, Json(body)).into_response();
+        if fields.is_empty() {
+            response
+        } else {
+            AuditEvent::ValidationFailure(fields).attach(response)
+        }
     }
 }
 
@@ -0,0 +1,258 @@
+/*!
+Handlers for administrative endpoints.
+*/
+use crate::{
+    allocator::{self, AllocatorStats},
+    audit,
+    downloads::DownloadTracker,
+    extractors::validator::ValidatingJson,
+    handlers::user_handlers::ndjson_response,
+    jobs::{ExportJob, EXPORT_JOB},
+    types::{context::RequestContext, handler::HandlerError, jwt::AdminAccess},
+    AppConfig, AUDIT_TARGET, USER_MS_TARGET,
+};
+use api_types::{AuditSearchRequest, DownloadResponse, JobResponse};
+use axum::{
+    body::BoxBody,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    BoxError, Json,
+};
+use errors::ConfigError;
+use futures::{stream, StreamExt, TryStreamExt};
+use http::Response;
+use hyper::Body;
+use serde::Deserialize;
+use serde_json::to_string;
+use std::sync::Arc;
+use tracing::{debug, event, Level};
+use user_persist::{
+    anomaly::{Anomaly, AnomalyDetector},
+    audit::{AuditFilter, AuditLog, AuditOperation, AuditRecord},
+    jobs::JobQueue,
+    mongo_persistence::MongoPersistence,
+    references::References,
+    types::SearchPage,
+    validation::{FieldFailures, ValidationStats},
+};
+
+/// List recently detected anomalies, newest first.
+pub async fn list_anomalies(
+    State(detector): State<Arc<dyn AnomalyDetector>>,
+    claims: AdminAccess,
+) -> Json<Vec<Anomaly>> {
+    debug!(target: USER_MS_TARGET, "Listing anomalies for {claims}");
+    Json(detector.recent())
+}
+
+/// Query parameters for the validation failures.
+#[derive(Debug, Deserialize)]
+pub struct ValidationFailureParams {
+    /// Number of failures to list.
+    #[serde(default = "default_failure_limit")]
+    limit: usize,
+}
+
+fn default_failure_limit() -> usize {
+    10
+}
+
+/// List the most frequent validation failures by field and code.
+pub async fn validation_failures(
+    State(stats): State<ValidationStats>,
+    Query(params): Query<ValidationFailureParams>,
+    claims: AdminAccess,
+) -> Json<Vec<FieldFailures>> {
+    debug!(target: USER_MS_TARGET, "Listing validation failures for {claims}");
+    Json(stats.top(params.limit))
+}
+
+/// Statistics of the global allocator.
+pub async fn allocator_stats(claims: AdminAccess) -> Result<Json<AllocatorStats>, HandlerError> {
+    debug!(target: USER_MS_TARGET, "Reading allocator statistics for {claims}");
+    Ok(Json(allocator::stats()?))
+}
+
+/// Query parameters for an integrity check.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct IntegrityParams {
+    /// Apply the safe repairs.
+    fix: bool,
+}
+
+/// Check the stored users for inconsistencies and stream the findings as
+/// newline delimited JSON. Only served by the mongodb backend.
+pub async fn check_integrity(
+    State(mongo): State<Option<Arc<MongoPersistence>>>,
+    State(references): State<References>,
+    State(audit_log): State<Arc<dyn AuditLog>>,
+    State(app_config): State<Arc<AppConfig>>,
+    Query(params): Query<IntegrityParams>,
+    claims: AdminAccess,
+) -> Result<Response<BoxBody>, HandlerError> {
+    let db = mongo.ok_or(ConfigError::Disabled("The mongodb backend"))?;
+
+    if params.fix {
+        event!(target: AUDIT_TARGET, Level::INFO, "integrity repair by {claims}");
+        audit::record(
+            audit_log.as_ref(),
+            &app_config,
+            &claims.0,
+            AuditOperation::IntegrityRepair,
+            None,
+            "integrity repair".to_owned(),
+        )
+        .await;
+    } else {
+        debug!(target: USER_MS_TARGET, "Checking integrity for {claims}");
+    }
+
+    // Dangling references of related collections follow the users.
+    let users = db.clone();
+    let dangling = stream::once(async move { references.dangling(users.as_ref()).await })
+        .map_ok(|findings| stream::iter(findings.into_iter().map(Ok)))
+        .try_flatten();
+    let stream =
+        db.check_integrity(params.fix).await?.chain(dangling).map(
+            |finding| -> Result<String, BoxError> { Ok(format!("{}\n", to_string(&finding?)?)) },
+        );
+
+    Ok(ndjson_response(Body::wrap_stream(stream)))
+}
+
+/// State of a background job such as a queued import.
+pub async fn get_job(
+    State(jobs): State<Arc<dyn JobQueue>>,
+    Path(id): Path<String>,
+    claims: AdminAccess,
+    context: RequestContext,
+) -> Result<Json<JobResponse>, HandlerError> {
+    debug!(target: USER_MS_TARGET, "Looking up job {id} for {claims}");
+    let job = jobs.get(&id).await?.ok_or(HandlerError::ResourceNotFound)?;
+    Ok(Json(JobResponse {
+        created_at: context.format_time_ms(job.created_at_ms),
+        ..job.into()
+    }))
+}
+
+/// Query parameters queueing an export.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ExportParams {
+    /// Name of a registered export format, `json` when not given.
+    format: Option<String>,
+}
+
+/// Queue a job materializing the user export to the export file. The job
+/// is answered with `202 Accepted` and its state reports the manifest of
+/// the export once written.
+pub async fn export_users(
+    State(jobs): State<Arc<dyn JobQueue>>,
+    State(audit_log): State<Arc<dyn AuditLog>>,
+    State(app_config): State<Arc<AppConfig>>,
+    Query(params): Query<ExportParams>,
+    claims: AdminAccess,
+) -> Result<(StatusCode, Json<JobResponse>), HandlerError> {
+    let export = &app_config.settings().export;
+    if export.file.is_none() {
+        return Err(ConfigError::Disabled("The export file").into());
+    }
+    let format = params.format.unwrap_or_else(|| "json".to_owned());
+    let formatter = export
+        .formats
+        .by_name(&format)
+        .ok_or(HandlerError::UnknownFormat(format))?;
+
+    let payload = ExportJob {
+        format: formatter.name().to_owned(),
+    };
+    let job = jobs
+        .enqueue(
+            EXPORT_JOB,
+            serde_json::to_value(payload)?,
+            app_config.settings().jobs.max_attempts,
+        )
+        .await?;
+    event!(
+      target: AUDIT_TARGET,
+      Level::INFO,
+      "export job {} queued by {claims}",
+      job.id
+    );
+    audit::record(
+        audit_log.as_ref(),
+        &app_config,
+        &claims.0,
+        AuditOperation::Export,
+        None,
+        format!("{} export job {} queued", formatter.name(), job.id),
+    )
+    .await;
+    Ok((StatusCode::ACCEPTED, Json(job.into())))
+}
+
+/// Query parameters of an audit log search.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AuditSearchParams {
+    /// Number of matching records skipped.
+    offset: u64,
+    /// Page size, bounded by the configured maximum.
+    limit: Option<u64>,
+}
+
+/// Search the audit log, answering a page of matching records newest
+/// first.
+pub async fn search_audit(
+    State(audit_log): State<Arc<dyn AuditLog>>,
+    State(app_config): State<Arc<AppConfig>>,
+    Query(params): Query<AuditSearchParams>,
+    claims: AdminAccess,
+    ValidatingJson(request): ValidatingJson<AuditSearchRequest>,
+) -> Result<Json<SearchPage<AuditRecord>>, HandlerError> {
+    let filter = AuditFilter::from(request);
+    debug!(target: USER_MS_TARGET, "Searching audit log with {filter:?} for {claims}");
+    let pagination = &app_config.settings().pagination;
+    let limit = params
+        .limit
+        .unwrap_or(pagination.default_page_size.into())
+        .min(pagination.max_page_size.into());
+    Ok(Json(audit_log.search(&filter, params.offset, limit).await?))
+}
+
+/// Streamed downloads in progress, oldest first.
+pub async fn list_downloads(
+    State(downloads): State<DownloadTracker>,
+    claims: AdminAccess,
+    context: RequestContext,
+) -> Json<Vec<DownloadResponse>> {
+    debug!(target: USER_MS_TARGET, "Listing downloads for {claims}");
+    let downloads = downloads
+        .list()
+        .into_iter()
+        .map(|download| DownloadResponse {
+            started_at: context.format_time_ms(download.started_at_ms),
+            id: download.id,
+            actor: download.actor,
+            format: download.format,
+            started_at_ms: download.started_at_ms,
+            bytes_sent: download.bytes_sent,
+        })
+        .collect();
+    Json(downloads)
+}
+
+/// Cancel a streamed download. Its body ends truncated so the client can
+/// resume it later.
+pub async fn cancel_download(
+    State(downloads): State<DownloadTracker>,
+    Path(id): Path<String>,
+    claims: AdminAccess,
+) -> Result<StatusCode, HandlerError> {
+    if !downloads.cancel(&id) {
+        return Err(HandlerError::ResourceNotFound);
+    }
+    event!(target: AUDIT_TARGET, Level::INFO, "download {id} cancelled by {claims}");
+    Ok(StatusCode::NO_CONTENT)
+}
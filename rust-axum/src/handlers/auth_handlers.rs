@@ -0,0 +1,58 @@
+/*!
+Handlers for the login and refresh endpoints.
+*/
+use crate::{arguments::AppConfig, types::handler::HandlerError, JWTClaims, Role, USER_MS_TARGET};
+use axum::{extract::State, Json};
+use bootstrap::token::{Grant, LoginRequest, RefreshRequest, TokenIssuer, TokenResponse};
+use errors::ConfigError;
+use jsonwebtoken::{encode, Header};
+use std::sync::Arc;
+use tracing::{event, Level};
+
+/// Exchange the credentials of an account for tokens.
+pub async fn login(
+    State(issuer): State<Option<Arc<TokenIssuer<Role>>>>,
+    State(config): State<Arc<AppConfig>>,
+    Json(request): Json<LoginRequest>,
+) -> Result<Json<TokenResponse>, HandlerError> {
+    let issuer = issuer.ok_or(ConfigError::Disabled("Login"))?;
+    // The user name isn't logged as users mistype passwords into it.
+    let grant = issuer
+        .login(&request, config.clock().now().timestamp())
+        .await
+        .map_err(|e| {
+            event!(target: USER_MS_TARGET, Level::WARN, "Login failed: {e}");
+            e
+        })?;
+    Ok(Json(sign(grant, &config)?))
+}
+
+/// Exchange a refresh token for new tokens.
+pub async fn refresh(
+    State(issuer): State<Option<Arc<TokenIssuer<Role>>>>,
+    State(config): State<Arc<AppConfig>>,
+    Json(request): Json<RefreshRequest>,
+) -> Result<Json<TokenResponse>, HandlerError> {
+    let issuer = issuer.ok_or(ConfigError::Disabled("Login"))?;
+    let grant = issuer
+        .refresh(&request, config.clock().now().timestamp())
+        .await
+        .map_err(|e| {
+            event!(target: USER_MS_TARGET, Level::WARN, "Refresh failed: {e}");
+            e
+        })?;
+    Ok(Json(sign(grant, &config)?))
+}
+
+/// Sign the access token of `grant`.
+fn sign(grant: Grant<Role>, config: &AppConfig) -> Result<TokenResponse, HandlerError> {
+    let claims = JWTClaims::issue(
+        grant.sub.clone(),
+        grant.role.clone(),
+        config.clock().now(),
+        chrono::Duration::seconds(grant.access_ttl.as_secs() as i64),
+        &config.settings().claims,
+    );
+    let access_token = encode(&Header::default(), &claims, config.jwt_encoding_key())?;
+    Ok(grant.response(access_token, claims.exp))
+}
@@ -0,0 +1,35 @@
+/*!
+Handlers for development only endpoints.
+*/
+use crate::{arguments::AppConfig, types::handler::HandlerError, JWTClaims, Role, USER_MS_TARGET};
+use axum::{extract::State, Json};
+use bootstrap::{DevTokenRequest, DevTokenResponse};
+use jsonwebtoken::{encode, Header};
+use std::sync::Arc;
+use tracing::{event, Level};
+
+/// Mint a signed JWT for local testing.
+pub async fn dev_token(
+    State(config): State<Arc<AppConfig>>,
+    Json(request): Json<DevTokenRequest<Role>>,
+) -> Result<Json<DevTokenResponse>, HandlerError> {
+    let ttl = request.validate()?;
+    let claims = JWTClaims::issue(
+        request.sub,
+        request.role,
+        config.clock().now(),
+        chrono::Duration::seconds(ttl.as_secs() as i64),
+        &config.settings().claims,
+    );
+
+    event!(
+      target: USER_MS_TARGET,
+      Level::WARN,
+      "Minting dev token for {claims}"
+    );
+
+    Ok(Json(DevTokenResponse {
+        token: encode(&Header::default(), &claims, config.jwt_encoding_key())?,
+        expires_at: claims.exp,
+    }))
+}
@@ -0,0 +1,37 @@
+/*!
+Handlers for the API documentation endpoints.
+*/
+use crate::{openapi, AppConfig};
+use axum::{extract::State, response::Html, Json};
+use std::sync::Arc;
+
+/// Swagger UI page browsing the OpenAPI document.
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>User service API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+  <script>
+    window.onload = () => {
+      window.ui = SwaggerUIBundle({ url: "/api/v1/openapi.json", dom_id: "#swagger-ui" });
+    };
+  </script>
+</body>
+</html>
+"##;
+
+/// OpenAPI document of the service with the roles of the configured route
+/// policy.
+pub async fn openapi(State(app_config): State<Arc<AppConfig>>) -> Json<utoipa::openapi::OpenApi> {
+    Json(openapi::document(&app_config.settings().route_policy))
+}
+
+/// Swagger UI of the OpenAPI document.
+pub async fn swagger_ui() -> Html<&'static str> {
+    Html(SWAGGER_UI)
+}
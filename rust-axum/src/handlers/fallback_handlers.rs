@@ -0,0 +1,113 @@
+/*!
+Handlers for requests that don't match a route or method.
+*/
+use crate::{types::handler::error_envelope, FRAMEWORK_TARGET};
+use axum::{
+    extract::Extension,
+    http::{
+        header::{ALLOW, CONTENT_LENGTH},
+        HeaderValue, Method, Request, StatusCode, Uri,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use tower::timeout::error::Elapsed;
+use tower_http::request_id::RequestId;
+use tracing::{event, Level};
+
+/// Fallback handler for paths without a route.
+pub async fn not_found(
+    method: Method,
+    uri: Uri,
+    req_id: Option<Extension<RequestId>>,
+) -> impl IntoResponse {
+    event!(
+      target: FRAMEWORK_TARGET,
+      Level::WARN,
+      "No route for {method} {}",
+      uri.path()
+    );
+
+    (
+        StatusCode::NOT_FOUND,
+        error_envelope(
+            "not.found",
+            format!("No route for {method} {}", uri.path()),
+            req_id.as_deref(),
+        ),
+    )
+}
+
+/// Replaces the empty body axum sends with a 405 by the JSON error
+/// envelope, keeping the `Allow` header listing the permitted methods.
+///
+/// An `OPTIONS` request for a route without an options handler is answered
+/// with a 204 and the `Allow` header instead.
+pub async fn method_not_allowed<B>(req: Request<B>, next: Next<B>) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let req_id = req.extensions().get::<RequestId>().cloned();
+
+    let response = next.run(req).await;
+
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+
+    if method == Method::OPTIONS {
+        let allow = response
+            .headers()
+            .get(ALLOW)
+            .and_then(|allow| allow.to_str().ok())
+            .map(|allow| format!("{allow},OPTIONS"))
+            .and_then(|allow| HeaderValue::from_str(&allow).ok())
+            .unwrap_or_else(|| HeaderValue::from_static("OPTIONS"));
+        return (StatusCode::NO_CONTENT, [(ALLOW, allow)]).into_response();
+    }
+
+    event!(
+      target: FRAMEWORK_TARGET,
+      Level::WARN,
+      "Method {method} not allowed for {path}"
+    );
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+
+    (
+        parts,
+        error_envelope(
+            "method.not_allowed",
+            format!("Method {method} not allowed for {path}"),
+            req_id.as_ref(),
+        ),
+    )
+        .into_response()
+}
+
+/// Converts errors from the timeout layer into the JSON error envelope.
+pub async fn handle_timeout(
+    method: Method,
+    uri: Uri,
+    req_id: Option<Extension<RequestId>>,
+    err: BoxError,
+) -> Response {
+    let (status, label, message) = if err.is::<Elapsed>() {
+        (
+            StatusCode::REQUEST_TIMEOUT,
+            "request.timeout",
+            format!("Request {method} {} timed out", uri.path()),
+        )
+    } else {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal.error",
+            format!("Unhandled error: {err}"),
+        )
+    };
+
+    event!(target: FRAMEWORK_TARGET, Level::WARN, "{message}");
+
+    (status, error_envelope(label, message, req_id.as_deref())).into_response()
+}
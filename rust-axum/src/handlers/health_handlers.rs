@@ -0,0 +1,21 @@
+/*!
+Handlers for health endpoints.
+*/
+use crate::tasks::Supervisor;
+use axum::{extract::State, http::StatusCode, Json};
+use serde_json::{json, Value};
+
+/// Readiness of the service. Unavailable while a background task is
+/// restarting or stopped.
+pub async fn readyz(State(tasks): State<Supervisor>) -> (StatusCode, Json<Value>) {
+    let ready = tasks.is_ready();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({ "ready": ready, "tasks": tasks.health() })),
+    )
+}
@@ -1,4 +1,11 @@
 /*!
 Handlers for api route endpoints.
 */
+pub mod admin_handlers;
+pub mod auth_handlers;
+pub mod dev_handlers;
+pub mod docs_handlers;
+pub mod fallback_handlers;
+pub mod health_handlers;
+pub mod signing_handlers;
 pub mod user_handlers;
@@ -0,0 +1,22 @@
+/*!
+Handlers for response signing endpoints.
+*/
+use crate::{security::signing::SIGNATURE_ALGORITHM, types::handler::HandlerError, AppConfig};
+use api_types::SigningKeyResponse;
+use axum::{extract::State, Json};
+use errors::ConfigError;
+use std::sync::Arc;
+
+/// Public key verifying the `X-Signature` header of responses. Not found
+/// when responses aren't signed.
+pub async fn signing_key(
+    State(app_config): State<Arc<AppConfig>>,
+) -> Result<Json<SigningKeyResponse>, HandlerError> {
+    let signer = app_config
+        .signer()
+        .ok_or(ConfigError::Disabled("Response signing"))?;
+    Ok(Json(SigningKeyResponse {
+        algorithm: SIGNATURE_ALGORITHM.to_owned(),
+        public_key: signer.public_key(),
+    }))
+}
@@ -1,37 +1,114 @@
 use crate::{
+    arguments::AppState,
+    audit,
+    downloads::DownloadTracker,
     extractors::{hashing::HashedValidatingJson, validator::ValidatingJson},
-    security::hashing::{HashableVector, HashingResponse},
+    jobs::{ImportJob, IMPORT_JOB},
+    security::{
+        cursor,
+        hashing::{Hashable, SearchBody},
+        resume::{self, RESUME_TOKEN_HEADER},
+    },
     types::{
         handler::{HandlerError, Persist},
-        jwt::{AdminAccess, UserAccess},
+        jwt::{AdminAccess, JWTClaims, Role, UserAccess},
     },
-    AppConfig, USER_MS_TARGET,
+    AppConfig, AUDIT_TARGET, USER_MS_TARGET,
 };
+use api_types::{CreateUserRequest, JobResponse, SearchRequest, UserResponse};
 use axum::{
-    extract::{Extension, Json, Path},
+    body::{boxed, BoxBody, Bytes},
+    extract::{Json, Path, Query, State},
     response::IntoResponse,
+    BoxError,
+};
+use bootstrap::conditional::{etag, PreconditionError};
+use chrono::DateTime;
+use errors::{ApiError, ConfigError};
+use futures::stream::StreamExt;
+use http::{
+    header::{ACCEPT, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_MATCH},
+    HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode,
 };
-use futures::stream::{self, StreamExt};
-use http::{Response, StatusCode};
 use hyper::Body;
+use mongodb::bson::Document;
+use serde::Deserialize;
 use serde_json::{to_string, Value};
-use std::sync::Arc;
-use tracing::debug;
+use std::{num::NonZeroU32, path::Path as StdPath, sync::Arc};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use tracing::{debug, event, Level};
 use user_persist::{
+    audit::{AuditLog, AuditOperation},
+    export::{read_manifest, FormatRegistry, Formatter, JsonArrayFormatter},
+    import::{csv_users, ColumnMapping, ImportReport, RowError},
+    jobs::JobQueue,
     mongo_persistence::MongoPersistence,
-    types::{UpdateUser, User, UserKey, UserSearch},
+    persistence::{query_capped, search_capped, CappedSearch, UserPersistence},
+    query::UserQuery,
+    references::References,
+    stats::{AgeBucket, StatsDate, StatsSnapshot},
+    types::{
+        BulkUpdate, BulkUpdateResult, Email, EmailValidation, SearchPage, UpdateUser, User,
+        UserKey, UserSearch,
+    },
 };
+use utoipa::IntoParams;
 
 type HandlerResult<T> = Result<T, HandlerError>;
-type AppCfg = Extension<Arc<AppConfig>>;
+type AppCfg = State<Arc<AppConfig>>;
+type Audit = State<Arc<dyn AuditLog>>;
+
+/// Content type of newline delimited JSON.
+pub(crate) const NDJSON: &str = "application/x-ndjson";
+
+/// Request header carrying client preferences.
+const PREFER: HeaderName = HeaderName::from_static("prefer");
+
+/// Response header listing the preferences that were honored.
+const PREFERENCE_APPLIED: HeaderName = HeaderName::from_static("preference-applied");
+
+/// Preference opting into the paged search envelope.
+const PAGE_ENVELOPE: &str = "page-envelope";
+
+/// The request asked for `preference` in one of its `Prefer` headers.
+fn prefers(headers: &HeaderMap, preference: &str) -> bool {
+    headers
+        .get_all(PREFER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|p| p.trim().eq_ignore_ascii_case(preference))
+}
 
-/// Get user handler.
+/// Response streaming newline delimited JSON. A failing stream aborts the
+/// response rather than truncating it silently.
+pub(crate) fn ndjson_response(body: Body) -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, NDJSON)
+        .body(boxed(body))
+        .unwrap()
+}
+
+/// Get user handler. The user is tagged with an `ETag` for conditional
+/// updates.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/{id}",
+    params(("id" = UserKey, Path, description = "Key of the user")),
+    responses(
+        (status = 200, description = "The user", body = HashedUser,
+            headers(("etag" = String, description = "Version of the user for conditional updates"))),
+        (status = 404, description = "User not found", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
 pub async fn get_user(
     db: Persist,
     Path(id): Path<UserKey>,
     claims: AdminAccess,
-    Extension(app_config): AppCfg,
-) -> impl IntoResponse {
+) -> HandlerResult<impl IntoResponse> {
     debug!(
       target: USER_MS_TARGET,
       "Received id: {id} with claims: {claims}"
@@ -48,69 +125,443 @@ pub async fn get_user(
       }
     );
 
-    user.map(|u| HashingResponse::new(app_config, u))
+    let user = user.ok_or(HandlerError::ResourceNotFound)?;
+    let etag = etag(&user)?;
+    Ok(([(ETAG, etag)], Json(user)))
+}
+
+/// Get user by email handler. Emails are compared once normalized so the
+/// lookup ignores case.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/by-email/{email}",
+    params(("email" = String, Path, description = "Email of the user, compared normalized")),
+    responses(
+        (status = 200, description = "The user", body = HashedUser),
+        (status = 404, description = "User not found", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
+pub async fn get_user_by_email(
+    db: Persist,
+    Path(email): Path<String>,
+    claims: AdminAccess,
+) -> impl IntoResponse {
+    let email = Email(email);
+    debug!(
+      target: USER_MS_TARGET,
+      "Received email: {email} with claims: {claims}"
+    );
+
+    db.get_user_by_email(&email)
+        .await?
+        .map(Json)
         .ok_or(HandlerError::ResourceNotFound)
 }
 
 /// Save user handler.
-#[axum_macros::debug_handler]
+#[utoipa::path(
+    post,
+    path = "/api/v1/user",
+    request_body = CreateUserRequest,
+    responses(
+        (status = 200, description = "The saved user", body = HashedUser),
+        (status = 400, description = "Invalid request", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
+#[axum_macros::debug_handler(state = AppState)]
 pub async fn save_user(
     db: Persist,
-    _claims: UserAccess,
-    Extension(app_config): AppCfg,
-    ValidatingJson(user): ValidatingJson<User>,
-) -> impl IntoResponse {
+    claims: UserAccess,
+    State(app_config): AppCfg,
+    State(audit_log): Audit,
+    ValidatingJson(request): ValidatingJson<CreateUserRequest>,
+) -> HandlerResult<impl IntoResponse> {
+    let user = User::from(request);
     debug!(target: USER_MS_TARGET, "saving user: {user}");
-    db.save_user(&user)
-        .await
-        .map_err(HandlerError::from)
-        .map(|u| HashingResponse::new(app_config, u))
+    let saved = db.save_user(&user).await?;
+    audit::record(
+        audit_log.as_ref(),
+        &app_config,
+        &claims.0,
+        AuditOperation::CreateUser,
+        saved.id.clone(),
+        "create user".to_owned(),
+    )
+    .await;
+    Ok(Json(saved))
 }
 
-/// Update user handler.
+/// Update user handler. An `If-Match` header must match the `ETag` of the
+/// stored user, and is required in strict mode.
+#[utoipa::path(
+    put,
+    path = "/api/v1/user",
+    request_body = UpdateUser,
+    params(("if-match" = Option<String>, Header, description = "ETag of the stored user")),
+    responses(
+        (status = 200, description = "The user was updated"),
+        (status = 400, description = "Invalid request", body = ErrorEnvelope),
+        (status = 404, description = "User not found", body = ErrorEnvelope),
+        (status = 412, description = "The user changed since it was read", body = ErrorEnvelope),
+        (status = 428, description = "Strict mode requires If-Match", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
 pub async fn update_user(
     db: Persist,
-    _claims: AdminAccess,
+    claims: AdminAccess,
+    State(app_config): AppCfg,
+    State(audit_log): Audit,
+    headers: HeaderMap,
     HashedValidatingJson(user): HashedValidatingJson<UpdateUser>,
 ) -> HandlerResult<StatusCode> {
     debug!(target: USER_MS_TARGET, "updating user with {user}");
-    db.update_user(&user)
-        .await
-        .map(|_| StatusCode::OK)
-        .map_err(HandlerError::from)
+    let preconditions = app_config.settings().preconditions;
+    // A header that isn't visible ASCII matches no entity tag.
+    let if_match = headers
+        .get(IF_MATCH)
+        .map(|value| value.to_str().unwrap_or_default());
+    let current = if preconditions.applies(if_match) {
+        let current = db.get_user(&user.id).await?;
+        let tag = current.as_ref().map(etag).transpose()?;
+        preconditions.check(if_match, tag.as_deref())?;
+        current
+    } else {
+        None
+    };
+    match current {
+        // A user changed since it was checked isn't overwritten.
+        Some(current) => {
+            if !db.update_user_if_unchanged(&user, &current).await? {
+                return Err(PreconditionError::Failed.into());
+            }
+        }
+        None => db.update_user(&user).await?,
+    }
+    audit::record(
+        audit_log.as_ref(),
+        &app_config,
+        &claims.0,
+        AuditOperation::UpdateUser,
+        Some(user.id.clone()),
+        "update user".to_owned(),
+    )
+    .await;
+    Ok(StatusCode::OK)
 }
 
-/// Search users handler.
-pub async fn search_users(
+/// Query parameters for a bulk update.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BulkUpdateParams {
+    /// Must be set to apply the update, guarding against accidental calls.
+    confirm: bool,
+    /// Only count the users the update would change.
+    dry_run: bool,
+}
+
+/// Bulk update handler. Updates every user matching a search, refusing
+/// when more users match than the configured limit.
+#[utoipa::path(
+    put,
+    path = "/api/v1/user/bulk",
+    params(BulkUpdateParams),
+    request_body = BulkUpdate,
+    responses(
+        (status = 200, description = "Users matched and changed", body = BulkUpdateResult),
+        (status = 400, description = "Invalid request", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
+pub async fn bulk_update_users(
     db: Persist,
     claims: AdminAccess,
-    Extension(app_config): AppCfg,
-    ValidatingJson(user_search): ValidatingJson<UserSearch>,
-) -> impl IntoResponse {
+    State(app_config): AppCfg,
+    State(audit_log): Audit,
+    Query(params): Query<BulkUpdateParams>,
+    ValidatingJson(update): ValidatingJson<BulkUpdate>,
+) -> HandlerResult<Json<BulkUpdateResult>> {
+    if !params.confirm {
+        return Err(HandlerError::UnconfirmedBulkUpdate);
+    }
+
+    let limit = app_config.settings().limits.bulk_update_limit;
+    let result = db.bulk_update(&update, limit, params.dry_run).await?;
+
+    event!(
+      target: AUDIT_TARGET,
+      Level::INFO,
+      "bulk update {update} by {claims}: matched {}, modified {}, dry run {}",
+      result.matched,
+      result.modified,
+      result.dry_run
+    );
+    if !result.dry_run {
+        audit::record(
+            audit_log.as_ref(),
+            &app_config,
+            &claims.0,
+            AuditOperation::BulkUpdate,
+            None,
+            format!(
+                "bulk update {update}: matched {}, modified {}",
+                result.matched, result.modified
+            ),
+        )
+        .await;
+    }
+
+    Ok(Json(result))
+}
+
+/// Query parameters for a search.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(default)]
+pub struct SearchParams {
+    /// Stream the results as newline delimited JSON.
+    stream: bool,
+    /// Number of matches skipped by a paged search.
+    offset: u64,
+    /// Page size of a paged search, bounded by the configured maximum.
+    limit: Option<u64>,
+    /// Cursor of the previous page continuing a paged search.
+    cursor: Option<String>,
+    /// Search every user. Only admins may search without criteria.
+    all: bool,
+}
+
+/// Search users handler. At most the configured maximum number of users are
+/// returned, with a `206 Partial Content` status when more matched. A search
+/// without criteria is rejected unless an admin passes `all=true`.
+///
+/// Clients sending `Prefer: page-envelope` instead receive a page of
+/// `limit` users from `offset` wrapped with the total number of matches.
+/// A page followed by more users carries a `nextCursor` which continues
+/// the search after its last user when passed as `cursor`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/search",
+    params(SearchParams),
+    request_body = SearchRequest,
+    responses(
+        (status = 200, description = "Matching users, as newline delimited JSON with stream=true", body = [HashedUser]),
+        (status = 206, description = "The first matching users, more matched", body = [HashedUser]),
+        (status = 400, description = "Invalid request", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
+pub async fn search_users(
+    db: Persist,
+    claims: JWTClaims,
+    State(app_config): AppCfg,
+    headers: HeaderMap,
+    Query(params): Query<SearchParams>,
+    ValidatingJson(request): ValidatingJson<SearchRequest>,
+) -> HandlerResult<Response<BoxBody>> {
+    let user_search = UserSearch::from(request);
     debug!(
       target: USER_MS_TARGET,
       "Searching for users with {user_search} and claims {claims}"
     );
-    db.search_users(&user_search)
-        .await
-        .map(|v| HashableVector::new(app_config, v))
-        .map_err(HandlerError::from)
-        .into_response()
+
+    if user_search.is_empty() && !(params.all && claims.role == Role::Admin) {
+        return Err(HandlerError::UnfilteredSearch);
+    }
+
+    if params.stream {
+        let stream = db.search_users_stream(&user_search).await?.map(
+            move |user| -> Result<String, BoxError> {
+                let hashed = user?.hash(app_config.hash_prefix());
+                Ok(format!("{}\n", to_string(&hashed)?))
+            },
+        );
+        return Ok(ndjson_response(Body::wrap_stream(stream)));
+    }
+
+    if prefers(&headers, PAGE_ENVELOPE) {
+        let pagination = &app_config.settings().pagination;
+        let limit = params
+            .limit
+            .unwrap_or(pagination.default_page_size.into())
+            .min(pagination.max_page_size.into());
+        let page = cursor_page(
+            db.as_ref(),
+            &app_config,
+            &user_search,
+            params.offset,
+            limit,
+            params.cursor.as_deref(),
+        )
+        .await?;
+        let mut response = Json(SearchBody::Page(page)).into_response();
+        response
+            .headers_mut()
+            .insert(PREFERENCE_APPLIED, HeaderValue::from_static(PAGE_ENVELOPE));
+        return Ok(response);
+    }
+
+    let max = app_config.settings().limits.max_search_results;
+    let CappedSearch { users, truncated } = search_capped(db.as_ref(), &user_search, max).await?;
+    let status = if truncated {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(SearchBody::List(users))).into_response())
+}
+
+/// Page of `limit` users of `search` continuing after `cursor`, or from
+/// `offset` without one, with a cursor for the next page when more users
+/// follow. A cursor whose last user no longer matches continues from the
+/// offset it recorded instead.
+async fn cursor_page(
+    db: &dyn UserPersistence,
+    app_config: &AppConfig,
+    search: &UserSearch,
+    offset: u64,
+    limit: u64,
+    cursor: Option<&str>,
+) -> HandlerResult<SearchPage<User>> {
+    // One more user than the page holds tells whether another page follows.
+    let cursor = cursor
+        .map(|cursor| cursor::verify(app_config, cursor, search))
+        .transpose()?;
+    let after = match &cursor {
+        Some(cursor) => db.search_after(search, &cursor.last_id, limit + 1).await?,
+        None => None,
+    };
+    let mut page = match (cursor, after) {
+        (Some(cursor), Some(items)) => {
+            // An empty page only counts the matches.
+            let counted = db.search_page(search, 0, 0).await?;
+            SearchPage {
+                items,
+                offset: cursor.offset,
+                ..counted
+            }
+        }
+        (cursor, _) => {
+            let offset = cursor.map_or(offset, |cursor| cursor.offset);
+            db.search_page(search, offset, limit + 1).await?
+        }
+    };
+
+    page.limit = limit;
+    if page.items.len() as u64 > limit {
+        page.items.truncate(limit as usize);
+        if let Some(last_id) = page.items.last().and_then(|user| user.id.clone()) {
+            let ttl = chrono::Duration::from_std(app_config.settings().pagination.cursor_ttl)
+                .unwrap_or_default();
+            page.next_cursor = Some(cursor::issue(
+                app_config,
+                search,
+                last_id,
+                page.offset + limit,
+                ttl,
+            )?);
+        }
+    }
+    Ok(page)
+}
+
+/// Query parameters of a user query.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct QueryParams {
+    /// Query such as `gender:Male age>120 name~smith`.
+    q: String,
+}
+
+/// Query users handler. Users are matched by the terms of the `q` query
+/// string, which is rejected with a `400 Bad Request` when it doesn't
+/// parse. Results are capped like a search.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user",
+    params(QueryParams),
+    responses(
+        (status = 200, description = "Matching users", body = [HashedUser]),
+        (status = 206, description = "The first matching users, more matched", body = [HashedUser]),
+        (status = 400, description = "Invalid request", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
+pub async fn query_users(
+    db: Persist,
+    claims: JWTClaims,
+    State(app_config): AppCfg,
+    Query(params): Query<QueryParams>,
+) -> HandlerResult<Response<BoxBody>> {
+    let query = UserQuery::parse(&params.q, app_config.settings().email_validation)?;
+    debug!(
+      target: USER_MS_TARGET,
+      "Querying users with {query} and claims {claims}"
+    );
+
+    let max = app_config.settings().limits.max_search_results;
+    let CappedSearch { users, truncated } = query_capped(db.as_ref(), &query, max).await?;
+    let status = if truncated {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(users)).into_response())
 }
 
 /// Delete user handler.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/user/{id}",
+    params(("id" = UserKey, Path, description = "Key of the user")),
+    responses(
+        (status = 200, description = "The user was deleted"),
+        (status = 404, description = "User not found", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
 pub async fn delete_user(
     db: Persist,
+    State(app_config): AppCfg,
+    State(audit_log): Audit,
+    State(references): State<References>,
     Path(id): Path<UserKey>,
-    _claims: AdminAccess,
+    claims: AdminAccess,
 ) -> impl IntoResponse {
-    match db.remove_user(&id).await {
-        Ok(_) => (StatusCode::OK).into_response(),
+    // Owned records go with the user. A failed cascade leaves dangling
+    // records the integrity check reports.
+    let removed = match db.remove_user(&id).await {
+        Ok(_) => references.user_removed(&id).await,
+        Err(e) => Err(e),
+    };
+    match removed {
+        Ok(cascaded) => {
+            debug!(target: USER_MS_TARGET, "Removed records of user {id}: {cascaded:?}");
+            audit::record(
+                audit_log.as_ref(),
+                &app_config,
+                &claims.0,
+                AuditOperation::DeleteUser,
+                Some(id),
+                "delete user".to_owned(),
+            )
+            .await;
+            (StatusCode::OK).into_response()
+        }
         Err(e) => HandlerError::from(e).into_response(),
     }
 }
 
 /// Count users handler.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/counts",
+    responses((status = 200, description = "Counts of users by gender", body = [Object])),
+    tag = "users"
+)]
 pub async fn count_users(db: Persist, claims: AdminAccess) -> HandlerResult<Json<Vec<Value>>> {
     debug!(target: USER_MS_TARGET, "Claims: {claims}");
     let counts = db.count_genders().await?;
@@ -118,36 +569,516 @@ pub async fn count_users(db: Persist, claims: AdminAccess) -> HandlerResult<Json
     Ok(Json(counts))
 }
 
+/// Query parameters bounding the statistics history. Both dates are
+/// inclusive and either may be left out.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(default)]
+pub struct HistoryParams {
+    #[param(value_type = Option<String>, format = Date)]
+    from: Option<StatsDate>,
+    #[param(value_type = Option<String>, format = Date)]
+    to: Option<StatsDate>,
+}
+
+/// User counts history handler.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/counts/history",
+    params(HistoryParams),
+    responses(
+        (status = 200, description = "Daily snapshots of the user counts", body = [Object]),
+        (status = 400, description = "Invalid request", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
+pub async fn count_users_history(
+    db: Persist,
+    claims: AdminAccess,
+    Query(params): Query<HistoryParams>,
+) -> HandlerResult<Json<Vec<StatsSnapshot>>> {
+    debug!(target: USER_MS_TARGET, "Claims: {claims}, history: {params:?}");
+    let history = db.stats_history(params.from, params.to).await?;
+    Ok(Json(history))
+}
+
+/// Query parameters of an import.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(default)]
+pub struct ImportParams {
+    /// Column mapping as JSON, replacing the configured mapping.
+    mapping: Option<String>,
+    /// Import in a background job rather than while the request waits.
+    background: bool,
+}
+
+/// Users of the rows of an imported file of `content_type`, with emails
+/// validated in `mode`.
+pub(crate) fn import_rows(
+    content_type: &str,
+    body: Bytes,
+    mapping: &ColumnMapping,
+    mode: EmailValidation,
+) -> HandlerResult<Vec<Result<User, RowError>>> {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    Ok(match essence.to_ascii_lowercase().as_str() {
+        "text/csv" => csv_users(&body, mapping, mode)?,
+        #[cfg(feature = "parquet")]
+        "application/vnd.apache.parquet" => {
+            user_persist::import::parquet_users(body, mapping, mode)?
+        }
+        _ => return Err(HandlerError::UnsupportedImport(content_type.to_owned())),
+    })
+}
+
+/// Save the imported `rows`, reporting those that can't be saved.
+pub(crate) async fn save_imported(
+    db: &dyn UserPersistence,
+    rows: Vec<Result<User, RowError>>,
+) -> ImportReport {
+    let mut report = ImportReport::default();
+    for (row, user) in (1..).zip(rows) {
+        let saved = match user {
+            Ok(user) => db.save_user(&user).await.map_err(|e| RowError {
+                row,
+                message: ApiError::from(&e).message,
+            }),
+            Err(error) => Err(error),
+        };
+        match saved {
+            Ok(_) => report.imported += 1,
+            Err(error) => report.fail(error),
+        }
+    }
+    report
+}
+
+/// Import users handler. Users are read from the CSV or, with the
+/// `parquet` feature, Parquet file of the body with the columns mapped to
+/// fields by the request or the configuration. Rows that can't be imported
+/// are reported rather than failing the import. With `background=true`
+/// the file is checked and queued as a job, and the job is answered with
+/// `202 Accepted`.
+#[allow(clippy::too_many_arguments)]
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/import",
+    params(ImportParams),
+    request_body(content = String, description = "CSV or Parquet file of users", content_type = "text/csv"),
+    responses(
+        (status = 200, description = "Report of the rows imported and rejected", body = Object),
+        (status = 202, description = "The import was queued as a job", body = Object),
+        (status = 400, description = "Invalid request", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
+pub async fn import_users(
+    db: Persist,
+    claims: AdminAccess,
+    State(app_config): AppCfg,
+    State(jobs): State<Arc<dyn JobQueue>>,
+    State(audit_log): Audit,
+    Query(params): Query<ImportParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> HandlerResult<Response<BoxBody>> {
+    let mapping = match params.mapping {
+        Some(json) => serde_json::from_str(&json).map_err(HandlerError::ImportMapping)?,
+        None => app_config.settings().import_mapping.clone(),
+    };
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let rows = import_rows(
+        content_type,
+        body.clone(),
+        &mapping,
+        app_config.settings().email_validation,
+    )?;
+
+    if params.background {
+        let payload = ImportJob {
+            content_type: content_type.to_owned(),
+            mapping,
+            data: base64::encode(&body),
+        };
+        let job = jobs
+            .enqueue(
+                IMPORT_JOB,
+                serde_json::to_value(payload)?,
+                app_config.settings().jobs.max_attempts,
+            )
+            .await?;
+        event!(
+          target: AUDIT_TARGET,
+          Level::INFO,
+          "import job {} queued by {claims}",
+          job.id
+        );
+        audit::record(
+            audit_log.as_ref(),
+            &app_config,
+            &claims.0,
+            AuditOperation::Import,
+            None,
+            format!("import job {} queued", job.id),
+        )
+        .await;
+        return Ok((StatusCode::ACCEPTED, Json(JobResponse::from(job))).into_response());
+    }
+
+    let report = save_imported(db.0.as_ref(), rows).await;
+    event!(
+      target: AUDIT_TARGET,
+      Level::INFO,
+      "import by {claims}: imported {}, failed {}",
+      report.imported,
+      report.failed
+    );
+    audit::record(
+        audit_log.as_ref(),
+        &app_config,
+        &claims.0,
+        AuditOperation::Import,
+        None,
+        format!(
+            "import: imported {}, failed {}",
+            report.imported, report.failed
+        ),
+    )
+    .await;
+
+    Ok(Json(report).into_response())
+}
+
+/// Query parameters of a user sample.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SampleParams {
+    /// Number of users, bounded by the maximum number of search results.
+    #[serde(default = "default_sample_size")]
+    #[param(value_type = Option<u64>)]
+    n: u64,
+}
+
+fn default_sample_size() -> u64 {
+    10
+}
+
+/// Sample users handler. Returns users picked at random so QA can look at
+/// representative records without exporting every user.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/sample",
+    params(SampleParams),
+    responses((status = 200, description = "Users picked at random", body = [HashedUser])),
+    tag = "users"
+)]
+pub async fn sample_users(
+    db: Persist,
+    claims: AdminAccess,
+    State(app_config): AppCfg,
+    Query(params): Query<SampleParams>,
+) -> HandlerResult<Response<BoxBody>> {
+    debug!(target: USER_MS_TARGET, "Claims: {claims}, sample: {params:?}");
+    let size = params
+        .n
+        .min(app_config.settings().limits.max_search_results);
+    let users = db.sample_users(size).await?;
+    Ok(Json(users).into_response())
+}
+
+/// Query parameters of the age histogram.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistogramParams {
+    /// Years of age covered by each bucket.
+    #[serde(default = "default_bucket_size")]
+    #[param(value_type = Option<u32>, minimum = 1)]
+    bucket: NonZeroU32,
+}
+
+fn default_bucket_size() -> NonZeroU32 {
+    NonZeroU32::new(10).unwrap()
+}
+
+/// Age histogram handler.
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/age-histogram",
+    params(HistogramParams),
+    responses((status = 200, description = "Number of users in each age bucket", body = [Object])),
+    tag = "users"
+)]
+pub async fn age_histogram(
+    db: Persist,
+    claims: AdminAccess,
+    Query(params): Query<HistogramParams>,
+) -> HandlerResult<Json<Vec<AgeBucket>>> {
+    debug!(target: USER_MS_TARGET, "Claims: {claims}, histogram: {params:?}");
+    let histogram = db.age_histogram(params.bucket).await?;
+    Ok(Json(histogram))
+}
+
+/// Serve a materialized export. Range requests are answered with
+/// `206 Partial Content` so interrupted downloads can resume.
+async fn serve_export(path: &StdPath, req: Request<Body>) -> HandlerResult<Response<BoxBody>> {
+    let mut response = ServeFile::new(path).oneshot(req).await?;
+
+    // Byte ranges refer to the file as stored so the compression layer
+    // must leave it alone.
+    response
+        .headers_mut()
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("identity"));
+
+    Ok(response.map(boxed))
+}
+
+/// Query parameters resuming a streamed download after the last user
+/// received. Both must be given together.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(default)]
+pub struct DownloadParams {
+    after: Option<UserKey>,
+    resume: Option<String>,
+    /// Name of a registered export format.
+    format: Option<String>,
+}
+
+/// Formatter of a download chosen by the `format` query parameter, then by
+/// the accepted media type of the highest quality with a registered format,
+/// the first of equal qualities, and otherwise a JSON array. Media types of
+/// quality zero are not acceptable.
+fn download_formatter(
+    formats: &FormatRegistry,
+    format: Option<&str>,
+    headers: &HeaderMap,
+) -> HandlerResult<Arc<dyn Formatter>> {
+    if let Some(name) = format {
+        return formats
+            .by_name(name)
+            .ok_or_else(|| HandlerError::UnknownFormat(name.to_owned()));
+    }
+    let mut best: Option<(Arc<dyn Formatter>, f32)> = None;
+    let media_types = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for media_type in media_types {
+        let quality = media_type
+            .split(';')
+            .skip(1)
+            .map(str::trim)
+            .find_map(|param| param.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+            .unwrap_or(0.0);
+        if let Some(format) = formats
+            .by_content_type(media_type)
+            .filter(|_| quality > 0.0)
+        {
+            if best.as_ref().is_none_or(|(_, q)| quality > *q) {
+                best = Some((format, quality));
+            }
+        }
+    }
+    Ok(best
+        .map(|(format, _)| format)
+        .unwrap_or_else(|| Arc::new(JsonArrayFormatter)))
+}
+
 // This gets a stream of MongoUser types that are
 // streamed from the mongodb cursor. The stream is
-// transformed to it's JSON form and wrapped in a
-// StreamBody resulting in a Stream from mongodb back
-// to http client.
+// transformed by the requested formatter and wrapped
+// in a StreamBody resulting in a Stream from mongodb
+// back to http client.
 
 /// Download users handler
+#[utoipa::path(
+    get,
+    path = "/api/v1/user/download",
+    params(DownloadParams),
+    responses(
+        (status = 200, description = "Every user in the requested format", body = [UserResponse]),
+        (status = 206, description = "Range of a materialized export"),
+        (status = 400, description = "Invalid request", body = ErrorEnvelope),
+        (status = 404, description = "The mongodb backend isn't configured", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
 pub async fn download_users(
-    db: Extension<Arc<MongoPersistence>>,
-    claims: AdminAccess,
-) -> HandlerResult<impl IntoResponse> {
-    debug!(target: USER_MS_TARGET, "Streaming users for {claims}");
+    State(downloader): State<Option<Arc<MongoPersistence>>>,
+    State(downloads): State<DownloadTracker>,
+    State(app_config): AppCfg,
+    AdminAccess(claims): AdminAccess,
+    Query(params): Query<DownloadParams>,
+    req: Request<Body>,
+) -> HandlerResult<Response<BoxBody>> {
+    debug!(target: USER_MS_TARGET, "Downloading users for {claims}");
+    let export = &app_config.settings().export;
+    let formatter = download_formatter(&export.formats, params.format.as_deref(), req.headers())?;
 
-    // Chain my stream with a header and footer
-    // in order to reconstitute a json array for
-    // the mongodb stream of documents returned.
-    let header = stream::iter(vec![Ok("[".to_string())]);
-    let footer = stream::iter(vec![Ok("]".to_string())]);
+    let after = match (params.after, params.resume) {
+        (None, None) => None,
+        (Some(after), Some(token)) => {
+            resume::verify(&app_config, &token, &claims.sub)?;
+            Some(after)
+        }
+        _ => return Err(resume::ResumeError::Invalid.into()),
+    };
 
-    let stream = db
-        .download()
+    // A materialized export in the requested format resumes with range
+    // requests instead. Exports without a manifest are JSON arrays.
+    if let (Some(path), None) = (&export.file, &after) {
+        if tokio::fs::metadata(path).await.is_ok() {
+            let format = match read_manifest(path).await {
+                Ok(manifest) => Some(manifest.map_or_else(|| "json".to_owned(), |m| m.format)),
+                Err(e) => {
+                    debug!(target: USER_MS_TARGET, "Manifest of {path:?} unreadable: {e}");
+                    None
+                }
+            };
+            if format.as_deref() == Some(formatter.name()) {
+                return serve_export(path, req).await;
+            }
+        }
+        debug!(target: USER_MS_TARGET, "Export {path:?} not materialized as {}, streaming", formatter.name());
+    }
+
+    let db = downloader.ok_or(ConfigError::Disabled("The mongodb backend"))?;
+
+    let records = db
+        .download(after.as_ref())
         .await?
         .filter_map(|r| async { r.ok() })
-        .map(|u| to_string(&u).map(|s| format!("{s},")));
+        .map(|u| Ok(serde_json::to_value(UserResponse::from(u))?))
+        .boxed();
 
-    let response_stream = header.chain(stream).chain(footer);
+    let response_stream = downloads.track(
+        claims.sub.clone(),
+        formatter.name().to_owned(),
+        app_config.clock().now().timestamp_millis(),
+        formatter.format(records),
+    );
+
+    // The token is only checked when the stream starts so a download
+    // outliving it is cut short when required. The truncated array tells
+    // the client to resume.
+    let body = if export.stop_at_token_expiry {
+        let remaining = (DateTime::from_timestamp(claims.exp, 0).unwrap_or_default()
+            - app_config.clock().now())
+        .to_std()
+        .unwrap_or_default();
+        Body::wrap_stream(response_stream.take_until(tokio::time::sleep(remaining)))
+    } else {
+        Body::wrap_stream(response_stream)
+    };
 
-    Ok(Response::builder()
+    // A stream from the database can't be resumed with byte ranges, only
+    // after the last user received with the resume token.
+    let mut response = Response::builder()
         .status(StatusCode::OK)
-        .header("Content-Type", "application/json")
-        .body(Body::wrap_stream(response_stream))
-        .unwrap())
+        .header(CONTENT_TYPE, formatter.content_type())
+        .header(ACCEPT_RANGES, "none");
+    if !export.resume_ttl.is_zero() {
+        let ttl = chrono::Duration::seconds(export.resume_ttl.as_secs() as i64);
+        response = response.header(
+            RESUME_TOKEN_HEADER,
+            resume::issue(&app_config, &claims.sub, ttl)?,
+        );
+    }
+    Ok(response.body(boxed(body)).unwrap())
+}
+
+/// Aggregate users handler. Runs a restricted pipeline on the user
+/// collection and streams the results as newline delimited JSON.
+#[utoipa::path(
+    post,
+    path = "/api/v1/user/aggregate",
+    request_body(content = [Object], description = "Pipeline stages"),
+    responses(
+        (status = 200, description = "Results as newline delimited JSON", body = Object, content_type = "application/x-ndjson"),
+        (status = 404, description = "The mongodb backend isn't configured", body = ErrorEnvelope),
+    ),
+    tag = "users"
+)]
+pub async fn aggregate_users(
+    State(downloader): State<Option<Arc<MongoPersistence>>>,
+    State(app_config): AppCfg,
+    State(audit_log): Audit,
+    claims: AdminAccess,
+    Json(pipeline): Json<Vec<Document>>,
+) -> HandlerResult<Response<BoxBody>> {
+    let db = downloader.ok_or(ConfigError::Disabled("The mongodb backend"))?;
+
+    // Stage specs may hold personal data so only the stage names are kept.
+    let stages = pipeline
+        .iter()
+        .flat_map(|stage| stage.keys())
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    event!(
+      target: AUDIT_TARGET,
+      Level::INFO,
+      "aggregate pipeline {stages:?} by {claims}"
+    );
+    audit::record(
+        audit_log.as_ref(),
+        &app_config,
+        &claims.0,
+        AuditOperation::Aggregate,
+        None,
+        format!("aggregate pipeline {stages:?}"),
+    )
+    .await;
+
+    let stream = db
+        .aggregate_users(pipeline)
+        .await?
+        .map(|r| r.map(|document| format!("{document}\n")));
+
+    Ok(ndjson_response(Body::wrap_stream(stream)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::download_formatter;
+    use http::{header::ACCEPT, HeaderMap, HeaderValue};
+    use user_persist::export::FormatRegistry;
+
+    #[test]
+    fn test_download_formatter() {
+        let formats = FormatRegistry::default();
+        let name = |format, accept: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(ACCEPT, HeaderValue::from_static(accept));
+            }
+            download_formatter(&formats, format, &headers).map(|f| f.name())
+        };
+
+        assert_eq!(name(None, None).ok(), Some("json"));
+        assert_eq!(
+            name(Some("CSV"), Some("application/json")).ok(),
+            Some("csv")
+        );
+        assert_eq!(
+            name(None, Some("application/xml, text/csv;q=0.9")).ok(),
+            Some("csv")
+        );
+        assert_eq!(
+            name(None, Some("text/csv;q=0.5, application/x-ndjson")).ok(),
+            Some("ndjson")
+        );
+        assert_eq!(
+            name(None, Some("text/csv;q=0, application/xml")).ok(),
+            Some("json")
+        );
+        assert_eq!(name(None, Some("text/html")).ok(), Some("json"));
+        assert!(name(Some("xml"), None).is_err());
+    }
 }
@@ -0,0 +1,238 @@
+/*!
+Background jobs run from the durable job queue.
+
+Handlers enqueue work in a [`JobQueue`] and a [`JobWorker`] spawned by the
+task [`Supervisor`](crate::tasks::Supervisor) runs it with the
+[`JobHandler`] registered for its kind. A failed job is retried after the
+backoff of the worker's [`RestartPolicy`]. With the mongodb queue, jobs
+queued or running when the process stops are run once it restarts.
+*/
+use crate::{
+    handlers::user_handlers::{import_rows, save_imported},
+    tasks::RestartPolicy,
+    AUDIT_TARGET, USER_MS_TARGET,
+};
+use api_types::UserResponse;
+use axum::body::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+use tracing::{event, Level};
+use user_persist::{
+    clock::Clock,
+    export::{materialize, FormatRegistry},
+    import::ColumnMapping,
+    jobs::JobQueue,
+    persistence::{PersistenceResult, UserPersistence},
+    streaming::StreamError,
+    types::{EmailValidation, UserSearch},
+};
+
+/// Kind of the jobs importing users.
+pub const IMPORT_JOB: &str = "import";
+/// Kind of the jobs materializing the user export.
+pub const EXPORT_JOB: &str = "export";
+
+/// Background job queue settings.
+#[derive(Clone, Copy, Debug)]
+pub struct JobSettings {
+    /// Time a worker has to finish a job before another worker may claim
+    /// it.
+    pub visibility_timeout: Duration,
+    /// Attempts of a job before it is marked dead.
+    pub max_attempts: u32,
+    /// Delay before looking for work again when the queue is empty.
+    pub poll_interval: Duration,
+    /// Delays before retrying a failed job.
+    pub retry: RestartPolicy,
+}
+
+impl Default for JobSettings {
+    fn default() -> Self {
+        Self {
+            visibility_timeout: Duration::from_secs(5 * 60),
+            max_attempts: 5,
+            poll_interval: Duration::from_secs(1),
+            retry: RestartPolicy::default(),
+        }
+    }
+}
+
+/// Runs the jobs of one kind.
+#[async_trait::async_trait]
+pub trait JobHandler: Send + Sync {
+    /// Run the job with `payload`, returning its result or why it failed.
+    async fn run(&self, payload: Value) -> Result<Value, String>;
+}
+
+/// Claims jobs from a queue and runs them one at a time.
+#[derive(Clone)]
+pub struct JobWorker {
+    queue: Arc<dyn JobQueue>,
+    handlers: BTreeMap<String, Arc<dyn JobHandler>>,
+    settings: JobSettings,
+}
+
+impl JobWorker {
+    /// Create a worker running the jobs of `queue`.
+    pub fn new(queue: Arc<dyn JobQueue>, settings: JobSettings) -> Self {
+        Self {
+            queue,
+            handlers: BTreeMap::new(),
+            settings,
+        }
+    }
+
+    /// Run jobs of `kind` with `handler`.
+    pub fn with_handler(mut self, kind: &str, handler: impl JobHandler + 'static) -> Self {
+        self.handlers.insert(kind.to_owned(), Arc::new(handler));
+        self
+    }
+
+    /// Claim and run the next job. Returns whether there was one.
+    pub async fn run_next(&self) -> PersistenceResult<bool> {
+        let visibility =
+            chrono::Duration::from_std(self.settings.visibility_timeout).unwrap_or_default();
+        let Some(job) = self.queue.claim(visibility).await? else {
+            return Ok(false);
+        };
+
+        let outcome = match self.handlers.get(&job.kind) {
+            Some(handler) => handler.run(job.payload.clone()).await,
+            None => Err(format!("no handler for {} jobs", job.kind)),
+        };
+        let held = match outcome {
+            Ok(result) => self.queue.complete(&job, result).await?,
+            Err(error) => {
+                event!(
+                  target: USER_MS_TARGET,
+                  Level::WARN,
+                  "Job {} ({}) failed on attempt {} of {}: {error}",
+                  job.id,
+                  job.kind,
+                  job.attempts,
+                  job.max_attempts
+                );
+                let retry_after = self.settings.retry.backoff(job.attempts);
+                let retry_after = chrono::Duration::from_std(retry_after).unwrap_or_default();
+                self.queue.fail(&job, &error, retry_after).await?
+            }
+        };
+        if !held {
+            event!(
+              target: USER_MS_TARGET,
+              Level::WARN,
+              "Job {} outlived its visibility timeout and was claimed again",
+              job.id
+            );
+        }
+        Ok(true)
+    }
+
+    /// Run jobs until the queue can't be read, waiting for work while it is
+    /// empty.
+    pub async fn run(self) {
+        loop {
+            match self.run_next().await {
+                Ok(true) => (),
+                Ok(false) => tokio::time::sleep(self.settings.poll_interval).await,
+                Err(e) => {
+                    event!(target: USER_MS_TARGET, Level::ERROR, "Job queue failed: {e}");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Payload of an [`IMPORT_JOB`].
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportJob {
+    /// Content type of the imported file.
+    pub content_type: String,
+    pub mapping: ColumnMapping,
+    /// The imported file encoded as base64.
+    pub data: String,
+}
+
+/// Runs [`IMPORT_JOB`]s, saving the users to `persist`. The result of a job
+/// is its import report.
+pub struct ImportJobHandler {
+    pub persist: Arc<dyn UserPersistence>,
+    /// Mode the emails of imported users are validated in.
+    pub email_validation: EmailValidation,
+}
+
+#[async_trait::async_trait]
+impl JobHandler for ImportJobHandler {
+    async fn run(&self, payload: Value) -> Result<Value, String> {
+        let job = serde_json::from_value::<ImportJob>(payload).map_err(|e| e.to_string())?;
+        let data = base64::decode(&job.data).map_err(|e| e.to_string())?;
+        let rows = import_rows(
+            &job.content_type,
+            Bytes::from(data),
+            &job.mapping,
+            self.email_validation,
+        )
+        .map_err(|e| e.to_string())?;
+        let report = save_imported(self.persist.as_ref(), rows).await;
+        event!(
+          target: AUDIT_TARGET,
+          Level::INFO,
+          "background import: imported {}, failed {}",
+          report.imported,
+          report.failed
+        );
+        serde_json::to_value(report).map_err(|e| e.to_string())
+    }
+}
+
+/// Payload of an [`EXPORT_JOB`].
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportJob {
+    /// Name of the export format.
+    pub format: String,
+}
+
+/// Runs [`EXPORT_JOB`]s, writing every user of the wrapped persistence to
+/// the export file. The result of a job is the manifest of the export.
+pub struct ExportJobHandler {
+    pub persist: Arc<dyn UserPersistence>,
+    pub formats: FormatRegistry,
+    pub path: PathBuf,
+    pub clock: Arc<dyn Clock>,
+}
+
+#[async_trait::async_trait]
+impl JobHandler for ExportJobHandler {
+    async fn run(&self, payload: Value) -> Result<Value, String> {
+        let job = serde_json::from_value::<ExportJob>(payload).map_err(|e| e.to_string())?;
+        let formatter = self
+            .formats
+            .by_name(&job.format)
+            .ok_or_else(|| format!("unknown export format {}", job.format))?;
+        let records = self
+            .persist
+            .search_users_stream(&UserSearch::default())
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(StreamError::from)
+            .and_then(|user| async move { Ok(serde_json::to_value(UserResponse::from(user))?) })
+            .boxed();
+        let manifest = materialize(&*formatter, records, &self.path, self.clock.as_ref())
+            .await
+            .map_err(|e| e.to_string())?;
+        event!(
+          target: AUDIT_TARGET,
+          Level::INFO,
+          "background export: {} users as {}, sha256 {}",
+          manifest.record_count,
+          manifest.format,
+          manifest.sha256
+        );
+        serde_json::to_value(manifest).map_err(|e| e.to_string())
+    }
+}
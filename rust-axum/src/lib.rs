@@ -1,66 +1,178 @@
 use crate::{
-    arguments::AppConfig,
-    handlers::user_handlers,
-    // middleware::hashing::HashingMiddleware,
+    arguments::{AppConfig, AppState},
+    handlers::{
+        admin_handlers, auth_handlers, dev_handlers, docs_handlers, fallback_handlers,
+        health_handlers, signing_handlers, user_handlers,
+    },
     types::jwt::{JWTClaims, Role},
 };
 use axum::{
-    extract::Extension,
-    http::header::HeaderName,
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
+    http::{
+        header::{HeaderName, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE},
+        Method,
+    },
     routing::{delete, get, post, put},
     Router,
 };
-use middleware::request_trace::RequestLogger;
-use std::sync::Arc;
+use middleware::{hashing::hashed, request_trace::RequestLogger};
+use security::hashing::SearchBody;
 use tower::ServiceBuilder;
 use tower_http::{
-    classify::StatusInRangeAsFailures, compression::CompressionLayer,
-    propagate_header::PropagateHeaderLayer, request_id::SetRequestIdLayer, trace::TraceLayer,
+    classify::StatusInRangeAsFailures,
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+    propagate_header::PropagateHeaderLayer,
+    request_id::SetRequestIdLayer,
+    set_header::SetResponseHeaderLayer,
+    trace::TraceLayer,
 };
-use user_persist::persistence::UserPersistence;
+use user_persist::types::User;
 
+pub mod allocator;
 pub mod arguments;
+pub mod audit;
+pub mod downloads;
 mod extractors;
 mod handlers;
-mod middleware;
+pub mod jobs;
+pub mod middleware;
+pub mod openapi;
 pub mod security;
+pub mod seed;
+pub mod stats;
+pub mod tasks;
 pub mod types;
 
 /// Tracing target for user-ms.
 pub const USER_MS_TARGET: &str = "user-ms";
 /// Tracing target for framework-ms.
 pub const FRAMEWORK_TARGET: &str = "framework-ms";
+/// Tracing target for audited admin actions.
+pub const AUDIT_TARGET: &str = "audit";
 /// Header name for correlation request identifier.
 pub const REQ_ID_HEADER: &str = "x-request-id";
 
 /// User endpoint routes with handler mappings.
-fn user_routes() -> Router {
-    Router::new()
+fn user_routes(state: &AppState) -> Router<AppState> {
+    let routes = Router::new()
         .route(
             "/user/:id",
-            get(user_handlers::get_user), //.layer(HashingMiddleware::hash_user_layer()),
+            hashed::<User>(state, get(user_handlers::get_user)),
+        )
+        .route(
+            "/user/by-email/:email",
+            hashed::<User>(state, get(user_handlers::get_user_by_email)),
+        )
+        .route(
+            "/user",
+            hashed::<Vec<User>>(state, get(user_handlers::query_users)),
         )
         .route(
             "/user",
-            post(user_handlers::save_user), // .layer(HashingMiddleware::hash_user_layer()),
+            hashed::<User>(state, post(user_handlers::save_user)),
         )
         // TODO: hashing middleware to validate hash on update.
         .route("/user", put(user_handlers::update_user))
+        .route("/user/bulk", put(user_handlers::bulk_update_users))
         .route(
             "/user/search",
-            post(user_handlers::search_users), // .layer(HashingMiddleware::hash_users_layer()),
+            hashed::<SearchBody<User>>(state, post(user_handlers::search_users)),
         )
+        .route("/user/aggregate", post(user_handlers::aggregate_users))
         .route("/user/counts", get(user_handlers::count_users))
+        .route(
+            "/user/counts/history",
+            get(user_handlers::count_users_history),
+        )
+        .route("/user/age-histogram", get(user_handlers::age_histogram))
+        .route(
+            "/user/sample",
+            hashed::<Vec<User>>(state, get(user_handlers::sample_users)),
+        )
+        .route("/user/import", post(user_handlers::import_users))
         .route("/user/download", get(user_handlers::download_users))
-        .route("/user/:id", delete(user_handlers::delete_user))
+        .route("/user/:id", delete(user_handlers::delete_user));
+    // Contracts are checked against the bodies of the handlers.
+    middleware::contract::with_contracts(routes, state)
+        // Fields are pruned before the JSON:API wrapping.
+        .route_layer(axum::middleware::from_fn(middleware::fields::sparse_fields))
+        .route_layer(axum::middleware::from_fn(middleware::json_api::json_api))
+}
+
+/// Admin endpoint routes with handler mappings.
+fn admin_routes(state: &AppState) -> Router<AppState> {
+    let routes = Router::new()
+        .route("/admin/anomalies", get(admin_handlers::list_anomalies))
+        .route(
+            "/admin/validation-failures",
+            get(admin_handlers::validation_failures),
+        )
+        .route("/admin/integrity", get(admin_handlers::check_integrity))
+        .route("/admin/jobs/:id", get(admin_handlers::get_job))
+        .route("/admin/exports", post(admin_handlers::export_users))
+        .route("/admin/audit/search", post(admin_handlers::search_audit))
+        .route("/admin/downloads", get(admin_handlers::list_downloads))
+        .route(
+            "/admin/downloads/:id",
+            delete(admin_handlers::cancel_download),
+        );
+    middleware::contract::with_contracts(routes, state)
+}
+
+/// Response signing routes.
+fn signing_routes() -> Router<AppState> {
+    Router::new().route(
+        "/.well-known/signing-key",
+        get(signing_handlers::signing_key),
+    )
+}
+
+/// OpenAPI document and Swagger UI routes.
+fn docs_routes() -> Router<AppState> {
+    Router::new()
+        .route("/openapi.json", get(docs_handlers::openapi))
+        .route("/docs", get(docs_handlers::swagger_ui))
+}
+
+/// Login and refresh routes, answered as disabled without an accounts
+/// file.
+fn auth_routes() -> Router<AppState> {
+    Router::new()
+        .route("/auth/login", post(auth_handlers::login))
+        .route("/auth/refresh", post(auth_handlers::refresh))
+}
+
+/// Development only routes.
+fn dev_routes() -> Router<AppState> {
+    Router::new().route("/dev/token", post(dev_handlers::dev_token))
+}
+
+/// Health routes served outside the versioned api.
+fn health_routes() -> Router<AppState> {
+    Router::new().route("/readyz", get(health_handlers::readyz))
+}
+
+/// Diagnostic routes served outside the versioned api.
+fn debug_routes() -> Router<AppState> {
+    Router::new().route("/debug/allocator", get(admin_handlers::allocator_stats))
 }
 
 /// Builds the routes and the layered middleware.
-pub fn build_app(persist: Arc<dyn UserPersistence>, app_config: AppConfig) -> Router {
+pub fn build_app(state: AppState) -> Router {
+    let settings = state.config().settings().clone();
+    let signer = state.config().signer().cloned();
+    let verifying = state
+        .config()
+        .signed_clients()
+        .is_some()
+        .then(|| state.clone());
+
     let tower_middleware = ServiceBuilder::new()
         .layer(SetRequestIdLayer::new(
             HeaderName::from_static(REQ_ID_HEADER),
-            middleware::MakeRequestUuid,
+            middleware::MakeRequestUuid(settings.request_id),
         ))
         .layer(PropagateHeaderLayer::new(HeaderName::from_static(
             REQ_ID_HEADER,
@@ -74,11 +186,102 @@ pub fn build_app(persist: Arc<dyn UserPersistence>, app_config: AppConfig) -> Ro
             .on_failure(RequestLogger)
             .on_response(RequestLogger),
         )
-        .layer(Extension(persist))
-        .layer(Extension(Arc::new(app_config)))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            CACHE_CONTROL,
+            settings.cache.cache_control(),
+        ))
+        .layer(axum::middleware::from_fn(middleware::panic::catch_panic))
+        .layer(HandleErrorLayer::new(fallback_handlers::handle_timeout))
+        .timeout(settings.limits.request_timeout)
+        .layer(DefaultBodyLimit::max(settings.limits.body_limit))
+        // Compression must remain the innermost global layer so the hashing
+        // layers of the method routers see uncompressed bodies.
         .layer(CompressionLayer::new());
 
-    Router::new()
-        .nest("/api/v1", user_routes())
-        .layer(tower_middleware)
+    let api_routes = user_routes(&state)
+        .merge(admin_routes(&state))
+        .merge(signing_routes())
+        .merge(auth_routes())
+        .merge(docs_routes());
+    let api_routes = if settings.dev_tokens {
+        api_routes.merge(dev_routes())
+    } else {
+        api_routes
+    };
+
+    let router = Router::new()
+        .nest("/api/v1", api_routes)
+        .merge(health_routes())
+        .merge(debug_routes())
+        .route_layer(axum::middleware::from_fn(
+            middleware::metrics::track_metrics,
+        ))
+        .route_layer(axum::middleware::from_fn(
+            middleware::audit::track_audit_events,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::rate_limit::rate_limit_headers,
+        ))
+        // Bodies are guarded per route, ahead of any route layer reading
+        // them.
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::body_timeout::read_timeouts,
+        ))
+        .fallback(fallback_handlers::not_found)
+        .with_state(state);
+
+    // Axum adds the `Allow` header around each route's layers, so the
+    // routes are wrapped as a whole for the middleware to see it.
+    let router = Router::new()
+        .fallback_service(router)
+        .layer(axum::middleware::from_fn(
+            fallback_handlers::method_not_allowed,
+        ));
+
+    // Signing wraps every other middleware except the global stack, so the
+    // signature covers the final body before it is compressed.
+    let router = match signer {
+        Some(signer) => router.layer(axum::middleware::from_fn_with_state(
+            signer,
+            middleware::signing::sign_response,
+        )),
+        None => router,
+    };
+    // Request bodies of registered clients are verified before any handler
+    // parses them.
+    let router = match verifying {
+        Some(state) => router.layer(axum::middleware::from_fn_with_state(
+            state,
+            middleware::signing::verify_request,
+        )),
+        None => router,
+    };
+
+    // The CORS layer answers every OPTIONS request as a preflight so it is
+    // only applied when cross origin requests are enabled. Otherwise OPTIONS
+    // is answered with the allowed methods by the router.
+    let router = if settings.cors.allowed_origins.is_empty() {
+        router
+    } else {
+        router.layer(
+            CorsLayer::new()
+                .allow_origin(AllowOrigin::list(settings.cors.allowed_origins))
+                .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+                .allow_headers([AUTHORIZATION, CONTENT_TYPE]),
+        )
+    };
+
+    // Mirroring sits inside the global middleware so it sees uncompressed
+    // bodies and the request id.
+    let router = match settings.mirror.base_url {
+        Some(base_url) => router.layer(middleware::mirror::MirrorLayer::new(
+            base_url,
+            settings.mirror.percent,
+        )),
+        None => router,
+    };
+
+    router.layer(tower_middleware)
 }
@@ -1,58 +1,239 @@
-use axum::extract::Extension;
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::{tls_rustls::RustlsConfig, Handle, HttpConfig};
+use bootstrap::{
+    install_panic_hook, metrics::install_metrics_exporter, token::LOGIN_PATH, DEV_TOKEN_PATH,
+};
 use clap::Parser;
 use rust_axum::{
-    arguments::{test_jwt, AppConfig, ProgramArgs},
+    arguments::{AppConfig, AppState, ProgramArgs},
     build_app,
-    types::jwt::Role,
+    downloads::DownloadTracker,
+    jobs::{ExportJobHandler, ImportJobHandler, JobWorker, EXPORT_JOB, IMPORT_JOB},
+    seed::seed_users,
+    stats::record_daily_snapshots,
+    tasks::{RestartPolicy, Supervisor, DEFAULT_LEASE},
     USER_MS_TARGET,
 };
-use std::{error::Error, net::SocketAddr, sync::Arc};
+use std::{error::Error, net::SocketAddr, sync::Arc, time::Duration};
 use tracing::{event, Level};
-use tracing_subscriber::EnvFilter;
-use user_persist::mongo_persistence::MongoPersistence;
+use user_persist::{
+    audit::MongoAuditLog,
+    jobs::{JobQueue, MongoJobQueue},
+    kv::MongoKvStore,
+    lock::KvLock,
+    mongo_persistence::MongoPersistence,
+    references::References,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_target(true)
-        .pretty()
-        // .json()
-        // .flatten_event(true)
-        .init();
-
     let program_opts = ProgramArgs::parse();
-    let app_config = AppConfig::new(&program_opts);
+    if program_opts.check() {
+        program_opts.self_check().await.exit();
+    }
+
+    let bootstrap = program_opts.bootstrap()?;
+    bootstrap.init_tracing();
+
+    install_panic_hook();
+
+    let app_config = AppConfig::new(&program_opts)?;
+    let token_issuer = program_opts.token_issuer()?;
 
-    // Print out some test JWT's.
     event!(
       target: USER_MS_TARGET,
-      Level::DEBUG,
-      "test admin jwt: {}",
-      test_jwt(&app_config, Role::Admin)
+      Level::INFO,
+      "Starting with the {} profile",
+      bootstrap.profile
     );
 
-    event!(
-      target: USER_MS_TARGET,
-      Level::DEBUG,
-      "test user jwt: {}",
-      test_jwt(&app_config, Role::User)
+    if app_config.settings().dev_tokens {
+        event!(
+          target: USER_MS_TARGET,
+          Level::WARN,
+          "Dev token endpoint enabled at {DEV_TOKEN_PATH}"
+        );
+    }
+
+    install_metrics_exporter(SocketAddr::from((
+        [0, 0, 0, 0],
+        program_opts.metrics_port(),
+    )))?;
+
+    let tls_config = match program_opts.server_tls_files() {
+        Some((cert_file, key_file)) => {
+            Some(RustlsConfig::from_pem_file(cert_file, key_file).await?)
+        }
+        None => None,
+    };
+
+    let seed_file = program_opts.seed_file().cloned();
+    let mongo_persist = Arc::new(MongoPersistence::new(program_opts.mongo_opts(&bootstrap)).await?);
+
+    if let Some(seed_file) = seed_file {
+        match seed_users(
+            mongo_persist.as_ref(),
+            &seed_file,
+            app_config.settings().email_validation,
+        )
+        .await?
+        {
+            Some(summary) => event!(
+              target: USER_MS_TARGET,
+              Level::INFO,
+              "Seeded empty database from {seed_file:?}: {summary}"
+            ),
+            None => event!(
+              target: USER_MS_TARGET,
+              Level::INFO,
+              "Users exist, not seeding from {seed_file:?}"
+            ),
+        }
+    }
+
+    // Singleton tasks are elected through leases shared by every replica,
+    // which also share rate limit counters.
+    let kv_store = MongoKvStore::new(&mongo_persist, app_config.clock().clone());
+    kv_store.ensure_indexes().await?;
+    let kv_store = Arc::new(kv_store);
+    let lock = Arc::new(KvLock::new(kv_store.clone()));
+
+    let tasks = Supervisor::default();
+    let (db, clock) = (mongo_persist.clone(), app_config.clock().clone());
+    tasks.spawn_singleton(
+        "stats-snapshots",
+        RestartPolicy::default(),
+        lock,
+        DEFAULT_LEASE,
+        move || record_daily_snapshots(db.clone(), clock.clone()),
+    );
+
+    let job_queue = MongoJobQueue::new(&mongo_persist, app_config.clock().clone());
+    job_queue.ensure_indexes().await?;
+    let jobs: Arc<dyn JobQueue> = Arc::new(job_queue);
+    let mut worker = JobWorker::new(jobs.clone(), app_config.settings().jobs).with_handler(
+        IMPORT_JOB,
+        ImportJobHandler {
+            persist: mongo_persist.clone(),
+            email_validation: app_config.settings().email_validation,
+        },
     );
+    if let Some(path) = &app_config.settings().export.file {
+        worker = worker.with_handler(
+            EXPORT_JOB,
+            ExportJobHandler {
+                persist: mongo_persist.clone(),
+                formats: app_config.settings().export.formats.clone(),
+                path: path.clone(),
+                clock: app_config.clock().clone(),
+            },
+        );
+    }
+    tasks.spawn("jobs", RestartPolicy::default(), move || {
+        worker.clone().run()
+    });
 
-    let config = RustlsConfig::from_pem_file(
-        program_opts.server_tls_cert_file(),
-        program_opts.server_tls_key_file(),
-    )
-    .await?;
+    let audit_log = MongoAuditLog::new(&mongo_persist);
+    audit_log.ensure_indexes().await?;
 
-    let mongo_persist = Arc::new(MongoPersistence::new(program_opts.mongo_opts()).await?);
+    let downloads = DownloadTracker::default();
+    let drain_timeout = app_config.settings().export.drain_timeout;
+    // Headers trickled in are cut off by the server, bodies by the read
+    // timeout middleware.
+    let http_config = HttpConfig::new()
+        .http1_header_read_timeout(app_config.settings().body_read.timeouts.read_timeout)
+        .build();
+    let state = AppState::new(mongo_persist.clone(), app_config)
+        .with_downloader(mongo_persist)
+        .with_downloads(downloads.clone())
+        .with_tasks(tasks.clone())
+        .with_jobs(jobs)
+        .with_references(References::default().register(Arc::new(audit_log.clone())))
+        .with_audit_log(Arc::new(audit_log))
+        .with_kv_store(kv_store.clone());
+    let app = build_app(match token_issuer {
+        Some(issuer) => {
+            event!(
+              target: USER_MS_TARGET,
+              Level::INFO,
+              "Login endpoint enabled at {LOGIN_PATH}"
+            );
+            state.with_token_issuer(issuer.with_kv_store(kv_store))
+        }
+        None => state,
+    });
 
-    let app = build_app(mongo_persist.clone(), app_config).layer(Extension(mongo_persist));
+    let handle = Handle::new();
+    tokio::spawn(graceful_shutdown(handle.clone(), downloads, drain_timeout));
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8443));
-    axum_server::bind_rustls(addr, config)
-        .serve(app.into_make_service())
-        .await
-        .map(Ok)?
+    let served = match tls_config {
+        Some(config) => {
+            axum_server::bind_rustls(addr, config)
+                .http_config(http_config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+        }
+        None => {
+            event!(
+              target: USER_MS_TARGET,
+              Level::WARN,
+              "Serving plain HTTP without a TLS certificate"
+            );
+            axum_server::bind(addr)
+                .http_config(http_config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+        }
+    };
+
+    tasks.shutdown().await;
+    Ok(served?)
+}
+
+/// Time cancelled downloads are given to end their response before the
+/// remaining connections are closed.
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+/// Drain open connections once the process is asked to stop. Streamed
+/// downloads are given up to `drain_timeout` to finish and are then
+/// cancelled.
+async fn graceful_shutdown(handle: Handle, downloads: DownloadTracker, drain_timeout: Duration) {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+    event!(
+      target: USER_MS_TARGET,
+      Level::INFO,
+      "Shutting down, draining {} downloads",
+      downloads.list().len()
+    );
+    handle.graceful_shutdown(Some(drain_timeout + CANCEL_GRACE));
+
+    let remaining = downloads.drain(drain_timeout).await;
+    if remaining > 0 {
+        event!(
+          target: USER_MS_TARGET,
+          Level::WARN,
+          "Cancelling {remaining} downloads still streaming after {drain_timeout:?}"
+        );
+        downloads.cancel_all();
+    }
 }
@@ -0,0 +1,93 @@
+/*!
+Security audit metrics.
+
+Rejections for authentication, authorization and validation failures tag
+their response with an [`AuditEvent`]. The [`track_audit_events`] route
+layer turns the event into a counter labeled with the matched route so
+dashboards can alert on spikes without parsing logs.
+*/
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+
+/// Counter of requests without a valid token labeled by reason.
+pub const AUTH_FAILURE_METRIC: &str = "auth_failure_total";
+/// Counter of requests with a token lacking the required role labeled by
+/// route.
+pub const FORBIDDEN_METRIC: &str = "forbidden_total";
+/// Counter of request bodies failing validation labeled by field.
+pub const VALIDATION_FAILURE_METRIC: &str = "validation_failure_total";
+
+/// Security relevant outcome of a request attached to its response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// The token was missing or rejected for the given reason.
+    AuthFailure(&'static str),
+    /// The token's role may not use the route.
+    Forbidden,
+    /// The request body failed validation of these fields.
+    ValidationFailure(Vec<&'static str>),
+}
+
+impl AuditEvent {
+    /// Attach the event to `response`.
+    pub fn attach(self, mut response: Response) -> Response {
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Count the audit event attached to the response, if any.
+pub async fn track_audit_events<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+
+    let response = next.run(req).await;
+
+    match response.extensions().get::<AuditEvent>() {
+        Some(AuditEvent::AuthFailure(reason)) => {
+            metrics::increment_counter!(AUTH_FAILURE_METRIC, "reason" => *reason);
+        }
+        Some(AuditEvent::Forbidden) => {
+            metrics::increment_counter!(FORBIDDEN_METRIC, "route" => route);
+        }
+        Some(AuditEvent::ValidationFailure(fields)) => {
+            for field in fields {
+                metrics::increment_counter!(VALIDATION_FAILURE_METRIC, "field" => *field);
+            }
+        }
+        None => (),
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::AuditEvent;
+    use crate::types::jwt::{AuthError, Role};
+    use axum::response::IntoResponse;
+
+    #[test]
+    fn test_auth_errors_attach_events() {
+        let event = |err: AuthError| {
+            err.into_response()
+                .extensions()
+                .get::<AuditEvent>()
+                .cloned()
+        };
+        assert_eq!(
+            event(AuthError::MissingAuth),
+            Some(AuditEvent::AuthFailure("missing_header"))
+        );
+        assert_eq!(
+            event(AuthError::Expired),
+            Some(AuditEvent::AuthFailure("expired"))
+        );
+        assert_eq!(
+            event(AuthError::RoleNotPermitted(Role::User)),
+            Some(AuditEvent::Forbidden)
+        );
+    }
+}
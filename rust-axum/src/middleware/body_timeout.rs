@@ -0,0 +1,120 @@
+/*!
+Read timeouts of request bodies.
+
+Request bodies are read through [`guard_body`] with the read timeouts of
+the matched route, so uploads trickled slower than the settings allow fail
+instead of holding a handler. Such requests are answered with `408` and the
+[`BODY_TIMEOUT_LABEL`] label whatever the handler made of the failed read.
+Middleware reading bodies before a route is matched reads them with
+[`read_body`] under the same timeouts.
+*/
+use crate::{types::handler::error_envelope, AppConfig, FRAMEWORK_TARGET};
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{MatchedPath, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use bootstrap::body_timeout::{guard_body, BodyTimeout, ReadTimeouts, BODY_TIMEOUT_LABEL};
+use futures::TryStreamExt;
+use http_body::{LengthLimitError, Limited};
+use std::sync::{Arc, OnceLock};
+use tower_http::request_id::RequestId;
+use tracing::{event, Level};
+
+/// Fail reading request bodies slower than the read timeouts of the
+/// route.
+pub async fn read_timeouts(
+    State(config): State<Arc<AppConfig>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if request.body().is_end_stream() {
+        return next.run(request).await;
+    }
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let timeouts = config.settings().body_read.timeouts(&route);
+    let req_id = request.extensions().get::<RequestId>().cloned();
+
+    let timed_out = Arc::new(OnceLock::new());
+    let on_timeout = {
+        let timed_out = timed_out.clone();
+        move |timeout: BodyTimeout| {
+            let _ = timed_out.set(timeout);
+            BoxError::from(timeout)
+        }
+    };
+    let request = request.map(|body| {
+        Body::wrap_stream(guard_body(
+            TryStreamExt::map_err(body, BoxError::from),
+            timeouts,
+            on_timeout,
+        ))
+    });
+
+    let response = next.run(request).await;
+    match timed_out.get() {
+        Some(timeout) => {
+            event!(
+              target: FRAMEWORK_TARGET,
+              Level::WARN,
+              "Request body of {route} timed out: {timeout}"
+            );
+            (
+                StatusCode::REQUEST_TIMEOUT,
+                error_envelope(BODY_TIMEOUT_LABEL, timeout, req_id.as_ref()),
+            )
+                .into_response()
+        }
+        None => response,
+    }
+}
+
+/// Read the whole of `body` within `timeouts` and `limit` bytes, answering
+/// `408` when it arrives too slowly, `413` when it is too large and `400`
+/// when it can't be read.
+pub async fn read_body(
+    body: Body,
+    timeouts: ReadTimeouts,
+    limit: usize,
+    req_id: Option<&RequestId>,
+) -> Result<Bytes, Response> {
+    let timed_out = Arc::new(OnceLock::new());
+    let on_timeout = {
+        let timed_out = timed_out.clone();
+        move |timeout: BodyTimeout| {
+            let _ = timed_out.set(timeout);
+            BoxError::from(timeout)
+        }
+    };
+    let body = Body::wrap_stream(guard_body(
+        TryStreamExt::map_err(body, BoxError::from),
+        timeouts,
+        on_timeout,
+    ));
+    hyper::body::to_bytes(Limited::new(body, limit))
+        .await
+        .map_err(|e| {
+            let (status, label, message) = match timed_out.get() {
+                Some(timeout) => (
+                    StatusCode::REQUEST_TIMEOUT,
+                    BODY_TIMEOUT_LABEL,
+                    timeout.to_string(),
+                ),
+                None if e.is::<LengthLimitError>() => (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "body.too_large",
+                    e.to_string(),
+                ),
+                None => (StatusCode::BAD_REQUEST, "body.unreadable", e.to_string()),
+            };
+            event!(target: FRAMEWORK_TARGET, Level::WARN, "Failed to read request body: {message}");
+            (status, error_envelope(label, message, req_id)).into_response()
+        })
+}
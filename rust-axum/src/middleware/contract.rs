@@ -0,0 +1,279 @@
+/*!
+Runtime checks of request and response bodies against the API contract.
+
+The bodies of the `api-types` crate are the contract every implementation
+of the API is held to. With `--contract-validation` set in the dev and
+staging profiles, the JSON bodies of the routes listed in [`contract`] are
+compared with their type: a body is accepted when deserializing it and
+serializing it back yields the same JSON, so fields the type doesn't know,
+misspelled fields and values of the wrong shape are reported.
+
+Divergences are logged, or with [`ContractMode::Enforce`] answered with
+`400 contract.request` for requests and `500 contract.response` for
+responses. Streamed bodies, other content types and error responses are
+not checked.
+*/
+use crate::{
+    arguments::AppState, middleware::hashing::MAX_HASHED_BODY_BYTES, security::hashing::HashedUser,
+    types::handler::error_envelope, AppConfig, FRAMEWORK_TARGET,
+};
+use api_types::{
+    AuditSearchRequest, CreateUserRequest, DownloadResponse, JobResponse, SearchRequest,
+};
+use axum::{
+    body::{boxed, Body, Bytes, Full},
+    extract::{MatchedPath, State},
+    http::{header::CONTENT_TYPE, HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use clap::ValueEnum;
+use http_body::{Body as _, Limited};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use tower_http::request_id::RequestId;
+use tracing::{event, Level};
+use user_persist::{audit::AuditRecord, types::SearchPage};
+
+/// What to do with a body diverging from the contract.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ContractMode {
+    /// Bodies aren't checked.
+    #[default]
+    Off,
+    /// Divergences are logged.
+    Log,
+    /// Divergences are logged and the request fails.
+    Enforce,
+}
+
+/// Check of a JSON body, describing where it diverges.
+type Check = fn(&Value) -> Result<(), String>;
+
+/// Contract of a route.
+#[derive(Clone, Copy, Default)]
+pub struct Contract {
+    pub request: Option<Check>,
+    pub response: Option<Check>,
+}
+
+/// Check that `body` round trips through `T` unchanged.
+pub fn conforms<T: DeserializeOwned + Serialize>(body: &Value) -> Result<(), String> {
+    let typed = serde_json::from_value::<T>(body.clone()).map_err(|e| e.to_string())?;
+    let expected = serde_json::to_value(typed).map_err(|e| e.to_string())?;
+    match divergence(&expected, body, "$") {
+        Some(path) => Err(format!("unexpected value at {path}")),
+        None => Ok(()),
+    }
+}
+
+/// Path of the first value of `actual` differing from `expected`. Nulls
+/// stand for absent optional fields.
+fn divergence(expected: &Value, actual: &Value, path: &str) -> Option<String> {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => actual
+            .iter()
+            .filter(|(_, value)| !value.is_null())
+            .find_map(|(key, value)| match expected.get(key) {
+                Some(expected) => divergence(expected, value, &format!("{path}.{key}")),
+                None => Some(format!("{path}.{key}")),
+            }),
+        (Value::Array(expected), Value::Array(actual)) if expected.len() == actual.len() => {
+            expected
+                .iter()
+                .zip(actual)
+                .enumerate()
+                .find_map(|(i, (expected, actual))| {
+                    divergence(expected, actual, &format!("{path}[{i}]"))
+                })
+        }
+        (expected, actual) if expected == actual => None,
+        _ => Some(path.to_owned()),
+    }
+}
+
+/// A search answers a list of users, or a page of them when enveloped.
+fn search_response(body: &Value) -> Result<(), String> {
+    conforms::<Vec<HashedUser>>(body).or_else(|_| conforms::<SearchPage<HashedUser>>(body))
+}
+
+/// Contract of the route matching `path` with `method`.
+pub fn contract(method: &Method, path: &str) -> Option<Contract> {
+    let contract = match (method.as_str(), path) {
+        ("POST", "/api/v1/user") => Contract {
+            request: Some(conforms::<CreateUserRequest>),
+            response: Some(conforms::<HashedUser>),
+        },
+        ("GET", "/api/v1/user/:id" | "/api/v1/user/by-email/:email") => Contract {
+            request: None,
+            response: Some(conforms::<HashedUser>),
+        },
+        ("POST", "/api/v1/user/search") => Contract {
+            request: Some(conforms::<SearchRequest>),
+            response: Some(search_response),
+        },
+        ("POST", "/api/v1/admin/audit/search") => Contract {
+            request: Some(conforms::<AuditSearchRequest>),
+            response: Some(conforms::<SearchPage<AuditRecord>>),
+        },
+        ("GET", "/api/v1/admin/jobs/:id") | ("POST", "/api/v1/admin/exports") => Contract {
+            request: None,
+            response: Some(conforms::<JobResponse>),
+        },
+        ("GET", "/api/v1/admin/downloads") => Contract {
+            request: None,
+            response: Some(conforms::<Vec<DownloadResponse>>),
+        },
+        _ => return None,
+    };
+    Some(contract)
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"))
+}
+
+/// Outcome of checking a body, logging a divergence.
+fn diverges(check: Check, bytes: &Bytes, what: &str, route: &str) -> Option<String> {
+    // Bodies which aren't JSON are rejected by the extractors.
+    let body = serde_json::from_slice::<Value>(bytes).ok()?;
+    let divergence = check(&body).err()?;
+    event!(
+      target: FRAMEWORK_TARGET,
+      Level::WARN,
+      "{what} of {route} diverges from the contract: {divergence}"
+    );
+    Some(divergence)
+}
+
+/// Check the contracts of `routes` when enabled in the settings of
+/// `state`.
+pub fn with_contracts(routes: Router<AppState>, state: &AppState) -> Router<AppState> {
+    if state.config().settings().contract == ContractMode::Off {
+        routes
+    } else {
+        routes.route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            check_contract,
+        ))
+    }
+}
+
+/// Check the bodies of routes with a contract.
+pub async fn check_contract(
+    State(config): State<Arc<AppConfig>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let mode = config.settings().contract;
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let Some(contract) = contract(request.method(), &route) else {
+        return next.run(request).await;
+    };
+    let req_id = request.extensions().get::<RequestId>().cloned();
+
+    let request = match contract.request.filter(|_| is_json(request.headers())) {
+        Some(check) => {
+            let (parts, body) = request.into_parts();
+            let body_limit = config.settings().limits.body_limit;
+            let bytes = match hyper::body::to_bytes(Limited::new(body, body_limit)).await {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    return (
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        error_envelope("body.too_large", e, req_id.as_ref()),
+                    )
+                        .into_response()
+                }
+            };
+            let divergence = diverges(check, &bytes, "Request", &route);
+            if let (Some(divergence), ContractMode::Enforce) = (divergence, mode) {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    error_envelope("contract.request", divergence, req_id.as_ref()),
+                )
+                    .into_response();
+            }
+            Request::from_parts(parts, Body::from(bytes))
+        }
+        None => request,
+    };
+
+    let response = next.run(request).await;
+    let Some(check) = contract.response else {
+        return response;
+    };
+    if !response.status().is_success() || !is_json(response.headers()) {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    if body.size_hint().upper().is_none() {
+        return Response::from_parts(parts, body);
+    }
+    let bytes = match hyper::body::to_bytes(Limited::new(body, MAX_HASHED_BODY_BYTES)).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let message = format!("Failed to read response body: {e}");
+            event!(target: FRAMEWORK_TARGET, Level::ERROR, "{message}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                error_envelope("internal.error", message, req_id.as_ref()),
+            )
+                .into_response();
+        }
+    };
+    match diverges(check, &bytes, "Response", &route) {
+        Some(divergence) if mode == ContractMode::Enforce => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            error_envelope("contract.response", divergence, req_id.as_ref()),
+        )
+            .into_response(),
+        _ => Response::from_parts(parts, boxed(Full::from(bytes))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::conforms;
+    use api_types::{CreateUserRequest, JobResponse};
+    use serde_json::json;
+
+    #[test]
+    fn test_conforms() {
+        let user = json!({
+            "name": "Test User",
+            "age": 100,
+            "email": "test@test.com",
+            "gender": "Male",
+            "phone": null,
+        });
+        assert_eq!(conforms::<CreateUserRequest>(&user), Ok(()));
+
+        let mut extra = user.clone();
+        extra["nickname"] = json!("Test");
+        assert_eq!(
+            conforms::<CreateUserRequest>(&extra),
+            Err("unexpected value at $.nickname".to_owned())
+        );
+
+        let job = json!({
+            "id": "1",
+            "kind": "import",
+            "state": "queued",
+            "attempts": 0,
+            "maxAttempts": 5,
+            "createdAtMs": 0,
+            "result": {"saved": 1},
+        });
+        assert_eq!(conforms::<JobResponse>(&job), Ok(()));
+        assert!(conforms::<JobResponse>(&json!({"id": 1})).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+/*!
+Sparse fieldsets for the user routes.
+
+A request with `?fields=name,email` receives only those fields of each
+user, as described in [`bootstrap::fields`]. The fields are pruned after
+the handler and the hashing middleware ran so `hashId` is computed from
+the whole user. An unknown field is rejected with a `400 Bad Request`
+before the handler runs.
+*/
+use crate::{middleware::map_json_body, types::handler::error_envelope};
+use axum::{
+    extract::Query,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bootstrap::fields::{FieldSet, INVALID_FIELDS_LABEL};
+use serde::Deserialize;
+use tower_http::request_id::RequestId;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FieldsParams {
+    fields: Option<String>,
+}
+
+/// Prune successful user responses to the fields the request asked for.
+pub async fn sparse_fields<B>(req: Request<B>, next: Next<B>) -> Response {
+    let req_id = req.extensions().get::<RequestId>().cloned();
+    // Other query parameters are checked by the handler.
+    let requested = Query::<FieldsParams>::try_from_uri(req.uri())
+        .map(|Query(params)| params.fields)
+        .unwrap_or_default();
+    let fields = match requested.map(|fields| fields.parse::<FieldSet>()) {
+        None => return next.run(req).await,
+        Some(Ok(fields)) => fields,
+        Some(Err(e)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                error_envelope(INVALID_FIELDS_LABEL, e, req_id.as_ref()),
+            )
+                .into_response()
+        }
+    };
+
+    let response = next.run(req).await;
+    map_json_body(response, req_id.as_ref(), "application/json", |mut body| {
+        fields.prune(&mut body);
+        Some(body)
+    })
+    .await
+}
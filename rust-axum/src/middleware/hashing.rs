@@ -1,135 +1,371 @@
-use crate::{security::hashing::Hashable, AppConfig, FRAMEWORK_TARGET};
+/*!
+Middleware that hashes JSON response bodies.
+
+The layer is parameterized by the type the response body deserializes
+to, for example `HashingLayer::<User>` for a single user and
+`HashingLayer::<Vec<User>>` for a list of users.
+
+Only bodies with a known size are hashed. Streaming bodies, such as the
+user download, have no upper bound and are passed through untouched. A
+body larger than the configured limit is never buffered and, like a body
+that doesn't deserialize as the type of the layer, results in a 500
+response.
+
+The hashing layer must see the uncompressed JSON body, so it has to be
+applied inside (after) the `CompressionLayer`. It is only mounted with
+[`hashed`] on the method router of a route, whose layers run inside every
+layer of the router and so inside the global middleware stack. A stack
+hashing compressed bodies can't be built.
+*/
+use crate::{
+    arguments::AppState, security::hashing::Hashable, types::handler::error_envelope, AppConfig,
+    FRAMEWORK_TARGET,
+};
 use axum::{
-  body::{boxed, Body},
-  http::Request,
-  response::Response,
+    body::{boxed, Full},
+    extract::FromRef,
+    http::{header::CONTENT_LENGTH, Request, StatusCode},
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
 };
 use futures::future::BoxFuture;
-use http_body::Body as _;
+use http_body::{Body as _, Limited};
 use hyper::body::Bytes;
+use serde::de::DeserializeOwned;
 use std::{
-  sync::Arc,
-  task::{Context, Poll},
+    marker::PhantomData,
+    sync::Arc,
+    task::{Context, Poll},
 };
 use tower::Service;
-use tower_layer::{layer_fn, LayerFn};
+use tower_http::request_id::RequestId;
+use tower_layer::Layer;
 use tracing::{event, Level};
-use user_persist::types::User;
-
-/// Deserialize the response and call its hash method.
-pub fn hash_user(hash_prefix: &str, bytes: Bytes) -> Bytes {
-  match serde_json::from_slice(&bytes).map(|b: User| b.hash(hash_prefix)) {
-    Ok(hashed) => Bytes::from(serde_json::to_vec(&hashed).unwrap()),
-    Err(e) => {
-      event!(
-        target: FRAMEWORK_TARGET,
-        Level::ERROR,
-        "Failed to hash response {e}"
-      );
-      bytes
-    }
-  }
+
+/// Largest response body the hashing middleware will buffer.
+pub const MAX_HASHED_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+/// Deserialize the response body as `T` and replace it with its hashed
+/// form.
+pub fn hash_body<T>(hash_prefix: &str, bytes: &[u8]) -> Result<Bytes, serde_json::Error>
+where
+    T: Hashable + DeserializeOwned,
+{
+    let value = serde_json::from_slice::<T>(bytes)?;
+    serde_json::to_vec(&value.hash(hash_prefix)).map(Bytes::from)
+}
+
+/// Hash the JSON responses of `route` deserialized as `T`.
+pub fn hashed<T>(state: &AppState, route: MethodRouter<AppState>) -> MethodRouter<AppState>
+where
+    T: Hashable + DeserializeOwned + 'static,
+{
+    route.layer(HashingLayer::<T>::new(Arc::from_ref(state)))
+}
+
+/// Layer that hashes JSON responses deserialized as `T`.
+pub struct HashingLayer<T> {
+    config: Arc<AppConfig>,
+    max_body_bytes: usize,
+    _payload: PhantomData<fn() -> T>,
+}
+
+impl<T> HashingLayer<T> {
+    /// Create a hashing layer using the hash prefix from the config. Routes
+    /// mount it with [`hashed`].
+    pub(crate) fn new(config: Arc<AppConfig>) -> Self {
+        Self {
+            config,
+            max_body_bytes: MAX_HASHED_BODY_BYTES,
+            _payload: PhantomData,
+        }
+    }
+
+    /// Set the largest response body that will be buffered for hashing.
+    pub fn max_body_bytes(self, max_body_bytes: usize) -> Self {
+        Self {
+            max_body_bytes,
+            ..self
+        }
+    }
 }
 
-/// Deserialize the response and call its hash method.
-pub fn hash_users(hash_prefix: &str, bytes: Bytes) -> Bytes {
-  match serde_json::from_slice(&bytes).map(|v: Vec<User>| {
-    v.iter().map(|u| u.hash(hash_prefix)).collect::<Vec<_>>()
-  }) {
-    Ok(hashed) => Bytes::from(serde_json::to_vec(&hashed).unwrap()),
-    Err(e) => {
-      event!(
-        target: FRAMEWORK_TARGET,
-        Level::ERROR,
-        "Failed to hash response {e}"
-      );
-      bytes
-    }
-  }
+impl<T> Clone for HashingLayer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            max_body_bytes: self.max_body_bytes,
+            _payload: PhantomData,
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct HashingMiddleware<S, F> {
-  pub inner: S,
-  pub hash_fn: F,
+impl<S, T> Layer<S> for HashingLayer<T> {
+    type Service = HashingMiddleware<S, T>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HashingMiddleware {
+            inner,
+            config: self.config.clone(),
+            max_body_bytes: self.max_body_bytes,
+            _payload: PhantomData,
+        }
+    }
 }
 
-type HashingFunc = fn(&str, Bytes) -> Bytes;
-
-impl<S> HashingMiddleware<S, HashingFunc> {
-  pub fn hash_users_layer(
-  ) -> LayerFn<fn(S) -> HashingMiddleware<S, HashingFunc>> {
-    layer_fn(|inner| HashingMiddleware {
-      inner,
-      hash_fn: hash_users,
-    })
-  }
-
-  pub fn hash_user_layer() -> LayerFn<fn(S) -> HashingMiddleware<S, HashingFunc>>
-  {
-    layer_fn(|inner| HashingMiddleware {
-      inner,
-      hash_fn: hash_user,
-    })
-  }
-
-  // pub fn hash_with_fn<F>(f: F) -> LayerFn<fn(S) -> HashingMiddleware<S, F>>
-  // where
-  //   F: FnMut(Bytes, &str) -> Bytes + Clone + 'static + Send,
-  // {
-  //   layer_fn(|inner| HashingMiddleware { inner, hash_fn: f })
-  // }
+/// Service created by [`HashingLayer`].
+pub struct HashingMiddleware<S, T> {
+    inner: S,
+    config: Arc<AppConfig>,
+    max_body_bytes: usize,
+    _payload: PhantomData<fn() -> T>,
 }
 
-impl<S, F> Service<Request<Body>> for HashingMiddleware<S, F>
+impl<S: Clone, T> Clone for HashingMiddleware<S, T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            max_body_bytes: self.max_body_bytes,
+            _payload: PhantomData,
+        }
+    }
+}
+
+/// Response sent when a body can't be hashed.
+fn hashing_failed(req_id: Option<&RequestId>, message: String) -> Response {
+    event!(target: FRAMEWORK_TARGET, Level::ERROR, "{message}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        error_envelope("internal.error", message, req_id),
+    )
+        .into_response()
+}
+
+impl<S, T, B> Service<Request<B>> for HashingMiddleware<S, T>
 where
-  S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
-  S::Future: Send + 'static,
-  F: FnMut(&str, Bytes) -> Bytes + Clone + 'static + Send,
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    T: Hashable + DeserializeOwned + 'static,
+    B: Send + 'static,
 {
-  type Response = S::Response;
-  type Error = S::Error;
-  type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
-
-  fn poll_ready(
-    &mut self,
-    cx: &mut Context<'_>,
-  ) -> Poll<Result<(), Self::Error>> {
-    self.inner.poll_ready(cx)
-  }
-
-  fn call(&mut self, req: Request<Body>) -> Self::Future {
-    let hash_prefix = req
-      .extensions()
-      .get::<Arc<AppConfig>>()
-      .map(|config| config.hash_prefix())
-      .unwrap_or_else(|| "default_prefix")
-      .to_owned();
-
-    event!(
-      target: FRAMEWORK_TARGET,
-      Level::DEBUG,
-      "hash_prefix: {hash_prefix}"
-    );
-
-    let clone = self.inner.clone();
-    let mut inner = std::mem::replace(&mut self.inner, clone);
-    let mut hash_f = self.hash_fn.clone();
-
-    Box::pin(async move {
-      let res = inner.call(req).await?;
-
-      if res.status().is_success() {
-        event!(target: FRAMEWORK_TARGET, Level::DEBUG, "Hashing response");
-
-        let response = res.map(move |body| {
-          boxed(body.map_data(move |bytes| hash_f(&hash_prefix, bytes)))
-        });
-
-        Ok(response)
-      } else {
-        Ok(res)
-      }
-    })
-  }
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let config = self.config.clone();
+        let max_body_bytes = self.max_body_bytes;
+        let req_id = req.extensions().get::<RequestId>().cloned();
+
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let res = inner.call(req).await?;
+
+            if !res.status().is_success() {
+                return Ok(res);
+            }
+
+            let (mut parts, body) = res.into_parts();
+
+            match body.size_hint().upper() {
+                None => {
+                    event!(
+                      target: FRAMEWORK_TARGET,
+                      Level::DEBUG,
+                      "Not hashing streaming response"
+                    );
+                    Ok(Response::from_parts(parts, body))
+                }
+                Some(size) if size > max_body_bytes as u64 => Ok(hashing_failed(
+                    req_id.as_ref(),
+                    format!("Response body of {size} bytes exceeds the {max_body_bytes} byte hashing limit"),
+                )),
+                Some(_) => match hyper::body::to_bytes(Limited::new(body, max_body_bytes)).await {
+                    Ok(bytes) => {
+                        event!(target: FRAMEWORK_TARGET, Level::DEBUG, "Hashing response");
+                        match hash_body::<T>(config.hash_prefix(), &bytes) {
+                            Ok(hashed) => {
+                                parts.headers.remove(CONTENT_LENGTH);
+                                Ok(Response::from_parts(parts, boxed(Full::from(hashed))))
+                            }
+                            Err(e) => Ok(hashing_failed(
+                                req_id.as_ref(),
+                                format!("Failed to hash response: {e}"),
+                            )),
+                        }
+                    }
+                    Err(e) => Ok(hashing_failed(
+                        req_id.as_ref(),
+                        format!("Failed to read response body for hashing: {e}"),
+                    )),
+                },
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HashingLayer;
+    use crate::{
+        arguments::AppConfig,
+        security::hashing::{Hashable, HashedUser},
+    };
+    use api_types::UserResponse;
+    use axum::{
+        body::{boxed, Body},
+        http::{Request, StatusCode},
+        response::{IntoResponse, Response},
+        Json,
+    };
+    use futures::stream;
+    use hyper::body::{to_bytes, Bytes};
+    use serde::de::DeserializeOwned;
+    use std::{convert::Infallible, sync::Arc};
+    use tower::{service_fn, Layer, ServiceExt};
+    use user_persist::types::{Email, Gender, User};
+
+    fn config() -> Arc<AppConfig> {
+        Arc::new(AppConfig::test(b"TEST_SECRET"))
+    }
+
+    fn test_user(id: &str) -> User {
+        User {
+            id: Some(id.parse().unwrap()),
+            name: "Test User".to_owned(),
+            age: 100,
+            email: Email("test@user.com".to_owned()),
+            gender: Gender::Male,
+            phone: None,
+            address: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    /// Call a hashing layer wrapping a service that returns `response`.
+    async fn call<T, R>(layer: HashingLayer<T>, response: R) -> Response
+    where
+        T: Hashable + DeserializeOwned + 'static,
+        R: IntoResponse + Clone + Send + 'static,
+    {
+        layer
+            .layer(service_fn(move |_req: Request<Body>| {
+                let response = response.clone();
+                async move { Ok::<_, Infallible>(response.into_response()) }
+            }))
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap()
+    }
+
+    async fn body_as<T: DeserializeOwned>(response: Response) -> T {
+        serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hash_get_user() {
+        let config = config();
+        let user = test_user("1");
+        let response = call(
+            HashingLayer::<User>::new(config.clone()),
+            Json(user.clone()),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let hashed = body_as::<HashedUser>(response).await;
+        assert_eq!(hashed.user, UserResponse::from(user.clone()));
+        assert_eq!(hashed.hash_id, user.hash(config.hash_prefix()).hash_id);
+    }
+
+    #[tokio::test]
+    async fn test_hash_save_user() {
+        let config = config();
+        let user = test_user("2");
+        let response = call(
+            HashingLayer::<User>::new(config.clone()),
+            (StatusCode::CREATED, Json(user.clone())),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let hashed = body_as::<HashedUser>(response).await;
+        assert_eq!(hashed.hash_id, user.hash(config.hash_prefix()).hash_id);
+    }
+
+    #[tokio::test]
+    async fn test_hash_search_users() {
+        let config = config();
+        let users = vec![test_user("1"), test_user("2")];
+        let response = call(
+            HashingLayer::<Vec<User>>::new(config.clone()),
+            Json(users.clone()),
+        )
+        .await;
+
+        let hashed = body_as::<Vec<HashedUser>>(response).await;
+        assert_eq!(hashed.len(), 2);
+        assert_eq!(
+            hashed[1].hash_id,
+            users[1].hash(config.hash_prefix()).hash_id
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_not_hashed() {
+        let response = call(
+            HashingLayer::<User>::new(config()),
+            (StatusCode::NOT_FOUND, "missing"),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "missing");
+    }
+
+    #[tokio::test]
+    async fn test_body_over_limit() {
+        let response = call(
+            HashingLayer::<User>::new(config()).max_body_bytes(8),
+            Json(test_user("1")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_body_not_hashable() {
+        let response = call(
+            HashingLayer::<User>::new(config()),
+            Json(vec![test_user("1")]),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_body_not_hashed() {
+        let response = HashingLayer::<Vec<User>>::new(config())
+            .layer(service_fn(|_req: Request<Body>| async {
+                let chunks = vec![Ok::<_, Infallible>(Bytes::from("[")), Ok(Bytes::from("]"))];
+                Ok::<_, Infallible>(Response::new(boxed(Body::wrap_stream(stream::iter(
+                    chunks,
+                )))))
+            }))
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap();
+
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "[]");
+    }
 }
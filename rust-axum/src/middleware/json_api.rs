@@ -0,0 +1,227 @@
+/*!
+Opt-in [JSON:API](https://jsonapi.org) output for the user routes.
+
+Clients sending `Accept: application/vnd.api+json` receive users as
+resource objects with a `self` link instead of plain objects. Users are
+wrapped after the handlers and the hashing middleware ran, so the
+attributes are exactly what a plain response holds, `hashId` included.
+A search page is wrapped with its total in `meta` and `first`, `prev`,
+`next` and `last` links.
+
+Error responses, streamed bodies and bodies that aren't users, such as
+gender counts, are passed through unchanged.
+*/
+use crate::middleware::map_json_body;
+use axum::{
+    http::{
+        header::{ACCEPT, VARY},
+        HeaderMap, HeaderValue, Request, Uri,
+    },
+    middleware::Next,
+    response::Response,
+};
+use serde_json::{json, Map, Value};
+use tower_http::request_id::RequestId;
+
+/// Media type of JSON:API documents.
+pub const JSON_API: &str = "application/vnd.api+json";
+
+/// Path of a user resource without its id.
+const USER_PATH: &str = "/api/v1/user/";
+
+/// The request accepts JSON:API documents.
+fn accepts_json_api(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case(JSON_API)
+        })
+}
+
+/// Resource object of a user, or `None` when `value` isn't a user.
+fn user_resource(value: Value) -> Option<Value> {
+    let mut attributes = match value {
+        Value::Object(attributes) => attributes,
+        _ => return None,
+    };
+    let id = match attributes.remove("id")? {
+        Value::String(id) => id,
+        _ => return None,
+    };
+    Some(json!({
+        "type": "users",
+        "id": id,
+        "attributes": attributes,
+        "links": {"self": format!("{USER_PATH}{id}")},
+    }))
+}
+
+/// `uri` with its `offset` and `limit` query parameters replaced.
+fn page_link(uri: &Uri, offset: u64, limit: u64) -> String {
+    let mut query = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| !pair.starts_with("offset=") && !pair.starts_with("limit="))
+        .collect::<Vec<_>>()
+        .join("&");
+    if !query.is_empty() {
+        query.push('&');
+    }
+    format!("{}?{query}offset={offset}&limit={limit}", uri.path())
+}
+
+/// Pagination links of a page of `limit` items from `offset` among
+/// `total`.
+fn page_links(uri: &Uri, total: u64, offset: u64, limit: u64) -> Map<String, Value> {
+    let mut links = Map::new();
+    links.insert("self".to_owned(), json!(page_link(uri, offset, limit)));
+    links.insert("first".to_owned(), json!(page_link(uri, 0, limit)));
+    if limit == 0 {
+        return links;
+    }
+    if offset > 0 {
+        let prev = offset.saturating_sub(limit);
+        links.insert("prev".to_owned(), json!(page_link(uri, prev, limit)));
+    }
+    if offset.saturating_add(limit) < total {
+        let next = offset + limit;
+        links.insert("next".to_owned(), json!(page_link(uri, next, limit)));
+    }
+    let last = total.saturating_sub(1) / limit * limit;
+    links.insert("last".to_owned(), json!(page_link(uri, last, limit)));
+    links
+}
+
+/// JSON:API document for a response body of the request to `uri`, or
+/// `None` when the body doesn't hold users.
+pub fn to_document(body: Value, uri: &Uri) -> Option<Value> {
+    match body {
+        Value::Array(users) => {
+            let data = users
+                .into_iter()
+                .map(user_resource)
+                .collect::<Option<Vec<_>>>()?;
+            Some(json!({"data": data, "links": {"self": uri.to_string()}}))
+        }
+        Value::Object(mut page) if page.contains_key("items") && page.contains_key("total") => {
+            let number = |page: &Map<String, Value>, key| page.get(key).and_then(Value::as_u64);
+            let (total, offset, limit) = (
+                number(&page, "total")?,
+                number(&page, "offset")?,
+                number(&page, "limit")?,
+            );
+            let data = match page.remove("items")? {
+                Value::Array(users) => users
+                    .into_iter()
+                    .map(user_resource)
+                    .collect::<Option<Vec<_>>>()?,
+                _ => return None,
+            };
+            let mut meta = json!({"total": total});
+            if let Some(estimated) = page.remove("estimated") {
+                meta["estimated"] = estimated;
+            }
+            Some(json!({
+                "data": data,
+                "meta": meta,
+                "links": page_links(uri, total, offset, limit),
+            }))
+        }
+        user => Some(json!({"data": user_resource(user)?})),
+    }
+}
+
+/// Wrap successful user responses as JSON:API documents when the request
+/// accepts them.
+pub async fn json_api<B>(req: Request<B>, next: Next<B>) -> Response {
+    let wanted = accepts_json_api(req.headers());
+    let uri = req.uri().clone();
+    let req_id = req.extensions().get::<RequestId>().cloned();
+
+    let mut response = next.run(req).await;
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept"));
+    if !wanted {
+        return response;
+    }
+
+    map_json_body(response, req_id.as_ref(), JSON_API, |body| {
+        to_document(body, &uri)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accepts_json_api, to_document};
+    use axum::http::{header::ACCEPT, HeaderMap, HeaderValue, Uri};
+    use serde_json::json;
+
+    #[test]
+    fn test_accepts_json_api() {
+        let accepts = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_static(accept));
+            accepts_json_api(&headers)
+        };
+        assert!(accepts("application/vnd.api+json"));
+        assert!(accepts("text/html, Application/Vnd.Api+Json"));
+        assert!(!accepts("application/json"));
+        assert!(!accepts_json_api(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_user_document() {
+        let uri = Uri::from_static("/api/v1/user/61c0d1954c6b974ca7000000");
+        let user = json!({"id": "61c0d1954c6b974ca7000000", "name": "Test User", "hashId": "abc"});
+        assert_eq!(
+            to_document(user, &uri),
+            Some(json!({
+                "data": {
+                    "type": "users",
+                    "id": "61c0d1954c6b974ca7000000",
+                    "attributes": {"name": "Test User", "hashId": "abc"},
+                    "links": {"self": "/api/v1/user/61c0d1954c6b974ca7000000"},
+                }
+            }))
+        );
+
+        let counts = json!([{"gender": "Male", "count": 1}]);
+        assert_eq!(to_document(counts, &uri), None);
+    }
+
+    #[test]
+    fn test_page_document() {
+        let uri = Uri::from_static("/api/v1/user/search?all=true&offset=20&limit=10");
+        let page = json!({
+            "items": [{"id": "1", "name": "Test User"}],
+            "total": 45,
+            "offset": 20,
+            "limit": 10,
+        });
+        let document = to_document(page, &uri).unwrap();
+        assert_eq!(document["data"][0]["links"]["self"], "/api/v1/user/1");
+        assert_eq!(document["meta"], json!({"total": 45}));
+        assert_eq!(
+            document["links"],
+            json!({
+                "self": "/api/v1/user/search?all=true&offset=20&limit=10",
+                "first": "/api/v1/user/search?all=true&offset=0&limit=10",
+                "prev": "/api/v1/user/search?all=true&offset=10&limit=10",
+                "next": "/api/v1/user/search?all=true&offset=30&limit=10",
+                "last": "/api/v1/user/search?all=true&offset=40&limit=10",
+            })
+        );
+    }
+}
@@ -0,0 +1,42 @@
+/*!
+Request latency metrics.
+*/
+use crate::FRAMEWORK_TARGET;
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+use bootstrap::metrics::REQUEST_DURATION_METRIC;
+use std::time::Instant;
+use tracing::{event, Level};
+
+/// Times each request and records it against the matched route template
+/// rather than the raw path so ids don't become labels.
+pub async fn track_metrics<B>(req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let method = req.method().to_string();
+
+    let response = next.run(req).await;
+
+    let latency = start.elapsed();
+    let status = response.status().as_u16().to_string();
+
+    event!(
+      target: FRAMEWORK_TARGET,
+      Level::DEBUG,
+      "{method} {route} completed in {} ms",
+      latency.as_millis()
+    );
+
+    metrics::histogram!(
+        REQUEST_DURATION_METRIC,
+        latency.as_secs_f64(),
+        "method" => method,
+        "route" => route,
+        "status" => status
+    );
+
+    response
+}
@@ -0,0 +1,435 @@
+/*!
+Middleware mirroring read-only requests to a secondary deployment.
+
+A configurable percentage of GET and HEAD requests is replayed against a
+secondary base URL once the primary response is ready. The mirrored
+request runs in a spawned task so it never adds latency for the client.
+The secondary response is compared with the primary one and the outcome
+is recorded in the [`MIRROR_METRIC`] counter.
+
+Only bodies with a known size up to [`MAX_COMPARED_BODY_BYTES`] are
+compared. Streaming responses are compared by status alone. The primary
+body is captured before it is compressed, so the secondary is asked for an
+uncompressed one.
+
+Mirrored requests go over plain HTTP, so they are sent without the
+`Authorization` and `Cookie` headers of the caller. The secondary rejects
+those of routes requiring a token, which are counted as status mismatches.
+*/
+use crate::FRAMEWORK_TARGET;
+use axum::{
+    body::{boxed, Full},
+    http::{
+        header::{ACCEPT_ENCODING, AUTHORIZATION, COOKIE, HOST},
+        HeaderMap, HeaderValue, Method, Request, Uri,
+    },
+    response::Response,
+};
+use futures::future::BoxFuture;
+use http::StatusCode;
+use http_body::Body as _;
+use hyper::{body::Bytes, client::HttpConnector, Body, Client};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tower::Service;
+use tower_layer::Layer;
+use tracing::{event, Level};
+
+/// Counter of mirrored requests labeled by comparison result.
+pub const MIRROR_METRIC: &str = "mirror_requests_total";
+
+/// Largest primary response body kept for comparison.
+pub const MAX_COMPARED_BODY_BYTES: usize = 1024 * 1024;
+
+/// Time allowed for the secondary to respond.
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of comparing a mirrored response with the primary response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorResult {
+    Match,
+    StatusMismatch,
+    BodyMismatch,
+    Error,
+}
+
+impl MirrorResult {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Match => "match",
+            Self::StatusMismatch => "status_mismatch",
+            Self::BodyMismatch => "body_mismatch",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Selects `percent` out of every hundred requests.
+#[derive(Debug, Clone)]
+struct Sampler {
+    percent: u64,
+    count: Arc<AtomicU64>,
+}
+
+impl Sampler {
+    fn sample(&self) -> bool {
+        self.count.fetch_add(1, Ordering::Relaxed) % 100 < self.percent
+    }
+}
+
+/// Layer mirroring read-only requests to a secondary base URL.
+#[derive(Debug, Clone)]
+pub struct MirrorLayer {
+    base_url: Uri,
+    sampler: Sampler,
+    client: Client<HttpConnector>,
+}
+
+impl MirrorLayer {
+    /// Mirror `percent` of read-only requests to `base_url`. Values above
+    /// 100 mirror every request.
+    pub fn new(base_url: Uri, percent: u8) -> Self {
+        Self {
+            base_url,
+            sampler: Sampler {
+                percent: u64::from(percent.min(100)),
+                count: Arc::default(),
+            },
+            client: Client::new(),
+        }
+    }
+}
+
+impl<S> Layer<S> for MirrorLayer {
+    type Service = MirrorMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MirrorMiddleware {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service created by [`MirrorLayer`].
+#[derive(Debug, Clone)]
+pub struct MirrorMiddleware<S> {
+    inner: S,
+    layer: MirrorLayer,
+}
+
+/// Request sent to the secondary.
+struct Mirrored {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+}
+
+impl MirrorLayer {
+    fn mirrored<B>(&self, req: &Request<B>) -> Option<Mirrored> {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) || !self.sampler.sample() {
+            return None;
+        }
+
+        let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+        let base = self.base_url.to_string();
+        let uri = format!("{}{path}", base.trim_end_matches('/'))
+            .parse::<Uri>()
+            .map_err(|e| {
+                event!(
+                  target: FRAMEWORK_TARGET,
+                  Level::WARN,
+                  "Can't mirror {path}: {e}"
+                )
+            })
+            .ok()?;
+
+        let mut headers = req.headers().clone();
+        for name in [HOST, AUTHORIZATION, COOKIE] {
+            headers.remove(name);
+        }
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+
+        Some(Mirrored {
+            method: req.method().clone(),
+            uri,
+            headers,
+        })
+    }
+}
+
+/// Send the mirrored request and compare its response with the primary.
+async fn compare(
+    client: Client<HttpConnector>,
+    mirrored: Mirrored,
+    status: StatusCode,
+    body: Option<Bytes>,
+) -> MirrorResult {
+    let mut builder = Request::builder().method(mirrored.method).uri(mirrored.uri);
+    if let Some(headers) = builder.headers_mut() {
+        *headers = mirrored.headers;
+    }
+    let req = match builder.body(Body::empty()) {
+        Ok(req) => req,
+        Err(_) => return MirrorResult::Error,
+    };
+
+    let response = match tokio::time::timeout(MIRROR_TIMEOUT, client.request(req)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            event!(target: FRAMEWORK_TARGET, Level::WARN, "Mirror request failed: {e}");
+            return MirrorResult::Error;
+        }
+        Err(_) => {
+            event!(target: FRAMEWORK_TARGET, Level::WARN, "Mirror request timed out");
+            return MirrorResult::Error;
+        }
+    };
+
+    if response.status() != status {
+        return MirrorResult::StatusMismatch;
+    }
+
+    match body {
+        None => MirrorResult::Match,
+        Some(expected) => match hyper::body::to_bytes(response.into_body()).await {
+            Ok(actual) if actual == expected => MirrorResult::Match,
+            Ok(_) => MirrorResult::BodyMismatch,
+            Err(_) => MirrorResult::Error,
+        },
+    }
+}
+
+impl<S, B> Service<Request<B>> for MirrorMiddleware<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let mirrored = self.layer.mirrored(&req);
+        let client = self.layer.client.clone();
+
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let res = inner.call(req).await?;
+
+            let mirrored = match mirrored {
+                Some(mirrored) => mirrored,
+                None => return Ok(res),
+            };
+
+            let status = res.status();
+            let (parts, body) = res.into_parts();
+
+            // Keep a copy of bodies already held in memory so they can be
+            // compared. Anything else is passed through untouched.
+            let (res, expected) = match body.size_hint().upper() {
+                Some(size) if size <= MAX_COMPARED_BODY_BYTES as u64 => {
+                    match hyper::body::to_bytes(body).await {
+                        Ok(bytes) => (
+                            Response::from_parts(parts, boxed(Full::from(bytes.clone()))),
+                            Some(bytes),
+                        ),
+                        Err(e) => {
+                            event!(
+                              target: FRAMEWORK_TARGET,
+                              Level::ERROR,
+                              "Failed to read response body for mirroring: {e}"
+                            );
+                            return Ok(Response::from_parts(
+                                parts,
+                                boxed(Full::from(Bytes::new())),
+                            ));
+                        }
+                    }
+                }
+                _ => (Response::from_parts(parts, body), None),
+            };
+
+            tokio::spawn(async move {
+                let path = mirrored.uri.path().to_owned();
+                let result = compare(client, mirrored, status, expected).await;
+                event!(
+                  target: FRAMEWORK_TARGET,
+                  Level::DEBUG,
+                  "Mirrored {path}: {}",
+                  result.as_str()
+                );
+                metrics::increment_counter!(MIRROR_METRIC, "result" => result.as_str());
+            });
+
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare, MirrorLayer, MirrorResult, Mirrored, Sampler};
+    use axum::{
+        body::Body,
+        http::{
+            header::{ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, COOKIE, HOST},
+            HeaderMap, Method, Request, StatusCode, Uri,
+        },
+        response::{IntoResponse, Response},
+    };
+    use hyper::{
+        body::Bytes,
+        service::{make_service_fn, service_fn},
+        Client, Server,
+    };
+    use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+    use tokio::sync::mpsc;
+    use tower::{Layer, ServiceExt};
+
+    /// Start a secondary answering every request with `body`, sending the
+    /// path of each request it receives to the returned channel.
+    fn secondary(body: &'static str) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    tx.send(req.uri().to_string()).unwrap();
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, rx)
+    }
+
+    fn mirrored(addr: SocketAddr) -> Mirrored {
+        Mirrored {
+            method: Method::GET,
+            uri: format!("http://{addr}/api/v1/user/1").parse().unwrap(),
+            headers: HeaderMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_mirrored_headers() {
+        let layer = MirrorLayer::new("http://secondary".parse::<Uri>().unwrap(), 100);
+        let req = Request::builder()
+            .uri("/api/v1/user/1")
+            .header(HOST, "primary")
+            .header(AUTHORIZATION, "Bearer token")
+            .header(COOKIE, "session=1")
+            .header(ACCEPT_ENCODING, "gzip")
+            .header(ACCEPT, "application/json")
+            .body(())
+            .unwrap();
+
+        let headers = layer.mirrored(&req).unwrap().headers;
+        assert_eq!(headers.get(ACCEPT_ENCODING).unwrap(), "identity");
+        assert_eq!(headers.get(ACCEPT).unwrap(), "application/json");
+        assert!([HOST, AUTHORIZATION, COOKIE]
+            .iter()
+            .all(|name| !headers.contains_key(name)));
+    }
+
+    #[test]
+    fn test_sampler() {
+        let sampler = Sampler {
+            percent: 25,
+            count: Arc::default(),
+        };
+        assert_eq!((0..100).filter(|_| sampler.sample()).count(), 25);
+    }
+
+    #[tokio::test]
+    async fn test_compare() {
+        let (addr, _rx) = secondary("user");
+        let client = Client::new();
+        let ok = StatusCode::OK;
+
+        assert_eq!(
+            compare(
+                client.clone(),
+                mirrored(addr),
+                ok,
+                Some(Bytes::from("user"))
+            )
+            .await,
+            MirrorResult::Match
+        );
+        assert_eq!(
+            compare(
+                client.clone(),
+                mirrored(addr),
+                ok,
+                Some(Bytes::from("other"))
+            )
+            .await,
+            MirrorResult::BodyMismatch
+        );
+        assert_eq!(
+            compare(client.clone(), mirrored(addr), StatusCode::NOT_FOUND, None).await,
+            MirrorResult::StatusMismatch
+        );
+        assert_eq!(
+            compare(client, mirrored(addr), ok, None).await,
+            MirrorResult::Match
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mirror_read_only() {
+        let (addr, mut rx) = secondary("user");
+        let layer = MirrorLayer::new(format!("http://{addr}").parse::<Uri>().unwrap(), 100);
+        let service = layer.layer(tower::service_fn(|_req: Request<Body>| async {
+            Ok::<_, Infallible>("user".into_response())
+        }));
+
+        let response = service
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/api/v1/user")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = service
+            .oneshot(
+                Request::builder()
+                    .uri("/api/v1/user/1?q=1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "user"
+        );
+
+        // Only the GET request is mirrored.
+        assert_eq!(rx.recv().await.unwrap(), "/api/v1/user/1?q=1");
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -2,18 +2,112 @@
 API server middleware.
 */
 
+use crate::{types::handler::error_envelope, FRAMEWORK_TARGET};
+use axum::{
+    body::{boxed, Full},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use clap::ValueEnum;
 use http::Request;
+use http_body::{Body as _, Limited};
+use serde_json::Value;
 use tower_http::request_id::{MakeRequestId, RequestId};
+use tracing::{event, Level};
+use user_persist::types::next_ulid;
 use uuid::Uuid;
 
-// pub mod hashing;
+pub mod audit;
+pub mod body_timeout;
+pub mod contract;
+pub mod fields;
+pub mod hashing;
+pub mod json_api;
+pub mod metrics;
+pub mod mirror;
+pub mod panic;
+pub mod rate_limit;
 pub mod request_trace;
+pub mod signing;
 
+/// Format of generated request ids.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RequestIdFormat {
+    /// Random UUID.
+    #[default]
+    Uuid4,
+    /// Time ordered UUID.
+    Uuid7,
+    /// Time ordered ULID.
+    Ulid,
+}
+
+/// Generates request ids in the configured format. Time ordered ids sort
+/// in the order requests were received which helps when correlating logs.
 #[derive(Clone, Copy)]
-pub struct MakeRequestUuid;
+pub struct MakeRequestUuid(pub RequestIdFormat);
 
 impl MakeRequestId for MakeRequestUuid {
     fn make_request_id<B>(&mut self, _request: &Request<B>) -> Option<RequestId> {
-        Uuid::new_v4().to_string().parse().map(RequestId::new).ok()
+        let id = match self.0 {
+            RequestIdFormat::Uuid4 => Uuid::new_v4().to_string(),
+            RequestIdFormat::Uuid7 => Uuid::now_v7().to_string(),
+            RequestIdFormat::Ulid => next_ulid().to_string(),
+        };
+        id.parse().map(RequestId::new).ok()
+    }
+}
+
+/// Replace the body of a successful JSON response with the value `f`
+/// makes of it, typed as `content_type`. Errors, streamed bodies, other
+/// content types and bodies `f` declines are passed through unchanged.
+pub(crate) async fn map_json_body(
+    response: Response,
+    req_id: Option<&RequestId>,
+    content_type: &'static str,
+    f: impl FnOnce(Value) -> Option<Value>,
+) -> Response {
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !response.status().is_success() || !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    if body.size_hint().upper().is_none() {
+        return Response::from_parts(parts, body);
+    }
+    let bytes =
+        match hyper::body::to_bytes(Limited::new(body, hashing::MAX_HASHED_BODY_BYTES)).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                let message = format!("Failed to read response body: {e}");
+                event!(target: FRAMEWORK_TARGET, Level::ERROR, "{message}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    error_envelope("internal.error", message, req_id),
+                )
+                    .into_response();
+            }
+        };
+
+    let mapped = serde_json::from_slice(&bytes)
+        .ok()
+        .and_then(f)
+        .and_then(|value| serde_json::to_vec(&value).ok());
+    match mapped {
+        Some(mapped) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            Response::from_parts(parts, boxed(Full::from(mapped)))
+        }
+        None => Response::from_parts(parts, boxed(Full::from(bytes))),
     }
 }
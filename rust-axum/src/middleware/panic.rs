@@ -0,0 +1,67 @@
+/*!
+Convert handler panics into a JSON 500 response.
+*/
+use crate::{types::handler::error_envelope, FRAMEWORK_TARGET};
+use axum::{
+    body::Body,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::FutureExt;
+use http::{Request, StatusCode};
+use std::{any::Any, panic::AssertUnwindSafe};
+use tower_http::request_id::RequestId;
+use tracing::{event, Level};
+
+/// Middleware answering a panic of the services it wraps with a JSON 500
+/// carrying the request id.
+pub async fn catch_panic(request: Request<Body>, next: Next<Body>) -> Response {
+    let req_id = request.extensions().get::<RequestId>().cloned();
+    match AssertUnwindSafe(next.run(request)).catch_unwind().await {
+        Ok(response) => response,
+        Err(err) => handle_panic(err, req_id.as_ref()),
+    }
+}
+
+/// Response for a panic of the request with id `req_id`.
+pub fn handle_panic(err: Box<dyn Any + Send + 'static>, req_id: Option<&RequestId>) -> Response {
+    let details = err
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| err.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned());
+
+    event!(
+      target: FRAMEWORK_TARGET,
+      Level::ERROR,
+      "Handler panicked: {details}"
+    );
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        error_envelope("internal.error", "Internal server error", req_id),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::handle_panic;
+    use http::{HeaderValue, StatusCode};
+    use tower_http::request_id::RequestId;
+
+    #[test]
+    fn test_handle_panic() {
+        let response = handle_panic(Box::new("boom"), None);
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_panic_request_id() {
+        let req_id = RequestId::new(HeaderValue::from_static("panicking-request"));
+        let response = handle_panic(Box::new("boom"), Some(&req_id));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let envelope = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(envelope["requestId"], "panicking-request");
+    }
+}
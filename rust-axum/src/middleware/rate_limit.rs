@@ -0,0 +1,149 @@
+/*!
+Advisory rate limit headers.
+
+Responses to authenticated requests carry the `RateLimit-Limit`,
+`RateLimit-Remaining` and `RateLimit-Reset` headers of the IETF rate limit
+fields draft so clients can slow down before they are throttled. Requests
+are counted by token subject in fixed windows kept in the [`KvStore`] of
+the service, so replicas sharing a store report the same budget. The
+headers are advisory: requests over the limit are still served.
+*/
+use crate::{arguments::RateLimitSettings, AppConfig, JWTClaims, FRAMEWORK_TARGET};
+use axum::{
+    extract::State,
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use tracing::{event, Level};
+use user_persist::{kv::KvStore, persistence::PersistenceResult};
+
+pub const RATE_LIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RATE_LIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Attempts to increment a counter raced by other requests before the
+/// last count read is reported.
+const MAX_ATTEMPTS: usize = 5;
+
+/// Budget of a subject in the current window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitState {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the window ends.
+    pub reset: u64,
+}
+
+/// Count a request of `subject` at `now` in seconds since the unix epoch.
+pub async fn consume(
+    kv: &dyn KvStore,
+    settings: RateLimitSettings,
+    subject: &str,
+    now: i64,
+) -> PersistenceResult<RateLimitState> {
+    let window = i64::try_from(settings.window.as_secs())
+        .unwrap_or(i64::MAX)
+        .max(1);
+    let start = now - now.rem_euclid(window);
+    let key = format!("rate-limit:{subject}:{start}");
+    let ttl = chrono::Duration::seconds(window);
+
+    let mut count = 0;
+    for _ in 0..MAX_ATTEMPTS {
+        let current = kv.get(&key).await?;
+        count = current
+            .as_deref()
+            .and_then(|count| count.parse::<u64>().ok())
+            .unwrap_or_default()
+            + 1;
+        if kv
+            .compare_and_set(&key, current.as_deref(), &count.to_string(), Some(ttl))
+            .await?
+        {
+            break;
+        }
+    }
+    Ok(RateLimitState {
+        limit: settings.requests,
+        remaining: settings.requests.saturating_sub(count),
+        reset: u64::try_from(start + window - now).unwrap_or_default(),
+    })
+}
+
+/// Add the rate limit headers to responses of requests with a valid token.
+pub async fn rate_limit_headers<B>(
+    State(config): State<Arc<AppConfig>>,
+    State(kv): State<Arc<dyn KvStore>>,
+    claims: Option<JWTClaims>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let settings = config.settings().rate_limit;
+    let Some(claims) = claims.filter(|_| settings.requests > 0) else {
+        return next.run(request).await;
+    };
+
+    let now = config.clock().now().timestamp();
+    let state = consume(kv.as_ref(), settings, &claims.sub, now).await;
+    let mut response = next.run(request).await;
+    match state {
+        Ok(state) => {
+            let headers = response.headers_mut();
+            headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(state.limit));
+            headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from(state.remaining));
+            headers.insert(RATE_LIMIT_RESET, HeaderValue::from(state.reset));
+        }
+        Err(e) => event!(
+          target: FRAMEWORK_TARGET,
+          Level::WARN,
+          "Rate limit of {} not counted: {e}",
+          claims.sub
+        ),
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::{consume, RateLimitState};
+    use crate::arguments::RateLimitSettings;
+    use std::time::Duration;
+    use user_persist::kv::MemoryKvStore;
+
+    #[tokio::test]
+    async fn test_fixed_window() {
+        let kv = MemoryKvStore::default();
+        let settings = RateLimitSettings {
+            requests: 2,
+            window: Duration::from_secs(60),
+        };
+        let state = |remaining, reset| RateLimitState {
+            limit: 2,
+            remaining,
+            reset,
+        };
+
+        assert_eq!(
+            consume(&kv, settings, "droberts", 1_200).await.unwrap(),
+            state(1, 60)
+        );
+        assert_eq!(
+            consume(&kv, settings, "droberts", 1_230).await.unwrap(),
+            state(0, 30)
+        );
+        assert_eq!(
+            consume(&kv, settings, "droberts", 1_259).await.unwrap(),
+            state(0, 1)
+        );
+        assert_eq!(
+            consume(&kv, settings, "other", 1_259).await.unwrap(),
+            state(1, 1)
+        );
+        assert_eq!(
+            consume(&kv, settings, "droberts", 1_260).await.unwrap(),
+            state(1, 60)
+        );
+    }
+}
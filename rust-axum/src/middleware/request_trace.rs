@@ -1,4 +1,4 @@
-use crate::USER_MS_TARGET;
+use crate::{types::jwt::JWTClaims, USER_MS_TARGET};
 use http::{header::HOST, Request, Response};
 use std::{fmt::Display, time::Duration};
 use tower_http::{
@@ -10,7 +10,8 @@ use tracing::{field, Span};
 #[derive(Clone, Debug)]
 pub struct RequestLogger;
 
-/// Each request span will have a requestId, uri and method.
+/// Each request span will have a requestId, uri and method. The subject
+/// and role are recorded once a token has been verified.
 impl<B> MakeSpan<B> for RequestLogger {
     fn make_span(&mut self, request: &Request<B>) -> Span {
         let req_id = request
@@ -32,14 +33,24 @@ impl<B> MakeSpan<B> for RequestLogger {
           "method" = request.method().as_str(),
           "statusCode" = field::Empty,
           "failureClass" = field::Empty,
+          "sub" = field::Empty,
+          "role" = field::Empty,
           %host
         )
     }
 }
 
+/// Record the principal of verified `claims` on the current request span
+/// so every later event of the request is attributable to it.
+pub fn record_principal(claims: &JWTClaims) {
+    let span = Span::current();
+    span.record("sub", claims.sub.as_str());
+    span.record("role", field::display(&claims.role));
+}
+
 impl<C: Display> OnFailure<C> for RequestLogger {
     fn on_failure(&mut self, failure_classification: C, latency: Duration, span: &Span) {
-        span.record("failureClass", &field::display(&failure_classification));
+        span.record("failureClass", field::display(&failure_classification));
         tracing::error!(
             "request failed with {failure_classification} in {} ms",
             latency.as_millis()
@@ -59,7 +70,7 @@ impl<B> OnRequest<B> for RequestLogger {
 
 impl<B> OnResponse<B> for RequestLogger {
     fn on_response(self, response: &Response<B>, latency: Duration, span: &Span) {
-        span.record("statusCode", &field::display(response.status().as_str()));
+        span.record("statusCode", field::display(response.status().as_str()));
         tracing::info!(
             "response completed with status {} in {} minutes {} seconds {} ms",
             response.status(),
@@ -0,0 +1,146 @@
+/*!
+Middleware signing response bodies and verifying signed request bodies.
+
+The response signature covers the body after every other middleware
+changed it except compression, so clients verify the body they read after
+decoding it. Streamed bodies have no upper bound and are sent without a
+signature, as are bodies larger than the hashing limit.
+
+`POST` and `PUT` requests of registered clients are verified before any
+handler parses their body. When signatures are required, requests that
+don't name a client are rejected rather than passed through.
+*/
+use crate::{
+    middleware::{body_timeout::read_body, hashing::MAX_HASHED_BODY_BYTES},
+    security::signing::{
+        request_payload, ResponseSigner, SignatureError, CLIENT_ID_HEADER, REQUEST_TOLERANCE_SECS,
+        SIGNATURE_HEADER, SIGNATURE_TIMESTAMP_HEADER,
+    },
+    types::handler::error_envelope,
+    AppConfig, FRAMEWORK_TARGET,
+};
+use axum::{
+    body::{boxed, Body, Full},
+    extract::State,
+    http::{HeaderName, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body::{Body as _, Limited};
+use std::sync::Arc;
+use tower_http::request_id::RequestId;
+use tracing::{event, Level};
+
+/// Sign the body of the response to `request` with `signer`.
+pub async fn sign_response<B>(
+    State(signer): State<Arc<ResponseSigner>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let req_id = request.extensions().get::<RequestId>().cloned();
+    let response = next.run(request).await;
+    let (mut parts, body) = response.into_parts();
+    if body
+        .size_hint()
+        .upper()
+        .is_none_or(|size| size > MAX_HASHED_BODY_BYTES as u64)
+    {
+        event!(target: FRAMEWORK_TARGET, Level::DEBUG, "Not signing unbounded response");
+        return Response::from_parts(parts, body);
+    }
+
+    match hyper::body::to_bytes(Limited::new(body, MAX_HASHED_BODY_BYTES)).await {
+        Ok(bytes) => {
+            if let Ok(signature) = HeaderValue::from_str(&signer.sign(&bytes)) {
+                parts
+                    .headers
+                    .insert(HeaderName::from_static(SIGNATURE_HEADER), signature);
+            }
+            Response::from_parts(parts, boxed(Full::from(bytes)))
+        }
+        Err(e) => {
+            let message = format!("Failed to read response body for signing: {e}");
+            event!(target: FRAMEWORK_TARGET, Level::ERROR, "{message}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                error_envelope("internal.error", message, req_id.as_ref()),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Verify the signature of a `POST` or `PUT` request naming a registered
+/// client, answering `401 Unauthorized` when it doesn't match or was made
+/// outside the tolerance. Requests not naming a client are passed through
+/// unless signatures are required.
+pub async fn verify_request(
+    State(config): State<Arc<AppConfig>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(clients) = config.signed_clients() else {
+        return next.run(request).await;
+    };
+    if !matches!(*request.method(), Method::POST | Method::PUT) {
+        return next.run(request).await;
+    }
+    let Some(client) = request.headers().get(CLIENT_ID_HEADER) else {
+        if clients.required() {
+            return SignatureError::MissingClient.into_response();
+        }
+        return next.run(request).await;
+    };
+    let client = client.to_str().unwrap_or_default().to_owned();
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let signature = header(SIGNATURE_HEADER);
+    let Some(timestamp) = header(SIGNATURE_TIMESTAMP_HEADER).and_then(|t| t.parse::<i64>().ok())
+    else {
+        return SignatureError::Timestamp.into_response();
+    };
+    let age = config.clock().now().timestamp() - timestamp;
+    if age.abs() > REQUEST_TOLERANCE_SECS {
+        return SignatureError::Expired(age).into_response();
+    }
+
+    // Unknown clients and unsigned requests are rejected before the body
+    // is read, and the body is read under the read timeouts of its route as
+    // no route layer has guarded it yet.
+    if let Err(e) = clients.check_client(&client, signature.as_deref()) {
+        return e.into_response();
+    }
+    let (parts, body) = request.into_parts();
+    let settings = config.settings();
+    let bytes = match read_body(
+        body,
+        settings.body_read.timeouts(parts.uri.path()),
+        settings.limits.body_limit,
+        parts.extensions.get::<RequestId>(),
+    )
+    .await
+    {
+        Ok(bytes) => bytes,
+        Err(response) => return response,
+    };
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or_else(|| parts.uri.path(), |path| path.as_str());
+    let payload = request_payload(parts.method.as_str(), path, timestamp, &bytes);
+    if let Err(e) = clients.verify(&client, &payload, signature.as_deref()) {
+        event!(
+          target: FRAMEWORK_TARGET,
+          Level::WARN,
+          "Rejected request of client {client}: {e}"
+        );
+        return e.into_response();
+    }
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
@@ -0,0 +1,195 @@
+/*!
+OpenAPI document of the user endpoints.
+
+Request and response types derive their schemas and the handlers of
+`user_handlers` are annotated with their route, parameters and responses,
+so the document served at `/api/v1/openapi.json` follows the code.
+
+The security of each operation is read from the guard extractors of its
+handler rather than annotated: operations whose handler takes `AdminAccess`
+or `UserAccess` require a bearer token of that role, those taking
+`JWTClaims` a token of any role. The route policy of the deployment
+replaces the role of the routes it lists, as it does when guarding them.
+*/
+use crate::{
+    handlers::user_handlers,
+    security::{
+        access::{access_of, Access},
+        hashing::HashedUser,
+    },
+};
+use api_types::{CreateUserRequest, SearchRequest, UserResponse};
+use bootstrap::route_policy::RoutePolicy;
+use errors::{AuthError, ErrorEnvelope};
+use std::collections::HashMap;
+use user_persist::types::{
+    Address, BulkUpdate, BulkUpdateResult, Email, Gender, PartialUpdateUser, Phone, SearchSort,
+    UpdateUser, User, UserKey, UserSearch,
+};
+use utoipa::{
+    openapi::{
+        path::{Operation, PathItemType},
+        security::{HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme},
+        ContentBuilder, Ref, ResponseBuilder,
+    },
+    OpenApi,
+};
+
+/// Name of the bearer token security scheme.
+pub const BEARER_SCHEME: &str = "bearer";
+
+/// Documents the handlers with the access their extractors require, so
+/// the paths and their security come from one list.
+macro_rules! api_doc {
+    ($($handler:ident),* $(,)?) => {
+        /// OpenAPI document of the service, without security.
+        #[derive(OpenApi)]
+        #[openapi(
+            paths($(user_handlers::$handler),*),
+            components(schemas(
+                Address,
+                AuthError,
+                BulkUpdate,
+                BulkUpdateResult,
+                CreateUserRequest,
+                Email,
+                ErrorEnvelope,
+                Gender,
+                HashedUser,
+                PartialUpdateUser,
+                Phone,
+                SearchRequest,
+                SearchSort,
+                UpdateUser,
+                User,
+                UserKey,
+                UserResponse,
+                UserSearch,
+            )),
+            tags((name = "users", description = "User records"))
+        )]
+        pub struct ApiDoc;
+
+        /// Access required by the handler of each operation id.
+        fn handler_access() -> HashMap<&'static str, Access> {
+            HashMap::from([$((stringify!($handler), access_of(&user_handlers::$handler))),*])
+        }
+    };
+}
+
+api_doc!(
+    get_user,
+    get_user_by_email,
+    query_users,
+    save_user,
+    update_user,
+    bulk_update_users,
+    search_users,
+    aggregate_users,
+    count_users,
+    count_users_history,
+    age_histogram,
+    sample_users,
+    import_users,
+    download_users,
+    delete_user,
+);
+
+/// OpenAPI document with the security each operation requires under
+/// `policy`.
+pub fn document(policy: &RoutePolicy) -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    openapi
+        .components
+        .get_or_insert_with(Default::default)
+        .add_security_scheme(
+            BEARER_SCHEME,
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+
+    let access = handler_access();
+    for (path, item) in openapi.paths.paths.iter_mut() {
+        for (method, operation) in item.operations.iter_mut() {
+            let required = operation
+                .operation_id
+                .as_deref()
+                .and_then(|id| access.get(id));
+            match required {
+                None | Some(Access::Public) => (),
+                Some(Access::Bearer) => secure(operation, Vec::new()),
+                Some(Access::Role(role)) => {
+                    let roles = policy
+                        .roles(method_name(method), path)
+                        .map(|roles| roles.iter().cloned().collect())
+                        .unwrap_or_else(|| vec![role.to_string()]);
+                    describe_roles(operation, &roles);
+                    secure(operation, roles);
+                    add_error(operation, "403", "The role of the token isn't allowed");
+                }
+            }
+        }
+    }
+    openapi
+}
+
+/// Require a bearer token with one of `roles` on `operation`, any role
+/// when there are none.
+fn secure(operation: &mut Operation, roles: Vec<String>) {
+    operation.security = Some(vec![SecurityRequirement::new(BEARER_SCHEME, roles)]);
+    add_error(operation, "401", "Missing, invalid or expired token");
+}
+
+/// Note the roles allowed on `operation` in its description, as the
+/// roles of a bearer scheme are only advisory in OpenAPI 3.0.
+fn describe_roles(operation: &mut Operation, roles: &[String]) {
+    let note = match roles {
+        [] => "No role may call this route.".to_owned(),
+        [role] => format!("Requires the `{role}` role."),
+        roles => format!("Requires one of the roles `{}`.", roles.join("`, `")),
+    };
+    operation.description = Some(match operation.description.take() {
+        Some(description) if !description.is_empty() => format!("{description}\n\n{note}"),
+        _ => note,
+    });
+}
+
+/// Document an error response of `status` unless the handler already
+/// does.
+fn add_error(operation: &mut Operation, status: &str, description: &str) {
+    operation
+        .responses
+        .responses
+        .entry(status.to_owned())
+        .or_insert_with(|| {
+            ResponseBuilder::new()
+                .description(description)
+                .content(
+                    "application/json",
+                    ContentBuilder::new()
+                        .schema(Ref::from_schema_name("ErrorEnvelope"))
+                        .build(),
+                )
+                .build()
+                .into()
+        });
+}
+
+/// Method of an operation as matched by the route policy.
+fn method_name(method: &PathItemType) -> &'static str {
+    match method {
+        PathItemType::Get => "GET",
+        PathItemType::Post => "POST",
+        PathItemType::Put => "PUT",
+        PathItemType::Delete => "DELETE",
+        PathItemType::Options => "OPTIONS",
+        PathItemType::Head => "HEAD",
+        PathItemType::Patch => "PATCH",
+        PathItemType::Trace => "TRACE",
+        PathItemType::Connect => "CONNECT",
+    }
+}
@@ -0,0 +1,125 @@
+/*!
+Access required by handlers, read from the types of their extractors.
+
+Each extractor a handler takes declares the access it enforces with
+[`Extractor`]: the `AdminAccess` and `UserAccess` guards require a bearer
+token of their role, `JWTClaims` a token of any role and the other
+extractors nothing. [`access_of`] folds the extractors of a handler into
+the [`Access`] it requires, so the OpenAPI document advertises the guards
+the handlers actually run. A handler taking an extractor that doesn't
+declare its access doesn't compile until it does.
+*/
+use crate::{
+    extractors::{hashing::HashedValidatingJson, validator::ValidatingJson},
+    security::hashing::HashValidating,
+    types::jwt::{AdminAccess, JWTClaims, Role, UserAccess},
+};
+use axum::{
+    body::Bytes,
+    extract::{Json, Path, Query, State},
+};
+use http::{HeaderMap, Request};
+use user_persist::Validate;
+
+/// Access a handler requires.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// No token.
+    Public,
+    /// A bearer token of any role.
+    Bearer,
+    /// A bearer token of the role, unless the route policy allows others.
+    Role(Role),
+}
+
+/// Extractor declaring the access it enforces.
+pub trait Extractor {
+    /// Access enforced by the extractor, none when it doesn't check tokens.
+    fn access() -> Option<Access> {
+        None
+    }
+}
+
+impl Extractor for AdminAccess {
+    fn access() -> Option<Access> {
+        Some(Access::Role(Role::Admin))
+    }
+}
+
+impl Extractor for UserAccess {
+    fn access() -> Option<Access> {
+        Some(Access::Role(Role::User))
+    }
+}
+
+impl Extractor for JWTClaims {
+    fn access() -> Option<Access> {
+        Some(Access::Bearer)
+    }
+}
+
+impl<T> Extractor for State<T> {}
+impl<T> Extractor for Path<T> {}
+impl<T> Extractor for Query<T> {}
+impl<T> Extractor for Json<T> {}
+impl<T: Validate> Extractor for ValidatingJson<T> {}
+impl<T: Validate + HashValidating> Extractor for HashedValidatingJson<T> {}
+impl<B> Extractor for Request<B> {}
+impl Extractor for HeaderMap {}
+impl Extractor for Bytes {}
+
+/// Handler whose extractors `Args` declare their access.
+pub trait GuardedHandler<Args> {
+    /// Access required by the first guard of the handler, public without
+    /// one.
+    fn access() -> Access;
+}
+
+macro_rules! guarded_handler {
+    ($($arg:ident),*) => {
+        impl<F, Fut, $($arg),*> GuardedHandler<($($arg,)*)> for F
+        where
+            F: FnOnce($($arg),*) -> Fut,
+            $($arg: Extractor),*
+        {
+            fn access() -> Access {
+                None$(.or_else($arg::access))*.unwrap_or(Access::Public)
+            }
+        }
+    };
+}
+
+guarded_handler!();
+guarded_handler!(T1);
+guarded_handler!(T1, T2);
+guarded_handler!(T1, T2, T3);
+guarded_handler!(T1, T2, T3, T4);
+guarded_handler!(T1, T2, T3, T4, T5);
+guarded_handler!(T1, T2, T3, T4, T5, T6);
+guarded_handler!(T1, T2, T3, T4, T5, T6, T7);
+guarded_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
+
+/// Access required by `handler`.
+pub fn access_of<H: GuardedHandler<Args>, Args>(_handler: &H) -> Access {
+    H::access()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{access_of, Access};
+    use crate::types::jwt::{AdminAccess, JWTClaims, Role, UserAccess};
+    use axum::extract::{Path, Query};
+
+    #[test]
+    fn test_access_of() {
+        async fn public(_: Query<()>) {}
+        async fn bearer(_: Path<String>, _: JWTClaims) {}
+        async fn admin(_: Path<String>, _: AdminAccess, _: Query<()>) {}
+        async fn user(_: UserAccess) {}
+
+        assert_eq!(access_of(&public), Access::Public);
+        assert_eq!(access_of(&bearer), Access::Bearer);
+        assert_eq!(access_of(&admin), Access::Role(Role::Admin));
+        assert_eq!(access_of(&user), Access::Role(Role::User));
+    }
+}
@@ -0,0 +1,154 @@
+/*!
+Signed cursors continuing a paged search.
+
+A page of search results carries a cursor naming the last user it holds
+so the next page starts right after that user, even when users were
+added or removed in between. Clients treat the cursor as opaque. It is
+signed with the HMAC of the JWT secret so it can't be forged or edited,
+and it records a hash of the search criteria so a cursor issued for one
+search can't be replayed with a wider one. Cursors expire after the
+configured time.
+*/
+use crate::AppConfig;
+use chrono::Duration;
+use jsonwebtoken::{decode, encode, Header, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use user_persist::types::{SearchSort, UserKey, UserSearch};
+
+const PURPOSE: &str = "search.cursor";
+
+/// Rejected search cursor.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CursorError {
+    #[error("cursor is invalid")]
+    Invalid,
+    #[error("cursor has expired")]
+    Expired,
+    #[error("cursor was issued for another search")]
+    Search,
+}
+
+/// Position of a page following the one a cursor was issued for.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Cursor {
+    /// Last user of the previous page.
+    pub last_id: UserKey,
+    pub sort: SearchSort,
+    /// Position of the page among all the matches.
+    pub offset: u64,
+    filter_hash: String,
+    exp: i64,
+    purpose: String,
+}
+
+/// Hash of the criteria of `search`, without its order.
+fn filter_hash(search: &UserSearch) -> String {
+    let criteria = UserSearch {
+        sort: SearchSort::default(),
+        ..search.clone()
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&criteria).unwrap_or_default());
+    base64::encode(hasher.finalize())
+}
+
+/// Issue a cursor for the page of `search` at `offset` following the
+/// user `last_id`, valid for `ttl`.
+pub fn issue(
+    config: &AppConfig,
+    search: &UserSearch,
+    last_id: UserKey,
+    offset: u64,
+    ttl: Duration,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let cursor = Cursor {
+        last_id,
+        sort: search.sort,
+        offset,
+        filter_hash: filter_hash(search),
+        exp: (config.clock().now() + ttl).timestamp(),
+        purpose: PURPOSE.to_owned(),
+    };
+    encode(&Header::default(), &cursor, config.jwt_encoding_key())
+}
+
+/// Check `cursor` was issued for `search` and hasn't expired.
+pub fn verify(
+    config: &AppConfig,
+    cursor: &str,
+    search: &UserSearch,
+) -> Result<Cursor, CursorError> {
+    // Expiry is checked against the configured clock.
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+
+    let cursor = decode::<Cursor>(cursor, config.jwt_decoding_key(), &validation)
+        .map_err(|_| CursorError::Invalid)?
+        .claims;
+    if cursor.purpose != PURPOSE {
+        Err(CursorError::Invalid)
+    } else if cursor.exp < config.clock().now().timestamp() {
+        Err(CursorError::Expired)
+    } else if cursor.sort != search.sort || cursor.filter_hash != filter_hash(search) {
+        Err(CursorError::Search)
+    } else {
+        Ok(cursor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{issue, verify, CursorError};
+    use crate::{security::resume, AppConfig};
+    use chrono::{Duration, Utc};
+    use std::sync::Arc;
+    use user_persist::{
+        clock::MockClock,
+        types::{Gender, SearchSort, UserKey, UserSearch},
+    };
+
+    #[test]
+    fn test_cursor() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let config = AppConfig::test(b"secret").with_clock(clock.clone());
+        let search = UserSearch {
+            gender: Some(Gender::Female),
+            ..UserSearch::default()
+        };
+        let last_id = "61c0d1954c6b974ca7000000".parse::<UserKey>().unwrap();
+        let cursor = issue(&config, &search, last_id.clone(), 20, Duration::hours(1)).unwrap();
+
+        let verified = verify(&config, &cursor, &search).unwrap();
+        assert_eq!((verified.last_id, verified.offset), (last_id, 20));
+
+        // A cursor can't widen or reorder the search it was issued for.
+        assert_eq!(
+            verify(&config, &cursor, &UserSearch::default()),
+            Err(CursorError::Search)
+        );
+        let by_id = UserSearch {
+            sort: SearchSort::Id,
+            ..search.clone()
+        };
+        assert_eq!(verify(&config, &cursor, &by_id), Err(CursorError::Search));
+
+        let mut tampered = cursor.clone();
+        tampered.insert(cursor.len() / 2, 'x');
+        assert_eq!(
+            verify(&config, &tampered, &search),
+            Err(CursorError::Invalid)
+        );
+        assert_eq!(
+            verify(&AppConfig::test(b"other"), &cursor, &search),
+            Err(CursorError::Invalid)
+        );
+        let resume = resume::issue(&config, "droberts", Duration::hours(1)).unwrap();
+        assert_eq!(verify(&config, &resume, &search), Err(CursorError::Invalid));
+
+        clock.advance(Duration::hours(1) + Duration::seconds(1));
+        assert_eq!(verify(&config, &cursor, &search), Err(CursorError::Expired));
+    }
+}
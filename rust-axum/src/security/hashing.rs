@@ -1,20 +1,19 @@
 /*!
 Provides hashing capabilities for API validation.
 */
-use crate::AppConfig;
+use api_types::UserResponse;
 use axum::response::{IntoResponse, Json, Response};
-use http::StatusCode;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::fmt::Display;
 use std::fmt::Formatter;
-use std::{fmt::Display, sync::Arc};
 use tracing::debug;
-use user_persist::types::{UpdateUser, User};
-use user_persist::{Validate, ValidationErrors};
+use user_persist::types::{SearchPage, UpdateUser, User};
+use utoipa::ToSchema;
 
 /// A type that can be converted into a hash.
 pub trait Hashable {
-    type Hashed: Serialize + IntoResponse;
+    type Hashed: Serialize;
     fn hash(&self, hash_prefix: &str) -> Self::Hashed;
 }
 
@@ -31,16 +30,20 @@ fn hash_value(value: &str) -> String {
     base64::encode(hasher.finalize())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// User with the hash validating its updates.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct HashedUser {
     #[serde(flatten)]
-    pub user: User,
-    pub hid: String,
+    pub user: UserResponse,
+    /// Also accepted as `hid` until the next release.
+    #[serde(alias = "hid")]
+    pub hash_id: String,
 }
 
 impl Display for HashedUser {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "hid: {}, {}", self.hid, self.user)
+        write!(f, "hashId: {}, {:?}", self.hash_id, self.user)
     }
 }
 
@@ -56,13 +59,7 @@ impl HashValidating for HashedUser {
             "{hash_prefix}{}{}",
             self.user.name, self.user.email
         ));
-        new_hash == self.hid
-    }
-}
-
-impl Validate for HashedUser {
-    fn validate(&self) -> Result<(), ValidationErrors> {
-        self.user.validate()
+        new_hash == self.hash_id
     }
 }
 
@@ -70,25 +67,33 @@ impl HashValidating for UpdateUser {
     fn is_valid(&self, hash_prefix: &str) -> bool {
         let new_hash = hash_value(&format!("{hash_prefix}{}{}", self.name, self.email.0));
         debug!(target: super::HASHING_TARGET, "computed hash: {new_hash}");
-        new_hash == self.hid
+        new_hash == self.hash_id
     }
 }
 
-impl Hashable for User {
+impl Hashable for UserResponse {
     type Hashed = HashedUser;
 
     fn hash(&self, hash_prefix: &str) -> Self::Hashed {
         HashedUser {
             user: self.clone(),
-            hid: hash_value(&format!("{hash_prefix}{}{}", self.name, self.email.0)),
+            hash_id: hash_value(&format!("{hash_prefix}{}{}", self.name, self.email.0)),
         }
     }
 }
 
+/// Users are hashed in their response form.
+impl Hashable for User {
+    type Hashed = HashedUser;
+
+    fn hash(&self, hash_prefix: &str) -> Self::Hashed {
+        UserResponse::from(self.clone()).hash(hash_prefix)
+    }
+}
+
 impl<T> Hashable for Vec<T>
 where
     T: Hashable,
-    Vec<<T as Hashable>::Hashed>: IntoResponse,
 {
     type Hashed = Vec<T::Hashed>;
     fn hash(&self, hash_prefix: &str) -> Self::Hashed {
@@ -96,51 +101,49 @@ where
     }
 }
 
-// Alternative to middleware
-pub struct HashingResponse<T: Hashable> {
-    payload: T,
-    config: Arc<AppConfig>,
-}
-
-impl<T: Hashable> HashingResponse<T> {
-    pub fn new(config: Arc<AppConfig>, payload: T) -> Self {
-        Self { payload, config }
-    }
-}
-
-impl<T: Hashable> IntoResponse for HashingResponse<T> {
-    fn into_response(self) -> Response {
-        let hashed = self.payload.hash(self.config.hash_prefix());
-        hashed.into_response()
+impl<T> Hashable for SearchPage<T>
+where
+    T: Hashable,
+{
+    type Hashed = SearchPage<T::Hashed>;
+    fn hash(&self, hash_prefix: &str) -> Self::Hashed {
+        SearchPage {
+            items: self.items.hash(hash_prefix),
+            total: self.total,
+            estimated: self.estimated,
+            offset: self.offset,
+            limit: self.limit,
+            next_cursor: self.next_cursor.clone(),
+        }
     }
 }
 
-/// Newtype to implement IntoResponse trait for Vec<T: Hashable>.
-pub struct HashableVector<T: Hashable> {
-    payload: Vec<T>,
-    config: Arc<AppConfig>,
-}
-
-impl<T: Hashable> HashableVector<T> {
-    pub fn new(config: Arc<AppConfig>, payload: Vec<T>) -> Self {
-        Self { config, payload }
-    }
+/// Body of a user search, a page when the page envelope is preferred and
+/// otherwise a list.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SearchBody<T> {
+    Page(SearchPage<T>),
+    List(Vec<T>),
 }
 
-impl<T: Hashable> IntoResponse for HashableVector<T> {
-    fn into_response(self) -> Response {
-        let hashed = self
-            .payload
-            .iter()
-            .map(|d| d.hash(self.config.hash_prefix()))
-            .collect::<Vec<_>>();
-        (StatusCode::OK, Json(hashed)).into_response()
+impl<T> Hashable for SearchBody<T>
+where
+    T: Hashable,
+{
+    type Hashed = SearchBody<T::Hashed>;
+    fn hash(&self, hash_prefix: &str) -> Self::Hashed {
+        match self {
+            Self::Page(page) => SearchBody::Page(page.hash(hash_prefix)),
+            Self::List(items) => SearchBody::List(items.hash(hash_prefix)),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Hashable;
+    use super::{Hashable, HashedUser};
+    use serde_json::json;
     use user_persist::types::{Email, Gender, User};
     #[test]
     fn test_hash_user() {
@@ -150,14 +153,43 @@ mod test {
             age: 100,
             email: Email("test@user.com".to_owned()),
             gender: Gender::Male,
+            phone: None,
+            address: None,
+            created_at: None,
+            updated_at: None,
         };
 
         let hashed = user.hash("some_prefix");
 
         print!("hashed user: {}", serde_json::to_string(&hashed).unwrap());
         assert_eq!(
-            hashed.hid,
+            hashed.hash_id,
             "0HBmtxUP3a38op1YHscpgdAPjyRDkHq89bzPnk8ibDo=".to_owned()
         );
     }
+
+    #[test]
+    fn test_hashed_user_wire_names() {
+        let wire = json!({
+            "name": "Test User",
+            "age": 100,
+            "email": "test@user.com",
+            "gender": "Male",
+            "hashId": "hash"
+        });
+
+        let hashed = serde_json::from_value::<HashedUser>(wire.clone()).unwrap();
+        assert_eq!(hashed.hash_id, "hash");
+        assert_eq!(serde_json::to_value(&hashed).unwrap(), wire);
+
+        let legacy = json!({
+            "name": "Test User",
+            "age": 100,
+            "email": "test@user.com",
+            "gender": "Male",
+            "hid": "hash"
+        });
+        let hashed = serde_json::from_value::<HashedUser>(legacy).unwrap();
+        assert_eq!(serde_json::to_value(&hashed).unwrap(), wire);
+    }
 }
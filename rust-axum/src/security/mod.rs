@@ -1,6 +1,10 @@
 /*!
 Module for security features.
 */
+pub mod access;
+pub mod cursor;
 pub mod hashing;
+pub mod resume;
+pub mod signing;
 
 pub const HASHING_TARGET: &str = "hashing";
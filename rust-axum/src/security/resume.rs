@@ -0,0 +1,106 @@
+/*!
+Signed tokens for resuming user downloads.
+
+A streamed download can outlive the JWT that authorized it. The response
+carries a resume token for the subject so the client can continue after the
+last user it received with a fresh JWT instead of restarting from zero.
+Resume tokens have no role so they can't be used as access tokens.
+*/
+use crate::AppConfig;
+use chrono::Duration;
+use jsonwebtoken::{decode, encode, Header, Validation};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Response header holding the resume token of a download.
+pub const RESUME_TOKEN_HEADER: &str = "download-resume-token";
+
+const PURPOSE: &str = "download.resume";
+
+/// Rejected resume token.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ResumeError {
+    #[error("resume token is invalid")]
+    Invalid,
+    #[error("resume token has expired")]
+    Expired,
+    #[error("resume token was issued to another subject")]
+    Subject,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ResumeClaims {
+    sub: String,
+    exp: i64,
+    purpose: String,
+}
+
+/// Issue a resume token for `sub` valid for `ttl`.
+pub fn issue(
+    config: &AppConfig,
+    sub: &str,
+    ttl: Duration,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = ResumeClaims {
+        sub: sub.to_owned(),
+        exp: (config.clock().now() + ttl).timestamp(),
+        purpose: PURPOSE.to_owned(),
+    };
+    encode(&Header::default(), &claims, config.jwt_encoding_key())
+}
+
+/// Check `token` was issued to `sub` and hasn't expired.
+pub fn verify(config: &AppConfig, token: &str, sub: &str) -> Result<(), ResumeError> {
+    // Expiry is checked against the configured clock.
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+
+    let claims = decode::<ResumeClaims>(token, config.jwt_decoding_key(), &validation)
+        .map_err(|_| ResumeError::Invalid)?
+        .claims;
+    if claims.purpose != PURPOSE {
+        Err(ResumeError::Invalid)
+    } else if claims.exp < config.clock().now().timestamp() {
+        Err(ResumeError::Expired)
+    } else if claims.sub != sub {
+        Err(ResumeError::Subject)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{issue, verify, ResumeError};
+    use crate::{arguments::test_jwt, types::jwt::Role, AppConfig};
+    use chrono::{Duration, Utc};
+    use std::sync::Arc;
+    use user_persist::clock::MockClock;
+
+    #[test]
+    fn test_resume_token() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let config = AppConfig::test(b"secret").with_clock(clock.clone());
+        let token = issue(&config, "droberts", Duration::hours(1)).unwrap();
+
+        assert_eq!(verify(&config, &token, "droberts"), Ok(()));
+        assert_eq!(
+            verify(&config, &token, "somebody"),
+            Err(ResumeError::Subject)
+        );
+        assert_eq!(
+            verify(&config, &test_jwt(&config, Role::Admin), "droberts"),
+            Err(ResumeError::Invalid)
+        );
+        assert_eq!(
+            verify(&AppConfig::test(b"other"), &token, "droberts"),
+            Err(ResumeError::Invalid)
+        );
+
+        clock.advance(Duration::hours(1) + Duration::seconds(1));
+        assert_eq!(
+            verify(&config, &token, "droberts"),
+            Err(ResumeError::Expired)
+        );
+    }
+}
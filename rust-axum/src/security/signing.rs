@@ -0,0 +1,270 @@
+/*!
+Ed25519 signatures of request and response bodies.
+
+High integrity clients verify the `X-Signature` header of a response
+against the public key served at `/api/v1/.well-known/signing-key`, which
+covers the whole body rather than only the users a `hashId` covers. The
+key pair is derived from a 32 byte seed given base64 encoded with
+`--response-signing-key`.
+
+Events sent to receivers are signed by the same key with the time they are
+sent, as described in [`api_types::events`].
+
+In the other direction, machine clients registered with their public key
+sign the requests they send. A request naming such a client in
+`X-Client-Id` must carry the `X-Signature` of its [`request_payload`],
+which binds the body to the method, the path and the time it was signed
+given in `X-Signature-Timestamp`. Requests signed outside the tolerance
+are rejected so a captured request can't be replayed later or sent to
+another route. With [`SignedClients::require_all`] every `POST` and `PUT`
+must name a client.
+*/
+use crate::types::handler::error_envelope;
+use api_types::events::{signature_header, signed_payload, DEFAULT_EVENT_TOLERANCE_SECS};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use secrecy::{ExposeSecret, SecretString};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug},
+};
+use thiserror::Error;
+
+/// Header holding the base64 signature of a request or response body.
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Request header naming the registered client signing the body.
+pub const CLIENT_ID_HEADER: &str = "x-client-id";
+
+/// Request header holding the time a request was signed, in seconds since
+/// the epoch.
+pub const SIGNATURE_TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+
+/// Age in seconds after which a signed request is rejected.
+pub const REQUEST_TOLERANCE_SECS: i64 = DEFAULT_EVENT_TOLERANCE_SECS;
+
+/// Length in bytes of an Ed25519 public key.
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// Name of the signature algorithm announced with the public key.
+pub const SIGNATURE_ALGORITHM: &str = "Ed25519";
+
+/// Signing key seed that can't be used.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("response signing key must be a base64 encoded 32 byte Ed25519 seed")]
+pub struct SigningKeyError;
+
+/// Signs response bodies with an Ed25519 key pair.
+pub struct ResponseSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl Debug for ResponseSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseSigner")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+impl ResponseSigner {
+    /// Create a signer from a base64 encoded 32 byte seed.
+    pub fn from_seed(seed: &SecretString) -> Result<Self, SigningKeyError> {
+        let seed = base64::decode(seed.expose_secret().trim()).map_err(|_| SigningKeyError)?;
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&seed).map_err(|_| SigningKeyError)?;
+        Ok(Self { key_pair })
+    }
+
+    /// Base64 signature of `body`.
+    pub fn sign(&self, body: &[u8]) -> String {
+        base64::encode(self.key_pair.sign(body))
+    }
+
+    /// Value of the event signature header of an event delivery of `body`
+    /// sent at `timestamp`, in seconds since the epoch.
+    pub fn sign_event(&self, body: &[u8], timestamp: i64) -> String {
+        let signature = self.key_pair.sign(&signed_payload(timestamp, body));
+        signature_header(timestamp, signature.as_ref())
+    }
+
+    /// Base64 public key verifying the signatures.
+    pub fn public_key(&self) -> String {
+        base64::encode(self.key_pair.public_key())
+    }
+}
+
+/// Bytes a client signs for a request of `method` to `path`, with its
+/// query, sent at `timestamp` in seconds since the epoch: the method, path
+/// and timestamp on their own lines followed by the body.
+pub fn request_payload(method: &str, path: &str, timestamp: i64, body: &[u8]) -> Vec<u8> {
+    let mut payload = format!("{method}\n{path}\n{timestamp}\n").into_bytes();
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// Request signature that doesn't verify.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("Missing client id, requests must be signed")]
+    MissingClient,
+    #[error("Unknown client `{0}`")]
+    UnknownClient(String),
+    #[error("Missing request signature")]
+    Missing,
+    #[error("Missing or malformed signature timestamp")]
+    Timestamp,
+    #[error("Request signed {0} seconds ago, outside the tolerance")]
+    Expired(i64),
+    #[error("Request signature doesn't match the request")]
+    Invalid,
+}
+
+impl IntoResponse for SignatureError {
+    fn into_response(self) -> Response {
+        let body = error_envelope("signature.invalid", &self, None);
+        (StatusCode::UNAUTHORIZED, body).into_response()
+    }
+}
+
+/// Public keys of the machine clients signing their requests.
+#[derive(Debug, Default)]
+pub struct SignedClients {
+    keys: BTreeMap<String, Vec<u8>>,
+    required: bool,
+}
+
+impl SignedClients {
+    /// Read the clients of a JSON object mapping client ids to base64
+    /// Ed25519 public keys.
+    pub fn from_json(json: &[u8]) -> Result<Self, String> {
+        let encoded =
+            serde_json::from_slice::<BTreeMap<String, String>>(json).map_err(|e| e.to_string())?;
+        let keys = encoded
+            .into_iter()
+            .map(|(client, key)| match base64::decode(key.trim()) {
+                Ok(key) if key.len() == PUBLIC_KEY_LEN => Ok((client, key)),
+                _ => Err(format!(
+                    "public key of client `{client}` is not a base64 Ed25519 key"
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            keys,
+            required: false,
+        })
+    }
+
+    /// Require every `POST` and `PUT` request to be signed by a client
+    /// rather than only those naming one.
+    pub fn require_all(self) -> Self {
+        Self {
+            required: true,
+            ..self
+        }
+    }
+
+    /// Whether requests not naming a client are rejected.
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    /// Check `client` is registered and sent a signature, before reading the
+    /// request it signed.
+    pub fn check_client(
+        &self,
+        client: &str,
+        signature: Option<&str>,
+    ) -> Result<(), SignatureError> {
+        if !self.keys.contains_key(client) {
+            return Err(SignatureError::UnknownClient(client.to_owned()));
+        }
+        signature.map(|_| ()).ok_or(SignatureError::Missing)
+    }
+
+    /// Check `signature` is the base64 signature of `payload`, the
+    /// [`request_payload`] of a request, by `client`.
+    pub fn verify(
+        &self,
+        client: &str,
+        payload: &[u8],
+        signature: Option<&str>,
+    ) -> Result<(), SignatureError> {
+        let key = self
+            .keys
+            .get(client)
+            .ok_or_else(|| SignatureError::UnknownClient(client.to_owned()))?;
+        let signature = signature.ok_or(SignatureError::Missing)?;
+        let signature = base64::decode(signature.trim()).map_err(|_| SignatureError::Invalid)?;
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(payload, &signature)
+            .map_err(|_| SignatureError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ResponseSigner, SignatureError, SignedClients, SigningKeyError};
+    use api_types::events::verify_event;
+    use ring::signature::{UnparsedPublicKey, ED25519};
+    use secrecy::SecretString;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let seed = SecretString::new(base64::encode([7u8; 32]));
+        let signer = ResponseSigner::from_seed(&seed).unwrap();
+        let signature = base64::decode(signer.sign(b"{\"id\":1}")).unwrap();
+        let public_key =
+            UnparsedPublicKey::new(&ED25519, base64::decode(signer.public_key()).unwrap());
+
+        assert!(public_key.verify(b"{\"id\":1}", &signature).is_ok());
+        assert!(public_key.verify(b"{\"id\":2}", &signature).is_err());
+    }
+
+    #[test]
+    fn test_sign_event() {
+        let seed = SecretString::new(base64::encode([7u8; 32]));
+        let signer = ResponseSigner::from_seed(&seed).unwrap();
+        let header = signer.sign_event(b"{}", 1000);
+        let public_key = base64::decode(signer.public_key()).unwrap();
+
+        assert_eq!(verify_event(&public_key, &header, b"{}", 1010, 300), Ok(()));
+        assert!(verify_event(&public_key, &header, b"{}", 2000, 300).is_err());
+    }
+
+    #[test]
+    fn test_verify_client_signature() {
+        let signer =
+            ResponseSigner::from_seed(&SecretString::new(base64::encode([9u8; 32]))).unwrap();
+        let clients = format!(r#"{{"billing": "{}"}}"#, signer.public_key());
+        let clients = SignedClients::from_json(clients.as_bytes()).unwrap();
+        let signature = signer.sign(b"{}");
+
+        assert_eq!(clients.verify("billing", b"{}", Some(&signature)), Ok(()));
+        assert_eq!(
+            clients.verify("billing", b"{ }", Some(&signature)),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            clients.verify("billing", b"{}", None),
+            Err(SignatureError::Missing)
+        );
+        assert_eq!(
+            clients.verify("crm", b"{}", Some(&signature)),
+            Err(SignatureError::UnknownClient("crm".to_owned()))
+        );
+        assert!(SignedClients::from_json(br#"{"billing": "c2hvcnQ="}"#).is_err());
+    }
+
+    #[test]
+    fn test_invalid_seed() {
+        for seed in ["not base64!", "c2hvcnQ="] {
+            assert_eq!(
+                ResponseSigner::from_seed(&SecretString::new(seed.to_owned())).unwrap_err(),
+                SigningKeyError
+            );
+        }
+    }
+}
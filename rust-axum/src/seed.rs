@@ -0,0 +1,157 @@
+/*!
+Seeding of an empty database at startup.
+
+Demo environments start with the users of a `--seed-file` holding a JSON
+array of user creation requests. The users are only saved when the
+database has none, so restarts don't duplicate them. Each user is checked
+like the body of a create request and entries that fail are logged and
+skipped rather than stopping the service.
+*/
+use crate::USER_MS_TARGET;
+use api_types::CreateUserRequest;
+use errors::ApiError;
+use serde_json::Value;
+use std::{
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tracing::{event, Level};
+use user_persist::{
+    persistence::{PersistenceError, UserPersistence},
+    types::{EmailValidation, User, UserSearch, ValidateRequest},
+};
+
+/// Seed file that can't be loaded.
+#[derive(Debug, Error)]
+pub enum SeedError {
+    #[error("can't read seed file {0:?}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("seed file {0:?} is not a JSON array: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error(transparent)]
+    Persistence(#[from] PersistenceError),
+}
+
+/// Outcome of seeding the database.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SeedSummary {
+    pub seeded: usize,
+    /// Entries which aren't valid user creation requests.
+    pub invalid: usize,
+    /// Valid entries the database refused, such as duplicate emails.
+    pub failed: usize,
+}
+
+impl Display for SeedSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "seeded {} users, {} invalid, {} failed",
+            self.seeded, self.invalid, self.failed
+        )
+    }
+}
+
+/// Save the users of the seed file at `path` when `db` has no users, with
+/// emails validated in `mode`. Returns `None` without reading the file when
+/// users exist.
+pub async fn seed_users(
+    db: &dyn UserPersistence,
+    path: &Path,
+    mode: EmailValidation,
+) -> Result<Option<SeedSummary>, SeedError> {
+    if !db.search_users(&UserSearch::default(), 1).await?.is_empty() {
+        return Ok(None);
+    }
+
+    let json = std::fs::read(path).map_err(|e| SeedError::Read(path.to_owned(), e))?;
+    let entries = serde_json::from_slice::<Vec<Value>>(&json)
+        .map_err(|e| SeedError::Parse(path.to_owned(), e))?;
+
+    let mut summary = SeedSummary::default();
+    for (index, entry) in entries.into_iter().enumerate() {
+        let request = serde_json::from_value::<CreateUserRequest>(entry)
+            .map_err(|e| e.to_string())
+            .and_then(|request| match request.validate_request(mode) {
+                Ok(()) => Ok(request),
+                Err(e) => Err(e.to_string()),
+            });
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                event!(
+                  target: USER_MS_TARGET,
+                  Level::WARN,
+                  "Skipping invalid seed user {index}: {e}"
+                );
+                summary.invalid += 1;
+                continue;
+            }
+        };
+        match db.save_user(&User::from(request)).await {
+            Ok(_) => summary.seeded += 1,
+            Err(e) => {
+                event!(
+                  target: USER_MS_TARGET,
+                  Level::WARN,
+                  "Seed user {index} not saved: {}",
+                  ApiError::from(&e).message
+                );
+                summary.failed += 1;
+            }
+        }
+    }
+    Ok(Some(summary))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{seed_users, SeedError, SeedSummary};
+    use serde_json::json;
+    use user_persist::{email::EmailNormalizer, memory::MemoryPersistence, types::EmailValidation};
+
+    #[tokio::test]
+    async fn test_seed_users() {
+        let path = std::env::temp_dir().join(format!("rust-axum-seed-{}.json", std::process::id()));
+        let user = |name: &str, age: u32, email: &str| json!({"name": name, "age": age, "email": email, "gender": "Female"});
+        let seed = json!([
+            user("Seed User", 100, "seed@test.com"),
+            user("Too Young", 20, "young@test.com"),
+            {"name": "No Email"},
+            user("Other User", 101, "other@test.com"),
+        ]);
+        std::fs::write(&path, seed.to_string()).unwrap();
+
+        let db = MemoryPersistence::new(EmailNormalizer::new(false));
+        let summary = seed_users(&db, &path, EmailValidation::Lenient)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            Some(SeedSummary {
+                seeded: 2,
+                invalid: 2,
+                failed: 0
+            })
+        );
+        assert_eq!(db.len(), 2);
+
+        // Users exist now, so the file isn't loaded again.
+        assert_eq!(
+            seed_users(&db, &path, EmailValidation::Lenient)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(db.len(), 2);
+
+        std::fs::write(&path, "{}").unwrap();
+        let empty = MemoryPersistence::new(EmailNormalizer::new(false));
+        assert!(matches!(
+            seed_users(&empty, &path, EmailValidation::Lenient).await,
+            Err(SeedError::Parse(..))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+/*!
+Scheduled job recording daily gender statistics.
+
+A snapshot of the gender counts is recorded at startup and after every
+UTC midnight so the history endpoint can chart trends without counting
+the whole collection on each request. Re-recording a day replaces its
+snapshot, so restarts and replicas recording the same day are harmless.
+*/
+use crate::USER_MS_TARGET;
+use chrono::{DateTime, Days, Utc};
+use std::{sync::Arc, time::Duration};
+use tracing::{event, Level};
+use user_persist::{
+    clock::Clock,
+    persistence::{PersistenceResult, UserPersistence},
+    stats::{StatsDate, StatsSnapshot},
+};
+
+/// Record the current gender counts as the snapshot of today.
+pub async fn record_snapshot(
+    db: &dyn UserPersistence,
+    clock: &dyn Clock,
+) -> PersistenceResult<StatsSnapshot> {
+    let snapshot = StatsSnapshot {
+        date: StatsDate::of(clock.now()),
+        counts: db.count_genders().await?,
+    };
+    db.save_stats_snapshot(&snapshot).await?;
+    Ok(snapshot)
+}
+
+/// Time left until the next UTC midnight.
+fn until_next_day(now: DateTime<Utc>) -> Duration {
+    let next_day = now
+        .date_naive()
+        .checked_add_days(Days::new(1))
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|midnight| midnight.and_utc());
+
+    next_day
+        .and_then(|midnight| (midnight - now).to_std().ok())
+        .unwrap_or(Duration::from_secs(24 * 60 * 60))
+}
+
+/// Record a snapshot now and then once a day. Meant to be run as a
+/// supervised background task.
+pub async fn record_daily_snapshots(db: Arc<dyn UserPersistence>, clock: Arc<dyn Clock>) {
+    loop {
+        match record_snapshot(db.as_ref(), clock.as_ref()).await {
+            Ok(snapshot) => event!(
+              target: USER_MS_TARGET,
+              Level::INFO,
+              "Recorded stats snapshot for {}",
+              snapshot.date
+            ),
+            Err(e) => event!(
+              target: USER_MS_TARGET,
+              Level::ERROR,
+              "Failed to record stats snapshot: {e}"
+            ),
+        }
+        tokio::time::sleep(until_next_day(clock.now())).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{record_snapshot, until_next_day};
+    use chrono::{DateTime, Utc};
+    use serde_json::json;
+    use std::time::Duration;
+    use user_persist::{
+        clock::MockClock,
+        memory::MemoryPersistence,
+        persistence::UserPersistence,
+        types::{Email, Gender, User},
+    };
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_until_next_day() {
+        assert_eq!(
+            until_next_day(time("2024-03-05T23:00:00Z")),
+            Duration::from_secs(60 * 60)
+        );
+        assert_eq!(
+            until_next_day(time("2024-03-05T00:00:00Z")),
+            Duration::from_secs(24 * 60 * 60)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_snapshot() {
+        let db = MemoryPersistence::default();
+        db.save_user(&User {
+            id: None,
+            name: "Test User".to_owned(),
+            age: 100,
+            email: Email("test@test.com".to_owned()),
+            gender: Gender::Female,
+            phone: None,
+            address: None,
+            created_at: None,
+            updated_at: None,
+        })
+        .await
+        .unwrap();
+        let clock = MockClock::new(time("2024-03-05T12:00:00Z"));
+
+        let snapshot = record_snapshot(&db, &clock).await.unwrap();
+        assert_eq!(snapshot.date.to_string(), "2024-03-05");
+        assert_eq!(
+            snapshot.counts,
+            vec![json!({"gender": "Female", "count": 1})]
+        );
+        assert_eq!(db.stats_history(None, None).await.unwrap(), vec![snapshot]);
+    }
+}
@@ -0,0 +1,370 @@
+/*!
+Supervision of background tasks.
+
+Background jobs such as the daily statistics snapshot are spawned by a
+[`Supervisor`] under a name. A task that panics or returns is restarted
+after a backoff which doubles with each consecutive failure up to a maximum.
+The state of every task is reported by `/readyz` and the tasks are stopped
+by [`Supervisor::shutdown`] once the server has drained.
+
+Tasks that must only run on one replica at a time are spawned with
+[`Supervisor::spawn_singleton`]. They wait in standby until their instance
+holds the task's lease of a [`DistributedLock`], and are stopped when the
+lease can't be renewed.
+*/
+use crate::USER_MS_TARGET;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{event, Level};
+use user_persist::lock::DistributedLock;
+
+/// Lease of singleton tasks. An instance that stops renewing it hands its
+/// tasks over to another instance within this time.
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(30);
+
+/// Delays before restarting a failed task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Delay before the first restart.
+    pub initial_backoff: Duration,
+    /// Longest delay between restarts. A task that ran for longer than this
+    /// before failing is restarted after the initial backoff again.
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Delay before restarting a task after `failures` consecutive failures.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// State of a supervised task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TaskState {
+    Running,
+    /// Waiting for the lease of a singleton task held by another instance.
+    Standby,
+    /// Waiting to be restarted after a failure.
+    Restarting,
+    Stopped,
+}
+
+/// Health of a supervised task.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskHealth {
+    pub name: &'static str,
+    pub state: TaskState,
+    /// Number of times the task was restarted.
+    pub restarts: u32,
+    /// Why the task last failed.
+    pub last_failure: Option<String>,
+}
+
+/// Spawns named background tasks, restarts them when they fail and stops
+/// them on shutdown.
+#[derive(Clone)]
+pub struct Supervisor {
+    health: Arc<Mutex<BTreeMap<&'static str, TaskHealth>>>,
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self {
+            health: Arc::default(),
+            handles: Arc::default(),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Supervisor {
+    /// Spawn the task created by `task` under `name`. A new task is created
+    /// whenever the previous one panics or returns.
+    pub fn spawn<F, Fut>(&self, name: &'static str, policy: RestartPolicy, task: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.update(name, |health| health.state = TaskState::Running);
+
+        let supervisor = self.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        let handle = tokio::spawn(async move {
+            let mut failures = 0;
+            while !*shutdown.borrow() {
+                supervisor.update(name, |health| health.state = TaskState::Running);
+                let started = Instant::now();
+                let mut run = tokio::spawn(task());
+
+                let failure = tokio::select! {
+                    result = &mut run => match result {
+                        Ok(()) => "task exited".to_owned(),
+                        Err(e) if e.is_panic() => "task panicked".to_owned(),
+                        Err(e) => e.to_string(),
+                    },
+                    _ = shutdown.changed() => {
+                        run.abort();
+                        break;
+                    }
+                };
+
+                if started.elapsed() > policy.max_backoff {
+                    failures = 0;
+                }
+                failures += 1;
+                let backoff = policy.backoff(failures);
+                event!(
+                  target: USER_MS_TARGET,
+                  Level::ERROR,
+                  "Background task {name} failed: {failure}, restarting in {backoff:?}"
+                );
+                supervisor.update(name, |health| {
+                    health.state = TaskState::Restarting;
+                    health.restarts += 1;
+                    health.last_failure = Some(failure);
+                });
+
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => (),
+                    _ = shutdown.changed() => break,
+                }
+            }
+            supervisor.update(name, |health| health.state = TaskState::Stopped);
+        });
+        self.handles.lock().unwrap().push(handle);
+    }
+
+    /// Spawn the task created by `task` under `name` while this instance
+    /// holds the lease of `name` from `lock`. The lease is renewed a few
+    /// times per `lease` while the task runs. Losing it stops the task,
+    /// which counts as a failure, and the instance waits for the lease again.
+    pub fn spawn_singleton<F, Fut>(
+        &self,
+        name: &'static str,
+        policy: RestartPolicy,
+        lock: Arc<dyn DistributedLock>,
+        lease: Duration,
+        task: F,
+    ) where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let task = Arc::new(task);
+        let chrono_lease = chrono::Duration::from_std(lease).expect("lease fits a chrono duration");
+        let renew_every = lease / 3;
+        self.spawn(name, policy, move || {
+            let (supervisor, lock, task) = (supervisor.clone(), lock.clone(), task.clone());
+            async move {
+                loop {
+                    match lock.acquire(name, chrono_lease).await {
+                        Ok(true) => break,
+                        Ok(false) => (),
+                        Err(e) => {
+                            event!(
+                              target: USER_MS_TARGET,
+                              Level::ERROR,
+                              "Lease of {name} can't be acquired: {e}"
+                            );
+                            return;
+                        }
+                    }
+                    supervisor.update(name, |health| health.state = TaskState::Standby);
+                    tokio::time::sleep(renew_every).await;
+                }
+                supervisor.update(name, |health| health.state = TaskState::Running);
+
+                let mut run = std::pin::pin!(task());
+                let mut renew = tokio::time::interval(renew_every);
+                renew.tick().await;
+                loop {
+                    tokio::select! {
+                        _ = &mut run => break,
+                        _ = renew.tick() => match lock.acquire(name, chrono_lease).await {
+                            Ok(true) => (),
+                            Ok(false) => {
+                                event!(
+                                  target: USER_MS_TARGET,
+                                  Level::ERROR,
+                                  "Lease of {name} was taken by another instance"
+                                );
+                                return;
+                            }
+                            Err(e) => {
+                                event!(
+                                  target: USER_MS_TARGET,
+                                  Level::ERROR,
+                                  "Lease of {name} can't be renewed: {e}"
+                                );
+                                break;
+                            }
+                        },
+                    }
+                }
+                if let Err(e) = lock.release(name).await {
+                    event!(
+                      target: USER_MS_TARGET,
+                      Level::WARN,
+                      "Lease of {name} can't be released: {e}"
+                    );
+                }
+            }
+        });
+    }
+
+    /// Health of every task ordered by name.
+    pub fn health(&self) -> Vec<TaskHealth> {
+        self.health.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Whether every task is running or in standby.
+    pub fn is_ready(&self) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .values()
+            .all(|health| matches!(health.state, TaskState::Running | TaskState::Standby))
+    }
+
+    /// Stop every task and wait for them to finish.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+
+    fn update(&self, name: &'static str, f: impl FnOnce(&mut TaskHealth)) {
+        let mut health = self.health.lock().unwrap();
+        f(health.entry(name).or_insert_with(|| TaskHealth {
+            name,
+            state: TaskState::Running,
+            restarts: 0,
+            last_failure: None,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RestartPolicy, Supervisor, TaskState};
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use user_persist::{kv::MemoryKvStore, lock::KvLock};
+
+    #[test]
+    fn test_backoff() {
+        let policy = RestartPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(7), Duration::from_secs(60));
+        assert_eq!(policy.backoff(100), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_restart_and_shutdown() {
+        let supervisor = Supervisor::default();
+        let runs = Arc::new(AtomicU32::new(0));
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        };
+
+        let task_runs = runs.clone();
+        supervisor.spawn("flaky", policy, move || {
+            let run = task_runs.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run == 0 {
+                    panic!("first run fails");
+                }
+                std::future::pending::<()>().await
+            }
+        });
+
+        while runs.load(Ordering::SeqCst) < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let health = supervisor.health();
+        assert_eq!(health[0].name, "flaky");
+        assert_eq!(health[0].restarts, 1);
+        assert_eq!(health[0].last_failure.as_deref(), Some("task panicked"));
+        assert!(supervisor.is_ready());
+
+        supervisor.shutdown().await;
+        assert_eq!(supervisor.health()[0].state, TaskState::Stopped);
+        assert!(!supervisor.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_singleton_failover() {
+        let store = Arc::new(MemoryKvStore::default());
+        let lease = Duration::from_millis(60);
+        let started = Arc::new(AtomicU32::new(0));
+        let replicas = [Supervisor::default(), Supervisor::default()];
+        for supervisor in &replicas {
+            let task_started = started.clone();
+            supervisor.spawn_singleton(
+                "snapshots",
+                RestartPolicy::default(),
+                Arc::new(KvLock::new(store.clone())),
+                lease,
+                move || {
+                    task_started.fetch_add(1, Ordering::SeqCst);
+                    std::future::pending::<()>()
+                },
+            );
+        }
+
+        tokio::time::sleep(lease * 2).await;
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+        let states = replicas
+            .iter()
+            .map(|supervisor| supervisor.health()[0].state)
+            .collect::<Vec<_>>();
+        assert!(states.contains(&TaskState::Running));
+        assert!(states.contains(&TaskState::Standby));
+        assert!(replicas.iter().all(Supervisor::is_ready));
+
+        // The standby replica takes over once the leader's lease expires.
+        let (leader, standby) = if states[0] == TaskState::Running {
+            (&replicas[0], &replicas[1])
+        } else {
+            (&replicas[1], &replicas[0])
+        };
+        leader.shutdown().await;
+        while started.load(Ordering::SeqCst) < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(standby.health()[0].state, TaskState::Running);
+        standby.shutdown().await;
+    }
+}
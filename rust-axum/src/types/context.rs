@@ -0,0 +1,276 @@
+/*!
+Locale and time zone of a request.
+
+The locale is negotiated from `Accept-Language` among the languages
+validation messages are translated to, falling back to English. The time
+zone is read from an `X-Timezone` header holding an IANA name such as
+`America/Toronto` and defaults to UTC. Times in responses are formatted in
+that zone.
+*/
+use crate::types::handler::error_envelope;
+use axum::{
+    http::{header::ACCEPT_LANGUAGE, HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, SecondsFormat};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::fmt::{self, Display};
+use thiserror::Error;
+use user_persist::{ValidationError, ValidationErrors, ValidationErrorsKind};
+
+/// Request header naming the time zone of the client.
+pub const TIMEZONE_HEADER: HeaderName = HeaderName::from_static("x-timezone");
+
+/// Languages of validation messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Fr,
+    Es,
+}
+
+impl Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::En => "en",
+            Self::Fr => "fr",
+            Self::Es => "es",
+        })
+    }
+}
+
+impl Locale {
+    /// Supported locale of a language tag such as `fr-CA`.
+    fn of_tag(tag: &str) -> Option<Self> {
+        let language = tag.split('-').next().unwrap_or_default();
+        match language.to_ascii_lowercase().as_str() {
+            "en" => Some(Self::En),
+            "fr" => Some(Self::Fr),
+            "es" => Some(Self::Es),
+            _ => None,
+        }
+    }
+
+    /// Preferred supported locale of an `Accept-Language` value. Ranges
+    /// are ranked by quality, in order for equal qualities.
+    pub fn negotiate(accept_language: &str) -> Self {
+        let mut best = None;
+        for range in accept_language.split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().unwrap_or_default();
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            if let Some(locale) = Self::of_tag(tag).filter(|_| quality > 0.0) {
+                if best.is_none_or(|(_, q)| quality > q) {
+                    best = Some((locale, quality));
+                }
+            }
+        }
+        best.map(|(locale, _)| locale).unwrap_or_default()
+    }
+
+    /// Locale negotiated from the `Accept-Language` header of a request.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map(Self::negotiate)
+            .unwrap_or_default()
+    }
+
+    /// Message explaining why a value failed validation.
+    pub fn validation_message(self, error: &ValidationError) -> String {
+        // Bounds are kept as floats, which read better without a zero
+        // fraction.
+        let bound = |name: &str| {
+            error.params.get(name).map(|value| match value.as_f64() {
+                Some(n) if n.fract() == 0.0 => format!("{n:.0}"),
+                _ => value.to_string(),
+            })
+        };
+        let (min, max) = (bound("min"), bound("max"));
+        match (self, error.code.as_ref(), min, max) {
+            (Self::En, "range", Some(min), Some(max)) => format!("must be between {min} and {max}"),
+            (Self::En, "range", Some(min), None) => format!("must be at least {min}"),
+            (Self::En, "range", None, Some(max)) => format!("must be at most {max}"),
+            (Self::En, "length", Some(min), Some(max)) => {
+                format!("must have between {min} and {max} characters")
+            }
+            (Self::En, "length", Some(min), None) => format!("must have at least {min} characters"),
+            (Self::En, "length", None, Some(max)) => format!("must have at most {max} characters"),
+            (Self::En, "invalid email", ..) => "is not a valid email address".to_owned(),
+            (Self::En, "empty update", ..) => "must change at least one field".to_owned(),
+            (Self::En, ..) => "is invalid".to_owned(),
+
+            (Self::Fr, "range", Some(min), Some(max)) => format!("doit être entre {min} et {max}"),
+            (Self::Fr, "range", Some(min), None) => format!("doit être au moins {min}"),
+            (Self::Fr, "range", None, Some(max)) => format!("doit être au plus {max}"),
+            (Self::Fr, "length", Some(min), Some(max)) => {
+                format!("doit avoir entre {min} et {max} caractères")
+            }
+            (Self::Fr, "length", Some(min), None) => {
+                format!("doit avoir au moins {min} caractères")
+            }
+            (Self::Fr, "length", None, Some(max)) => format!("doit avoir au plus {max} caractères"),
+            (Self::Fr, "invalid email", ..) => "n'est pas une adresse courriel valide".to_owned(),
+            (Self::Fr, "empty update", ..) => "doit modifier au moins un champ".to_owned(),
+            (Self::Fr, ..) => "est invalide".to_owned(),
+
+            (Self::Es, "range", Some(min), Some(max)) => format!("debe estar entre {min} y {max}"),
+            (Self::Es, "range", Some(min), None) => format!("debe ser al menos {min}"),
+            (Self::Es, "range", None, Some(max)) => format!("debe ser como máximo {max}"),
+            (Self::Es, "length", Some(min), Some(max)) => {
+                format!("debe tener entre {min} y {max} caracteres")
+            }
+            (Self::Es, "length", Some(min), None) => {
+                format!("debe tener al menos {min} caracteres")
+            }
+            (Self::Es, "length", None, Some(max)) => {
+                format!("debe tener como máximo {max} caracteres")
+            }
+            (Self::Es, "invalid email", ..) => "no es una dirección de correo válida".to_owned(),
+            (Self::Es, "empty update", ..) => "debe cambiar al menos un campo".to_owned(),
+            (Self::Es, ..) => "no es válido".to_owned(),
+        }
+    }
+
+    /// Set the message of every error of `errors` without one.
+    pub fn localize(self, errors: &mut ValidationErrors) {
+        for kind in errors.errors_mut().values_mut() {
+            match kind {
+                ValidationErrorsKind::Field(errors) => {
+                    for error in errors.iter_mut().filter(|error| error.message.is_none()) {
+                        error.message = Some(self.validation_message(error).into());
+                    }
+                }
+                ValidationErrorsKind::Struct(errors) => self.localize(errors),
+                ValidationErrorsKind::List(errors) => {
+                    errors.values_mut().for_each(|errors| self.localize(errors))
+                }
+            }
+        }
+    }
+}
+
+/// Time zone header that isn't an IANA time zone name.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Unknown time zone `{0}`")]
+pub struct TimezoneError(pub String);
+
+impl IntoResponse for TimezoneError {
+    fn into_response(self) -> Response {
+        let body = error_envelope("timezone.invalid", &self, None);
+        (StatusCode::BAD_REQUEST, body).into_response()
+    }
+}
+
+/// Locale and time zone of the client making a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestContext {
+    pub locale: Locale,
+    pub timezone: Tz,
+}
+
+impl Default for RequestContext {
+    fn default() -> Self {
+        Self {
+            locale: Locale::default(),
+            timezone: Tz::UTC,
+        }
+    }
+}
+
+impl RequestContext {
+    /// Context of a request with `headers`.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, TimezoneError> {
+        let timezone = match headers.get(TIMEZONE_HEADER) {
+            Some(value) => {
+                let name = value.to_str().unwrap_or_default().trim();
+                name.parse::<Tz>()
+                    .map_err(|_| TimezoneError(name.to_owned()))?
+            }
+            None => Tz::UTC,
+        };
+        Ok(Self {
+            locale: Locale::from_headers(headers),
+            timezone,
+        })
+    }
+
+    /// RFC 3339 time of `millis` since the epoch in the request's time
+    /// zone.
+    pub fn format_time_ms(&self, millis: i64) -> Option<String> {
+        DateTime::from_timestamp_millis(millis).map(|time| {
+            time.with_timezone(&self.timezone)
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Locale, RequestContext, TimezoneError, TIMEZONE_HEADER};
+    use api_types::CreateUserRequest;
+    use axum::http::{header::ACCEPT_LANGUAGE, HeaderMap, HeaderValue};
+    use user_persist::types::{EmailValidation, ValidateRequest};
+
+    #[test]
+    fn test_negotiate_locale() {
+        assert_eq!(Locale::negotiate("fr-CA,fr;q=0.9,en;q=0.8"), Locale::Fr);
+        assert_eq!(Locale::negotiate("de-DE, es;q=0.5, en;q=0.7"), Locale::En);
+        assert_eq!(Locale::negotiate("es;q=0, fr;q=0.1"), Locale::Fr);
+        assert_eq!(Locale::negotiate("de, *"), Locale::En);
+        assert_eq!(Locale::negotiate(""), Locale::En);
+    }
+
+    #[test]
+    fn test_request_context() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            RequestContext::from_headers(&headers),
+            Ok(RequestContext::default())
+        );
+
+        headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("es-MX"));
+        headers.insert(TIMEZONE_HEADER, HeaderValue::from_static("America/Toronto"));
+        let context = RequestContext::from_headers(&headers).unwrap();
+        assert_eq!(context.locale, Locale::Es);
+        assert_eq!(
+            context.format_time_ms(1_700_000_000_000).as_deref(),
+            Some("2023-11-14T17:13:20.000-05:00")
+        );
+
+        headers.insert(TIMEZONE_HEADER, HeaderValue::from_static("Mars/Olympus"));
+        assert_eq!(
+            RequestContext::from_headers(&headers),
+            Err(TimezoneError("Mars/Olympus".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_localize() {
+        let user = serde_json::from_value::<CreateUserRequest>(serde_json::json!({
+            "name": "Test User",
+            "age": 20,
+            "email": "not an email",
+            "gender": "Female",
+        }))
+        .unwrap();
+        let mut errors = user.validate_request(EmailValidation::Lenient).unwrap_err();
+        Locale::Fr.localize(&mut errors);
+        let errors = errors.field_errors();
+        assert_eq!(
+            errors["age"][0].message.as_deref(),
+            Some("doit être au moins 100")
+        );
+        assert_eq!(
+            errors["email"][0].message.as_deref(),
+            Some("n'est pas une adresse courriel valide")
+        );
+    }
+}
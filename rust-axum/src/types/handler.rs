@@ -1,18 +1,32 @@
 /*!
 Types for handler functions.
 */
-use crate::USER_MS_TARGET;
+use crate::{
+    allocator::AllocatorError,
+    security::{cursor::CursorError, resume::ResumeError},
+    USER_MS_TARGET,
+};
 use axum::{
-    extract::Extension,
+    extract::State,
     response::{IntoResponse, Response},
     Json,
 };
+use bootstrap::{
+    conditional::PreconditionError, content_type::UNSUPPORTED_MEDIA_TYPE_LABEL, token::IssueError,
+    DevTokenError,
+};
+use errors::{ApiError, ConfigError};
 use http::StatusCode;
-use serde_json::json;
-use std::sync::Arc;
+use serde_json::Value;
+use std::{fmt::Display, sync::Arc};
 use thiserror::Error;
+use tower_http::request_id::RequestId;
 use tracing::{event, Level};
-use user_persist::persistence::{PersistenceError, UserPersistence};
+use user_persist::{
+    import::ImportError,
+    persistence::{PersistenceError, UserPersistence},
+    query::QueryError,
+};
 
 /// Common error type for handlers.
 #[derive(Debug, Error)]
@@ -21,33 +35,112 @@ pub enum HandlerError {
     PersistenceError(#[from] PersistenceError),
     #[error("Resource not found")]
     ResourceNotFound,
+    #[error("{0}")]
+    Disabled(#[from] ConfigError),
+    #[error("Export error: `{0}`")]
+    ExportError(#[from] std::io::Error),
+    #[error("Allocator error: `{0}`")]
+    AllocatorError(#[from] AllocatorError),
+    #[error("Invalid token request: `{0}`")]
+    DevTokenError(#[from] DevTokenError),
+    #[error("Token error: `{0}`")]
+    TokenError(#[from] jsonwebtoken::errors::Error),
+    #[error("Token issue failed: `{0}`")]
+    IssueError(#[from] IssueError),
+    #[error("Bulk update requires confirm=true")]
+    UnconfirmedBulkUpdate,
+    #[error("Search requires at least one filter")]
+    UnfilteredSearch,
+    #[error("Download can't be resumed: `{0}`")]
+    ResumeError(#[from] ResumeError),
+    #[error("Search can't be continued: `{0}`")]
+    CursorError(#[from] CursorError),
+    #[error("Invalid query: {0}")]
+    QueryError(#[from] QueryError),
+    #[error("{0}")]
+    PreconditionError(#[from] PreconditionError),
+    #[error("Serialization error: `{0}`")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Unknown export format `{0}`")]
+    UnknownFormat(String),
+    #[error("Invalid import mapping: {0}")]
+    ImportMapping(serde_json::Error),
+    #[error("{0}")]
+    ImportError(#[from] ImportError),
+    #[error("Expected a Content-Type of text/csv, not `{0}`")]
+    UnsupportedImport(String),
+}
+
+impl From<&HandlerError> for ApiError {
+    fn from(err: &HandlerError) -> Self {
+        match err {
+            HandlerError::PersistenceError(e) => e.into(),
+            HandlerError::ResourceNotFound => ApiError::not_found(err),
+            HandlerError::Disabled(e) => (*e).into(),
+            HandlerError::AllocatorError(AllocatorError::Unavailable) => {
+                ConfigError::Disabled("Allocator statistics").into()
+            }
+            HandlerError::DevTokenError(e) => e.into(),
+            HandlerError::IssueError(e) => e.into(),
+            HandlerError::UnconfirmedBulkUpdate => {
+                ApiError::new(StatusCode::BAD_REQUEST, "bulk.unconfirmed", err)
+            }
+            HandlerError::UnfilteredSearch => ApiError::unfiltered_search(),
+            HandlerError::ResumeError(_) => {
+                ApiError::new(StatusCode::BAD_REQUEST, "resume.invalid", err)
+            }
+            HandlerError::CursorError(_) => {
+                ApiError::new(StatusCode::BAD_REQUEST, "cursor.invalid", err)
+            }
+            HandlerError::QueryError(_) => {
+                ApiError::new(StatusCode::BAD_REQUEST, "query.invalid", err)
+            }
+            HandlerError::PreconditionError(e) => e.into(),
+            HandlerError::SerializationError(e) => e.into(),
+            HandlerError::UnknownFormat(_) => {
+                ApiError::new(StatusCode::BAD_REQUEST, "format.unknown", err)
+            }
+            HandlerError::ImportMapping(_) | HandlerError::ImportError(_) => {
+                ApiError::new(StatusCode::BAD_REQUEST, "import.invalid", err)
+            }
+            HandlerError::UnsupportedImport(_) => ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                UNSUPPORTED_MEDIA_TYPE_LABEL,
+                err,
+            ),
+            #[cfg(feature = "jemalloc")]
+            HandlerError::AllocatorError(_) => ApiError::internal(),
+            HandlerError::ExportError(_) | HandlerError::TokenError(_) => ApiError::internal(),
+        }
+    }
 }
 
 impl IntoResponse for HandlerError {
     fn into_response(self) -> Response {
-        let error_message = format!("{self}");
-
         event!(
           target: USER_MS_TARGET,
           Level::ERROR,
-          "Server error: {error_message}"
+          "Server error: {self}"
         );
 
-        let body = json!({
-          "label": "server.error",
-          "message": error_message
-        });
-
-        (
-            match self {
-                Self::ResourceNotFound => StatusCode::NOT_FOUND,
-                _ => StatusCode::INTERNAL_SERVER_ERROR,
-            },
-            Json(body),
-        )
-            .into_response()
+        let error = ApiError::from(&self);
+        (error.status, Json(error.envelope(None))).into_response()
     }
 }
 
+/// Standard JSON error envelope. The request id is included when one has
+/// been assigned to the request.
+pub fn error_envelope(
+    label: &str,
+    message: impl Display,
+    req_id: Option<&RequestId>,
+) -> Json<Value> {
+    Json(errors::envelope(
+        label,
+        message,
+        req_id.and_then(|id| id.header_value().to_str().ok()),
+    ))
+}
+
 /// Type alias for UserPersistence Trait object.
-pub type Persist = Extension<Arc<dyn UserPersistence>>;
+pub type Persist = State<Arc<dyn UserPersistence>>;
@@ -1,13 +1,14 @@
 /*!
 JWT types and trait implementations.
 */
-use crate::USER_MS_TARGET;
+use crate::{middleware::audit::AuditEvent, USER_MS_TARGET};
 use axum::response::{IntoResponse, Json, Response};
-use chrono::DateTime;
-use http::StatusCode;
+use bootstrap::claims::{ClaimsError, ClaimsPolicy, RegisteredClaims};
+use chrono::{DateTime, Utc};
+use errors::ApiError;
+use http::{header::WWW_AUTHENTICATE, HeaderValue};
 use jsonwebtoken::DecodingKey;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use std::{
     convert::Infallible,
     fmt::{self, Display, Formatter},
@@ -27,6 +28,15 @@ pub struct JWTClaims {
     pub role: Role,
     /// Expiration date time in unix epoch.
     pub exp: i64,
+    /// Issued at date time in unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    /// Audience the token was issued for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Issuer of the token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
 }
 
 impl Display for JWTClaims {
@@ -36,8 +46,40 @@ impl Display for JWTClaims {
     }
 }
 
+impl JWTClaims {
+    /// Claims issued at `now` for `ttl` with the audience and issuer
+    /// required by `policy`.
+    pub fn issue(
+        sub: String,
+        role: Role,
+        now: DateTime<Utc>,
+        ttl: chrono::Duration,
+        policy: &ClaimsPolicy,
+    ) -> Self {
+        Self {
+            sub,
+            role,
+            exp: (now + ttl).timestamp(),
+            iat: Some(now.timestamp()),
+            aud: policy.audience.clone(),
+            iss: policy.issuer.clone(),
+        }
+    }
+
+    /// Check the registered claims against `policy` at `now`.
+    pub fn validate(&self, policy: &ClaimsPolicy, now: DateTime<Utc>) -> Result<(), AuthError> {
+        let registered = RegisteredClaims {
+            exp: self.exp,
+            iat: self.iat,
+            aud: self.aud.as_deref(),
+            iss: self.iss.as_deref(),
+        };
+        Ok(policy.validate(registered, now.timestamp())?)
+    }
+}
+
 /// Sum Type for Roles
-#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
 pub enum Role {
     Admin,
     User,
@@ -83,10 +125,35 @@ pub enum AuthError {
     MissingAuth,
     #[error("Invalid token")]
     InvalidToken,
+    #[error("Token has expired")]
+    Expired,
+    #[error("Token claims rejected: {0}")]
+    InvalidClaims(ClaimsError),
     #[error("Role `{0}` is not permitted access")]
     RoleNotPermitted(Role),
 }
 
+impl From<ClaimsError> for AuthError {
+    fn from(err: ClaimsError) -> Self {
+        match err {
+            ClaimsError::Expired => Self::Expired,
+            _ => Self::InvalidClaims(err),
+        }
+    }
+}
+
+impl From<&AuthError> for errors::AuthError {
+    fn from(err: &AuthError) -> Self {
+        match err {
+            AuthError::MissingAuth => Self::MissingHeader,
+            AuthError::InvalidToken => Self::InvalidSignature,
+            AuthError::Expired => Self::Expired,
+            AuthError::InvalidClaims(_) => Self::InvalidClaims,
+            AuthError::RoleNotPermitted(_) => Self::WrongRole,
+        }
+    }
+}
+
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
         event!(
@@ -94,10 +161,24 @@ impl IntoResponse for AuthError {
           Level::ERROR,
           "Autorization failed: {self}"
         );
-        let body = Json(json!({
-            "error": "not authorized",
-        }));
-        (StatusCode::FORBIDDEN, body).into_response()
+        let reason = errors::AuthError::from(&self);
+        let error = ApiError::from(reason);
+        let mut response = (error.status, Json(error.envelope(None))).into_response();
+        // Requests without a valid token are challenged for one while a
+        // valid token without the required role is forbidden.
+        if let Some(challenge) = reason.challenge() {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+        }
+        let event = match self {
+            Self::MissingAuth => AuditEvent::AuthFailure("missing_header"),
+            Self::InvalidToken => AuditEvent::AuthFailure("invalid_token"),
+            Self::Expired => AuditEvent::AuthFailure("expired"),
+            Self::InvalidClaims(_) => AuditEvent::AuthFailure("invalid_claims"),
+            Self::RoleNotPermitted(_) => AuditEvent::Forbidden,
+        };
+        event.attach(response)
     }
 }
 
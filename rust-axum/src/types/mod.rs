@@ -1,5 +1,6 @@
 /*!
  Data Types.
 */
+pub mod context;
 pub mod handler;
 pub mod jwt;
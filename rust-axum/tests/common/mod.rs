@@ -3,19 +3,24 @@ use axum::{
     http::Response,
     Router,
 };
+use bootstrap::token::{hash_password, Accounts, TokenIssuer, TokenSettings};
 use rust_axum::{
-    arguments::{test_jwt, AppConfig},
+    arguments::{test_jwt, AppConfig, AppState, Settings},
     build_app,
+    downloads::DownloadTracker,
+    security::signing::{ResponseSigner, SignedClients},
     types::jwt::Role,
 };
 use serde::Deserialize;
 use std::{
     fmt::Debug,
+    num::NonZeroU32,
     sync::{Arc, Once},
 };
 use test_persist::TestPersistence;
 use tracing::debug;
 use tracing_subscriber::EnvFilter;
+use user_persist::{clock::Clock, jobs::JobQueue, persistence::UserPersistence};
 
 pub mod test_persist;
 
@@ -37,12 +42,142 @@ static SECRET: &[u8] = "TEST_SECRET".as_bytes();
 
 /// Build test Router.
 pub fn app(persistence: Option<Arc<TestPersistence>>) -> Router {
-    init_log();
-    let persist = match persistence {
-        Some(p) => p,
-        None => Arc::new(TestPersistence::new()),
-    };
-    build_app(persist, AppConfig::test(SECRET))
+    match persistence {
+        Some(persistence) => TestApp::default().persistence(persistence),
+        None => TestApp::default(),
+    }
+    .build()
+}
+
+/// Builder of a test Router over an [`AppState`]. Every part not set
+/// keeps its test default.
+#[derive(Default)]
+pub struct TestApp {
+    persistence: Option<Arc<dyn UserPersistence>>,
+    settings: Option<Settings>,
+    clock: Option<Arc<dyn Clock>>,
+    signer: Option<ResponseSigner>,
+    signed_clients: Option<SignedClients>,
+    jobs: Option<Arc<dyn JobQueue>>,
+    downloads: Option<DownloadTracker>,
+    token_issuer: Option<TokenIssuer<Role>>,
+}
+
+impl TestApp {
+    /// Store users in `persistence`.
+    pub fn persistence(self, persistence: Arc<dyn UserPersistence>) -> Self {
+        Self {
+            persistence: Some(persistence),
+            ..self
+        }
+    }
+
+    /// Use `settings`.
+    pub fn settings(self, settings: Settings) -> Self {
+        Self {
+            settings: Some(settings),
+            ..self
+        }
+    }
+
+    /// Read the time from `clock`.
+    pub fn clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock: Some(clock),
+            ..self
+        }
+    }
+
+    /// Sign response bodies with `signer`.
+    pub fn signer(self, signer: ResponseSigner) -> Self {
+        Self {
+            signer: Some(signer),
+            ..self
+        }
+    }
+
+    /// Verify the requests of `clients`.
+    pub fn signed_clients(self, clients: SignedClients) -> Self {
+        Self {
+            signed_clients: Some(clients),
+            ..self
+        }
+    }
+
+    /// Enqueue background jobs in `jobs`.
+    pub fn jobs(self, jobs: Arc<dyn JobQueue>) -> Self {
+        Self {
+            jobs: Some(jobs),
+            ..self
+        }
+    }
+
+    /// Register streamed downloads with `downloads`.
+    pub fn downloads(self, downloads: DownloadTracker) -> Self {
+        Self {
+            downloads: Some(downloads),
+            ..self
+        }
+    }
+
+    /// Serve logins of `username` with `password` as an admin.
+    pub fn accounts(self, username: &str, password: &str) -> Self {
+        let hash = hash_password(password, NonZeroU32::new(1).unwrap()).unwrap();
+        let accounts = format!(r#"{{"{username}": {{"role": "Admin", "password": "{hash}"}}}}"#);
+        let issuer = TokenIssuer::new(
+            Accounts::from_json(accounts.as_bytes()).unwrap(),
+            TokenSettings::default(),
+        );
+        Self {
+            token_issuer: Some(issuer),
+            ..self
+        }
+    }
+
+    /// Config of the parts issuing and checking tokens.
+    fn token_config(&self) -> AppConfig {
+        let config = AppConfig::test(SECRET);
+        let config = match &self.settings {
+            Some(settings) => config.with_settings(settings.clone()).unwrap(),
+            None => config,
+        };
+        match &self.clock {
+            Some(clock) => config.with_clock(clock.clone()),
+            None => config,
+        }
+    }
+
+    /// Authorization header for `role` issued at the current time of the
+    /// clock.
+    pub fn jwt(&self, role: Role) -> String {
+        format!("Bearer {}", test_jwt(&self.token_config(), role))
+    }
+
+    /// Build the test Router.
+    pub fn build(self) -> Router {
+        init_log();
+        let mut config = self.token_config();
+        if let Some(signer) = self.signer {
+            config = config.with_signer(signer);
+        }
+        if let Some(clients) = self.signed_clients {
+            config = config.with_signed_clients(clients);
+        }
+        let persistence = self
+            .persistence
+            .unwrap_or_else(|| Arc::new(TestPersistence::new()));
+        let mut state = AppState::new(persistence, config);
+        if let Some(jobs) = self.jobs {
+            state = state.with_jobs(jobs);
+        }
+        if let Some(downloads) = self.downloads {
+            state = state.with_downloads(downloads);
+        }
+        if let Some(issuer) = self.token_issuer {
+            state = state.with_token_issuer(issuer);
+        }
+        build_app(state)
+    }
 }
 
 /// Add an authorization header token value for given role.
@@ -61,7 +196,6 @@ where
 }
 
 /// Consume the body and return it as a String.
-#[allow(dead_code)]
 pub async fn body_as_str<T>(response: Response<T>) -> String
 where
     T: HttpBody,
@@ -74,7 +208,6 @@ where
 }
 
 /// Consume and print the body.
-#[allow(dead_code)]
 pub async fn dump_result<T>(response: Response<T>)
 where
     T: HttpBody,
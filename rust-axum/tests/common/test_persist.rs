@@ -5,9 +5,13 @@ use std::{collections::HashMap, ops::Deref, sync::Arc, sync::RwLock};
 use user_persist::persistence::PersistenceResult;
 use user_persist::{
     persistence::{PersistenceError, UserPersistence},
-    types::{Email, Gender, UpdateUser, User, UserKey, UserSearch},
+    stats::{StatsDate, StatsSnapshot},
+    types::{BulkUpdate, BulkUpdateResult, Email, Gender, UpdateUser, User, UserKey, UserSearch},
 };
 
+/// Searching for this name fails with a timeout.
+pub const SLOW_QUERY_NAME: &str = "Slow Query";
+
 /// Create a test user.
 pub fn test_user(id: Option<UserKey>) -> User {
     User {
@@ -16,6 +20,10 @@ pub fn test_user(id: Option<UserKey>) -> User {
         email: Email(String::from("test@test.com")),
         age: 100,
         gender: Gender::Male,
+        phone: None,
+        address: None,
+        created_at: None,
+        updated_at: None,
     }
 }
 
@@ -54,10 +62,18 @@ impl UserPersistence for TestPersistence {
         Ok(user)
     }
 
+    async fn get_user_by_email(&self, email: &Email) -> PersistenceResult<Option<User>> {
+        let m = self.read().unwrap();
+        let user = m
+            .values()
+            .find(|u| u.email.eq_ignore_ascii_case(email))
+            .map(|u| u.to_owned());
+        Ok(user)
+    }
+
     async fn save_user(&self, user: &User) -> Result<User, PersistenceError> {
-        let oid = ObjectId::new().to_string();
         let mut updated_user = user.clone();
-        let user_key = UserKey(oid);
+        let user_key = UserKey::from(ObjectId::new());
         updated_user.id = Some(user_key.clone());
 
         {
@@ -77,28 +93,95 @@ impl UserPersistence for TestPersistence {
         Ok(())
     }
 
+    async fn update_user_if_unchanged(
+        &self,
+        user: &UpdateUser,
+        current: &User,
+    ) -> PersistenceResult<bool> {
+        let mut m = self.write().unwrap();
+        match m.get_mut(&user.id) {
+            Some(old_user) if old_user == current => {
+                old_user.name.clone_from(&user.name);
+                old_user.age = user.age;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
     async fn remove_user(&self, user: &UserKey) -> PersistenceResult<()> {
         let mut m = self.write().unwrap();
         m.remove(user);
         Ok(())
     }
 
-    async fn search_users(&self, _user_search: &UserSearch) -> Result<Vec<User>, PersistenceError> {
+    async fn search_users(
+        &self,
+        user_search: &UserSearch,
+        _limit: u64,
+    ) -> Result<Vec<User>, PersistenceError> {
+        if user_search.name.as_deref() == Some(SLOW_QUERY_NAME) {
+            return Err(PersistenceError::Timeout);
+        }
         Ok(vec![test_user(Some(
             "61c0d1954c6b974ca7000000".parse().unwrap(),
         ))])
     }
 
+    async fn bulk_update(
+        &self,
+        update: &BulkUpdate,
+        limit: u64,
+        dry_run: bool,
+    ) -> PersistenceResult<BulkUpdateResult> {
+        let mut m = self.write().unwrap();
+        let mut matched = m
+            .values_mut()
+            .filter(|u| update.search.gender.as_ref().is_none_or(|g| g == &u.gender))
+            .filter(|u| update.search.name.as_ref().is_none_or(|n| n == &u.name))
+            .collect::<Vec<_>>();
+        let count = matched.len() as u64;
+        if count > limit {
+            return Err(PersistenceError::BulkLimitExceeded {
+                matched: count,
+                limit,
+            });
+        }
+        if !dry_run {
+            matched.iter_mut().for_each(|u| update.set.apply(u));
+        }
+        Ok(BulkUpdateResult {
+            matched: count,
+            modified: if dry_run { 0 } else { count },
+            dry_run,
+        })
+    }
+
     async fn count_genders(&self) -> Result<Vec<Value>, PersistenceError> {
         Ok(vec![
             json!({
-                "_id": "Male",
+                "gender": "Male",
                 "count": 6
             }),
             json!({
-                "_id": "Female",
+                "gender": "Female",
                 "count": 12
             }),
         ])
     }
+
+    async fn save_stats_snapshot(&self, _snapshot: &StatsSnapshot) -> PersistenceResult<()> {
+        Ok(())
+    }
+
+    async fn stats_history(
+        &self,
+        from: Option<StatsDate>,
+        _to: Option<StatsDate>,
+    ) -> PersistenceResult<Vec<StatsSnapshot>> {
+        Ok(vec![StatsSnapshot {
+            date: from.unwrap_or_else(|| "2024-03-05".parse().unwrap()),
+            counts: vec![json!({"gender": "Male", "count": 6})],
+        }])
+    }
 }
@@ -0,0 +1,95 @@
+use crate::common::{add_jwt, app, MIME_JSON};
+use axum::{
+    body::{Body, BoxBody},
+    http::{
+        header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE},
+        Method, Request, Response, StatusCode,
+    },
+};
+use flate2::read::GzDecoder;
+use rust_axum::{
+    arguments::AppConfig,
+    security::hashing::{Hashable, HashedUser},
+    types::jwt::Role,
+};
+use serde::de::DeserializeOwned;
+use std::io::Read;
+use tower::ServiceExt;
+use user_persist::types::{Email, UserSearch};
+
+pub mod common;
+
+/// Decompress a gzip encoded response body and deserialize it.
+async fn gzip_body_as<T: DeserializeOwned>(response: Response<BoxBody>) -> T {
+    assert_eq!(
+        response.headers().get(CONTENT_ENCODING).unwrap(),
+        "gzip",
+        "response was not compressed"
+    );
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let mut json = String::new();
+    GzDecoder::new(&bytes[..])
+        .read_to_string(&mut json)
+        .unwrap();
+    serde_json::from_str(&json).unwrap()
+}
+
+/// Check the hash in a hashed user matches the hash of its user.
+fn assert_hash_valid(hashed: &HashedUser) {
+    let prefix_config = AppConfig::test(b"TEST_SECRET");
+    assert_eq!(
+        hashed.hash_id,
+        hashed.user.hash(prefix_config.hash_prefix()).hash_id
+    );
+}
+
+#[tokio::test]
+async fn get_user_gzip() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .header(ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let user = gzip_body_as::<HashedUser>(response).await;
+    assert_eq!(
+        &user.hash_id,
+        "LCZLrq1TUum5LmbwzIoopIolNqLGv8iewjdsu7/49G8="
+    );
+    assert_hash_valid(&user);
+}
+
+#[tokio::test]
+async fn search_users_gzip() {
+    let search = UserSearch {
+        email: Some(Email("test@test.com".to_owned())),
+        name: None,
+        gender: None,
+        ..Default::default()
+    };
+
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/search")
+                .method(Method::POST)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .header(ACCEPT_ENCODING, "gzip")
+                .body(Body::from(serde_json::to_string(&search).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let users = gzip_body_as::<Vec<HashedUser>>(response).await;
+    users.iter().for_each(assert_hash_valid);
+}
@@ -0,0 +1,221 @@
+use crate::common::{add_jwt, app, body_as, body_as_str, test_persist::TestPersistence, TestApp};
+use axum::{
+    body::Body,
+    http::{
+        header::{ACCEPT_RANGES, AUTHORIZATION, CONTENT_RANGE, RANGE},
+        Method, Request, StatusCode,
+    },
+    Router,
+};
+use rust_axum::{
+    arguments::{AppConfig, Settings},
+    jobs::{ExportJobHandler, JobSettings, JobWorker, EXPORT_JOB},
+    types::jwt::Role,
+};
+use serde_json::Value;
+use std::{path::PathBuf, sync::Arc};
+use tower::ServiceExt;
+use user_persist::{
+    export::{manifest_path, FormatRegistry},
+    jobs::MemoryJobQueue,
+};
+
+pub mod common;
+
+const EXPORT: &str = r#"[{"name":"Test User"},{"name":"Other User"}]"#;
+
+/// Write an export artifact to a file unique to the test.
+fn materialize_export(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rust-axum-{name}-{}.json", std::process::id()));
+    std::fs::write(&path, EXPORT).unwrap();
+    path
+}
+
+fn export_app(path: PathBuf) -> Router {
+    let mut settings = Settings::default();
+    settings.export.file = Some(path);
+    TestApp::default().settings(settings).build()
+}
+
+fn download_request(range: Option<&str>) -> Request<Body> {
+    let builder = Request::builder()
+        .uri("/api/v1/user/download")
+        .header(AUTHORIZATION, add_jwt(Role::Admin));
+    match range {
+        Some(range) => builder.header(RANGE, range),
+        None => builder,
+    }
+    .body(Body::empty())
+    .unwrap()
+}
+
+#[tokio::test]
+async fn download_export() {
+    let path = materialize_export("full");
+    let response = export_app(path.clone())
+        .oneshot(download_request(None))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(ACCEPT_RANGES).unwrap(), "bytes");
+    assert_eq!(body_as_str(response).await, EXPORT);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn download_export_range() {
+    let path = materialize_export("range");
+    let response = export_app(path.clone())
+        .oneshot(download_request(Some("bytes=1-20")))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers().get(CONTENT_RANGE).unwrap(),
+        &format!("bytes 1-20/{}", EXPORT.len())
+    );
+    assert_eq!(body_as_str(response).await, &EXPORT[1..=20]);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn download_export_range_not_satisfiable() {
+    let path = materialize_export("unsatisfiable");
+    let response = export_app(path.clone())
+        .oneshot(download_request(Some("bytes=1000-2000")))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn download_not_materialized() {
+    // Without an export or a database to stream from there is nothing to
+    // download.
+    let response = app(None).oneshot(download_request(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn download_format() {
+    let download = |query: &str| {
+        Request::builder()
+            .uri(format!("/api/v1/user/download{query}"))
+            .header(AUTHORIZATION, add_jwt(Role::Admin))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let path = materialize_export("format");
+    let response = export_app(path.clone())
+        .oneshot(download("?format=xml"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // The materialized export is a JSON array so other formats are streamed
+    // from the database.
+    let response = export_app(path.clone())
+        .oneshot(download("?format=csv"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn export_job_manifest() {
+    let path = std::env::temp_dir().join(format!("rust-axum-export-job-{}", std::process::id()));
+    let persist = Arc::new(TestPersistence::new());
+    let queue = Arc::new(MemoryJobQueue::default());
+    let request = |method, uri: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, add_jwt(Role::Admin))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app(None)
+        .oneshot(request(Method::POST, "/api/v1/admin/exports"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let mut settings = Settings::default();
+    settings.export.file = Some(path.clone());
+    let app = TestApp::default()
+        .persistence(persist.clone())
+        .jobs(queue.clone())
+        .settings(settings)
+        .build();
+    let response = app
+        .clone()
+        .oneshot(request(Method::POST, "/api/v1/admin/exports?format=xml"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app
+        .clone()
+        .oneshot(request(Method::POST, "/api/v1/admin/exports?format=csv"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let queued = body_as::<Value>(response).await;
+    let id = queued["id"].as_str().unwrap();
+
+    let worker = JobWorker::new(queue, JobSettings::default()).with_handler(
+        EXPORT_JOB,
+        ExportJobHandler {
+            persist,
+            formats: FormatRegistry::default(),
+            path: path.clone(),
+            clock: AppConfig::test(b"secret").clock().clone(),
+        },
+    );
+    assert!(worker.run_next().await.unwrap());
+
+    let response = app
+        .clone()
+        .oneshot(request(Method::GET, &format!("/api/v1/admin/jobs/{id}")))
+        .await
+        .unwrap();
+    let done = body_as::<Value>(response).await;
+    assert_eq!(done["state"], "succeeded");
+    let manifest = &done["result"];
+    assert_eq!(manifest["format"], "csv");
+    assert_eq!(manifest["recordCount"], 1);
+    assert_eq!(
+        manifest["schemaVersion"],
+        user_persist::schema::SchemaRegistry::default().current_version()
+    );
+    assert_eq!(
+        manifest["size"].as_u64(),
+        Some(std::fs::metadata(&path).unwrap().len())
+    );
+    assert_eq!(manifest["sha256"].as_str().unwrap().len(), 64);
+
+    // The artifact is only served in the format of its manifest.
+    let response = app
+        .clone()
+        .oneshot(request(Method::GET, "/api/v1/user/download?format=csv"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+    assert!(body_as_str(response).await.starts_with("id,name,age,email"));
+    let response = app
+        .oneshot(request(Method::GET, "/api/v1/user/download"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    std::fs::remove_file(manifest_path(&path)).unwrap();
+    std::fs::remove_file(path).unwrap();
+}
@@ -1,21 +1,60 @@
 use crate::common::{
-    add_jwt, app, body_as, body_as_str, dump_result, test_persist::test_user, MIME_JSON,
-    TEST_TARGET,
+    add_jwt, app, body_as, body_as_str, dump_result,
+    test_persist::{test_user, TestPersistence, SLOW_QUERY_NAME},
+    TestApp, MIME_JSON, TEST_TARGET,
 };
+use api_types::DownloadResponse;
 use axum::{
     body::Body,
     http::{
-        header::{AUTHORIZATION, CONTENT_TYPE},
+        header::{ACCEPT, ALLOW, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, WWW_AUTHENTICATE},
         Method, Request, StatusCode,
     },
+    Router,
 };
-use rust_axum::{security::hashing::HashedUser, types::jwt::Role};
+use bootstrap::{
+    conditional::Preconditions,
+    route_policy::RoutePolicy,
+    token::{TokenResponse, LOGIN_PATH, REFRESH_PATH, TOKEN_ERROR_LABEL},
+    DevTokenResponse, DEV_TOKEN_PATH,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures::{stream, StreamExt};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use rust_axum::{
+    arguments::Settings,
+    downloads::DownloadTracker,
+    jobs::{ImportJobHandler, JobSettings, JobWorker, IMPORT_JOB},
+    middleware::{contract::ContractMode, json_api::JSON_API, RequestIdFormat},
+    security::{
+        hashing::HashedUser,
+        signing::{
+            request_payload, ResponseSigner, SignedClients, CLIENT_ID_HEADER,
+            REQUEST_TOLERANCE_SECS, SIGNATURE_HEADER, SIGNATURE_TIMESTAMP_HEADER,
+        },
+    },
+    types::jwt::Role,
+    REQ_ID_HEADER,
+};
+use secrecy::SecretString;
 use serde_json::{from_str, json, to_string, Value};
+use std::sync::Arc;
 use tower::ServiceExt;
 use tracing::debug;
-use user_persist::types::{Email, UpdateUser, User, UserKey, UserSearch};
+use user_persist::{
+    clock::MockClock,
+    email::EmailNormalizer,
+    jobs::MemoryJobQueue,
+    memory::MemoryPersistence,
+    patch::Patch,
+    persistence::UserPersistence,
+    types::{
+        BulkUpdateResult, Email, EmailValidation, Gender, KeyFormat, SearchPage, UpdateUser, User,
+        UserKey, UserSearch,
+    },
+};
 
-mod common;
+pub mod common;
 
 #[tokio::test]
 async fn get_user() {
@@ -32,7 +71,199 @@ async fn get_user() {
 
     assert_eq!(response.status(), StatusCode::OK);
     let user = body_as::<HashedUser>(response).await;
-    assert_eq!(&user.hid, "LCZLrq1TUum5LmbwzIoopIolNqLGv8iewjdsu7/49G8=")
+    assert_eq!(
+        &user.hash_id,
+        "LCZLrq1TUum5LmbwzIoopIolNqLGv8iewjdsu7/49G8="
+    )
+}
+
+#[tokio::test]
+async fn get_user_json_api() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .header(ACCEPT, JSON_API)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], JSON_API);
+    let document = body_as::<Value>(response).await;
+    assert_eq!(document["data"]["type"], json!("users"));
+    assert_eq!(document["data"]["id"], json!("61c0d1954c6b974ca7000000"));
+    assert_eq!(
+        document["data"]["attributes"]["hashId"],
+        json!("LCZLrq1TUum5LmbwzIoopIolNqLGv8iewjdsu7/49G8=")
+    );
+    assert_eq!(
+        document["data"]["links"]["self"],
+        json!("/api/v1/user/61c0d1954c6b974ca7000000")
+    );
+}
+
+#[tokio::test]
+async fn get_user_fields() {
+    let get_user = |fields: &str| {
+        app(None).oneshot(
+            Request::builder()
+                .uri(format!(
+                    "/api/v1/user/61c0d1954c6b974ca7000000?fields={fields}"
+                ))
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let response = get_user("name,email").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let user = body_as::<Value>(response).await;
+    let mut fields = user.as_object().unwrap().keys().collect::<Vec<_>>();
+    fields.sort();
+    assert_eq!(fields, vec!["email", "hashId", "id", "name"]);
+
+    let response = get_user("name,password").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body_as::<Value>(response).await["label"],
+        json!("fields.invalid")
+    );
+}
+
+#[tokio::test]
+async fn get_user_by_email() {
+    let get_user = |email: &str| {
+        app(None).oneshot(
+            Request::builder()
+                .uri(format!("/api/v1/user/by-email/{email}"))
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let response = get_user("Test@Test.com").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let user = body_as::<HashedUser>(response).await;
+    assert_eq!(
+        &user.hash_id,
+        "LCZLrq1TUum5LmbwzIoopIolNqLGv8iewjdsu7/49G8="
+    );
+
+    let response = get_user("nobody@test.com").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn get_user_token_expiry() {
+    let clock = Arc::new(MockClock::new(
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+    ));
+    let app = TestApp::default().clock(clock.clone());
+    let auth = app.jwt(Role::Admin);
+    let app = app.build();
+    let get_user = || {
+        app.clone().oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .header(AUTHORIZATION, auth.clone())
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    // Tokens expire after 25 minutes and are accepted for a minute longer.
+    clock.advance(Duration::minutes(26));
+    assert_eq!(get_user().await.unwrap().status(), StatusCode::OK);
+
+    clock.advance(Duration::seconds(1));
+    let response = get_user().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        response.headers().get(WWW_AUTHENTICATE).unwrap(),
+        r#"Bearer error="invalid_token""#
+    );
+}
+
+#[tokio::test]
+async fn get_user_token_audience() {
+    let mut settings = Settings::default();
+    settings.claims.audience = Some("users".to_owned());
+    let response = TestApp::default()
+        .settings(settings)
+        .build()
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(body_as::<Value>(response).await["reason"], "invalid_claims");
+}
+
+#[tokio::test]
+async fn get_user_missing_token() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+}
+
+#[tokio::test]
+async fn route_policy() {
+    let route_policy = RoutePolicy::from_json(
+        br#"{"GET /api/v1/user/{id}": ["Admin", "User"], "GET /api/v1/admin/anomalies": []}"#,
+    )
+    .unwrap();
+    let app = TestApp::default()
+        .settings(Settings {
+            route_policy,
+            ..Settings::default()
+        })
+        .build();
+    let get = |uri: &str, role| {
+        Request::builder()
+            .uri(uri)
+            .header(AUTHORIZATION, add_jwt(role))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let user = "/api/v1/user/61c0d1954c6b974ca7000000";
+    for role in [Role::Admin, Role::User] {
+        let response = app.clone().oneshot(get(user, role)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = app
+        .clone()
+        .oneshot(get("/api/v1/admin/anomalies", Role::Admin))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    // Routes the policy doesn't list keep the role of their handler.
+    let response = app
+        .oneshot(get("/api/v1/user/counts", Role::User))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
 }
 
 #[tokio::test]
@@ -90,6 +321,108 @@ async fn save_user() {
     assert!(saved_user.id.is_some());
 }
 
+#[tokio::test]
+async fn contract_validation() {
+    let save_user = |body: Value| {
+        Request::builder()
+            .uri("/api/v1/user")
+            .method(Method::POST)
+            .header(CONTENT_TYPE, MIME_JSON)
+            .header(AUTHORIZATION, add_jwt(Role::User))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let user = json!({
+        "name": "Test User",
+        "age": 100,
+        "email": "test@test.com",
+        "gender": "Male",
+    });
+    let mut diverging = user.clone();
+    diverging["nickname"] = json!("Test");
+
+    let app = TestApp::default()
+        .settings(Settings {
+            contract: ContractMode::Enforce,
+            ..Settings::default()
+        })
+        .build();
+    let response = app.clone().oneshot(save_user(user)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(body_as::<HashedUser>(response).await.user.id.is_some());
+
+    let response = app.oneshot(save_user(diverging.clone())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error = body_as::<Value>(response).await;
+    assert_eq!(error["label"], "contract.request");
+    assert!(error["message"].as_str().unwrap().contains("$.nickname"));
+
+    // Divergences are only logged unless enforced.
+    let app = TestApp::default()
+        .settings(Settings {
+            contract: ContractMode::Log,
+            ..Settings::default()
+        })
+        .build();
+    let response = app.oneshot(save_user(diverging)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn body_read_timeout() {
+    let mut settings = Settings::default();
+    settings.body_read.routes.insert(
+        "/api/v1/user".to_owned(),
+        std::time::Duration::from_millis(50),
+    );
+    let app = TestApp::default().settings(settings).build();
+
+    // The body stalls after its first chunk.
+    let body =
+        stream::iter([Ok::<_, std::io::Error>(r#"{"name": "Test"#)]).chain(stream::pending());
+    let request = Request::builder()
+        .uri("/api/v1/user")
+        .method(Method::POST)
+        .header(CONTENT_TYPE, MIME_JSON)
+        .header(AUTHORIZATION, add_jwt(Role::User))
+        .body(Body::wrap_stream(body))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    let error = body_as::<Value>(response).await;
+    assert_eq!(error["label"], "body.timeout");
+    assert!(error["requestId"].is_string());
+}
+
+#[tokio::test]
+async fn save_user_content_type() {
+    let save_user = |content_type: Option<&str>| {
+        let mut request = Request::builder()
+            .uri("/api/v1/user")
+            .method(Method::POST)
+            .header(AUTHORIZATION, add_jwt(Role::User));
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        let json_user = serde_json::to_string(&test_user(None)).unwrap();
+        app(None).oneshot(request.body(Body::from(json_user)).unwrap())
+    };
+
+    let response = save_user(Some("application/json; charset=utf-8"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    for content_type in [None, Some("text/plain")] {
+        let response = save_user(content_type).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            body_as::<Value>(response).await["label"],
+            json!("unsupported.media_type")
+        );
+    }
+}
+
 #[tokio::test]
 async fn save_user_validation_rejection() {
     let json_user = r#"{
@@ -121,13 +454,13 @@ async fn save_user_validation_rejection() {
     debug!(target: TEST_TARGET, "json errors {body}");
 
     let email_validation_code = validation_errors
-        .get("validation_errors")
+        .get("validationErrors")
         .and_then(|v| v.get("email"))
         .and_then(|v| v.get(0))
         .and_then(|v| v.get("code"));
 
     let age_validation_code = validation_errors
-        .get("validation_errors")
+        .get("validationErrors")
         .and_then(|v| v.get("age"))
         .and_then(|v| v.get(0))
         .and_then(|v| v.get("code"));
@@ -139,11 +472,13 @@ async fn save_user_validation_rejection() {
 #[tokio::test]
 async fn update_user() {
     let update_user = UpdateUser {
-        id: UserKey("fakekey".into()),
+        id: "fakekey".parse().unwrap(),
         name: "New Name".into(),
         email: Email("test@test.com".into()),
         age: 100,
-        hid: "xBS6Bfv589WArC5A3psqFZRv/sPe8thJqRHBaipYsho=".into(),
+        hash_id: "xBS6Bfv589WArC5A3psqFZRv/sPe8thJqRHBaipYsho=".into(),
+        phone: Patch::Absent,
+        address: Patch::Absent,
     };
 
     let update_user_json = to_string(&update_user).unwrap();
@@ -171,11 +506,13 @@ async fn update_user() {
 #[tokio::test]
 async fn update_user_bad_hash() {
     let update_user = UpdateUser {
-        id: UserKey("fakekey".into()),
+        id: "fakekey".parse().unwrap(),
         name: "New Name".into(),
         email: Email("test@test.com".into()),
         age: 100,
-        hid: "invalid_hash".into(),
+        hash_id: "invalid_hash".into(),
+        phone: Patch::Absent,
+        address: Patch::Absent,
     };
 
     let update_user_json = to_string(&update_user).unwrap();
@@ -204,12 +541,73 @@ async fn update_user_bad_hash() {
     );
 }
 
+#[tokio::test]
+async fn update_user_if_match() {
+    let update_user = |app: Router, if_match: Option<&str>| {
+        let update_user = UpdateUser {
+            id: "61c0d1954c6b974ca7000000".parse().unwrap(),
+            name: "New Name".into(),
+            email: Email("test@test.com".into()),
+            age: 100,
+            hash_id: "xBS6Bfv589WArC5A3psqFZRv/sPe8thJqRHBaipYsho=".into(),
+            phone: Patch::Absent,
+            address: Patch::Absent,
+        };
+        let mut request = Request::builder()
+            .uri("/api/v1/user")
+            .method(Method::PUT)
+            .header(CONTENT_TYPE, MIME_JSON)
+            .header(AUTHORIZATION, add_jwt(Role::Admin));
+        if let Some(if_match) = if_match {
+            request = request.header(IF_MATCH, if_match);
+        }
+        app.oneshot(
+            request
+                .body(Body::from(to_string(&update_user).unwrap()))
+                .unwrap(),
+        )
+    };
+
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let etag = response.headers().get(ETAG).unwrap().to_str().unwrap();
+
+    let response = update_user(app(None), Some(etag)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = update_user(app(None), Some("\"stale\"")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = update_user(app(None), None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let strict = || {
+        TestApp::default()
+            .settings(Settings {
+                preconditions: Preconditions { strict: true },
+                ..Settings::default()
+            })
+            .build()
+    };
+    let response = update_user(strict(), None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+    let response = update_user(strict(), Some(etag)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
 #[tokio::test]
 async fn search_users() {
     let search = UserSearch {
         email: Some(Email("test@test.com".to_owned())),
         name: None,
         gender: None,
+        ..Default::default()
     };
 
     let search_json = to_string(&search).unwrap();
@@ -232,18 +630,1532 @@ async fn search_users() {
 }
 
 #[tokio::test]
-async fn count_users() {
+async fn search_users_user_role() {
+    let search = |body: &'static str| {
+        Request::builder()
+            .uri("/api/v1/user/search")
+            .method(Method::POST)
+            .header(CONTENT_TYPE, MIME_JSON)
+            .header(AUTHORIZATION, add_jwt(Role::User))
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let response = app(None).oneshot(search("{}")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body_as::<Value>(response).await,
+        json!({
+            "label": "search.unfiltered",
+            "message": "Search requires at least one filter or all=true",
+            "requestId": null,
+        })
+    );
+
+    let response = app(None)
+        .oneshot(search(r#"{"name": "Test User"}"#))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn search_users_unfiltered() {
+    let search = |query: &str, role: Role, body: &'static str| {
+        Request::builder()
+            .uri(format!("/api/v1/user/search{query}"))
+            .method(Method::POST)
+            .header(CONTENT_TYPE, MIME_JSON)
+            .header(AUTHORIZATION, add_jwt(role))
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let response = app(None)
+        .oneshot(search("", Role::Admin, "{}"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app(None)
+        .oneshot(search("?all=true", Role::Admin, "{}"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app(None)
+        .oneshot(search("?all=true", Role::User, "{}"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app(None)
+        .oneshot(search("", Role::Admin, r#"{"nmae": "Test User"}"#))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body_as::<Value>(response).await["label"],
+        json!("json_parse.failed")
+    );
+}
+
+#[tokio::test]
+async fn query_users() {
+    let query = |q: &str| {
+        Request::builder()
+            .uri(format!("/api/v1/user?q={q}"))
+            .header(AUTHORIZATION, add_jwt(Role::User))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app(None)
+        .oneshot(query("gender:Male%20age%3C=100%20name~test"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let users = body_as::<Vec<Value>>(response).await;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0]["name"], json!("Test User"));
+
+    let response = app(None)
+        .oneshot(query("gender:Male+age%3E120"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_as::<Vec<Value>>(response).await, Vec::<Value>::new());
+
+    let response = app(None).oneshot(query("phone:555")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body_as::<Value>(response).await,
+        json!({
+            "label": "query.invalid",
+            "message": "Invalid query: Unknown field `phone`",
+            "requestId": null,
+        })
+    );
+}
+
+#[tokio::test]
+async fn search_users_stream() {
     let response = app(None)
         .oneshot(
             Request::builder()
-                .uri("/api/v1/user/counts")
+                .uri("/api/v1/user/search?stream=true")
+                .method(Method::POST)
+                .header(CONTENT_TYPE, MIME_JSON)
                 .header(AUTHORIZATION, add_jwt(Role::Admin))
-                .body(Body::empty())
+                .body(Body::from(r#"{"name": "Test User"}"#))
                 .unwrap(),
         )
         .await
         .unwrap();
 
     assert_eq!(response.status(), StatusCode::OK);
-    dump_result(response).await;
+    assert_eq!(
+        response.headers().get(CONTENT_TYPE).unwrap(),
+        "application/x-ndjson"
+    );
+    let body = body_as_str(response).await;
+    let users = body
+        .lines()
+        .map(|line| from_str::<HashedUser>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].user.name, "Test User");
+    assert!(body.ends_with('\n'));
+}
+
+#[tokio::test]
+async fn search_users_timeout() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/search")
+                .method(Method::POST)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::from(format!(r#"{{"name": "{SLOW_QUERY_NAME}"}}"#)))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    let body = body_as::<Value>(response).await;
+    assert_eq!(body["label"], "persistence.timeout");
+    assert_eq!(body["message"], "Database operation timed out");
+}
+
+#[tokio::test]
+async fn search_users_page_envelope() {
+    for (query, items, limit) in [("", 1, 20), ("?offset=1&limit=1000", 0, 100)] {
+        let response = app(None)
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/api/v1/user/search{query}"))
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, MIME_JSON)
+                    .header(AUTHORIZATION, add_jwt(Role::Admin))
+                    .header("prefer", "respond-async, page-envelope")
+                    .body(Body::from(r#"{"name": "Test User"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("preference-applied").unwrap(),
+            "page-envelope"
+        );
+        let page = body_as::<SearchPage<HashedUser>>(response).await;
+        assert_eq!(page.items.len(), items);
+        assert_eq!(page.total, 1);
+        assert!(!page.estimated);
+        assert_eq!(page.limit, limit);
+    }
+}
+
+#[tokio::test]
+async fn search_users_cursor() {
+    let persist = Arc::new(MemoryPersistence::new(EmailNormalizer::new(false)));
+    for name in ["Ann", "Bea", "Cat"] {
+        let user = User {
+            name: name.to_owned(),
+            email: Email(format!("{name}@test.com")),
+            gender: Gender::Female,
+            ..test_user(None)
+        };
+        persist.save_user(&user).await.unwrap();
+    }
+    let app = TestApp::default().persistence(persist.clone()).build();
+    let search = |query: String, body: &'static str| {
+        Request::builder()
+            .uri(format!("/api/v1/user/search{query}"))
+            .method(Method::POST)
+            .header(CONTENT_TYPE, MIME_JSON)
+            .header(AUTHORIZATION, add_jwt(Role::Admin))
+            .header("prefer", "page-envelope")
+            .body(Body::from(body))
+            .unwrap()
+    };
+    let female = r#"{"gender": "Female"}"#;
+
+    let response = app
+        .clone()
+        .oneshot(search("?limit=2".to_owned(), female))
+        .await
+        .unwrap();
+    let page = body_as::<SearchPage<HashedUser>>(response).await;
+    let names = page
+        .items
+        .iter()
+        .map(|u| u.user.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["Ann", "Bea"]);
+    let cursor = page.next_cursor.unwrap();
+
+    // A user added before the cursor's position doesn't shift the next page.
+    let early = User {
+        name: "Aby".to_owned(),
+        email: Email("aby@test.com".to_owned()),
+        gender: Gender::Female,
+        ..test_user(None)
+    };
+    persist.save_user(&early).await.unwrap();
+    let response = app
+        .clone()
+        .oneshot(search(format!("?limit=2&cursor={cursor}"), female))
+        .await
+        .unwrap();
+    let page = body_as::<SearchPage<HashedUser>>(response).await;
+    let names = page
+        .items
+        .iter()
+        .map(|u| u.user.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["Cat"]);
+    assert_eq!((page.offset, page.total), (2, 4));
+    assert_eq!(page.next_cursor, None);
+
+    // Cursors can't be edited or reused for another search.
+    for (cursor, body) in [
+        (format!("{cursor}x"), female),
+        (cursor.clone(), r#"{"gender": "Male"}"#),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(search(format!("?cursor={cursor}"), body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_as::<Value>(response).await["label"], "cursor.invalid");
+    }
+}
+
+#[tokio::test]
+async fn count_users() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/counts")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    dump_result(response).await;
+}
+
+#[tokio::test]
+async fn count_users_history() {
+    let history = |query: &str| {
+        Request::builder()
+            .uri(format!("/api/v1/user/counts/history{query}"))
+            .header(AUTHORIZATION, add_jwt(Role::Admin))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app(None)
+        .oneshot(history("?from=2024-03-01&to=2024-03-31"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_as::<Value>(response).await,
+        json!([{"date": "2024-03-01", "counts": [{"gender": "Male", "count": 6}]}])
+    );
+
+    let response = app(None).oneshot(history("?from=March")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn import_users() {
+    let import = |query: &str, content_type: &str, body: &'static str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("/api/v1/user/import{query}"))
+            .header(AUTHORIZATION, add_jwt(Role::Admin))
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    };
+    let app = TestApp::default()
+        .settings(Settings {
+            import_mapping: serde_json::from_value(json!({
+                "Full Name": "name",
+                "Years": "age",
+                "E-mail": "email",
+                "Sex": "gender",
+            }))
+            .unwrap(),
+            ..Settings::default()
+        })
+        .build();
+
+    let csv = "Full Name,Years,E-mail,Sex\n\
+               Imported User,100,imported@test.com,F\n\
+               Young User,20,young@test.com,M\n";
+    let response = app
+        .clone()
+        .oneshot(import("", "text/csv", csv))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let report = body_as::<Value>(response).await;
+    assert_eq!(report["imported"], 1);
+    assert_eq!(report["failed"], 1);
+    assert_eq!(report["errors"][0]["row"], 2);
+
+    // The mapping of the request must map every required field.
+    let response = app
+        .clone()
+        .oneshot(import(
+            "?mapping=%7B%22Name%22%3A%22name%22%7D",
+            "text/csv",
+            csv,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app
+        .oneshot(import("", "application/json", "[]"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn background_import() {
+    let persist = Arc::new(TestPersistence::new());
+    let queue = Arc::new(MemoryJobQueue::default());
+    let app = TestApp::default()
+        .persistence(persist.clone())
+        .jobs(queue.clone())
+        .build();
+    let job = |id: &str| {
+        Request::builder()
+            .uri(format!("/api/v1/admin/jobs/{id}"))
+            .header(AUTHORIZATION, add_jwt(Role::Admin))
+            .header("x-timezone", "Asia/Tokyo")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/api/v1/user/import?background=true")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .header(CONTENT_TYPE, "text/csv")
+                .body(Body::from(
+                    "name,age,email,gender,phone,address\n\
+                     Imported User,100,imported@test.com,Female,,\n",
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let queued = body_as::<Value>(response).await;
+    assert_eq!(queued["state"], "queued");
+    assert!(queued.get("payload").is_none());
+    let id = queued["id"].as_str().unwrap();
+
+    let worker = JobWorker::new(queue, JobSettings::default()).with_handler(
+        IMPORT_JOB,
+        ImportJobHandler {
+            persist,
+            email_validation: EmailValidation::Lenient,
+        },
+    );
+    assert!(worker.run_next().await.unwrap());
+    assert!(!worker.run_next().await.unwrap());
+
+    let response = app.clone().oneshot(job(id)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let done = body_as::<Value>(response).await;
+    assert_eq!(done["state"], "succeeded");
+    assert_eq!(done["attempts"], 1);
+    assert_eq!(done["result"]["imported"], 1);
+    assert!(done["createdAt"].as_str().unwrap().ends_with("+09:00"));
+
+    let response = app.oneshot(job("missing")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn request_context() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user")
+                .method(Method::POST)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .header(AUTHORIZATION, add_jwt(Role::User))
+                .header("accept-language", "es-MX, en;q=0.5")
+                .body(Body::from(
+                    json!({"name": "Test User", "age": 1, "email": "test@test.com", "gender": "Male"})
+                        .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = body_as::<Value>(response).await;
+    assert_eq!(
+        body["validationErrors"]["age"][0]["message"],
+        "debe ser al menos 100"
+    );
+
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/admin/jobs/missing")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .header("x-timezone", "Mars/Olympus")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body_as::<Value>(response).await["label"],
+        "timezone.invalid"
+    );
+}
+
+#[tokio::test]
+async fn admin_downloads() {
+    let downloads = DownloadTracker::default();
+    let app = TestApp::default().downloads(downloads.clone()).build();
+    let mut body = downloads.track(
+        "droberts".to_owned(),
+        "json".to_owned(),
+        1_700_000_000_000,
+        stream::iter([Ok(b"[".to_vec())])
+            .chain(stream::pending())
+            .boxed(),
+    );
+    body.next().await.unwrap().unwrap();
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/admin/downloads")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let listed = body_as::<Vec<DownloadResponse>>(response).await;
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].actor, "droberts");
+    assert_eq!(listed[0].bytes_sent, 1);
+    assert_eq!(
+        listed[0].started_at.as_deref(),
+        Some("2023-11-14T22:13:20.000Z")
+    );
+
+    let cancel = |id: &str, role: Role| {
+        Request::builder()
+            .uri(format!("/api/v1/admin/downloads/{id}"))
+            .method(Method::DELETE)
+            .header(AUTHORIZATION, add_jwt(role))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app
+        .clone()
+        .oneshot(cancel(&listed[0].id, Role::User))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = app
+        .clone()
+        .oneshot(cancel(&listed[0].id, Role::Admin))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(body.next().await.is_none());
+    drop(body);
+    assert!(downloads.list().is_empty());
+
+    let response = app.oneshot(cancel("missing", Role::Admin)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn sample_users() {
+    let sample = |role: Role| {
+        Request::builder()
+            .uri("/api/v1/user/sample?n=5")
+            .header(AUTHORIZATION, add_jwt(role))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app(None).oneshot(sample(Role::Admin)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_as::<Vec<HashedUser>>(response).await.len(), 1);
+
+    let response = app(None).oneshot(sample(Role::User)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn age_histogram() {
+    let histogram = |query: &str| {
+        Request::builder()
+            .uri(format!("/api/v1/user/age-histogram{query}"))
+            .header(AUTHORIZATION, add_jwt(Role::Admin))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app(None).oneshot(histogram("")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_as::<Value>(response).await,
+        json!([{"lower": 100, "upper": 110, "count": 1}])
+    );
+
+    let response = app(None).oneshot(histogram("?bucket=25")).await.unwrap();
+    assert_eq!(
+        body_as::<Value>(response).await,
+        json!([{"lower": 100, "upper": 125, "count": 1}])
+    );
+
+    let response = app(None).oneshot(histogram("?bucket=0")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn bulk_update_users() {
+    let persist = Arc::new(TestPersistence::new());
+    let app = app(Some(persist.clone()));
+    let update = json!({"search": {"gender": "Male"}, "set": {"age": 120}});
+    let bulk_update = |query: &str| {
+        Request::builder()
+            .uri(format!("/api/v1/user/bulk{query}"))
+            .method(Method::PUT)
+            .header(CONTENT_TYPE, MIME_JSON)
+            .header(AUTHORIZATION, add_jwt(Role::Admin))
+            .body(Body::from(update.to_string()))
+            .unwrap()
+    };
+    let key = "61c0d1954c6b974ca7000000".parse::<UserKey>().unwrap();
+
+    // Nothing is changed without confirmation.
+    let response = app.clone().oneshot(bulk_update("")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app
+        .clone()
+        .oneshot(bulk_update("?confirm=true&dryRun=true"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_as::<BulkUpdateResult>(response).await,
+        BulkUpdateResult {
+            matched: 1,
+            modified: 0,
+            dry_run: true
+        }
+    );
+    assert_eq!(persist.get_user(&key).await.unwrap().unwrap().age, 100);
+
+    let response = app.oneshot(bulk_update("?confirm=true")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_as::<BulkUpdateResult>(response).await,
+        BulkUpdateResult {
+            matched: 1,
+            modified: 1,
+            dry_run: false
+        }
+    );
+    assert_eq!(persist.get_user(&key).await.unwrap().unwrap().age, 120);
+}
+
+#[tokio::test]
+async fn bulk_update_users_empty_set() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/bulk?confirm=true")
+                .method(Method::PUT)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::from(r#"{"search": {}, "set": {}}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn aggregate_users_without_mongo() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/aggregate")
+                .method(Method::POST)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::from(r#"[{"$count": "total"}]"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Aggregation is only served by the mongodb backend.
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn check_integrity_without_mongo() {
+    let check = |role: Role| {
+        app(None).oneshot(
+            Request::builder()
+                .uri("/api/v1/admin/integrity?fix=true")
+                .header(AUTHORIZATION, add_jwt(role))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    assert_eq!(
+        check(Role::User).await.unwrap().status(),
+        StatusCode::FORBIDDEN
+    );
+    // Integrity checks are only served by the mongodb backend.
+    assert_eq!(
+        check(Role::Admin).await.unwrap().status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn ulid_request_ids() {
+    let settings = Settings {
+        request_id: RequestIdFormat::Ulid,
+        ..Settings::default()
+    };
+    let app = TestApp::default().settings(settings).build();
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/v1/unknown")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let id = response.headers()[REQ_ID_HEADER].to_str().unwrap();
+        ids.push(id.parse::<UserKey>().unwrap());
+    }
+
+    assert!(ids.iter().all(|id| id.format() == Some(KeyFormat::Ulid)));
+    assert!(ids[0] < ids[1]);
+}
+
+#[tokio::test]
+async fn unmatched_route() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/unknown")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response.headers().contains_key(REQ_ID_HEADER));
+
+    let body = body_as::<Value>(response).await;
+    assert_eq!(body.get("label"), Some(&json!("not.found")));
+    assert!(body.get("requestId").and_then(Value::as_str).is_some());
+}
+
+#[tokio::test]
+async fn method_not_allowed() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user")
+                .method(Method::PATCH)
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+    let allow = response
+        .headers()
+        .get(ALLOW)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    assert!(allow.contains("POST"));
+    assert!(allow.contains("PUT"));
+
+    let body = body_as::<Value>(response).await;
+    assert_eq!(body.get("label"), Some(&json!("method.not_allowed")));
+}
+
+#[tokio::test]
+async fn head_user() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .method(Method::HEAD)
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), MIME_JSON);
+    assert_eq!(body_as_str(response).await, "");
+}
+
+#[tokio::test]
+async fn options_user() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .method(Method::OPTIONS)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let allow = response.headers().get(ALLOW).unwrap().to_str().unwrap();
+    assert!(allow.contains("GET"), "{allow}");
+    assert!(allow.contains("HEAD"), "{allow}");
+    assert!(allow.contains("DELETE"), "{allow}");
+    assert!(allow.contains("OPTIONS"), "{allow}");
+}
+
+#[tokio::test]
+async fn list_anomalies() {
+    let update_user = UpdateUser {
+        id: "fakekey".parse().unwrap(),
+        name: "New Name".into(),
+        email: Email("test@test.com".into()),
+        age: 100,
+        hash_id: "xBS6Bfv589WArC5A3psqFZRv/sPe8thJqRHBaipYsho=".into(),
+        phone: Patch::Absent,
+        address: Patch::Absent,
+    };
+    let update_user_json = to_string(&update_user).unwrap();
+    let app = app(None);
+
+    // One more update than the default detector allows in its window.
+    for _ in 0..11 {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/v1/user")
+                    .method(Method::PUT)
+                    .header(CONTENT_TYPE, MIME_JSON)
+                    .header(AUTHORIZATION, add_jwt(Role::Admin))
+                    .body(Body::from(update_user_json.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/admin/anomalies")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let anomalies = body_as::<Vec<Value>>(response).await;
+    assert_eq!(anomalies.len(), 1);
+    assert_eq!(anomalies[0]["kind"], json!("frequent_updates"));
+    assert_eq!(anomalies[0]["key"], json!("fakekey"));
+    assert_eq!(anomalies[0]["count"], json!(11));
+}
+
+#[tokio::test]
+async fn validation_failures() {
+    let app = app(None);
+    for age in [1, 2] {
+        let user = json!({
+            "name": "Test User",
+            "age": age,
+            "email": "test@test.com",
+            "gender": "Male",
+        });
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/v1/user")
+                    .method(Method::POST)
+                    .header(CONTENT_TYPE, MIME_JSON)
+                    .header(AUTHORIZATION, add_jwt(Role::User))
+                    .body(Body::from(user.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/admin/validation-failures?limit=5")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let failures = body_as::<Value>(response).await;
+    assert_eq!(
+        failures,
+        json!([{"field": "age", "code": "range", "count": 2}])
+    );
+}
+
+#[tokio::test]
+async fn login_and_refresh() {
+    let app = TestApp::default().accounts("droberts", "secret").build();
+    let post = |uri: &str, body: Value| {
+        Request::builder()
+            .uri(uri)
+            .method(Method::POST)
+            .header(CONTENT_TYPE, MIME_JSON)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = app
+        .clone()
+        .oneshot(post(
+            LOGIN_PATH,
+            json!({"username": "droberts", "password": "guess"}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        body_as::<Value>(response).await["label"],
+        json!(TOKEN_ERROR_LABEL)
+    );
+
+    let response = app
+        .clone()
+        .oneshot(post(
+            LOGIN_PATH,
+            json!({"username": "droberts", "password": "secret"}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let login = body_as::<TokenResponse>(response).await;
+
+    // The access token authorizes admin requests.
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/admin/anomalies")
+                .header(AUTHORIZATION, format!("Bearer {}", login.access_token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let refresh = |token: &str| post(REFRESH_PATH, json!({ "refreshToken": token }));
+    let response = app
+        .clone()
+        .oneshot(refresh(&login.refresh_token))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let rotated = body_as::<TokenResponse>(response).await;
+    assert_ne!(rotated.refresh_token, login.refresh_token);
+
+    let response = app.oneshot(refresh(&login.refresh_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn login_disabled() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri(LOGIN_PATH)
+                .method(Method::POST)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .body(Body::from(
+                    r#"{"username": "droberts", "password": "secret"}"#,
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn rate_limit_headers() {
+    let mut settings = Settings::default();
+    settings.rate_limit.requests = 2;
+    let app = TestApp::default().settings(settings).build();
+    let get_user = |token: Option<String>| {
+        let request = Request::builder().uri("/api/v1/user/61c0d1954c6b974ca7000000");
+        match token {
+            Some(token) => request.header(AUTHORIZATION, token),
+            None => request,
+        }
+        .body(Body::empty())
+        .unwrap()
+    };
+    let header = |response: &axum::response::Response, name: &str| {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap().to_owned())
+    };
+
+    let mut remaining = Vec::new();
+    for _ in 0..3 {
+        let response = app
+            .clone()
+            .oneshot(get_user(Some(add_jwt(Role::Admin))))
+            .await
+            .unwrap();
+        // Requests over the limit are still served.
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "ratelimit-limit").as_deref(), Some("2"));
+        assert!(header(&response, "ratelimit-reset").is_some());
+        remaining.push(header(&response, "ratelimit-remaining").unwrap());
+    }
+    assert_eq!(remaining, ["1", "0", "0"]);
+
+    let response = app.oneshot(get_user(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(header(&response, "ratelimit-limit"), None);
+}
+
+#[tokio::test]
+async fn allocator_stats() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/debug/allocator")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    if cfg!(any(feature = "jemalloc", feature = "mimalloc")) {
+        assert_eq!(response.status(), StatusCode::OK);
+        let stats = body_as::<Value>(response).await;
+        assert!(stats["allocator"].is_string());
+        assert!(stats["resident"].as_u64().is_some());
+    } else {
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+async fn readyz() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/readyz")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_as::<Value>(response).await;
+    assert_eq!(body, json!({"ready": true, "tasks": []}));
+}
+
+#[tokio::test]
+async fn dev_token() {
+    let settings = Settings {
+        dev_tokens: true,
+        ..Settings::default()
+    };
+    let app = TestApp::default().settings(settings).build();
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(DEV_TOKEN_PATH)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .body(Body::from(
+                    json!({"role": "Admin", "sub": "dev", "ttl": 60}).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let minted = body_as::<DevTokenResponse>(response).await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .header(AUTHORIZATION, format!("Bearer {}", minted.token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn dev_token_invalid_ttl() {
+    let settings = Settings {
+        dev_tokens: true,
+        ..Settings::default()
+    };
+    let response = TestApp::default()
+        .settings(settings)
+        .build()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(DEV_TOKEN_PATH)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .body(Body::from(
+                    json!({"role": "User", "sub": "dev", "ttl": 0}).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn dev_token_disabled() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(DEV_TOKEN_PATH)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .body(Body::from(
+                    json!({"role": "Admin", "sub": "dev"}).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn audit_search() {
+    let app = app(None);
+    let id = "61c0d1954c6b974ca7000000";
+    let search = |query: &str, body: Value, role: Role| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("/api/v1/admin/audit/search{query}"))
+            .header(AUTHORIZATION, add_jwt(role))
+            .header(CONTENT_TYPE, MIME_JSON)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user")
+                .method(Method::POST)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .header(AUTHORIZATION, add_jwt(Role::User))
+                .body(Body::from(to_string(&test_user(None)).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!("/api/v1/user/{id}"))
+                .method(Method::DELETE)
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .clone()
+        .oneshot(search(
+            "?limit=1",
+            json!({"actor": "droberts"}),
+            Role::Admin,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = body_as::<Value>(response).await;
+    assert_eq!(page["total"], 2);
+    assert_eq!(page["items"].as_array().unwrap().len(), 1);
+
+    let response = app
+        .clone()
+        .oneshot(search("", json!({"targetUser": id}), Role::Admin))
+        .await
+        .unwrap();
+    let page = body_as::<Value>(response).await;
+    assert_eq!(page["total"], 1);
+    assert_eq!(page["items"][0]["operation"], "deleteUser");
+    assert_eq!(page["items"][0]["actor"], "droberts");
+
+    let response = app
+        .clone()
+        .oneshot(search(
+            "",
+            json!({"operation": "createUser", "toMs": 0}),
+            Role::Admin,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(body_as::<Value>(response).await["total"], 0);
+
+    let response = app
+        .clone()
+        .oneshot(search("", json!({"user": "droberts"}), Role::Admin))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app
+        .oneshot(search("", json!({}), Role::User))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn response_signing() {
+    let persist = Arc::new(TestPersistence::new());
+    let id = persist
+        .save_user(&test_user(None))
+        .await
+        .unwrap()
+        .id
+        .unwrap();
+    let seed = SecretString::new(base64::encode([3u8; 32]));
+    let signed = TestApp::default()
+        .persistence(persist)
+        .signer(ResponseSigner::from_seed(&seed).unwrap())
+        .build();
+    let get = |uri: String| {
+        Request::builder()
+            .uri(uri)
+            .header(AUTHORIZATION, add_jwt(Role::Admin))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = signed
+        .clone()
+        .oneshot(get("/api/v1/.well-known/signing-key".to_owned()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let key = body_as::<Value>(response).await;
+    assert_eq!(key["algorithm"], "Ed25519");
+    let public_key = UnparsedPublicKey::new(
+        &ED25519,
+        base64::decode(key["publicKey"].as_str().unwrap()).unwrap(),
+    );
+
+    let response = signed
+        .oneshot(get(format!("/api/v1/user/{id}")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let signature = base64::decode(response.headers()[SIGNATURE_HEADER].as_bytes()).unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(public_key.verify(&body, &signature).is_ok());
+
+    // Responses aren't signed unless a key is configured.
+    let response = app(None)
+        .oneshot(get("/api/v1/.well-known/signing-key".to_owned()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response.headers().get(SIGNATURE_HEADER).is_none());
+}
+
+/// Request saving a user, signed by `client` as `client_id` at
+/// `timestamp` for `method` `path` when given.
+fn signed_save(
+    client: Option<(&ResponseSigner, &str)>,
+    signed: (&str, &str, i64),
+    body: &str,
+) -> Request<Body> {
+    let mut request = Request::builder()
+        .uri("/api/v1/user")
+        .method(Method::POST)
+        .header(CONTENT_TYPE, MIME_JSON)
+        .header(AUTHORIZATION, add_jwt(Role::User));
+    if let Some((client, client_id)) = client {
+        let (method, path, timestamp) = signed;
+        let payload = request_payload(method, path, timestamp, body.as_bytes());
+        request = request
+            .header(CLIENT_ID_HEADER, client_id)
+            .header(SIGNATURE_TIMESTAMP_HEADER, timestamp)
+            .header(SIGNATURE_HEADER, client.sign(&payload));
+    }
+    request.body(Body::from(body.to_owned())).unwrap()
+}
+
+#[tokio::test]
+async fn request_signature_verification() {
+    let client = ResponseSigner::from_seed(&SecretString::new(base64::encode([5u8; 32]))).unwrap();
+    let clients = format!(r#"{{"billing": "{}"}}"#, client.public_key());
+    let app = TestApp::default()
+        .signed_clients(SignedClients::from_json(clients.as_bytes()).unwrap())
+        .build();
+    let body = to_string(&test_user(None)).unwrap();
+    let now = Utc::now().timestamp();
+    let billing = Some((&client, "billing"));
+
+    let signed = signed_save(billing, ("POST", "/api/v1/user", now), &body);
+    let response = app.clone().oneshot(signed).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The signature covers the body, the method, the path and the time.
+    let mut tampered = signed_save(billing, ("POST", "/api/v1/user", now), &body);
+    *tampered.body_mut() = Body::from(body.replace("100", "101"));
+    let response = app.clone().oneshot(tampered).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        body_as::<Value>(response).await["label"],
+        "signature.invalid"
+    );
+    for signed in [
+        ("PUT", "/api/v1/user", now),
+        ("POST", "/api/v1/user/search", now),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(signed_save(billing, signed, &body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{signed:?}");
+    }
+
+    let mut redated = signed_save(billing, ("POST", "/api/v1/user", now), &body);
+    redated
+        .headers_mut()
+        .insert(SIGNATURE_TIMESTAMP_HEADER, (now - 1).into());
+    let response = app.clone().oneshot(redated).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Requests signed outside the tolerance can't be replayed.
+    let stale = now - REQUEST_TOLERANCE_SECS - 60;
+    let response = app
+        .clone()
+        .oneshot(signed_save(billing, ("POST", "/api/v1/user", stale), &body))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let crm = Some((&client, "crm"));
+    let response = app
+        .clone()
+        .oneshot(signed_save(crm, ("POST", "/api/v1/user", now), &body))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Requests not naming a client aren't verified unless required.
+    let unsigned = || signed_save(None, ("POST", "/api/v1/user", now), &body);
+    let response = app.oneshot(unsigned()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let required = SignedClients::from_json(clients.as_bytes())
+        .unwrap()
+        .require_all();
+    let response = TestApp::default()
+        .signed_clients(required)
+        .build()
+        .oneshot(unsigned())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn signed_request_body_read_timeout() {
+    let client = ResponseSigner::from_seed(&SecretString::new(base64::encode([5u8; 32]))).unwrap();
+    let clients = format!(r#"{{"billing": "{}"}}"#, client.public_key());
+    let mut settings = Settings::default();
+    settings.body_read.routes.insert(
+        "/api/v1/user".to_owned(),
+        std::time::Duration::from_millis(50),
+    );
+    let app = TestApp::default()
+        .signed_clients(SignedClients::from_json(clients.as_bytes()).unwrap())
+        .settings(settings)
+        .build();
+
+    // Verifying reads the body before any route layer, under the same
+    // timeouts.
+    let mut request = signed_save(
+        Some((&client, "billing")),
+        ("POST", "/api/v1/user", Utc::now().timestamp()),
+        "",
+    );
+    let body =
+        stream::iter([Ok::<_, std::io::Error>(r#"{"name": "Test"#)]).chain(stream::pending());
+    *request.body_mut() = Body::wrap_stream(body);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    let error = body_as::<Value>(response).await;
+    assert_eq!(error["label"], "body.timeout");
+    assert!(error["requestId"].is_string());
+
+    // Unknown clients are rejected without reading the body.
+    let mut request = signed_save(
+        Some((&client, "crm")),
+        ("POST", "/api/v1/user", Utc::now().timestamp()),
+        "",
+    );
+    *request.body_mut() = Body::wrap_stream(stream::pending::<Result<String, std::io::Error>>());
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn openapi_document() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_as::<Value>(response).await;
+    assert!(body["openapi"].as_str().unwrap().starts_with("3."));
+    let get_user = &body["paths"]["/api/v1/user/{id}"]["get"];
+    assert_eq!(
+        get_user["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/HashedUser"
+    );
+    assert!(body["paths"]["/api/v1/user/bulk"]["put"]["parameters"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|param| param["name"] == "dryRun"));
+    for schema in ["HashedUser", "UpdateUser", "UserSearch", "ErrorEnvelope"] {
+        assert!(
+            body["components"]["schemas"][schema].is_object(),
+            "{schema}"
+        );
+    }
+
+    // Security comes from the guards of the handlers.
+    assert_eq!(
+        body["components"]["securitySchemes"]["bearer"],
+        json!({"type": "http", "scheme": "bearer", "bearerFormat": "JWT"})
+    );
+    assert_eq!(get_user["security"], json!([{"bearer": ["Admin"]}]));
+    assert!(get_user["responses"]["403"].is_object());
+    assert_eq!(
+        body["paths"]["/api/v1/user"]["post"]["security"],
+        json!([{"bearer": ["User"]}])
+    );
+    let search = &body["paths"]["/api/v1/user/search"]["post"];
+    assert_eq!(search["security"], json!([{"bearer": []}]));
+    assert!(search["responses"]["401"].is_object());
+    assert!(search["responses"]["403"].is_null());
+    for (path, item) in body["paths"].as_object().unwrap() {
+        for (method, operation) in item.as_object().unwrap() {
+            assert!(operation["security"].is_array(), "{method} {path}");
+        }
+    }
+
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/docs")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(body_as_str(response).await.contains("/api/v1/openapi.json"));
+}
+
+#[tokio::test]
+async fn openapi_route_policy() {
+    let settings = Settings {
+        route_policy: RoutePolicy::from_json(br#"{"GET /api/v1/user/:id": ["Admin", "User"]}"#)
+            .unwrap(),
+        ..Settings::default()
+    };
+    let response = TestApp::default()
+        .settings(settings)
+        .build()
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = body_as::<Value>(response).await;
+    let get_user = &body["paths"]["/api/v1/user/{id}"]["get"];
+    assert_eq!(get_user["security"], json!([{"bearer": ["Admin", "User"]}]));
+    assert!(get_user["description"]
+        .as_str()
+        .unwrap()
+        .ends_with("Requires one of the roles `Admin`, `User`."));
+    assert_eq!(
+        body["paths"]["/api/v1/user/{id}"]["delete"]["security"],
+        json!([{"bearer": ["Admin"]}])
+    );
 }
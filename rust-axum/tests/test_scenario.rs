@@ -10,9 +10,12 @@ use rust_axum::{security::hashing::HashedUser, types::jwt::Role};
 use std::sync::Arc;
 use tower::ServiceExt;
 use tracing::debug;
-use user_persist::types::{UpdateUser, User};
+use user_persist::{
+    patch::Patch,
+    types::{UpdateUser, User},
+};
 
-mod common;
+pub mod common;
 
 /// Runs a test scenario. A user is saved/updated/fetched/deleted/fetched.
 #[tokio::test]
@@ -73,7 +76,9 @@ async fn update_user(persist: Arc<TestPersistence>, user: &HashedUser) {
     let update_user = UpdateUser {
         id: user.user.id.clone().expect("No user id"),
         name: user.user.name.clone(),
-        hid: user.hid.clone(),
+        hash_id: user.hash_id.clone(),
+        phone: Patch::Absent,
+        address: Patch::Absent,
         age: 150,
         email: user.user.email.clone(),
     };
@@ -0,0 +1,195 @@
+use crate::common::{add_jwt, app, body_as, MIME_JSON};
+use axum::{
+    body::Body,
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        Method, Request, StatusCode,
+    },
+};
+use insta::{assert_json_snapshot, with_settings};
+use rust_axum::{types::jwt::Role, REQ_ID_HEADER};
+use serde_json::Value;
+use tower::ServiceExt;
+
+pub mod common;
+
+#[tokio::test]
+async fn hashed_user_snapshot() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/61c0d1954c6b974ca7000000")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_json_snapshot!(body_as::<Value>(response).await, @r###"
+    {
+      "id": "61c0d1954c6b974ca7000000",
+      "name": "Test User",
+      "age": 100,
+      "email": "test@test.com",
+      "gender": "Male",
+      "hashId": "LCZLrq1TUum5LmbwzIoopIolNqLGv8iewjdsu7/49G8="
+    }
+    "###);
+}
+
+#[tokio::test]
+async fn search_users_snapshot() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/search")
+                .method(Method::POST)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::from(r#"{"name": "Test"}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_json_snapshot!(body_as::<Value>(response).await, @r###"
+    [
+      {
+        "id": "61c0d1954c6b974ca7000000",
+        "name": "Test User",
+        "age": 100,
+        "email": "test@test.com",
+        "gender": "Male",
+        "hashId": "LCZLrq1TUum5LmbwzIoopIolNqLGv8iewjdsu7/49G8="
+      }
+    ]
+    "###);
+}
+
+#[tokio::test]
+async fn validation_failure_snapshot() {
+    let json_user = r#"{
+    "name": "Test User",
+    "age": 1,
+    "email": "bad_value",
+    "gender": "Male"
+  }"#;
+
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user")
+                .method(Method::POST)
+                .header(CONTENT_TYPE, MIME_JSON)
+                .header(AUTHORIZATION, add_jwt(Role::User))
+                .body(Body::from(json_user))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    // Validation errors are held in a hash map so their order is not stable.
+    let body = body_as::<Value>(response).await;
+    with_settings!({ sort_maps => true }, {
+        assert_json_snapshot!(body, @r###"
+        {
+          "label": "validation.failed",
+          "validationErrors": {
+            "age": [
+              {
+                "code": "range",
+                "message": "must be at least 100",
+                "params": {
+                  "min": 100.0,
+                  "value": 1
+                }
+              }
+            ],
+            "email": [
+              {
+                "code": "invalid email",
+                "message": "is not a valid email address",
+                "params": {
+                  "value": "bad_value"
+                }
+              }
+            ]
+          }
+        }
+        "###);
+    });
+}
+
+#[tokio::test]
+async fn user_not_found_snapshot() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/71c0d1954c6b974ca7000000")
+                .header(AUTHORIZATION, add_jwt(Role::Admin))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_json_snapshot!(body_as::<Value>(response).await, @r###"
+    {
+      "label": "not.found",
+      "message": "Resource not found",
+      "requestId": null
+    }
+    "###);
+}
+
+#[tokio::test]
+async fn unmatched_route_snapshot() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/unknown")
+                .header(REQ_ID_HEADER, "test-request-id")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_json_snapshot!(body_as::<Value>(response).await, @r###"
+    {
+      "label": "not.found",
+      "message": "No route for GET /api/v1/unknown",
+      "requestId": "test-request-id"
+    }
+    "###);
+}
+
+#[tokio::test]
+async fn method_not_allowed_snapshot() {
+    let response = app(None)
+        .oneshot(
+            Request::builder()
+                .uri("/api/v1/user/counts")
+                .method(Method::POST)
+                .header(REQ_ID_HEADER, "test-request-id")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_json_snapshot!(body_as::<Value>(response).await, @r###"
+    {
+      "label": "method.not_allowed",
+      "message": "Method POST not allowed for /api/v1/user/counts",
+      "requestId": "test-request-id"
+    }
+    "###);
+}
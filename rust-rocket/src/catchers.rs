@@ -1,19 +1,113 @@
-use crate::{guards::UserErrorMessage, types::USER_MS_TARGET};
+use crate::{context::RequestContext, routes::allowed_methods, types::USER_MS_TARGET, USER_PATH};
+use bootstrap::content_type::{ContentTypeError, UNSUPPORTED_MEDIA_TYPE_LABEL};
 use rocket::{
+    http::Header,
     serde::json::{json, Value},
     Request,
 };
+use serde::Serialize;
+use std::path::Path;
 use tracing::{event, Level};
-use user_persist::ValidationErrors;
+
+/// Unified JSON error shape returned by all catchers.
+fn error_body(req: &Request, label: &str, message: impl Serialize) -> Value {
+    let req_id = RequestContext::of(req).request_id().0;
+    json!({
+      "label": label,
+      "message": message,
+      "requestId": req_id.map(|id| id.to_string()),
+    })
+}
+
+/// Unauthorized response challenging the client for a bearer token.
+#[derive(Responder)]
+pub struct BearerChallenge {
+    body: Value,
+    challenge: Header<'static>,
+}
+
+#[catch(401)]
+pub fn unauthorized(req: &Request) -> BearerChallenge {
+    let reason = RequestContext::of(req).auth_failure();
+    let mut body = error_body(req, "unauthorized", "Missing or invalid token");
+    body["reason"] = json!(reason);
+    BearerChallenge {
+        body,
+        challenge: Header::new(
+            "WWW-Authenticate",
+            reason.and_then(|r| r.challenge()).unwrap_or("Bearer"),
+        ),
+    }
+}
 
 #[catch(403)]
-pub fn not_authorized() -> Value {
-    json!([{"label": "unauthorized", "message": "Not authorized to make request"}])
+pub fn not_authorized(req: &Request) -> Value {
+    let mut body = error_body(req, "unauthorized", "Not authorized to make request");
+    body["reason"] = json!(RequestContext::of(req).auth_failure());
+    body
 }
 
 #[catch(404)]
-pub fn not_found() -> Value {
-    json!([])
+pub fn not_found(req: &Request) -> Value {
+    error_body(req, "not.found", "Resource not found")
+}
+
+/// Method not allowed response listing the methods the resource allows.
+#[derive(Responder)]
+pub struct MethodNotAllowed {
+    body: Value,
+    allow: Header<'static>,
+}
+
+#[catch(405)]
+pub fn method_not_allowed(req: &Request) -> MethodNotAllowed {
+    event!(
+      target: USER_MS_TARGET,
+      Level::WARN,
+      "Method {} not allowed for {}",
+      req.method(),
+      req.uri()
+    );
+    let allowed = Path::new(req.uri().path().as_str())
+        .strip_prefix(USER_PATH)
+        .ok()
+        .and_then(allowed_methods);
+    MethodNotAllowed {
+        body: error_body(
+            req,
+            "method.not_allowed",
+            format!("Method {} not allowed", req.method()),
+        ),
+        allow: Header::new("Allow", allowed.unwrap_or_default()),
+    }
+}
+
+#[catch(413)]
+pub fn payload_too_large(req: &Request) -> Value {
+    event!(
+      target: USER_MS_TARGET,
+      Level::WARN,
+      "Payload too large for {}",
+      req.uri()
+    );
+    error_body(req, "payload.too_large", "Payload limit exceeded")
+}
+
+#[catch(415)]
+pub fn unsupported_media_type(req: &Request) -> Value {
+    let error_message = RequestContext::of(req)
+        .error_message()
+        .map(str::to_owned)
+        .unwrap_or_else(|| ContentTypeError::Missing.to_string());
+
+    event!(
+      target: USER_MS_TARGET,
+      Level::WARN,
+      "Unsupported media type {:?} for {}",
+      req.content_type(),
+      req.uri()
+    );
+    error_body(req, UNSUPPORTED_MEDIA_TYPE_LABEL, error_message)
 }
 
 #[catch(422)]
@@ -24,12 +118,14 @@ pub fn unprocessable_entry(req: &Request) -> Value {
       "Returning error responder for {}",
       req.uri()
     );
-    json! [{"label": "failed.request", "message": "failed to service request"}]
+    let mut body = error_body(req, "failed.request", "failed to service request");
+    body["validation"] = json!(RequestContext::of(req).validation_errors());
+    body
 }
 
 #[catch(400)]
 pub fn bad_request(req: &Request) -> Value {
-    let validation_errors = req.local_cache::<Option<ValidationErrors>, _>(|| None);
+    let validation_errors = RequestContext::of(req).validation_errors();
     let message = match validation_errors {
         Some(_) => "validation failed",
         None => "invalid or malformed request",
@@ -41,13 +137,16 @@ pub fn bad_request(req: &Request) -> Value {
       "Invalid request for {}",
       req.uri()
     );
-    json! [{"label": "bad.request", "message": message, "validation": validation_errors}]
+    let mut body = error_body(req, "bad.request", message);
+    body["validation"] = json!(validation_errors);
+    body
 }
 
 #[catch(500)]
 pub fn internal_server_error(req: &Request) -> Value {
-    let error_message =
-        req.local_cache(|| Some(UserErrorMessage("Internal server error".to_owned())));
+    let error_message = RequestContext::of(req)
+        .error_message()
+        .unwrap_or("Internal server error");
 
     event!(
       target: USER_MS_TARGET,
@@ -57,5 +156,5 @@ pub fn internal_server_error(req: &Request) -> Value {
       req.uri()
     );
 
-    json! [{"label": "internal.error", "message": error_message}]
+    error_body(req, "internal.error", error_message)
 }
@@ -0,0 +1,201 @@
+/*!
+Request local context.
+
+Each request has a single [`RequestContext`] in its local cache, stored by
+the [`RequestContextFairing`] when the request arrives. The fairings,
+guards, catchers and responders read the request id, start time, verified
+principal and the failure reported by a guard through its accessors rather
+than caching each value separately.
+
+The principal and the failure are set once by the guards, after the
+context is stored, so they are kept in write once cells.
+*/
+use crate::managed_clock;
+use chrono::{DateTime, Utc};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    outcome::Outcome::Success,
+    request::{FromRequest, Outcome},
+    Data, Request, Response,
+};
+use std::{
+    convert::Infallible,
+    fmt::{Display, Formatter},
+    sync::OnceLock,
+};
+use tracing::instrument;
+use user_persist::ValidationErrors;
+use uuid::Uuid;
+
+/// Identifier of a request, taken from its `X-Request-Id` header or
+/// generated. Empty when the context fairing isn't attached.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RequestId(pub Option<Uuid>);
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.unwrap_or_default())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Success(RequestContext::of(req).request_id())
+    }
+}
+
+/// Subject and role of the verified token of a request, so request logs
+/// are attributable to a principal.
+#[derive(Clone, Debug, Default)]
+pub struct Principal {
+    pub sub: Option<String>,
+    pub role: Option<String>,
+}
+
+/// Failure reported by a guard for the catcher answering the request.
+#[derive(Clone, Debug)]
+pub enum RequestError {
+    /// Message returned to the client.
+    Message(String),
+    /// The body failed validation.
+    Validation(ValidationErrors),
+    /// The token was missing or rejected, with the reason returned to the
+    /// client when it has one.
+    Auth(Option<errors::AuthError>),
+}
+
+/// State of a request shared by the fairings, guards and catchers.
+#[derive(Debug, Default)]
+pub struct RequestContext {
+    id: RequestId,
+    started_at: Option<DateTime<Utc>>,
+    principal: OnceLock<Principal>,
+    error: OnceLock<RequestError>,
+}
+
+impl RequestContext {
+    /// Context of `req`, empty when the context fairing isn't attached.
+    pub fn of<'r>(req: &'r Request<'_>) -> &'r Self {
+        req.local_cache(RequestContext::default)
+    }
+
+    pub fn request_id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Time the request arrived.
+    pub fn started_at(&self) -> Option<DateTime<Utc>> {
+        self.started_at
+    }
+
+    /// Principal of the request's verified token, empty when no token was
+    /// verified.
+    pub fn principal(&self) -> &Principal {
+        static ANONYMOUS: Principal = Principal {
+            sub: None,
+            role: None,
+        };
+        self.principal.get().unwrap_or(&ANONYMOUS)
+    }
+
+    /// Record the principal of the request's verified token.
+    pub fn set_principal(&self, principal: Principal) {
+        let _ = self.principal.set(principal);
+    }
+
+    /// Report why the request failed. Only the first failure is kept.
+    pub fn fail(&self, error: RequestError) {
+        let _ = self.error.set(error);
+    }
+
+    /// Message of the failure reported for the request.
+    pub fn error_message(&self) -> Option<&str> {
+        match self.error.get() {
+            Some(RequestError::Message(message)) => Some(message),
+            _ => None,
+        }
+    }
+
+    /// Validation errors of the request body.
+    pub fn validation_errors(&self) -> Option<&ValidationErrors> {
+        match self.error.get() {
+            Some(RequestError::Validation(errors)) => Some(errors),
+            _ => None,
+        }
+    }
+
+    /// Why the request's token was rejected.
+    pub fn auth_failure(&self) -> Option<errors::AuthError> {
+        match self.error.get() {
+            Some(RequestError::Auth(reason)) => *reason,
+            _ => None,
+        }
+    }
+}
+
+/// Fairing storing the context of each request. The request id is taken
+/// from the `X-Request-Id` header when it holds a UUID, otherwise a new one
+/// is generated, and is sent back as the `X-Request-Id` response header.
+pub struct RequestContextFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestContextFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request context",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    #[instrument(
+        skip_all,
+        level = "debug",
+        target = "ms-framework",
+        name = "request-span"
+    )]
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        let id = req
+            .headers()
+            .get_one("X-Request-Id")
+            .and_then(|h| Uuid::parse_str(h).ok())
+            .unwrap_or_else(Uuid::new_v4);
+        let started_at = managed_clock(req).now();
+        req.local_cache(|| RequestContext {
+            id: RequestId(Some(id)),
+            started_at: Some(started_at),
+            ..RequestContext::default()
+        });
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let req_id = RequestContext::of(req).request_id();
+        res.set_header(Header::new("X-Request-Id", req_id.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Principal, RequestContext, RequestError};
+    use errors::AuthError;
+
+    #[test]
+    fn test_first_failure_kept() {
+        let context = RequestContext::default();
+        assert!(context.principal().sub.is_none());
+        context.set_principal(Principal {
+            sub: Some("somebody".to_owned()),
+            role: None,
+        });
+        assert_eq!(context.principal().sub.as_deref(), Some("somebody"));
+
+        context.fail(RequestError::Auth(Some(AuthError::Expired)));
+        context.fail(RequestError::Message("later".to_owned()));
+        assert_eq!(context.auth_failure(), Some(AuthError::Expired));
+        assert_eq!(context.error_message(), None);
+        assert!(context.validation_errors().is_none());
+    }
+}
@@ -1,8 +1,12 @@
 use crate::{
-    fairings::RequestId,
-    types::{AdminAccess, JWTClaims, JWTError, JsonValidation, Role, UserAccess},
+    context::{Principal, RequestContext, RequestError},
+    managed_claims_policy, managed_clock, managed_email_validation, route_permits,
+    types::{
+        AdminAccess, JWTClaims, JWTError, JsonValidation, ManagedClaimsPolicy, Role, UserAccess,
+    },
     FRAMEWORK_TARGET, TEST_JWT_SECRET,
 };
+use bootstrap::content_type::{check_json, ContentTypeError};
 use hmac::{Hmac, Mac};
 use jwt::VerifyWithKey;
 use rocket::{
@@ -11,14 +15,20 @@ use rocket::{
     request::{self, local_cache, FromRequest, Outcome},
     Data, Request,
 };
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use sha2::Sha256;
+use std::convert::Infallible;
 use thiserror::Error;
 use tracing::{event, Level};
-use user_persist::Validate;
+use user_persist::{types::ValidateRequest, validation::count_failures};
 
 #[derive(Debug, Error)]
 pub enum JsonValidationError {
+    #[error("Unsupported content type")]
+    ContentType {
+        #[from]
+        source: ContentTypeError,
+    },
     #[error("Validation failed")]
     ValidationFailed {
         #[from]
@@ -38,22 +48,35 @@ pub enum JsonValidationError {
     },
 }
 
-#[derive(Serialize, Debug)]
-pub struct UserErrorMessage(pub String);
-
 /// A Json Data Guard that runs valiation on the deserialized types via
 /// the valiation crate. The validation crate requires the derserialized
-/// type have the `Validate` trait.
+/// type have the `Validate` trait, and emails are checked through
+/// `ValidateRequest` in the managed email validation mode.
 #[rocket::async_trait]
 impl<'r, T> FromData<'r> for JsonValidation<T>
 where
-    T: Deserialize<'r> + Validate,
+    T: Deserialize<'r> + ValidateRequest,
 {
     type Error = JsonValidationError;
 
     async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> rocket::data::Outcome<'r, Self> {
         let limit = req.limits().get("json").unwrap_or(Limits::JSON);
-        let req_id = req.local_cache(|| RequestId(None));
+        let context = RequestContext::of(req);
+        let req_id = context.request_id();
+
+        if let Err(e) = check_json(req.headers().get_one("Content-Type")) {
+            event!(
+              target: FRAMEWORK_TARGET,
+              Level::WARN,
+              %req_id,
+              "Unsupported content type {} {}: {e}",
+              req.method(),
+              req.uri()
+            );
+
+            context.fail(RequestError::Message(e.to_string()));
+            return rocket::data::Outcome::Error((Status::UnsupportedMediaType, e.into()));
+        }
         let string = match data.open(limit).into_string().await {
             Ok(s) if s.is_complete() => s.into_inner(),
             Ok(_) => {
@@ -64,7 +87,7 @@ where
                   "Payload limit exceeded"
                 );
 
-                req.local_cache(|| Some(UserErrorMessage("payload limit exceeded".to_owned())));
+                context.fail(RequestError::Message("payload limit exceeded".to_owned()));
 
                 return rocket::data::Outcome::Error((
                     Status::PayloadTooLarge,
@@ -81,7 +104,7 @@ where
                   req.uri()
                 );
 
-                req.local_cache(|| Some(UserErrorMessage(e.to_string())));
+                context.fail(RequestError::Message(e.to_string()));
 
                 return rocket::data::Outcome::Error((
                     Status::InternalServerError,
@@ -95,7 +118,7 @@ where
         match serde_json::from_str::<T>(string)
             .map_err(|e| JsonValidationError::ParseError { source: e })
         {
-            Ok(t) => match t.validate() {
+            Ok(t) => match t.validate_request(managed_email_validation(req)) {
                 Ok(_) => rocket::data::Outcome::Success(JsonValidation(t)),
                 Err(e) => {
                     event!(
@@ -107,7 +130,8 @@ where
                       req.uri()
                     );
 
-                    req.local_cache(|| Some(e.clone()));
+                    count_failures(&e);
+                    context.fail(RequestError::Validation(e.clone()));
                     rocket::data::Outcome::Error((
                         Status::BadRequest,
                         JsonValidationError::ValidationFailed { source: e },
@@ -124,8 +148,8 @@ where
                   req.uri()
                 );
 
-                req.local_cache(|| Some(UserErrorMessage(e.to_string())));
-                rocket::data::Outcome::Error((Status::InternalServerError, e))
+                context.fail(RequestError::Message(e.to_string()));
+                rocket::data::Outcome::Error((Status::BadRequest, e))
             }
         }
     }
@@ -136,8 +160,16 @@ where
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Fail the request with the status of the JWT error and report its reason
+/// for the catchers.
+fn reject<S>(req: &Request<'_>, e: JWTError) -> request::Outcome<S, JWTError> {
+    RequestContext::of(req).fail(RequestError::Auth(e.reason()));
+    Outcome::Error((e.status(), e))
+}
+
 fn extract_jwt(req: &'_ Request<'_>) -> Result<JWTClaims, JWTError> {
-    let req_id = req.local_cache(|| RequestId(None));
+    let context = RequestContext::of(req);
+    let req_id = context.request_id();
     match req.headers().get_one("Authorization").map(|s| &s[7..]) {
         Some(jwt_token) => {
             event!(
@@ -152,8 +184,13 @@ fn extract_jwt(req: &'_ Request<'_>) -> Result<JWTClaims, JWTError> {
             let key = HmacSha256::new_from_slice(TEST_JWT_SECRET)?;
 
             let claims: JWTClaims = jwt_token.verify_with_key(&key)?;
+            let claims = claims.check(&managed_claims_policy(req), managed_clock(req).now())?;
 
-            Ok(claims.check_expired()?)
+            context.set_principal(Principal {
+                sub: Some(claims.sub.clone()),
+                role: Some(format!("{:?}", claims.role)),
+            });
+            Ok(claims)
         }
         None => Err(JWTError::NoAuthorizationHeader),
     }
@@ -167,7 +204,7 @@ impl<'r> FromRequest<'r> for JWTClaims {
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
         match extract_jwt(req) {
             Ok(j) => Outcome::Success(j),
-            Err(e) => Outcome::Error((Status::Forbidden, e)),
+            Err(e) => reject(req, e),
         }
     }
 }
@@ -177,10 +214,10 @@ impl<'r> FromRequest<'r> for UserAccess {
     type Error = JWTError;
 
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let req_id = req.local_cache(|| RequestId(None));
+        let req_id = RequestContext::of(req).request_id();
         match extract_jwt(req) {
-            Ok(j) if j.role == Role::User => request::Outcome::Success(UserAccess(j)),
-            Ok(_) => Outcome::Error((Status::Forbidden, JWTError::InvalidRole)),
+            Ok(j) if route_permits(req, &j, Role::User) => request::Outcome::Success(UserAccess(j)),
+            Ok(_) => reject(req, JWTError::InvalidRole),
             Err(e) => {
                 event!(
                   target: FRAMEWORK_TARGET,
@@ -191,7 +228,7 @@ impl<'r> FromRequest<'r> for UserAccess {
                   req.uri()
                 );
 
-                rocket::request::Outcome::Error((Status::Forbidden, e))
+                reject(req, e)
             }
         }
     }
@@ -202,10 +239,12 @@ impl<'r> FromRequest<'r> for AdminAccess {
     type Error = JWTError;
 
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let req_id = req.local_cache(|| RequestId(None));
+        let req_id = RequestContext::of(req).request_id();
         match extract_jwt(req) {
-            Ok(j) if j.role == Role::Admin => request::Outcome::Success(AdminAccess(j)),
-            Ok(_) => rocket::request::Outcome::Error((Status::Forbidden, JWTError::InvalidRole)),
+            Ok(j) if route_permits(req, &j, Role::Admin) => {
+                request::Outcome::Success(AdminAccess(j))
+            }
+            Ok(_) => reject(req, JWTError::InvalidRole),
             Err(e) => {
                 event!(
                   target: FRAMEWORK_TARGET,
@@ -215,8 +254,17 @@ impl<'r> FromRequest<'r> for AdminAccess {
                   req.method(),
                   req.uri()
                 );
-                rocket::request::Outcome::Error((Status::Forbidden, e))
+                reject(req, e)
             }
         }
     }
 }
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ManagedClaimsPolicy {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(ManagedClaimsPolicy(managed_claims_policy(req)))
+    }
+}
@@ -0,0 +1,169 @@
+/*!
+REST API using the rocket framework.
+*/
+#[macro_use]
+extern crate rocket;
+
+pub mod catchers;
+pub mod context;
+pub mod fairings;
+pub mod guards;
+pub mod routes;
+#[cfg(test)]
+mod tests;
+pub mod types;
+
+use crate::types::{JWTClaims, JWTError, Role};
+use bootstrap::{claims::ClaimsPolicy, route_policy::RoutePolicy, token::TokenIssuer};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use jwt::SignWithKey;
+use rocket::{Build, Request, Rocket};
+use sha2::Sha256;
+use std::sync::Arc;
+use user_persist::{
+    clock::{Clock, SystemClock},
+    export::FormatRegistry,
+    mongo_persistence::MongoPersistence,
+    persistence::UserPersistence,
+    types::EmailValidation,
+};
+
+// This would be sourced from some vault service.
+pub const TEST_JWT_SECRET: &[u8] = bootstrap::TEST_JWT_SECRET;
+pub const FRAMEWORK_TARGET: &str = "ms-framework";
+
+/// Mount point for the user routes.
+pub const USER_PATH: &str = "/api/v1/user";
+/// Mount point for the login and refresh routes.
+pub const AUTH_PATH: &str = "/api/v1/auth";
+/// Mount point for the development only routes.
+pub const DEV_PATH: &str = "/api/v1/dev";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Create a test JWT authorization header value for a given role.
+pub fn test_jwt(role: Role) -> String {
+    let claims = JWTClaims::issue(
+        "somebody".to_owned(),
+        role,
+        Utc::now(),
+        Duration::minutes(15),
+        &ClaimsPolicy::default(),
+    );
+    format!("Bearer {}", sign_jwt(&claims).unwrap())
+}
+
+/// Sign the claims with the JWT secret.
+pub fn sign_jwt(claims: &JWTClaims) -> Result<String, JWTError> {
+    let key = HmacSha256::new_from_slice(TEST_JWT_SECRET)?;
+    Ok(claims.sign_with_key(&key)?)
+}
+
+/// Clock managed by the rocket instance, otherwise the system clock.
+pub(crate) fn managed_clock(req: &Request<'_>) -> Arc<dyn Clock> {
+    req.rocket()
+        .state::<Arc<dyn Clock>>()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(SystemClock))
+}
+
+/// Claims policy managed by the rocket instance, otherwise the default
+/// policy.
+pub(crate) fn managed_claims_policy(req: &Request<'_>) -> ClaimsPolicy {
+    req.rocket()
+        .state::<ClaimsPolicy>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Email validation mode managed by the rocket instance, otherwise the
+/// default mode.
+pub(crate) fn managed_email_validation(req: &Request<'_>) -> EmailValidation {
+    req.rocket()
+        .state::<EmailValidation>()
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Whether the role of `claims` may call the route of `req`, whose handler
+/// requires `required` unless the route policy managed by the rocket
+/// instance lists it.
+pub(crate) fn route_permits(req: &Request<'_>, claims: &JWTClaims, required: Role) -> bool {
+    let role = format!("{:?}", claims.role);
+    let required = format!("{required:?}");
+    match (req.rocket().state::<RoutePolicy>(), req.route()) {
+        (Some(policy), Some(route)) => {
+            policy.permits(req.method().as_str(), route.uri.path(), &role, &required)
+        }
+        _ => role == required,
+    }
+}
+
+/// Build the rocket instance with the user routes. The download route
+/// streams directly from mongodb, in any format of the default
+/// `FormatRegistry`, so it is only mounted when a `downloader` is given.
+/// Expiry checks and request timing read the time from a managed
+/// `Arc<dyn Clock>` when there is one.
+pub fn build_rocket(
+    persist: Arc<dyn UserPersistence>,
+    downloader: Option<MongoPersistence>,
+) -> Rocket<Build> {
+    let rocket = rocket::build()
+        .attach(context::RequestContextFairing)
+        .attach(fairings::LoggerFairing)
+        .attach(fairings::RequestTimer)
+        .manage(persist)
+        .mount(
+            USER_PATH,
+            routes![
+                routes::count_genders,
+                routes::get_user,
+                routes::get_user_by_email,
+                routes::save_user,
+                routes::find_users,
+                routes::update_user,
+                routes::options,
+                routes::get_unmatched,
+                routes::post_unmatched,
+                routes::put_unmatched,
+                routes::patch_unmatched,
+                routes::delete_unmatched
+            ],
+        )
+        .register(
+            USER_PATH,
+            catchers![
+                catchers::not_found,
+                catchers::method_not_allowed,
+                catchers::payload_too_large,
+                catchers::unsupported_media_type,
+                catchers::bad_request,
+                catchers::unprocessable_entry,
+                catchers::internal_server_error,
+                catchers::unauthorized,
+                catchers::not_authorized
+            ],
+        );
+
+    match downloader {
+        Some(downloader) => rocket
+            .manage(downloader)
+            .manage(FormatRegistry::default())
+            .mount(USER_PATH, routes![routes::download]),
+        None => rocket,
+    }
+}
+
+/// Mount the development token endpoint. Only used when dev tokens are
+/// enabled.
+pub fn with_dev_tokens(rocket: Rocket<Build>) -> Rocket<Build> {
+    rocket.mount(DEV_PATH, routes![routes::dev_token])
+}
+
+/// Mount the login and refresh endpoints issuing tokens with `issuer`.
+pub fn with_token_issuer(rocket: Rocket<Build>, issuer: TokenIssuer<Role>) -> Rocket<Build> {
+    rocket
+        .manage(issuer)
+        .mount(AUTH_PATH, routes![routes::login, routes::refresh])
+}
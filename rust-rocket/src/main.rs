@@ -1,66 +1,113 @@
 #[macro_use]
 extern crate rocket;
 
-mod catchers;
-mod fairings;
-mod guards;
-mod routes;
-#[cfg(test)]
-mod tests;
-mod types;
-
-use crate::types::{JWTClaims, Role};
-use chrono::{Duration, Utc};
+use bootstrap::{
+    check::CheckReport,
+    claims::ClaimsArgs,
+    install_panic_hook,
+    metrics::install_metrics_exporter,
+    route_policy::RoutePolicyArgs,
+    token::{TokenArgs, LOGIN_PATH},
+    Bootstrap, BootstrapArgs, BootstrapError, Configured, DEV_TOKEN_PATH,
+};
 use clap::Parser;
-use hmac::{Hmac, Mac};
-use jwt::SignWithKey;
-use sha2::Sha256;
-use std::{fmt, process, sync::Arc};
+use rust_rocket::{
+    build_rocket,
+    types::{self, Role},
+    with_dev_tokens, with_token_issuer, TEST_JWT_SECRET,
+};
+use std::{fmt, net::SocketAddr, path::PathBuf, process, sync::Arc};
 use tracing::{event, Level};
-use tracing_subscriber::EnvFilter;
-use user_persist::{mongo_persistence::MongoPersistence, persistence::UserPersistence, MongoArgs};
-
-// This would be sourced from some vault service.
-const TEST_JWT_SECRET: &[u8] = b"TEST_SECRET";
-const FRAMEWORK_TARGET: &str = "ms-framework";
+use user_persist::{
+    clock::SystemClock, kv::MongoKvStore, mongo_persistence::MongoPersistence,
+    persistence::UserPersistence, MongoArgs,
+};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(about, version, author)]
 struct ProgramArgs {
     #[clap(flatten)]
     mongo_opts: MongoArgs,
+    #[clap(flatten)]
+    bootstrap: BootstrapArgs,
+    #[clap(flatten)]
+    claims: ClaimsArgs,
+    #[clap(flatten)]
+    tokens: TokenArgs,
+    #[clap(flatten)]
+    route_policy: RoutePolicyArgs,
+    #[clap(long, default_value = "9100")]
+    metrics_port: u16,
 }
 
 impl fmt::Display for ProgramArgs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "mongo_opts {}", self.mongo_opts)
+        write!(
+            f,
+            "mongo_opts {} metrics_port {}",
+            self.mongo_opts, self.metrics_port
+        )
     }
 }
 
-type HmacSha256 = Hmac<Sha256>;
+impl ProgramArgs {
+    /// Resolve the startup settings for the deployment profile. The server
+    /// certificate is read from the rocket configuration.
+    fn bootstrap(&self) -> Result<Bootstrap, BootstrapError> {
+        self.bootstrap.resolve(Configured {
+            jwt_secret: Some(TEST_JWT_SECRET),
+            tls: rocket::Config::figment().contains("tls"),
+            allow_invalid_certificates: self.mongo_opts.allow_invalid_certificates(),
+        })
+    }
 
-fn test_jwt(role: Role) -> String {
-    let key = HmacSha256::new_from_slice(TEST_JWT_SECRET).unwrap();
-    let expiration = Utc::now() + Duration::minutes(15);
-    let claims = JWTClaims {
-        sub: "somebody".to_owned(),
-        role,
-        exp: expiration.timestamp(),
-    };
-    format!("Bearer {}", claims.sign_with_key(&key).unwrap())
+    /// Run the startup self-check.
+    async fn self_check(self) -> CheckReport {
+        let mut report = CheckReport::default();
+        let bootstrap = report.record("config", self.bootstrap());
+        report.record("accounts", self.tokens.issuer::<Role>());
+        report.record("route policy", self.route_policy.policy());
+        let figment = rocket::Config::figment();
+        let tls_files = figment
+            .extract_inner::<PathBuf>("tls.certs")
+            .ok()
+            .zip(figment.extract_inner::<PathBuf>("tls.key").ok());
+        report.tls_files(
+            tls_files
+                .as_ref()
+                .map(|(cert_file, key_file)| (cert_file.as_path(), key_file.as_path())),
+        );
+        report.jwt_secret(Some(TEST_JWT_SECRET));
+        let mongo_opts = match bootstrap {
+            Some(bootstrap) => self
+                .mongo_opts
+                .with_allow_invalid_certificates(bootstrap.allow_invalid_certificates),
+            None => self.mongo_opts,
+        };
+        report.mongo(mongo_opts).await;
+        report
+    }
 }
 
 #[rocket::main]
 async fn main() {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_target(true)
-        .pretty()
-        // .json()
-        // .flatten_event(true)
-        .init();
-
     let program_opts = ProgramArgs::parse();
+    if program_opts.bootstrap.check {
+        program_opts.self_check().await.exit();
+    }
+
+    let bootstrap = match program_opts.bootstrap() {
+        Ok(bootstrap) => bootstrap,
+        Err(e) => {
+            eprintln!("Invalid startup settings: {e}");
+            process::exit(1);
+        }
+    };
+    bootstrap.init_tracing();
+
+    install_panic_hook();
+
+    let email_validation = program_opts.mongo_opts.email_validation();
 
     event!(
       target: types::USER_MS_TARGET,
@@ -70,44 +117,75 @@ async fn main() {
 
     event!(
       target: types::USER_MS_TARGET,
-      Level::DEBUG,
-      "admin {}",
-      test_jwt(Role::Admin)
+      Level::INFO,
+      "Starting with the {} profile",
+      bootstrap.profile
     );
 
-    match MongoPersistence::new(program_opts.mongo_opts).await {
+    if let Err(e) =
+        install_metrics_exporter(SocketAddr::from(([0, 0, 0, 0], program_opts.metrics_port)))
+    {
+        error!("Failed to install metrics exporter: {e}");
+        process::exit(1);
+    }
+
+    let token_issuer = match program_opts.tokens.issuer::<Role>() {
+        Ok(issuer) => issuer,
+        Err(e) => {
+            eprintln!("Invalid accounts: {e}");
+            process::exit(1);
+        }
+    };
+
+    let route_policy = match program_opts.route_policy.policy() {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("Invalid route policy: {e}");
+            process::exit(1);
+        }
+    };
+
+    let mongo_opts = program_opts
+        .mongo_opts
+        .with_allow_invalid_certificates(bootstrap.allow_invalid_certificates);
+
+    match MongoPersistence::new(mongo_opts).await {
         Ok(db) => {
-            let mongo_persist: Arc<dyn UserPersistence> = Arc::new(db);
-
-            let _ = rocket::build()
-                .attach(fairings::RequestIdFairing)
-                .attach(fairings::LoggerFairing)
-                .attach(fairings::RequestTimer)
-                .manage(mongo_persist)
-                .mount(
-                    "/api/v1/user",
-                    routes![
-                        routes::count_genders,
-                        routes::get_user,
-                        routes::save_user,
-                        routes::find_users,
-                        routes::update_user,
-                        routes::download
-                    ],
-                )
-                .register(
-                    "/api/v1/user",
-                    catchers![
-                        catchers::not_found,
-                        catchers::bad_request,
-                        catchers::unprocessable_entry,
-                        catchers::internal_server_error,
-                        catchers::not_authorized
-                    ],
-                )
-                .launch()
-                .await
-                .unwrap();
+            let mongo_persist: Arc<dyn UserPersistence> = Arc::new(db.clone());
+
+            let rocket = build_rocket(mongo_persist, Some(db.clone()))
+                .manage(program_opts.claims.policy())
+                .manage(route_policy)
+                .manage(email_validation);
+            let rocket = if bootstrap.dev_tokens {
+                event!(
+                  target: types::USER_MS_TARGET,
+                  Level::WARN,
+                  "Dev token endpoint enabled at {DEV_TOKEN_PATH}"
+                );
+                with_dev_tokens(rocket)
+            } else {
+                rocket
+            };
+            let rocket = match token_issuer {
+                Some(issuer) => {
+                    event!(
+                      target: types::USER_MS_TARGET,
+                      Level::INFO,
+                      "Login endpoint enabled at {LOGIN_PATH}"
+                    );
+                    // Refresh tokens are shared by every replica.
+                    let kv_store = MongoKvStore::new(&db, Arc::new(SystemClock));
+                    if let Err(e) = kv_store.ensure_indexes().await {
+                        error!("Failed to create key value indexes: {e}");
+                        process::exit(1);
+                    }
+                    with_token_issuer(rocket, issuer.with_kv_store(Arc::new(kv_store)))
+                }
+                None => rocket,
+            };
+
+            let _ = rocket.launch().await.unwrap();
         }
         Err(e) => {
             error!("Failed to connect to database: {e}");
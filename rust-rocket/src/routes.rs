@@ -1,20 +1,46 @@
 use crate::{
-    fairings::RequestId,
-    types::{AdminAccess, ErrorResponder, JsonValidation, UserAccess, UserKeyReq, USER_MS_TARGET},
+    context::RequestId,
+    sign_jwt,
+    types::{
+        AdminAccess, ErrorResponder, GenderCount, JWTClaims, JsonValidation, ManagedClaimsPolicy,
+        Role, UserAccess, UserKeyReq, USER_MS_TARGET,
+    },
 };
+use api_types::{CreateUserRequest, SearchRequest, UserResponse};
+use bootstrap::{
+    token::{Grant, IssueError, LoginRequest, RefreshRequest, TokenIssuer, TokenResponse},
+    DevTokenRequest, DevTokenResponse,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures::stream::{BoxStream, StreamExt};
 use mongodb::bson::doc;
-use rocket::{response::stream::ByteStream, serde::json::Json, State};
-use serde_json::Value;
-use std::sync::Arc;
+use rocket::{
+    http::{Accept, ContentType, Header, Method, Status},
+    response::stream::ByteStream,
+    serde::json::Json,
+    Either, State,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tracing::{event, Level};
 use user_persist::{
+    export::{FormatRegistry, Formatter, JsonArrayFormatter},
     mongo_persistence::MongoPersistence,
-    persistence::UserPersistence,
-    types::{UpdateUser, User, UserSearch},
+    persistence::{
+        search_capped, CappedSearch, PersistenceError, UserPersistence, DEFAULT_MAX_SEARCH_RESULTS,
+    },
+    types::{Email, UpdateUser, User, UserSearch},
 };
 
-type JsonUser = Json<User>;
+type JsonUser = Json<UserResponse>;
 type HandlerResult<T> = Result<T, ErrorResponder<'static>>;
+/// Newline delimited json lines of streamed users.
+type UserLines = BoxStream<'static, Vec<u8>>;
+/// Search results either capped or streamed.
+type SearchResponse =
+    Either<(Status, Json<Vec<UserResponse>>), (ContentType, ByteStream<UserLines>)>;
 type UserPersist = State<Arc<dyn UserPersistence>>;
 
 // Gets a single user document by primary key.
@@ -28,25 +54,39 @@ pub async fn get_user(
     event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "claims: {role:?}");
     let user = db.get_user(&id.0).await?;
     event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "fetched user: {user:?}");
-    Ok(user.map(Json))
+    Ok(user.map(|u| Json(u.into())))
+}
+
+// Gets the user with an email, ignoring case.
+#[get("/by-email/<email>")]
+pub async fn get_user_by_email(
+    email: String,
+    req_id: RequestId,
+    db: &UserPersist,
+    role: AdminAccess,
+) -> HandlerResult<Option<JsonUser>> {
+    event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "claims: {role:?}");
+    let user = db.get_user_by_email(&Email(email)).await?;
+    event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "fetched user: {user:?}");
+    Ok(user.map(|u| Json(u.into())))
 }
 
 // Creates a new user record.
-#[post("/", format = "json", data = "<user>")]
+#[post("/", data = "<user>")]
 pub async fn save_user(
-    user: JsonValidation<User>,
+    user: JsonValidation<CreateUserRequest>,
     req_id: RequestId,
     db: &UserPersist,
     _role: UserAccess,
 ) -> HandlerResult<JsonUser> {
-    let JsonValidation(u) = user;
-    let saved_user = db.save_user(&u).await?;
+    let JsonValidation(request) = user;
+    let saved_user = db.save_user(&User::from(request)).await?;
     event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "Saved user {saved_user:?}");
-    Ok(Json(saved_user))
+    Ok(Json(saved_user.into()))
 }
 
 // Updates a user with the UpdateUser criteria.
-#[put("/", format = "json", data = "<user>")]
+#[put("/", data = "<user>")]
 pub async fn update_user(
     db: &UserPersist,
     req_id: RequestId,
@@ -66,40 +106,134 @@ pub async fn count_genders(
     db: &UserPersist,
     req_id: RequestId,
     #[allow(unused)] role: UserAccess,
-) -> HandlerResult<Json<Vec<Value>>> {
-    let docs = db.count_genders().await?;
-    event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "User counts: {docs:?}");
-    Ok(Json(docs))
+) -> HandlerResult<Json<Vec<GenderCount>>> {
+    let counts = GenderCount::from_groups(db.count_genders().await?)?;
+    event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "User counts: {counts:?}");
+    Ok(Json(counts))
 }
 
-// Searches for users with the UserSearch criteria.
+// Searches for users with the UserSearch criteria. With `stream=true` the
+// users are streamed as newline delimited json. Otherwise at most
+// DEFAULT_MAX_SEARCH_RESULTS users are returned with a 206 status when more
+// matched. A search without criteria requires `all=true`.
 #[tracing::instrument(skip(db), level = "debug", target = "user-ms", name = "search-span")]
-#[post("/search", format = "json", data = "<user_search>")]
+#[post("/search?<stream>&<all>", data = "<user_search>")]
 pub async fn find_users(
-    user_search: JsonValidation<UserSearch>,
+    user_search: JsonValidation<SearchRequest>,
+    stream: Option<bool>,
+    all: Option<bool>,
     req_id: RequestId,
     db: &UserPersist,
     role: AdminAccess,
-) -> HandlerResult<Json<Vec<User>>> {
-    let search = user_search.0;
+) -> HandlerResult<SearchResponse> {
+    let search = UserSearch::from(user_search.0);
     event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "Searching with {search:?}");
-    let result = db.search_users(&search).await?;
-    event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "Found {result:?}");
-    Ok(Json(result))
+
+    if search.is_empty() && !all.unwrap_or_default() {
+        return Err(ErrorResponder::with_status(
+            "search.unfiltered",
+            "Search requires at least one filter or all=true",
+            Status::BadRequest,
+        ));
+    }
+
+    if stream.unwrap_or_default() {
+        let users = db.search_users_stream(&search).await?;
+        let bstream = ByteStream! {
+            for await user in users {
+              let line = user
+                .map_err(|e| e.to_string())
+                .and_then(|u| serde_json::to_vec(&UserResponse::from(u)).map_err(|e| e.to_string()));
+              match line {
+                Ok(mut line) => {
+                  line.push(b'\n');
+                  yield line
+                },
+                Err(e) => {
+                  event!(target: USER_MS_TARGET, Level::ERROR, %req_id, "Failed to stream search: {e}");
+                  break
+                },
+              }
+            }
+        };
+        return Ok(Either::Right((
+            ContentType::new("application", "x-ndjson"),
+            ByteStream(bstream.0.boxed()),
+        )));
+    }
+
+    let CappedSearch { users, truncated } =
+        search_capped(db.inner().as_ref(), &search, DEFAULT_MAX_SEARCH_RESULTS).await?;
+    event!(target: USER_MS_TARGET, Level::DEBUG, %req_id, "Found {users:?}");
+    let status = if truncated {
+        Status::PartialContent
+    } else {
+        Status::Ok
+    };
+    let users = users.into_iter().map(UserResponse::from).collect();
+    Ok(Either::Left((status, Json(users))))
 }
 
-// Stream all users as json.
-#[get("/download")]
+/// Formatter of the download, chosen by the `format` query parameter or
+/// else the accepted media type of the highest quality with a registered
+/// format, the first of equal qualities. Media types of quality zero are
+/// not acceptable. Users are downloaded as a JSON array by default.
+pub(crate) fn download_formatter(
+    formats: &FormatRegistry,
+    format: Option<&str>,
+    accept: Option<&Accept>,
+) -> HandlerResult<Arc<dyn Formatter>> {
+    if let Some(name) = format {
+        return formats.by_name(name).ok_or_else(|| {
+            ErrorResponder::with_status(
+                "format.unknown",
+                format!("Unknown export format `{name}`"),
+                Status::BadRequest,
+            )
+        });
+    }
+    let accepted = accept
+        .into_iter()
+        .flat_map(Accept::iter)
+        .filter(|media| media.weight_or(1.0) > 0.0)
+        .filter_map(|media| {
+            let format = formats.by_content_type(&format!("{}/{}", media.top(), media.sub()))?;
+            Some((media.weight_or(1.0), format))
+        })
+        .fold(None, |best, (weight, format)| match best {
+            Some((best_weight, _)) if best_weight >= weight => best,
+            _ => Some((weight, format)),
+        })
+        .map(|(_, format)| format);
+    Ok(accepted.unwrap_or_else(|| Arc::new(JsonArrayFormatter)))
+}
+
+// Stream all users in the requested format.
+#[get("/download?<format>")]
 pub async fn download(
     db: &State<MongoPersistence>,
+    formats: &State<FormatRegistry>,
+    format: Option<&str>,
+    accept: Option<&Accept>,
     req_id: RequestId,
     #[allow(unused)] role: AdminAccess,
-) -> HandlerResult<ByteStream![Vec<u8>]> {
-    let stream = db.download().await?;
+) -> HandlerResult<(ContentType, ByteStream![Vec<u8>])> {
+    let formatter = download_formatter(formats, format, accept)?;
+    let content_type =
+        ContentType::parse_flexible(formatter.content_type()).unwrap_or(ContentType::Binary);
+    let records = db
+        .download(None)
+        .await?
+        .map(|user| {
+            let user = user.map_err(PersistenceError::from)?;
+            Ok(serde_json::to_value(UserResponse::from(user))?)
+        })
+        .boxed();
+    let export = formatter.format(records);
     let bstream = ByteStream! {
-        for await user in stream {
-          match user {
-            Ok(u) => yield serde_json::to_string(&u).unwrap_or_default().into_bytes(),
+        for await chunk in export {
+          match chunk {
+            Ok(bytes) => yield bytes,
             Err(e) => {
               event!(target: USER_MS_TARGET, Level::ERROR, %req_id, "Failed to stream downloads: {e}");
               break
@@ -107,5 +241,158 @@ pub async fn download(
           }
         }
     };
-    Ok(bstream)
+    Ok((content_type, bstream))
+}
+
+/// Empty response listing the methods allowed for a resource.
+#[derive(Responder)]
+#[response(status = 204)]
+pub struct AllowedMethods((), Header<'static>);
+
+/// Methods allowed for a path relative to the user mount point. HEAD is
+/// answered automatically by rocket for every GET route.
+pub(crate) fn allowed_methods(path: &Path) -> Option<&'static str> {
+    match path.to_str()? {
+        "" => Some("POST, PUT, OPTIONS"),
+        "counts" | "download" => Some("GET, HEAD, OPTIONS"),
+        "search" => Some("POST, OPTIONS"),
+        id if !id.contains('/') => Some("GET, HEAD, OPTIONS"),
+        email
+            if email
+                .strip_prefix("by-email/")
+                .is_some_and(|email| !email.contains('/')) =>
+        {
+            Some("GET, HEAD, OPTIONS")
+        }
+        _ => None,
+    }
+}
+
+// Lists the methods allowed for a user resource.
+#[options("/<path..>")]
+pub fn options(path: PathBuf) -> Option<AllowedMethods> {
+    allowed_methods(&path).map(|methods| AllowedMethods((), Header::new("Allow", methods)))
+}
+
+/// Status of a request for `path` that no route of its `method` matched.
+/// Rocket answers these with a 404, so a user resource requested with a
+/// method it doesn't allow is failed with a 405 for the catcher instead.
+fn unmatched(path: &Path, method: Method) -> Status {
+    match allowed_methods(path) {
+        Some(methods)
+            if !methods
+                .split(", ")
+                .any(|allowed| allowed == method.as_str()) =>
+        {
+            Status::MethodNotAllowed
+        }
+        _ => Status::NotFound,
+    }
+}
+
+// Fails GET requests no other route matched.
+#[get("/<path..>", rank = 100)]
+pub fn get_unmatched(path: PathBuf) -> Status {
+    unmatched(&path, Method::Get)
+}
+
+// Fails POST requests no other route matched.
+#[post("/<path..>", rank = 100)]
+pub fn post_unmatched(path: PathBuf) -> Status {
+    unmatched(&path, Method::Post)
+}
+
+// Fails PUT requests no other route matched.
+#[put("/<path..>", rank = 100)]
+pub fn put_unmatched(path: PathBuf) -> Status {
+    unmatched(&path, Method::Put)
+}
+
+// Fails PATCH requests no other route matched.
+#[patch("/<path..>", rank = 100)]
+pub fn patch_unmatched(path: PathBuf) -> Status {
+    unmatched(&path, Method::Patch)
+}
+
+// Fails DELETE requests no other route matched.
+#[delete("/<path..>", rank = 100)]
+pub fn delete_unmatched(path: PathBuf) -> Status {
+    unmatched(&path, Method::Delete)
+}
+
+// Mints a signed JWT for local testing.
+#[post("/token", format = "json", data = "<request>")]
+pub async fn dev_token(
+    request: Json<DevTokenRequest<Role>>,
+    req_id: RequestId,
+    policy: ManagedClaimsPolicy,
+) -> HandlerResult<Json<DevTokenResponse>> {
+    let ttl = request.validate()?;
+    let request = request.into_inner();
+    let claims = JWTClaims::issue(
+        request.sub,
+        request.role,
+        Utc::now(),
+        Duration::seconds(ttl.as_secs() as i64),
+        &policy.0,
+    );
+    event!(target: USER_MS_TARGET, Level::WARN, %req_id, "minting dev token for {claims:?}");
+    Ok(Json(DevTokenResponse {
+        token: sign_jwt(&claims)?,
+        expires_at: claims.exp,
+    }))
+}
+
+// Exchanges the credentials of an account for tokens.
+#[post("/login", format = "json", data = "<request>")]
+pub async fn login(
+    request: Json<LoginRequest>,
+    issuer: &State<TokenIssuer<Role>>,
+    req_id: RequestId,
+    policy: ManagedClaimsPolicy,
+) -> HandlerResult<Json<TokenResponse>> {
+    let now = Utc::now();
+    let grant = issuer
+        .login(&request, now.timestamp())
+        .await
+        .map_err(|e| rejected(e, req_id))?;
+    Ok(Json(sign_grant(grant, now, &policy)?))
+}
+
+// Exchanges a refresh token for new tokens.
+#[post("/refresh", format = "json", data = "<request>")]
+pub async fn refresh(
+    request: Json<RefreshRequest>,
+    issuer: &State<TokenIssuer<Role>>,
+    req_id: RequestId,
+    policy: ManagedClaimsPolicy,
+) -> HandlerResult<Json<TokenResponse>> {
+    let now = Utc::now();
+    let grant = issuer
+        .refresh(&request, now.timestamp())
+        .await
+        .map_err(|e| rejected(e, req_id))?;
+    Ok(Json(sign_grant(grant, now, &policy)?))
+}
+
+fn rejected(e: IssueError, req_id: RequestId) -> ErrorResponder<'static> {
+    event!(target: USER_MS_TARGET, Level::WARN, %req_id, "token request rejected: {e}");
+    e.into()
+}
+
+/// Sign the access token of `grant`.
+fn sign_grant(
+    grant: Grant<Role>,
+    now: DateTime<Utc>,
+    policy: &ManagedClaimsPolicy,
+) -> HandlerResult<TokenResponse> {
+    let claims = JWTClaims::issue(
+        grant.sub.clone(),
+        grant.role.clone(),
+        now,
+        Duration::seconds(grant.access_ttl.as_secs() as i64),
+        &policy.0,
+    );
+    let access_token = sign_jwt(&claims)?;
+    Ok(grant.response(access_token, claims.exp))
 }
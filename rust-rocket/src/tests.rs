@@ -1,61 +1,62 @@
+// Rocket errors are large but only ever returned from failing tests.
+#![allow(clippy::result_large_err, clippy::large_enum_variant)]
+
 use crate::{
-    catchers, fairings, routes,
-    types::{JWTClaims, Role},
-    TEST_JWT_SECRET,
+    build_rocket,
+    routes::download_formatter,
+    types::{GenderCount, JWTClaims, Role},
+    with_dev_tokens, with_token_issuer, TEST_JWT_SECRET, USER_PATH,
+};
+use bootstrap::{
+    claims::ClaimsPolicy,
+    route_policy::RoutePolicy,
+    token::{
+        hash_password, Accounts, TokenIssuer, TokenResponse, TokenSettings, LOGIN_PATH,
+        REFRESH_PATH,
+    },
+    DevTokenResponse, DEV_TOKEN_PATH,
 };
 use chrono::{Duration, Utc};
 use hmac::{Hmac, Mac};
 use jwt::SignWithKey;
 use rocket::{
-    http::{ContentType, Header, Status},
+    http::{Accept, ContentType, Header, Status},
     local::blocking::Client,
     Build, Rocket,
 };
 use serde_json::{json, Value};
 use sha2::Sha256;
-use std::sync::{Arc, Once};
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, Once},
+};
+use test_support::mock::MockDatabase;
 use thiserror::Error;
 use tracing::{event, Level};
 use tracing_subscriber::EnvFilter;
-use user_persist::persistence::PersistenceResult;
+use user_persist::clock::{Clock, MockClock};
 use user_persist::{
+    export::FormatRegistry,
     persistence::{PersistenceError, UserPersistence},
-    types::{Email, Gender, UpdateUser, User, UserKey, UserSearch},
+    types::{Email, Gender, User, UserSearch},
 };
 
-const USER_PATH: &str = "/api/v1/user";
-
 fn get_rocket() -> Rocket<Build> {
-    let mongo_pesist: Arc<dyn UserPersistence> = Arc::new(TestPersistence);
-    rocket::build()
-        .manage(mongo_pesist)
-        .attach(fairings::RequestIdFairing)
-        .attach(fairings::LoggerFairing)
-        .attach(fairings::RequestTimer)
-        .mount(
-            USER_PATH,
-            routes![
-                routes::count_genders,
-                routes::get_user,
-                routes::save_user,
-                routes::find_users,
-                routes::update_user,
-                // routes::download
-            ],
-        )
-        .register(
-            USER_PATH,
-            catchers![
-                catchers::not_found,
-                catchers::bad_request,
-                catchers::unprocessable_entry,
-                catchers::internal_server_error
-            ],
-        )
+    let mongo_pesist: Arc<dyn UserPersistence> = Arc::new(test_persistence());
+    build_rocket(mongo_pesist, None).mount(USER_PATH, routes![panic_route])
+}
+
+fn get_rocket_with_clock(clock: Arc<dyn Clock>) -> Rocket<Build> {
+    get_rocket().manage(clock)
 }
 
 const TEST_TARGET: &str = "test";
 
+#[get("/panic")]
+fn panic_route() -> &'static str {
+    panic!("test panic")
+}
+
 static INIT: Once = Once::new();
 
 #[derive(Debug, Error)]
@@ -74,8 +75,10 @@ enum TestError {
 
 type TestResult<T> = Result<T, TestError>;
 
-#[derive(Debug, Clone)]
-pub struct TestPersistence;
+/// Looking up this user fails as if the database was down.
+const UNAVAILABLE_USER_ID: &str = "000000000000000000000000";
+/// Saving a user with this email fails as a duplicate.
+const DUPLICATE_EMAIL: &str = "duplicate@test.com";
 
 fn test_user() -> User {
     User {
@@ -84,48 +87,45 @@ fn test_user() -> User {
         email: Email(String::from("test@test.com")),
         age: 100,
         gender: Gender::Male,
+        phone: None,
+        address: None,
+        created_at: None,
+        updated_at: None,
     }
 }
 
 // A mock persistence for testing.
-#[async_trait]
-impl UserPersistence for TestPersistence {
-    async fn get_user(&self, id: &UserKey) -> Result<Option<User>, PersistenceError> {
-        if id.0 == "61c0d1954c6b974ca7000000" {
-            Ok(Some(test_user()))
-        } else {
-            Ok(None)
-        }
-    }
-
-    async fn save_user(&self, user: &User) -> Result<User, PersistenceError> {
-        Ok(user.clone())
-    }
-
-    async fn update_user(&self, _user: &UpdateUser) -> Result<(), PersistenceError> {
-        Ok(())
-    }
-
-    async fn remove_user(&self, _user: &UserKey) -> PersistenceResult<()> {
-        todo!()
-    }
-
-    async fn search_users(&self, _user_search: &UserSearch) -> Result<Vec<User>, PersistenceError> {
-        Ok(vec![test_user()])
-    }
-
-    async fn count_genders(&self) -> Result<Vec<Value>, PersistenceError> {
-        Ok(vec![
-            json! (   {
-                "_id": "Male",
-                "count": 6
-            }),
-            json!({
-                "_id": "Female",
-                "count": 12
-            }),
-        ])
-    }
+fn test_persistence() -> MockDatabase {
+    MockDatabase::builder()
+        .on_get_user(|id| match id.to_string().as_str() {
+            "61c0d1954c6b974ca7000000" => Ok(Some(test_user())),
+            UNAVAILABLE_USER_ID => Err(PersistenceError::TestError),
+            _ => Ok(None),
+        })
+        .on_get_user_by_email(|email| {
+            let user = test_user();
+            Ok(user.email.eq_ignore_ascii_case(email).then_some(user))
+        })
+        .on_save_user(|user| {
+            if user.email.as_str() == DUPLICATE_EMAIL {
+                return Err(PersistenceError::DuplicateKey);
+            }
+            Ok(user.clone())
+        })
+        .on_search_users(|_, _| Ok(vec![test_user()]))
+        .on_count_genders(|| {
+            Ok(vec![
+                json!({
+                    "gender": "Male",
+                    "count": 6
+                }),
+                json!({
+                    "gender": "Female",
+                    "count": 12
+                }),
+            ])
+        })
+        .build()
 }
 
 // Setup tracing first.
@@ -143,23 +143,13 @@ type HmacSha256 = Hmac<Sha256>;
 
 fn test_jwt(role: Role) -> String {
     let key = HmacSha256::new_from_slice(TEST_JWT_SECRET).unwrap();
-    let expiration = Utc::now() + Duration::minutes(5);
-    let claims = JWTClaims {
-        sub: "somebody".to_owned(),
+    let claims = JWTClaims::issue(
+        "somebody".to_owned(),
         role,
-        exp: expiration.timestamp(),
-    };
-    format!("Bearer {}", claims.sign_with_key(&key).unwrap())
-}
-
-fn test_jwt_expired(role: Role) -> String {
-    let key = HmacSha256::new_from_slice(TEST_JWT_SECRET).unwrap();
-    let expiration = Utc::now() - Duration::minutes(5);
-    let claims = JWTClaims {
-        sub: "somebody".to_owned(),
-        role,
-        exp: expiration.timestamp(),
-    };
+        Utc::now(),
+        Duration::minutes(5),
+        &ClaimsPolicy::default(),
+    );
     format!("Bearer {}", claims.sign_with_key(&key).unwrap())
 }
 
@@ -180,6 +170,25 @@ fn get_user() -> TestResult<()> {
     Ok(())
 }
 
+#[test]
+fn get_user_by_email() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let get_user = |email: &str| {
+        client
+            .get(format!("/api/v1/user/by-email/{email}"))
+            .header(Header::new("Authorization", test_jwt(Role::Admin)))
+            .dispatch()
+    };
+
+    let response = get_user("Test@Test.com");
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_json::<User>(), Some(test_user()));
+
+    assert_eq!(get_user("nobody@test.com").status(), Status::NotFound);
+    Ok(())
+}
+
 // Call get user with User role and valid user.
 #[test]
 fn get_user_invalid_access() -> TestResult<()> {
@@ -195,24 +204,63 @@ fn get_user_invalid_access() -> TestResult<()> {
     let body = response.into_string().unwrap_or_default();
     event!(target: TEST_TARGET, Level::DEBUG, "response: {body}");
     assert_eq!(status, Status::Forbidden);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?["reason"],
+        "wrong_role"
+    );
     Ok(())
 }
 
-// Call get user with User role and valid user but with a jwt that has expired
+// Call get user with Admin role and valid user but with a jwt that has expired
 #[test]
 fn get_user_invalid_access_expired_claim() -> TestResult<()> {
     init_log();
 
+    let clock = Arc::new(MockClock::new(Utc::now()));
+    let client = Client::tracked(get_rocket_with_clock(clock.clone()))?;
+    let jwt = test_jwt(Role::Admin);
+    let get_user = || {
+        client
+            .get("/api/v1/user/61c0d1954c6b974ca7000000")
+            .header(Header::new("Authorization", jwt.clone()))
+            .dispatch()
+    };
+
+    // Test tokens expire after 5 minutes and are accepted for a minute
+    // longer.
+    clock.advance(Duration::minutes(5));
+    assert_eq!(get_user().status(), Status::Ok);
+
+    clock.advance(Duration::minutes(2));
+    let response = get_user();
+    let status = response.status();
+    let body = response.into_string().unwrap_or_default();
+    event!(target: TEST_TARGET, Level::DEBUG, "response: {body}");
+    assert_eq!(status, Status::Unauthorized);
+    assert_eq!(serde_json::from_str::<Value>(&body)?["reason"], "expired");
+    Ok(())
+}
+
+// Call get user without a jwt.
+#[test]
+fn get_user_missing_token() -> TestResult<()> {
+    init_log();
+
     let client = Client::tracked(get_rocket())?;
     let response = client
         .get("/api/v1/user/61c0d1954c6b974ca7000000")
-        .header(Header::new("Authorization", test_jwt_expired(Role::User)))
         .dispatch();
 
-    let status = response.status();
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(
+        response.headers().get_one("WWW-Authenticate"),
+        Some("Bearer")
+    );
     let body = response.into_string().unwrap_or_default();
-    event!(target: TEST_TARGET, Level::DEBUG, "response: {body}");
-    assert_eq!(status, Status::Forbidden);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body)?["reason"],
+        "missing_header"
+    );
     Ok(())
 }
 
@@ -235,6 +283,36 @@ fn save_user() -> TestResult<()> {
     Ok(())
 }
 
+#[test]
+fn save_user_content_type() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let json_user = serde_json::to_string(&test_user())?;
+    let save_user = |content_type: Option<&str>| {
+        let mut request = client
+            .post("/api/v1/user")
+            .header(Header::new("Authorization", test_jwt(Role::User)))
+            .body(&json_user);
+        if let Some(content_type) = content_type {
+            request = request.header(Header::new("Content-Type", content_type.to_owned()));
+        }
+        request.dispatch()
+    };
+
+    assert_eq!(
+        save_user(Some("application/json; charset=utf-8")).status(),
+        Status::Ok
+    );
+
+    for content_type in [None, Some("text/plain")] {
+        let response = save_user(content_type);
+        assert_eq!(response.status(), Status::UnsupportedMediaType);
+        let body = serde_json::from_str::<Value>(&response.into_string().unwrap_or_default())?;
+        assert_eq!(body.get("label"), Some(&json!("unsupported.media_type")));
+    }
+    Ok(())
+}
+
 #[test]
 fn save_user_rejection() -> TestResult<()> {
     init_log();
@@ -293,6 +371,7 @@ fn search_users() -> TestResult<()> {
         email: Some(Email("test@somewhere.com".to_owned())),
         gender: None,
         name: None,
+        ..Default::default()
     };
     let response = client
         .post("/api/v1/user/search")
@@ -304,6 +383,54 @@ fn search_users() -> TestResult<()> {
     Ok(())
 }
 
+#[test]
+fn search_users_unfiltered() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let search = |uri: &'static str, body: &'static str| {
+        client
+            .post(uri)
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", test_jwt(Role::Admin)))
+            .body(body)
+            .dispatch()
+            .status()
+    };
+
+    assert_eq!(search("/api/v1/user/search", "{}"), Status::BadRequest);
+    assert_eq!(search("/api/v1/user/search?all=true", "{}"), Status::Ok);
+    assert_eq!(
+        search("/api/v1/user/search", r#"{"nmae": "Test User"}"#),
+        Status::BadRequest
+    );
+    Ok(())
+}
+
+#[test]
+fn search_users_stream() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .post("/api/v1/user/search?stream=true")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", test_jwt(Role::Admin)))
+        .body(r#"{"name": "Test User"}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.content_type(),
+        Some(ContentType::new("application", "x-ndjson"))
+    );
+
+    let body = response.into_string().unwrap_or_default();
+    let users = body
+        .lines()
+        .map(serde_json::from_str::<User>)
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(users, vec![test_user()]);
+    Ok(())
+}
+
 #[test]
 fn count_genders() -> TestResult<()> {
     init_log();
@@ -319,6 +446,387 @@ fn count_genders() -> TestResult<()> {
     event!(target: TEST_TARGET, Level::DEBUG, "body: {body}");
 
     assert_eq!(status, Status::Ok);
+    assert_eq!(
+        serde_json::from_str::<Vec<GenderCount>>(&body)?,
+        vec![
+            GenderCount {
+                gender: Gender::Male,
+                count: 6
+            },
+            GenderCount {
+                gender: Gender::Female,
+                count: 12
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn persistence_error_status() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .get(format!("/api/v1/user/{UNAVAILABLE_USER_ID}"))
+        .header(Header::new("Authorization", test_jwt(Role::Admin)))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    let body = serde_json::from_str::<Value>(&response.into_string().unwrap_or_default())?;
+    assert_eq!(body.get("label"), Some(&json!("persistence.error")));
+    assert!(body.get("requestId").and_then(Value::as_str).is_some());
+
+    let user = User {
+        email: Email(DUPLICATE_EMAIL.to_owned()),
+        ..test_user()
+    };
+    let response = client
+        .post("/api/v1/user")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", test_jwt(Role::User)))
+        .body(serde_json::to_string(&user)?)
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Conflict);
+    Ok(())
+}
+
+#[test]
+fn get_user_not_found() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .get("/api/v1/user/71c0d1954c6b974ca7000000")
+        .header(Header::new("Authorization", test_jwt(Role::Admin)))
+        .dispatch();
+
+    let status = response.status();
+    let body = serde_json::from_str::<Value>(&response.into_string().unwrap_or_default())?;
+    event!(target: TEST_TARGET, Level::DEBUG, "response: {body}");
+
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(body.get("label"), Some(&json!("not.found")));
+    assert!(body.get("requestId").and_then(Value::as_str).is_some());
+    Ok(())
+}
+
+#[test]
+fn handler_panic() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client.get("/api/v1/user/panic").dispatch();
+
+    let status = response.status();
+    let body = serde_json::from_str::<Value>(&response.into_string().unwrap_or_default())?;
+    event!(target: TEST_TARGET, Level::DEBUG, "response: {body}");
 
+    assert_eq!(status, Status::InternalServerError);
+    assert_eq!(body.get("label"), Some(&json!("internal.error")));
+    Ok(())
+}
+
+#[test]
+fn head_user() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .head("/api/v1/user/61c0d1954c6b974ca7000000")
+        .header(Header::new("Authorization", test_jwt(Role::Admin)))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    assert_eq!(response.into_string().unwrap_or_default(), "");
+    Ok(())
+}
+
+#[test]
+fn options_user() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .options("/api/v1/user/61c0d1954c6b974ca7000000")
+        .dispatch();
+
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(
+        response.headers().get_one("Allow"),
+        Some("GET, HEAD, OPTIONS")
+    );
+
+    let response = client.options("/api/v1/user/search").dispatch();
+    assert_eq!(response.headers().get_one("Allow"), Some("POST, OPTIONS"));
+    Ok(())
+}
+
+#[test]
+fn method_not_allowed() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .delete("/api/v1/user/61c0d1954c6b974ca7000000")
+        .dispatch();
+
+    assert_eq!(response.status(), Status::MethodNotAllowed);
+    assert_eq!(
+        response.headers().get_one("Allow"),
+        Some("GET, HEAD, OPTIONS")
+    );
+    let body = serde_json::from_str::<Value>(&response.into_string().unwrap_or_default())?;
+    assert_eq!(body["label"], "method.not_allowed");
+
+    let response = client.put("/api/v1/user/search").dispatch();
+    assert_eq!(response.status(), Status::MethodNotAllowed);
+    assert_eq!(response.headers().get_one("Allow"), Some("POST, OPTIONS"));
+
+    let response = client.get("/api/v1/user/unknown/path").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    Ok(())
+}
+
+#[test]
+fn dev_token() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(with_dev_tokens(get_rocket()))?;
+    let response = client
+        .post(DEV_TOKEN_PATH)
+        .header(ContentType::JSON)
+        .body(json!({"role": "Admin", "sub": "dev"}).to_string())
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    let minted: DevTokenResponse = serde_json::from_str(&response.into_string().unwrap())?;
+
+    let response = client
+        .get("/api/v1/user/61c0d1954c6b974ca7000000")
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", minted.token),
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = client
+        .post(DEV_TOKEN_PATH)
+        .header(ContentType::JSON)
+        .body(json!({"role": "Admin", "sub": "dev", "ttl": 0}).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    Ok(())
+}
+
+#[test]
+fn dev_token_disabled() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .post(DEV_TOKEN_PATH)
+        .header(ContentType::JSON)
+        .body(json!({"role": "Admin", "sub": "dev"}).to_string())
+        .dispatch();
+
+    assert_eq!(response.status(), Status::NotFound);
+    Ok(())
+}
+
+#[test]
+fn route_policy() -> TestResult<()> {
+    init_log();
+    let policy = RoutePolicy::from_json(
+        br#"{"GET /api/v1/user/:id": ["Admin", "User"], "POST /api/v1/user": ["Admin"]}"#,
+    )
+    .unwrap();
+    let client = Client::tracked(get_rocket().manage(policy))?;
+    let get_user = |role| {
+        client
+            .get("/api/v1/user/61c0d1954c6b974ca7000000")
+            .header(Header::new("Authorization", test_jwt(role)))
+            .dispatch()
+            .status()
+    };
+    assert_eq!(get_user(Role::User), Status::Ok);
+    assert_eq!(get_user(Role::Admin), Status::Ok);
+
+    let save_user = |role| {
+        client
+            .post("/api/v1/user")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", test_jwt(role)))
+            .body(serde_json::to_string(&test_user()).unwrap())
+            .dispatch()
+            .status()
+    };
+    assert_eq!(save_user(Role::User), Status::Forbidden);
+    assert_eq!(save_user(Role::Admin), Status::Ok);
+    Ok(())
+}
+
+#[test]
+fn login_and_refresh() -> TestResult<()> {
+    init_log();
+    let hash = hash_password("secret", NonZeroU32::new(1).unwrap()).unwrap();
+    let accounts = format!(r#"{{"droberts": {{"role": "Admin", "password": "{hash}"}}}}"#);
+    let issuer = TokenIssuer::new(
+        Accounts::from_json(accounts.as_bytes()).unwrap(),
+        TokenSettings::default(),
+    );
+    let client = Client::tracked(with_token_issuer(get_rocket(), issuer))?;
+    let post = |path, body: Value| {
+        client
+            .post(path)
+            .header(ContentType::JSON)
+            .body(body.to_string())
+            .dispatch()
+    };
+
+    let response = post(
+        LOGIN_PATH,
+        json!({"username": "droberts", "password": "guess"}),
+    );
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let response = post(
+        LOGIN_PATH,
+        json!({"username": "droberts", "password": "secret"}),
+    );
+    assert_eq!(response.status(), Status::Ok);
+    let login: TokenResponse = serde_json::from_str(&response.into_string().unwrap())?;
+
+    let response = client
+        .get("/api/v1/user/61c0d1954c6b974ca7000000")
+        .header(Header::new(
+            "Authorization",
+            format!("Bearer {}", login.access_token),
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = post(
+        REFRESH_PATH,
+        json!({"refreshToken": login.refresh_token.clone()}),
+    );
+    assert_eq!(response.status(), Status::Ok);
+    let response = post(REFRESH_PATH, json!({"refreshToken": login.refresh_token}));
+    assert_eq!(response.status(), Status::Unauthorized);
+    Ok(())
+}
+
+#[test]
+fn download_format() {
+    let formats = FormatRegistry::default();
+    let name = |format, accept: Option<Accept>| {
+        download_formatter(&formats, format, accept.as_ref()).map(|f| f.name())
+    };
+
+    assert_eq!(name(None, None).ok(), Some("json"));
+    assert_eq!(name(Some("CSV"), Some(Accept::JSON)).ok(), Some("csv"));
+    assert_eq!(
+        name(None, "application/xml, text/csv;q=0.9".parse().ok()).ok(),
+        Some("csv")
+    );
+    assert_eq!(
+        name(None, "text/csv;q=0.5, application/x-ndjson".parse().ok()).ok(),
+        Some("ndjson")
+    );
+    assert_eq!(
+        name(None, "text/csv;q=0, application/xml".parse().ok()).ok(),
+        Some("json")
+    );
+    assert_eq!(name(None, Some(Accept::HTML)).ok(), Some("json"));
+    assert!(name(Some("xml"), None).is_err());
+}
+
+const TEST_REQUEST_ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+#[test]
+fn user_snapshot() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .get("/api/v1/user/61c0d1954c6b974ca7000000")
+        .header(Header::new("Authorization", test_jwt(Role::Admin)))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    let body = serde_json::from_str::<Value>(&response.into_string().unwrap_or_default())?;
+    insta::assert_json_snapshot!(body, @r###"
+    {
+      "name": "Test User",
+      "age": 100,
+      "email": "test@test.com",
+      "gender": "Male"
+    }
+    "###);
+    Ok(())
+}
+
+#[test]
+fn user_not_found_snapshot() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .get("/api/v1/user/71c0d1954c6b974ca7000000")
+        .header(Header::new("Authorization", test_jwt(Role::Admin)))
+        .header(Header::new("X-Request-Id", TEST_REQUEST_ID))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::NotFound);
+    let body = serde_json::from_str::<Value>(&response.into_string().unwrap_or_default())?;
+    insta::assert_json_snapshot!(body, @r###"
+    {
+      "label": "not.found",
+      "message": "Resource not found",
+      "requestId": "67e55044-10b1-426f-9247-bb680e5fe0c8"
+    }
+    "###);
+    Ok(())
+}
+
+#[test]
+fn validation_failure_snapshot() -> TestResult<()> {
+    init_log();
+    let client = Client::tracked(get_rocket())?;
+    let response = client
+        .post("/api/v1/user")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", test_jwt(Role::User)))
+        .header(Header::new("X-Request-Id", TEST_REQUEST_ID))
+        .body(r#"{"name": "Test User", "age": 5, "email": "bad-email-value", "gender": "Male"}"#)
+        .dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = serde_json::from_str::<Value>(&response.into_string().unwrap_or_default())?;
+    // Validation errors are held in a hash map so their order is not stable.
+    insta::with_settings!({ sort_maps => true }, {
+        insta::assert_json_snapshot!(body, @r###"
+        {
+          "label": "bad.request",
+          "message": "validation failed",
+          "requestId": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+          "validation": {
+            "age": [
+              {
+                "code": "range",
+                "message": null,
+                "params": {
+                  "min": 100.0,
+                  "value": 5
+                }
+              }
+            ],
+            "email": [
+              {
+                "code": "invalid email",
+                "message": null,
+                "params": {
+                  "value": "bad-email-value"
+                }
+              }
+            ]
+          }
+        }
+        "###);
+    });
     Ok(())
 }
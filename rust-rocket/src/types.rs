@@ -1,16 +1,32 @@
-use crate::{fairings::RequestId, FRAMEWORK_TARGET};
-use chrono::{DateTime, Utc};
+use crate::{
+    context::{RequestContext, RequestId},
+    FRAMEWORK_TARGET,
+};
+use bootstrap::{
+    claims::{ClaimsError, ClaimsPolicy, RegisteredClaims},
+    token::IssueError,
+    DevTokenError,
+};
+use chrono::{DateTime, Duration, Utc};
+use errors::{ApiError, AuthError};
 use mongodb::bson::oid::ObjectId;
 use rocket::{
     http::{ContentType, Header, Status},
     request::{FromParam, Request},
     response::{Responder, Response},
-    serde::{json::serde_json::to_string, Deserialize, Serialize},
+    serde::{
+        json::serde_json::{self, json, to_string, Value},
+        Deserialize, Serialize,
+    },
 };
 use std::io::Cursor;
 use thiserror::Error;
 use tracing::{event, Level};
-use user_persist::{persistence::PersistenceError, types::UserKey, Validate};
+use user_persist::{
+    persistence::PersistenceError,
+    types::{Gender, UserKey},
+    Validate,
+};
 
 pub const USER_MS_TARGET: &str = "user-ms";
 
@@ -24,7 +40,7 @@ impl<'a> FromParam<'a> for UserKeyReq {
 
     fn from_param(param: &'a str) -> Result<Self, Self::Error> {
         let object_id = ObjectId::parse_str(param)?;
-        Ok(UserKeyReq(UserKey(object_id.to_string())))
+        Ok(UserKeyReq(UserKey::from(object_id)))
     }
 }
 
@@ -39,37 +55,105 @@ pub struct JsonValidation<T: Validate>(pub T);
 pub struct ErrorResponder<'a> {
     label: &'a str,
     message: String,
+    /// Status overriding the default of 422.
+    #[serde(skip)]
+    status: Option<Status>,
+}
+
+impl<'a> ErrorResponder<'a> {
+    /// Error response with an explicit status.
+    pub fn with_status(label: &'a str, message: impl Into<String>, status: Status) -> Self {
+        ErrorResponder {
+            label,
+            message: message.into(),
+            status: Some(status),
+        }
+    }
+}
+
+impl From<ApiError> for ErrorResponder<'static> {
+    fn from(err: ApiError) -> Self {
+        ErrorResponder {
+            label: err.label,
+            message: err.message,
+            status: Some(Status::new(err.status.as_u16())),
+        }
+    }
 }
 
 impl From<PersistenceError> for ErrorResponder<'static> {
     fn from(err: PersistenceError) -> Self {
+        ApiError::from(&err).into()
+    }
+}
+
+impl From<serde_json::Error> for ErrorResponder<'static> {
+    fn from(err: serde_json::Error) -> Self {
+        ApiError::from(&err).into()
+    }
+}
+
+impl From<DevTokenError> for ErrorResponder<'static> {
+    fn from(err: DevTokenError) -> Self {
+        ApiError::from(&err).into()
+    }
+}
+
+impl From<IssueError> for ErrorResponder<'static> {
+    fn from(err: IssueError) -> Self {
+        ApiError::from(&err).into()
+    }
+}
+
+impl From<JWTError> for ErrorResponder<'static> {
+    fn from(err: JWTError) -> Self {
         ErrorResponder {
             message: err.to_string(),
-            label: "persistence.error",
+            label: "token.error",
+            status: None,
         }
     }
 }
 
-/// Error responder to set a status of 422 and as JSON error resonse.
+/// Error responder answering with the status of the error, 422 by default,
+/// and the `{label, message, requestId}` JSON body used by the catchers.
 impl<'r> Responder<'r, 'static> for ErrorResponder<'static> {
     fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
-        let json = to_string(&self).unwrap_or_default();
-        let req_id = req
-            .local_cache(|| RequestId(None))
-            .0
-            .unwrap_or_default()
-            .to_string();
+        let RequestId(req_id) = RequestContext::of(req).request_id();
+        let json = to_string(&json!({
+          "label": self.label,
+          "message": self.message,
+          "requestId": req_id.map(|id| id.to_string()),
+        }))
+        .unwrap_or_default();
         Response::build()
             .header(ContentType::JSON)
-            .header(Header::new("X-Request-Id", req_id))
-            .status(Status::UnprocessableEntity)
+            .header(Header::new(
+                "X-Request-Id",
+                req_id.unwrap_or_default().to_string(),
+            ))
+            .status(self.status.unwrap_or(Status::UnprocessableEntity))
             .sized_body(json.len(), Cursor::new(json))
             .ok()
     }
 }
 
+/// Number of users of a gender.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct GenderCount {
+    pub gender: Gender,
+    pub count: u64,
+}
+
+impl GenderCount {
+    /// Read the `{"gender": .., "count": ..}` groups of a gender count.
+    pub fn from_groups(groups: Vec<Value>) -> Result<Vec<Self>, serde_json::Error> {
+        groups.into_iter().map(serde_json::from_value).collect()
+    }
+}
+
 /// Enumeration of Roles
-#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
 pub enum Role {
     Admin,
     User,
@@ -85,6 +169,15 @@ pub struct JWTClaims {
     pub role: Role,
     /// Expiration date time in unix epoch.
     pub exp: i64,
+    /// Issued at date time in unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    /// Audience the token was issued for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Issuer of the token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
 }
 
 /// Error type for all errors that
@@ -108,27 +201,86 @@ pub enum JWTError {
     InvalidRole,
     #[error("JWT has expired")]
     Expired,
+    #[error("JWT claims rejected: {source}")]
+    InvalidClaims { source: ClaimsError },
+}
+
+impl From<ClaimsError> for JWTError {
+    fn from(source: ClaimsError) -> Self {
+        match source {
+            ClaimsError::Expired => Self::Expired,
+            _ => Self::InvalidClaims { source },
+        }
+    }
+}
+
+impl JWTError {
+    /// Missing or invalid tokens are unauthorized while a valid token
+    /// without the required role is forbidden.
+    pub fn status(&self) -> Status {
+        match self {
+            Self::InvalidRole => Status::Forbidden,
+            Self::InvalidJwtLength { .. } => Status::InternalServerError,
+            Self::NoAuthorizationHeader
+            | Self::VerificationFailed { .. }
+            | Self::Expired
+            | Self::InvalidClaims { .. } => Status::Unauthorized,
+        }
+    }
+
+    /// Reason reported to the client. Server side key errors are not the
+    /// client's to fix and have none.
+    pub fn reason(&self) -> Option<AuthError> {
+        match self {
+            Self::NoAuthorizationHeader => Some(AuthError::MissingHeader),
+            Self::VerificationFailed { .. } => Some(AuthError::InvalidSignature),
+            Self::InvalidRole => Some(AuthError::WrongRole),
+            Self::Expired => Some(AuthError::Expired),
+            Self::InvalidClaims { .. } => Some(AuthError::InvalidClaims),
+            Self::InvalidJwtLength { .. } => None,
+        }
+    }
 }
 
 impl JWTClaims {
-    /// Method that checks if the JWT has expired.
-    /// This is has a max age of 5 minutes.
-    pub fn check_expired(self) -> Result<Self, JWTError> {
-        let exp = DateTime::from_timestamp(self.exp, 0).ok_or(JWTError::Expired)?;
-        let now = Utc::now();
-        let exp_minutes = (exp - now).num_minutes();
+    /// Claims issued at `now` for `ttl` with the audience and issuer
+    /// required by `policy`.
+    pub fn issue(
+        sub: String,
+        role: Role,
+        now: DateTime<Utc>,
+        ttl: Duration,
+        policy: &ClaimsPolicy,
+    ) -> Self {
+        Self {
+            sub,
+            role,
+            exp: (now + ttl).timestamp(),
+            iat: Some(now.timestamp()),
+            aud: policy.audience.clone(),
+            iss: policy.issuer.clone(),
+        }
+    }
 
+    /// Method that checks the registered claims against `policy` at `now`.
+    pub fn check(self, policy: &ClaimsPolicy, now: DateTime<Utc>) -> Result<Self, JWTError> {
         event!(
           target: FRAMEWORK_TARGET,
           Level::DEBUG,
-          "Jwt expires in: {exp_minutes} minutes"
+          "Jwt expires in: {} minutes",
+          (self.exp - now.timestamp()) / 60
         );
 
-        if exp_minutes <= 0 {
-            Err(JWTError::Expired)
-        } else {
-            Ok(self)
-        }
+        policy.validate(
+            RegisteredClaims {
+                exp: self.exp,
+                iat: self.iat,
+                aud: self.aud.as_deref(),
+                iss: self.iss.as_deref(),
+            },
+            now.timestamp(),
+        )?;
+        Ok(self)
     }
 }
 
@@ -139,3 +291,8 @@ pub struct UserAccess(#[allow(dead_code)] pub JWTClaims);
 /// JWT Claims when the role is Admin
 #[derive(Debug)]
 pub struct AdminAccess(#[allow(dead_code)] pub JWTClaims);
+
+/// Claims policy managed by the rocket instance, otherwise the default
+/// policy.
+#[derive(Debug)]
+pub struct ManagedClaimsPolicy(pub ClaimsPolicy);
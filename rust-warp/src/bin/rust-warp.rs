@@ -1,35 +1,52 @@
 // mod argparse;
 
+use bootstrap::{install_panic_hook, metrics::install_metrics_exporter};
 use clap::Parser;
 use rust_warp::{filters::user, ServerOptions};
-use std::sync::Arc;
-use tracing::info;
-use tracing_subscriber::EnvFilter;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::{info, warn};
 use user_persist::mongo_persistence::MongoPersistence;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        // .json()
-        // .flatten_event(true)
-        .pretty()
-        .init();
-
     let server_args = ServerOptions::parse();
+    if server_args.bootstrap.check {
+        server_args.self_check().await.exit();
+    }
+
+    let bootstrap = server_args.bootstrap()?;
+    bootstrap.init_tracing();
+
+    install_panic_hook();
 
     info!("Using options: {server_args}");
+    info!("Starting with the {} profile", bootstrap.profile);
+
+    install_metrics_exporter(SocketAddr::from(([0, 0, 0, 0], server_args.metrics_port)))?;
 
-    let api = user(Arc::new(
-        MongoPersistence::new(server_args.mongo_args).await?,
-    ));
+    let email_validation = server_args.mongo_args.email_validation();
+    let mongo_args = server_args
+        .mongo_args
+        .with_allow_invalid_certificates(bootstrap.allow_invalid_certificates);
+    let api = user(
+        Arc::new(MongoPersistence::new(mongo_args).await?),
+        email_validation,
+    );
 
-    warp::serve(api)
-        .tls()
-        .cert_path(server_args.server_cert)
-        .key_path(server_args.server_key)
-        .run(([127, 0, 0, 1], 8443))
-        .await;
+    match server_args.server_cert.zip(server_args.server_key) {
+        Some((cert, key)) => {
+            warp::serve(api)
+                .tls()
+                .cert_path(cert)
+                .key_path(key)
+                .run(([127, 0, 0, 1], 8443))
+                .await
+        }
+        None => {
+            warn!("Serving plain HTTP without a TLS certificate");
+            warp::serve(api).run(([127, 0, 0, 1], 8443)).await
+        }
+    }
 
     Ok(())
 }
@@ -1,18 +1,122 @@
-use crate::handlers;
-use serde_json::json;
-use std::{convert::Infallible, sync::Arc};
+use crate::{
+    handlers,
+    types::{ApiRejection, SearchParams},
+};
+use api_types::{CreateUserRequest, SearchRequest};
+use bootstrap::content_type::{check_json, UNSUPPORTED_MEDIA_TYPE_LABEL};
+use bootstrap::metrics::REQUEST_DURATION_METRIC;
+use errors::ApiError;
+use futures::{Future, FutureExt};
+use serde::de::DeserializeOwned;
+use std::{convert::Infallible, panic::AssertUnwindSafe, sync::Arc};
 use tracing::{event, info_span, Level};
-use user_persist::{persistence::UserPersistence, types::UserKey};
+use user_persist::{
+    persistence::UserPersistence,
+    types::{EmailValidation, UserKey, ValidateRequest},
+    validation::count_failures,
+};
 use uuid::Uuid;
-use warp::Filter;
+use warp::{
+    body::BodyDeserializeError,
+    http::{
+        header::{HeaderValue, ALLOW, WWW_AUTHENTICATE},
+        Method, StatusCode,
+    },
+    log::Info,
+    path::FullPath,
+    reject::{InvalidQuery, MethodNotAllowed, PayloadTooLarge},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
 
 const FRAMEWORK_TARGET: &str = "ms-framework";
+const REQ_ID_HEADER: &str = "x-request-id";
+
+/// Literal path segments under the user api. Any other segment is an id.
+const USER_ROUTE_LITERALS: &[&str] = &["search", "counts"];
+
+/// Warp does not expose the matched route so rebuild the template from
+/// the path, replacing id segments with a placeholder.
+fn route_template(path: &str) -> String {
+    match path.trim_end_matches('/').strip_prefix("/api/v1/user") {
+        Some("") => "/api/v1/user".to_owned(),
+        Some(rest) => match rest.trim_start_matches('/') {
+            segment if USER_ROUTE_LITERALS.contains(&segment) => format!("/api/v1/user/{segment}"),
+            segment if !segment.contains('/') => "/api/v1/user/{id}".to_owned(),
+            _ => "unmatched".to_owned(),
+        },
+        None => "unmatched".to_owned(),
+    }
+}
+
+/// Methods each route template accepts, for the `Allow` header of a 405
+/// or an OPTIONS response.
+fn allowed_methods(route: &str) -> Option<&'static str> {
+    match route {
+        "/api/v1/user" | "/api/v1/user/search" => Some("POST, OPTIONS"),
+        "/api/v1/user/{id}" | "/api/v1/user/counts" => Some("GET, HEAD, OPTIONS"),
+        _ => None,
+    }
+}
+
+/// JSON request body validated with emails checked in `mode`. Bodies with
+/// another or no content type are rejected with
+/// [`ApiRejection::UnsupportedMediaType`] and invalid ones with
+/// [`ApiRejection::Validation`], counting their validation failures.
+fn json_body<T>(mode: EmailValidation) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + ValidateRequest + Send,
+{
+    warp::header::optional::<String>("content-type")
+        .and_then(|content_type: Option<String>| async move {
+            check_json(content_type.as_deref())
+                .map_err(|e| warp::reject::custom(ApiRejection::UnsupportedMediaType(e)))
+        })
+        .untuple_one()
+        .and(warp::body::json())
+        .and_then(move |body: T| async move {
+            body.validate_request(mode).map(|_| body).map_err(|e| {
+                count_failures(&e);
+                warp::reject::custom(ApiRejection::Validation(e))
+            })
+        })
+}
+
+/// Accepts GET requests and HEAD requests, whose body hyper drops.
+fn get_or_head() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::get().or(warp::head()).unify()
+}
+
+/// Log the duration of each request and record it in the latency histogram.
+fn record_request_duration(info: Info) {
+    let latency = info.elapsed();
+    let route = route_template(info.path());
+
+    event!(
+      target: FRAMEWORK_TARGET,
+      Level::INFO,
+      "{} {route} completed in {} ms",
+      info.method(),
+      latency.as_millis()
+    );
+
+    metrics::histogram!(
+        REQUEST_DURATION_METRIC,
+        latency.as_secs_f64(),
+        "method" => info.method().to_string(),
+        "route" => route,
+        "status" => info.status().as_u16().to_string()
+    );
+}
 
 type UserPersist = Arc<dyn UserPersistence>;
 
-/// Provides the persistence API
-fn with_db(db: UserPersist) -> impl Filter<Extract = (UserPersist,), Error = Infallible> + Clone {
-    warp::any().map(move || db.clone())
+/// Provides a clone of `state`, such as the persistence API, to handlers.
+fn with_state<S>(state: S) -> impl Filter<Extract = (S,), Error = Infallible> + Clone
+where
+    S: Clone + Send + Sync,
+{
+    warp::any().map(move || state.clone())
 }
 
 fn test_wrapper<F, T>(
@@ -35,9 +139,31 @@ where
         })
 }
 
-/// Top level filter for the User API.
+/// Runs a handler converting a panic into an [`ApiRejection::Panic`] so
+/// the client gets a 500 rather than a reset connection.
+async fn catch_panic<F, R>(handler: F) -> Result<Response, Rejection>
+where
+    F: Future<Output = Result<R, Rejection>>,
+    R: Reply,
+{
+    match AssertUnwindSafe(handler).catch_unwind().await {
+        Ok(result) => result.map(Reply::into_response),
+        Err(_) => Err(warp::reject::custom(ApiRejection::Panic)),
+    }
+}
+
+/// Extracts the caller supplied request id.
+fn request_id() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::header::optional::<String>(REQ_ID_HEADER)
+        .or(warp::any().map(|| None::<String>))
+        .unify()
+}
+
+/// Top level filter for the User API, validating the emails of requests in
+/// `email_validation`.
 pub fn user(
     db: UserPersist,
+    email_validation: EmailValidation,
 ) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
     let base_path = warp::path("api")
         .and(warp::path("v1"))
@@ -45,71 +171,209 @@ pub fn user(
 
     let routes = base_path.and(
         get_user(db.clone())
-            .or(search_users(db.clone()))
-            .or(save_user(db.clone()))
+            .or(search_users(db.clone(), email_validation))
+            .or(save_user(db.clone(), email_validation))
             .or(count_genders(db)),
     );
 
-    routes
+    let api = routes
     .with(warp::filters::compression::gzip())
     .with(warp::trace(|req| {
       let headers = req.request_headers();
-      let req_id = headers.get("x-request-id")
+      let req_id = headers.get(REQ_ID_HEADER)
         .and_then(|v| v.to_str().ok().map(String::from))
         .unwrap_or_else(|| Uuid::new_v4().to_string());
       info_span!(target: FRAMEWORK_TARGET, "request-span", %req_id, method = %req.method(), path = %req.path())
-    }))
-    // .map(|reply| {
-    //   warp::reply::with_header(reply, "x-request-id", "abc")
-    // })
-    .recover(handle_rejection)
-    .with(warp::wrap_fn(test_wrapper))
-}
-
-async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infallible> {
-    let error_body = json!({
-      "label": "error",
-      "message": format!("{err:?}"),
-    });
-    let json = warp::reply::json(&error_body);
-    Ok(warp::reply::with_status(
-        json,
-        warp::http::StatusCode::BAD_REQUEST,
-    ))
+    }));
+
+    // Rejections are handled here rather than with `recover` so the error
+    // envelope has access to the request.
+    request_id()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(
+            api.map(|reply| Ok::<_, Rejection>(Reply::into_response(reply)))
+                .or_else(|rejection| async move {
+                    Ok::<_, Infallible>((Err::<Response, _>(rejection),))
+                }),
+        )
+        .map(
+            |req_id: Option<String>,
+             method: Method,
+             path: FullPath,
+             result: Result<Response, Rejection>| {
+                result.unwrap_or_else(|err| handle_rejection(req_id, &method, path.as_str(), err))
+            },
+        )
+        .with(warp::wrap_fn(test_wrapper))
+        .with(warp::log::custom(record_request_duration))
+}
+
+/// Map a rejection to the JSON error envelope.
+fn handle_rejection(
+    req_id: Option<String>,
+    method: &Method,
+    path: &str,
+    err: Rejection,
+) -> Response {
+    // No route matches OPTIONS so it is answered here for any known route.
+    if method == Method::OPTIONS {
+        if let Some(allow) = allowed_methods(&route_template(path)) {
+            let mut response = StatusCode::NO_CONTENT.into_response();
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static(allow));
+            return response;
+        }
+    }
+
+    // Warp combines the rejections of every route tried, so the ones raised
+    // once a route matched the path are looked for first.
+    let error = if let Some(rejection) = err.find::<ApiRejection>() {
+        ApiError::from(rejection)
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        // Raised by warp's own JSON filter for `+json` content types.
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            UNSUPPORTED_MEDIA_TYPE_LABEL,
+            e,
+        )
+    } else if let Some(e) = err.find::<BodyDeserializeError>() {
+        // The other routes add their method rejections to a search with an
+        // invalid body, so it is checked before them.
+        ApiError::new(StatusCode::BAD_REQUEST, "json_parse.failed", e)
+    } else if let Some(e) = err.find::<InvalidQuery>() {
+        ApiError::new(StatusCode::BAD_REQUEST, "query.invalid", e)
+    } else if let Some(e) = err.find::<PayloadTooLarge>() {
+        ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "body.too_large", e)
+    } else if err.find::<MethodNotAllowed>().is_some() {
+        ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method.not_allowed",
+            format!("Method {method} not allowed for {path}"),
+        )
+    } else if err.is_not_found() {
+        ApiError::not_found(format!("No route for {method} {path}"))
+    } else {
+        // Details of other rejections stay in the logs.
+        event!(
+          target: FRAMEWORK_TARGET,
+          Level::ERROR,
+          "Unhandled rejection of {method} {path}: {err:?}"
+        );
+        ApiError::internal()
+    };
+    let status = error.status;
+
+    event!(
+      target: FRAMEWORK_TARGET,
+      Level::WARN,
+      "Rejected {method} {path} with {status}"
+    );
+
+    let mut response = warp::reply::with_status(
+        warp::reply::json(&error.envelope(req_id.as_deref())),
+        status,
+    )
+    .into_response();
+
+    if let Some(challenge) = error.reason.and_then(|reason| reason.challenge()) {
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+    }
+    if status == StatusCode::METHOD_NOT_ALLOWED {
+        if let Some(allow) = allowed_methods(&route_template(path)) {
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static(allow));
+        }
+    }
+
+    response
 }
 
 pub fn get_user(
     db: UserPersist,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path!(UserKey)
-        .and(warp::get())
-        .and(with_db(db))
-        .and_then(handlers::handle_get_user)
+        .and(get_or_head())
+        .and(with_state(db))
+        .and_then(|id: UserKey, db: UserPersist| catch_panic(handlers::handle_get_user(id, db)))
 }
 
 pub fn search_users(
     db: UserPersist,
+    email_validation: EmailValidation,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("search")
         .and(warp::post())
-        .and(warp::body::json())
-        .and(with_db(db))
-        .and_then(handlers::handle_search_users)
+        .and(json_body(email_validation))
+        .and(warp::query::<SearchParams>())
+        .and(with_state(db))
+        .and_then(
+            |search: SearchRequest, params: SearchParams, db: UserPersist| {
+                catch_panic(handlers::handle_search_users(search, params, db))
+            },
+        )
 }
 
 pub fn save_user(
     db: UserPersist,
+    email_validation: EmailValidation,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::post()
-        .and(warp::body::json())
-        .and(with_db(db))
-        .and_then(handlers::handle_save_user)
+        .and(json_body(email_validation))
+        .and(with_state(db))
+        .and_then(|request: CreateUserRequest, db: UserPersist| {
+            catch_panic(handlers::handle_save_user(request, db))
+        })
 }
 
 pub fn count_genders(
     db: UserPersist,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("counts")
-        .and(with_db(db))
-        .and_then(handlers::handle_count_genders)
+        .and(get_or_head())
+        .and(with_state(db))
+        .and_then(|db: UserPersist| catch_panic(handlers::handle_count_genders(db)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{catch_panic, route_template};
+    use crate::types::ApiRejection;
+    use warp::Rejection;
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        let result = catch_panic(async {
+            if true {
+                panic!("test panic");
+            }
+            Ok::<_, Rejection>(warp::reply())
+        })
+        .await;
+
+        assert!(matches!(
+            result.unwrap_err().find::<ApiRejection>(),
+            Some(ApiRejection::Panic)
+        ));
+    }
+
+    #[test]
+    fn test_route_template() {
+        assert_eq!(
+            route_template("/api/v1/user/61c0d1954c6b974ca7000000"),
+            "/api/v1/user/{id}"
+        );
+        assert_eq!(route_template("/api/v1/user/search"), "/api/v1/user/search");
+        assert_eq!(
+            route_template("/api/v1/user/counts/"),
+            "/api/v1/user/counts"
+        );
+        assert_eq!(route_template("/api/v1/user"), "/api/v1/user");
+        assert_eq!(route_template("/api/v1/user/a/b"), "unmatched");
+        assert_eq!(route_template("/other"), "unmatched");
+    }
 }
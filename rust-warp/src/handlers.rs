@@ -1,17 +1,28 @@
-use crate::types::WarpPersistenceError;
-use std::sync::Arc;
+use crate::types::{ApiRejection, SearchParams};
+use api_types::{CreateUserRequest, SearchRequest, UserResponse};
+use futures::StreamExt;
+use std::{error::Error, sync::Arc};
 use tracing::{event, instrument, Level};
 use user_persist::{
-    persistence::{PersistenceError, UserPersistence},
+    persistence::{
+        search_capped, CappedSearch, PersistenceError, UserPersistence, DEFAULT_MAX_SEARCH_RESULTS,
+    },
     types::{User, UserKey, UserSearch},
 };
-use warp::{http::StatusCode, reply, Rejection, Reply};
+use warp::{
+    http::{header::CONTENT_TYPE, StatusCode},
+    hyper::Body,
+    reply::{self, Response},
+    Rejection, Reply,
+};
 
-fn to_warp_error(err: PersistenceError) -> WarpPersistenceError {
-    WarpPersistenceError(err.to_string())
+fn to_warp_error(err: PersistenceError) -> Rejection {
+    warp::reject::custom(ApiRejection::from(err))
 }
 
 const USER_MS_TARGET: &str = "user-ms";
+/// Content type of newline delimited JSON.
+const NDJSON: &str = "application/x-ndjson";
 
 type UserPersist = Arc<dyn UserPersistence>;
 
@@ -24,33 +35,71 @@ pub async fn handle_get_user(id: UserKey, db: UserPersist) -> Result<impl Reply,
     let user = db.get_user(&id).await.map_err(to_warp_error)?;
     event!(target: USER_MS_TARGET, Level::DEBUG, "User: {user:?}");
     match user {
-        Some(u) => Ok(reply::json(&u).into_response()),
-        None => Ok(reply::with_status("", StatusCode::NOT_FOUND).into_response()),
+        Some(u) => Ok(reply::json(&UserResponse::from(u))),
+        None => Err(warp::reject::custom(ApiRejection::NotFound(format!(
+            "No user with id {id}"
+        )))),
     }
 }
 
 #[instrument(skip(db, search), name = "request-span", target = "user-ms")]
 pub async fn handle_search_users(
-    search: UserSearch,
+    search: SearchRequest,
+    params: SearchParams,
     db: UserPersist,
-) -> Result<impl Reply, Rejection> {
+) -> Result<Response, Rejection> {
+    let search = UserSearch::from(search);
     event!(
       target: USER_MS_TARGET,
       Level::DEBUG,
       "searching with {search:?}"
     );
-    let users = db.search_users(&search).await.map_err(to_warp_error)?;
+    if search.is_empty() && !params.all {
+        return Err(warp::reject::custom(ApiRejection::UnfilteredSearch));
+    }
+    if params.stream {
+        // A failing stream aborts the response rather than truncating it.
+        let stream = db
+            .search_users_stream(&search)
+            .await
+            .map_err(to_warp_error)?
+            .map(|user| -> Result<String, Box<dyn Error + Send + Sync>> {
+                let user = UserResponse::from(user?);
+                Ok(format!("{}\n", serde_json::to_string(&user)?))
+            });
+        let response = Response::new(Body::wrap_stream(stream));
+        return Ok(reply::with_header(response, CONTENT_TYPE, NDJSON).into_response());
+    }
+    let CappedSearch { users, truncated } =
+        search_capped(db.as_ref(), &search, DEFAULT_MAX_SEARCH_RESULTS)
+            .await
+            .map_err(to_warp_error)?;
     event!(
       target: USER_MS_TARGET,
       Level::DEBUG,
       "search result: {users:?}"
     );
-    Ok(reply::json(&users))
+    let status = if truncated {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+    let users = users
+        .into_iter()
+        .map(UserResponse::from)
+        .collect::<Vec<_>>();
+    Ok(reply::with_status(reply::json(&users), status).into_response())
 }
 
-pub async fn handle_save_user(user: User, db: UserPersist) -> Result<impl Reply, Rejection> {
-    let saved_user = db.save_user(&user).await.map_err(to_warp_error)?;
-    Ok(reply::json(&saved_user))
+pub async fn handle_save_user(
+    request: CreateUserRequest,
+    db: UserPersist,
+) -> Result<impl Reply, Rejection> {
+    let saved_user = db
+        .save_user(&User::from(request))
+        .await
+        .map_err(to_warp_error)?;
+    Ok(reply::json(&UserResponse::from(saved_user)))
 }
 
 pub async fn handle_count_genders(db: UserPersist) -> Result<impl Reply, Rejection> {
@@ -3,6 +3,7 @@ pub mod filters;
 mod handlers;
 mod types;
 
+use bootstrap::{check::CheckReport, Bootstrap, BootstrapArgs, BootstrapError, Configured};
 use clap::Parser;
 use std::{
     fmt::{self, Display},
@@ -13,12 +14,44 @@ use user_persist::MongoArgs;
 #[derive(Parser, Debug, Clone)]
 #[clap(about, version, author)]
 pub struct ServerOptions {
-    #[clap(long)]
-    pub server_cert: PathBuf,
-    #[clap(long)]
-    pub server_key: PathBuf,
+    #[clap(long, requires = "server_key")]
+    pub server_cert: Option<PathBuf>,
+    #[clap(long, requires = "server_cert")]
+    pub server_key: Option<PathBuf>,
+    #[clap(long, default_value = "9100")]
+    pub metrics_port: u16,
     #[clap(flatten)]
     pub mongo_args: MongoArgs,
+    #[clap(flatten)]
+    pub bootstrap: BootstrapArgs,
+}
+
+impl ServerOptions {
+    /// Resolve the startup settings for the deployment profile. The warp
+    /// service doesn't authorize requests so it has no JWT secret.
+    pub fn bootstrap(&self) -> Result<Bootstrap, BootstrapError> {
+        self.bootstrap.resolve(Configured {
+            jwt_secret: None,
+            tls: self.server_cert.is_some() && self.server_key.is_some(),
+            allow_invalid_certificates: self.mongo_args.allow_invalid_certificates(),
+        })
+    }
+
+    /// Run the startup self-check.
+    pub async fn self_check(self) -> CheckReport {
+        let mut report = CheckReport::default();
+        let bootstrap = report.record("config", self.bootstrap());
+        report.tls_files(self.server_cert.as_deref().zip(self.server_key.as_deref()));
+        report.jwt_secret(None);
+        let mongo_args = match bootstrap {
+            Some(bootstrap) => self
+                .mongo_args
+                .with_allow_invalid_certificates(bootstrap.allow_invalid_certificates),
+            None => self.mongo_args,
+        };
+        report.mongo(mongo_args).await;
+        report
+    }
 }
 
 impl Display for ServerOptions {
@@ -1,14 +1,87 @@
-use serde::{Deserialize, Serialize};
-use user_persist::persistence::PersistenceError;
-use warp::reject::Reject;
+use bootstrap::content_type::ContentTypeError;
+use errors::{ApiError, AuthError};
+use serde::Deserialize;
+use user_persist::{persistence::PersistenceError, ValidationErrors};
+use warp::{http::StatusCode, reject::Reject};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct WarpPersistenceError(pub String);
+/// Typed rejection of the user api. Each variant maps to the status and
+/// label every service answers the failure with.
+#[derive(Debug)]
+pub enum ApiRejection {
+    /// The requested user doesn't exist.
+    NotFound(String),
+    /// The request body failed validation.
+    Validation(ValidationErrors),
+    /// The request's token was missing or rejected.
+    Auth(AuthError),
+    /// A database operation failed.
+    Database(ApiError),
+    /// A search without criteria.
+    UnfilteredSearch,
+    /// A JSON body with another or no content type.
+    UnsupportedMediaType(ContentTypeError),
+    /// A handler panicked.
+    Panic,
+}
 
-impl Reject for WarpPersistenceError {}
+impl Reject for ApiRejection {}
 
-impl From<PersistenceError> for WarpPersistenceError {
+impl From<PersistenceError> for ApiRejection {
     fn from(err: PersistenceError) -> Self {
-        WarpPersistenceError(err.to_string())
+        ApiRejection::Database(ApiError::from(&err))
+    }
+}
+
+impl From<&ApiRejection> for ApiError {
+    fn from(rejection: &ApiRejection) -> Self {
+        match rejection {
+            ApiRejection::NotFound(message) => ApiError::not_found(message),
+            ApiRejection::Validation(e) => {
+                ApiError::new(StatusCode::BAD_REQUEST, "validation.failed", e)
+            }
+            ApiRejection::Auth(e) => ApiError::from(*e),
+            ApiRejection::Database(e) => e.clone(),
+            ApiRejection::UnfilteredSearch => ApiError::unfiltered_search(),
+            ApiRejection::UnsupportedMediaType(e) => ApiError::from(e),
+            ApiRejection::Panic => ApiError::internal(),
+        }
+    }
+}
+
+/// Query parameters for a search.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchParams {
+    /// Stream the results as newline delimited JSON.
+    pub stream: bool,
+    /// Search every user when no criteria are given.
+    pub all: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::ApiRejection;
+    use errors::{ApiError, AuthError};
+    use user_persist::persistence::PersistenceError;
+    use warp::http::StatusCode;
+
+    #[test]
+    fn test_rejection_errors() {
+        let error = |rejection: ApiRejection| ApiError::from(&rejection);
+        assert_eq!(
+            error(ApiRejection::NotFound("User not found".to_owned())).label,
+            "not.found"
+        );
+        assert_eq!(
+            error(PersistenceError::Timeout.into()).status,
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        let auth = error(ApiRejection::Auth(AuthError::Expired));
+        assert_eq!(auth.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(auth.reason, Some(AuthError::Expired));
+        assert_eq!(
+            error(ApiRejection::Panic).status,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
     }
 }
@@ -1,19 +1,17 @@
-use async_trait::async_trait;
 use flate2::read::GzDecoder;
 use rust_warp::filters::user;
 use serde_json::{from_str, json, Value};
 use std::{
     convert::Infallible,
-    fmt::Debug,
     io::Read,
     sync::{Arc, Once},
 };
+use test_support::mock::MockDatabase;
 use tracing::{event, Level};
 use tracing_subscriber::EnvFilter;
-use user_persist::persistence::PersistenceResult;
 use user_persist::{
-    persistence::{PersistenceError, UserPersistence},
-    types::{Email, Gender, UpdateUser, User, UserKey, UserSearch},
+    persistence::PersistenceError,
+    types::{Email, EmailValidation, Gender, User},
 };
 use warp::{hyper::body::Bytes, Filter, Reply};
 
@@ -31,9 +29,6 @@ fn init_log() {
     });
 }
 
-#[derive(Debug, Clone)]
-pub struct TestPersistence;
-
 fn test_user() -> User {
     User {
         id: None,
@@ -41,45 +36,26 @@ fn test_user() -> User {
         email: Email(String::from("test@test.com")),
         age: 100,
         gender: Gender::Male,
+        phone: None,
+        address: None,
+        created_at: None,
+        updated_at: None,
     }
 }
 
 // A mock persistence for testing.
-#[async_trait]
-impl UserPersistence for TestPersistence {
-    async fn get_user(&self, id: &UserKey) -> Result<Option<User>, PersistenceError> {
-        if id.0 == "61c0d1954c6b974ca7000000" {
-            Ok(Some(test_user()))
-        } else {
-            Ok(None)
-        }
-    }
-
-    async fn save_user(&self, user: &User) -> Result<User, PersistenceError> {
-        Ok(user.clone())
-    }
-
-    async fn update_user(&self, _user: &UpdateUser) -> Result<(), PersistenceError> {
-        Ok(())
-    }
-
-    async fn remove_user(&self, _user: &UserKey) -> PersistenceResult<()> {
-        todo!()
-    }
-
-    async fn search_users(&self, _user_search: &UserSearch) -> Result<Vec<User>, PersistenceError> {
-        Ok(vec![test_user()])
-    }
-
-    async fn count_genders(&self) -> Result<Vec<Value>, PersistenceError> {
-        Err(PersistenceError::TestError)
-    }
+fn test_persistence() -> MockDatabase {
+    MockDatabase::builder()
+        .on_get_user(|id| Ok((id.to_string() == "61c0d1954c6b974ca7000000").then(test_user)))
+        .on_search_users(|_, _| Ok(vec![test_user()]))
+        .fail_count_with(PersistenceError::TestError)
+        .build()
 }
 
 fn test_user_filter() -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
     init_log();
-    let test_db = Arc::new(TestPersistence);
-    user(test_db)
+    let test_db = Arc::new(test_persistence());
+    user(test_db, EmailValidation::Lenient)
 }
 
 fn decompress_body(b: Bytes) -> String {
@@ -120,12 +96,12 @@ async fn test_get_user_404() {
     let res = warp::test::request()
         .path("/api/v1/user/abc")
         .reply(&filter)
-        .await
-        .map(decompress_body);
+        .await;
 
     event!(target: TEST_TARGET, Level::DEBUG, "Body: {:?}", res.body());
 
     assert_eq!(res.status(), 404);
+    assert_eq!(error_label(res.body()), "not.found");
 }
 
 // Good bson. Does not find result.
@@ -135,10 +111,266 @@ async fn test_get_user_no_user() {
     let res = warp::test::request()
         .path("/api/v1/user/61c0e3c94c6b977028000000")
         .reply(&filter)
-        .await
-        .map(decompress_body);
+        .await;
+
+    event!(target: TEST_TARGET, Level::DEBUG, "Body: {:?}", res.body());
+
+    // Errors are answered outside the compression layer.
+    assert_eq!(res.status(), 404);
+    assert_eq!(error_label(res.body()), "not.found");
+}
+
+// No route for path.
+#[tokio::test]
+async fn test_unmatched_route() {
+    let filter = test_user_filter();
+    let res = warp::test::request()
+        .path("/api/v1/unknown")
+        .header("x-request-id", "test-request-id")
+        .reply(&filter)
+        .await;
+
+    event!(target: TEST_TARGET, Level::DEBUG, "Body: {:?}", res.body());
+
+    assert_eq!(res.status(), 404);
+    let body = from_str::<Value>(std::str::from_utf8(res.body()).unwrap()).unwrap();
+    assert_eq!(body.get("label"), Some(&json!("not.found")));
+    assert_eq!(body.get("requestId"), Some(&json!("test-request-id")));
+}
+
+// Route exists but not for the method used.
+#[tokio::test]
+async fn test_method_not_allowed() {
+    let filter = test_user_filter();
+    let res = warp::test::request()
+        .method("PUT")
+        .path("/api/v1/user/61c0d1954c6b974ca7000000")
+        .reply(&filter)
+        .await;
 
     event!(target: TEST_TARGET, Level::DEBUG, "Body: {:?}", res.body());
 
+    assert_eq!(res.status(), 405);
+    assert_eq!(res.headers().get("allow").unwrap(), "GET, HEAD, OPTIONS");
+    let body = from_str::<Value>(std::str::from_utf8(res.body()).unwrap()).unwrap();
+    assert_eq!(body.get("label"), Some(&json!("method.not_allowed")));
+}
+
+#[tokio::test]
+async fn test_head_user() {
+    let filter = test_user_filter();
+    let res = warp::test::request()
+        .method("HEAD")
+        .path("/api/v1/user/61c0d1954c6b974ca7000000")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+}
+
+#[tokio::test]
+async fn test_options_user() {
+    let filter = test_user_filter();
+    let res = warp::test::request()
+        .method("OPTIONS")
+        .path("/api/v1/user/61c0d1954c6b974ca7000000")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 204);
+    assert_eq!(res.headers().get("allow").unwrap(), "GET, HEAD, OPTIONS");
+
+    let res = warp::test::request()
+        .method("OPTIONS")
+        .path("/api/v1/user/search")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.headers().get("allow").unwrap(), "POST, OPTIONS");
+}
+
+#[tokio::test]
+async fn test_search_users_stream() {
+    let filter = test_user_filter();
+    let res = warp::test::request()
+        .method("POST")
+        .path("/api/v1/user/search?stream=true")
+        .json(&json!({"name": "Test User"}))
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.headers().get("content-type").unwrap(),
+        "application/x-ndjson"
+    );
+    let body = decompress_body(res.into_body());
+    let users = body
+        .lines()
+        .map(|line| from_str::<User>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(users, vec![test_user()]);
+}
+
+#[tokio::test]
+async fn test_search_users_unfiltered() {
+    let filter = test_user_filter();
+    let search = |path: &str, body: Value| {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .json(&body)
+            .reply(&filter)
+    };
+
+    let res = search("/api/v1/user/search", json!({})).await;
+    assert_eq!(res.status(), 400);
+
+    let res = search("/api/v1/user/search?all=true", json!({})).await;
+    assert_eq!(res.status(), 200);
+
+    let res = search("/api/v1/user/search", json!({"nmae": "Test User"})).await;
+    assert_eq!(res.status(), 400);
+}
+
+#[tokio::test]
+async fn test_save_user_content_type() {
+    let filter = test_user_filter();
+    let body = json!({"name": "Test User", "age": 100, "email": "test@test.com", "gender": "Male"});
+    let save_user = |content_type: Option<&str>| {
+        let mut req = warp::test::request()
+            .method("POST")
+            .path("/api/v1/user")
+            .body(body.to_string());
+        if let Some(content_type) = content_type {
+            req = req.header("content-type", content_type);
+        }
+        req.reply(&filter)
+    };
+
+    let res = save_user(Some("application/json; charset=utf-8")).await;
+    assert_eq!(res.status(), 200);
+
+    for content_type in [None, Some("text/plain")] {
+        let res = save_user(content_type).await;
+        assert_eq!(res.status(), 415);
+        let body = from_str::<Value>(std::str::from_utf8(res.body()).unwrap()).unwrap();
+        assert_eq!(body.get("label"), Some(&json!("unsupported.media_type")));
+    }
+}
+
+#[tokio::test]
+async fn test_user_snapshot() {
+    let filter = test_user_filter();
+    let res = warp::test::request()
+        .path("/api/v1/user/61c0d1954c6b974ca7000000")
+        .reply(&filter)
+        .await
+        .map(decompress_body)
+        .map(|b| from_str::<Value>(&b).unwrap());
+
+    assert_eq!(res.status(), 200);
+    insta::assert_json_snapshot!(res.into_body(), @r###"
+    {
+      "name": "Test User",
+      "age": 100,
+      "email": "test@test.com",
+      "gender": "Male"
+    }
+    "###);
+}
+
+#[tokio::test]
+async fn test_unmatched_route_snapshot() {
+    let filter = test_user_filter();
+    let res = warp::test::request()
+        .path("/api/v1/unknown")
+        .header("x-request-id", "test-request-id")
+        .reply(&filter)
+        .await;
+
     assert_eq!(res.status(), 404);
+    let body = from_str::<Value>(std::str::from_utf8(res.body()).unwrap()).unwrap();
+    insta::assert_json_snapshot!(body, @r###"
+    {
+      "label": "not.found",
+      "message": "No route for GET /api/v1/unknown",
+      "requestId": "test-request-id"
+    }
+    "###);
+}
+
+#[tokio::test]
+async fn test_method_not_allowed_snapshot() {
+    let filter = test_user_filter();
+    let res = warp::test::request()
+        .method("PUT")
+        .path("/api/v1/user/61c0d1954c6b974ca7000000")
+        .reply(&filter)
+        .await;
+
+    assert_eq!(res.status(), 405);
+    let body = from_str::<Value>(std::str::from_utf8(res.body()).unwrap()).unwrap();
+    insta::assert_json_snapshot!(body, @r###"
+    {
+      "label": "method.not_allowed",
+      "message": "Method PUT not allowed for /api/v1/user/61c0d1954c6b974ca7000000",
+      "requestId": null
+    }
+    "###);
+}
+
+fn error_label(body: &Bytes) -> Value {
+    from_str::<Value>(std::str::from_utf8(body).unwrap()).unwrap()["label"].clone()
+}
+
+// Rejections of the route matching the path win over the method and path
+// rejections warp collects from the other routes.
+#[tokio::test]
+async fn test_rejection_order() {
+    let filter = test_user_filter();
+    let post = |path: &str, body: &str| {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("content-type", "application/json")
+            .body(body)
+            .reply(&filter)
+    };
+
+    let res = post("/api/v1/user/search", "{").await;
+    assert_eq!(res.status(), 400);
+    assert_eq!(error_label(res.body()), "json_parse.failed");
+
+    let res = post(
+        "/api/v1/user",
+        r#"{"name": "Test User", "age": 99, "email": "test@test.com", "gender": "Male"}"#,
+    )
+    .await;
+    assert_eq!(res.status(), 400);
+    assert_eq!(error_label(res.body()), "validation.failed");
+
+    let res = post(
+        "/api/v1/user/search?stream=maybe",
+        r#"{"name": "Test User"}"#,
+    )
+    .await;
+    assert_eq!(res.status(), 400);
+    assert_eq!(error_label(res.body()), "query.invalid");
+
+    let res = post("/api/v1/user/search", "{}").await;
+    assert_eq!(error_label(res.body()), "search.unfiltered");
+
+    let res = warp::test::request()
+        .path("/api/v1/user/counts")
+        .reply(&filter)
+        .await;
+    assert_eq!(res.status(), 503);
+    let body = from_str::<Value>(std::str::from_utf8(res.body()).unwrap()).unwrap();
+    assert_eq!(body["label"], "persistence.error");
+    assert_eq!(body["message"], "Database unavailable");
 }
@@ -0,0 +1,116 @@
+/*!
+Fixtures for integration tests against real services.
+
+[`MongoFixture`] starts a disposable MongoDB container with
+[testcontainers](https://docs.rs/testcontainers). Tests using it need a
+docker daemon so they are marked `#[ignore]` and run with:
+
+```text
+cargo test -p test-support -- --ignored
+```
+
+[`mock::MockDatabase`] is an in-process persistence scripted per test.
+*/
+pub mod mock;
+
+use lazy_static::lazy_static;
+use mongodb::{
+    bson::doc,
+    options::{ClientOptions, ServerAddress},
+    Client,
+};
+use std::time::Duration;
+use testcontainers::{clients::Cli, core::WaitFor, Container, GenericImage, RunnableImage};
+use tracing::debug;
+use user_persist::{mongo_persistence::MongoPersistence, persistence::PersistenceResult};
+
+/// Tracing target for test fixtures.
+pub const TEST_SUPPORT_TARGET: &str = "test-support";
+
+const MONGO_IMAGE: &str = "mongo";
+const MONGO_TAG: &str = "6.0";
+const MONGO_PORT: u16 = 27017;
+const REPLICA_SET: &str = "rs0";
+
+lazy_static! {
+    static ref DOCKER: Cli = Cli::default();
+}
+
+/// A disposable MongoDB. The container is removed when the fixture is
+/// dropped.
+///
+/// The server runs as a single node replica set so transactions and change
+/// streams are available.
+pub struct MongoFixture {
+    client: Client,
+    _container: Container<'static, GenericImage>,
+}
+
+impl MongoFixture {
+    /// Start the container and wait until the replica set has a primary.
+    pub async fn start() -> Self {
+        let image = GenericImage::new(MONGO_IMAGE, MONGO_TAG)
+            .with_exposed_port(MONGO_PORT)
+            .with_wait_for(WaitFor::message_on_stdout("Waiting for connections"));
+        let args = vec![
+            "--replSet".to_owned(),
+            REPLICA_SET.to_owned(),
+            "--bind_ip_all".to_owned(),
+        ];
+        let container = DOCKER.run(RunnableImage::from((image, args)));
+        let port = container.get_host_port_ipv4(MONGO_PORT);
+
+        let options = ClientOptions::builder()
+            .hosts(vec![ServerAddress::Tcp {
+                host: "127.0.0.1".to_owned(),
+                port: Some(port),
+            }])
+            .direct_connection(true)
+            .build();
+        let client = Client::with_options(options).expect("mongodb client");
+
+        let fixture = Self {
+            client,
+            _container: container,
+        };
+        fixture.initiate_replica_set().await;
+        fixture
+    }
+
+    /// Initiate the replica set and poll until this node is writable.
+    async fn initiate_replica_set(&self) {
+        let admin = self.client.database("admin");
+        admin
+            .run_command(
+                doc! {"replSetInitiate": {
+                  "_id": REPLICA_SET,
+                  "members": [{"_id": 0, "host": format!("127.0.0.1:{MONGO_PORT}")}]
+                }},
+                None,
+            )
+            .await
+            .expect("replSetInitiate");
+
+        for attempt in 0..120 {
+            match admin.run_command(doc! {"hello": 1}, None).await {
+                Ok(hello) if hello.get_bool("isWritablePrimary").unwrap_or(false) => return,
+                hello => debug!(
+                  target: TEST_SUPPORT_TARGET,
+                  "waiting for primary, attempt {attempt}: {hello:?}"
+                ),
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+        panic!("replica set has no primary");
+    }
+
+    /// Client connected to the container.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Persistence using the database `name`, with indexes created.
+    pub async fn persistence(&self, name: &str) -> PersistenceResult<MongoPersistence> {
+        MongoPersistence::from_database(self.client.database(name), false).await
+    }
+}
@@ -0,0 +1,472 @@
+/*!
+Scriptable persistence for handler tests.
+
+[`MockDatabaseBuilder`] scripts only the methods a test exercises with
+closures instead of a full [`UserPersistence`] implementation:
+
+```
+use test_support::mock::MockDatabase;
+use user_persist::persistence::PersistenceError;
+
+let db = MockDatabase::builder()
+    .on_get_user(|_id| Ok(None))
+    .fail_search_with(PersistenceError::TestError)
+    .build();
+```
+
+Methods left out behave like an empty database. Lookups find nothing,
+writes succeed and searches, counts and statistics are empty.
+*/
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use serde_json::Value;
+use std::{fmt, sync::Arc};
+use user_persist::{
+    persistence::{PersistenceError, PersistenceResult, UserPersistence, UserStream},
+    stats::{StatsDate, StatsSnapshot},
+    types::{BulkUpdate, BulkUpdateResult, Email, UpdateUser, User, UserKey, UserSearch},
+};
+
+type Script<F> = Option<Arc<F>>;
+
+type GetUser = dyn Fn(&UserKey) -> PersistenceResult<Option<User>> + Send + Sync;
+type GetUserByEmail = dyn Fn(&Email) -> PersistenceResult<Option<User>> + Send + Sync;
+type SaveUser = dyn Fn(&User) -> PersistenceResult<User> + Send + Sync;
+type UpdateUserFn = dyn Fn(&UpdateUser) -> PersistenceResult<()> + Send + Sync;
+type RemoveUser = dyn Fn(&UserKey) -> PersistenceResult<()> + Send + Sync;
+type SearchUsers = dyn Fn(&UserSearch, u64) -> PersistenceResult<Vec<User>> + Send + Sync;
+type SearchUsersStream = dyn Fn(&UserSearch) -> PersistenceResult<UserStream> + Send + Sync;
+type BulkUpdateFn =
+    dyn Fn(&BulkUpdate, u64, bool) -> PersistenceResult<BulkUpdateResult> + Send + Sync;
+type CountGenders = dyn Fn() -> PersistenceResult<Vec<Value>> + Send + Sync;
+type SaveStatsSnapshot = dyn Fn(&StatsSnapshot) -> PersistenceResult<()> + Send + Sync;
+type StatsHistory = dyn Fn(Option<StatsDate>, Option<StatsDate>) -> PersistenceResult<Vec<StatsSnapshot>>
+    + Send
+    + Sync;
+
+/// Builder of a [`MockDatabase`] scripting each method with a closure.
+#[derive(Clone, Default)]
+pub struct MockDatabaseBuilder {
+    get_user: Script<GetUser>,
+    get_user_by_email: Script<GetUserByEmail>,
+    save_user: Script<SaveUser>,
+    update_user: Script<UpdateUserFn>,
+    remove_user: Script<RemoveUser>,
+    search_users: Script<SearchUsers>,
+    search_users_stream: Script<SearchUsersStream>,
+    bulk_update: Script<BulkUpdateFn>,
+    count_genders: Script<CountGenders>,
+    save_stats_snapshot: Script<SaveStatsSnapshot>,
+    stats_history: Script<StatsHistory>,
+}
+
+impl MockDatabaseBuilder {
+    pub fn on_get_user(
+        self,
+        f: impl Fn(&UserKey) -> PersistenceResult<Option<User>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            get_user: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Script email lookups, which otherwise search by email.
+    pub fn on_get_user_by_email(
+        self,
+        f: impl Fn(&Email) -> PersistenceResult<Option<User>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            get_user_by_email: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    pub fn on_save_user(
+        self,
+        f: impl Fn(&User) -> PersistenceResult<User> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            save_user: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    pub fn on_update_user(
+        self,
+        f: impl Fn(&UpdateUser) -> PersistenceResult<()> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            update_user: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    pub fn on_remove_user(
+        self,
+        f: impl Fn(&UserKey) -> PersistenceResult<()> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            remove_user: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Script searches with the search and its limit.
+    pub fn on_search_users(
+        self,
+        f: impl Fn(&UserSearch, u64) -> PersistenceResult<Vec<User>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            search_users: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Script streamed searches, which otherwise stream the users of
+    /// [`on_search_users`](Self::on_search_users).
+    pub fn on_search_users_stream(
+        self,
+        f: impl Fn(&UserSearch) -> PersistenceResult<UserStream> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            search_users_stream: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Script bulk updates with the update, its limit and whether it is a
+    /// dry run.
+    pub fn on_bulk_update(
+        self,
+        f: impl Fn(&BulkUpdate, u64, bool) -> PersistenceResult<BulkUpdateResult>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self {
+            bulk_update: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    pub fn on_count_genders(
+        self,
+        f: impl Fn() -> PersistenceResult<Vec<Value>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            count_genders: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    pub fn on_save_stats_snapshot(
+        self,
+        f: impl Fn(&StatsSnapshot) -> PersistenceResult<()> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            save_stats_snapshot: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    pub fn on_stats_history(
+        self,
+        f: impl Fn(Option<StatsDate>, Option<StatsDate>) -> PersistenceResult<Vec<StatsSnapshot>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self {
+            stats_history: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Fail every lookup by key with `err`.
+    pub fn fail_get_user_with(self, err: PersistenceError) -> Self {
+        self.on_get_user(move |_| Err(err.clone()))
+    }
+
+    /// Fail every save with `err`.
+    pub fn fail_save_with(self, err: PersistenceError) -> Self {
+        self.on_save_user(move |_| Err(err.clone()))
+    }
+
+    /// Fail every search, streamed or not, with `err`.
+    pub fn fail_search_with(self, err: PersistenceError) -> Self {
+        let stream_err = err.clone();
+        self.on_search_users(move |_, _| Err(err.clone()))
+            .on_search_users_stream(move |_| Err(stream_err.clone()))
+    }
+
+    /// Fail every gender count with `err`.
+    pub fn fail_count_with(self, err: PersistenceError) -> Self {
+        self.on_count_genders(move || Err(err.clone()))
+    }
+
+    pub fn build(self) -> MockDatabase {
+        MockDatabase(self)
+    }
+}
+
+/// Persistence answering with the closures of a [`MockDatabaseBuilder`].
+#[derive(Clone)]
+pub struct MockDatabase(MockDatabaseBuilder);
+
+impl MockDatabase {
+    pub fn builder() -> MockDatabaseBuilder {
+        MockDatabaseBuilder::default()
+    }
+}
+
+/// Lists the scripted methods.
+impl fmt::Debug for MockDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        let scripted = [
+            ("get_user", b.get_user.is_some()),
+            ("get_user_by_email", b.get_user_by_email.is_some()),
+            ("save_user", b.save_user.is_some()),
+            ("update_user", b.update_user.is_some()),
+            ("remove_user", b.remove_user.is_some()),
+            ("search_users", b.search_users.is_some()),
+            ("search_users_stream", b.search_users_stream.is_some()),
+            ("bulk_update", b.bulk_update.is_some()),
+            ("count_genders", b.count_genders.is_some()),
+            ("save_stats_snapshot", b.save_stats_snapshot.is_some()),
+            ("stats_history", b.stats_history.is_some()),
+        ];
+        f.debug_struct("MockDatabase")
+            .field(
+                "scripted",
+                &scripted
+                    .iter()
+                    .filter(|(_, is_scripted)| *is_scripted)
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[async_trait]
+impl UserPersistence for MockDatabase {
+    async fn get_user(&self, id: &UserKey) -> PersistenceResult<Option<User>> {
+        self.0.get_user.as_ref().map_or(Ok(None), |f| f(id))
+    }
+
+    async fn get_user_by_email(&self, email: &Email) -> PersistenceResult<Option<User>> {
+        match &self.0.get_user_by_email {
+            Some(f) => f(email),
+            None => {
+                let search = UserSearch {
+                    email: Some(email.clone()),
+                    ..Default::default()
+                };
+                Ok(self.search_users(&search, 1).await?.pop())
+            }
+        }
+    }
+
+    async fn save_user(&self, user: &User) -> PersistenceResult<User> {
+        self.0
+            .save_user
+            .as_ref()
+            .map_or_else(|| Ok(user.clone()), |f| f(user))
+    }
+
+    async fn update_user(&self, user: &UpdateUser) -> PersistenceResult<()> {
+        self.0.update_user.as_ref().map_or(Ok(()), |f| f(user))
+    }
+
+    async fn update_user_if_unchanged(
+        &self,
+        user: &UpdateUser,
+        current: &User,
+    ) -> PersistenceResult<bool> {
+        // Unchanged while the lookup finds the update time it was read with.
+        match self.get_user(&user.id).await? {
+            Some(stored) if stored.updated_at == current.updated_at => {
+                self.update_user(user).await.map(|()| true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_user(&self, id: &UserKey) -> PersistenceResult<()> {
+        self.0.remove_user.as_ref().map_or(Ok(()), |f| f(id))
+    }
+
+    async fn search_users(&self, search: &UserSearch, limit: u64) -> PersistenceResult<Vec<User>> {
+        self.0
+            .search_users
+            .as_ref()
+            .map_or(Ok(Vec::new()), |f| f(search, limit))
+    }
+
+    async fn search_users_stream(&self, search: &UserSearch) -> PersistenceResult<UserStream> {
+        match &self.0.search_users_stream {
+            Some(f) => f(search),
+            None => {
+                let users = self.search_users(search, u64::MAX).await?;
+                Ok(stream::iter(users.into_iter().map(Ok)).boxed())
+            }
+        }
+    }
+
+    async fn bulk_update(
+        &self,
+        update: &BulkUpdate,
+        limit: u64,
+        dry_run: bool,
+    ) -> PersistenceResult<BulkUpdateResult> {
+        match &self.0.bulk_update {
+            Some(f) => f(update, limit, dry_run),
+            None => Ok(BulkUpdateResult {
+                matched: 0,
+                modified: 0,
+                dry_run,
+            }),
+        }
+    }
+
+    async fn count_genders(&self) -> PersistenceResult<Vec<Value>> {
+        self.0
+            .count_genders
+            .as_ref()
+            .map_or(Ok(Vec::new()), |f| f())
+    }
+
+    async fn save_stats_snapshot(&self, snapshot: &StatsSnapshot) -> PersistenceResult<()> {
+        self.0
+            .save_stats_snapshot
+            .as_ref()
+            .map_or(Ok(()), |f| f(snapshot))
+    }
+
+    async fn stats_history(
+        &self,
+        from: Option<StatsDate>,
+        to: Option<StatsDate>,
+    ) -> PersistenceResult<Vec<StatsSnapshot>> {
+        self.0
+            .stats_history
+            .as_ref()
+            .map_or(Ok(Vec::new()), |f| f(from, to))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MockDatabase;
+    use futures::StreamExt;
+    use user_persist::{
+        clock::{Clock, SystemClock},
+        patch::Patch,
+        persistence::{PersistenceError, UserPersistence},
+        types::{Email, Gender, UpdateUser, User, UserSearch},
+    };
+
+    fn user() -> User {
+        User {
+            id: None,
+            name: "Test User".to_owned(),
+            age: 100,
+            email: Email("test@test.com".to_owned()),
+            gender: Gender::Male,
+            phone: None,
+            address: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn search() -> UserSearch {
+        UserSearch {
+            gender: Some(Gender::Male),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unscripted() {
+        let db = MockDatabase::builder().build();
+        let key = "61c0d1954c6b974ca7000000".parse().unwrap();
+        assert_eq!(db.get_user(&key).await.unwrap(), None);
+        assert_eq!(db.save_user(&user()).await.unwrap(), user());
+        assert!(db.remove_user(&key).await.is_ok());
+        assert!(db.search_users(&search(), 10).await.unwrap().is_empty());
+        assert!(format!("{db:?}").contains("scripted: []"));
+    }
+
+    #[tokio::test]
+    async fn test_scripted() {
+        let db = MockDatabase::builder()
+            .on_search_users(|_, _| Ok(vec![user()]))
+            .fail_count_with(PersistenceError::TestError)
+            .build();
+
+        let email = Email("TEST@test.com".to_owned());
+        assert_eq!(db.get_user_by_email(&email).await.unwrap(), Some(user()));
+        let streamed = db
+            .search_users_stream(&search())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(streamed.len(), 1);
+        assert!(matches!(
+            db.count_genders().await,
+            Err(PersistenceError::TestError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_update_if_unchanged() {
+        let updated_at = SystemClock.now();
+        let db = MockDatabase::builder()
+            .on_get_user(move |_| {
+                Ok(Some(User {
+                    updated_at: Some(updated_at),
+                    ..user()
+                }))
+            })
+            .build();
+        let update = UpdateUser {
+            id: "61c0d1954c6b974ca7000000".parse().unwrap(),
+            name: "New Name".to_owned(),
+            email: Email("test@test.com".to_owned()),
+            age: 100,
+            hash_id: String::new(),
+            phone: Patch::Absent,
+            address: Patch::Absent,
+        };
+
+        let current = User {
+            updated_at: Some(updated_at),
+            ..user()
+        };
+        assert!(db
+            .update_user_if_unchanged(&update, &current)
+            .await
+            .unwrap());
+        // Written by someone else since it was read.
+        assert!(!db.update_user_if_unchanged(&update, &user()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_fail_search() {
+        let db = MockDatabase::builder()
+            .fail_search_with(PersistenceError::Timeout)
+            .build();
+        assert!(matches!(
+            db.search_users(&search(), 10).await,
+            Err(PersistenceError::Timeout)
+        ));
+        assert!(matches!(
+            db.search_users_stream(&search()).await.err(),
+            Some(PersistenceError::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,455 @@
+//! End to end tests of the mongodb persistence. These need a docker
+//! daemon and are run with `cargo test -p test-support -- --ignored`.
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    change_stream::event::OperationType,
+};
+use std::{num::NonZeroU32, time::Duration};
+use test_support::MongoFixture;
+use user_persist::{
+    audit::MongoAuditLog,
+    mongo_persistence::{MongoPersistence, PendingMigration},
+    patch::Patch,
+    persistence::{PersistenceError, UserPersistence},
+    stats::AgeBucket,
+    types::{Email, Gender, Phone, UpdateUser, User, UserSearch},
+};
+
+fn test_user(email: &str) -> User {
+    User {
+        id: None,
+        name: String::from("Test User"),
+        email: Email(String::from(email)),
+        age: 100,
+        gender: Gender::Male,
+        phone: None,
+        address: None,
+        created_at: None,
+        updated_at: None,
+    }
+}
+
+fn users(persistence: &MongoPersistence) -> mongodb::Collection<Document> {
+    persistence.collection::<Document>("users")
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn creates_indexes() {
+    let fixture = MongoFixture::start().await;
+    let persistence = fixture.persistence("indexes").await.unwrap();
+
+    let indexes = users(&persistence).list_index_names().await.unwrap();
+    assert!(indexes.iter().any(|name| name == "email_normalized_1"));
+    assert_eq!(
+        persistence.pending_migrations().await.unwrap(),
+        vec![PendingMigration::AuditIndexes]
+    );
+    MongoAuditLog::new(&persistence)
+        .ensure_indexes()
+        .await
+        .unwrap();
+    assert_eq!(persistence.pending_migrations().await.unwrap(), vec![]);
+
+    // The normalized email is unique.
+    persistence
+        .save_user(&test_user("Test@Example.com"))
+        .await
+        .unwrap();
+    assert!(persistence
+        .save_user(&test_user("test@example.com"))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn user_round_trip() {
+    let fixture = MongoFixture::start().await;
+    let persistence = fixture.persistence("round_trip").await.unwrap();
+
+    let saved = persistence
+        .save_user(&test_user("test@example.com"))
+        .await
+        .unwrap();
+    let id = saved.id.clone().unwrap();
+    assert_eq!(persistence.get_user(&id).await.unwrap(), Some(saved));
+
+    persistence
+        .update_user(&UpdateUser {
+            id: id.clone(),
+            name: String::from("Updated User"),
+            email: Email(String::from("updated@example.com")),
+            age: 101,
+            hash_id: String::new(),
+            phone: Patch::Value(Phone(String::from("555-0100"))),
+            address: Patch::Absent,
+        })
+        .await
+        .unwrap();
+    let updated = persistence.get_user(&id).await.unwrap().unwrap();
+    assert_eq!(updated.phone, Some(Phone(String::from("555-0100"))));
+
+    persistence
+        .update_user(&UpdateUser {
+            id: id.clone(),
+            name: String::from("Updated User"),
+            email: Email(String::from("updated@example.com")),
+            age: 101,
+            hash_id: String::new(),
+            phone: Patch::Null,
+            address: Patch::Absent,
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        persistence.get_user(&id).await.unwrap().unwrap().phone,
+        None
+    );
+
+    let found = persistence
+        .search_users(
+            &UserSearch {
+                email: Some(Email(String::from("Updated@Example.com"))),
+                gender: None,
+                name: None,
+                ..Default::default()
+            },
+            10,
+        )
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].name, "Updated User");
+
+    let counts = persistence.count_genders().await.unwrap();
+    assert_eq!(counts.len(), 1);
+
+    let histogram = persistence
+        .age_histogram(NonZeroU32::new(10).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(
+        histogram,
+        vec![AgeBucket {
+            lower: 100,
+            upper: 110,
+            count: 1
+        }]
+    );
+
+    persistence.remove_user(&id).await.unwrap();
+    assert_eq!(persistence.get_user(&id).await.unwrap(), None);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn search_collation() {
+    let fixture = MongoFixture::start().await;
+    let persistence = fixture.persistence("collation").await.unwrap();
+
+    for (name, email) in [
+        ("Zed", "zed@example.com"),
+        ("Álvarez", "alvarez@example.com"),
+        ("bob", "bob@example.com"),
+    ] {
+        persistence
+            .save_user(&User {
+                name: String::from(name),
+                ..test_user(email)
+            })
+            .await
+            .unwrap();
+    }
+
+    // Names sort by locale rather than by code point.
+    let found = persistence
+        .search_users(
+            &UserSearch {
+                email: None,
+                gender: Some(Gender::Male),
+                name: None,
+                ..Default::default()
+            },
+            10,
+        )
+        .await
+        .unwrap();
+    let names = found.iter().map(|u| u.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["Álvarez", "bob", "Zed"]);
+
+    // Names match regardless of case.
+    let found = persistence
+        .search_users(
+            &UserSearch {
+                email: None,
+                gender: None,
+                name: Some(String::from("ÁLVAREZ")),
+                ..Default::default()
+            },
+            10,
+        )
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn transactions() {
+    let fixture = MongoFixture::start().await;
+    let persistence = fixture.persistence("transactions").await.unwrap();
+    let collection = users(&persistence);
+    let mut session = fixture.client().start_session(None).await.unwrap();
+
+    session.start_transaction(None).await.unwrap();
+    collection
+        .insert_one_with_session(doc! {"name": "aborted"}, None, &mut session)
+        .await
+        .unwrap();
+    session.abort_transaction().await.unwrap();
+    assert_eq!(
+        collection
+            .count_documents(doc! {"name": "aborted"}, None)
+            .await
+            .unwrap(),
+        0
+    );
+
+    session.start_transaction(None).await.unwrap();
+    collection
+        .insert_one_with_session(doc! {"name": "committed"}, None, &mut session)
+        .await
+        .unwrap();
+    session.commit_transaction().await.unwrap();
+    assert_eq!(
+        collection
+            .count_documents(doc! {"name": "committed"}, None)
+            .await
+            .unwrap(),
+        1
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn watch_changes() {
+    let fixture = MongoFixture::start().await;
+    let persistence = fixture.persistence("watch").await.unwrap();
+    let mut changes = users(&persistence).watch(None, None).await.unwrap();
+
+    persistence
+        .save_user(&test_user("test@example.com"))
+        .await
+        .unwrap();
+
+    let event = tokio::time::timeout(Duration::from_secs(10), changes.next())
+        .await
+        .expect("change event")
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.operation_type, OperationType::Insert);
+    assert_eq!(
+        event
+            .full_document
+            .unwrap()
+            .get_str("email_normalized")
+            .unwrap(),
+        "test@example.com"
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn aggregate_users() {
+    let fixture = MongoFixture::start().await;
+    let persistence = fixture.persistence("aggregate").await.unwrap();
+    for email in ["a@example.com", "b@example.com"] {
+        persistence.save_user(&test_user(email)).await.unwrap();
+    }
+
+    let results = persistence
+        .aggregate_users(vec![
+            doc! {"$group": {"_id": "$gender", "total": {"$sum": 1}}},
+            doc! {"$project": {"_id": 0, "gender": "$_id", "total": 1}},
+        ])
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0]["gender"], "Male");
+    assert_eq!(results[0]["total"], 2);
+
+    assert!(matches!(
+        persistence
+            .aggregate_users(vec![doc! {"$out": "copied"}])
+            .await,
+        Err(PersistenceError::InvalidQuery(_))
+    ));
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn upgrades_old_users() {
+    let fixture = MongoFixture::start().await;
+    let persistence = fixture.persistence("schema").await.unwrap();
+
+    // Users written before versioning have no schema version.
+    let id = users(&persistence)
+        .insert_one(
+            doc! {
+                "name": "Old User",
+                "age": 100,
+                "email": "old@example.com",
+                "email_normalized": "old@example.com",
+                "gender": "Female",
+            },
+            None,
+        )
+        .await
+        .unwrap()
+        .inserted_id
+        .as_object_id()
+        .unwrap();
+    let user = persistence.get_user(&id.into()).await.unwrap().unwrap();
+    assert_eq!(user.name, "Old User");
+
+    persistence
+        .save_user(&test_user("new@example.com"))
+        .await
+        .unwrap();
+    let stored = users(&persistence)
+        .find_one(doc! {"email_normalized": "new@example.com"}, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(stored.get("schema_version").is_some());
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn conditionally_updates_old_users() {
+    let fixture = MongoFixture::start().await;
+    let persistence = fixture.persistence("conditional").await.unwrap();
+
+    // Users written before schema version 2 have no update time.
+    let id = users(&persistence)
+        .insert_one(
+            doc! {
+                "name": "Old User",
+                "age": 100,
+                "email": "old@example.com",
+                "email_normalized": "old@example.com",
+                "gender": "Female",
+                "schema_version": 1,
+            },
+            None,
+        )
+        .await
+        .unwrap()
+        .inserted_id
+        .as_object_id()
+        .unwrap();
+    let current = persistence.get_user(&id.into()).await.unwrap().unwrap();
+    assert!(current.updated_at.is_some());
+
+    let update = |name: &str| UpdateUser {
+        id: id.into(),
+        name: name.to_owned(),
+        email: Email("old@example.com".to_owned()),
+        age: 100,
+        hash_id: String::new(),
+        phone: Patch::Absent,
+        address: Patch::Absent,
+    };
+    assert!(persistence
+        .update_user_if_unchanged(&update("First Writer"), &current)
+        .await
+        .unwrap());
+    // The first update set the time, so a writer holding the backfilled
+    // one lost the race.
+    assert!(!persistence
+        .update_user_if_unchanged(&update("Second Writer"), &current)
+        .await
+        .unwrap());
+
+    let current = persistence.get_user(&id.into()).await.unwrap().unwrap();
+    assert_eq!(current.name, "First Writer");
+    assert!(persistence
+        .update_user_if_unchanged(&update("Third Writer"), &current)
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+#[ignore = "requires docker"]
+async fn timestamps_users() {
+    let fixture = MongoFixture::start().await;
+    let persistence = fixture.persistence("timestamps").await.unwrap();
+
+    let saved = persistence
+        .save_user(&test_user("new@example.com"))
+        .await
+        .unwrap();
+    let created_at = saved.created_at.unwrap();
+    assert_eq!(saved.updated_at, Some(created_at));
+    let stored = users(&persistence)
+        .find_one(doc! {"email_normalized": "new@example.com"}, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(stored.get("created_at"), Some(Bson::DateTime(_))));
+
+    let search = |created_after| UserSearch {
+        created_after: Some(created_after),
+        ..Default::default()
+    };
+    assert_eq!(
+        persistence
+            .search_users(&search(Default::default()), 10)
+            .await
+            .unwrap(),
+        vec![saved]
+    );
+    assert!(persistence
+        .search_users(&search(created_at), 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Users saved before the times were recorded are created when their
+    // key was generated.
+    let id = users(&persistence)
+        .insert_one(
+            doc! {
+                "name": "Old User",
+                "age": 100,
+                "email": "old@example.com",
+                "email_normalized": "old@example.com",
+                "gender": "Female",
+                "schema_version": 1,
+            },
+            None,
+        )
+        .await
+        .unwrap()
+        .inserted_id
+        .as_object_id()
+        .unwrap();
+    let user = persistence.get_user(&id.into()).await.unwrap().unwrap();
+    assert_eq!(
+        user.created_at.map(|t| t.timestamp()),
+        Some(id.timestamp().timestamp_millis() / 1000)
+    );
+    assert_eq!(user.updated_at, user.created_at);
+    assert_eq!(
+        persistence.pending_migrations().await.unwrap(),
+        vec![
+            PendingMigration::Timestamps { users: 1 },
+            PendingMigration::AuditIndexes
+        ]
+    );
+}
@@ -0,0 +1,415 @@
+/*!
+Detection of unusual rates of change to user records.
+
+An [`AnomalyDetector`] is notified of every successful mutation made
+through an [`AnomalyDetecting`] persistence wrapper. The default
+[`ThresholdDetector`] flags a record updated too many times within a
+window and mass removals, emitting a WARN event and a metric for each
+anomaly and keeping the most recent ones for inspection.
+*/
+use crate::{
+    clock::{Clock, SystemClock},
+    persistence::{PersistenceResult, UserPersistence, UserStream},
+    query::UserQuery,
+    stats::{AgeBucket, StatsDate, StatsSnapshot},
+    types::{
+        BulkUpdate, BulkUpdateResult, Email, SearchPage, UpdateUser, User, UserKey, UserSearch,
+    },
+    PERSISTENCE_TARGET,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::warn;
+
+/// Counter incremented for every detected anomaly.
+pub const ANOMALY_METRIC: &str = "user_anomalies_total";
+
+/// A successful change made to a user record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    Save(UserKey),
+    Update(UserKey),
+    Remove(UserKey),
+}
+
+/// Kind of unusual activity detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    /// A single record was updated too many times within the window.
+    FrequentUpdates,
+    /// Too many records were removed within the window.
+    MassRemoval,
+}
+
+impl AnomalyKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::FrequentUpdates => "frequent_updates",
+            Self::MassRemoval => "mass_removal",
+        }
+    }
+}
+
+/// Unusual activity reported by a detector.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    /// Record the activity applies to, if it is specific to one.
+    pub key: Option<UserKey>,
+    /// Number of mutations seen within the window.
+    pub count: usize,
+    /// Window the mutations were counted over.
+    pub window_secs: u64,
+    /// Milliseconds since the unix epoch when the anomaly was detected.
+    pub detected_at_ms: u64,
+}
+
+/// Observes mutations to user records and reports unusual activity.
+pub trait AnomalyDetector: Send + Sync + Debug {
+    /// Record a mutation returning an anomaly if it is unusual.
+    fn record(&self, mutation: &Mutation) -> Option<Anomaly>;
+    /// Most recent anomalies, newest first.
+    fn recent(&self) -> Vec<Anomaly>;
+}
+
+/// Number of anomalies kept by [`ThresholdDetector`].
+const RECENT_ANOMALIES: usize = 100;
+
+/// Detector flagging mutation counts above fixed thresholds within a
+/// sliding window.
+#[derive(Debug)]
+pub struct ThresholdDetector {
+    max_updates_per_record: usize,
+    max_removals: usize,
+    window: Duration,
+    updates: Mutex<HashMap<UserKey, VecDeque<DateTime<Utc>>>>,
+    removals: Mutex<VecDeque<DateTime<Utc>>>,
+    recent: Mutex<VecDeque<Anomaly>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for ThresholdDetector {
+    fn default() -> Self {
+        Self::new(10, 20, Duration::from_secs(60))
+    }
+}
+
+/// Whether `time` is older than `length` at `now`. Times ahead of `now`
+/// are never expired.
+fn expired(time: &DateTime<Utc>, now: DateTime<Utc>, length: Duration) -> bool {
+    (now - *time).to_std().is_ok_and(|elapsed| elapsed > length)
+}
+
+/// Add `now` to the window dropping expired entries and return the number
+/// of entries in the window.
+fn slide(window: &mut VecDeque<DateTime<Utc>>, now: DateTime<Utc>, length: Duration) -> usize {
+    while window.front().is_some_and(|t| expired(t, now, length)) {
+        window.pop_front();
+    }
+    window.push_back(now);
+    window.len()
+}
+
+impl ThresholdDetector {
+    /// Create a detector allowing `max_updates_per_record` updates of the
+    /// same record and `max_removals` removals within `window`.
+    pub fn new(max_updates_per_record: usize, max_removals: usize, window: Duration) -> Self {
+        Self {
+            max_updates_per_record,
+            max_removals,
+            window,
+            updates: Mutex::default(),
+            removals: Mutex::default(),
+            recent: Mutex::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Read the time from `clock` instead of the system clock.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    fn count_update(&self, key: &UserKey, now: DateTime<Utc>) -> usize {
+        let mut updates = self.updates.lock().unwrap();
+        let window = self.window;
+        // Forget records with no updates left in the window.
+        updates.retain(|_, times| times.back().is_some_and(|t| !expired(t, now, window)));
+        slide(updates.entry(key.clone()).or_default(), now, window)
+    }
+
+    fn count_removal(&self, now: DateTime<Utc>) -> usize {
+        slide(&mut self.removals.lock().unwrap(), now, self.window)
+    }
+
+    fn report(
+        &self,
+        kind: AnomalyKind,
+        key: Option<UserKey>,
+        count: usize,
+        now: DateTime<Utc>,
+    ) -> Anomaly {
+        let anomaly = Anomaly {
+            kind,
+            key,
+            count,
+            window_secs: self.window.as_secs(),
+            detected_at_ms: u64::try_from(now.timestamp_millis()).unwrap_or_default(),
+        };
+
+        warn!(
+          target: PERSISTENCE_TARGET,
+          kind = kind.as_str(),
+          count,
+          window_secs = anomaly.window_secs,
+          user_id = ?anomaly.key.as_ref().map(|key| key.masked().to_string()),
+          "mutation anomaly detected"
+        );
+        metrics::increment_counter!(ANOMALY_METRIC, "kind" => kind.as_str());
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_ANOMALIES {
+            recent.pop_back();
+        }
+        recent.push_front(anomaly.clone());
+        anomaly
+    }
+}
+
+impl AnomalyDetector for ThresholdDetector {
+    fn record(&self, mutation: &Mutation) -> Option<Anomaly> {
+        let now = self.clock.now();
+        match mutation {
+            Mutation::Save(_) => None,
+            Mutation::Update(key) => {
+                let count = self.count_update(key, now);
+                (count > self.max_updates_per_record).then(|| {
+                    self.report(AnomalyKind::FrequentUpdates, Some(key.clone()), count, now)
+                })
+            }
+            Mutation::Remove(_) => {
+                let count = self.count_removal(now);
+                (count > self.max_removals)
+                    .then(|| self.report(AnomalyKind::MassRemoval, None, count, now))
+            }
+        }
+    }
+
+    fn recent(&self) -> Vec<Anomaly> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Persistence wrapper notifying a detector of every successful mutation.
+#[derive(Debug, Clone)]
+pub struct AnomalyDetecting {
+    inner: Arc<dyn UserPersistence>,
+    detector: Arc<dyn AnomalyDetector>,
+}
+
+impl AnomalyDetecting {
+    pub fn new(inner: Arc<dyn UserPersistence>, detector: Arc<dyn AnomalyDetector>) -> Self {
+        Self { inner, detector }
+    }
+}
+
+#[async_trait::async_trait]
+impl UserPersistence for AnomalyDetecting {
+    async fn get_user(&self, id: &UserKey) -> PersistenceResult<Option<User>> {
+        self.inner.get_user(id).await
+    }
+
+    async fn get_user_by_email(&self, email: &Email) -> PersistenceResult<Option<User>> {
+        self.inner.get_user_by_email(email).await
+    }
+
+    async fn save_user(&self, user: &User) -> PersistenceResult<User> {
+        let saved = self.inner.save_user(user).await?;
+        if let Some(id) = &saved.id {
+            self.detector.record(&Mutation::Save(id.clone()));
+        }
+        Ok(saved)
+    }
+
+    async fn update_user(&self, user: &UpdateUser) -> PersistenceResult<()> {
+        self.inner.update_user(user).await?;
+        self.detector.record(&Mutation::Update(user.id.clone()));
+        Ok(())
+    }
+
+    async fn update_user_if_unchanged(
+        &self,
+        user: &UpdateUser,
+        current: &User,
+    ) -> PersistenceResult<bool> {
+        let updated = self.inner.update_user_if_unchanged(user, current).await?;
+        if updated {
+            self.detector.record(&Mutation::Update(user.id.clone()));
+        }
+        Ok(updated)
+    }
+
+    async fn remove_user(&self, id: &UserKey) -> PersistenceResult<()> {
+        self.inner.remove_user(id).await?;
+        self.detector.record(&Mutation::Remove(id.clone()));
+        Ok(())
+    }
+
+    async fn search_users(&self, user: &UserSearch, limit: u64) -> PersistenceResult<Vec<User>> {
+        self.inner.search_users(user, limit).await
+    }
+
+    async fn query_users(&self, query: &UserQuery, limit: u64) -> PersistenceResult<Vec<User>> {
+        self.inner.query_users(query, limit).await
+    }
+
+    async fn search_users_stream(&self, search: &UserSearch) -> PersistenceResult<UserStream> {
+        self.inner.search_users_stream(search).await
+    }
+
+    async fn search_page(
+        &self,
+        search: &UserSearch,
+        offset: u64,
+        limit: u64,
+    ) -> PersistenceResult<SearchPage<User>> {
+        self.inner.search_page(search, offset, limit).await
+    }
+
+    async fn search_after(
+        &self,
+        search: &UserSearch,
+        after: &UserKey,
+        limit: u64,
+    ) -> PersistenceResult<Option<Vec<User>>> {
+        self.inner.search_after(search, after, limit).await
+    }
+
+    async fn bulk_update(
+        &self,
+        update: &BulkUpdate,
+        limit: u64,
+        dry_run: bool,
+    ) -> PersistenceResult<BulkUpdateResult> {
+        self.inner.bulk_update(update, limit, dry_run).await
+    }
+
+    async fn count_genders(&self) -> PersistenceResult<Vec<Value>> {
+        self.inner.count_genders().await
+    }
+
+    async fn age_histogram(&self, bucket_size: NonZeroU32) -> PersistenceResult<Vec<AgeBucket>> {
+        self.inner.age_histogram(bucket_size).await
+    }
+
+    async fn sample_users(&self, size: u64) -> PersistenceResult<Vec<User>> {
+        self.inner.sample_users(size).await
+    }
+
+    async fn save_stats_snapshot(&self, snapshot: &StatsSnapshot) -> PersistenceResult<()> {
+        self.inner.save_stats_snapshot(snapshot).await
+    }
+
+    async fn stats_history(
+        &self,
+        from: Option<StatsDate>,
+        to: Option<StatsDate>,
+    ) -> PersistenceResult<Vec<StatsSnapshot>> {
+        self.inner.stats_history(from, to).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Anomaly, AnomalyDetector, AnomalyKind, Mutation, ThresholdDetector};
+    use crate::{clock::MockClock, types::UserKey};
+    use chrono::DateTime;
+    use serde_json::json;
+    use std::{sync::Arc, time::Duration};
+
+    fn key(id: &str) -> UserKey {
+        id.parse().unwrap()
+    }
+
+    #[test]
+    fn test_frequent_updates() {
+        let detector = ThresholdDetector::new(2, 10, Duration::from_secs(60));
+        assert!(detector.record(&Mutation::Update(key("1"))).is_none());
+        assert!(detector.record(&Mutation::Update(key("1"))).is_none());
+        assert!(detector.record(&Mutation::Update(key("2"))).is_none());
+
+        let anomaly = detector.record(&Mutation::Update(key("1"))).unwrap();
+        assert_eq!(anomaly.kind, AnomalyKind::FrequentUpdates);
+        assert_eq!(anomaly.key, Some(key("1")));
+        assert_eq!(anomaly.count, 3);
+        assert_eq!(detector.recent(), vec![anomaly]);
+    }
+
+    #[test]
+    fn test_mass_removal() {
+        let detector = ThresholdDetector::new(10, 2, Duration::from_secs(60));
+        assert!(detector.record(&Mutation::Remove(key("1"))).is_none());
+        assert!(detector.record(&Mutation::Remove(key("2"))).is_none());
+
+        let anomaly = detector.record(&Mutation::Remove(key("3"))).unwrap();
+        assert_eq!(anomaly.kind, AnomalyKind::MassRemoval);
+        assert_eq!(anomaly.key, None);
+    }
+
+    #[test]
+    fn test_window_expiry() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(MockClock::new(start));
+        let detector =
+            ThresholdDetector::new(1, 10, Duration::from_secs(60)).with_clock(clock.clone());
+        assert!(detector.record(&Mutation::Update(key("1"))).is_none());
+
+        // Still inside the window at its boundary.
+        clock.advance(chrono::Duration::seconds(60));
+        let anomaly = detector.record(&Mutation::Update(key("1"))).unwrap();
+        assert_eq!(anomaly.detected_at_ms, 1_700_000_060_000);
+
+        // Both earlier updates fall out of the window.
+        clock.advance(chrono::Duration::seconds(61));
+        assert!(detector.record(&Mutation::Update(key("1"))).is_none());
+        assert_eq!(detector.recent().len(), 1);
+    }
+
+    #[test]
+    fn test_saves_ignored() {
+        let detector = ThresholdDetector::new(0, 0, Duration::from_secs(60));
+        assert!(detector.record(&Mutation::Save(key("1"))).is_none());
+    }
+
+    #[test]
+    fn test_anomaly_wire_names() {
+        let anomaly = Anomaly {
+            kind: AnomalyKind::FrequentUpdates,
+            key: Some(key("1")),
+            count: 11,
+            window_secs: 60,
+            detected_at_ms: 1_700_000_000_000,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&anomaly).unwrap(),
+            json!({
+                "kind": "frequent_updates",
+                "key": "1",
+                "count": 11,
+                "windowSecs": 60,
+                "detectedAtMs": 1_700_000_000_000_u64
+            })
+        );
+    }
+}
@@ -0,0 +1,335 @@
+/*!
+Persistent log of audited operations.
+
+Operations changing users or reading them in bulk are recorded as
+[`AuditRecord`]s naming the actor, the operation and the affected user, so
+incident response can search them without access to the service logs or
+the database. Records are written through the [`AuditLog`] trait, kept in
+memory for tests and in the [`AUDIT_COLLECTION_NAME`] collection by the
+services.
+*/
+use crate::{
+    mongo_persistence::{timed, OperationTimeouts},
+    persistence::PersistenceResult,
+    references::{ReferenceKind, UserReferences},
+    types::{SearchPage, UserKey},
+};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    options::{CountOptions, FindOptions, IndexOptions},
+    Collection, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Debug, sync::Mutex};
+
+/// Name of the mongodb collection holding audit records.
+pub const AUDIT_COLLECTION_NAME: &str = "audit_log";
+
+/// Names of the indexes searches use, by time alone, by actor and by
+/// target user.
+pub const AUDIT_INDEX_NAMES: [&str; 3] = ["at", "actor_at", "target_user_at"];
+
+/// Kind of an audited operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditOperation {
+    CreateUser,
+    UpdateUser,
+    DeleteUser,
+    BulkUpdate,
+    Import,
+    Aggregate,
+    IntegrityRepair,
+    Export,
+}
+
+/// An audited operation.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    /// Time of the operation in milliseconds since the epoch.
+    pub at_ms: i64,
+    /// Subject of the token authorizing the operation.
+    pub actor: String,
+    pub operation: AuditOperation,
+    /// User the operation changed, for operations on a single user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_user: Option<UserKey>,
+    /// Description of the operation, such as the criteria of a bulk update.
+    pub detail: String,
+}
+
+/// Criteria of an audit log search. Records match when they match every
+/// criterion given.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub operation: Option<AuditOperation>,
+    pub target_user: Option<UserKey>,
+    /// Earliest time in milliseconds since the epoch, inclusive.
+    pub from_ms: Option<i64>,
+    /// Latest time in milliseconds since the epoch, exclusive.
+    pub to_ms: Option<i64>,
+}
+
+impl AuditFilter {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        self.actor
+            .as_ref()
+            .is_none_or(|actor| *actor == record.actor)
+            && self.operation.is_none_or(|op| op == record.operation)
+            && self
+                .target_user
+                .as_ref()
+                .is_none_or(|user| Some(user) == record.target_user.as_ref())
+            && self.from_ms.is_none_or(|from| record.at_ms >= from)
+            && self.to_ms.is_none_or(|to| record.at_ms < to)
+    }
+
+    fn to_document(&self) -> PersistenceResult<Document> {
+        let mut filter = Document::new();
+        if let Some(actor) = &self.actor {
+            filter.insert("actor", actor);
+        }
+        if let Some(operation) = self.operation {
+            filter.insert(
+                "operation",
+                mongodb::bson::to_bson(&operation).map_err(mongodb::error::Error::from)?,
+            );
+        }
+        if let Some(user) = &self.target_user {
+            filter.insert("targetUser", user.to_string());
+        }
+        let mut at = Document::new();
+        if let Some(from) = self.from_ms {
+            at.insert("$gte", from);
+        }
+        if let Some(to) = self.to_ms {
+            at.insert("$lt", to);
+        }
+        if !at.is_empty() {
+            filter.insert("atMs", at);
+        }
+        Ok(filter)
+    }
+}
+
+/// Storage of audit records.
+#[async_trait::async_trait]
+pub trait AuditLog: Send + Sync + Debug {
+    /// Add `record` to the log.
+    async fn record(&self, record: &AuditRecord) -> PersistenceResult<()>;
+
+    /// Page of `limit` records matching `filter` from `offset`, newest
+    /// first.
+    async fn search(
+        &self,
+        filter: &AuditFilter,
+        offset: u64,
+        limit: u64,
+    ) -> PersistenceResult<SearchPage<AuditRecord>>;
+}
+
+/// [`AuditLog`] keeping records in memory.
+#[derive(Debug, Default)]
+pub struct MemoryAuditLog {
+    records: Mutex<Vec<AuditRecord>>,
+}
+
+#[async_trait::async_trait]
+impl AuditLog for MemoryAuditLog {
+    async fn record(&self, record: &AuditRecord) -> PersistenceResult<()> {
+        self.records.lock().unwrap().push(record.clone());
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        filter: &AuditFilter,
+        offset: u64,
+        limit: u64,
+    ) -> PersistenceResult<SearchPage<AuditRecord>> {
+        let records = self.records.lock().unwrap();
+        let mut matched = records
+            .iter()
+            .filter(|record| filter.matches(record))
+            .collect::<Vec<_>>();
+        // Records are appended in order, which the stable sort keeps for
+        // records of the same time.
+        matched.sort_by_key(|record| std::cmp::Reverse(record.at_ms));
+        Ok(SearchPage {
+            total: matched.len() as u64,
+            estimated: false,
+            items: matched
+                .into_iter()
+                .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+                .take(usize::try_from(limit).unwrap_or(usize::MAX))
+                .cloned()
+                .collect(),
+            offset,
+            limit,
+            next_cursor: None,
+        })
+    }
+}
+
+/// [`AuditLog`] keeping records in the [`AUDIT_COLLECTION_NAME`]
+/// collection.
+#[derive(Debug, Clone)]
+pub struct MongoAuditLog {
+    collection: Collection<AuditRecord>,
+    timeouts: OperationTimeouts,
+}
+
+impl MongoAuditLog {
+    /// Store records in `db` with the default operation limits.
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection(AUDIT_COLLECTION_NAME),
+            timeouts: OperationTimeouts::default(),
+        }
+    }
+
+    /// Limit operations to `timeouts`.
+    pub fn with_timeouts(self, timeouts: OperationTimeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    /// Create the indexes searches by time, actor and target user use.
+    pub async fn ensure_indexes(&self) -> PersistenceResult<()> {
+        let keys = [
+            doc! {"atMs": -1},
+            doc! {"actor": 1, "atMs": -1},
+            doc! {"targetUser": 1, "atMs": -1},
+        ];
+        let indexes = keys.into_iter().zip(AUDIT_INDEX_NAMES).map(|(keys, name)| {
+            IndexModel::builder()
+                .keys(keys)
+                .options(IndexOptions::builder().name(name.to_owned()).build())
+                .build()
+        });
+        self.collection.create_indexes(indexes, None).await?;
+        Ok(())
+    }
+}
+
+/// Audit records are history kept when their target user is removed or
+/// merged.
+impl UserReferences for MongoAuditLog {
+    fn collection(&self) -> &'static str {
+        AUDIT_COLLECTION_NAME
+    }
+
+    fn kind(&self) -> ReferenceKind {
+        ReferenceKind::History
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditLog for MongoAuditLog {
+    async fn record(&self, record: &AuditRecord) -> PersistenceResult<()> {
+        timed(
+            "insert_one",
+            AUDIT_COLLECTION_NAME,
+            self.timeouts.write,
+            self.collection.insert_one(record, None),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        filter: &AuditFilter,
+        offset: u64,
+        limit: u64,
+    ) -> PersistenceResult<SearchPage<AuditRecord>> {
+        let filter = filter.to_document()?;
+        let count_options = CountOptions::builder().max_time(self.timeouts.read).build();
+        let total = timed(
+            "count_documents",
+            AUDIT_COLLECTION_NAME,
+            self.timeouts.read,
+            self.collection
+                .count_documents(filter.clone(), count_options),
+        )
+        .await?;
+
+        let options = FindOptions::builder()
+            .sort(doc! {"atMs": -1, "_id": -1})
+            .skip(offset)
+            .limit(i64::try_from(limit).unwrap_or(i64::MAX))
+            .max_time(self.timeouts.read)
+            .build();
+        let items = timed("find", AUDIT_COLLECTION_NAME, self.timeouts.read, async {
+            self.collection
+                .find(filter, options)
+                .await?
+                .try_collect::<Vec<_>>()
+                .await
+        })
+        .await?;
+        Ok(SearchPage {
+            items,
+            total,
+            estimated: false,
+            offset,
+            limit,
+            next_cursor: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditFilter, AuditLog, AuditOperation, AuditRecord, MemoryAuditLog};
+
+    fn record(at_ms: i64, actor: &str, operation: AuditOperation) -> AuditRecord {
+        AuditRecord {
+            at_ms,
+            actor: actor.to_owned(),
+            operation,
+            target_user: None,
+            detail: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_search() {
+        let log = MemoryAuditLog::default();
+        let target = "61c0d1954c6b974ca7000000".parse().unwrap();
+        for record in [
+            record(1_000, "alice", AuditOperation::CreateUser),
+            AuditRecord {
+                target_user: Some(target),
+                ..record(2_000, "bob", AuditOperation::DeleteUser)
+            },
+            record(3_000, "alice", AuditOperation::BulkUpdate),
+        ] {
+            log.record(&record).await.unwrap();
+        }
+
+        let page = log.search(&AuditFilter::default(), 0, 2).await.unwrap();
+        assert_eq!(page.total, 3);
+        let times = page.items.iter().map(|r| r.at_ms).collect::<Vec<_>>();
+        assert_eq!(times, [3_000, 2_000]);
+
+        let alice = AuditFilter {
+            actor: Some("alice".to_owned()),
+            to_ms: Some(3_000),
+            ..AuditFilter::default()
+        };
+        let page = log.search(&alice, 0, 10).await.unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].operation, AuditOperation::CreateUser);
+
+        let deleted = AuditFilter {
+            target_user: Some("61c0d1954c6b974ca7000000".parse().unwrap()),
+            operation: Some(AuditOperation::DeleteUser),
+            ..AuditFilter::default()
+        };
+        let page = log.search(&deleted, 0, 10).await.unwrap();
+        assert_eq!(page.items[0].actor, "bob");
+    }
+}
@@ -0,0 +1,78 @@
+/*!
+Conversion of BSON aggregation results to plain JSON.
+
+The `From<Bson> for Value` conversion produces relaxed extended JSON, so
+an ObjectId becomes `{"$oid": ".."}` and a date `{"$date": ".."}`. Results
+surfaced to clients are converted here instead so they only contain plain
+strings, numbers, booleans, arrays and objects.
+*/
+use mongodb::bson::{Bson, Document};
+use serde_json::{Map, Number, Value};
+
+/// Convert a BSON document to a plain JSON object.
+pub fn document_to_json(document: Document) -> Value {
+    Value::Object(
+        document
+            .into_iter()
+            .map(|(key, value)| (key, bson_to_json(value)))
+            .collect::<Map<_, _>>(),
+    )
+}
+
+/// Convert a BSON value to plain JSON.
+pub fn bson_to_json(value: Bson) -> Value {
+    match value {
+        Bson::Double(d) => Number::from_f64(d)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        Bson::String(s) | Bson::Symbol(s) | Bson::JavaScriptCode(s) => Value::String(s),
+        Bson::Array(values) => Value::Array(values.into_iter().map(bson_to_json).collect()),
+        Bson::Document(document) => document_to_json(document),
+        Bson::Boolean(b) => Value::Bool(b),
+        Bson::Null | Bson::Undefined | Bson::MaxKey | Bson::MinKey => Value::Null,
+        Bson::Int32(i) => Value::from(i),
+        Bson::Int64(i) => Value::from(i),
+        Bson::ObjectId(oid) => Value::String(oid.to_hex()),
+        Bson::DateTime(dt) => dt
+            .try_to_rfc3339_string()
+            .map(Value::String)
+            .unwrap_or_else(|_| Value::from(dt.timestamp_millis())),
+        Bson::Timestamp(ts) => Value::from(ts.time),
+        other => other.into_relaxed_extjson(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::document_to_json;
+    use mongodb::bson::{doc, oid::ObjectId, DateTime};
+    use serde_json::json;
+
+    #[test]
+    fn test_gender_count() {
+        let converted = document_to_json(doc! {"_id": "Male", "count": 6_i32});
+        assert_eq!(converted, json!({"_id": "Male", "count": 6}));
+    }
+
+    #[test]
+    fn test_plain_values() {
+        let oid = ObjectId::parse_str("61c0d1954c6b974ca7000000").unwrap();
+        let converted = document_to_json(doc! {
+            "_id": oid,
+            "total": 12_i64,
+            "ratio": 0.5,
+            "at": DateTime::from_millis(0),
+            "nested": [{"id": oid}],
+        });
+        assert_eq!(
+            converted,
+            json!({
+                "_id": "61c0d1954c6b974ca7000000",
+                "total": 12,
+                "ratio": 0.5,
+                "at": "1970-01-01T00:00:00Z",
+                "nested": [{"id": "61c0d1954c6b974ca7000000"}],
+            })
+        );
+    }
+}
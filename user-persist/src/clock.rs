@@ -0,0 +1,71 @@
+/*!
+Clock abstraction for time dependent logic.
+
+Code that checks expiry or measures time reads it from a [`Clock`] so
+tests can substitute a [`MockClock`] and move time deterministically
+instead of sleeping or racing the system clock.
+*/
+use chrono::{DateTime, Duration, Utc};
+use std::{fmt::Debug, sync::Mutex};
+
+/// Source of the current time.
+pub trait Clock: Send + Sync + Debug {
+    /// The current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Clock reading the system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Clock that only moves when told to.
+#[derive(Debug)]
+pub struct MockClock(Mutex<DateTime<Utc>>);
+
+impl MockClock {
+    /// Create a clock stopped at `now`.
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self(Mutex::new(now))
+    }
+
+    /// Move the clock to `now`.
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.0.lock().unwrap() = now;
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Clock, MockClock};
+    use chrono::{DateTime, Duration};
+
+    #[test]
+    fn test_mock_clock() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::seconds(90));
+        assert_eq!(clock.now(), start + Duration::seconds(90));
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
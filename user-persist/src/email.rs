@@ -0,0 +1,81 @@
+/*!
+Email normalization.
+
+Emails are stored as entered by the user alongside a normalized form. The
+normalized form is used for lookups and the unique index so that
+`Foo@Bar.com` and `foo@bar.com` refer to the same user.
+*/
+use crate::types::Email;
+
+/// Normalizes emails into a canonical form.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EmailNormalizer {
+    strip_plus_tags: bool,
+}
+
+impl EmailNormalizer {
+    /// Create a new normalizer. When `strip_plus_tags` is set a sub-address
+    /// such as `user+news@example.com` normalizes to `user@example.com`.
+    pub fn new(strip_plus_tags: bool) -> Self {
+        Self { strip_plus_tags }
+    }
+
+    /// Normalize an email. The value is trimmed and lowercased and the domain
+    /// is converted to its ASCII (punycode) form. Values without an `@` are
+    /// only trimmed and lowercased.
+    pub fn normalize(&self, email: &Email) -> String {
+        let email = email.trim().to_lowercase();
+
+        match email.rsplit_once('@') {
+            Some((local, domain)) => {
+                let local = match local.split_once('+') {
+                    Some((untagged, _tag)) if self.strip_plus_tags => untagged,
+                    _ => local,
+                };
+                let domain = idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_owned());
+                format!("{local}@{domain}")
+            }
+            None => email,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EmailNormalizer;
+    use crate::types::Email;
+
+    fn normalize(normalizer: EmailNormalizer, email: &str) -> String {
+        normalizer.normalize(&Email(email.into()))
+    }
+
+    #[test]
+    fn test_case_and_whitespace() {
+        let normalizer = EmailNormalizer::default();
+        assert_eq!(normalize(normalizer, " Foo@Bar.com "), "foo@bar.com");
+        assert_eq!(
+            normalize(normalizer, "foo@bar.com"),
+            normalize(normalizer, "FOO@BAR.COM")
+        );
+    }
+
+    #[test]
+    fn test_plus_tags() {
+        assert_eq!(
+            normalize(EmailNormalizer::default(), "foo+news@bar.com"),
+            "foo+news@bar.com"
+        );
+        assert_eq!(
+            normalize(EmailNormalizer::new(true), "foo+news@bar.com"),
+            "foo@bar.com"
+        );
+    }
+
+    #[test]
+    fn test_idn_domain() {
+        assert_eq!(
+            normalize(EmailNormalizer::default(), "user@Bücher.example"),
+            "user@xn--bcher-kva.example"
+        );
+    }
+}
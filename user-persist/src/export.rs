@@ -0,0 +1,645 @@
+/*!
+Formats users are exported in.
+
+Each [`Formatter`] encodes a stream of records, users in their JSON form,
+into the bytes of one format. Handlers and export jobs look formatters up
+in a [`FormatRegistry`] by name or content type so a format is added by
+registering it rather than by changing every caller.
+
+The default registry holds:
+
+* `json`, a JSON array.
+* `ndjson`, one JSON object per line.
+* `csv`, a header row followed by a row per user.
+
+With the `parquet` feature [`ParquetFormatter`] can be registered to
+export Parquet files for analytics ingestion.
+
+Exports written to a file by [`materialize`] are described by an
+[`ExportManifest`] stored next to them, so consumers can check the artifact
+they read is the complete one.
+*/
+use crate::{clock::Clock, schema::SchemaRegistry, streaming::StreamError};
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+/// Records to export, each the JSON form of a user.
+pub type RecordStream = BoxStream<'static, Result<Value, StreamError>>;
+
+/// Encoded export read incrementally.
+pub type ExportStream = BoxStream<'static, Result<Vec<u8>, StreamError>>;
+
+/// Columns of a user export in a tabular format.
+pub const USER_COLUMNS: &[&str] = &[
+    "id",
+    "name",
+    "age",
+    "email",
+    "gender",
+    "phone",
+    "address",
+    "createdAt",
+    "updatedAt",
+];
+
+/// Encoding of exported records in one format.
+pub trait Formatter: Send + Sync + Debug {
+    /// Name clients choose the format with.
+    fn name(&self) -> &'static str;
+    /// Content type of the encoded export.
+    fn content_type(&self) -> &'static str;
+    /// Encode `records`. The export fails with the first failing record.
+    fn format(&self, records: RecordStream) -> ExportStream;
+}
+
+/// Users as a JSON array.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonArrayFormatter;
+
+impl Formatter for JsonArrayFormatter {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn format(&self, records: RecordStream) -> ExportStream {
+        let items = records.enumerate().map(|(i, record)| {
+            let separator = if i == 0 { "" } else { "," };
+            Ok(format!("{separator}{}", serde_json::to_string(&record?)?).into_bytes())
+        });
+        stream::once(async { Ok(b"[".to_vec()) })
+            .chain(items)
+            .chain(stream::once(async { Ok(b"]".to_vec()) }))
+            .boxed()
+    }
+}
+
+/// Users as newline delimited JSON.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NdjsonFormatter;
+
+impl Formatter for NdjsonFormatter {
+    fn name(&self) -> &'static str {
+        "ndjson"
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/x-ndjson"
+    }
+
+    fn format(&self, records: RecordStream) -> ExportStream {
+        records
+            .map(|record| Ok(format!("{}\n", serde_json::to_string(&record?)?).into_bytes()))
+            .boxed()
+    }
+}
+
+/// Users as comma separated values. Fields missing from a record are left
+/// empty and nested values are written as JSON.
+#[derive(Debug, Clone)]
+pub struct CsvFormatter {
+    columns: Arc<[&'static str]>,
+}
+
+impl Default for CsvFormatter {
+    fn default() -> Self {
+        Self::new(USER_COLUMNS)
+    }
+}
+
+impl CsvFormatter {
+    pub fn new(columns: &[&'static str]) -> Self {
+        Self {
+            columns: columns.into(),
+        }
+    }
+
+    fn row(fields: impl IntoIterator<Item = String>) -> Vec<u8> {
+        let mut row = fields
+            .into_iter()
+            .map(|field| csv_field(&field))
+            .collect::<Vec<_>>()
+            .join(",");
+        row.push_str("\r\n");
+        row.into_bytes()
+    }
+}
+
+/// Text of the `column` field of `record`, none when it is missing or null.
+/// Nested values are written as JSON.
+fn field_text(record: &Value, column: &str) -> Option<String> {
+    match record.get(column) {
+        None | Some(Value::Null) => None,
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(value) => Some(value.to_string()),
+    }
+}
+
+/// Quote `field` when it holds a separator, quote or line break. A field
+/// spreadsheets would evaluate as a formula is prefixed with `'` and quoted
+/// so it is read as text.
+fn csv_field(field: &str) -> String {
+    if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("\"'{}\"", field.replace('"', "\"\""))
+    } else if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+impl Formatter for CsvFormatter {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    fn content_type(&self) -> &'static str {
+        "text/csv"
+    }
+
+    fn format(&self, records: RecordStream) -> ExportStream {
+        let header = Self::row(self.columns.iter().map(|c| c.to_string()));
+        let columns = self.columns.clone();
+        let rows = records.map(move |record| {
+            let record = record?;
+            Ok(Self::row(columns.iter().map(|column| {
+                field_text(&record, column).unwrap_or_default()
+            })))
+        });
+        stream::once(async { Ok(header) }).chain(rows).boxed()
+    }
+}
+
+/// Users as a Parquet file of the [`USER_COLUMNS`], written a row group
+/// at a time as records are read. `age` is an unsigned integer column and
+/// the others are text, nested values being written as JSON.
+#[cfg(feature = "parquet")]
+#[derive(Debug, Clone, Copy)]
+pub struct ParquetFormatter {
+    row_group_size: usize,
+}
+
+#[cfg(feature = "parquet")]
+impl Default for ParquetFormatter {
+    fn default() -> Self {
+        Self::new(10_000)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl ParquetFormatter {
+    /// Formatter writing row groups of at most `row_group_size` users.
+    pub fn new(row_group_size: usize) -> Self {
+        Self {
+            row_group_size: row_group_size.max(1),
+        }
+    }
+
+    fn schema() -> arrow_schema::SchemaRef {
+        use arrow_schema::{DataType, Field, Schema};
+        let fields = USER_COLUMNS
+            .iter()
+            .map(|&column| {
+                let data_type = match column {
+                    "age" => DataType::UInt32,
+                    _ => DataType::Utf8,
+                };
+                Field::new(column, data_type, true)
+            })
+            .collect::<Vec<_>>();
+        Arc::new(Schema::new(fields))
+    }
+
+    /// Columnar batch of `records`.
+    fn batch(records: &[Value]) -> Result<arrow_array::RecordBatch, StreamError> {
+        use arrow_array::{ArrayRef, RecordBatch, StringArray, UInt32Array};
+        let columns = USER_COLUMNS
+            .iter()
+            .map(|&column| -> ArrayRef {
+                match column {
+                    "age" => Arc::new(
+                        records
+                            .iter()
+                            .map(|r| r.get(column).and_then(Value::as_u64))
+                            .map(|age| age.and_then(|age| u32::try_from(age).ok()))
+                            .collect::<UInt32Array>(),
+                    ),
+                    _ => Arc::new(
+                        records
+                            .iter()
+                            .map(|r| field_text(r, column))
+                            .collect::<StringArray>(),
+                    ),
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(RecordBatch::try_new(Self::schema(), columns)
+            .map_err(parquet::errors::ParquetError::from)?)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl Formatter for ParquetFormatter {
+    fn name(&self) -> &'static str {
+        "parquet"
+    }
+
+    fn content_type(&self) -> &'static str {
+        "application/vnd.apache.parquet"
+    }
+
+    fn format(&self, records: RecordStream) -> ExportStream {
+        use futures::TryStreamExt;
+        use parquet::arrow::ArrowWriter;
+
+        let writer = match ArrowWriter::try_new(Vec::new(), Self::schema(), None) {
+            Ok(writer) => writer,
+            Err(e) => return stream::once(async { Err(e.into()) }).boxed(),
+        };
+        let row_groups = records.try_chunks(self.row_group_size);
+
+        // Each row group is taken out of the buffer once flushed, the writer
+        // keeping track of the offsets for the footer.
+        stream::unfold(Some((row_groups, writer)), |state| async move {
+            let (mut row_groups, mut writer) = state?;
+            match row_groups.next().await {
+                Some(Ok(records)) => {
+                    let written = Self::batch(&records).and_then(|batch| {
+                        writer.write(&batch)?;
+                        writer.flush()?;
+                        Ok(std::mem::take(writer.inner_mut()))
+                    });
+                    let next = written.is_ok().then_some((row_groups, writer));
+                    Some((written, next))
+                }
+                Some(Err(e)) => Some((Err(e.1), None)),
+                None => {
+                    let footer = writer
+                        .finish()
+                        .map(|_| std::mem::take(writer.inner_mut()))
+                        .map_err(StreamError::from);
+                    Some((footer, None))
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+/// Formatters by name and content type.
+#[derive(Debug, Clone)]
+pub struct FormatRegistry {
+    formatters: Vec<Arc<dyn Formatter>>,
+}
+
+impl Default for FormatRegistry {
+    fn default() -> Self {
+        Self::empty()
+            .register(JsonArrayFormatter)
+            .register(NdjsonFormatter)
+            .register(CsvFormatter::default())
+    }
+}
+
+impl FormatRegistry {
+    /// Registry without any format.
+    pub fn empty() -> Self {
+        Self {
+            formatters: Vec::new(),
+        }
+    }
+
+    /// Add `formatter`, replacing any format of the same name.
+    pub fn register(mut self, formatter: impl Formatter + 'static) -> Self {
+        self.formatters.retain(|f| f.name() != formatter.name());
+        self.formatters.push(Arc::new(formatter));
+        self
+    }
+
+    /// Formatter named `name`, ignoring case.
+    pub fn by_name(&self, name: &str) -> Option<Arc<dyn Formatter>> {
+        self.formatters
+            .iter()
+            .find(|f| f.name().eq_ignore_ascii_case(name))
+            .cloned()
+    }
+
+    /// Formatter of `content_type`, ignoring its parameters.
+    pub fn by_content_type(&self, content_type: &str) -> Option<Arc<dyn Formatter>> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        self.formatters
+            .iter()
+            .find(|f| f.content_type().eq_ignore_ascii_case(essence))
+            .cloned()
+    }
+
+    /// Names of the registered formats.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.formatters.iter().map(|f| f.name()).collect()
+    }
+}
+
+/// Description of a materialized export.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportManifest {
+    /// Name of the format of the artifact.
+    pub format: String,
+    pub content_type: String,
+    /// Number of records exported.
+    pub record_count: u64,
+    /// Size of the artifact in bytes.
+    pub size: u64,
+    /// Hex encoded SHA-256 digest of the artifact.
+    pub sha256: String,
+    /// Schema version of the exported users.
+    pub schema_version: u32,
+    /// When the export started reading users, in milliseconds since the
+    /// epoch.
+    pub started_at_ms: i64,
+    /// When the artifact was complete.
+    pub finished_at_ms: i64,
+}
+
+/// Failure materializing an export.
+#[derive(Debug, Error)]
+pub enum MaterializeError {
+    #[error("Export failed: {0}")]
+    Stream(#[from] StreamError),
+    #[error("Writing the export failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+}
+
+/// Path of the manifest of the export at `path`.
+pub fn manifest_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".manifest.json")
+}
+
+/// Manifest of the export at `path`, none when it has none.
+pub async fn read_manifest(path: &Path) -> Result<Option<ExportManifest>, MaterializeError> {
+    match tokio::fs::read(manifest_path(path)).await {
+        Ok(manifest) => Ok(Some(serde_json::from_slice(&manifest)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write `records` encoded by `formatter` to `path` with its manifest,
+/// hashing the artifact as it is written. Both are written to temporary
+/// files renamed once complete, the manifest last, so a manifest always
+/// describes the artifact next to it.
+pub async fn materialize(
+    formatter: &dyn Formatter,
+    records: RecordStream,
+    path: &Path,
+    clock: &dyn Clock,
+) -> Result<ExportManifest, MaterializeError> {
+    let started_at_ms = clock.now().timestamp_millis();
+    let count = Arc::new(AtomicU64::new(0));
+    let records = {
+        let count = count.clone();
+        records
+            .inspect(move |record| {
+                if record.is_ok() {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .boxed()
+    };
+
+    let partial = with_suffix(path, ".partial");
+    let mut file = tokio::fs::File::create(&partial).await?;
+    let mut digest = Sha256::new();
+    let mut size = 0;
+    let mut chunks = formatter.format(records);
+    while let Some(chunk) = chunks.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                drop(file);
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(e.into());
+            }
+        };
+        digest.update(&chunk);
+        size += chunk.len() as u64;
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&partial, path).await?;
+
+    let manifest = ExportManifest {
+        format: formatter.name().to_owned(),
+        content_type: formatter.content_type().to_owned(),
+        record_count: count.load(Ordering::Relaxed),
+        size,
+        sha256: digest
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect(),
+        schema_version: SchemaRegistry::default().current_version(),
+        started_at_ms,
+        finished_at_ms: clock.now().timestamp_millis(),
+    };
+    let manifest_file = manifest_path(path);
+    let partial = with_suffix(&manifest_file, ".partial");
+    tokio::fs::write(&partial, serde_json::to_vec_pretty(&manifest)?).await?;
+    tokio::fs::rename(&partial, &manifest_file).await?;
+    Ok(manifest)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        materialize, read_manifest, CsvFormatter, FormatRegistry, Formatter, RecordStream,
+    };
+    use crate::{clock::MockClock, persistence::PersistenceError};
+    use chrono::Utc;
+    use futures::{stream, StreamExt, TryStreamExt};
+    use serde_json::json;
+
+    fn records() -> RecordStream {
+        stream::iter(vec![
+            Ok(json!({"id": "1", "name": "Test User", "age": 100})),
+            Ok(json!({"id": "2", "name": "Other, \"User\"", "address": {"city": "Paris"}})),
+        ])
+        .boxed()
+    }
+
+    async fn export(formatter: &dyn Formatter, records: RecordStream) -> String {
+        let chunks = formatter
+            .format(records)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json_formats() {
+        let registry = FormatRegistry::default();
+        let json = export(&*registry.by_name("json").unwrap(), records()).await;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap()[1]["id"],
+            "2"
+        );
+        let empty = export(&*registry.by_name("json").unwrap(), stream::empty().boxed()).await;
+        assert_eq!(empty, "[]");
+
+        let ndjson = export(&*registry.by_name("NDJSON").unwrap(), records()).await;
+        assert_eq!(ndjson.lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_csv_format() {
+        let csv = export(&CsvFormatter::new(&["id", "name", "address"]), records()).await;
+        assert_eq!(
+            csv,
+            "id,name,address\r\n\
+             1,Test User,\r\n\
+             2,\"Other, \"\"User\"\"\",\"{\"\"city\"\":\"\"Paris\"\"}\"\r\n"
+        );
+
+        let header = export(&CsvFormatter::default(), stream::empty().boxed()).await;
+        assert_eq!(
+            header,
+            "id,name,age,email,gender,phone,address,createdAt,updatedAt\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_csv_formulas() {
+        let records = stream::iter(vec![
+            Ok(json!({"name": "=HYPERLINK(\"http://evil\")", "phone": "+1 555-0100"})),
+            Ok(json!({"name": "@SUM(A1)", "phone": "-1"})),
+            Ok(json!({"name": "\tTab", "phone": "\rReturn"})),
+            Ok(json!({"name": "Plain = text", "phone": "555-0100"})),
+        ])
+        .boxed();
+        let csv = export(&CsvFormatter::new(&["name", "phone"]), records).await;
+        assert_eq!(
+            csv,
+            "name,phone\r\n\
+             \"'=HYPERLINK(\"\"http://evil\"\")\",\"'+1 555-0100\"\r\n\
+             \"'@SUM(A1)\",\"'-1\"\r\n\
+             \"'\tTab\",\"'\rReturn\"\r\n\
+             Plain = text,555-0100\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_record() {
+        let records =
+            stream::iter(vec![Ok(json!({})), Err(PersistenceError::Timeout.into())]).boxed();
+        let chunks = CsvFormatter::default()
+            .format(records)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[2].is_err());
+    }
+
+    #[tokio::test]
+    async fn test_materialize() {
+        let path =
+            std::env::temp_dir().join(format!("user-persist-export-{}.csv", std::process::id()));
+        let clock = MockClock::new(Utc::now());
+        let manifest = materialize(&CsvFormatter::new(&["id"]), records(), &path, &clock)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "id\r\n1\r\n2\r\n");
+        assert_eq!(manifest.format, "csv");
+        assert_eq!(manifest.record_count, 2);
+        assert_eq!(manifest.size, 10);
+        assert_eq!(
+            manifest.sha256,
+            "7d6665daf354ca9bd7f41cd93b86694168642d03230041b95203096f2faf6294"
+        );
+        assert_eq!(read_manifest(&path).await.unwrap(), Some(manifest));
+
+        let records =
+            stream::iter(vec![Ok(json!({})), Err(PersistenceError::Timeout.into())]).boxed();
+        let other = path.with_extension("failed");
+        assert!(
+            materialize(&CsvFormatter::default(), records, &other, &clock)
+                .await
+                .is_err()
+        );
+        assert!(!other.exists());
+        assert_eq!(read_manifest(&other).await.unwrap(), None);
+
+        std::fs::remove_file(super::manifest_path(&path)).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = FormatRegistry::default();
+        assert_eq!(registry.names(), ["json", "ndjson", "csv"]);
+        assert_eq!(
+            registry
+                .by_content_type("text/csv; charset=utf-8")
+                .unwrap()
+                .name(),
+            "csv"
+        );
+        assert!(registry.by_name("parquet").is_none());
+
+        let registry = registry.register(CsvFormatter::new(&["id"]));
+        assert_eq!(registry.names(), ["json", "ndjson", "csv"]);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[tokio::test]
+    async fn test_parquet_format() {
+        use super::ParquetFormatter;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let chunks = ParquetFormatter::new(1)
+            .format(records())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        // A chunk per row group and one for the footer.
+        assert_eq!(chunks.len(), 3);
+
+        let path =
+            std::env::temp_dir().join(format!("user-persist-{}.parquet", std::process::id()));
+        std::fs::write(&path, chunks.concat()).unwrap();
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let rows = reader
+            .build()
+            .unwrap()
+            .map(|batch| batch.unwrap().num_rows())
+            .sum::<usize>();
+        assert_eq!(rows, 2);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+/*!
+Typed construction of mongodb user search queries.
+
+A [`UserFilter`] holds the criteria of a search and renders them as a
+query document. Criteria left out of the search are left out of the
+query rather than matched against `null`.
+*/
+use crate::{
+    email::EmailNormalizer,
+    name::NameNormalizer,
+    query::{AgeRange, UserQuery},
+    sanitize::{self, SanitizeError},
+    types::{Email, Gender, UserSearch},
+};
+use chrono::{DateTime, Utc};
+use mongodb::bson::{self, doc, Bson, Document, Regex};
+
+/// Criteria of a user search.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserFilter {
+    email: Option<Email>,
+    gender: Option<Gender>,
+    name: Option<String>,
+    name_containing: Option<String>,
+    age: AgeRange,
+    created_after: Option<DateTime<Utc>>,
+    updated_before: Option<DateTime<Utc>>,
+}
+
+impl From<&UserSearch> for UserFilter {
+    fn from(search: &UserSearch) -> Self {
+        Self {
+            email: search.email.clone(),
+            gender: search.gender.clone(),
+            name: search.name.clone(),
+            created_after: search.created_after,
+            updated_before: search.updated_before,
+            ..Self::default()
+        }
+    }
+}
+
+impl From<&UserQuery> for UserFilter {
+    fn from(query: &UserQuery) -> Self {
+        Self {
+            name_containing: query.name_contains.clone(),
+            age: query.age,
+            ..Self::from(&query.search)
+        }
+    }
+}
+
+impl UserFilter {
+    /// Match users with this email once normalized.
+    pub fn email(self, email: Email) -> Self {
+        Self {
+            email: Some(email),
+            ..self
+        }
+    }
+
+    /// Match users of this gender.
+    pub fn gender(self, gender: Gender) -> Self {
+        Self {
+            gender: Some(gender),
+            ..self
+        }
+    }
+
+    /// Match users with this name, replacing a partial name.
+    pub fn name(self, name: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            name_containing: None,
+            ..self
+        }
+    }
+
+    /// Match users whose name contains `part` regardless of case,
+    /// replacing an exact name.
+    pub fn name_containing(self, part: impl Into<String>) -> Self {
+        Self {
+            name: None,
+            name_containing: Some(part.into()),
+            ..self
+        }
+    }
+
+    /// Match users with an age in `age`.
+    pub fn age(self, age: AgeRange) -> Self {
+        Self { age, ..self }
+    }
+
+    /// Match users created after `time`.
+    pub fn created_after(self, time: DateTime<Utc>) -> Self {
+        Self {
+            created_after: Some(time),
+            ..self
+        }
+    }
+
+    /// Match users last updated before `time`.
+    pub fn updated_before(self, time: DateTime<Utc>) -> Self {
+        Self {
+            updated_before: Some(time),
+            ..self
+        }
+    }
+
+    /// The filter matches every user.
+    pub fn is_empty(&self) -> bool {
+        self.email.is_none()
+            && self.gender.is_none()
+            && self.name.is_none()
+            && self.name_containing.is_none()
+            && self.age.is_unbounded()
+            && self.created_after.is_none()
+            && self.updated_before.is_none()
+    }
+
+    /// Query document for the filter. Emails are compared in the form
+    /// produced by `emails`, names in the field and form chosen by `names`
+    /// and names longer than the sanitizing limit are rejected.
+    pub fn to_document(
+        &self,
+        emails: &EmailNormalizer,
+        names: &NameNormalizer,
+    ) -> Result<Document, SanitizeError> {
+        // User supplied values are inserted as strings, never documents, so
+        // they can't add operators to the query.
+        let mut query = Document::new();
+        if let Some(email) = &self.email {
+            query.insert("email_normalized", emails.normalize(email));
+        }
+        if let Some(gender) = &self.gender {
+            query.insert("gender", gender.clone());
+        }
+        if let Some(name) = &self.name {
+            query.insert(names.field(), names.search_key(sanitize::bounded(name)?));
+        }
+        if let Some(part) = &self.name_containing {
+            let pattern = Regex {
+                pattern: sanitize::escape_regex(&names.search_key(sanitize::bounded(part)?)),
+                options: "i".to_owned(),
+            };
+            query.insert(names.field(), Bson::RegularExpression(pattern));
+        }
+        if !self.age.is_unbounded() {
+            let mut range = Document::new();
+            if let Some(min) = self.age.min {
+                range.insert("$gte", i64::from(min));
+            }
+            if let Some(max) = self.age.max {
+                range.insert("$lte", i64::from(max));
+            }
+            query.insert("age", range);
+        }
+        if let Some(time) = self.created_after {
+            query.insert("created_at", doc! {"$gt": bson_time(time)});
+        }
+        if let Some(time) = self.updated_before {
+            query.insert("updated_at", doc! {"$lt": bson_time(time)});
+        }
+
+        Ok(query)
+    }
+}
+
+/// Time stored in mongodb, which keeps milliseconds.
+pub(crate) fn bson_time(time: DateTime<Utc>) -> bson::DateTime {
+    bson::DateTime::from_millis(time.timestamp_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use super::UserFilter;
+    use crate::{
+        email::EmailNormalizer,
+        name::NameNormalizer,
+        query::{AgeRange, UserQuery},
+        sanitize::SanitizeError,
+        types::{Email, Gender, UserSearch},
+    };
+    use chrono::DateTime;
+    use mongodb::bson::{self, doc, Bson, Document, Regex};
+
+    fn query(filter: UserFilter) -> Document {
+        filter
+            .to_document(&EmailNormalizer::default(), &NameNormalizer::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_filter_combinations() {
+        let email = || Email("Test@Example.com".to_owned());
+        let cases = [
+            (UserFilter::default(), doc! {}),
+            (
+                UserFilter::default().email(email()),
+                doc! {"email_normalized": "test@example.com"},
+            ),
+            (
+                UserFilter::default().gender(Gender::Female),
+                doc! {"gender": "Female"},
+            ),
+            (UserFilter::default().name("Test"), doc! {"name": "Test"}),
+            (
+                UserFilter::default().email(email()).gender(Gender::Male),
+                doc! {"email_normalized": "test@example.com", "gender": "Male"},
+            ),
+            (
+                UserFilter::default().email(email()).name("Test"),
+                doc! {"email_normalized": "test@example.com", "name": "Test"},
+            ),
+            (
+                UserFilter::default().gender(Gender::Male).name("Test"),
+                doc! {"gender": "Male", "name": "Test"},
+            ),
+            (
+                UserFilter::default()
+                    .email(email())
+                    .gender(Gender::Female)
+                    .name("Test"),
+                doc! {
+                    "email_normalized": "test@example.com",
+                    "gender": "Female",
+                    "name": "Test"
+                },
+            ),
+        ];
+
+        for (filter, expected) in cases {
+            assert_eq!(filter.is_empty(), expected.is_empty());
+            assert_eq!(query(filter), expected);
+        }
+    }
+
+    #[test]
+    fn test_ranges() {
+        let filter = UserFilter::default()
+            .gender(Gender::Male)
+            .name_containing("sm.th")
+            .age(AgeRange {
+                min: Some(121),
+                max: None,
+            });
+        assert!(!filter.is_empty());
+        assert_eq!(
+            query(filter),
+            doc! {
+                "gender": "Male",
+                "name": Bson::RegularExpression(Regex {
+                    pattern: r"sm\.th".to_owned(),
+                    options: "i".to_owned(),
+                }),
+                "age": {"$gte": 121_i64},
+            }
+        );
+
+        let query = "name~test age>=20 age<=30".parse::<UserQuery>().unwrap();
+        assert_eq!(
+            UserFilter::from(&query),
+            UserFilter::default().name_containing("test").age(AgeRange {
+                min: Some(20),
+                max: Some(30),
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_search() {
+        let search = UserSearch {
+            email: None,
+            gender: Some(Gender::Male),
+            name: Some("Test".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            UserFilter::from(&search),
+            UserFilter::default().gender(Gender::Male).name("Test")
+        );
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let time = DateTime::from_timestamp_millis(1_700_000_000_123).unwrap();
+        let search = UserSearch {
+            created_after: Some(time),
+            updated_before: Some(time),
+            ..Default::default()
+        };
+        let filter = UserFilter::from(&search);
+        assert!(!filter.is_empty());
+        assert_eq!(
+            filter,
+            UserFilter::default()
+                .created_after(time)
+                .updated_before(time)
+        );
+        let time = bson::DateTime::from_millis(1_700_000_000_123);
+        assert_eq!(
+            query(filter),
+            doc! {"created_at": {"$gt": time}, "updated_at": {"$lt": time}}
+        );
+    }
+
+    #[test]
+    fn test_rejects_long_name() {
+        let filter = UserFilter::default().name("a".repeat(1000));
+        assert_eq!(
+            filter.to_document(&EmailNormalizer::default(), &NameNormalizer::default()),
+            Err(SanitizeError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_name_normalization() {
+        let filter = UserFilter::default().name("Jose\u{301}");
+        assert_eq!(query(filter.clone()), doc! {"name": "Jos\u{e9}"});
+        assert_eq!(
+            filter
+                .name_containing("Mü")
+                .to_document(&EmailNormalizer::default(), &NameNormalizer::new(true))
+                .unwrap(),
+            doc! {
+                "name_normalized": Bson::RegularExpression(Regex {
+                    pattern: "mue".to_owned(),
+                    options: "i".to_owned(),
+                }),
+            }
+        );
+    }
+}
@@ -0,0 +1,420 @@
+/*!
+Import of users from the files of other systems.
+
+A [`ColumnMapping`] names the user field each column of a file holds so
+files exported by legacy systems are imported as they are. Values are read
+as text and coerced to their field: ages may be written as decimals, and
+genders as `M` or `female` in any case. Empty optional values are left out.
+
+Rows that can't be read, coerced or validated are reported by number as a
+[`RowError`] while the other rows are imported. A file is only rejected as
+a whole when it can't be read or lacks a mapped column.
+*/
+use crate::types::{Address, Email, EmailValidation, Gender, Phone, User, ValidateRequest};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display},
+};
+use thiserror::Error;
+
+/// Maximum number of row errors listed in an import report.
+pub const MAX_ROW_ERRORS: usize = 100;
+
+/// User field a column is imported into.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportField {
+    Name,
+    Age,
+    Email,
+    Gender,
+    Phone,
+    Address,
+}
+
+impl ImportField {
+    const ALL: [Self; 6] = [
+        Self::Name,
+        Self::Age,
+        Self::Email,
+        Self::Gender,
+        Self::Phone,
+        Self::Address,
+    ];
+
+    fn required(&self) -> bool {
+        !matches!(self, Self::Phone | Self::Address)
+    }
+}
+
+impl Display for ImportField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Name => "name",
+            Self::Age => "age",
+            Self::Email => "email",
+            Self::Gender => "gender",
+            Self::Phone => "phone",
+            Self::Address => "address",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Failure to import a file.
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("Field `{0}` is mapped from more than one column")]
+    DuplicateField(ImportField),
+    #[error("No column is mapped to field `{0}`")]
+    UnmappedField(ImportField),
+    #[error("Mapped column `{0}` is missing from the file")]
+    MissingColumn(String),
+    #[error("Invalid CSV file: {0}")]
+    Csv(#[from] csv::Error),
+    #[cfg(feature = "parquet")]
+    #[error("Invalid Parquet file: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+}
+
+/// Column of the file holding each imported field, written as
+/// `{"Full Name": "name", "Years": "age"}`. Every required field must be
+/// mapped from exactly one column.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(try_from = "BTreeMap<String, ImportField>")]
+pub struct ColumnMapping(BTreeMap<String, ImportField>);
+
+/// Columns named after the fields they hold.
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self(
+            ImportField::ALL
+                .into_iter()
+                .map(|field| (field.to_string(), field))
+                .collect(),
+        )
+    }
+}
+
+impl TryFrom<BTreeMap<String, ImportField>> for ColumnMapping {
+    type Error = ImportError;
+    fn try_from(columns: BTreeMap<String, ImportField>) -> Result<Self, Self::Error> {
+        let mut mapped = BTreeSet::new();
+        for field in columns.values() {
+            if !mapped.insert(*field) {
+                return Err(ImportError::DuplicateField(*field));
+            }
+        }
+        match ImportField::ALL
+            .into_iter()
+            .find(|field| field.required() && !mapped.contains(field))
+        {
+            Some(field) => Err(ImportError::UnmappedField(field)),
+            None => Ok(Self(columns)),
+        }
+    }
+}
+
+impl ColumnMapping {
+    /// Names of the mapped columns.
+    pub fn columns(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    /// User of a row whose `value` of a column is its text, none when it is
+    /// empty or null, with its email validated in `mode`.
+    pub fn user(
+        &self,
+        value: impl Fn(&str) -> Option<String>,
+        mode: EmailValidation,
+    ) -> Result<User, String> {
+        let mut fields = self
+            .0
+            .iter()
+            .filter_map(|(column, field)| Some((*field, value(column)?)))
+            .filter(|(_, text)| !text.trim().is_empty())
+            .collect::<BTreeMap<_, _>>();
+        let mut required = |field: ImportField| {
+            fields
+                .remove(&field)
+                .map(|text| text.trim().to_owned())
+                .ok_or_else(|| format!("Missing {field}"))
+        };
+
+        let user = User {
+            id: None,
+            name: required(ImportField::Name)?,
+            age: coerce_age(&required(ImportField::Age)?)?,
+            email: Email(required(ImportField::Email)?),
+            gender: coerce_gender(&required(ImportField::Gender)?)?,
+            phone: fields
+                .remove(&ImportField::Phone)
+                .map(|text| Phone(text.trim().to_owned())),
+            address: fields
+                .remove(&ImportField::Address)
+                .map(|text| Address(text.trim().to_owned())),
+            created_at: None,
+            updated_at: None,
+        };
+        user.validate_request(mode).map_err(|e| e.to_string())?;
+        Ok(user)
+    }
+}
+
+/// Age written as a whole number, possibly with a zero fraction.
+fn coerce_age(text: &str) -> Result<u32, String> {
+    text.parse::<u32>()
+        .ok()
+        .or_else(|| {
+            text.parse::<f64>()
+                .ok()
+                .filter(|age| age.fract() == 0.0 && (0.0..=f64::from(u32::MAX)).contains(age))
+                .map(|age| age as u32)
+        })
+        .ok_or_else(|| format!("Age `{text}` is not a whole number"))
+}
+
+/// Gender written in full or by its initial in any case.
+fn coerce_gender(text: &str) -> Result<Gender, String> {
+    match text.to_lowercase().as_str() {
+        "male" | "m" => Ok(Gender::Male),
+        "female" | "f" => Ok(Gender::Female),
+        _ => Err(format!("Unknown gender `{text}`")),
+    }
+}
+
+/// Row of a file that wasn't imported. Rows are numbered from 1 without
+/// counting a header.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RowError {
+    pub row: u64,
+    pub message: String,
+}
+
+/// Outcome of an import.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    /// Number of users saved.
+    pub imported: u64,
+    /// Number of rows that weren't imported.
+    pub failed: u64,
+    /// The first [`MAX_ROW_ERRORS`] rows that weren't imported.
+    pub errors: Vec<RowError>,
+}
+
+impl ImportReport {
+    /// Record a row that wasn't imported.
+    pub fn fail(&mut self, error: RowError) {
+        self.failed += 1;
+        if self.errors.len() < MAX_ROW_ERRORS {
+            self.errors.push(error);
+        }
+    }
+}
+
+/// Users of the rows of a CSV file with a header row, one per row in order,
+/// with emails validated in `mode`.
+pub fn csv_users(
+    data: &[u8],
+    mapping: &ColumnMapping,
+    mode: EmailValidation,
+) -> Result<Vec<Result<User, RowError>>, ImportError> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data);
+    let headers = reader.headers()?.clone();
+    let indexes = mapping
+        .columns()
+        .map(
+            |column| match headers.iter().position(|h| h.trim() == column) {
+                Some(index) => Ok((column, index)),
+                None => Err(ImportError::MissingColumn(column.to_owned())),
+            },
+        )
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+
+    Ok(reader
+        .records()
+        .zip(1..)
+        .map(|(record, row)| {
+            let record = record.map_err(|e| e.to_string());
+            record
+                .and_then(|record| {
+                    mapping.user(
+                        |column| record.get(indexes[column]).map(str::to_owned),
+                        mode,
+                    )
+                })
+                .map_err(|message| RowError { row, message })
+        })
+        .collect())
+}
+
+/// Users of the rows of a Parquet file, one per row in order, with emails
+/// validated in `mode`. Values of any type are read as their text.
+#[cfg(feature = "parquet")]
+pub fn parquet_users(
+    data: bytes::Bytes,
+    mapping: &ColumnMapping,
+    mode: EmailValidation,
+) -> Result<Vec<Result<User, RowError>>, ImportError> {
+    use arrow_cast::display::{ArrayFormatter, FormatOptions};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(data)?.build()?;
+    let options = FormatOptions::default();
+    let mut users = Vec::new();
+    let mut row = 0;
+    for batch in reader {
+        let batch = batch.map_err(parquet::errors::ParquetError::from)?;
+        let schema = batch.schema();
+        let columns = mapping
+            .columns()
+            .map(|column| {
+                let index = schema
+                    .index_of(column)
+                    .map_err(|_| ImportError::MissingColumn(column.to_owned()))?;
+                let array = batch.column(index);
+                let formatter = ArrayFormatter::try_new(array.as_ref(), &options)
+                    .map_err(parquet::errors::ParquetError::from)?;
+                Ok((column, (array, formatter)))
+            })
+            .collect::<Result<BTreeMap<_, _>, ImportError>>()?;
+
+        for i in 0..batch.num_rows() {
+            row += 1;
+            let user = mapping.user(
+                |column| {
+                    let (array, formatter) = &columns[column];
+                    (!array.is_null(i)).then(|| formatter.value(i).to_string())
+                },
+                mode,
+            );
+            users.push(user.map_err(|message| RowError { row, message }));
+        }
+    }
+    Ok(users)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{csv_users, ColumnMapping, ImportError, ImportReport, RowError, MAX_ROW_ERRORS};
+    use crate::types::{Address, EmailValidation, Gender};
+    use serde_json::json;
+
+    fn legacy_mapping() -> ColumnMapping {
+        serde_json::from_value(json!({
+            "Full Name": "name",
+            "Years": "age",
+            "E-mail": "email",
+            "Sex": "gender",
+            "Street": "address",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_mapping() {
+        assert!(matches!(
+            serde_json::from_value::<ColumnMapping>(json!({"a": "name", "b": "name"})),
+            Err(e) if e.to_string().contains("more than one column")
+        ));
+        assert!(matches!(
+            ColumnMapping::try_from(
+                [("Name".to_owned(), super::ImportField::Name)]
+                    .into_iter()
+                    .collect::<std::collections::BTreeMap<_, _>>()
+            ),
+            Err(ImportError::UnmappedField(super::ImportField::Age))
+        ));
+        assert_eq!(
+            ColumnMapping::default().columns().collect::<Vec<_>>(),
+            ["address", "age", "email", "gender", "name", "phone"]
+        );
+    }
+
+    #[test]
+    fn test_csv_import() {
+        let data = "Id,Full Name,Years,E-mail,Sex,Street\n\
+                    1,Test User,100.0,test@test.com,m,\n\
+                    2,Other User,old,other@test.com,F,1 Main St\n\
+                    3,Young User,20,young@test.com,Female,\"1 Main St, Apt 2\"\n\
+                    4,Last User,101,last@test.com,FEMALE,\"1 Main St, Apt 2\"\n";
+        let users =
+            csv_users(data.as_bytes(), &legacy_mapping(), EmailValidation::Lenient).unwrap();
+        assert_eq!(users.len(), 4);
+
+        let first = users[0].as_ref().unwrap();
+        assert_eq!((first.age, &first.gender), (100, &Gender::Male));
+        assert_eq!(first.address, None);
+        assert_eq!(
+            users[1],
+            Err(RowError {
+                row: 2,
+                message: "Age `old` is not a whole number".to_owned()
+            })
+        );
+        assert_eq!(users[2].as_ref().unwrap_err().row, 3);
+        assert_eq!(
+            users[3].as_ref().unwrap().address,
+            Some(Address("1 Main St, Apt 2".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_missing_column() {
+        let data = "Full Name,Years,E-mail\nTest User,100,test@test.com\n";
+        assert!(matches!(
+            csv_users(data.as_bytes(), &legacy_mapping(), EmailValidation::Lenient),
+            Err(ImportError::MissingColumn(column)) if column == "Sex"
+        ));
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = ImportReport::default();
+        for row in 0..=MAX_ROW_ERRORS as u64 {
+            report.fail(RowError {
+                row,
+                message: String::new(),
+            });
+        }
+        assert_eq!(report.failed, MAX_ROW_ERRORS as u64 + 1);
+        assert_eq!(report.errors.len(), MAX_ROW_ERRORS);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[tokio::test]
+    async fn test_parquet_import() {
+        use super::parquet_users;
+        use crate::export::{Formatter, ParquetFormatter};
+        use futures::{stream, StreamExt, TryStreamExt};
+
+        let records = stream::iter(vec![
+            Ok(json!({"name": "Test User", "age": 100, "email": "test@test.com", "gender": "Male"})),
+            Ok(json!({"name": "Other User", "email": "other@test.com", "gender": "Female"})),
+        ])
+        .boxed();
+        let chunks = ParquetFormatter::default()
+            .format(records)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        let users = parquet_users(
+            chunks.concat().into(),
+            &ColumnMapping::default(),
+            EmailValidation::Lenient,
+        )
+        .unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].as_ref().unwrap().age, 100);
+        assert_eq!(
+            users[1],
+            Err(RowError {
+                row: 2,
+                message: "Missing age".to_owned()
+            })
+        );
+    }
+}
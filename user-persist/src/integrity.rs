@@ -0,0 +1,256 @@
+/*!
+Consistency checks of stored users.
+
+Documents written by older versions of the services can be missing fields
+that are now required or lack the normalized email used for lookups and
+the unique index, the normalized name used by transliterated searches or
+the creation and update times searches filter on. [`check_document`]
+reports what is wrong with a stored user. Only missing or outdated
+normalized emails and names are repaired, by normalizing the stored values
+again, and missing times, by taking them from the key, as every other
+repair would have to guess at data.
+*/
+use crate::{
+    email::EmailNormalizer,
+    name::NameNormalizer,
+    persistence::PersistenceResult,
+    schema::missing_timestamps,
+    types::{Email, EmailValidation},
+};
+use futures::stream::BoxStream;
+use mongodb::bson::{Bson, Document};
+use serde::Serialize;
+
+/// Fields every stored user must have.
+pub const REQUIRED_FIELDS: &[&str] = &["name", "age", "email", "gender"];
+
+/// Findings of an integrity check read incrementally.
+pub type IntegrityStream = BoxStream<'static, PersistenceResult<IntegrityFinding>>;
+
+/// Problem with a stored user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "issue", rename_all = "camelCase")]
+pub enum IntegrityIssue {
+    /// The email isn't valid once normalized.
+    InvalidEmail,
+    /// Required fields are missing or null.
+    MissingFields { fields: Vec<&'static str> },
+    /// The normalized email is missing or differs from the stored email
+    /// normalized again.
+    StaleNormalizedEmail,
+    /// The normalized name is missing or differs from the stored name
+    /// normalized again.
+    StaleNormalizedName,
+    /// The creation or update time is missing.
+    MissingTimestamps,
+    /// Records of a related collection belong to a user which doesn't
+    /// exist.
+    DanglingReference { collection: &'static str },
+}
+
+impl IntegrityIssue {
+    /// Whether the issue can be repaired without guessing at data.
+    pub fn repairable(&self) -> bool {
+        matches!(
+            self,
+            Self::StaleNormalizedEmail | Self::StaleNormalizedName | Self::MissingTimestamps
+        )
+    }
+}
+
+/// Issue found with a stored user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityFinding {
+    /// Key of the user.
+    pub id: String,
+    #[serde(flatten)]
+    pub issue: IntegrityIssue,
+    /// The issue was repaired.
+    pub repaired: bool,
+}
+
+/// Issues of a stored user document with emails normalized by `emails`
+/// and validated in `mode` and names normalized by `names`.
+pub fn check_document(
+    document: &Document,
+    emails: &EmailNormalizer,
+    mode: EmailValidation,
+    names: &NameNormalizer,
+) -> Vec<IntegrityIssue> {
+    let mut issues = Vec::new();
+
+    let missing = REQUIRED_FIELDS
+        .iter()
+        .copied()
+        .filter(|field| matches!(document.get(field), None | Some(Bson::Null)))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        issues.push(IntegrityIssue::MissingFields { fields: missing });
+    }
+
+    if let Ok(email) = document.get_str("email") {
+        let normalized = emails.normalize(&Email(email.to_owned()));
+        if !Email(normalized.clone()).is_valid(mode) {
+            issues.push(IntegrityIssue::InvalidEmail);
+        }
+        if document.get_str("email_normalized").ok() != Some(normalized.as_str()) {
+            issues.push(IntegrityIssue::StaleNormalizedEmail);
+        }
+    }
+
+    if let Ok(name) = document.get_str("name") {
+        if document.get_str("name_normalized").ok() != Some(names.normalize(name).as_str()) {
+            issues.push(IntegrityIssue::StaleNormalizedName);
+        }
+    }
+
+    if !missing_timestamps(document).is_empty() {
+        issues.push(IntegrityIssue::MissingTimestamps);
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_document, IntegrityFinding, IntegrityIssue};
+    use crate::{email::EmailNormalizer, name::NameNormalizer, types::EmailValidation};
+    use mongodb::bson::{doc, oid::ObjectId, Bson};
+    use serde_json::json;
+
+    fn emails() -> EmailNormalizer {
+        EmailNormalizer::default()
+    }
+
+    fn names() -> NameNormalizer {
+        NameNormalizer::default()
+    }
+
+    #[test]
+    fn test_consistent_document() {
+        let document = doc! {
+            "name": "Test User",
+            "name_normalized": "test user",
+            "age": 100,
+            "email": "Test@Test.com",
+            "email_normalized": "test@test.com",
+            "gender": "Male",
+        };
+        assert_eq!(
+            check_document(&document, &emails(), EmailValidation::Lenient, &names()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_old_document() {
+        let document = doc! {"name": "Test User", "age": Bson::Null, "email": "Test@Test.com"};
+        assert_eq!(
+            check_document(&document, &emails(), EmailValidation::Lenient, &names()),
+            vec![
+                IntegrityIssue::MissingFields {
+                    fields: vec!["age", "gender"]
+                },
+                IntegrityIssue::StaleNormalizedEmail,
+                IntegrityIssue::StaleNormalizedName,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_email() {
+        let document = doc! {
+            "name": "Test User",
+            "name_normalized": "test user",
+            "age": 100,
+            "email": "not an email",
+            "email_normalized": "not an email",
+            "gender": "Male",
+        };
+        assert_eq!(
+            check_document(&document, &emails(), EmailValidation::Lenient, &names()),
+            vec![IntegrityIssue::InvalidEmail]
+        );
+    }
+
+    #[test]
+    fn test_stale_normalized_email() {
+        let document = doc! {
+            "name": "Test User",
+            "name_normalized": "test user",
+            "age": 100,
+            "email": "test+news@test.com",
+            "email_normalized": "test+news@test.com",
+            "gender": "Male",
+        };
+        let issues = check_document(
+            &document,
+            &EmailNormalizer::new(true),
+            EmailValidation::Lenient,
+            &names(),
+        );
+        assert_eq!(issues, vec![IntegrityIssue::StaleNormalizedEmail]);
+        assert!(issues[0].repairable());
+    }
+
+    #[test]
+    fn test_stale_normalized_name() {
+        let document = doc! {
+            "name": "Jörg Müller",
+            "name_normalized": "jörg müller",
+            "age": 100,
+            "email": "test@test.com",
+            "email_normalized": "test@test.com",
+            "gender": "Male",
+        };
+        assert_eq!(
+            check_document(&document, &emails(), EmailValidation::Lenient, &names()),
+            vec![]
+        );
+        let issues = check_document(
+            &document,
+            &emails(),
+            EmailValidation::Lenient,
+            &NameNormalizer::new(true),
+        );
+        assert_eq!(issues, vec![IntegrityIssue::StaleNormalizedName]);
+        assert!(issues[0].repairable());
+    }
+
+    #[test]
+    fn test_missing_timestamps() {
+        let document = doc! {
+            "_id": ObjectId::new(),
+            "name": "Test User",
+            "name_normalized": "test user",
+            "age": 100,
+            "email": "test@test.com",
+            "email_normalized": "test@test.com",
+            "gender": "Male",
+        };
+        let issues = check_document(&document, &emails(), EmailValidation::Lenient, &names());
+        assert_eq!(issues, vec![IntegrityIssue::MissingTimestamps]);
+        assert!(issues[0].repairable());
+    }
+
+    #[test]
+    fn test_finding_json() {
+        let finding = IntegrityFinding {
+            id: "61c0d1954c6b974ca7000000".to_owned(),
+            issue: IntegrityIssue::MissingFields {
+                fields: vec!["gender"],
+            },
+            repaired: false,
+        };
+        assert_eq!(
+            serde_json::to_value(finding).unwrap(),
+            json!({
+                "id": "61c0d1954c6b974ca7000000",
+                "issue": "missingFields",
+                "fields": ["gender"],
+                "repaired": false,
+            })
+        );
+    }
+}
@@ -0,0 +1,530 @@
+/*!
+Durable queue of background jobs.
+
+Imports and other long running work are enqueued as [`Job`]s rather than
+run by the request creating them. A worker claims a job, which hides it
+from other workers for a visibility timeout, and then completes or fails
+it. A failed job is retried after a delay until it has been attempted
+`max_attempts` times and is then left in the [`JobState::Dead`] state for
+an operator to look at. A job whose worker died while running it becomes
+visible again once its visibility timeout passes, so work survives process
+restarts when the queue is kept in mongodb.
+
+Only the worker holding the latest claim of a job can complete or fail it.
+*/
+use crate::{
+    clock::{Clock, SystemClock},
+    mongo_persistence::{timed, OperationTimeouts},
+    persistence::PersistenceResult,
+};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::{
+    bson::{self, doc},
+    options::{
+        FindOneAndUpdateOptions, FindOneOptions, IndexOptions, ReturnDocument, UpdateModifications,
+    },
+    Collection, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+/// Name of the mongodb collection holding jobs.
+pub const JOBS_COLLECTION_NAME: &str = "jobs";
+
+/// Error recorded for a job whose worker stopped responding during its
+/// last attempt.
+const VISIBILITY_EXPIRED: &str = "visibility timeout expired";
+
+/// Progress of a job.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobState {
+    /// Waiting to be claimed, possibly for a retry.
+    Queued,
+    /// Claimed by a worker.
+    Running,
+    Succeeded,
+    /// Failed on every attempt.
+    Dead,
+}
+
+/// Work enqueued for a background worker.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: String,
+    /// Selects the handler running the job.
+    pub kind: String,
+    /// Input of the handler.
+    pub payload: Value,
+    pub state: JobState,
+    /// Number of times the job was claimed.
+    pub attempts: u32,
+    pub max_attempts: u32,
+    /// When a queued job may next be claimed or a running job's claim
+    /// expires, in milliseconds since the epoch.
+    pub visible_at_ms: i64,
+    pub created_at_ms: i64,
+    /// Why the last attempt failed.
+    pub last_error: Option<String>,
+    /// Output of the handler once the job succeeded.
+    pub result: Option<Value>,
+}
+
+impl Job {
+    fn new(kind: &str, payload: Value, max_attempts: u32, now: DateTime<Utc>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind: kind.to_owned(),
+            payload,
+            state: JobState::Queued,
+            attempts: 0,
+            max_attempts: max_attempts.max(1),
+            visible_at_ms: now.timestamp_millis(),
+            created_at_ms: now.timestamp_millis(),
+            last_error: None,
+            result: None,
+        }
+    }
+
+    /// Whether a worker may claim the job at `now`.
+    fn claimable(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.state, JobState::Queued | JobState::Running)
+            && self.visible_at_ms <= now.timestamp_millis()
+    }
+
+    /// Whether `claimed` is the latest claim of this job.
+    fn claimed_by(&self, claimed: &Job) -> bool {
+        self.id == claimed.id
+            && self.state == JobState::Running
+            && self.attempts == claimed.attempts
+    }
+}
+
+/// Queue of jobs shared by the request handlers enqueuing them and the
+/// workers running them.
+#[async_trait::async_trait]
+pub trait JobQueue: Send + Sync + Debug {
+    /// Add a job of `kind` attempted at most `max_attempts` times.
+    async fn enqueue(
+        &self,
+        kind: &str,
+        payload: Value,
+        max_attempts: u32,
+    ) -> PersistenceResult<Job>;
+
+    /// Claim the job that has been visible the longest, hiding it from
+    /// other workers for `visibility`. Running jobs whose claim expired
+    /// are claimed again, or marked dead if they have no attempts left.
+    async fn claim(&self, visibility: Duration) -> PersistenceResult<Option<Job>>;
+
+    /// Record the result of the claimed `job`. Returns false when the claim
+    /// expired and the job was claimed again.
+    async fn complete(&self, job: &Job, result: Value) -> PersistenceResult<bool>;
+
+    /// Record why the claimed `job` failed and queue it again after
+    /// `retry_after`, unless it has no attempts left. Returns false when
+    /// the claim expired and the job was claimed again.
+    async fn fail(&self, job: &Job, error: &str, retry_after: Duration) -> PersistenceResult<bool>;
+
+    /// Job with the id `id`.
+    async fn get(&self, id: &str) -> PersistenceResult<Option<Job>>;
+}
+
+/// [`JobQueue`] keeping jobs in memory. Jobs are lost when the process
+/// stops.
+#[derive(Debug)]
+pub struct MemoryJobQueue {
+    jobs: Mutex<Vec<Job>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for MemoryJobQueue {
+    fn default() -> Self {
+        Self {
+            jobs: Mutex::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl MemoryJobQueue {
+    /// Schedule jobs by the time of `clock`.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Apply `f` to the job `claimed` if the claim is still held.
+    fn update_claimed(&self, claimed: &Job, f: impl FnOnce(&mut Job)) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.iter_mut().find(|job| job.claimed_by(claimed)) {
+            Some(job) => {
+                f(job);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl JobQueue for MemoryJobQueue {
+    async fn enqueue(
+        &self,
+        kind: &str,
+        payload: Value,
+        max_attempts: u32,
+    ) -> PersistenceResult<Job> {
+        let job = Job::new(kind, payload, max_attempts, self.clock.now());
+        self.jobs.lock().unwrap().push(job.clone());
+        Ok(job)
+    }
+
+    async fn claim(&self, visibility: Duration) -> PersistenceResult<Option<Job>> {
+        let now = self.clock.now();
+        let mut jobs = self.jobs.lock().unwrap();
+        for job in jobs.iter_mut() {
+            if job.claimable(now) && job.attempts >= job.max_attempts {
+                job.state = JobState::Dead;
+                job.last_error = Some(VISIBILITY_EXPIRED.to_owned());
+            }
+        }
+        let next = jobs
+            .iter_mut()
+            .filter(|job| job.claimable(now))
+            .min_by_key(|job| job.visible_at_ms);
+        Ok(next.map(|job| {
+            job.state = JobState::Running;
+            job.attempts += 1;
+            job.visible_at_ms = (now + visibility).timestamp_millis();
+            job.clone()
+        }))
+    }
+
+    async fn complete(&self, job: &Job, result: Value) -> PersistenceResult<bool> {
+        Ok(self.update_claimed(job, |job| {
+            job.state = JobState::Succeeded;
+            job.result = Some(result);
+        }))
+    }
+
+    async fn fail(&self, job: &Job, error: &str, retry_after: Duration) -> PersistenceResult<bool> {
+        let now = self.clock.now();
+        Ok(self.update_claimed(job, |job| {
+            job.last_error = Some(error.to_owned());
+            if job.attempts >= job.max_attempts {
+                job.state = JobState::Dead;
+            } else {
+                job.state = JobState::Queued;
+                job.visible_at_ms = (now + retry_after).timestamp_millis();
+            }
+        }))
+    }
+
+    async fn get(&self, id: &str) -> PersistenceResult<Option<Job>> {
+        let jobs = self.jobs.lock().unwrap();
+        Ok(jobs.iter().find(|job| job.id == id).cloned())
+    }
+}
+
+fn mongo_time(time: DateTime<Utc>) -> bson::DateTime {
+    bson::DateTime::from_millis(time.timestamp_millis())
+}
+
+/// Job as stored in mongodb.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MongoJob {
+    #[serde(rename = "_id")]
+    id: String,
+    kind: String,
+    payload: Value,
+    state: JobState,
+    attempts: u32,
+    max_attempts: u32,
+    visible_at: bson::DateTime,
+    created_at: bson::DateTime,
+    #[serde(default)]
+    last_error: Option<String>,
+    #[serde(default)]
+    result: Option<Value>,
+}
+
+impl From<Job> for MongoJob {
+    fn from(job: Job) -> Self {
+        Self {
+            id: job.id,
+            kind: job.kind,
+            payload: job.payload,
+            state: job.state,
+            attempts: job.attempts,
+            max_attempts: job.max_attempts,
+            visible_at: bson::DateTime::from_millis(job.visible_at_ms),
+            created_at: bson::DateTime::from_millis(job.created_at_ms),
+            last_error: job.last_error,
+            result: job.result,
+        }
+    }
+}
+
+impl From<MongoJob> for Job {
+    fn from(job: MongoJob) -> Self {
+        Self {
+            id: job.id,
+            kind: job.kind,
+            payload: job.payload,
+            state: job.state,
+            attempts: job.attempts,
+            max_attempts: job.max_attempts,
+            visible_at_ms: job.visible_at.timestamp_millis(),
+            created_at_ms: job.created_at.timestamp_millis(),
+            last_error: job.last_error,
+            result: job.result,
+        }
+    }
+}
+
+/// [`JobQueue`] keeping jobs in the [`JOBS_COLLECTION_NAME`] collection so
+/// they outlive the process and are shared by every instance of a service.
+/// Claims are made with a single `findOneAndUpdate` so two workers never
+/// hold the same claim.
+#[derive(Debug, Clone)]
+pub struct MongoJobQueue {
+    collection: Collection<MongoJob>,
+    timeouts: OperationTimeouts,
+    clock: Arc<dyn Clock>,
+}
+
+impl MongoJobQueue {
+    /// Store jobs in `db` with the default operation limits.
+    pub fn new(db: &Database, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            collection: db.collection(JOBS_COLLECTION_NAME),
+            timeouts: OperationTimeouts::default(),
+            clock,
+        }
+    }
+
+    /// Limit operations to `timeouts`.
+    pub fn with_timeouts(self, timeouts: OperationTimeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    /// Create the index claims are looked up by.
+    pub async fn ensure_indexes(&self) -> PersistenceResult<()> {
+        let index = IndexModel::builder()
+            .keys(doc! {"state": 1, "visibleAt": 1})
+            .options(IndexOptions::builder().name("claim".to_owned()).build())
+            .build();
+        self.collection.create_index(index, None).await?;
+        Ok(())
+    }
+
+    fn now(&self) -> bson::DateTime {
+        mongo_time(self.clock.now())
+    }
+
+    fn later(&self, delay: Duration) -> bson::DateTime {
+        mongo_time(self.clock.now() + delay)
+    }
+
+    /// Apply `update` to the job `claimed` if the claim is still held.
+    async fn update_claimed(
+        &self,
+        claimed: &Job,
+        update: impl Into<UpdateModifications>,
+    ) -> PersistenceResult<bool> {
+        let filter = doc! {
+            "_id": &claimed.id,
+            "state": "running",
+            "attempts": claimed.attempts,
+        };
+        let result = timed(
+            "update_one",
+            JOBS_COLLECTION_NAME,
+            self.timeouts.write,
+            self.collection.update_one(filter, update, None),
+        )
+        .await?;
+        Ok(result.matched_count == 1)
+    }
+}
+
+#[async_trait::async_trait]
+impl JobQueue for MongoJobQueue {
+    async fn enqueue(
+        &self,
+        kind: &str,
+        payload: Value,
+        max_attempts: u32,
+    ) -> PersistenceResult<Job> {
+        let job = Job::new(kind, payload, max_attempts, self.clock.now());
+        timed(
+            "insert_one",
+            JOBS_COLLECTION_NAME,
+            self.timeouts.write,
+            self.collection
+                .insert_one(MongoJob::from(job.clone()), None),
+        )
+        .await?;
+        Ok(job)
+    }
+
+    async fn claim(&self, visibility: Duration) -> PersistenceResult<Option<Job>> {
+        let now = self.now();
+        timed(
+            "update_many",
+            JOBS_COLLECTION_NAME,
+            self.timeouts.write,
+            self.collection.update_many(
+                doc! {
+                    "state": "running",
+                    "visibleAt": {"$lte": now},
+                    "$expr": {"$gte": ["$attempts", "$maxAttempts"]},
+                },
+                doc! {"$set": {"state": "dead", "lastError": VISIBILITY_EXPIRED}},
+                None,
+            ),
+        )
+        .await?;
+
+        let options = FindOneAndUpdateOptions::builder()
+            .sort(doc! {"visibleAt": 1})
+            .return_document(ReturnDocument::After)
+            .max_time(self.timeouts.write)
+            .build();
+        let job = timed(
+            "find_one_and_update",
+            JOBS_COLLECTION_NAME,
+            self.timeouts.write,
+            self.collection.find_one_and_update(
+                doc! {
+                    "state": {"$in": ["queued", "running"]},
+                    "visibleAt": {"$lte": now},
+                },
+                doc! {
+                    "$set": {"state": "running", "visibleAt": self.later(visibility)},
+                    "$inc": {"attempts": 1},
+                },
+                options,
+            ),
+        )
+        .await?;
+        Ok(job.map(Job::from))
+    }
+
+    async fn complete(&self, job: &Job, result: Value) -> PersistenceResult<bool> {
+        let result = bson::to_bson(&result).map_err(mongodb::error::Error::from)?;
+        self.update_claimed(job, doc! {"$set": {"state": "succeeded", "result": result}})
+            .await
+    }
+
+    async fn fail(&self, job: &Job, error: &str, retry_after: Duration) -> PersistenceResult<bool> {
+        // A pipeline update so the state is decided from the stored attempts.
+        let update = doc! {"$set": {
+            "lastError": {"$literal": error},
+            "state": {"$cond": [{"$gte": ["$attempts", "$maxAttempts"]}, "dead", "queued"]},
+            "visibleAt": self.later(retry_after),
+        }};
+        self.update_claimed(job, vec![update]).await
+    }
+
+    async fn get(&self, id: &str) -> PersistenceResult<Option<Job>> {
+        let options = FindOneOptions::builder()
+            .max_time(self.timeouts.read)
+            .build();
+        let job = timed(
+            "find_one",
+            JOBS_COLLECTION_NAME,
+            self.timeouts.read,
+            self.collection.find_one(doc! {"_id": id}, options),
+        )
+        .await?;
+        Ok(job.map(Job::from))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{JobQueue, JobState, MemoryJobQueue};
+    use crate::clock::MockClock;
+    use chrono::{Duration, Utc};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_memory_retry_and_dead_letter() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let queue = MemoryJobQueue::default().with_clock(clock.clone());
+        let visibility = Duration::minutes(5);
+
+        let job = queue
+            .enqueue("import", json!({"rows": 1}), 2)
+            .await
+            .unwrap();
+        let first = queue.claim(visibility).await.unwrap().unwrap();
+        assert_eq!((first.id.as_str(), first.attempts), (job.id.as_str(), 1));
+        assert!(queue.claim(visibility).await.unwrap().is_none());
+
+        assert!(queue
+            .fail(&first, "mongo down", Duration::seconds(10))
+            .await
+            .unwrap());
+        assert!(queue.claim(visibility).await.unwrap().is_none());
+        clock.advance(Duration::seconds(10));
+
+        let second = queue.claim(visibility).await.unwrap().unwrap();
+        assert_eq!(second.attempts, 2);
+        // The first claim can't complete the job anymore.
+        assert!(!queue.complete(&first, json!(null)).await.unwrap());
+        assert!(queue
+            .fail(&second, "mongo still down", Duration::seconds(10))
+            .await
+            .unwrap());
+
+        let dead = queue.get(&job.id).await.unwrap().unwrap();
+        assert_eq!(dead.state, JobState::Dead);
+        assert_eq!(dead.last_error.as_deref(), Some("mongo still down"));
+        clock.advance(Duration::minutes(1));
+        assert!(queue.claim(visibility).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_memory_visibility_timeout() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let queue = MemoryJobQueue::default().with_clock(clock.clone());
+        let visibility = Duration::minutes(5);
+
+        let job = queue.enqueue("import", json!({}), 2).await.unwrap();
+        let abandoned = queue.claim(visibility).await.unwrap().unwrap();
+
+        // The worker died, so the job is claimed again once the claim expires.
+        clock.advance(visibility);
+        let retried = queue.claim(visibility).await.unwrap().unwrap();
+        assert_eq!(retried.attempts, 2);
+        assert!(!queue
+            .fail(&abandoned, "late", Duration::zero())
+            .await
+            .unwrap());
+        assert!(queue
+            .complete(&retried, json!({"imported": 1}))
+            .await
+            .unwrap());
+
+        let done = queue.get(&job.id).await.unwrap().unwrap();
+        assert_eq!(done.state, JobState::Succeeded);
+        assert_eq!(done.result, Some(json!({"imported": 1})));
+
+        // A job abandoned on its last attempt is dead rather than retried.
+        queue.enqueue("import", json!({}), 1).await.unwrap();
+        let last = queue.claim(visibility).await.unwrap().unwrap();
+        clock.advance(visibility);
+        assert!(queue.claim(visibility).await.unwrap().is_none());
+        let dead = queue.get(&last.id).await.unwrap().unwrap();
+        assert_eq!(dead.state, JobState::Dead);
+    }
+}
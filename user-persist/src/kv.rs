@@ -0,0 +1,336 @@
+/*!
+Key value storage for short lived service state.
+
+Idempotency records, rate limit counters and token revocations only need
+to read, write and atomically replace a value by key, usually with an
+expiry. They are written against the [`KvStore`] trait so a deployment can
+keep that state in memory for a single instance or share it through
+mongodb without changing the code using it.
+
+Values are strings. Callers encode structured values themselves, usually
+as JSON.
+*/
+use crate::{
+    clock::{Clock, SystemClock},
+    mongo_persistence::{timed, OperationTimeouts},
+    persistence::{PersistenceError, PersistenceResult},
+};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::{
+    bson::{self, doc, Document},
+    options::{FindOneOptions, IndexOptions, ReplaceOptions, UpdateOptions},
+    Collection, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+/// Name of the mongodb collection holding key value entries.
+pub const KV_COLLECTION_NAME: &str = "kv_store";
+
+/// Storage of string values by key with an optional time to live.
+#[async_trait::async_trait]
+pub trait KvStore: Send + Sync + Debug {
+    /// Value of `key` unless it is missing or has expired.
+    async fn get(&self, key: &str) -> PersistenceResult<Option<String>>;
+
+    /// Set `key` to `value`, expiring after `ttl` if given.
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> PersistenceResult<()>;
+
+    /// Set `key` to `value` only if its current value is `expected`, where
+    /// `None` expects the key to be missing or expired. Returns whether the
+    /// value was set.
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+        ttl: Option<Duration>,
+    ) -> PersistenceResult<bool>;
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    value: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// [`KvStore`] keeping entries in a map guarded by a lock. Entries aren't
+/// shared between instances of a service.
+#[derive(Debug)]
+pub struct MemoryKvStore {
+    entries: Mutex<HashMap<String, MemoryEntry>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for MemoryKvStore {
+    fn default() -> Self {
+        Self {
+            entries: Mutex::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl MemoryKvStore {
+    /// Expire entries by the time of `clock`.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    fn entry(&self, value: &str, ttl: Option<Duration>) -> MemoryEntry {
+        MemoryEntry {
+            value: value.to_owned(),
+            expires_at: ttl.map(|ttl| self.clock.now() + ttl),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl KvStore for MemoryKvStore {
+    async fn get(&self, key: &str) -> PersistenceResult<Option<String>> {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at.is_some_and(|at| at <= now) => {
+                entries.remove(key);
+                Ok(None)
+            }
+            entry => Ok(entry.map(|entry| entry.value.clone())),
+        }
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> PersistenceResult<()> {
+        let entry = self.entry(value, ttl);
+        self.entries.lock().unwrap().insert(key.to_owned(), entry);
+        Ok(())
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+        ttl: Option<Duration>,
+    ) -> PersistenceResult<bool> {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        let current = entries
+            .get(key)
+            .filter(|entry| entry.expires_at.is_none_or(|at| at > now))
+            .map(|entry| entry.value.as_str());
+        if current != expected {
+            return Ok(false);
+        }
+        entries.insert(key.to_owned(), self.entry(value, ttl));
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MongoEntry {
+    #[serde(rename = "_id")]
+    key: String,
+    value: String,
+    #[serde(rename = "expiresAt", default)]
+    expires_at: Option<bson::DateTime>,
+}
+
+/// [`KvStore`] keeping entries in the [`KV_COLLECTION_NAME`] collection so
+/// they are shared by every instance of a service. Expired entries are
+/// ignored when read and removed by a TTL index.
+#[derive(Debug, Clone)]
+pub struct MongoKvStore {
+    collection: Collection<MongoEntry>,
+    timeouts: OperationTimeouts,
+    clock: Arc<dyn Clock>,
+}
+
+impl MongoKvStore {
+    /// Store entries in `db` with the default operation limits.
+    pub fn new(db: &Database, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            collection: db.collection(KV_COLLECTION_NAME),
+            timeouts: OperationTimeouts::default(),
+            clock,
+        }
+    }
+
+    /// Limit operations to `timeouts`.
+    pub fn with_timeouts(self, timeouts: OperationTimeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    /// Create the TTL index removing expired entries.
+    pub async fn ensure_indexes(&self) -> PersistenceResult<()> {
+        let index = IndexModel::builder()
+            .keys(doc! {"expiresAt": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(std::time::Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        self.collection.create_index(index, None).await?;
+        Ok(())
+    }
+
+    fn now(&self) -> bson::DateTime {
+        bson::DateTime::from_millis(self.clock.now().timestamp_millis())
+    }
+
+    fn expires_at(&self, ttl: Option<Duration>) -> Option<bson::DateTime> {
+        ttl.map(|ttl| bson::DateTime::from_millis((self.clock.now() + ttl).timestamp_millis()))
+    }
+
+    /// Query document matching `key` while it hasn't expired. The TTL
+    /// monitor only runs periodically so expired entries can still exist.
+    fn live(&self, key: &str) -> Document {
+        doc! {
+            "_id": key,
+            "$or": [{"expiresAt": null}, {"expiresAt": {"$gt": self.now()}}],
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl KvStore for MongoKvStore {
+    async fn get(&self, key: &str) -> PersistenceResult<Option<String>> {
+        let options = FindOneOptions::builder()
+            .max_time(self.timeouts.read)
+            .build();
+        let entry = timed(
+            "find_one",
+            KV_COLLECTION_NAME,
+            self.timeouts.read,
+            self.collection.find_one(self.live(key), options),
+        )
+        .await?;
+        Ok(entry.map(|entry| entry.value))
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> PersistenceResult<()> {
+        let entry = MongoEntry {
+            key: key.to_owned(),
+            value: value.to_owned(),
+            expires_at: self.expires_at(ttl),
+        };
+        timed(
+            "replace_one",
+            KV_COLLECTION_NAME,
+            self.timeouts.write,
+            self.collection.replace_one(
+                doc! {"_id": key},
+                entry,
+                ReplaceOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        value: &str,
+        ttl: Option<Duration>,
+    ) -> PersistenceResult<bool> {
+        let update = doc! {"$set": {"value": value, "expiresAt": self.expires_at(ttl)}};
+        let (filter, upsert) = match expected {
+            Some(expected) => {
+                let mut filter = self.live(key);
+                filter.insert("value", expected);
+                (filter, false)
+            }
+            // Replace an expired entry or insert a new one. A live entry
+            // doesn't match so the upsert fails on the duplicate `_id`.
+            None => (doc! {"_id": key, "expiresAt": {"$lte": self.now()}}, true),
+        };
+
+        let result = timed(
+            "update_one",
+            KV_COLLECTION_NAME,
+            self.timeouts.write,
+            self.collection.update_one(
+                filter,
+                update,
+                UpdateOptions::builder().upsert(upsert).build(),
+            ),
+        )
+        .await;
+        match result {
+            Ok(result) => Ok(result.matched_count == 1 || result.upserted_id.is_some()),
+            Err(PersistenceError::DuplicateKey) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KvStore, MemoryKvStore};
+    use crate::clock::MockClock;
+    use chrono::{Duration, Utc};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_memory_expiry() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let store = MemoryKvStore::default().with_clock(clock.clone());
+
+        store
+            .set("token", "revoked", Some(Duration::minutes(5)))
+            .await
+            .unwrap();
+        store.set("forever", "1", None).await.unwrap();
+        assert_eq!(
+            store.get("token").await.unwrap().as_deref(),
+            Some("revoked")
+        );
+
+        clock.advance(Duration::minutes(5));
+        assert_eq!(store.get("token").await.unwrap(), None);
+        assert_eq!(store.get("forever").await.unwrap().as_deref(), Some("1"));
+        assert_eq!(store.get("missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_compare_and_set() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let store = MemoryKvStore::default().with_clock(clock.clone());
+        let ttl = Some(Duration::seconds(60));
+
+        assert!(store
+            .compare_and_set("count", None, "1", ttl)
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_set("count", None, "1", ttl)
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_set("count", Some("5"), "6", ttl)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_set("count", Some("1"), "2", ttl)
+            .await
+            .unwrap());
+        assert_eq!(store.get("count").await.unwrap().as_deref(), Some("2"));
+
+        // An expired entry counts as missing.
+        clock.advance(Duration::seconds(60));
+        assert!(!store
+            .compare_and_set("count", Some("2"), "3", ttl)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_set("count", None, "1", ttl)
+            .await
+            .unwrap());
+    }
+}
@@ -1,15 +1,48 @@
+// Allows `#[derive(MaskedDebug)]` to be used inside this crate.
+extern crate self as user_persist;
+
+pub mod anomaly;
+pub mod audit;
+pub mod bson_json;
+pub mod clock;
+pub mod email;
+pub mod export;
+pub mod filter;
+pub mod import;
+pub mod integrity;
+pub mod jobs;
+pub mod kv;
+pub mod lock;
+pub mod masked;
+pub mod memory;
 pub mod mongo_persistence;
+pub mod name;
+pub mod patch;
 pub mod persistence;
+pub mod query;
+pub mod references;
+pub mod sanitize;
+pub mod schema;
+pub mod stats;
+pub mod streaming;
 pub mod types;
+pub mod validation;
 
 use clap::Args;
-use mongodb::options::{AuthMechanism, ClientOptions, Credential, ServerAddress, Tls, TlsOptions};
+use mongo_persistence::{AggregationSettings, NameMatching, OperationTimeouts};
+use mongodb::options::{
+    AuthMechanism, ClientOptions, Collation, Credential, ServerAddress, Tls, TlsOptions,
+};
 use mongodb::Client;
+use secrecy::{ExposeSecret, SecretString};
 use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
+use std::time::Duration;
 use tracing::info;
+use types::EmailValidation;
 
-pub use validator::{Validate, ValidationErrors};
+pub use masked_debug::MaskedDebug;
+pub use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
 
 /// Tracing target for persistence.
 pub const PERSISTENCE_TARGET: &str = "persistence";
@@ -23,15 +56,15 @@ pub async fn init_mongo_client(
 
     let credentials = Credential::builder()
         .username(Some(args.mongo_user))
-        .password(Some(args.mongo_pass))
+        .password(Some(args.mongo_pass.expose_secret().to_owned()))
         .source(Some(args.mongo_db))
         .mechanism(Some(AuthMechanism::ScramSha256))
         .build();
 
     let tls_options = TlsOptions::builder()
-        // Only for testing self signed certificate. You could setup with openssl and export
-        // SSL_CERT_FILE and then this can be removed.
-        .allow_invalid_certificates(Some(true))
+        // Only for testing self signed certificates and refused with the prod profile.
+        // You could setup with openssl and export SSL_CERT_FILE instead.
+        .allow_invalid_certificates(args.mongo_allow_invalid_certificates)
         .ca_file_path(Some(args.mongo_ca_file))
         .cert_key_file_path(Some(args.mongo_key_file))
         .build();
@@ -49,7 +82,8 @@ pub async fn init_mongo_client(
     let result = client.list_databases(None, None).await?;
     info!(
       target: PERSISTENCE_TARGET,
-      "Connected to mongodb: {result:?}"
+      databases = result.len(),
+      "Connected to mongodb"
     );
     Ok(client.database(db_name))
 }
@@ -61,7 +95,7 @@ pub struct MongoArgs {
     #[clap(long)]
     mongo_user: String,
     #[clap(long)]
-    mongo_pass: String,
+    mongo_pass: SecretString,
     #[clap(long)]
     mongo_db: String,
     #[clap(long)]
@@ -72,6 +106,95 @@ pub struct MongoArgs {
     mongo_ca_file: PathBuf,
     #[clap(long)]
     mongo_key_file: PathBuf,
+    /// Accept self signed mongodb certificates. Defaults from the profile.
+    #[clap(long)]
+    mongo_allow_invalid_certificates: Option<bool>,
+    /// Strip plus tags (user+tag@example.com) when normalizing emails.
+    #[clap(long)]
+    strip_email_tags: bool,
+    /// Match names once transliterated to Latin letters so `Mueller`
+    /// matches `Müller`. Users saved before must be repaired with the
+    /// integrity check first.
+    #[clap(long)]
+    transliterate_names: bool,
+    /// Email validation mode applied to requests.
+    #[clap(long, value_enum, default_value = "lenient")]
+    email_validation: EmailValidation,
+    /// Locale names are matched and sorted in, such as `es` or `fr_CA`.
+    #[clap(long, default_value = "en")]
+    collation_locale: String,
+    /// How strictly names are compared when searching.
+    #[clap(long, value_enum, default_value = "case-insensitive")]
+    name_matching: NameMatching,
+    /// Fail aggregations that would need to spill to disk.
+    #[clap(long)]
+    no_aggregation_disk_use: bool,
+    /// Aggregation execution time limit in milliseconds, 0 for no limit.
+    #[clap(long, default_value = "30000")]
+    aggregation_max_time_ms: u64,
+    /// Time limit of lookups, searches and counts in milliseconds, 0 for no
+    /// limit.
+    #[clap(long, default_value = "5000")]
+    mongo_read_timeout_ms: u64,
+    /// Time limit of inserts, updates and deletes in milliseconds, 0 for no
+    /// limit.
+    #[clap(long, default_value = "5000")]
+    mongo_write_timeout_ms: u64,
+    /// Write users upgraded to the current schema back when they are
+    /// looked up.
+    #[clap(long)]
+    schema_write_back: bool,
+}
+
+/// Duration of a millisecond setting where 0 means no limit.
+fn time_limit(millis: u64) -> Option<Duration> {
+    (millis > 0).then(|| Duration::from_millis(millis))
+}
+
+impl MongoArgs {
+    /// Email validation mode applied to requests.
+    pub fn email_validation(&self) -> EmailValidation {
+        self.email_validation
+    }
+
+    /// Collation searches match and sort names with.
+    pub fn search_collation(&self) -> Collation {
+        self.name_matching.collation(&self.collation_locale)
+    }
+
+    /// Limits applied to aggregations.
+    pub fn aggregation_settings(&self) -> AggregationSettings {
+        AggregationSettings {
+            allow_disk_use: !self.no_aggregation_disk_use,
+            max_time: time_limit(self.aggregation_max_time_ms),
+        }
+    }
+
+    /// Time limits of database operations.
+    pub fn operation_timeouts(&self) -> OperationTimeouts {
+        OperationTimeouts {
+            read: time_limit(self.mongo_read_timeout_ms),
+            write: time_limit(self.mongo_write_timeout_ms),
+        }
+    }
+
+    /// Whether users upgraded to the current schema are written back.
+    pub fn schema_write_back(&self) -> bool {
+        self.schema_write_back
+    }
+
+    /// Explicit mongodb certificate validation setting.
+    pub fn allow_invalid_certificates(&self) -> Option<bool> {
+        self.mongo_allow_invalid_certificates
+    }
+
+    /// Set whether self signed mongodb certificates are accepted.
+    pub fn with_allow_invalid_certificates(self, allow: bool) -> Self {
+        Self {
+            mongo_allow_invalid_certificates: Some(allow),
+            ..self
+        }
+    }
 }
 
 impl Display for MongoArgs {
@@ -85,8 +208,34 @@ impl Display for MongoArgs {
       app_name {} \
       mongo_ca_file {:?} \
       mongo_key_file {:?} \
+      mongo_allow_invalid_certificates {:?} \
+      strip_email_tags {} \
+      transliterate_names {} \
+      email_validation {:?} \
+      collation_locale {} \
+      name_matching {:?} \
+      no_aggregation_disk_use {} \
+      aggregation_max_time_ms {} \
+      mongo_read_timeout_ms {} \
+      mongo_write_timeout_ms {} \
+      schema_write_back {} \
       ",
-            self.mongo_db, self.mongo_host, self.app_name, self.mongo_ca_file, self.mongo_key_file,
+            self.mongo_db,
+            self.mongo_host,
+            self.app_name,
+            self.mongo_ca_file,
+            self.mongo_key_file,
+            self.mongo_allow_invalid_certificates,
+            self.strip_email_tags,
+            self.transliterate_names,
+            self.email_validation,
+            self.collation_locale,
+            self.name_matching,
+            self.no_aggregation_disk_use,
+            self.aggregation_max_time_ms,
+            self.mongo_read_timeout_ms,
+            self.mongo_write_timeout_ms,
+            self.schema_write_back,
         )
     }
 }
@@ -0,0 +1,119 @@
+/*!
+Leases electing a single instance to run a background task.
+
+When several replicas of a service run, work such as the daily statistics
+snapshot must only be done by one of them. A [`DistributedLock`] grants a
+named lease to one instance at a time. The holder renews the lease while
+it works and another instance can only take it once it has expired, so a
+replica that dies gives up its leases after at most one lease period.
+*/
+use crate::{kv::KvStore, persistence::PersistenceResult};
+use chrono::Duration;
+use std::{fmt::Debug, sync::Arc};
+
+/// Named leases held by one instance at a time.
+#[async_trait::async_trait]
+pub trait DistributedLock: Send + Sync + Debug {
+    /// Take the lease of `name` for `lease`, or extend it if this instance
+    /// already holds it. Returns whether this instance holds the lease.
+    async fn acquire(&self, name: &str, lease: Duration) -> PersistenceResult<bool>;
+
+    /// Give up the lease of `name` if this instance holds it.
+    async fn release(&self, name: &str) -> PersistenceResult<()>;
+}
+
+/// [`DistributedLock`] keeping leases as expiring entries of a
+/// [`KvStore`]. Leases are shared between instances when the store is,
+/// such as with [`MongoKvStore`](crate::kv::MongoKvStore).
+#[derive(Debug)]
+pub struct KvLock {
+    store: Arc<dyn KvStore>,
+    /// Identifies this instance as the holder of a lease.
+    owner: String,
+}
+
+impl KvLock {
+    /// Hold leases in `store` under a new random owner.
+    pub fn new(store: Arc<dyn KvStore>) -> Self {
+        Self {
+            store,
+            owner: uuid::Uuid::new_v4().to_string(),
+        }
+    }
+
+    /// Owner leases are held under.
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    fn key(name: &str) -> String {
+        format!("lock:{name}")
+    }
+}
+
+#[async_trait::async_trait]
+impl DistributedLock for KvLock {
+    async fn acquire(&self, name: &str, lease: Duration) -> PersistenceResult<bool> {
+        let key = Self::key(name);
+        let owner = Some(self.owner.as_str());
+        Ok(self
+            .store
+            .compare_and_set(&key, owner, &self.owner, Some(lease))
+            .await?
+            || self
+                .store
+                .compare_and_set(&key, None, &self.owner, Some(lease))
+                .await?)
+    }
+
+    async fn release(&self, name: &str) -> PersistenceResult<()> {
+        // Expire the entry at once rather than deleting it, which the store
+        // can't do conditionally.
+        self.store
+            .compare_and_set(
+                &Self::key(name),
+                Some(&self.owner),
+                &self.owner,
+                Some(Duration::zero()),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DistributedLock, KvLock};
+    use crate::{clock::MockClock, kv::MemoryKvStore};
+    use chrono::{Duration, Utc};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_kv_lock() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let store = Arc::new(MemoryKvStore::default().with_clock(clock.clone()));
+        let (first, second) = (KvLock::new(store.clone()), KvLock::new(store));
+        let lease = Duration::seconds(30);
+
+        assert!(first.acquire("snapshots", lease).await.unwrap());
+        assert!(!second.acquire("snapshots", lease).await.unwrap());
+        assert!(second.acquire("exports", lease).await.unwrap());
+
+        // Renewing keeps the lease past its first expiry.
+        clock.advance(Duration::seconds(20));
+        assert!(first.acquire("snapshots", lease).await.unwrap());
+        clock.advance(Duration::seconds(20));
+        assert!(!second.acquire("snapshots", lease).await.unwrap());
+
+        // An expired lease can be taken over.
+        clock.advance(lease);
+        assert!(second.acquire("snapshots", lease).await.unwrap());
+        assert!(!first.acquire("snapshots", lease).await.unwrap());
+
+        // Only the holder can release a lease.
+        first.release("snapshots").await.unwrap();
+        assert!(!first.acquire("snapshots", lease).await.unwrap());
+        second.release("snapshots").await.unwrap();
+        assert!(first.acquire("snapshots", lease).await.unwrap());
+    }
+}
@@ -0,0 +1,172 @@
+/*!
+Masking of personally identifiable values for display.
+*/
+use crate::{
+    patch::Patch,
+    types::{Address, Email, Phone},
+};
+use std::fmt::{self, Debug, Display};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Display wrapper that masks a value, revealing only a configurable number
+/// of leading and trailing graphemes. At least one grapheme is always
+/// masked so a short value is never fully revealed.
+#[derive(Clone, Copy, Debug)]
+pub struct Masked<T> {
+    value: T,
+    head: usize,
+    tail: usize,
+}
+
+impl<T: AsRef<str>> Masked<T> {
+    /// Mask a value revealing the first and last grapheme.
+    pub fn new(value: T) -> Self {
+        Self {
+            value,
+            head: 1,
+            tail: 1,
+        }
+    }
+
+    /// Number of leading graphemes to reveal.
+    pub fn head(self, head: usize) -> Self {
+        Self { head, ..self }
+    }
+
+    /// Number of trailing graphemes to reveal.
+    pub fn tail(self, tail: usize) -> Self {
+        Self { tail, ..self }
+    }
+}
+
+impl<T: AsRef<str>> Display for Masked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let graphemes = self.value.as_ref().graphemes(true).collect::<Vec<_>>();
+        let len = graphemes.len();
+
+        let revealable = len.saturating_sub(1);
+        let tail = self.tail.min(revealable);
+        let head = self.head.min(revealable - tail);
+
+        write!(
+            f,
+            "{}{}{}",
+            graphemes[..head].concat(),
+            "*".repeat(len - head - tail),
+            graphemes[len - tail..].concat()
+        )
+    }
+}
+
+/// Values that can be formatted with personally identifiable information
+/// masked.
+pub trait MaskedValue {
+    fn fmt_masked(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+impl MaskedValue for String {
+    fn fmt_masked(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Masked::new(self))
+    }
+}
+
+impl MaskedValue for Email {
+    fn fmt_masked(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl MaskedValue for Phone {
+    fn fmt_masked(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl MaskedValue for Address {
+    fn fmt_masked(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl<T: MaskedValue> MaskedValue for Option<T> {
+    fn fmt_masked(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Some(value) => f
+                .debug_tuple("Some")
+                .field(&MaskedDebugField(value))
+                .finish(),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+impl<T: MaskedValue> MaskedValue for Patch<T> {
+    fn fmt_masked(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Absent => f.write_str("Absent"),
+            Self::Null => f.write_str("Null"),
+            Self::Value(value) => f
+                .debug_tuple("Value")
+                .field(&MaskedDebugField(value))
+                .finish(),
+        }
+    }
+}
+
+/// Debug adapter for a masked field. Generated by `#[derive(MaskedDebug)]`
+/// for fields annotated with `#[masked]`.
+pub struct MaskedDebugField<'a, T>(pub &'a T);
+
+impl<T: MaskedValue> Debug for MaskedDebugField<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_masked(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Masked;
+    use crate::types::{Email, Gender, User};
+
+    #[test]
+    fn test_mask_ascii() {
+        assert_eq!(Masked::new("Test User").to_string(), "T*******r");
+        assert_eq!(Masked::new("").to_string(), "");
+        assert_eq!(Masked::new("a").to_string(), "*");
+        assert_eq!(Masked::new("ab").to_string(), "*b");
+    }
+
+    #[test]
+    fn test_mask_multibyte() {
+        assert_eq!(Masked::new("Zoë Ångström").to_string(), "Z**********m");
+        assert_eq!(Masked::new("e\u{301}lan").to_string(), "e\u{301}**n");
+    }
+
+    #[test]
+    fn test_mask_reveal_counts() {
+        assert_eq!(
+            Masked::new("5551234567").head(0).tail(4).to_string(),
+            "******4567"
+        );
+        assert_eq!(Masked::new("123").head(2).tail(2).to_string(), "*23");
+    }
+
+    #[test]
+    fn test_masked_debug() {
+        let user = User {
+            id: None,
+            name: "Test User".into(),
+            age: 100,
+            email: Email("test@test.com".into()),
+            gender: Gender::Male,
+            phone: None,
+            address: None,
+            created_at: None,
+            updated_at: None,
+        };
+        let debug = format!("{user:?}");
+        assert!(!debug.contains("Test User"), "{debug}");
+        assert!(!debug.contains("test@test.com"), "{debug}");
+        assert!(debug.contains("T*******r"), "{debug}");
+    }
+}
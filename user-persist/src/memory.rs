@@ -0,0 +1,837 @@
+/*!
+In-memory persistence.
+
+Users are kept in a map guarded by a lock. This backend has no external
+dependencies which makes it suitable for local development and for
+benchmarking the frameworks without database latency.
+
+Keys are ObjectIds by default or ULIDs with [`KeyFormat::Ulid`]. Both sort
+by creation time so iteration follows insertion order. Names are sorted
+exactly and matched once normalized rather than with the collation of
+mongodb.
+*/
+use crate::{
+    clock::{Clock, SystemClock},
+    email::EmailNormalizer,
+    name::{self, NameNormalizer},
+    persistence::{PersistenceError, PersistenceResult, UserPersistence},
+    query::UserQuery,
+    stats::{StatsDate, StatsSnapshot},
+    types::{
+        BulkUpdate, BulkUpdateResult, KeyFormat, SearchSort, UpdateUser, User, UserKey, UserSearch,
+    },
+};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
+
+/// In-memory implementation of [`UserPersistence`].
+#[derive(Debug)]
+pub struct MemoryPersistence {
+    users: RwLock<BTreeMap<UserKey, User>>,
+    stats: RwLock<BTreeMap<StatsDate, StatsSnapshot>>,
+    email_normalizer: EmailNormalizer,
+    name_normalizer: NameNormalizer,
+    key_format: KeyFormat,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for MemoryPersistence {
+    fn default() -> Self {
+        Self::new(EmailNormalizer::default())
+    }
+}
+
+impl MemoryPersistence {
+    pub fn new(email_normalizer: EmailNormalizer) -> Self {
+        Self {
+            users: RwLock::default(),
+            stats: RwLock::default(),
+            email_normalizer,
+            name_normalizer: NameNormalizer::default(),
+            key_format: KeyFormat::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Generate keys for new users in `key_format`.
+    pub fn with_key_format(self, key_format: KeyFormat) -> Self {
+        Self { key_format, ..self }
+    }
+
+    /// Timestamp saved and updated users with `clock`.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Match names in the form produced by `name_normalizer`.
+    pub fn with_name_normalizer(self, name_normalizer: NameNormalizer) -> Self {
+        Self {
+            name_normalizer,
+            ..self
+        }
+    }
+
+    /// Number of users stored.
+    pub fn len(&self) -> usize {
+        self.users.read().unwrap().len()
+    }
+
+    /// Whether no users are stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn matches(&self, user: &User, search: &UserSearch) -> bool {
+        search.email.as_ref().is_none_or(|email| {
+            self.email_normalizer.normalize(email) == self.email_normalizer.normalize(&user.email)
+        }) && search.gender.as_ref().is_none_or(|g| g == &user.gender)
+            && search.name.as_ref().is_none_or(|name| {
+                self.name_normalizer.normalize(name) == self.name_normalizer.normalize(&user.name)
+            })
+            && search
+                .created_after
+                .is_none_or(|time| user.created_at.is_some_and(|created| created > time))
+            && search
+                .updated_before
+                .is_none_or(|time| user.updated_at.is_some_and(|updated| updated < time))
+    }
+}
+
+#[async_trait::async_trait]
+impl UserPersistence for MemoryPersistence {
+    async fn get_user(&self, id: &UserKey) -> PersistenceResult<Option<User>> {
+        Ok(self.users.read().unwrap().get(id).cloned())
+    }
+
+    async fn save_user(&self, user: &User) -> PersistenceResult<User> {
+        let key = self.key_format.generate();
+        let now = self.clock.now();
+        let saved = User {
+            id: Some(key.clone()),
+            name: name::compose(&user.name),
+            created_at: Some(now),
+            updated_at: Some(now),
+            ..user.clone()
+        };
+        self.users.write().unwrap().insert(key, saved.clone());
+        Ok(saved)
+    }
+
+    async fn update_user(&self, user: &UpdateUser) -> PersistenceResult<()> {
+        if let Some(existing) = self.users.write().unwrap().get_mut(&user.id) {
+            existing.name = name::compose(&user.name);
+            existing.age = user.age;
+            existing.email = user.email.clone();
+            user.phone.apply(&mut existing.phone);
+            user.address.apply(&mut existing.address);
+            existing.updated_at = Some(self.clock.now());
+        }
+        Ok(())
+    }
+
+    async fn update_user_if_unchanged(
+        &self,
+        user: &UpdateUser,
+        current: &User,
+    ) -> PersistenceResult<bool> {
+        let mut users = self.users.write().unwrap();
+        match users.get_mut(&user.id) {
+            Some(existing) if existing == current => {
+                existing.name = name::compose(&user.name);
+                existing.age = user.age;
+                existing.email = user.email.clone();
+                user.phone.apply(&mut existing.phone);
+                user.address.apply(&mut existing.address);
+                existing.updated_at = Some(self.clock.now());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_user(&self, id: &UserKey) -> PersistenceResult<()> {
+        self.users.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn search_users(&self, search: &UserSearch, limit: u64) -> PersistenceResult<Vec<User>> {
+        let users = self.users.read().unwrap();
+        let mut matched = users
+            .values()
+            .filter(|user| self.matches(user, search))
+            .collect::<Vec<_>>();
+        // Users are iterated in key order, which the stable sort keeps for
+        // users with the same name or time.
+        match search.sort {
+            SearchSort::Name => matched.sort_by(|a, b| a.name.cmp(&b.name)),
+            SearchSort::Id => (),
+            SearchSort::Created => matched.sort_by_key(|user| user.created_at),
+            SearchSort::Updated => matched.sort_by_key(|user| user.updated_at),
+        }
+        Ok(matched
+            .into_iter()
+            .take(usize::try_from(limit).unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    async fn query_users(&self, query: &UserQuery, limit: u64) -> PersistenceResult<Vec<User>> {
+        let users = self.search_users(&query.search, u64::MAX).await?;
+        Ok(users
+            .into_iter()
+            .filter(|user| query.matches_ranges(user, &self.name_normalizer))
+            .take(usize::try_from(limit).unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    async fn bulk_update(
+        &self,
+        update: &BulkUpdate,
+        limit: u64,
+        dry_run: bool,
+    ) -> PersistenceResult<BulkUpdateResult> {
+        let mut users = self.users.write().unwrap();
+        let mut matched = users
+            .values_mut()
+            .filter(|user| self.matches(user, &update.search))
+            .collect::<Vec<_>>();
+
+        let matched_count = matched.len() as u64;
+        if matched_count > limit {
+            return Err(PersistenceError::BulkLimitExceeded {
+                matched: matched_count,
+                limit,
+            });
+        }
+
+        let mut modified = 0;
+        if !dry_run {
+            let now = self.clock.now();
+            for user in matched.iter_mut() {
+                let before = user.clone();
+                update.set.apply(user);
+                if **user != before {
+                    user.updated_at = Some(now);
+                    modified += 1;
+                }
+            }
+        }
+
+        Ok(BulkUpdateResult {
+            matched: matched_count,
+            modified,
+            dry_run,
+        })
+    }
+
+    async fn count_genders(&self) -> PersistenceResult<Vec<Value>> {
+        let mut counts = BTreeMap::<String, u64>::new();
+        for user in self.users.read().unwrap().values() {
+            *counts.entry(user.gender.to_string()).or_default() += 1;
+        }
+        Ok(counts
+            .into_iter()
+            .map(|(gender, count)| json!({"gender": gender, "count": count}))
+            .collect())
+    }
+
+    async fn save_stats_snapshot(&self, snapshot: &StatsSnapshot) -> PersistenceResult<()> {
+        self.stats
+            .write()
+            .unwrap()
+            .insert(snapshot.date, snapshot.clone());
+        Ok(())
+    }
+
+    async fn stats_history(
+        &self,
+        from: Option<StatsDate>,
+        to: Option<StatsDate>,
+    ) -> PersistenceResult<Vec<StatsSnapshot>> {
+        // A reversed range would panic.
+        if matches!((from, to), (Some(from), Some(to)) if from > to) {
+            return Ok(Vec::new());
+        }
+        let bounds = (
+            from.map_or(Bound::Unbounded, Bound::Included),
+            to.map_or(Bound::Unbounded, Bound::Included),
+        );
+        Ok(self
+            .stats
+            .read()
+            .unwrap()
+            .range(bounds)
+            .map(|(_, snapshot)| snapshot.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryPersistence;
+    use crate::{
+        clock::{Clock, MockClock},
+        name::NameNormalizer,
+        patch::Patch,
+        persistence::{query_capped, search_capped, PersistenceError, UserPersistence},
+        query::UserQuery,
+        stats::{StatsDate, StatsSnapshot},
+        types::{
+            BulkUpdate, BulkUpdateResult, Email, Gender, KeyFormat, PartialUpdateUser, Phone,
+            SearchSort, UpdateUser, User, UserSearch,
+        },
+    };
+    use chrono::{DateTime, Duration};
+    use futures::TryStreamExt;
+    use serde_json::json;
+    use std::{collections::BTreeSet, sync::Arc};
+
+    fn user(name: &str, email: &str, gender: Gender) -> User {
+        User {
+            id: None,
+            name: name.to_owned(),
+            age: 100,
+            email: Email(email.to_owned()),
+            gender,
+            phone: None,
+            address: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_get_update_remove() {
+        let db = MemoryPersistence::default();
+        let saved = db
+            .save_user(&user("Test", "test@test.com", Gender::Male))
+            .await
+            .unwrap();
+        let id = saved.id.clone().unwrap();
+        assert_eq!(db.get_user(&id).await.unwrap(), Some(saved));
+
+        db.update_user(&UpdateUser {
+            id: id.clone(),
+            name: "Updated".to_owned(),
+            email: Email("updated@test.com".to_owned()),
+            age: 120,
+            hash_id: String::new(),
+            phone: Patch::Absent,
+            address: Patch::Absent,
+        })
+        .await
+        .unwrap();
+        let updated = db.get_user(&id).await.unwrap().unwrap();
+        assert_eq!(updated.name, "Updated");
+        assert_eq!(updated.age, 120);
+
+        db.remove_user(&id).await.unwrap();
+        assert!(db.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_racing_conditional_updates() {
+        let db = Arc::new(MemoryPersistence::default());
+        let saved = db
+            .save_user(&user("Test", "test@test.com", Gender::Male))
+            .await
+            .unwrap();
+        let id = saved.id.clone().unwrap();
+        let read = db.get_user(&id).await.unwrap().unwrap();
+        let update = |name: &str| UpdateUser {
+            id: id.clone(),
+            name: name.to_owned(),
+            email: Email("test@test.com".to_owned()),
+            age: 100,
+            hash_id: String::new(),
+            phone: Patch::Absent,
+            address: Patch::Absent,
+        };
+
+        // Both updates were checked against the same read of the user.
+        let (first, second) = (update("First"), update("Second"));
+        let tasks = [&first, &second].map(|update| {
+            let (db, update, read) = (db.clone(), update.clone(), read.clone());
+            tokio::spawn(async move { db.update_user_if_unchanged(&update, &read).await })
+        });
+        let mut updated = Vec::new();
+        for task in tasks {
+            updated.push(task.await.unwrap().unwrap());
+        }
+        assert_eq!(updated.iter().filter(|&&updated| updated).count(), 1);
+
+        let stored = db.get_user(&id).await.unwrap().unwrap();
+        let winner = if updated[0] { &first } else { &second };
+        assert_eq!(stored.name, winner.name);
+        assert!(!db
+            .update_user_if_unchanged(&update("Third"), &read)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_update_patches() {
+        let db = MemoryPersistence::default();
+        let saved = db
+            .save_user(&User {
+                phone: Some(Phone("555-0100".to_owned())),
+                ..user("Test", "test@test.com", Gender::Male)
+            })
+            .await
+            .unwrap();
+        let id = saved.id.clone().unwrap();
+        let update = |phone| UpdateUser {
+            id: id.clone(),
+            name: "Test".to_owned(),
+            email: Email("test@test.com".to_owned()),
+            age: 100,
+            hash_id: String::new(),
+            phone,
+            address: Patch::Absent,
+        };
+
+        db.update_user(&update(Patch::Absent)).await.unwrap();
+        let updated = db.get_user(&id).await.unwrap().unwrap();
+        assert_eq!(updated.phone, Some(Phone("555-0100".to_owned())));
+
+        db.update_user(&update(Patch::Null)).await.unwrap();
+        let updated = db.get_user(&id).await.unwrap().unwrap();
+        assert_eq!(updated.phone, None);
+    }
+
+    #[tokio::test]
+    async fn test_search_and_count() {
+        let db = MemoryPersistence::default();
+        for u in [
+            user("A", "A@Test.com", Gender::Male),
+            user("B", "b@test.com", Gender::Female),
+            user("C", "c@test.com", Gender::Female),
+        ] {
+            db.save_user(&u).await.unwrap();
+        }
+
+        let found = db
+            .search_users(
+                &UserSearch {
+                    email: Some(Email("a@test.com".to_owned())),
+                    gender: None,
+                    name: None,
+                    ..Default::default()
+                },
+                10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "A");
+
+        let by_email = db
+            .get_user_by_email(&Email("a@TEST.com".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(by_email.map(|u| u.name), Some("A".to_owned()));
+        assert_eq!(
+            db.get_user_by_email(&Email("d@test.com".to_owned()))
+                .await
+                .unwrap(),
+            None
+        );
+
+        let found = db
+            .search_users(
+                &UserSearch {
+                    email: None,
+                    gender: Some(Gender::Female),
+                    name: None,
+                    ..Default::default()
+                },
+                10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 2);
+
+        let streamed = db
+            .search_users_stream(&UserSearch {
+                email: None,
+                gender: Some(Gender::Female),
+                name: None,
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(streamed, found);
+
+        let female = UserSearch {
+            email: None,
+            gender: Some(Gender::Female),
+            name: None,
+            ..Default::default()
+        };
+        let capped = search_capped(&db, &female, 1).await.unwrap();
+        assert_eq!(capped.users, found[..1]);
+        assert!(capped.truncated);
+        let capped = search_capped(&db, &female, 2).await.unwrap();
+        assert_eq!(capped.users, found);
+        assert!(!capped.truncated);
+
+        let page = db.search_page(&female, 1, 5).await.unwrap();
+        assert_eq!(page.items, found[1..]);
+        assert_eq!((page.total, page.offset, page.limit), (2, 1, 5));
+        assert!(!page.estimated);
+
+        assert_eq!(
+            db.count_genders().await.unwrap(),
+            vec![
+                json!({"gender": "Female", "count": 2}),
+                json!({"gender": "Male", "count": 1})
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_sort() {
+        let db = MemoryPersistence::default();
+        for name in ["B", "A", "B", "C"] {
+            db.save_user(&user(name, "test@test.com", Gender::Male))
+                .await
+                .unwrap();
+        }
+        let keys = |users: Vec<User>| users.into_iter().map(|u| u.id.unwrap()).collect::<Vec<_>>();
+        let by_id = db
+            .search_users(
+                &UserSearch {
+                    sort: SearchSort::Id,
+                    ..Default::default()
+                },
+                10,
+            )
+            .await
+            .unwrap();
+        let names = by_id.iter().map(|u| u.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["B", "A", "B", "C"]);
+
+        let by_name = db.search_users(&UserSearch::default(), 10).await.unwrap();
+        let ids = keys(by_id);
+        assert_eq!(
+            keys(by_name),
+            [&ids[1], &ids[0], &ids[2], &ids[3]].map(Clone::clone)
+        );
+
+        let page = db.search_page(&UserSearch::default(), 1, 2).await.unwrap();
+        assert_eq!(keys(page.items), [&ids[0], &ids[2]].map(Clone::clone));
+    }
+
+    #[tokio::test]
+    async fn test_sample_users() {
+        let db = MemoryPersistence::default();
+        for i in 0..20 {
+            db.save_user(&user(&format!("User {i}"), "test@test.com", Gender::Male))
+                .await
+                .unwrap();
+        }
+
+        let sample = db.sample_users(5).await.unwrap();
+        assert_eq!(sample.len(), 5);
+        let keys = sample
+            .iter()
+            .map(|u| u.id.clone().unwrap())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(keys.len(), 5);
+        for user in sample {
+            let id = user.id.clone().unwrap();
+            assert_eq!(db.get_user(&id).await.unwrap(), Some(user));
+        }
+
+        assert_eq!(db.sample_users(50).await.unwrap().len(), 20);
+        assert!(db.sample_users(0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_users() {
+        let db = MemoryPersistence::default();
+        for (name, age) in [
+            ("Anna Smith", 30),
+            ("Bob Smithers", 125),
+            ("Carl Jones", 130),
+        ] {
+            let u = User {
+                age,
+                ..user(name, "x@test.com", Gender::Male)
+            };
+            db.save_user(&u).await.unwrap();
+        }
+
+        let names = |users: Vec<User>| users.into_iter().map(|u| u.name).collect::<Vec<_>>();
+        let query = "gender:Male age>120 name~smith"
+            .parse::<UserQuery>()
+            .unwrap();
+        assert_eq!(
+            names(db.query_users(&query, 10).await.unwrap()),
+            vec!["Bob Smithers"]
+        );
+
+        let query = "age>=30".parse::<UserQuery>().unwrap();
+        assert_eq!(db.query_users(&query, 10).await.unwrap().len(), 3);
+        let capped = query_capped(&db, &query, 2).await.unwrap();
+        assert_eq!(names(capped.users), vec!["Anna Smith", "Bob Smithers"]);
+        assert!(capped.truncated);
+    }
+
+    #[tokio::test]
+    async fn test_name_normalization() {
+        let plain = MemoryPersistence::default();
+        let transliterating =
+            MemoryPersistence::default().with_name_normalizer(NameNormalizer::new(true));
+        for db in [&plain, &transliterating] {
+            let saved = db
+                .save_user(&user("Mu\u{308}ller", "x@test.com", Gender::Male))
+                .await
+                .unwrap();
+            assert_eq!(saved.name, "M\u{fc}ller");
+        }
+
+        let search = |name: &str| UserSearch {
+            name: Some(name.to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            plain
+                .search_users(&search("MÜLLER"), 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(plain
+            .search_users(&search("Mueller"), 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            transliterating
+                .search_users(&search("Mueller"), 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let query = "name~muel".parse::<UserQuery>().unwrap();
+        assert!(plain.query_users(&query, 10).await.unwrap().is_empty());
+        assert_eq!(
+            transliterating.query_users(&query, 10).await.unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timestamps() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(MockClock::new(start));
+        let db = MemoryPersistence::default().with_clock(clock.clone());
+        let first = db
+            .save_user(&user("B", "b@test.com", Gender::Male))
+            .await
+            .unwrap();
+        assert_eq!(first.created_at, Some(start));
+        assert_eq!(first.updated_at, Some(start));
+
+        clock.advance(Duration::minutes(1));
+        let second = db
+            .save_user(&user("A", "a@test.com", Gender::Male))
+            .await
+            .unwrap();
+        clock.advance(Duration::minutes(1));
+        db.update_user(&UpdateUser {
+            id: first.id.clone().unwrap(),
+            name: "B".to_owned(),
+            email: first.email.clone(),
+            age: first.age,
+            hash_id: String::new(),
+            phone: Patch::Absent,
+            address: Patch::Absent,
+        })
+        .await
+        .unwrap();
+        let updated = db
+            .get_user(first.id.as_ref().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.created_at, Some(start));
+        assert_eq!(updated.updated_at, Some(clock.now()));
+
+        let names = |search: UserSearch| {
+            let users = futures::executor::block_on(db.search_users(&search, 10)).unwrap();
+            users.into_iter().map(|u| u.name).collect::<Vec<_>>()
+        };
+        let sorted = UserSearch {
+            sort: SearchSort::Updated,
+            ..Default::default()
+        };
+        assert_eq!(names(sorted), ["A", "B"]);
+        let created = UserSearch {
+            created_after: Some(start),
+            ..Default::default()
+        };
+        assert_eq!(names(created), ["A"]);
+        let updated = UserSearch {
+            updated_before: second.updated_at.map(|t| t + Duration::seconds(1)),
+            sort: SearchSort::Created,
+            ..Default::default()
+        };
+        assert_eq!(names(updated), ["A"]);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_update() {
+        let db = MemoryPersistence::default();
+        for u in [
+            user("A", "a@test.com", Gender::Male),
+            user("B", "b@test.com", Gender::Female),
+            user("C", "c@test.com", Gender::Female),
+        ] {
+            db.save_user(&u).await.unwrap();
+        }
+
+        let update = BulkUpdate {
+            search: UserSearch {
+                email: None,
+                gender: Some(Gender::Female),
+                name: None,
+                ..Default::default()
+            },
+            set: PartialUpdateUser {
+                age: Some(120),
+                phone: Patch::Value(Phone("555-0100".to_owned())),
+                ..PartialUpdateUser::default()
+            },
+        };
+
+        assert!(matches!(
+            db.bulk_update(&update, 1, false).await,
+            Err(PersistenceError::BulkLimitExceeded {
+                matched: 2,
+                limit: 1
+            })
+        ));
+
+        assert_eq!(
+            db.bulk_update(&update, 2, true).await.unwrap(),
+            BulkUpdateResult {
+                matched: 2,
+                modified: 0,
+                dry_run: true
+            }
+        );
+        assert!(db
+            .search_users(&update.search, 10)
+            .await
+            .unwrap()
+            .iter()
+            .all(|u| u.age == 100));
+
+        assert_eq!(
+            db.bulk_update(&update, 2, false).await.unwrap(),
+            BulkUpdateResult {
+                matched: 2,
+                modified: 2,
+                dry_run: false
+            }
+        );
+        let updated = db.search_users(&update.search, 10).await.unwrap();
+        assert!(updated
+            .iter()
+            .all(|u| u.age == 120 && u.phone == Some(Phone("555-0100".to_owned()))));
+
+        // Users already updated are matched but not modified.
+        assert_eq!(db.bulk_update(&update, 2, false).await.unwrap().modified, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stats_history() {
+        let db = MemoryPersistence::default();
+        let snapshot = |date: &str, count: u64| StatsSnapshot {
+            date: date.parse().unwrap(),
+            counts: vec![json!({"gender": "Male", "count": count})],
+        };
+        for s in [
+            snapshot("2024-03-02", 1),
+            snapshot("2024-03-01", 1),
+            snapshot("2024-03-03", 1),
+            // Replaces the earlier snapshot of the same day.
+            snapshot("2024-03-02", 2),
+        ] {
+            db.save_stats_snapshot(&s).await.unwrap();
+        }
+
+        let date = |d: &str| Some(d.parse::<StatsDate>().unwrap());
+
+        assert_eq!(
+            db.stats_history(None, None).await.unwrap(),
+            vec![
+                snapshot("2024-03-01", 1),
+                snapshot("2024-03-02", 2),
+                snapshot("2024-03-03", 1)
+            ]
+        );
+        assert_eq!(
+            db.stats_history(date("2024-03-02"), date("2024-03-02"))
+                .await
+                .unwrap(),
+            vec![snapshot("2024-03-02", 2)]
+        );
+        assert_eq!(
+            db.stats_history(date("2024-03-02"), None)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(db
+            .stats_history(date("2024-03-03"), date("2024-03-01"))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ulid_keys() {
+        let db = MemoryPersistence::default().with_key_format(KeyFormat::Ulid);
+        let mut ids = Vec::new();
+        for n in 0..10 {
+            let saved = db
+                .save_user(&user(&n.to_string(), "test@test.com", Gender::Male))
+                .await
+                .unwrap();
+            ids.push(saved.id.unwrap());
+        }
+        assert!(ids.iter().all(|id| id.format() == Some(KeyFormat::Ulid)));
+
+        // Users are listed in insertion order.
+        let names = db
+            .search_users(
+                &UserSearch {
+                    email: None,
+                    gender: None,
+                    name: None,
+                    ..Default::default()
+                },
+                10,
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, (0..10).map(|n| n.to_string()).collect::<Vec<_>>());
+    }
+}
@@ -2,65 +2,445 @@
 This module provides data access to a a mongodb user collection.
 */
 use crate::{
+    audit::{AUDIT_COLLECTION_NAME, AUDIT_INDEX_NAMES},
+    bson_json::document_to_json,
+    clock::{Clock, SystemClock},
+    email::EmailNormalizer,
+    filter::{bson_time, UserFilter},
     init_mongo_client,
-    persistence::{PersistenceResult, UserPersistence},
-    types::{Email, Gender, UpdateUser, User, UserKey, UserSearch},
-    MongoArgs, PERSISTENCE_TARGET,
+    integrity::{check_document, IntegrityFinding, IntegrityStream},
+    name::{self, NameNormalizer},
+    patch::Patch,
+    persistence::{
+        PersistenceError, PersistenceResult, UserPersistence, UserStream, DUPLICATE_KEY,
+    },
+    query::UserQuery,
+    sanitize,
+    schema::{missing_timestamps, SchemaRegistry},
+    stats::{AgeBucket, StatsDate, StatsSnapshot},
+    types::{
+        Address, BulkUpdate, BulkUpdateResult, Email, EmailValidation, Gender, InvalidKeyError,
+        Phone, SearchPage, SearchSort, UpdateUser, User, UserKey, UserSearch,
+    },
+    MaskedDebug, MongoArgs, PERSISTENCE_TARGET,
 };
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
 use futures::{
-    stream::{Stream, TryStreamExt},
+    stream::{self, Stream, TryStreamExt},
     StreamExt,
 };
 use mongodb::{
-    bson::{doc, oid::ObjectId, Bson, Document},
-    error::Result as MongoResult,
-    options::AggregateOptions,
-    results::InsertOneResult,
-    Collection, Database,
+    bson::{self, doc, from_document, oid::ObjectId, Bson, Document},
+    error::{ErrorKind, Result as MongoResult, WriteFailure},
+    options::{
+        AggregateOptions, Collation, CollationStrength, CountOptions,
+        EstimatedDocumentCountOptions, FindOneOptions, FindOptions, IndexOptions, ReplaceOptions,
+        UpdateOptions,
+    },
+    results::{InsertOneResult, UpdateResult},
+    Collection, Database, IndexModel,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::ops::Deref;
-use tracing::{debug, instrument};
+use std::{
+    fmt::{self, Display},
+    future::Future,
+    num::NonZeroU32,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, instrument, warn};
 
 const COLLECTION_NAME: &str = "users";
+const STATS_COLLECTION_NAME: &str = "stats_history";
+/// Name mongodb gives the normalized email index.
+const EMAIL_INDEX_NAME: &str = "email_normalized_1";
+/// Users repaired in each transaction of an integrity check.
+pub const INTEGRITY_REPAIR_BATCH: usize = 500;
+
+/// How strictly names are compared when searching.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum NameMatching {
+    /// Ignore case and accents so `alvarez` matches `Álvarez`.
+    Base,
+    /// Ignore case only.
+    #[default]
+    CaseInsensitive,
+    /// Compare names exactly.
+    Exact,
+}
+
+impl NameMatching {
+    /// Collation comparing names in `locale` with this strictness.
+    pub fn collation(self, locale: &str) -> Collation {
+        let strength = match self {
+            Self::Base => CollationStrength::Primary,
+            Self::CaseInsensitive => CollationStrength::Secondary,
+            Self::Exact => CollationStrength::Tertiary,
+        };
+        Collation::builder()
+            .locale(locale.to_owned())
+            .strength(strength)
+            .build()
+    }
+}
+
+/// Limits applied to aggregations on the user collection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AggregationSettings {
+    /// Let large groupings and sorts spill to disk rather than fail.
+    pub allow_disk_use: bool,
+    /// Time the server may spend running an aggregation.
+    pub max_time: Option<Duration>,
+}
+
+impl Default for AggregationSettings {
+    fn default() -> Self {
+        Self {
+            allow_disk_use: true,
+            max_time: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl AggregationSettings {
+    /// Driver options applying these settings.
+    pub fn options(&self) -> AggregateOptions {
+        AggregateOptions::builder()
+            .allow_disk_use(self.allow_disk_use)
+            .max_time(self.max_time)
+            .build()
+    }
+}
+
+/// Time limits of database operations. Reads are also given to the server
+/// as `maxTimeMS` so it stops working on queries the client gave up on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OperationTimeouts {
+    /// Lookups, searches and counts.
+    pub read: Option<Duration>,
+    /// Inserts, updates and deletes.
+    pub write: Option<Duration>,
+}
+
+impl Default for OperationTimeouts {
+    fn default() -> Self {
+        Self {
+            read: Some(Duration::from_secs(5)),
+            write: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+/// Run a database operation, failing with [`PersistenceError::Timeout`]
+/// when it takes longer than `limit`. The outcome is logged with the
+/// operation, collection and duration as fields.
+pub(crate) async fn timed<T>(
+    operation: &'static str,
+    collection: &'static str,
+    limit: Option<Duration>,
+    future: impl Future<Output = MongoResult<T>>,
+) -> PersistenceResult<T> {
+    let start = Instant::now();
+    let result = match limit {
+        Some(limit) => match tokio::time::timeout(limit, future).await {
+            Ok(result) => result.map_err(PersistenceError::from),
+            Err(_) => Err(PersistenceError::Timeout),
+        },
+        None => future.await.map_err(PersistenceError::from),
+    };
+    let duration_ms = u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX);
+
+    match &result {
+        Ok(_) => debug!(
+          target: PERSISTENCE_TARGET,
+          operation,
+          collection,
+          duration_ms,
+          "mongo operation completed"
+        ),
+        Err(e) => warn!(
+          target: PERSISTENCE_TARGET,
+          operation,
+          collection,
+          duration_ms,
+          timed_out = matches!(e, PersistenceError::Timeout),
+          error_code = ?error_code(e),
+          "mongo operation failed"
+        ),
+    }
+    result
+}
+
+/// Server error code of a failed operation. Error messages are kept out of
+/// logs as they can quote the offending values.
+fn error_code(err: &PersistenceError) -> Option<i32> {
+    match err {
+        PersistenceError::MongoError(e) => match e.kind.as_ref() {
+            ErrorKind::Command(c) => Some(c.code),
+            ErrorKind::Write(WriteFailure::WriteError(w)) => Some(w.code),
+            ErrorKind::Write(WriteFailure::WriteConcernError(w)) => Some(w.code),
+            _ => None,
+        },
+        PersistenceError::DuplicateKey => Some(DUPLICATE_KEY),
+        _ => None,
+    }
+}
+
+/// Names of the indexes of `collection`, which has none when it doesn't
+/// exist yet.
+async fn index_names<T>(collection: &Collection<T>) -> PersistenceResult<Vec<String>> {
+    // Listing indexes of a collection which doesn't exist yet fails with
+    // NamespaceNotFound.
+    match collection.list_index_names().await {
+        Ok(indexes) => Ok(indexes),
+        Err(e) if matches!(*e.kind, ErrorKind::Command(ref c) if c.code == 26) => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
 
 /// An implementation of UserPersistence for MongoDB.
 #[derive(Debug, Clone)]
-pub struct MongoPersistence(Database);
+pub struct MongoPersistence {
+    db: Database,
+    email_normalizer: EmailNormalizer,
+    /// Mode emails found by integrity checks are validated in.
+    email_validation: EmailValidation,
+    name_normalizer: NameNormalizer,
+    /// Collation searches match and sort names with.
+    collation: Collation,
+    /// Limits applied to aggregations.
+    aggregation: AggregationSettings,
+    timeouts: OperationTimeouts,
+    /// Upgraders of stored users.
+    schema: SchemaRegistry,
+    /// Write users upgraded on lookup back.
+    schema_write_back: bool,
+    /// Clock users are timestamped with when saved and updated.
+    clock: Arc<dyn Clock>,
+}
 
 impl Deref for MongoPersistence {
     type Target = Database;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.db
+    }
+}
+
+/// Schema change not yet applied to the user collection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PendingMigration {
+    /// The unique normalized email index hasn't been created.
+    EmailIndex,
+    /// Users saved before email normalization was introduced.
+    EmailNormalization { users: u64 },
+    /// Users saved before their creation and update times were recorded.
+    Timestamps { users: u64 },
+    /// The indexes of audit log searches haven't been created.
+    AuditIndexes,
+}
+
+impl Display for PendingMigration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmailIndex => write!(f, "missing {EMAIL_INDEX_NAME} index"),
+            Self::EmailNormalization { users } => {
+                write!(f, "{users} users without a normalized email")
+            }
+            Self::Timestamps { users } => {
+                write!(f, "{users} users without creation and update times")
+            }
+            Self::AuditIndexes => write!(f, "missing {AUDIT_COLLECTION_NAME} indexes"),
+        }
     }
 }
 
 impl MongoPersistence {
     /// Creates a new MongoPersistence API.
     pub async fn new(options: MongoArgs) -> PersistenceResult<Self> {
+        let persistence = Self::connect(options).await?;
+        persistence.ensure_indexes().await?;
+        Ok(persistence)
+    }
+
+    /// Connect without changing the database.
+    pub async fn connect(options: MongoArgs) -> PersistenceResult<Self> {
+        let email_normalizer = EmailNormalizer::new(options.strip_email_tags);
+        let email_validation = options.email_validation();
+        let name_normalizer = NameNormalizer::new(options.transliterate_names);
+        let collation = options.search_collation();
+        let aggregation = options.aggregation_settings();
+        let timeouts = options.operation_timeouts();
+        let schema_write_back = options.schema_write_back();
         let db = init_mongo_client(options).await?;
-        Ok(Self(db))
+        Ok(Self {
+            db,
+            email_normalizer,
+            email_validation,
+            name_normalizer,
+            collation,
+            aggregation,
+            timeouts,
+            schema: SchemaRegistry::default(),
+            schema_write_back,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Use an existing database connection, creating the indexes. Names
+    /// are compared with the default collation without transliteration and
+    /// operations have the default limits. Upgraded users aren't written
+    /// back.
+    pub async fn from_database(db: Database, strip_email_tags: bool) -> PersistenceResult<Self> {
+        let persistence = Self {
+            db,
+            email_normalizer: EmailNormalizer::new(strip_email_tags),
+            email_validation: EmailValidation::default(),
+            name_normalizer: NameNormalizer::default(),
+            collation: NameMatching::default().collation("en"),
+            aggregation: AggregationSettings::default(),
+            timeouts: OperationTimeouts::default(),
+            schema: SchemaRegistry::default(),
+            schema_write_back: false,
+            clock: Arc::new(SystemClock),
+        };
+        persistence.ensure_indexes().await?;
+        Ok(persistence)
+    }
+
+    /// Timestamp saved and updated users with `clock`.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Schema changes the database is missing.
+    pub async fn pending_migrations(&self) -> PersistenceResult<Vec<PendingMigration>> {
+        let mut pending = Vec::new();
+        let indexes = index_names(&self.user_collection()).await?;
+        if !indexes.iter().any(|name| name == EMAIL_INDEX_NAME) {
+            pending.push(PendingMigration::EmailIndex);
+        }
+        let users = self
+            .user_collection()
+            .count_documents(doc! {"email_normalized": {"$exists": false}}, None)
+            .await?;
+        if users > 0 {
+            pending.push(PendingMigration::EmailNormalization { users });
+        }
+        let users = self
+            .user_collection()
+            .count_documents(doc! {"created_at": {"$exists": false}}, None)
+            .await?;
+        if users > 0 {
+            pending.push(PendingMigration::Timestamps { users });
+        }
+        let audit = index_names(&self.collection::<Document>(AUDIT_COLLECTION_NAME)).await?;
+        if !AUDIT_INDEX_NAMES
+            .iter()
+            .all(|index| audit.iter().any(|name| name == index))
+        {
+            pending.push(PendingMigration::AuditIndexes);
+        }
+        Ok(pending)
+    }
+
+    /// Create the unique index on the normalized email. Records saved before
+    /// normalization was introduced have no normalized email and are excluded.
+    async fn ensure_indexes(&self) -> PersistenceResult<()> {
+        let index = IndexModel::builder()
+            .keys(doc! {"email_normalized": 1})
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .partial_filter_expression(doc! {"email_normalized": {"$exists": true}})
+                    .build(),
+            )
+            .build();
+        self.user_collection().create_index(index, None).await?;
+
+        // One snapshot per day.
+        let index = IndexModel::builder()
+            .keys(doc! {"date": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.stats_collection().create_index(index, None).await?;
+        Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl UserPersistence for MongoPersistence {
+    #[instrument(
+        skip_all,
+        level = "debug",
+        target = "persistence",
+        fields(user_id = %id.masked())
+    )]
     async fn get_user(&self, id: &UserKey) -> PersistenceResult<Option<User>> {
-        let user = self
-            .user_collection()
-            .find_one(doc! {"_id": ObjectId::try_from(id)?}, None)
-            .await?
-            .map(User::from);
+        let filter = doc! {"_id": ObjectId::try_from(id)?};
+        let options = FindOneOptions::builder()
+            .max_time(self.timeouts.read)
+            .build();
+        let document = timed(
+            "find_one",
+            COLLECTION_NAME,
+            self.timeouts.read,
+            self.document_collection().find_one(filter, options),
+        )
+        .await?;
+
+        match document {
+            Some(document) => Ok(Some(self.look_up_user(document).await?.into())),
+            None => Ok(None),
+        }
+    }
 
-        Ok(user)
+    #[instrument(
+        skip_all,
+        level = "debug",
+        target = "persistence",
+        fields(email = %email)
+    )]
+    async fn get_user_by_email(&self, email: &Email) -> PersistenceResult<Option<User>> {
+        let filter = doc! {"email_normalized": self.email_normalizer.normalize(email)};
+        let options = FindOneOptions::builder()
+            .max_time(self.timeouts.read)
+            .build();
+        let document = timed(
+            "find_one",
+            COLLECTION_NAME,
+            self.timeouts.read,
+            self.document_collection().find_one(filter, options),
+        )
+        .await?;
+
+        match document {
+            Some(document) => Ok(Some(self.look_up_user(document).await?.into())),
+            None => Ok(None),
+        }
     }
 
     async fn save_user(&self, user: &User) -> PersistenceResult<User> {
-        let mongo_user = MongoUser::from(user.to_owned());
+        let now = bson_time(self.clock.now());
+        let mongo_user = MongoUser {
+            created_at: Some(now),
+            updated_at: Some(now),
+            name: name::compose(&user.name),
+            name_normalized: Some(self.name_normalizer.normalize(&user.name)),
+            email_normalized: Some(self.email_normalizer.normalize(&user.email)),
+            ..MongoUser::from(user.to_owned())
+        };
 
-        let InsertOneResult { inserted_id, .. } =
-            self.user_collection().insert_one(mongo_user, None).await?;
+        let InsertOneResult { inserted_id, .. } = timed(
+            "insert_one",
+            COLLECTION_NAME,
+            self.timeouts.write,
+            self.user_collection().insert_one(&mongo_user, None),
+        )
+        .await?;
 
         let key = match inserted_id {
             Bson::ObjectId(k) => Some(k),
@@ -69,36 +449,98 @@ impl UserPersistence for MongoPersistence {
 
         Ok(User {
             id: key.map(UserKey::from),
-            ..user.clone()
+            ..User::from(mongo_user)
         })
     }
 
+    #[instrument(
+        skip_all,
+        level = "debug",
+        target = "persistence",
+        fields(user_id = %user.id.masked())
+    )]
     async fn update_user(&self, user: &UpdateUser) -> PersistenceResult<()> {
         let query = doc! {"_id": ObjectId::try_from(&user.id)?};
-        let update_fields = doc! {"name": &user.name, "age": &user.age, "email": &user.email};
-        let update = doc! {"$set": update_fields};
+        let updated = timed(
+            "update_one",
+            COLLECTION_NAME,
+            self.timeouts.write,
+            self.user_collection()
+                .update_one(query, self.user_update(user), None),
+        )
+        .await?;
 
-        let updated = self
-            .user_collection()
-            .update_one(query, update, None)
-            .await?;
-
-        debug!(target: PERSISTENCE_TARGET, "update result: {updated:?}",);
+        debug!(
+          target: PERSISTENCE_TARGET,
+          matched = updated.matched_count,
+          modified = updated.modified_count,
+          "user updated"
+        );
 
         Ok(())
     }
 
+    #[instrument(
+        skip_all,
+        level = "debug",
+        target = "persistence",
+        fields(user_id = %user.id.masked())
+    )]
+    async fn update_user_if_unchanged(
+        &self,
+        user: &UpdateUser,
+        current: &User,
+    ) -> PersistenceResult<bool> {
+        // Every update sets `updated_at`, so a user still holding the time it
+        // was read with hasn't been written since. Users stored before the
+        // times were recorded are read with a backfilled time unless it was
+        // written back, and are unchanged while they still have none.
+        let query = doc! {
+            "_id": ObjectId::try_from(&user.id)?,
+            "$or": [
+                {"updated_at": current.updated_at.map(bson_time)},
+                {"updated_at": {"$exists": false}},
+            ],
+        };
+        let updated = timed(
+            "update_one",
+            COLLECTION_NAME,
+            self.timeouts.write,
+            self.user_collection()
+                .update_one(query, self.user_update(user), None),
+        )
+        .await?;
+
+        debug!(
+          target: PERSISTENCE_TARGET,
+          matched = updated.matched_count,
+          modified = updated.modified_count,
+          "user conditionally updated"
+        );
+
+        Ok(updated.matched_count > 0)
+    }
+
+    #[instrument(
+        skip_all,
+        level = "debug",
+        target = "persistence",
+        fields(user_id = %key.masked())
+    )]
     async fn remove_user(&self, key: &UserKey) -> PersistenceResult<()> {
-        let result = self
-            .user_collection()
-            .delete_one(
-                doc! {
-                  "_id": ObjectId::try_from(key)?
-                },
-                None,
-            )
-            .await?;
-        debug!(target: PERSISTENCE_TARGET, "delete result: {result:?}");
+        let filter = doc! {"_id": ObjectId::try_from(key)?};
+        let result = timed(
+            "delete_one",
+            COLLECTION_NAME,
+            self.timeouts.write,
+            self.user_collection().delete_one(filter, None),
+        )
+        .await?;
+        debug!(
+          target: PERSISTENCE_TARGET,
+          deleted = result.deleted_count,
+          "user removed"
+        );
         Ok(())
     }
 
@@ -108,79 +550,720 @@ impl UserPersistence for MongoPersistence {
         target = "persistence",
         name = "search-span"
     )]
-    async fn search_users(&self, user_search: &UserSearch) -> PersistenceResult<Vec<User>> {
-        let search = doc! { "email": &user_search.email, "gender": &user_search.gender,
-            "name": &user_search.name
-        };
+    async fn search_users(
+        &self,
+        user_search: &UserSearch,
+        limit: u64,
+    ) -> PersistenceResult<Vec<User>> {
+        let filter = self.search_filter(user_search)?;
+        self.find_users(filter, user_search.sort, limit).await
+    }
 
-        let filtered_null = search
-            .into_iter()
-            .filter(|(_, value)| value != &Bson::Null)
-            .collect::<Document>();
+    #[instrument(skip_all, level = "debug", target = "persistence", name = "query-span")]
+    async fn query_users(&self, query: &UserQuery, limit: u64) -> PersistenceResult<Vec<User>> {
+        let filter =
+            UserFilter::from(query).to_document(&self.email_normalizer, &self.name_normalizer)?;
+        self.find_users(filter, query.search.sort, limit).await
+    }
+
+    async fn search_users_stream(&self, user_search: &UserSearch) -> PersistenceResult<UserStream> {
+        let filter = self.search_filter(user_search)?;
 
         debug!(
           target: PERSISTENCE_TARGET,
-          "mongo search query: {filtered_null}",
+          filter_fields = ?filter.keys().collect::<Vec<_>>(),
+          "streaming users"
         );
 
-        let result = self
-            .user_collection()
-            .find(filtered_null, None)
-            .await?
-            .try_collect::<Vec<MongoUser>>()
-            .await?
-            .into_iter()
-            .map(User::from)
-            .collect::<Vec<_>>();
+        // Only opening the cursor is timed as the stream is read at the pace
+        // of the client.
+        let options = FindOptions::builder()
+            .collation(self.collation.clone())
+            .sort(sort_document(user_search.sort))
+            .build();
 
-        Ok(result)
+        let schema = self.schema;
+        Ok(timed(
+            "find",
+            COLLECTION_NAME,
+            self.timeouts.read,
+            self.document_collection().find(filter, options),
+        )
+        .await?
+        .map(move |r| {
+            r.and_then(|document| read_user(&schema, document))
+                .map(User::from)
+                .map_err(PersistenceError::from)
+        })
+        .boxed())
     }
 
-    async fn count_genders(&self) -> PersistenceResult<Vec<Value>> {
-        let pipeline = vec![doc! {
-          "$group": {"_id": "$gender", "count": {"$count": {}}}
-        }];
+    async fn search_page(
+        &self,
+        user_search: &UserSearch,
+        offset: u64,
+        limit: u64,
+    ) -> PersistenceResult<SearchPage<User>> {
+        let filter = self.search_filter(user_search)?;
 
-        let docs = self
-            .collection::<Document>(COLLECTION_NAME)
-            .aggregate(
-                pipeline.into_iter(),
-                AggregateOptions::builder().allow_disk_use(true).build(),
+        // Counting every user scans the whole collection while its metadata
+        // holds an estimate.
+        let (total, estimated) = if filter.is_empty() {
+            let options = EstimatedDocumentCountOptions::builder()
+                .max_time(self.timeouts.read)
+                .build();
+            let total = timed(
+                "estimated_document_count",
+                COLLECTION_NAME,
+                self.timeouts.read,
+                self.user_collection().estimated_document_count(options),
             )
+            .await?;
+            (total, true)
+        } else {
+            let total = timed(
+                "count_documents",
+                COLLECTION_NAME,
+                self.timeouts.read,
+                self.user_collection()
+                    .count_documents(filter.clone(), self.count_options()),
+            )
+            .await?;
+            (total, false)
+        };
+
+        // Mongodb treats a limit of 0 as no limit.
+        let items = if limit == 0 {
+            Vec::new()
+        } else {
+            timed("find", COLLECTION_NAME, self.timeouts.read, async {
+                self.document_collection()
+                    .find(filter, self.search_options(user_search.sort, offset, limit))
+                    .await?
+                    .map(|r| r.and_then(|document| read_user(&self.schema, document)))
+                    .map_ok(User::from)
+                    .try_collect()
+                    .await
+            })
             .await?
-            .try_collect::<Vec<_>>()
-            .await?
-            .into_iter()
-            .map(Bson::from)
-            .map(Value::from)
-            .collect();
+        };
+
+        Ok(SearchPage {
+            items,
+            total,
+            estimated,
+            offset,
+            limit,
+            next_cursor: None,
+        })
+    }
+
+    async fn search_after(
+        &self,
+        user_search: &UserSearch,
+        after: &UserKey,
+        limit: u64,
+    ) -> PersistenceResult<Option<Vec<User>>> {
+        let filter = self.search_filter(user_search)?;
+        let after = ObjectId::try_from(after)?;
+
+        // The last user must still match for the users after it to be the
+        // rest of the same search.
+        let mut last_filter = filter.clone();
+        last_filter.insert("_id", after);
+        let options = FindOneOptions::builder()
+            .collation(self.collation.clone())
+            .max_time(self.timeouts.read)
+            .build();
+        let last = timed(
+            "find_one",
+            COLLECTION_NAME,
+            self.timeouts.read,
+            self.document_collection().find_one(last_filter, options),
+        )
+        .await?;
+        let Some(last) = last else {
+            return Ok(None);
+        };
+
+        let following = match sort_field(user_search.sort) {
+            None => doc! {"_id": {"$gt": after}},
+            Some(field) => {
+                let value = last.get(field).cloned().unwrap_or(Bson::Null);
+                doc! {"$or": [
+                    {field: {"$gt": value.clone()}},
+                    {field: value, "_id": {"$gt": after}},
+                ]}
+            }
+        };
+        let filter = doc! {"$and": [filter, following]};
+        self.find_users(filter, user_search.sort, limit)
+            .await
+            .map(Some)
+    }
+
+    async fn bulk_update(
+        &self,
+        update: &BulkUpdate,
+        limit: u64,
+        dry_run: bool,
+    ) -> PersistenceResult<BulkUpdateResult> {
+        let filter = self.search_filter(&update.search)?;
+
+        let matched = timed(
+            "count_documents",
+            COLLECTION_NAME,
+            self.timeouts.read,
+            self.user_collection()
+                .count_documents(filter.clone(), self.count_options()),
+        )
+        .await?;
+
+        if matched > limit {
+            return Err(PersistenceError::BulkLimitExceeded { matched, limit });
+        }
+
+        if dry_run {
+            return Ok(BulkUpdateResult {
+                matched,
+                modified: 0,
+                dry_run,
+            });
+        }
+
+        let mut update_fields = Document::new();
+        if let Some(name) = &update.set.name {
+            update_fields.insert("name", name::compose(name));
+            update_fields.insert("name_normalized", self.name_normalizer.normalize(name));
+        }
+        if let Some(age) = update.set.age {
+            update_fields.insert("age", age);
+        }
+        if let Some(gender) = &update.set.gender {
+            update_fields.insert("gender", gender.clone());
+        }
+        let mut removed_fields = Document::new();
+        patch_field(
+            "phone",
+            &update.set.phone,
+            &mut update_fields,
+            &mut removed_fields,
+        );
+        patch_field(
+            "address",
+            &update.set.address,
+            &mut update_fields,
+            &mut removed_fields,
+        );
+
+        // The update is a pipeline so `updated_at` only moves, and a user is
+        // only counted as modified, when one of its fields differs. Values
+        // are literals so strings starting with `$` aren't field paths.
+        let mut differs = Vec::new();
+        let mut set = Document::new();
+        for (field, value) in update_fields {
+            differs.push(doc! {"$ne": [format!("${field}"), {"$literal": value.clone()}]});
+            set.insert(field, doc! {"$literal": value});
+        }
+        for field in removed_fields.keys() {
+            differs.push(doc! {"$ne": [{"$type": format!("${field}")}, "missing"]});
+        }
+        set.insert(
+            "updated_at",
+            doc! {"$cond": [{"$or": differs}, bson_time(self.clock.now()), "$updated_at"]},
+        );
+        let mut changes = vec![doc! {"$set": set}];
+        // Mongodb rejects an empty $unset.
+        if !removed_fields.is_empty() {
+            changes.push(doc! {"$unset": removed_fields.keys().collect::<Vec<_>>()});
+        }
+
+        debug!(
+          target: PERSISTENCE_TARGET,
+          filter_fields = ?filter.keys().collect::<Vec<_>>(),
+          update_stages = changes.len(),
+          matched,
+          "bulk updating users"
+        );
+
+        // Users inserted after counting may also be updated.
+        let UpdateResult {
+            matched_count,
+            modified_count,
+            ..
+        } = timed(
+            "update_many",
+            COLLECTION_NAME,
+            self.timeouts.write,
+            self.user_collection().update_many(
+                filter,
+                changes,
+                UpdateOptions::builder()
+                    .collation(self.collation.clone())
+                    .build(),
+            ),
+        )
+        .await?;
+
+        Ok(BulkUpdateResult {
+            matched: matched_count,
+            modified: modified_count,
+            dry_run,
+        })
+    }
+
+    async fn count_genders(&self) -> PersistenceResult<Vec<Value>> {
+        let pipeline = vec![
+            doc! {"$group": {"_id": "$gender", "count": {"$count": {}}}},
+            doc! {"$replaceWith": {"gender": "$_id", "count": "$count"}},
+        ];
+
+        let docs = timed(
+            "aggregate",
+            COLLECTION_NAME,
+            self.aggregation.max_time,
+            async {
+                self.collection::<Document>(COLLECTION_NAME)
+                    .aggregate(pipeline.into_iter(), self.aggregate_options())
+                    .await?
+                    .try_collect::<Vec<_>>()
+                    .await
+            },
+        )
+        .await?
+        .into_iter()
+        .map(document_to_json)
+        .collect();
 
         Ok(docs)
     }
+
+    async fn age_histogram(&self, bucket_size: NonZeroU32) -> PersistenceResult<Vec<AgeBucket>> {
+        // `$bucket` needs every boundary up front so users are grouped by
+        // their age rounded down to the bucket size instead.
+        let size = i64::from(bucket_size.get());
+        let pipeline = vec![
+            doc! {"$match": {"age": {"$type": "number"}}},
+            doc! {"$group": {
+                "_id": {"$subtract": ["$age", {"$mod": ["$age", size]}]},
+                "count": {"$count": {}},
+            }},
+            doc! {"$sort": {"_id": 1}},
+        ];
+
+        let groups = timed(
+            "aggregate",
+            COLLECTION_NAME,
+            self.aggregation.max_time,
+            async {
+                self.collection::<Document>(COLLECTION_NAME)
+                    .aggregate(pipeline.into_iter(), self.aggregate_options())
+                    .await?
+                    .map(|r| r.and_then(|document| Ok(from_document::<AgeGroup>(document)?)))
+                    .try_collect::<Vec<_>>()
+                    .await
+            },
+        )
+        .await?;
+
+        Ok(groups
+            .into_iter()
+            .map(|group| AgeBucket {
+                count: group.count,
+                ..AgeBucket::of(group.lower, bucket_size)
+            })
+            .collect())
+    }
+
+    async fn sample_users(&self, size: u64) -> PersistenceResult<Vec<User>> {
+        // Mongodb rejects a sample of 0 users.
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let pipeline = vec![doc! {"$sample": {"size": i64::try_from(size).unwrap_or(i64::MAX)}}];
+
+        let users = timed(
+            "aggregate",
+            COLLECTION_NAME,
+            self.aggregation.max_time,
+            async {
+                self.collection::<Document>(COLLECTION_NAME)
+                    .aggregate(pipeline.into_iter(), self.aggregate_options())
+                    .await?
+                    .map(|r| r.and_then(|document| read_user(&self.schema, document)))
+                    .try_collect::<Vec<_>>()
+                    .await
+            },
+        )
+        .await?;
+
+        Ok(users.into_iter().map(User::from).collect())
+    }
+
+    async fn save_stats_snapshot(&self, snapshot: &StatsSnapshot) -> PersistenceResult<()> {
+        timed(
+            "replace_one",
+            STATS_COLLECTION_NAME,
+            self.timeouts.write,
+            self.stats_collection().replace_one(
+                doc! {"date": snapshot.date.to_string()},
+                snapshot,
+                ReplaceOptions::builder().upsert(true).build(),
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn stats_history(
+        &self,
+        from: Option<StatsDate>,
+        to: Option<StatsDate>,
+    ) -> PersistenceResult<Vec<StatsSnapshot>> {
+        // Dates are stored as `YYYY-MM-DD` strings which sort by date.
+        let mut range = Document::new();
+        if let Some(from) = from {
+            range.insert("$gte", from.to_string());
+        }
+        if let Some(to) = to {
+            range.insert("$lte", to.to_string());
+        }
+        let filter = if range.is_empty() {
+            doc! {}
+        } else {
+            doc! {"date": range}
+        };
+
+        let options = FindOptions::builder()
+            .sort(doc! {"date": 1})
+            .max_time(self.timeouts.read)
+            .build();
+        let snapshots = timed("find", STATS_COLLECTION_NAME, self.timeouts.read, async {
+            self.stats_collection()
+                .find(filter, options)
+                .await?
+                .try_collect()
+                .await
+        })
+        .await?;
+
+        Ok(snapshots)
+    }
 }
 
 impl MongoPersistence {
+    /// Update document writing `user` over the stored user.
+    fn user_update(&self, user: &UpdateUser) -> Document {
+        let mut update_fields = doc! {
+            "name": name::compose(&user.name),
+            "name_normalized": self.name_normalizer.normalize(&user.name),
+            "age": &user.age,
+            "email": &user.email,
+            "email_normalized": self.email_normalizer.normalize(&user.email),
+            "updated_at": bson_time(self.clock.now()),
+        };
+        let mut removed_fields = Document::new();
+        patch_field(
+            "phone",
+            &user.phone,
+            &mut update_fields,
+            &mut removed_fields,
+        );
+        patch_field(
+            "address",
+            &user.address,
+            &mut update_fields,
+            &mut removed_fields,
+        );
+
+        let mut update = doc! {"$set": update_fields};
+        // Mongodb rejects an empty $unset.
+        if !removed_fields.is_empty() {
+            update.insert("$unset", removed_fields);
+        }
+        update
+    }
+
     /// Get the user collection.
     fn user_collection(&self) -> Collection<MongoUser> {
         self.collection::<MongoUser>(COLLECTION_NAME)
     }
 
+    /// Get the user collection as untyped documents, read before they are
+    /// upgraded to the current schema.
+    fn document_collection(&self) -> Collection<Document> {
+        self.collection::<Document>(COLLECTION_NAME)
+    }
+
+    /// Read a looked up user upgraded to the current schema. When enabled
+    /// the upgraded user is written back unless it changed since it was
+    /// read.
+    async fn look_up_user(&self, document: Document) -> PersistenceResult<MongoUser> {
+        let original = self.schema_write_back.then(|| document.clone());
+        let mut upgraded = document;
+        if !self.schema.upgrade(&mut upgraded) {
+            return Ok(from_document(upgraded).map_err(mongodb::error::Error::from)?);
+        }
+        if let Some(original) = original {
+            // A failed write back is logged and retried by the next lookup.
+            let _ = timed(
+                "replace_one",
+                COLLECTION_NAME,
+                self.timeouts.write,
+                self.document_collection()
+                    .replace_one(original, &upgraded, None),
+            )
+            .await;
+        }
+        Ok(from_document(upgraded).map_err(mongodb::error::Error::from)?)
+    }
+
+    /// Get the statistics history collection.
+    fn stats_collection(&self) -> Collection<StatsSnapshot> {
+        self.collection::<StatsSnapshot>(STATS_COLLECTION_NAME)
+    }
+
+    /// Options of a search returning `limit` users from `offset` in `sort`
+    /// order with the search collation. The limit must not be 0 which
+    /// mongodb treats as no limit.
+    fn search_options(&self, sort: SearchSort, offset: u64, limit: u64) -> FindOptions {
+        FindOptions::builder()
+            .collation(self.collation.clone())
+            .sort(sort_document(sort))
+            .skip(offset)
+            .limit(i64::try_from(limit).ok())
+            .max_time(self.timeouts.read)
+            .build()
+    }
+
+    /// Options of every aggregation on the user collection.
+    fn aggregate_options(&self) -> AggregateOptions {
+        self.aggregation.options()
+    }
+
+    /// Count matching names with the search collation.
+    fn count_options(&self) -> CountOptions {
+        CountOptions::builder()
+            .collation(self.collation.clone())
+            .max_time(self.timeouts.read)
+            .build()
+    }
+
+    /// Query document matching a user search.
+    fn search_filter(&self, user_search: &UserSearch) -> PersistenceResult<Document> {
+        Ok(UserFilter::from(user_search)
+            .to_document(&self.email_normalizer, &self.name_normalizer)?)
+    }
+
+    /// Find at most `limit` users matching `filter` in `sort` order.
+    async fn find_users(
+        &self,
+        filter: Document,
+        sort: SearchSort,
+        limit: u64,
+    ) -> PersistenceResult<Vec<User>> {
+        debug!(
+          target: PERSISTENCE_TARGET,
+          filter_fields = ?filter.keys().collect::<Vec<_>>(),
+          limit,
+          "searching users"
+        );
+
+        // Mongodb treats a limit of 0 as no limit.
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let users = timed("find", COLLECTION_NAME, self.timeouts.read, async {
+            self.document_collection()
+                .find(filter, self.search_options(sort, 0, limit))
+                .await?
+                .map(|r| r.and_then(|document| read_user(&self.schema, document)))
+                .try_collect::<Vec<MongoUser>>()
+                .await
+        })
+        .await?;
+        let result = users.into_iter().map(User::from).collect::<Vec<_>>();
+
+        debug!(
+          target: PERSISTENCE_TARGET,
+          result_count = result.len(),
+          "found users"
+        );
+
+        Ok(result)
+    }
+
     /// Extra capabilities outside of the Persistence trait.
-    /// Download all users from the mongodb collection.
-    pub async fn download(&self) -> PersistenceResult<impl Stream<Item = MongoResult<User>>> {
+    /// Download all users from the mongodb collection in id order. A
+    /// download resumes with the users following `after`.
+    pub async fn download(
+        &self,
+        after: Option<&UserKey>,
+    ) -> PersistenceResult<impl Stream<Item = MongoResult<User>>> {
+        let filter = match after {
+            Some(key) => doc! {"_id": {"$gt": ObjectId::try_from(key)?}},
+            None => doc! {},
+        };
+        let options = FindOptions::builder().sort(doc! {"_id": 1}).build();
+        let schema = self.schema;
         Ok(self
-            .user_collection()
-            .find(doc! {}, None)
+            .document_collection()
+            .find(filter, options)
             .await?
-            .map(|r| r.map(User::from)))
+            .map(move |r| r.and_then(|document| read_user(&schema, document)))
+            .map_ok(User::from))
     }
+
+    /// Run a user supplied aggregation pipeline on the user collection once
+    /// it is checked to only use permitted stages and operators.
+    pub async fn aggregate_users(
+        &self,
+        pipeline: Vec<Document>,
+    ) -> PersistenceResult<impl Stream<Item = MongoResult<Value>>> {
+        sanitize::check_pipeline(&pipeline)?;
+
+        Ok(self
+            .collection::<Document>(COLLECTION_NAME)
+            .aggregate(pipeline, self.aggregate_options())
+            .await?
+            .map(|r| r.map(document_to_json)))
+    }
+
+    /// Check every stored user for integrity issues in id order. With
+    /// `repair` the missing and outdated normalized emails and names and the
+    /// missing times are rewritten in batches of [`INTEGRITY_REPAIR_BATCH`]
+    /// users, each in its own transaction, and the findings of a batch are
+    /// streamed once it is committed. A batch that fails to commit is not
+    /// repaired and ends the stream with its error, leaving the batches
+    /// before it repaired. Transactions need a replica set.
+    pub async fn check_integrity(&self, repair: bool) -> PersistenceResult<IntegrityStream> {
+        let collection = self.collection::<Document>(COLLECTION_NAME);
+        let options = FindOptions::builder().sort(doc! {"_id": 1}).build();
+        let documents = timed(
+            "find",
+            COLLECTION_NAME,
+            self.timeouts.read,
+            collection.find(doc! {}, options),
+        )
+        .await?
+        .map_err(PersistenceError::from);
+
+        if !repair {
+            let emails = self.email_normalizer;
+            let mode = self.email_validation;
+            let names = self.name_normalizer;
+            return Ok(documents
+                .map_ok(move |document| {
+                    stream::iter(integrity_findings(&document, &emails, mode, &names).map(Ok))
+                })
+                .try_flatten()
+                .boxed());
+        }
+
+        let persistence = self.clone();
+        Ok(documents
+            .try_chunks(INTEGRITY_REPAIR_BATCH)
+            .map_err(|e| e.1)
+            .and_then(move |batch| {
+                let persistence = persistence.clone();
+                async move { persistence.repair_integrity(&batch).await }
+            })
+            .map_ok(|findings| stream::iter(findings.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed())
+    }
+
+    /// Integrity findings of a batch of stored user `documents`, repairing
+    /// them in a single transaction.
+    async fn repair_integrity(
+        &self,
+        documents: &[Document],
+    ) -> PersistenceResult<Vec<IntegrityFinding>> {
+        let collection = self.collection::<Document>(COLLECTION_NAME);
+        let emails = self.email_normalizer;
+        let names = self.name_normalizer;
+        // A session dropped before its transaction is committed aborts it.
+        let mut session = collection.client().start_session(None).await?;
+        session.start_transaction(None).await?;
+        let mut findings = Vec::new();
+        for document in documents {
+            let mut document_findings =
+                integrity_findings(document, &emails, self.email_validation, &names)
+                    .collect::<Vec<_>>();
+            let repairable = document_findings.iter().any(|f| f.issue.repairable());
+            if let (true, Some(id)) = (repairable, document.get("_id")) {
+                let mut normalized = Document::new();
+                if let Ok(email) = document.get_str("email") {
+                    normalized.insert(
+                        "email_normalized",
+                        emails.normalize(&Email(email.to_owned())),
+                    );
+                }
+                if let Ok(name) = document.get_str("name") {
+                    normalized.insert("name_normalized", names.normalize(name));
+                }
+                normalized.extend(missing_timestamps(document));
+                timed(
+                    "update_one",
+                    COLLECTION_NAME,
+                    self.timeouts.write,
+                    collection.update_one_with_session(
+                        doc! {"_id": id},
+                        doc! {"$set": normalized},
+                        None,
+                        &mut session,
+                    ),
+                )
+                .await?;
+                for finding in &mut document_findings {
+                    finding.repaired = finding.issue.repairable();
+                }
+            }
+            findings.extend(document_findings);
+        }
+        timed(
+            "commit_transaction",
+            COLLECTION_NAME,
+            self.timeouts.write,
+            session.commit_transaction(),
+        )
+        .await?;
+
+        debug!(
+          target: PERSISTENCE_TARGET,
+          checked = documents.len(),
+          findings = findings.len(),
+          "repaired user integrity batch"
+        );
+        Ok(findings)
+    }
+}
+
+/// Integrity findings of a stored user document, keyed by its `_id`.
+fn integrity_findings(
+    document: &Document,
+    emails: &EmailNormalizer,
+    mode: EmailValidation,
+    names: &NameNormalizer,
+) -> impl Iterator<Item = IntegrityFinding> {
+    let id = match document.get("_id") {
+        Some(Bson::ObjectId(id)) => id.to_hex(),
+        Some(id) => id.to_string(),
+        None => String::new(),
+    };
+    check_document(document, emails, mode, names)
+        .into_iter()
+        .map(move |issue| IntegrityFinding {
+            id: id.clone(),
+            issue,
+            repaired: false,
+        })
 }
 
-impl From<UserKey> for Bson {
-    fn from(user_key: UserKey) -> Self {
-        ObjectId::parse_str(user_key.0)
-            .map(Bson::ObjectId)
-            .unwrap_or_else(|_| Bson::Null)
+impl TryFrom<UserKey> for Bson {
+    type Error = InvalidKeyError;
+    fn try_from(user_key: UserKey) -> Result<Self, Self::Error> {
+        ObjectId::try_from(&user_key).map(Bson::ObjectId)
     }
 }
 
@@ -199,15 +1282,65 @@ impl From<Email> for Bson {
     }
 }
 
+impl From<Phone> for Bson {
+    fn from(phone: Phone) -> Self {
+        Bson::String(phone.0)
+    }
+}
+
+impl From<Address> for Bson {
+    fn from(address: Address) -> Self {
+        Bson::String(address.0)
+    }
+}
+
+/// Add a patched field to the `$set` or `$unset` document of an update.
+fn patch_field<T>(field: &str, patch: &Patch<T>, set: &mut Document, unset: &mut Document)
+where
+    T: Clone + Into<Bson>,
+{
+    match patch {
+        Patch::Absent => (),
+        Patch::Null => {
+            unset.insert(field, "");
+        }
+        Patch::Value(value) => {
+            set.insert(field, value.clone());
+        }
+    }
+}
+
 /// User type as it is saved in mongodb.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, MaskedDebug, Deserialize, Serialize)]
 pub struct MongoUser {
     #[serde(skip_serializing)]
     pub _id: Option<ObjectId>,
+    #[masked]
     pub name: String,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_normalized: Option<String>,
     pub age: u32,
+    #[masked]
     pub email: String,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_normalized: Option<String>,
     pub gender: Gender,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<Phone>,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<bson::DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<bson::DateTime>,
+    /// Version of the schema the user was written with, 0 before
+    /// versioning.
+    #[serde(default)]
+    pub schema_version: u32,
 }
 
 impl From<MongoUser> for User {
@@ -218,6 +1351,10 @@ impl From<MongoUser> for User {
             age: mongo_user.age,
             email: Email(mongo_user.email),
             gender: mongo_user.gender,
+            phone: mongo_user.phone,
+            address: mongo_user.address,
+            created_at: mongo_user.created_at.and_then(chrono_time),
+            updated_at: mongo_user.updated_at.and_then(chrono_time),
         }
     }
 }
@@ -227,16 +1364,142 @@ impl From<User> for MongoUser {
         MongoUser {
             _id: None,
             name: user.name,
+            name_normalized: None,
             age: user.age,
             email: user.email.0,
+            email_normalized: None,
             gender: user.gender,
+            phone: user.phone,
+            address: user.address,
+            created_at: user.created_at.map(bson_time),
+            updated_at: user.updated_at.map(bson_time),
+            schema_version: SchemaRegistry::default().current_version(),
         }
     }
 }
 
+/// Users of an age histogram bucket.
+#[derive(Deserialize)]
+struct AgeGroup {
+    #[serde(rename = "_id")]
+    lower: u32,
+    count: u64,
+}
+
+/// Deserialize a stored user upgraded to the current schema.
+fn read_user(schema: &SchemaRegistry, mut document: Document) -> MongoResult<MongoUser> {
+    schema.upgrade(&mut document);
+    Ok(from_document(document)?)
+}
+
+/// Time read from mongodb.
+fn chrono_time(time: bson::DateTime) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_millis(time.timestamp_millis())
+}
+
+/// Field a search is sorted by before the key, none when sorted by key.
+fn sort_field(sort: SearchSort) -> Option<&'static str> {
+    match sort {
+        SearchSort::Name => Some("name"),
+        SearchSort::Id => None,
+        SearchSort::Created => Some("created_at"),
+        SearchSort::Updated => Some("updated_at"),
+    }
+}
+
+/// Sort document of a search. Sorting by a field ends with the key so
+/// users with the same value keep their order across pages.
+fn sort_document(sort: SearchSort) -> Document {
+    match sort_field(sort) {
+        Some(field) => doc! {field: 1, "_id": 1},
+        None => doc! {"_id": 1},
+    }
+}
+
+/// Users are stored with ObjectId keys so keys of other formats can't
+/// match any user.
 impl TryFrom<&UserKey> for ObjectId {
-    type Error = mongodb::bson::oid::Error;
+    type Error = InvalidKeyError;
     fn try_from(user_key: &UserKey) -> Result<Self, Self::Error> {
-        ObjectId::parse_str(&user_key.0)
+        match user_key {
+            UserKey::ObjectId(oid) => Ok(*oid),
+            key => Err(InvalidKeyError(key.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{patch_field, AggregationSettings, NameMatching};
+    use crate::{
+        patch::Patch,
+        types::{InvalidKeyError, Phone, UserKey},
+    };
+    use mongodb::{
+        bson::{doc, oid::ObjectId, Bson, Document},
+        options::CollationStrength,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_key_conversion() {
+        let oid = ObjectId::new();
+        assert_eq!(Bson::try_from(UserKey::from(oid)), Ok(Bson::ObjectId(oid)));
+
+        // Keys of other formats are rejected rather than queried as null.
+        let key = "fakekey".parse::<UserKey>().unwrap();
+        assert_eq!(
+            ObjectId::try_from(&key),
+            Err(InvalidKeyError("fakekey".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_patch_field() {
+        let mut set = Document::new();
+        let mut unset = Document::new();
+
+        patch_field::<Phone>("phone", &Patch::Absent, &mut set, &mut unset);
+        assert!(set.is_empty() && unset.is_empty());
+
+        patch_field("phone", &Patch::<Phone>::Null, &mut set, &mut unset);
+        assert_eq!(unset, doc! {"phone": ""});
+
+        let phone = Patch::Value(Phone("555-0100".to_owned()));
+        patch_field("phone", &phone, &mut set, &mut unset);
+        assert_eq!(set, doc! {"phone": "555-0100"});
+    }
+
+    #[test]
+    fn test_name_matching_collation() {
+        let collation = NameMatching::default().collation("es");
+        assert_eq!(collation.locale, "es");
+        assert!(matches!(
+            collation.strength,
+            Some(CollationStrength::Secondary)
+        ));
+        assert!(matches!(
+            NameMatching::Base.collation("es").strength,
+            Some(CollationStrength::Primary)
+        ));
+        assert!(matches!(
+            NameMatching::Exact.collation("es").strength,
+            Some(CollationStrength::Tertiary)
+        ));
+    }
+
+    #[test]
+    fn test_aggregation_options() {
+        let options = AggregationSettings::default().options();
+        assert_eq!(options.allow_disk_use, Some(true));
+        assert_eq!(options.max_time, Some(Duration::from_secs(30)));
+
+        let options = AggregationSettings {
+            allow_disk_use: false,
+            max_time: None,
+        }
+        .options();
+        assert_eq!(options.allow_disk_use, Some(false));
+        assert_eq!(options.max_time, None);
     }
 }
@@ -0,0 +1,142 @@
+/*!
+Name normalization.
+
+Names are stored in Unicode normalization form C so that a name typed with
+a combining accent and one typed with the precomposed letter are the same
+string. A normalized form, stored alongside the name, is case folded so
+that `STRASSE` matches `Straße`. With transliteration the normalized form
+also spells umlauts out and drops the remaining accents, so `Mueller`
+matches `Müller` and `Jose` matches `José`.
+*/
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Name in Unicode normalization form C.
+pub fn compose(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Normalizes names into the form they are searched by.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NameNormalizer {
+    transliterate: bool,
+}
+
+impl NameNormalizer {
+    /// Create a new normalizer. When `transliterate` is set names are
+    /// reduced to their Latin letters so `Müller` normalizes to `mueller`.
+    pub fn new(transliterate: bool) -> Self {
+        Self { transliterate }
+    }
+
+    /// Whether names are searched by their normalized form rather than by
+    /// the stored name compared with the collation.
+    pub fn transliterates(&self) -> bool {
+        self.transliterate
+    }
+
+    /// Normalize a name. The value is trimmed, composed and case folded and,
+    /// when transliterating, reduced to its Latin letters.
+    pub fn normalize(&self, name: &str) -> String {
+        let folded = fold_case(&compose(name.trim()));
+        if !self.transliterate {
+            return folded;
+        }
+
+        folded
+            .chars()
+            .flat_map(|c| {
+                let spelled: Option<&str> = match c {
+                    'ä' => Some("ae"),
+                    'ö' => Some("oe"),
+                    'ü' => Some("ue"),
+                    'æ' => Some("ae"),
+                    'œ' => Some("oe"),
+                    'ø' => Some("o"),
+                    'đ' => Some("d"),
+                    'ł' => Some("l"),
+                    'þ' => Some("th"),
+                    _ => None,
+                };
+                match spelled {
+                    Some(spelled) => spelled.chars().collect::<Vec<_>>(),
+                    None => vec![c],
+                }
+            })
+            .collect::<String>()
+            .nfd()
+            .filter(|c| !is_combining_mark(*c))
+            .collect()
+    }
+
+    /// Query value of a search for `name` in the field given by
+    /// [`NameNormalizer::field`].
+    pub fn search_key(&self, name: &str) -> String {
+        if self.transliterate {
+            self.normalize(name)
+        } else {
+            compose(name)
+        }
+    }
+
+    /// Stored field searches for a name are compared against.
+    pub fn field(&self) -> &'static str {
+        if self.transliterate {
+            "name_normalized"
+        } else {
+            "name"
+        }
+    }
+}
+
+/// Full case folding of a composed name. Lowercasing covers every letter
+/// except those folding to several letters or to another lowercase one.
+fn fold_case(name: &str) -> String {
+    let mut folded = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            'ß' | 'ẞ' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            _ => folded.extend(c.to_lowercase()),
+        }
+    }
+    folded
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compose, NameNormalizer};
+
+    #[test]
+    fn test_compose() {
+        assert_eq!(compose("Jose\u{301}"), "Jos\u{e9}");
+        assert_eq!(compose("Jos\u{e9}"), "Jos\u{e9}");
+    }
+
+    #[test]
+    fn test_case_folding() {
+        let normalizer = NameNormalizer::default();
+        assert_eq!(normalizer.normalize(" Straße "), "strasse");
+        assert_eq!(normalizer.normalize("STRASSE"), "strasse");
+        assert_eq!(
+            normalizer.normalize("Jose\u{301}"),
+            normalizer.normalize("JOS\u{c9}")
+        );
+        assert_ne!(normalizer.normalize("José"), normalizer.normalize("Jose"));
+        assert_eq!(normalizer.search_key("Jose\u{301}"), "Jos\u{e9}");
+    }
+
+    #[test]
+    fn test_transliteration() {
+        let normalizer = NameNormalizer::new(true);
+        assert_eq!(normalizer.normalize("Müller"), "mueller");
+        assert_eq!(normalizer.normalize("Mueller"), "mueller");
+        assert_eq!(normalizer.normalize("Mu\u{308}ller"), "mueller");
+        assert_eq!(normalizer.normalize("Álvarez"), "alvarez");
+        assert_eq!(
+            normalizer.normalize("Søren Kierkegaard"),
+            "soren kierkegaard"
+        );
+        assert_eq!(normalizer.search_key("José"), "jose");
+        assert_eq!(normalizer.field(), "name_normalized");
+    }
+}
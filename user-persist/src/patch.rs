@@ -0,0 +1,108 @@
+/*!
+Tri-state fields for partial updates.
+*/
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Field of a partial update that distinguishes a field left out of the
+/// request from one explicitly set to `null`.
+///
+/// Fields must be annotated with `#[serde(default, skip_serializing_if =
+/// "Patch::is_absent")]` so a missing field deserializes as `Absent` and
+/// is left out when serialized.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Patch<T> {
+    /// Leave the field unchanged.
+    #[default]
+    Absent,
+    /// Clear the field.
+    Null,
+    /// Set the field to a new value.
+    Value(T),
+}
+
+impl<T> Patch<T> {
+    /// The field was left out of the update.
+    pub fn is_absent(&self) -> bool {
+        matches!(self, Self::Absent)
+    }
+
+    /// Apply the patch to an optional field.
+    pub fn apply(&self, field: &mut Option<T>)
+    where
+        T: Clone,
+    {
+        match self {
+            Self::Absent => (),
+            Self::Null => *field = None,
+            Self::Value(value) => *field = Some(value.clone()),
+        }
+    }
+}
+
+impl<T> From<Option<T>> for Patch<T> {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Self::Null, Self::Value)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Patch<T> {
+    /// Only called when the field is present. A missing field takes the
+    /// `Absent` default.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Option::<T>::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl<T: Serialize> Serialize for Patch<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Absent | Self::Null => serializer.serialize_none(),
+            Self::Value(value) => serializer.serialize_some(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Patch;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+    struct Update {
+        #[serde(default, skip_serializing_if = "Patch::is_absent")]
+        phone: Patch<String>,
+    }
+
+    #[test]
+    fn test_patch_serde() {
+        let cases = [
+            (json!({}), Patch::Absent),
+            (json!({"phone": null}), Patch::Null),
+            (
+                json!({"phone": "555-0100"}),
+                Patch::Value("555-0100".to_owned()),
+            ),
+        ];
+
+        for (wire, phone) in cases {
+            let update = serde_json::from_value::<Update>(wire.clone()).unwrap();
+            assert_eq!(update, Update { phone });
+            assert_eq!(serde_json::to_value(&update).unwrap(), wire);
+        }
+    }
+
+    #[test]
+    fn test_patch_apply() {
+        let mut phone = Some("555-0100".to_owned());
+
+        Patch::Absent.apply(&mut phone);
+        assert_eq!(phone.as_deref(), Some("555-0100"));
+
+        Patch::Value("555-0199".to_owned()).apply(&mut phone);
+        assert_eq!(phone.as_deref(), Some("555-0199"));
+
+        Patch::Null.apply(&mut phone);
+        assert_eq!(phone, None);
+    }
+}
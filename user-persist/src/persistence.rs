@@ -1,40 +1,259 @@
 /*!
 Generic UserPersistence Trait and types.
 */
-use crate::types::{UpdateUser, User, UserKey, UserSearch};
+use crate::{
+    name::NameNormalizer,
+    query::UserQuery,
+    sanitize::SanitizeError,
+    stats::{age_histogram, AgeBucket, StatsDate, StatsSnapshot},
+    types::{
+        BulkUpdate, BulkUpdateResult, Email, InvalidKeyError, SearchPage, UpdateUser, User,
+        UserKey, UserSearch,
+    },
+};
+use futures::stream::{self, BoxStream, StreamExt};
+use mongodb::error::{ErrorKind, WriteFailure};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde_json::Value;
-use std::fmt::Debug;
+use std::{fmt::Debug, num::NonZeroU32};
 use thiserror::Error;
 
 /// Type alias for user-persist Result.
 pub type PersistenceResult<T> = Result<T, PersistenceError>;
 
+/// Users read incrementally from persistent storage.
+pub type UserStream = BoxStream<'static, PersistenceResult<User>>;
+
+/// Default maximum number of users returned by a search.
+pub const DEFAULT_MAX_SEARCH_RESULTS: u64 = 1000;
+
 /// Abstract our persistence API so it can be swapped out
 /// for any backend.
 #[async_trait::async_trait]
 pub trait UserPersistence: Send + Sync + Debug {
     /// Lookup a user from persistent storage.
     async fn get_user(&self, id: &UserKey) -> PersistenceResult<Option<User>>;
+    /// Lookup the user whose email matches `email` once both are
+    /// normalized. Normalized emails are unique so at most one user is
+    /// found. By default the user is found with `search_users`.
+    async fn get_user_by_email(&self, email: &Email) -> PersistenceResult<Option<User>> {
+        let search = UserSearch {
+            email: Some(email.clone()),
+            ..Default::default()
+        };
+        Ok(self.search_users(&search, 1).await?.pop())
+    }
     /// Save a user to persistent storage.
     async fn save_user(&self, user: &User) -> PersistenceResult<User>;
     /// Update a user in persistent storage.
     async fn update_user(&self, user: &UpdateUser) -> PersistenceResult<()>;
+    /// Update a user in persistent storage unless it changed since it was
+    /// read as `current`, returning whether it was updated. Of two updates
+    /// of the same read user at most one succeeds.
+    async fn update_user_if_unchanged(
+        &self,
+        user: &UpdateUser,
+        current: &User,
+    ) -> PersistenceResult<bool>;
     /// Remove a user from persistent storage.
     async fn remove_user(&self, user: &UserKey) -> PersistenceResult<()>;
-    /// Search for users with search criteria in `UserSearch` from
-    /// persistent storage.
-    async fn search_users(&self, user: &UserSearch) -> PersistenceResult<Vec<User>>;
-    /// Count the number of users grouping by gender.
+    /// Search for at most `limit` users with search criteria in
+    /// `UserSearch` from persistent storage.
+    async fn search_users(&self, user: &UserSearch, limit: u64) -> PersistenceResult<Vec<User>>;
+    /// Search for at most `limit` users matching `query`. By default the
+    /// users matching its search are filtered by its ranges once read.
+    async fn query_users(&self, query: &UserQuery, limit: u64) -> PersistenceResult<Vec<User>> {
+        let users = self.search_users(&query.search, u64::MAX).await?;
+        Ok(users
+            .into_iter()
+            .filter(|user| query.matches_ranges(user, &NameNormalizer::default()))
+            .take(usize::try_from(limit).unwrap_or(usize::MAX))
+            .collect())
+    }
+    /// Search for users streaming the results so they don't have to be
+    /// held in memory together. By default the results of `search_users`
+    /// are streamed once collected.
+    async fn search_users_stream(&self, user: &UserSearch) -> PersistenceResult<UserStream> {
+        let users = self.search_users(user, u64::MAX).await?;
+        Ok(stream::iter(users.into_iter().map(Ok)).boxed())
+    }
+    /// Search for a page of at most `limit` users starting at `offset` with
+    /// the total number of users matching. By default the page is cut from
+    /// the results of `search_users`.
+    async fn search_page(
+        &self,
+        user: &UserSearch,
+        offset: u64,
+        limit: u64,
+    ) -> PersistenceResult<SearchPage<User>> {
+        let users = self.search_users(user, u64::MAX).await?;
+        let total = users.len() as u64;
+        let items = users
+            .into_iter()
+            .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+            .take(usize::try_from(limit).unwrap_or(usize::MAX))
+            .collect();
+        Ok(SearchPage {
+            items,
+            total,
+            estimated: false,
+            offset,
+            limit,
+            next_cursor: None,
+        })
+    }
+    /// Search for at most `limit` users following the user with key
+    /// `after` in the order of the search, or `None` when that user no
+    /// longer matches. By default the users are cut from the results of
+    /// `search_users`.
+    async fn search_after(
+        &self,
+        user: &UserSearch,
+        after: &UserKey,
+        limit: u64,
+    ) -> PersistenceResult<Option<Vec<User>>> {
+        let users = self.search_users(user, u64::MAX).await?;
+        let Some(position) = users.iter().position(|u| u.id.as_ref() == Some(after)) else {
+            return Ok(None);
+        };
+        Ok(Some(
+            users
+                .into_iter()
+                .skip(position + 1)
+                .take(usize::try_from(limit).unwrap_or(usize::MAX))
+                .collect(),
+        ))
+    }
+    /// Apply `update.set` to every user matching `update.search`. Nothing is
+    /// changed when more than `limit` users match or for a `dry_run`, which
+    /// only counts the matches.
+    async fn bulk_update(
+        &self,
+        update: &BulkUpdate,
+        limit: u64,
+        dry_run: bool,
+    ) -> PersistenceResult<BulkUpdateResult>;
+    /// Count the number of users grouping by gender. Each group is a
+    /// `{"gender": .., "count": ..}` object.
     async fn count_genders(&self) -> Result<Vec<Value>, PersistenceError>;
+    /// Count the users in buckets of `bucket_size` years of age, youngest
+    /// first. By default the buckets are computed from every user returned
+    /// by `search_users`.
+    async fn age_histogram(&self, bucket_size: NonZeroU32) -> PersistenceResult<Vec<AgeBucket>> {
+        let users = self.search_users(&UserSearch::default(), u64::MAX).await?;
+        Ok(age_histogram(users.iter().map(|u| u.age), bucket_size))
+    }
+    /// Pick at most `size` users at random. By default every user is
+    /// streamed through [`reservoir_sample`] so only the sample is held in
+    /// memory.
+    async fn sample_users(&self, size: u64) -> PersistenceResult<Vec<User>> {
+        let users = self.search_users_stream(&UserSearch::default()).await?;
+        reservoir_sample(users, size, &mut StdRng::from_entropy()).await
+    }
+    /// Record the statistics of a day, replacing any snapshot already
+    /// recorded for the same day.
+    async fn save_stats_snapshot(&self, snapshot: &StatsSnapshot) -> PersistenceResult<()>;
+    /// Snapshots from `from` to `to` inclusive, oldest first. Either bound
+    /// may be left open.
+    async fn stats_history(
+        &self,
+        from: Option<StatsDate>,
+        to: Option<StatsDate>,
+    ) -> PersistenceResult<Vec<StatsSnapshot>>;
+}
+
+/// Users found by a search capped at a maximum number of results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CappedSearch {
+    pub users: Vec<User>,
+    /// More users matched than were returned.
+    pub truncated: bool,
+}
+
+/// Search for at most `max` users, reporting whether more matched. One
+/// more user than `max` is read to tell whether the results were cut off.
+pub async fn search_capped(
+    db: &dyn UserPersistence,
+    search: &UserSearch,
+    max: u64,
+) -> PersistenceResult<CappedSearch> {
+    let mut users = db.search_users(search, max.saturating_add(1)).await?;
+    let truncated = users.len() as u64 > max;
+    users.truncate(usize::try_from(max).unwrap_or(usize::MAX));
+    Ok(CappedSearch { users, truncated })
+}
+
+/// Query for at most `max` users, reporting whether more matched.
+pub async fn query_capped(
+    db: &dyn UserPersistence,
+    query: &UserQuery,
+    max: u64,
+) -> PersistenceResult<CappedSearch> {
+    let mut users = db.query_users(query, max.saturating_add(1)).await?;
+    let truncated = users.len() as u64 > max;
+    users.truncate(usize::try_from(max).unwrap_or(usize::MAX));
+    Ok(CappedSearch { users, truncated })
+}
+
+/// Pick at most `size` users of `users` at random, each with the same
+/// chance, reading the stream once.
+pub async fn reservoir_sample(
+    mut users: UserStream,
+    size: u64,
+    rng: &mut (impl Rng + Send),
+) -> PersistenceResult<Vec<User>> {
+    let size = usize::try_from(size).unwrap_or(usize::MAX);
+    let mut sample = Vec::new();
+    let mut seen = 0_usize;
+    while let Some(user) = users.next().await {
+        let user = user?;
+        seen += 1;
+        if sample.len() < size {
+            sample.push(user);
+        } else {
+            let slot = rng.gen_range(0..seen);
+            if slot < size {
+                sample[slot] = user;
+            }
+        }
+    }
+    Ok(sample)
 }
 
 /// Enumeration of persistence errors.
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum PersistenceError {
     #[error("Mongodb error: `{0}`")]
-    MongoError(#[from] mongodb::error::Error),
+    MongoError(mongodb::error::Error),
     #[error("Persistence Test Failure")]
     TestError,
     #[error("Bson error: `{0}`")]
     BsonError(#[from] mongodb::bson::oid::Error),
+    #[error("Invalid query: `{0}`")]
+    InvalidQuery(#[from] SanitizeError),
+    #[error("Invalid key: `{0}`")]
+    InvalidKey(#[from] InvalidKeyError),
+    #[error("Bulk update matches {matched} users, more than the limit of {limit}")]
+    BulkLimitExceeded { matched: u64, limit: u64 },
+    #[error("Database operation timed out")]
+    Timeout,
+    #[error("A user with the same unique value already exists")]
+    DuplicateKey,
+}
+
+/// Mongodb error code of an operation stopped by its `maxTimeMS`.
+const MAX_TIME_MS_EXPIRED: i32 = 50;
+/// Mongodb error code of a write violating a unique index.
+pub const DUPLICATE_KEY: i32 = 11000;
+
+impl From<mongodb::error::Error> for PersistenceError {
+    fn from(err: mongodb::error::Error) -> Self {
+        match *err.kind {
+            ErrorKind::Command(ref c) if c.code == MAX_TIME_MS_EXPIRED => Self::Timeout,
+            ErrorKind::Write(WriteFailure::WriteError(ref w)) if w.code == DUPLICATE_KEY => {
+                Self::DuplicateKey
+            }
+            _ => Self::MongoError(err),
+        }
+    }
 }
@@ -0,0 +1,412 @@
+/*!
+Query strings for simple user searches.
+
+A query such as `gender:Male age>120 name~smith` is a list of terms
+separated by whitespace. Each term is a field, an operator and a value.
+Values containing whitespace are quoted, as in `name:"Test User"`.
+
+| Field    | Operators                  | Matches                           |
+|----------|----------------------------|-----------------------------------|
+| `email`  | `:`                        | The email once normalized         |
+| `gender` | `:`                        | `Male` or `Female`, in any case   |
+| `name`   | `:` `~`                    | The exact name or a part of it    |
+| `age`    | `:` `>` `>=` `<` `<=`      | An age or a range of ages         |
+
+Queries are parsed strictly. Unknown fields, unsupported operators,
+invalid values and fields given twice are rejected rather than ignored so
+a typo doesn't silently widen the search. Age bounds may be combined into
+a range. Values are left out of error messages as they can be personal.
+*/
+use crate::{
+    masked::Masked,
+    name::NameNormalizer,
+    types::{Email, EmailValidation, Gender, User, UserSearch, ValidateRequest},
+    MaskedDebug,
+};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// Rejected query string.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum QueryError {
+    #[error("Query has no terms")]
+    Empty,
+    #[error("Query has an unterminated quote")]
+    UnterminatedQuote,
+    #[error("Term {0} must be a field, an operator and a value")]
+    Malformed(usize),
+    #[error("Unknown field `{0}`")]
+    UnknownField(String),
+    #[error("Operator `{operator}` is not supported for `{field}`")]
+    Operator {
+        field: &'static str,
+        operator: &'static str,
+    },
+    #[error("Invalid value for `{0}`")]
+    InvalidValue(&'static str),
+    #[error("Field `{0}` is given more than once")]
+    Duplicate(&'static str),
+    #[error("Age range matches no users")]
+    EmptyRange,
+}
+
+/// Inclusive range of ages. Either bound may be left open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AgeRange {
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+}
+
+impl AgeRange {
+    /// Whether the range has neither bound and matches every age.
+    pub fn is_unbounded(&self) -> bool {
+        self.min.is_none() && self.max.is_none()
+    }
+
+    /// Whether `age` is within the range.
+    pub fn contains(&self, age: u32) -> bool {
+        self.min.is_none_or(|min| age >= min) && self.max.is_none_or(|max| age <= max)
+    }
+
+    fn at_least(self, min: u32) -> Self {
+        Self {
+            min: Some(self.min.map_or(min, |current| current.max(min))),
+            ..self
+        }
+    }
+
+    fn at_most(self, max: u32) -> Self {
+        Self {
+            max: Some(self.max.map_or(max, |current| current.min(max))),
+            ..self
+        }
+    }
+}
+
+impl Display for AgeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bound = |bound: Option<u32>| bound.map(|b| b.to_string()).unwrap_or_default();
+        write!(f, "{}..={}", bound(self.min), bound(self.max))
+    }
+}
+
+/// User search with ranges, parsed from a query string.
+#[derive(Clone, MaskedDebug)]
+pub struct UserQuery {
+    /// Criteria matched exactly.
+    pub search: UserSearch,
+    pub age: AgeRange,
+    /// Part of the name, compared once normalized.
+    #[masked]
+    pub name_contains: Option<String>,
+}
+
+impl UserQuery {
+    /// Whether `user` is within the ranges of the query with names
+    /// normalized by `names`. The criteria of the search are not checked.
+    pub fn matches_ranges(&self, user: &User, names: &NameNormalizer) -> bool {
+        self.age.contains(user.age)
+            && self
+                .name_contains
+                .as_ref()
+                .is_none_or(|part| names.normalize(&user.name).contains(&names.normalize(part)))
+    }
+}
+
+impl Display for UserQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            r#"{}, age = "{}", name contains = "{}""#,
+            self.search,
+            self.age,
+            self.name_contains
+                .as_ref()
+                .map(|part| Masked::new(part).to_string())
+                .unwrap_or_default()
+        )
+    }
+}
+
+/// Comparison of a term.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Equals,
+    Contains,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+impl Operator {
+    /// Longer operators come first so `>=` isn't read as `>`.
+    const ALL: [(&'static str, Operator); 6] = [
+        (">=", Self::GreaterOrEqual),
+        ("<=", Self::LessOrEqual),
+        (":", Self::Equals),
+        ("~", Self::Contains),
+        (">", Self::Greater),
+        ("<", Self::Less),
+    ];
+
+    fn as_str(self) -> &'static str {
+        Self::ALL
+            .iter()
+            .find(|(_, operator)| *operator == self)
+            .map(|(symbol, _)| *symbol)
+            .unwrap_or_default()
+    }
+}
+
+/// Split a query into terms at whitespace outside of quotes, removing the
+/// quotes.
+fn terms(query: &str) -> Result<Vec<String>, QueryError> {
+    let mut terms = Vec::new();
+    let mut term = String::new();
+    let mut quoted = false;
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !term.is_empty() {
+                    terms.push(std::mem::take(&mut term));
+                }
+            }
+            c => term.push(c),
+        }
+    }
+    if quoted {
+        return Err(QueryError::UnterminatedQuote);
+    }
+    if !term.is_empty() {
+        terms.push(term);
+    }
+    Ok(terms)
+}
+
+/// Split a term into its field, operator and value.
+fn split_term(term: &str, position: usize) -> Result<(&str, Operator, &str), QueryError> {
+    let field_end = term
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .ok_or(QueryError::Malformed(position))?;
+    let (field, rest) = term.split_at(field_end);
+    let (symbol, operator) = Operator::ALL
+        .iter()
+        .find(|(symbol, _)| rest.starts_with(symbol))
+        .ok_or(QueryError::Malformed(position))?;
+    let value = &rest[symbol.len()..];
+    if field.is_empty() || value.is_empty() {
+        return Err(QueryError::Malformed(position));
+    }
+    Ok((field, *operator, value))
+}
+
+/// Set a criterion that may only be given once.
+fn set_once<T>(slot: &mut Option<T>, field: &'static str, value: T) -> Result<(), QueryError> {
+    if slot.is_some() {
+        return Err(QueryError::Duplicate(field));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+impl UserQuery {
+    /// Parse a query string, validating its email in `mode`.
+    pub fn parse(query: &str, mode: EmailValidation) -> Result<Self, QueryError> {
+        let terms = terms(query)?;
+        if terms.is_empty() {
+            return Err(QueryError::Empty);
+        }
+
+        let mut search = UserSearch::default();
+        let mut age = AgeRange::default();
+        let mut name_contains = None;
+
+        for (index, term) in terms.iter().enumerate() {
+            let (field, operator, value) = split_term(term, index + 1)?;
+            let unsupported = |field| QueryError::Operator {
+                field,
+                operator: operator.as_str(),
+            };
+            match (field, operator) {
+                ("email", Operator::Equals) => {
+                    set_once(&mut search.email, "email", Email(value.to_owned()))?
+                }
+                ("gender", Operator::Equals) => {
+                    let gender = match value.to_ascii_lowercase().as_str() {
+                        "male" => Gender::Male,
+                        "female" => Gender::Female,
+                        _ => return Err(QueryError::InvalidValue("gender")),
+                    };
+                    set_once(&mut search.gender, "gender", gender)?
+                }
+                ("name", Operator::Equals | Operator::Contains) => {
+                    if search.name.is_some() || name_contains.is_some() {
+                        return Err(QueryError::Duplicate("name"));
+                    }
+                    if operator == Operator::Equals {
+                        search.name = Some(value.to_owned());
+                    } else {
+                        name_contains = Some(value.to_owned());
+                    }
+                }
+                ("age", Operator::Contains) => return Err(unsupported("age")),
+                ("age", operator) => {
+                    let bound = value
+                        .parse::<u32>()
+                        .map_err(|_| QueryError::InvalidValue("age"))?;
+                    age = match operator {
+                        Operator::Greater => {
+                            age.at_least(bound.checked_add(1).ok_or(QueryError::EmptyRange)?)
+                        }
+                        Operator::GreaterOrEqual => age.at_least(bound),
+                        Operator::Less => {
+                            age.at_most(bound.checked_sub(1).ok_or(QueryError::EmptyRange)?)
+                        }
+                        Operator::LessOrEqual => age.at_most(bound),
+                        _ => age.at_least(bound).at_most(bound),
+                    };
+                }
+                ("email", _) => return Err(unsupported("email")),
+                ("gender", _) => return Err(unsupported("gender")),
+                ("name", _) => return Err(unsupported("name")),
+                (field, _) => return Err(QueryError::UnknownField(field.to_owned())),
+            }
+        }
+
+        if matches!((age.min, age.max), (Some(min), Some(max)) if min > max) {
+            return Err(QueryError::EmptyRange);
+        }
+        if let Err(errors) = search.validate_request(mode) {
+            let field = if errors.field_errors().contains_key("email") {
+                "email"
+            } else {
+                "name"
+            };
+            return Err(QueryError::InvalidValue(field));
+        }
+
+        Ok(Self {
+            search,
+            age,
+            name_contains,
+        })
+    }
+}
+
+impl FromStr for UserQuery {
+    type Err = QueryError;
+
+    /// Parse a query string, validating its email in the default mode.
+    fn from_str(query: &str) -> Result<Self, Self::Err> {
+        Self::parse(query, EmailValidation::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AgeRange, QueryError, UserQuery};
+    use crate::types::{Email, Gender};
+
+    fn parse(query: &str) -> Result<UserQuery, QueryError> {
+        query.parse::<UserQuery>()
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse("gender:Male age>120 name~smith").unwrap();
+        assert_eq!(query.search.gender, Some(Gender::Male));
+        assert_eq!(query.search.email, None);
+        assert_eq!(query.search.name, None);
+        assert_eq!(
+            query.age,
+            AgeRange {
+                min: Some(121),
+                max: None
+            }
+        );
+        assert_eq!(query.name_contains.as_deref(), Some("smith"));
+
+        let query = parse(r#" email:test@test.com   name:"Test User" gender:female "#).unwrap();
+        assert_eq!(query.search.email, Some(Email("test@test.com".to_owned())));
+        assert_eq!(query.search.name.as_deref(), Some("Test User"));
+        assert_eq!(query.search.gender, Some(Gender::Female));
+        assert!(query.age.is_unbounded());
+    }
+
+    #[test]
+    fn test_parse_age_range() {
+        let range = |query| parse(query).unwrap().age;
+        assert_eq!(
+            range("age>=20 age<30"),
+            AgeRange {
+                min: Some(20),
+                max: Some(29)
+            }
+        );
+        assert_eq!(
+            range("age:42"),
+            AgeRange {
+                min: Some(42),
+                max: Some(42)
+            }
+        );
+        assert_eq!(
+            range("age>10 age>20 age<=50"),
+            AgeRange {
+                min: Some(21),
+                max: Some(50)
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let cases = [
+            ("", QueryError::Empty),
+            ("   ", QueryError::Empty),
+            (r#"name:"Test"#, QueryError::UnterminatedQuote),
+            ("gender", QueryError::Malformed(1)),
+            ("gender:Male age>", QueryError::Malformed(2)),
+            (":Male", QueryError::Malformed(1)),
+            ("gender=Male", QueryError::Malformed(1)),
+            ("phone:555", QueryError::UnknownField("phone".to_owned())),
+            (
+                "gender~Male",
+                QueryError::Operator {
+                    field: "gender",
+                    operator: "~",
+                },
+            ),
+            (
+                "age~12",
+                QueryError::Operator {
+                    field: "age",
+                    operator: "~",
+                },
+            ),
+            ("gender:Other", QueryError::InvalidValue("gender")),
+            ("age>old", QueryError::InvalidValue("age")),
+            ("age>-1", QueryError::InvalidValue("age")),
+            ("email:not-an-email", QueryError::InvalidValue("email")),
+            ("gender:Male gender:Female", QueryError::Duplicate("gender")),
+            ("name:Test name~es", QueryError::Duplicate("name")),
+            ("age>50 age<20", QueryError::EmptyRange),
+            ("age<0", QueryError::EmptyRange),
+            ("age>4294967295", QueryError::EmptyRange),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(parse(query).err(), Some(expected), "{query}");
+        }
+    }
+
+    #[test]
+    fn test_error_omits_values() {
+        let message = parse("email:secret").err().unwrap().to_string();
+        assert!(!message.contains("secret"));
+    }
+}
@@ -0,0 +1,360 @@
+/*!
+References to users from related collections.
+
+Collections holding records about users, such as the audit log, key them
+by the string form of their [`UserKey`] in a single field, the way the
+audit log keys its `targetUser`. Each collection is registered with the
+[`References`] of the service as a [`UserReferences`] declaring what its
+records are to the user:
+
+- [`ReferenceKind::Owned`] records, such as avatars or credentials, belong
+  to the user. They are removed with the user, move to the surviving user
+  when users are merged, and are reported by the integrity check when the
+  user they reference doesn't exist.
+- [`ReferenceKind::History`] records, such as audit records, describe what
+  happened to the user and are left as they are.
+*/
+use crate::{
+    integrity::{IntegrityFinding, IntegrityIssue},
+    mongo_persistence::{timed, OperationTimeouts},
+    persistence::{PersistenceResult, UserPersistence},
+    types::UserKey,
+};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Collection, Database,
+};
+use std::{collections::BTreeSet, fmt::Debug, sync::Arc};
+
+/// What the records of a related collection are to the user they
+/// reference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferenceKind {
+    /// The records belong to the user.
+    Owned,
+    /// The records are history kept after the user is gone.
+    History,
+}
+
+/// A collection referencing users.
+#[async_trait::async_trait]
+pub trait UserReferences: Send + Sync + Debug {
+    /// Name of the collection.
+    fn collection(&self) -> &'static str;
+
+    fn kind(&self) -> ReferenceKind;
+
+    /// Remove the records of `user`, returning how many were. Only called
+    /// for owned records.
+    async fn remove_user(&self, _user: &UserKey) -> PersistenceResult<u64> {
+        Ok(0)
+    }
+
+    /// Move the records of `from` to `to`, returning how many were. Only
+    /// called for owned records.
+    async fn reassign(&self, _from: &UserKey, _to: &UserKey) -> PersistenceResult<u64> {
+        Ok(0)
+    }
+
+    /// Keys of the users the records reference. Only called for owned
+    /// records.
+    async fn referenced_users(&self) -> PersistenceResult<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Collections referencing users.
+#[derive(Clone, Debug, Default)]
+pub struct References {
+    collections: Vec<Arc<dyn UserReferences>>,
+}
+
+impl References {
+    /// Add `collection` to the collections referencing users.
+    pub fn register(mut self, collection: Arc<dyn UserReferences>) -> Self {
+        self.collections.push(collection);
+        self
+    }
+
+    /// Collections referencing users, in registration order.
+    pub fn collections(&self) -> impl Iterator<Item = &Arc<dyn UserReferences>> {
+        self.collections.iter()
+    }
+
+    fn owned(&self) -> impl Iterator<Item = &Arc<dyn UserReferences>> {
+        self.collections
+            .iter()
+            .filter(|collection| collection.kind() == ReferenceKind::Owned)
+    }
+
+    /// Remove the owned records of the removed `user`, returning how many
+    /// were removed from each collection.
+    pub async fn user_removed(
+        &self,
+        user: &UserKey,
+    ) -> PersistenceResult<Vec<(&'static str, u64)>> {
+        let mut removed = Vec::new();
+        for collection in self.owned() {
+            removed.push((collection.collection(), collection.remove_user(user).await?));
+        }
+        Ok(removed)
+    }
+
+    /// Move the owned records of `from` to `to` when `from` is merged into
+    /// `to`, returning how many were moved in each collection.
+    pub async fn users_merged(
+        &self,
+        from: &UserKey,
+        to: &UserKey,
+    ) -> PersistenceResult<Vec<(&'static str, u64)>> {
+        let mut moved = Vec::new();
+        for collection in self.owned() {
+            moved.push((
+                collection.collection(),
+                collection.reassign(from, to).await?,
+            ));
+        }
+        Ok(moved)
+    }
+
+    /// Findings of owned records referencing users of `persist` which
+    /// don't exist, one for each missing user of a collection.
+    pub async fn dangling(
+        &self,
+        persist: &dyn UserPersistence,
+    ) -> PersistenceResult<Vec<IntegrityFinding>> {
+        let mut findings = Vec::new();
+        for collection in self.owned() {
+            let referenced = collection.referenced_users().await?;
+            for id in referenced.into_iter().collect::<BTreeSet<_>>() {
+                let exists = match id.parse::<UserKey>() {
+                    Ok(key) => persist.get_user(&key).await?.is_some(),
+                    Err(_) => false,
+                };
+                if !exists {
+                    findings.push(IntegrityFinding {
+                        id,
+                        issue: IntegrityIssue::DanglingReference {
+                            collection: collection.collection(),
+                        },
+                        repaired: false,
+                    });
+                }
+            }
+        }
+        Ok(findings)
+    }
+}
+
+/// Mongodb collection referencing users in a field.
+#[derive(Clone, Debug)]
+pub struct MongoReferences {
+    collection: Collection<Document>,
+    name: &'static str,
+    field: &'static str,
+    kind: ReferenceKind,
+    timeouts: OperationTimeouts,
+}
+
+impl MongoReferences {
+    /// Collection `name` of `db` referencing users in `field`.
+    pub fn new(
+        db: &Database,
+        name: &'static str,
+        field: &'static str,
+        kind: ReferenceKind,
+    ) -> Self {
+        Self {
+            collection: db.collection(name),
+            name,
+            field,
+            kind,
+            timeouts: OperationTimeouts::default(),
+        }
+    }
+
+    /// Limit operations to `timeouts`.
+    pub fn with_timeouts(self, timeouts: OperationTimeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+}
+
+#[async_trait::async_trait]
+impl UserReferences for MongoReferences {
+    fn collection(&self) -> &'static str {
+        self.name
+    }
+
+    fn kind(&self) -> ReferenceKind {
+        self.kind
+    }
+
+    async fn remove_user(&self, user: &UserKey) -> PersistenceResult<u64> {
+        let result = timed(
+            "delete_many",
+            self.name,
+            self.timeouts.write,
+            self.collection
+                .delete_many(doc! {self.field: user.to_string()}, None),
+        )
+        .await?;
+        Ok(result.deleted_count)
+    }
+
+    async fn reassign(&self, from: &UserKey, to: &UserKey) -> PersistenceResult<u64> {
+        let result = timed(
+            "update_many",
+            self.name,
+            self.timeouts.write,
+            self.collection.update_many(
+                doc! {self.field: from.to_string()},
+                doc! {"$set": {self.field: to.to_string()}},
+                None,
+            ),
+        )
+        .await?;
+        Ok(result.modified_count)
+    }
+
+    async fn referenced_users(&self) -> PersistenceResult<Vec<String>> {
+        let keys = timed(
+            "distinct",
+            self.name,
+            self.timeouts.read,
+            self.collection.distinct(self.field, None, None),
+        )
+        .await?;
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| match key {
+                Bson::String(key) => Some(key),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReferenceKind, References, UserReferences};
+    use crate::{
+        integrity::IntegrityIssue,
+        memory::MemoryPersistence,
+        persistence::{PersistenceResult, UserPersistence},
+        types::{Email, Gender, User, UserKey},
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Owned records keyed by user.
+    #[derive(Debug, Default)]
+    struct Avatars(Mutex<Vec<String>>);
+
+    #[async_trait::async_trait]
+    impl UserReferences for Avatars {
+        fn collection(&self) -> &'static str {
+            "avatars"
+        }
+
+        fn kind(&self) -> ReferenceKind {
+            ReferenceKind::Owned
+        }
+
+        async fn remove_user(&self, user: &UserKey) -> PersistenceResult<u64> {
+            let mut avatars = self.0.lock().unwrap();
+            let before = avatars.len();
+            avatars.retain(|key| *key != user.to_string());
+            Ok((before - avatars.len()) as u64)
+        }
+
+        async fn reassign(&self, from: &UserKey, to: &UserKey) -> PersistenceResult<u64> {
+            let (from, to) = (from.to_string(), to.to_string());
+            let mut moved = 0;
+            for key in self
+                .0
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .filter(|key| **key == from)
+            {
+                key.clone_from(&to);
+                moved += 1;
+            }
+            Ok(moved)
+        }
+
+        async fn referenced_users(&self) -> PersistenceResult<Vec<String>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    /// History records, never touched.
+    #[derive(Debug)]
+    struct History;
+
+    impl UserReferences for History {
+        fn collection(&self) -> &'static str {
+            "history"
+        }
+
+        fn kind(&self) -> ReferenceKind {
+            ReferenceKind::History
+        }
+    }
+
+    fn user(email: &str) -> User {
+        User {
+            id: None,
+            name: "Test User".to_owned(),
+            age: 100,
+            email: Email(email.to_owned()),
+            gender: Gender::Male,
+            phone: None,
+            address: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cascades() {
+        let persist = MemoryPersistence::default();
+        let kept = persist.save_user(&user("kept@test.com")).await.unwrap();
+        let merged = persist.save_user(&user("merged@test.com")).await.unwrap();
+        let (kept, merged) = (kept.id.unwrap(), merged.id.unwrap());
+
+        let avatars = Arc::new(Avatars::default());
+        avatars.0.lock().unwrap().extend([
+            kept.to_string(),
+            merged.to_string(),
+            merged.to_string(),
+        ]);
+        let references = References::default()
+            .register(Arc::new(History))
+            .register(avatars.clone());
+
+        assert!(references.dangling(&persist).await.unwrap().is_empty());
+        assert_eq!(
+            references.users_merged(&merged, &kept).await.unwrap(),
+            [("avatars", 2)]
+        );
+        persist.remove_user(&merged).await.unwrap();
+        assert!(references.dangling(&persist).await.unwrap().is_empty());
+
+        persist.remove_user(&kept).await.unwrap();
+        let findings = references.dangling(&persist).await.unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].id, kept.to_string());
+        assert_eq!(
+            findings[0].issue,
+            IntegrityIssue::DanglingReference {
+                collection: "avatars"
+            }
+        );
+
+        assert_eq!(
+            references.user_removed(&kept).await.unwrap(),
+            [("avatars", 3)]
+        );
+        assert!(references.dangling(&persist).await.unwrap().is_empty());
+    }
+}
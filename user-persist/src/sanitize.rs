@@ -0,0 +1,278 @@
+/*!
+Sanitizing of user supplied values before they are placed in a query.
+*/
+use mongodb::bson::{Bson, Document};
+use thiserror::Error;
+
+/// Maximum length of a user supplied value used in a pattern match.
+pub const MAX_PATTERN_LEN: usize = 100;
+
+/// Maximum nesting depth of a user supplied document.
+pub const MAX_DOCUMENT_DEPTH: usize = 8;
+
+/// Maximum number of stages in a user supplied aggregation pipeline.
+pub const MAX_PIPELINE_STAGES: usize = 10;
+
+/// Stages a user supplied pipeline may use. Stages writing to or reading
+/// from other collections are left out.
+const PIPELINE_STAGES: &[&str] = &[
+    "$match",
+    "$project",
+    "$group",
+    "$sort",
+    "$limit",
+    "$skip",
+    "$count",
+    "$unwind",
+    "$sortByCount",
+    "$addFields",
+];
+
+/// Operators a user supplied pipeline may use within its stages. Operators
+/// running javascript or regular expressions are left out, as are values
+/// of those types given in extended JSON.
+const PIPELINE_OPERATORS: &[&str] = &[
+    // Query
+    "$eq",
+    "$ne",
+    "$gt",
+    "$gte",
+    "$lt",
+    "$lte",
+    "$in",
+    "$nin",
+    "$and",
+    "$or",
+    "$nor",
+    "$not",
+    "$exists",
+    // Accumulators
+    "$sum",
+    "$avg",
+    "$min",
+    "$max",
+    "$first",
+    "$last",
+    "$push",
+    "$addToSet",
+    "$count",
+    // Expressions
+    "$cond",
+    "$ifNull",
+    "$add",
+    "$subtract",
+    "$multiply",
+    "$divide",
+    "$size",
+    "$toLower",
+    "$toUpper",
+    "$concat",
+];
+
+/// Enumeration of sanitizing failures.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SanitizeError {
+    #[error("Operator key `{0}` not permitted")]
+    OperatorKey(String),
+    #[error("Value exceeds the maximum length")]
+    TooLong,
+    #[error("Document exceeds the maximum nesting depth")]
+    TooDeep,
+    #[error("Pipeline must have between 1 and {MAX_PIPELINE_STAGES} stages")]
+    PipelineLength,
+    #[error("Pipeline stage `{0}` not permitted")]
+    Stage(String),
+    #[error("Pipeline stages must have a single key")]
+    MalformedStage,
+    #[error("Value of type `{0}` not permitted")]
+    ValueType(&'static str),
+}
+
+/// Escape all regex metacharacters so the value only matches itself.
+pub fn escape_regex(value: &str) -> String {
+    regex::escape(value)
+}
+
+/// Reject values longer than `MAX_PATTERN_LEN` characters.
+pub fn bounded(value: &str) -> Result<&str, SanitizeError> {
+    if value.chars().count() > MAX_PATTERN_LEN {
+        Err(SanitizeError::TooLong)
+    } else {
+        Ok(value)
+    }
+}
+
+/// Check a user supplied aggregation pipeline only uses permitted stages
+/// and operators and is within the stage count and depth limits.
+pub fn check_pipeline(pipeline: &[Document]) -> Result<(), SanitizeError> {
+    if pipeline.is_empty() || pipeline.len() > MAX_PIPELINE_STAGES {
+        return Err(SanitizeError::PipelineLength);
+    }
+    for stage in pipeline {
+        if stage.len() != 1 {
+            return Err(SanitizeError::MalformedStage);
+        }
+        for (name, spec) in stage {
+            if !PIPELINE_STAGES.contains(&name.as_str()) {
+                return Err(SanitizeError::Stage(name.clone()));
+            }
+            check_value(spec, PIPELINE_OPERATORS, 0)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_document(
+    document: &Document,
+    allowed: &[&str],
+    depth: usize,
+) -> Result<(), SanitizeError> {
+    if depth > MAX_DOCUMENT_DEPTH {
+        return Err(SanitizeError::TooDeep);
+    }
+    for (key, value) in document {
+        if key.starts_with('$') && !allowed.contains(&key.as_str()) {
+            return Err(SanitizeError::OperatorKey(key.clone()));
+        }
+        check_value(value, allowed, depth)?;
+    }
+    Ok(())
+}
+
+fn check_value(value: &Bson, allowed: &[&str], depth: usize) -> Result<(), SanitizeError> {
+    match value {
+        Bson::Document(d) => check_document(d, allowed, depth + 1),
+        Bson::Array(values) => values
+            .iter()
+            .try_for_each(|v| check_value(v, allowed, depth + 1)),
+        Bson::RegularExpression(_) => Err(SanitizeError::ValueType("regularExpression")),
+        Bson::JavaScriptCode(_) => Err(SanitizeError::ValueType("javascript")),
+        Bson::JavaScriptCodeWithScope(_) => Err(SanitizeError::ValueType("javascriptWithScope")),
+        Bson::DbPointer(_) => Err(SanitizeError::ValueType("dbPointer")),
+        Bson::Symbol(_) => Err(SanitizeError::ValueType("symbol")),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mongodb::bson::{doc, JavaScriptCodeWithScope};
+
+    #[test]
+    fn test_escape_regex() {
+        assert_eq!(escape_regex("a.b*(c)"), r"a\.b\*\(c\)");
+    }
+
+    #[test]
+    fn test_bounded() {
+        assert_eq!(bounded("Smith"), Ok("Smith"));
+        assert_eq!(
+            bounded(&"a".repeat(MAX_PATTERN_LEN + 1)),
+            Err(SanitizeError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_reject_operator_keys() {
+        assert!(check_pipeline(&[doc! {"$match": {"name": "$where"}}]).is_ok());
+        assert_eq!(
+            check_pipeline(&[doc! {"$match": {"a": [{"$where": "1"}]}}]),
+            Err(SanitizeError::OperatorKey("$where".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_reject_deep_documents() {
+        let mut document = doc! {"leaf": 1};
+        for _ in 0..=MAX_DOCUMENT_DEPTH {
+            document = doc! {"nested": document};
+        }
+        assert_eq!(
+            check_pipeline(&[doc! {"$match": document}]),
+            Err(SanitizeError::TooDeep)
+        );
+    }
+
+    #[test]
+    fn test_check_pipeline() {
+        let pipeline = [
+            doc! {"$match": {"age": {"$gte": 100}, "$or": [{"gender": "Male"}]}},
+            doc! {"$group": {"_id": "$gender", "total": {"$sum": 1}}},
+            doc! {"$sort": {"total": -1}},
+            doc! {"$limit": 10},
+        ];
+        assert_eq!(check_pipeline(&pipeline), Ok(()));
+
+        assert_eq!(check_pipeline(&[]), Err(SanitizeError::PipelineLength));
+        assert_eq!(
+            check_pipeline(&vec![doc! {"$limit": 1}; MAX_PIPELINE_STAGES + 1]),
+            Err(SanitizeError::PipelineLength)
+        );
+        assert_eq!(
+            check_pipeline(&[doc! {"$out": "stolen"}]),
+            Err(SanitizeError::Stage("$out".to_owned()))
+        );
+        assert_eq!(
+            check_pipeline(&[doc! {"$match": {}, "$limit": 1}]),
+            Err(SanitizeError::MalformedStage)
+        );
+        assert_eq!(
+            check_pipeline(&[doc! {"$match": {"$where": "sleep(1000)"}}]),
+            Err(SanitizeError::OperatorKey("$where".to_owned()))
+        );
+        assert_eq!(
+            check_pipeline(&[doc! {"$match": {"name": {"$regex": "(a+)+$"}}}]),
+            Err(SanitizeError::OperatorKey("$regex".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_reject_extended_json_values() {
+        // Pipelines are deserialized from the JSON body, where extended JSON
+        // turns into regular expressions and code rather than documents.
+        let pipeline = |value: serde_json::Value| {
+            serde_json::from_value::<Vec<Document>>(
+                serde_json::json!([{"$match": {"name": value}}]),
+            )
+            .unwrap()
+        };
+
+        let regex = pipeline(
+            serde_json::json!({"$regularExpression": {"pattern": "(a+)+$", "options": ""}}),
+        );
+        assert!(matches!(
+            regex[0].get_document("$match").unwrap().get("name"),
+            Some(Bson::RegularExpression(_))
+        ));
+        assert_eq!(
+            check_pipeline(&regex),
+            Err(SanitizeError::ValueType("regularExpression"))
+        );
+        assert_eq!(
+            check_pipeline(&pipeline(serde_json::json!({"$code": "sleep(1000)"}))),
+            Err(SanitizeError::ValueType("javascript"))
+        );
+        assert_eq!(
+            check_pipeline(&[doc! {"$match": {"name": Bson::JavaScriptCodeWithScope(
+                JavaScriptCodeWithScope {
+                    code: "sleep(1000)".to_owned(),
+                    scope: doc! {},
+                }
+            )}}]),
+            Err(SanitizeError::ValueType("javascriptWithScope"))
+        );
+        assert_eq!(
+            check_pipeline(&pipeline(serde_json::json!({"$symbol": "name"}))),
+            Err(SanitizeError::ValueType("symbol"))
+        );
+        assert_eq!(
+            check_pipeline(&pipeline(serde_json::json!(["$in", {"$symbol": "name"}]))),
+            Err(SanitizeError::ValueType("symbol"))
+        );
+        assert_eq!(
+            check_pipeline(&pipeline(serde_json::json!("Smith"))),
+            Ok(())
+        );
+    }
+}
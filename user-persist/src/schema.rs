@@ -0,0 +1,204 @@
+/*!
+Versioning of stored user documents.
+
+Each stored user records the schema version it was written with, and users
+written before versioning have none. Documents are upgraded when they are
+read, one [`Upgrader`] per version, so the model can evolve without
+migrating the whole collection at once. Upgraded users can be written back
+so they are only upgraded once.
+
+Documents written by a newer version of the services are read as they are,
+their unknown fields being ignored.
+*/
+use mongodb::bson::{Bson, Document};
+
+/// Field holding the schema version of a stored user.
+pub const SCHEMA_VERSION_FIELD: &str = "schema_version";
+
+/// Upgrade of a stored user to `version` from the version before it.
+/// Updates only set the fields they change, so an upgrade must leave
+/// fields already in the new shape alone.
+#[derive(Debug, Clone, Copy)]
+pub struct Upgrader {
+    pub version: u32,
+    pub description: &'static str,
+    pub upgrade: fn(&mut Document),
+}
+
+/// Upgraders of the stored users, oldest first.
+const UPGRADERS: &[Upgrader] = &[
+    Upgrader {
+        version: 1,
+        description: "record the version of users written before versioning",
+        upgrade: |_| (),
+    },
+    Upgrader {
+        version: 2,
+        description:
+            "backfill the creation and update times of users written before they were recorded",
+        upgrade: |document| {
+            let missing = missing_timestamps(document);
+            document.extend(missing);
+        },
+    },
+];
+
+/// Creation and update times a stored user is missing. A user is taken to
+/// be created when its ObjectId key was generated and, without an update
+/// time, to not have been updated since.
+pub fn missing_timestamps(document: &Document) -> Document {
+    let mut missing = Document::new();
+    let created_at = match document.get("created_at") {
+        Some(created_at) => created_at.clone(),
+        None => match document.get_object_id("_id") {
+            Ok(id) => {
+                let created_at = Bson::DateTime(id.timestamp());
+                missing.insert("created_at", created_at.clone());
+                created_at
+            }
+            Err(_) => return missing,
+        },
+    };
+    if !document.contains_key("updated_at") {
+        missing.insert("updated_at", created_at);
+    }
+    missing
+}
+
+/// Schema version of a stored user, 0 for users written before
+/// versioning.
+pub fn schema_version(document: &Document) -> u32 {
+    match document.get(SCHEMA_VERSION_FIELD) {
+        Some(Bson::Int32(version)) => u32::try_from(*version).unwrap_or_default(),
+        Some(Bson::Int64(version)) => u32::try_from(*version).unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+/// Upgraders of the stored users by version.
+#[derive(Debug, Clone, Copy)]
+pub struct SchemaRegistry {
+    upgraders: &'static [Upgrader],
+}
+
+impl Default for SchemaRegistry {
+    fn default() -> Self {
+        Self::new(UPGRADERS)
+    }
+}
+
+impl SchemaRegistry {
+    /// Create a registry of `upgraders`, which must upgrade to consecutive
+    /// versions starting from 1.
+    pub fn new(upgraders: &'static [Upgrader]) -> Self {
+        assert!(
+            upgraders
+                .iter()
+                .zip(1..)
+                .all(|(u, version)| u.version == version),
+            "upgraders must upgrade to consecutive versions from 1"
+        );
+        Self { upgraders }
+    }
+
+    /// Version users are written with.
+    pub fn current_version(&self) -> u32 {
+        self.upgraders.last().map_or(0, |u| u.version)
+    }
+
+    /// Upgrade a stored user to the current version. Returns whether it
+    /// was changed.
+    pub fn upgrade(&self, document: &mut Document) -> bool {
+        let version = schema_version(document);
+        let pending = self
+            .upgraders
+            .iter()
+            .filter(|u| u.version > version)
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            return false;
+        }
+        for upgrader in pending {
+            (upgrader.upgrade)(document);
+        }
+        document.insert(SCHEMA_VERSION_FIELD, i64::from(self.current_version()));
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{missing_timestamps, schema_version, SchemaRegistry, Upgrader};
+    use mongodb::bson::{doc, oid::ObjectId, Bson};
+
+    const UPGRADERS: &[Upgrader] = &[
+        Upgrader {
+            version: 1,
+            description: "baseline",
+            upgrade: |_| (),
+        },
+        Upgrader {
+            version: 2,
+            description: "rename years to age",
+            upgrade: |document| {
+                if let Some(years) = document.remove("years") {
+                    document.insert("age", years);
+                }
+            },
+        },
+    ];
+
+    #[test]
+    fn test_upgrade() {
+        let registry = SchemaRegistry::new(UPGRADERS);
+        assert_eq!(registry.current_version(), 2);
+
+        let mut document = doc! {"name": "Test User", "years": 100};
+        assert!(registry.upgrade(&mut document));
+        assert_eq!(
+            document,
+            doc! {"name": "Test User", "age": 100, "schema_version": 2_i64}
+        );
+        assert_eq!(schema_version(&document), 2);
+        assert!(!registry.upgrade(&mut document));
+    }
+
+    #[test]
+    fn test_newer_document() {
+        let registry = SchemaRegistry::new(UPGRADERS);
+        let mut document = doc! {"name": "Test User", "schema_version": 3};
+        assert!(!registry.upgrade(&mut document));
+        assert_eq!(schema_version(&document), 3);
+    }
+
+    #[test]
+    fn test_default_registry() {
+        let registry = SchemaRegistry::default();
+        let mut document = doc! {"name": "Test User"};
+        assert_eq!(schema_version(&document), 0);
+        assert!(registry.upgrade(&mut document));
+        assert_eq!(schema_version(&document), registry.current_version());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_gap_in_versions() {
+        SchemaRegistry::new(&UPGRADERS[1..]);
+    }
+
+    #[test]
+    fn test_missing_timestamps() {
+        let id = ObjectId::parse_str("61c0d1954c6b974ca7000000").unwrap();
+        let created_at = Bson::DateTime(id.timestamp());
+        assert_eq!(
+            missing_timestamps(&doc! {"_id": id}),
+            doc! {"created_at": created_at.clone(), "updated_at": created_at.clone()}
+        );
+
+        let mut document = doc! {"_id": id, "schema_version": 1};
+        assert!(SchemaRegistry::default().upgrade(&mut document));
+        assert_eq!(document.get("updated_at"), Some(&created_at));
+        assert_eq!(missing_timestamps(&document), doc! {});
+        assert_eq!(missing_timestamps(&doc! {"name": "Test User"}), doc! {});
+    }
+}
@@ -0,0 +1,153 @@
+/*!
+User statistics for reporting: daily snapshots kept to chart trends over
+time and the age histogram.
+*/
+use chrono::{DateTime, NaiveDate, ParseError, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    num::NonZeroU32,
+    str::FromStr,
+};
+
+/// Calendar day of a snapshot written as `YYYY-MM-DD`. Dates in this form
+/// sort as strings so they can be range queried in the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatsDate(pub NaiveDate);
+
+impl StatsDate {
+    /// The UTC day of `time`.
+    pub fn of(time: DateTime<Utc>) -> Self {
+        Self(time.date_naive())
+    }
+}
+
+impl Display for StatsDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.format("%Y-%m-%d"))
+    }
+}
+
+impl FromStr for StatsDate {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").map(Self)
+    }
+}
+
+impl Serialize for StatsDate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for StatsDate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Gender counts recorded for a day.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsSnapshot {
+    pub date: StatsDate,
+    /// Counts in the form returned by
+    /// [`count_genders`](crate::persistence::UserPersistence::count_genders).
+    pub counts: Vec<Value>,
+}
+
+/// Number of users with an age from `lower` up to but excluding `upper`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AgeBucket {
+    pub lower: u32,
+    pub upper: u32,
+    pub count: u64,
+}
+
+impl AgeBucket {
+    /// Bucket of `bucket_size` years holding `age`.
+    pub fn of(age: u32, bucket_size: NonZeroU32) -> Self {
+        let lower = age - age % bucket_size;
+        Self {
+            lower,
+            upper: lower.saturating_add(bucket_size.get()),
+            count: 0,
+        }
+    }
+}
+
+/// Histogram of `ages` in buckets of `bucket_size` years, youngest first.
+/// Buckets without users are left out.
+pub fn age_histogram(
+    ages: impl IntoIterator<Item = u32>,
+    bucket_size: NonZeroU32,
+) -> Vec<AgeBucket> {
+    let mut buckets = BTreeMap::<u32, AgeBucket>::new();
+    for age in ages {
+        let bucket = AgeBucket::of(age, bucket_size);
+        buckets.entry(bucket.lower).or_insert(bucket).count += 1;
+    }
+    buckets.into_values().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{age_histogram, AgeBucket, StatsDate, StatsSnapshot};
+    use chrono::{DateTime, NaiveDate, Utc};
+    use serde_json::json;
+    use std::num::NonZeroU32;
+
+    #[test]
+    fn test_stats_date() {
+        let time: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-03-05T23:59:59Z")
+            .unwrap()
+            .into();
+        let date = StatsDate::of(time);
+        assert_eq!(
+            date,
+            StatsDate(NaiveDate::from_ymd_opt(2024, 3, 5).unwrap())
+        );
+        assert_eq!(date.to_string(), "2024-03-05");
+        assert_eq!("2024-03-05".parse::<StatsDate>().unwrap(), date);
+        assert!("05/03/2024".parse::<StatsDate>().is_err());
+    }
+
+    #[test]
+    fn test_snapshot_serde() {
+        let wire = json!({
+            "date": "2024-03-05",
+            "counts": [{"gender": "Male", "count": 6}]
+        });
+        let snapshot = serde_json::from_value::<StatsSnapshot>(wire.clone()).unwrap();
+        assert_eq!(snapshot.date.to_string(), "2024-03-05");
+        assert_eq!(serde_json::to_value(&snapshot).unwrap(), wire);
+    }
+
+    #[test]
+    fn test_age_histogram() {
+        let bucket_size = NonZeroU32::new(10).unwrap();
+        assert_eq!(
+            age_histogram([105, 100, 131, 109], bucket_size),
+            vec![
+                AgeBucket {
+                    lower: 100,
+                    upper: 110,
+                    count: 3
+                },
+                AgeBucket {
+                    lower: 130,
+                    upper: 140,
+                    count: 1
+                },
+            ]
+        );
+        assert_eq!(age_histogram([], bucket_size), vec![]);
+        assert_eq!(AgeBucket::of(u32::MAX, bucket_size).upper, u32::MAX);
+    }
+}
@@ -0,0 +1,119 @@
+/*!
+Newline delimited JSON encoding of user streams.
+
+A database error part way through a streamed response can't change the
+status that was already sent. The [`StreamErrorPolicy`] decides whether the
+client sees the failure as an aborted body or as a final marker line.
+*/
+use crate::{persistence::PersistenceError, PERSISTENCE_TARGET};
+use futures::{
+    future,
+    stream::{Stream, StreamExt},
+};
+use serde::Serialize;
+use serde_json::json;
+use thiserror::Error;
+use tracing::warn;
+
+/// What the client sees when a stream fails part way through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StreamErrorPolicy {
+    /// Fail the body so the chunked response is never terminated.
+    #[default]
+    Abort,
+    /// End the body after a `{"error":"stream.truncated"}` line.
+    Truncate,
+}
+
+/// Failure encoding a user stream.
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error("Persistence error: `{0}`")]
+    Persistence(#[from] PersistenceError),
+    #[error("Serialization error: `{0}`")]
+    Serialization(#[from] serde_json::Error),
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: `{0}`")]
+    Parquet(#[from] parquet::errors::ParquetError),
+}
+
+/// Line ending a truncated stream.
+pub fn truncation_marker() -> String {
+    format!("{}\n", json!({"error": "stream.truncated"}))
+}
+
+/// Encode each user as a JSON line handling failures with `policy`. Users
+/// can be streamed in any serializable form.
+pub fn encode_ndjson<T: Serialize>(
+    users: impl Stream<Item = Result<T, PersistenceError>> + Send,
+    policy: StreamErrorPolicy,
+) -> impl Stream<Item = Result<String, StreamError>> + Send {
+    users
+        .map(|user| Ok(format!("{}\n", serde_json::to_string(&user?)?)))
+        .scan(false, move |failed, line: Result<String, StreamError>| {
+            if *failed {
+                return future::ready(None);
+            }
+            let line = match line {
+                Ok(line) => Ok(line),
+                Err(e) => {
+                    *failed = true;
+                    warn!(target: PERSISTENCE_TARGET, error = %e, ?policy, "user stream failed");
+                    match policy {
+                        StreamErrorPolicy::Abort => Err(e),
+                        StreamErrorPolicy::Truncate => Ok(truncation_marker()),
+                    }
+                }
+            };
+            future::ready(Some(line))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_ndjson, truncation_marker, StreamErrorPolicy};
+    use crate::{
+        persistence::PersistenceError,
+        types::{Email, Gender, User},
+    };
+    use futures::{stream, StreamExt};
+
+    fn user() -> User {
+        User {
+            id: None,
+            name: "Test User".to_owned(),
+            age: 100,
+            email: Email("test@test.com".to_owned()),
+            gender: Gender::Male,
+            phone: None,
+            address: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    async fn encode(policy: StreamErrorPolicy) -> Vec<Result<String, String>> {
+        let users =
+            stream::iter(vec![Ok(user()), Err(PersistenceError::Timeout), Ok(user())]).boxed();
+        encode_ndjson(users, policy)
+            .map(|line| line.map_err(|e| e.to_string()))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_abort_policy() {
+        let lines = encode(StreamErrorPolicy::Abort).await;
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].as_ref().unwrap().contains("Test User"));
+        assert!(lines[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn test_truncate_policy() {
+        let lines = encode(StreamErrorPolicy::Truncate).await;
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].as_ref().unwrap().contains("Test User"));
+        assert_eq!(lines[1], Ok(truncation_marker()));
+    }
+}
@@ -1,7 +1,14 @@
 /*!
 User persistence types.
+
+Types sent over the wire use camelCase field names. Field names that
+changed keep their old name as a serde alias so requests using it are
+still accepted until the next release.
 */
-use crate::PERSISTENCE_TARGET;
+use crate::{masked::Masked, patch::Patch, MaskedDebug, PERSISTENCE_TARGET};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use email_address::EmailAddress;
 use lazy_static::lazy_static;
 use mongodb::bson::oid::ObjectId;
 use regex::Regex;
@@ -9,12 +16,16 @@ use serde::{Deserialize, Serialize};
 use std::{
     fmt::{self, Display},
     ops::Deref,
+    sync::Mutex,
 };
+use thiserror::Error;
 use tracing::{event, Level};
-use validator::{Validate, ValidationError};
+use ulid::{Generator, Ulid};
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
 
 /// User Gender
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub enum Gender {
     Male,
     Female,
@@ -34,12 +45,13 @@ impl Display for Gender {
 }
 
 /// Email newtype.
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
-pub struct Email(pub String);
+#[derive(Clone, MaskedDebug, Deserialize, Serialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Email(#[masked] pub String);
 
 impl Display for Email {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", mask_str(self))
+        write!(f, "{}", Masked::new(&self.0))
     }
 }
 
@@ -50,126 +62,602 @@ impl Deref for Email {
     }
 }
 
+/// Phone number newtype.
+#[derive(Clone, MaskedDebug, Deserialize, Serialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Phone(#[masked] pub String);
+
+impl Display for Phone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Masked::new(&self.0).head(0).tail(2))
+    }
+}
+
+impl Deref for Phone {
+    type Target = String;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Postal address newtype.
+#[derive(Clone, MaskedDebug, Deserialize, Serialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Address(#[masked] pub String);
+
+impl Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Masked::new(&self.0).tail(0))
+    }
+}
+
+impl Deref for Address {
+    type Target = String;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Maximum length of an email address.
+pub const MAX_EMAIL_LEN: usize = 254;
+/// Maximum length of the local part of an email address.
+pub const MAX_EMAIL_LOCAL_LEN: usize = 64;
+
+/// Email validation mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum EmailValidation {
+    /// Pattern match on the general shape of an email.
+    #[default]
+    Lenient,
+    /// Parse the email according to RFC 5322.
+    Strict,
+}
+
 impl Email {
     /// Validate email.
-    fn is_valid(&self) -> bool {
+    pub(crate) fn is_valid(&self, mode: EmailValidation) -> bool {
         lazy_static! {
             static ref RE: Regex = Regex::new(r"[a-zA-Z0-9+._-]+@[a-zA-Z-]+\.[a-z]+").unwrap();
         }
-        RE.is_match(self)
+        let within_limits = self.len() <= MAX_EMAIL_LEN
+            && self
+                .rsplit_once('@')
+                .is_none_or(|(local, _)| local.len() <= MAX_EMAIL_LOCAL_LEN);
+
+        within_limits
+            && match mode {
+                EmailValidation::Lenient => RE.is_match(self),
+                EmailValidation::Strict => EmailAddress::is_valid(self),
+            }
     }
 }
 
 /// Email validator.
-fn validate_email(email: &Email) -> Result<(), ValidationError> {
+pub fn validate_email(email: &Email, mode: EmailValidation) -> Result<(), ValidationError> {
     event!(
       target: PERSISTENCE_TARGET,
       Level::DEBUG,
-      "validating email {email}"
+      ?mode,
+      "validating email"
     );
-    if email.is_valid() {
+    if email.is_valid(mode) {
         Ok(())
     } else {
         Err(ValidationError::new("invalid email"))
     }
 }
 
+/// Errors of the field `field` holding `email`, if any, when it isn't
+/// valid in `mode`.
+pub fn validate_email_field(
+    field: &'static str,
+    email: Option<&Email>,
+    mode: EmailValidation,
+) -> Result<(), ValidationErrors> {
+    match email.map(|email| validate_email(email, mode)) {
+        Some(Err(mut error)) => {
+            error.add_param("value".into(), &email);
+            let mut errors = ValidationErrors::new();
+            errors.add(field, error);
+            Err(errors)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Request validated with the [`EmailValidation`] mode of the service.
+/// `Validate` checks everything but the emails, which are checked in the
+/// mode the service passes to `validate_request`.
+pub trait ValidateRequest: Validate {
+    /// Check the emails of the request in `mode`. Requests without emails
+    /// have none to check.
+    fn validate_emails(&self, _mode: EmailValidation) -> Result<(), ValidationErrors> {
+        Ok(())
+    }
+
+    /// Validate the request checking its emails in `mode`.
+    fn validate_request(&self, mode: EmailValidation) -> Result<(), ValidationErrors> {
+        merge_errors(self.validate(), self.validate_emails(mode))
+    }
+}
+
+/// Errors of both results, nested errors of the same field merged.
+fn merge_errors(
+    first: Result<(), ValidationErrors>,
+    second: Result<(), ValidationErrors>,
+) -> Result<(), ValidationErrors> {
+    let (mut first, second) = match (first, second) {
+        (Ok(()), result) | (result, Ok(())) => return result,
+        (Err(first), Err(second)) => (first, second),
+    };
+    for (field, kind) in second.into_errors() {
+        let merged = match (first.errors_mut().remove(field), kind) {
+            (Some(ValidationErrorsKind::Field(mut errors)), ValidationErrorsKind::Field(more)) => {
+                errors.extend(more);
+                ValidationErrorsKind::Field(errors)
+            }
+            (Some(ValidationErrorsKind::Struct(errors)), ValidationErrorsKind::Struct(more)) => {
+                match merge_errors(Err(*errors), Err(*more)) {
+                    Err(errors) => ValidationErrorsKind::Struct(Box::new(errors)),
+                    Ok(()) => continue,
+                }
+            }
+            (_, kind) => kind,
+        };
+        first.errors_mut().insert(field, merged);
+    }
+    Err(first)
+}
+
+/// Longest opaque key accepted.
+pub const MAX_OPAQUE_KEY_LEN: usize = 64;
+
 /// User primary key.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct UserKey(pub String);
+///
+/// Keys are parsed into the format they were generated in so a malformed
+/// key is rejected when it is received rather than producing a query that
+/// can never match. Keys serialize as their string form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum UserKey {
+    /// Mongodb ObjectId.
+    ObjectId(ObjectId),
+    /// ULID.
+    Ulid(Ulid),
+    /// Key of another backend made of at most [`MAX_OPAQUE_KEY_LEN`]
+    /// ASCII letters, digits, `-` or `_`.
+    Opaque(String),
+}
 
-impl Deref for UserKey {
-    type Target = String;
-    fn deref(&self) -> &Self::Target {
-        &self.0
+/// Keys are documented as the strings they serialize as.
+#[cfg(feature = "openapi")]
+impl<'s> utoipa::ToSchema<'s> for UserKey {
+    fn schema() -> (
+        &'s str,
+        utoipa::openapi::RefOr<utoipa::openapi::schema::Schema>,
+    ) {
+        let schema = utoipa::openapi::ObjectBuilder::new()
+            .schema_type(utoipa::openapi::SchemaType::String)
+            .description(Some("ObjectId, ULID or opaque key of the user"))
+            .example(Some("61c0d1954c6b974ca7000000".into()));
+        ("UserKey", schema.into())
     }
 }
 
 impl Display for UserKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        match self {
+            Self::ObjectId(oid) => write!(f, "{oid}"),
+            Self::Ulid(ulid) => write!(f, "{ulid}"),
+            Self::Opaque(key) => write!(f, "{key}"),
+        }
     }
 }
 
 impl From<ObjectId> for UserKey {
     fn from(oid: ObjectId) -> Self {
-        Self(oid.to_string())
+        Self::ObjectId(oid)
+    }
+}
+
+impl From<Ulid> for UserKey {
+    fn from(ulid: Ulid) -> Self {
+        Self::Ulid(ulid)
+    }
+}
+
+impl From<UserKey> for String {
+    fn from(key: UserKey) -> Self {
+        key.to_string()
+    }
+}
+
+/// Generate a ULID ordered after every ULID previously generated by this
+/// process.
+pub fn next_ulid() -> Ulid {
+    lazy_static! {
+        static ref ULIDS: Mutex<Generator> = Mutex::new(Generator::new());
+    }
+    // Only fails when the random part overflows within one millisecond.
+    ULIDS
+        .lock()
+        .unwrap()
+        .generate()
+        .unwrap_or_else(|_| Ulid::new())
+}
+
+/// Format of generated user keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum KeyFormat {
+    /// 24 character hex mongodb ObjectId.
+    #[default]
+    ObjectId,
+    /// 26 character Crockford base32 ULID.
+    Ulid,
+}
+
+impl KeyFormat {
+    /// Generate a new key. Keys of both formats start with a timestamp so
+    /// later keys sort after earlier ones. ULIDs generated within the same
+    /// millisecond stay in order.
+    pub fn generate(&self) -> UserKey {
+        match self {
+            Self::ObjectId => UserKey::from(ObjectId::new()),
+            Self::Ulid => UserKey::from(next_ulid()),
+        }
+    }
+}
+
+impl UserKey {
+    /// Format of the key if it was generated as an ObjectId or a ULID.
+    pub fn format(&self) -> Option<KeyFormat> {
+        match self {
+            Self::ObjectId(_) => Some(KeyFormat::ObjectId),
+            Self::Ulid(_) => Some(KeyFormat::Ulid),
+            Self::Opaque(_) => None,
+        }
+    }
+
+    /// The key masked for logs, revealing its last characters.
+    pub fn masked(&self) -> Masked<String> {
+        Masked::new(self.to_string()).head(0).tail(6)
     }
 }
 
 /// Key error.
-#[derive(Debug)]
-pub struct InvalidKeyError;
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[error("Invalid user key `{0}`")]
+pub struct InvalidKeyError(pub String);
 
 impl std::str::FromStr for UserKey {
     type Err = InvalidKeyError;
     fn from_str(s: &str) -> Result<UserKey, InvalidKeyError> {
-        if s.is_empty() {
-            Err(InvalidKeyError)
+        if let Ok(oid) = ObjectId::parse_str(s) {
+            Ok(Self::ObjectId(oid))
+        } else if let Ok(ulid) = Ulid::from_string(s) {
+            Ok(Self::Ulid(ulid))
+        } else if !s.is_empty()
+            && s.len() <= MAX_OPAQUE_KEY_LEN
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            Ok(Self::Opaque(s.to_owned()))
         } else {
-            Ok(UserKey(s.to_string()))
+            Err(InvalidKeyError(s.to_owned()))
         }
     }
 }
 
+impl TryFrom<String> for UserKey {
+    type Error = InvalidKeyError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
 /// User type.
-#[derive(Clone, Debug, Deserialize, Serialize, Validate, PartialEq, Eq)]
+#[derive(Clone, MaskedDebug, Deserialize, Serialize, Validate, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
 pub struct User {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<UserKey>,
+    #[masked]
     pub name: String,
     #[validate(range(min = 100))]
     pub age: u32,
-    #[validate(custom = "validate_email")]
+    #[masked]
     pub email: Email,
     pub gender: Gender,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<Phone>,
+    #[masked]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+    /// Time the user was saved, set by the persistence layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Time the user was last saved or updated, set by the persistence
+    /// layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
-/// Mask a string value showing only the first and last character and
-/// masking the rest.
-fn mask_str(str: &str) -> String {
-    let head = str.chars().next().unwrap_or_default();
-    let last = str.chars().last().unwrap_or_default();
-    let mask_chars_len = if str.len() > 3 { str.len() - 2 } else { 1 };
-    let mask_chars = "*".repeat(mask_chars_len);
-
-    format!("{}{}{}", head, mask_chars, last)
+impl ValidateRequest for User {
+    fn validate_emails(&self, mode: EmailValidation) -> Result<(), ValidationErrors> {
+        validate_email_field("email", Some(&self.email), mode)
+    }
 }
 
 impl Display for User {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", mask_str(&self.name), mask_str(&self.email))
+        write!(f, "{} {}", Masked::new(&self.name), self.email)?;
+        if let Some(phone) = &self.phone {
+            write!(f, " {phone}")?;
+        }
+        if let Some(address) = &self.address {
+            write!(f, " {address}")?;
+        }
+        Ok(())
     }
 }
 
 /// Request type to update a user record.
-#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+#[derive(Clone, MaskedDebug, Deserialize, Serialize, Validate)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
 pub struct UpdateUser {
     pub id: UserKey,
+    #[masked]
     pub name: String,
-    #[validate(custom = "validate_email")]
+    #[masked]
     pub email: Email,
     #[validate(range(min = 100))]
     pub age: u32,
-    pub hid: String,
+    /// Also accepted as `hid` until the next release.
+    #[serde(alias = "hid")]
+    pub hash_id: String,
+    /// Left unchanged when absent and cleared when `null`.
+    #[masked]
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    pub phone: Patch<Phone>,
+    /// Left unchanged when absent and cleared when `null`.
+    #[masked]
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    pub address: Patch<Address>,
+}
+
+impl ValidateRequest for UpdateUser {
+    fn validate_emails(&self, mode: EmailValidation) -> Result<(), ValidationErrors> {
+        validate_email_field("email", Some(&self.email), mode)
+    }
 }
 
 impl Display for UpdateUser {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {} {}", self.id, mask_str(&self.name), self.age)
+        write!(f, "{} {} {}", self.id, Masked::new(&self.name), self.age)
+    }
+}
+
+/// Fields set on every user matched by a bulk update. Fields left out are
+/// unchanged.
+#[derive(Clone, Default, MaskedDebug, Deserialize, Serialize, Validate)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+#[validate(schema(function = "validate_partial_update"))]
+pub struct PartialUpdateUser {
+    #[masked]
+    #[validate(length(min = 1, max = 100))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[validate(range(min = 100))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gender: Option<Gender>,
+    /// Left unchanged when absent and cleared when `null`.
+    #[masked]
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    pub phone: Patch<Phone>,
+    /// Left unchanged when absent and cleared when `null`.
+    #[masked]
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    pub address: Patch<Address>,
+}
+
+impl PartialUpdateUser {
+    /// Whether the update leaves every field unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.age.is_none()
+            && self.gender.is_none()
+            && self.phone.is_absent()
+            && self.address.is_absent()
+    }
+
+    /// Apply the update to `user`.
+    pub fn apply(&self, user: &mut User) {
+        if let Some(name) = &self.name {
+            user.name.clone_from(name);
+        }
+        if let Some(age) = self.age {
+            user.age = age;
+        }
+        if let Some(gender) = &self.gender {
+            user.gender = gender.clone();
+        }
+        self.phone.apply(&mut user.phone);
+        self.address.apply(&mut user.address);
+    }
+}
+
+/// Lists the names of the fields changed.
+impl Display for PartialUpdateUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            ("name", self.name.is_some()),
+            ("age", self.age.is_some()),
+            ("gender", self.gender.is_some()),
+            ("phone", !self.phone.is_absent()),
+            ("address", !self.address.is_absent()),
+        ];
+        let changed = fields
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        write!(f, "{}", changed.join(", "))
+    }
+}
+
+/// Rejects an update that doesn't change anything.
+fn validate_partial_update(update: &PartialUpdateUser) -> Result<(), ValidationError> {
+    if update.is_empty() {
+        Err(ValidationError::new("empty update"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Request type to update every user matching a search.
+#[derive(Clone, MaskedDebug, Deserialize, Serialize, Validate)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct BulkUpdate {
+    #[validate]
+    pub search: UserSearch,
+    #[validate]
+    pub set: PartialUpdateUser,
+}
+
+impl ValidateRequest for BulkUpdate {
+    fn validate_emails(&self, mode: EmailValidation) -> Result<(), ValidationErrors> {
+        ValidationErrors::merge(Ok(()), "search", self.search.validate_emails(mode))
+    }
+}
+
+impl Display for BulkUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "search: {}, set: {}", self.search, self.set)
+    }
+}
+
+/// Outcome of a bulk update.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct BulkUpdateResult {
+    /// Users matching the search.
+    pub matched: u64,
+    /// Users changed by the update. Always zero for a dry run.
+    pub modified: u64,
+    /// Whether the update was only counted, not applied.
+    pub dry_run: bool,
+}
+
+/// Order of search results. Every order ends with the user key so results
+/// come back in the same order every time and pages don't overlap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSort {
+    /// By name, then by key.
+    #[default]
+    Name,
+    /// By key, which follows creation order.
+    Id,
+    /// By creation time, then by key.
+    Created,
+    /// By time of the last update, then by key.
+    Updated,
+}
+
+impl SearchSort {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
     }
 }
 
-/// Request type for user search.
-#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+/// Request type for user search. Unknown fields are rejected so a
+/// misspelled criterion doesn't silently widen the search.
+#[derive(Clone, Default, MaskedDebug, Deserialize, Serialize, Validate)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct UserSearch {
-    #[validate(custom = "validate_email")]
+    #[masked]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub email: Option<Email>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub gender: Option<Gender>,
+    #[masked]
+    #[validate(length(max = 100))]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    /// Only users created after this time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_after: Option<DateTime<Utc>>,
+    /// Only users last updated before this time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_before: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "SearchSort::is_default")]
+    pub sort: SearchSort,
+}
+
+impl ValidateRequest for UserSearch {
+    fn validate_emails(&self, mode: EmailValidation) -> Result<(), ValidationErrors> {
+        validate_email_field("email", self.email.as_ref(), mode)
+    }
+}
+
+/// Page of search results with the total number of matches.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchPage<T> {
+    pub items: Vec<T>,
+    /// Number of users matching the search across every page.
+    pub total: u64,
+    /// The total is an estimate rather than an exact count.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+    /// Position of the first item among all the matches.
+    pub offset: u64,
+    /// Maximum number of items in the page.
+    pub limit: u64,
+    /// Opaque cursor continuing after the last item, when more items
+    /// follow.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl<T> SearchPage<T> {
+    /// Convert the items keeping the page position and totals.
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> SearchPage<U> {
+        SearchPage {
+            items: self.items.into_iter().map(f).collect(),
+            total: self.total,
+            estimated: self.estimated,
+            offset: self.offset,
+            limit: self.limit,
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
+impl UserSearch {
+    /// Whether the search has no criteria and matches every user. The sort
+    /// is not a criterion.
+    pub fn is_empty(&self) -> bool {
+        self.email.is_none()
+            && self.gender.is_none()
+            && self.name.is_none()
+            && self.created_after.is_none()
+            && self.updated_before.is_none()
+    }
 }
 
 impl Display for UserSearch {
@@ -177,20 +665,75 @@ impl Display for UserSearch {
         write!(
             f,
             r#"email = "{}", gender = "{}", name = "{}""#,
-            self.email.as_ref().map(|s| mask_str(s)).unwrap_or_default(),
+            self.email
+                .as_ref()
+                .map(|email| email.to_string())
+                .unwrap_or_default(),
             self.gender
                 .as_ref()
                 .map(|g| format!("{g}"))
                 .unwrap_or_default(),
-            self.name.as_ref().map(|s| mask_str(s)).unwrap_or_default()
+            self.name
+                .as_ref()
+                .map(|name| Masked::new(name).to_string())
+                .unwrap_or_default()
         )
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Email, User};
+    use super::{
+        Address, Email, EmailValidation, InvalidKeyError, KeyFormat, Phone, UpdateUser, User,
+        UserKey, UserSearch, ValidateRequest, MAX_EMAIL_LEN,
+    };
     use crate::types::Gender;
+    use serde_json::json;
+
+    #[test]
+    fn test_email_validation_modes() {
+        let numeric_domain = Email("user@host1.io".into());
+        assert!(!numeric_domain.is_valid(EmailValidation::Lenient));
+        assert!(numeric_domain.is_valid(EmailValidation::Strict));
+
+        let no_domain = Email("user@".into());
+        assert!(!no_domain.is_valid(EmailValidation::Strict));
+    }
+
+    #[test]
+    fn test_validate_request() {
+        let user = User {
+            id: None,
+            name: "Test User".to_owned(),
+            age: 20,
+            email: Email("user@host1.io".to_owned()),
+            gender: Gender::Male,
+            phone: None,
+            address: None,
+            created_at: None,
+            updated_at: None,
+        };
+        let errors = user.validate_request(EmailValidation::Lenient).unwrap_err();
+        let mut fields = errors.field_errors().into_keys().collect::<Vec<_>>();
+        fields.sort_unstable();
+        assert_eq!(fields, ["age", "email"]);
+
+        let errors = user.validate_request(EmailValidation::Strict).unwrap_err();
+        assert_eq!(
+            errors.field_errors().into_keys().collect::<Vec<_>>(),
+            ["age"]
+        );
+    }
+
+    #[test]
+    fn test_email_max_lengths() {
+        let long_local = Email(format!("{}@example.com", "a".repeat(65)));
+        assert!(!long_local.is_valid(EmailValidation::Lenient));
+        assert!(!long_local.is_valid(EmailValidation::Strict));
+
+        let long_email = Email(format!("user@{}.com", "a".repeat(MAX_EMAIL_LEN)));
+        assert!(!long_email.is_valid(EmailValidation::Lenient));
+    }
 
     #[test]
     fn test_deserialize_user() {
@@ -209,8 +752,135 @@ mod test {
                 name: "Scenario User".into(),
                 email: Email("scenario@test.com".into()),
                 age: 20,
-                gender: Gender::Female
+                gender: Gender::Female,
+                phone: None,
+                address: None,
+                created_at: None,
+                updated_at: None,
             }
         );
     }
+
+    #[test]
+    fn test_key_formats() {
+        for format in [KeyFormat::ObjectId, KeyFormat::Ulid] {
+            assert_eq!(format.generate().format(), Some(format));
+        }
+
+        // ULIDs generated within the same millisecond stay in order.
+        let keys = (0..100)
+            .map(|_| KeyFormat::Ulid.generate())
+            .collect::<Vec<_>>();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = "61c0d1954c6b974ca7000000".parse::<UserKey>().unwrap();
+        assert_eq!(key.format(), Some(KeyFormat::ObjectId));
+        assert_eq!(key.to_string(), "61c0d1954c6b974ca7000000");
+        assert_eq!(key.masked().to_string(), "******************000000");
+
+        let key = "01ARZ3NDEKTSV4RRFFQ69G5FAV".parse::<UserKey>().unwrap();
+        assert_eq!(key.format(), Some(KeyFormat::Ulid));
+        assert_eq!(key.to_string(), "01ARZ3NDEKTSV4RRFFQ69G5FAV");
+
+        assert_eq!(
+            "fake_key-1".parse::<UserKey>(),
+            Ok(UserKey::Opaque("fake_key-1".to_owned()))
+        );
+        for invalid in ["", "a b", "{\"$ne\": null}", &"a".repeat(65)] {
+            assert_eq!(
+                invalid.parse::<UserKey>(),
+                Err(InvalidKeyError(invalid.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_serde() {
+        let key = "61c0d1954c6b974ca7000000".parse::<UserKey>().unwrap();
+        assert_eq!(
+            serde_json::to_string(&key).unwrap(),
+            "\"61c0d1954c6b974ca7000000\""
+        );
+        assert_eq!(
+            serde_json::from_str::<UserKey>("\"61c0d1954c6b974ca7000000\"").unwrap(),
+            key
+        );
+        assert!(serde_json::from_str::<UserKey>("\"not a key\"").is_err());
+    }
+
+    #[test]
+    fn test_user_wire_names() {
+        let user = User {
+            id: Some("61c0d1954c6b974ca7000000".parse().unwrap()),
+            name: "Test User".into(),
+            age: 100,
+            email: Email("test@test.com".into()),
+            gender: Gender::Male,
+            phone: Some(Phone("555-0100".into())),
+            address: Some(Address("1 Main St".into())),
+            created_at: None,
+            updated_at: None,
+        };
+        let wire = json!({
+            "id": "61c0d1954c6b974ca7000000",
+            "name": "Test User",
+            "age": 100,
+            "email": "test@test.com",
+            "gender": "Male",
+            "phone": "555-0100",
+            "address": "1 Main St"
+        });
+
+        assert_eq!(serde_json::to_value(&user).unwrap(), wire);
+        assert_eq!(serde_json::from_value::<User>(wire).unwrap(), user);
+    }
+
+    #[test]
+    fn test_update_user_wire_names() {
+        let wire = json!({
+            "id": "61c0d1954c6b974ca7000000",
+            "name": "Test User",
+            "email": "test@test.com",
+            "age": 100,
+            "hashId": "hash"
+        });
+
+        let update = serde_json::from_value::<UpdateUser>(wire.clone()).unwrap();
+        assert_eq!(update.hash_id, "hash");
+        assert_eq!(serde_json::to_value(&update).unwrap(), wire);
+    }
+
+    #[test]
+    fn test_update_user_legacy_names() {
+        let update = serde_json::from_value::<UpdateUser>(json!({
+            "id": "61c0d1954c6b974ca7000000",
+            "name": "Test User",
+            "email": "test@test.com",
+            "age": 100,
+            "hid": "hash"
+        }))
+        .unwrap();
+
+        assert_eq!(update.hash_id, "hash");
+        let wire = serde_json::to_value(&update).unwrap();
+        assert_eq!(wire["hashId"], json!("hash"));
+        assert!(wire.get("hid").is_none());
+    }
+
+    #[test]
+    fn test_user_search_wire_names() {
+        let wire = json!({
+            "email": "test@test.com",
+            "gender": "Female",
+            "name": "Test"
+        });
+
+        let search = serde_json::from_value::<UserSearch>(wire.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&search).unwrap(), wire);
+
+        assert!(serde_json::from_value::<UserSearch>(json!({"nmae": "Test"})).is_err());
+    }
 }
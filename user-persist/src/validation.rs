@@ -0,0 +1,197 @@
+/*!
+Counts of validation failures.
+
+Every failed validation of a request body is counted with
+[`count_failures`], incrementing [`VALIDATION_FAILURE_METRIC`] with `field`
+and `code` labels for each failure, so the clients that keep sending bad
+ages or emails show up on the dashboards. Services with an admin endpoint
+reporting the most frequently failing fields count them with
+[`ValidationStats::record`], which also keeps in-process counts.
+
+Fields of nested structs and lists are named by their path, such as
+`address.city`. List indices are left out to bound the label values.
+*/
+use crate::{ValidationErrors, ValidationErrorsKind};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Counter incremented for every failed validation of a field.
+pub const VALIDATION_FAILURE_METRIC: &str = "validation_failures_total";
+
+/// How many times validation of a field failed with a code.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldFailures {
+    pub field: String,
+    pub code: String,
+    pub count: u64,
+}
+
+/// Failures of `errors` as field paths and codes.
+pub fn failures(errors: &ValidationErrors) -> Vec<(String, String)> {
+    let mut failures = Vec::new();
+    collect(errors, "", &mut failures);
+    failures
+}
+
+fn collect(errors: &ValidationErrors, prefix: &str, failures: &mut Vec<(String, String)>) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() {
+            (*field).to_owned()
+        } else {
+            format!("{prefix}.{field}")
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => failures.extend(
+                errors
+                    .iter()
+                    .map(|error| (path.clone(), error.code.to_string())),
+            ),
+            ValidationErrorsKind::Struct(errors) => collect(errors, &path, failures),
+            ValidationErrorsKind::List(items) => {
+                for errors in items.values() {
+                    collect(errors, &path, failures);
+                }
+            }
+        }
+    }
+}
+
+/// Increment the metric for each failure of `errors`, returning them.
+pub fn count_failures(errors: &ValidationErrors) -> Vec<(String, String)> {
+    let failures = failures(errors);
+    for (field, code) in &failures {
+        metrics::increment_counter!(
+            VALIDATION_FAILURE_METRIC,
+            "field" => field.clone(),
+            "code" => code.clone()
+        );
+    }
+    failures
+}
+
+/// Validation failures counted since the service started.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationStats {
+    counts: Arc<Mutex<HashMap<(String, String), u64>>>,
+}
+
+impl ValidationStats {
+    /// Count the failures of `errors`.
+    pub fn record(&self, errors: &ValidationErrors) {
+        let failures = count_failures(errors);
+        let mut counts = self.counts.lock().unwrap();
+        for failure in failures {
+            *counts.entry(failure).or_default() += 1;
+        }
+    }
+
+    /// The `limit` most frequent failures, most frequent first.
+    pub fn top(&self, limit: usize) -> Vec<FieldFailures> {
+        let mut top = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((field, code), count)| FieldFailures {
+                field: field.clone(),
+                code: code.clone(),
+                count: *count,
+            })
+            .collect::<Vec<_>>();
+        top.sort_unstable_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.field.cmp(&b.field))
+                .then_with(|| a.code.cmp(&b.code))
+        });
+        top.truncate(limit);
+        top
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{failures, FieldFailures, ValidationStats};
+    use crate::{Validate, ValidationError, ValidationErrors};
+
+    fn errors(fields: &[(&'static str, &'static str)]) -> ValidationErrors {
+        let mut errors = ValidationErrors::new();
+        for (field, code) in fields {
+            errors.add(field, ValidationError::new(code));
+        }
+        errors
+    }
+
+    #[test]
+    fn test_nested_failures() {
+        #[derive(Validate)]
+        struct Address {
+            #[validate(length(min = 1))]
+            city: String,
+        }
+
+        #[derive(Validate)]
+        struct Person {
+            #[validate(range(min = 18))]
+            age: u32,
+            #[validate]
+            address: Address,
+            #[validate]
+            previous: Vec<Address>,
+        }
+
+        let person = Person {
+            age: 10,
+            address: Address {
+                city: String::new(),
+            },
+            previous: vec![
+                Address {
+                    city: String::new(),
+                },
+                Address {
+                    city: String::new(),
+                },
+            ],
+        };
+        let mut failures = failures(&person.validate().unwrap_err());
+        failures.sort();
+        assert_eq!(
+            failures,
+            [
+                ("address.city".to_owned(), "length".to_owned()),
+                ("age".to_owned(), "range".to_owned()),
+                ("previous.city".to_owned(), "length".to_owned()),
+                ("previous.city".to_owned(), "length".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_top_failures() {
+        let stats = ValidationStats::default();
+        stats.record(&errors(&[("age", "range"), ("email", "email")]));
+        stats.record(&errors(&[("email", "email")]));
+        stats.record(&errors(&[("name", "length")]));
+
+        assert_eq!(
+            stats.top(2),
+            [
+                FieldFailures {
+                    field: "email".to_owned(),
+                    code: "email".to_owned(),
+                    count: 2
+                },
+                FieldFailures {
+                    field: "age".to_owned(),
+                    code: "range".to_owned(),
+                    count: 1
+                },
+            ]
+        );
+    }
+}